@@ -0,0 +1,28 @@
+"""A module extension generating a repository for each of a few tools.
+
+razel doesn't run it yet: vendor/ holds hand-written copies of the repositories it generates, which must be kept
+in step with it.
+"""
+
+_BUILD = """filegroup(
+    name = "{name}",
+    srcs = ["run.sh"],
+    visibility = ["//visibility:public"],
+)
+"""
+
+def _tool_repo_impl(repository_ctx):
+    name = repository_ctx.attr.tool
+    repository_ctx.file("run.sh", "#!/bin/sh\necho \"{} $@\"\n".format(name), executable = True)
+    repository_ctx.file("BUILD.bazel", _BUILD.format(name = name))
+
+_tool_repo = repository_rule(
+    implementation = _tool_repo_impl,
+    attrs = {"tool": attr.string()},
+)
+
+def _tools_impl(module_ctx):
+    for tool in ["formatter", "linter"]:
+        _tool_repo(name = tool, tool = tool)
+
+tools = module_extension(implementation = _tools_impl)
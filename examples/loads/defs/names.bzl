@@ -0,0 +1,3 @@
+load(":prefix.bzl", "PREFIX")
+
+NAMES = [PREFIX + name for name in ["alpha", "beta"]]
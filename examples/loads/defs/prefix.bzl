@@ -0,0 +1 @@
+PREFIX = "gen_"
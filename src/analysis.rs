@@ -0,0 +1,53 @@
+//! The analysis phase of a build, as far as razel gets without running actions: loading every target the requested
+//! ones depend on, which fails on a dependency that doesn't exist or can't be seen, and working out the result of each
+//! genquery among them.
+
+use crate::bazel::label::Label;
+use crate::bazel::rule::Rule;
+use crate::genquery;
+use crate::query;
+use crate::workspace::Workspace;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// A genquery among the analyzed targets, and its result.
+pub struct Genquery {
+    pub rule: Rule,
+    pub result: Vec<u8>,
+}
+
+/// What analysis worked out, for the execution phase to build.
+#[derive(Default)]
+pub struct Analysis {
+    genqueries: HashMap<Label<'static>, Genquery>,
+}
+
+impl Analysis {
+    /// The genquery `label`, if it is one of the analyzed targets.
+    pub fn genquery(&self, label: &Label<'static>) -> Option<&Genquery> {
+        self.genqueries.get(label)
+    }
+}
+
+/// Analyzes `labels` and the targets they depend on.
+pub async fn analyze(
+    workspace: &Arc<Workspace>,
+    labels: &[Label<'static>],
+) -> anyhow::Result<Analysis> {
+    let mut analysis = Analysis::default();
+    if labels.is_empty() {
+        return Ok(analysis);
+    }
+    for (label, package) in query::deps_closure(workspace.clone(), labels).await? {
+        if let Some(rule) = package
+            .rules
+            .get(label.name())
+            .filter(|r| r.rule_class == "genquery")
+        {
+            let result = genquery::evaluate(workspace.clone(), &label, rule).await?;
+            let rule = rule.clone();
+            analysis.genqueries.insert(label, Genquery { rule, result });
+        }
+    }
+    Ok(analysis)
+}
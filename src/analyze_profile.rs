@@ -0,0 +1,300 @@
+//! `razel analyze-profile`: a summary of a `--profile` trace, for when opening it in a trace viewer is too much.
+//!
+//! Spans are rebuilt from the begin/end events of the trace, and nested by time within each track. The summary has the
+//! wall time taken by each kind of span, the critical path through the invocation, and the slowest actions: the spans
+//! with nothing inside them, which is where the time actually went.
+
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+
+/// How many of the slowest actions to list.
+const SLOWEST_ACTIONS: usize = 10;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Trace {
+    trace_events: Vec<TraceEvent>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TraceEvent {
+    name: String,
+    ph: String,
+    ts: u64,
+    #[serde(default)]
+    id: String,
+    #[serde(default)]
+    args: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug)]
+struct Span {
+    name: String,
+    args: serde_json::Map<String, serde_json::Value>,
+    track: String,
+    start: u64,
+    end: u64,
+    children: Vec<usize>,
+    has_parent: bool,
+}
+
+impl Span {
+    fn duration(&self) -> u64 {
+        self.end - self.start
+    }
+
+    /// The span's name and arguments, e.g. `load package package=@@//foo`.
+    fn describe(&self) -> String {
+        let mut text = self.name.clone();
+        for (key, value) in &self.args {
+            match value {
+                serde_json::Value::String(s) => text.push_str(&format!(" {key}={s}")),
+                value => text.push_str(&format!(" {key}={value}")),
+            }
+        }
+        text
+    }
+}
+
+fn seconds(micros: u64) -> String {
+    format!("{:.3} s", micros as f64 / 1e6)
+}
+
+/// Pairs up the begin and end events of `events`, and nests the resulting spans. Spans that never ended are dropped.
+fn spans(mut events: Vec<TraceEvent>) -> Vec<Span> {
+    events.sort_by_key(|e| e.ts);
+    let mut spans = Vec::new();
+    let mut open: HashMap<(String, String), Vec<usize>> = HashMap::new();
+    let mut ended = Vec::new();
+    for event in events {
+        let key = (event.id, event.name);
+        match event.ph.as_str() {
+            "b" | "B" => {
+                open.entry(key.clone()).or_default().push(spans.len());
+                spans.push(Span {
+                    name: key.1,
+                    args: event.args,
+                    track: key.0,
+                    start: event.ts,
+                    end: event.ts,
+                    children: Vec::new(),
+                    has_parent: false,
+                });
+                ended.push(false);
+            }
+            "e" | "E" => {
+                if let Some(i) = open.get_mut(&key).and_then(Vec::pop) {
+                    spans[i].end = event.ts;
+                    ended[i] = true;
+                }
+            }
+            _ => {}
+        }
+    }
+    let mut ended = ended.into_iter();
+    spans.retain(|_| ended.next().unwrap());
+
+    // Within a track, a span is inside the innermost span that contains it.
+    let mut order: Vec<usize> = (0..spans.len()).collect();
+    order.sort_by_key(|&i| {
+        (
+            &spans[i].track,
+            spans[i].start,
+            std::cmp::Reverse(spans[i].end),
+        )
+    });
+    let mut ancestors: Vec<usize> = Vec::new();
+    for i in order {
+        while let Some(&top) = ancestors.last() {
+            if spans[top].track == spans[i].track && spans[top].end >= spans[i].end {
+                break;
+            }
+            ancestors.pop();
+        }
+        if let Some(&parent) = ancestors.last() {
+            spans[parent].children.push(i);
+            spans[i].has_parent = true;
+        }
+        ancestors.push(i);
+    }
+    spans
+}
+
+/// Appends the critical path through `candidates` that ends by `end` to `path`, with the depth of each span.
+///
+/// Going backwards from `end`, each step of the path is the span that finished last before the next one started, as
+/// that is the one the rest of the invocation was waiting for. The path through each step's children is included
+/// beneath it.
+fn critical_path(
+    spans: &[Span],
+    candidates: &[usize],
+    end: u64,
+    depth: usize,
+    path: &mut Vec<(usize, usize)>,
+) {
+    let mut by_end = candidates.to_vec();
+    by_end.sort_by_key(|&i| std::cmp::Reverse(spans[i].end));
+    let mut cursor = end;
+    let mut chain = Vec::new();
+    for i in by_end {
+        if spans[i].end <= cursor {
+            chain.push(i);
+            cursor = spans[i].start;
+        }
+    }
+    for &i in chain.iter().rev() {
+        path.push((depth, i));
+        critical_path(spans, &spans[i].children, spans[i].end, depth + 1, path);
+    }
+}
+
+/// Summarizes the JSON trace written by `--profile`.
+pub fn analyze(trace: &str) -> anyhow::Result<String> {
+    let trace: Trace = serde_json::from_str(trace)
+        .map_err(|e| anyhow::anyhow!("Not a profile written by --profile: {e}"))?;
+    let spans = spans(trace.trace_events);
+    let (Some(start), Some(end)) = (
+        spans.iter().map(|s| s.start).min(),
+        spans.iter().map(|s| s.end).max(),
+    ) else {
+        return Ok("The profile has no spans\n".to_string());
+    };
+    let total = end - start;
+    let mut text = format!("Profile of {}, {} spans\n", seconds(total), spans.len());
+
+    // The wall time each kind of span took, counting concurrent spans once.
+    let mut by_name: BTreeMap<&str, Vec<(u64, u64)>> = BTreeMap::new();
+    for span in &spans {
+        by_name
+            .entry(&span.name)
+            .or_default()
+            .push((span.start, span.end));
+    }
+    let mut phases: Vec<(&str, u64, usize)> = by_name
+        .into_iter()
+        .map(|(name, mut intervals)| {
+            intervals.sort();
+            let count = intervals.len();
+            let mut wall = 0;
+            let mut covered = 0;
+            for (start, end) in intervals {
+                let start = start.max(covered);
+                if end > start {
+                    wall += end - start;
+                    covered = end;
+                }
+            }
+            (name, wall, count)
+        })
+        .collect();
+    phases.sort_by_key(|&(name, wall, _)| (std::cmp::Reverse(wall), name));
+    text.push_str("\n=== PHASE SUMMARY ===\n");
+    for (name, wall, count) in phases {
+        let percent = if total == 0 {
+            100.0
+        } else {
+            wall as f64 * 100.0 / total as f64
+        };
+        text.push_str(&format!(
+            "{:>12} {percent:>6.1}%  {name} ({count})\n",
+            seconds(wall)
+        ));
+    }
+
+    let roots: Vec<usize> = (0..spans.len()).filter(|&i| !spans[i].has_parent).collect();
+    let mut path = Vec::new();
+    critical_path(&spans, &roots, end, 0, &mut path);
+    let length: u64 = path
+        .iter()
+        .filter(|(depth, _)| *depth == 0)
+        .map(|&(_, i)| spans[i].duration())
+        .sum();
+    text.push_str(&format!("\n=== CRITICAL PATH ({}) ===\n", seconds(length)));
+    for (depth, i) in path {
+        text.push_str(&format!(
+            "{:>12}  {:indent$}{}\n",
+            seconds(spans[i].duration()),
+            "",
+            spans[i].describe(),
+            indent = depth * 2
+        ));
+    }
+
+    let mut actions: Vec<&Span> = spans.iter().filter(|s| s.children.is_empty()).collect();
+    actions.sort_by_key(|s| std::cmp::Reverse(s.duration()));
+    text.push_str("\n=== SLOWEST ACTIONS ===\n");
+    for action in actions.iter().take(SLOWEST_ACTIONS) {
+        text.push_str(&format!(
+            "{:>12}  {}\n",
+            seconds(action.duration()),
+            action.describe()
+        ));
+    }
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(
+        name: &str,
+        ph: &str,
+        ts: u64,
+        id: &str,
+        args: serde_json::Value,
+    ) -> serde_json::Value {
+        serde_json::json!({"name": name, "cat": "razel", "ph": ph, "ts": ts, "pid": 1, "tid": 1, "id": id, "args": args})
+    }
+
+    #[test]
+    fn test_analyze() {
+        let none = serde_json::json!({});
+        let trace = serde_json::json!({
+            "traceEvents": [
+                event("fetch repository", "b", 0, "0x1", serde_json::json!({"repo": "rules_go+"})),
+                event("download", "b", 100_000, "0x1", serde_json::json!({"url": "https://example.com/go.zip"})),
+                event("download", "e", 700_000, "0x1", none.clone()),
+                event("fetch repository", "e", 800_000, "0x1", none.clone()),
+                // Concurrent with the fetch, but finished before it, so not on the critical path.
+                event("load package", "b", 200_000, "0x2", serde_json::json!({"package": "@@//a"})),
+                event("load package", "e", 500_000, "0x2", none.clone()),
+                event("load package", "b", 800_000, "0x3", serde_json::json!({"package": "@@//b"})),
+                event("load bzl", "b", 800_000, "0x3", serde_json::json!({"label": "@@//b:defs.bzl"})),
+                event("load bzl", "e", 900_000, "0x3", none.clone()),
+                event("load package", "e", 1_000_000, "0x3", none.clone()),
+                // Never ended, e.g. because the invocation was interrupted.
+                event("load package", "b", 900_000, "0x4", serde_json::json!({"package": "@@//c"})),
+            ],
+            "displayTimeUnit": "ms",
+        });
+
+        assert_eq!(
+            analyze(&trace.to_string()).unwrap(),
+            "Profile of 1.000 s, 5 spans\n\
+             \n\
+             === PHASE SUMMARY ===\n\
+             \x20    0.800 s   80.0%  fetch repository (1)\n\
+             \x20    0.600 s   60.0%  download (1)\n\
+             \x20    0.500 s   50.0%  load package (2)\n\
+             \x20    0.100 s   10.0%  load bzl (1)\n\
+             \n\
+             === CRITICAL PATH (1.000 s) ===\n\
+             \x20    0.800 s  fetch repository repo=rules_go+\n\
+             \x20    0.600 s    download url=https://example.com/go.zip\n\
+             \x20    0.200 s  load package package=@@//b\n\
+             \x20    0.100 s    load bzl label=@@//b:defs.bzl\n\
+             \n\
+             === SLOWEST ACTIONS ===\n\
+             \x20    0.600 s  download url=https://example.com/go.zip\n\
+             \x20    0.300 s  load package package=@@//a\n\
+             \x20    0.100 s  load bzl label=@@//b:defs.bzl\n"
+        );
+
+        assert!(analyze("{}").is_err());
+        assert_eq!(
+            analyze(r#"{"traceEvents": []}"#).unwrap(),
+            "The profile has no spans\n"
+        );
+    }
+}
@@ -0,0 +1,709 @@
+//! `razel aquery`, which prints the actions that build the targets a query matches. Only `genrule` and `genquery`
+//! have actions so far, one each, as razel doesn't analyze other rules yet.
+//!
+//! The query may be wrapped in functions that filter the actions rather than the targets: `mnemonic(PATTERN, expr)`,
+//! `inputs(PATTERN, expr)` and `outputs(PATTERN, expr)` keep the actions whose mnemonic, or one of whose input or output
+//! paths, the regular expression PATTERN matches in full. Paths are relative to the execution root, e.g.
+//! `bazel-out/k8-fastbuild/bin/pkg/out.txt`.
+//!
+//! The command line of a genrule is its `cmd` with its variables expanded as Bazel expands them: `$@`, `$<`,
+//! `$(SRCS)`, `$(OUTS)`, `$(@D)` and `$(RULEDIR)`, functions like `$(location LABEL)` of the rule's prerequisites, and
+//! the "Make" variables of the configuration, see `Configuration::make_variables`.
+//!
+//! Actions are printed like `bazel aquery --output=text` prints them, or with `--output=starlark` as the value of the
+//! `--starlark:expr` expression for each, see `crate::starlark::expr`.
+//!
+//! For tools that analyze the action graph, such as its parallelism or fan-out, `--output=graph` prints the actions as
+//! a DOT graph, with an edge from each action to those that take one of its outputs as an input, and `--output=json`
+//! prints them with the artifacts between them. Each artifact has its size if it is on disk already: a source file, or
+//! an output that a previous build wrote to the execution root. razel has no content-addressed store of its own yet,
+//! so the sizes of outputs that were never built are unknown.
+
+use crate::bazel::Configuration;
+use crate::bazel::label::Label;
+use crate::bazel::rule::{AttrValue, Rule};
+use crate::error::AnalysisError;
+use crate::exit_code::{ExitCode, WithExitCode};
+use crate::query::{self, Edges, Expr, QueryContext, Universe};
+use crate::starlark::expr::OutputExpr;
+use crate::workspace::Workspace;
+use chumsky::span::Spanned;
+use regex::Regex;
+use serde::Serialize;
+use starlark::values::list::AllocList;
+use starlark::values::structs::AllocStruct;
+use starlark::values::{Heap, Value};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// The value of `aquery --output`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Output {
+    /// Each action's mnemonic, owner, configuration, inputs, outputs and command line.
+    #[default]
+    Text,
+    /// The value of the `--starlark:expr` expression for each action.
+    Starlark,
+    /// The actions as a DOT graph, with the artifacts that connect them as edges.
+    Graph,
+    /// The actions and the artifacts between them as JSON, with the size of each artifact on disk.
+    Json,
+}
+
+/// An action, with its paths relative to the execution root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Action {
+    mnemonic: &'static str,
+    description: String,
+    owner: Label<'static>,
+    inputs: Vec<String>,
+    outputs: Vec<String>,
+    /// The command the action runs, if it runs one.
+    arguments: Vec<String>,
+}
+
+/// Which part of an action a filter function matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Mnemonic,
+    Inputs,
+    Outputs,
+}
+
+#[derive(Debug)]
+struct Filter {
+    field: Field,
+    /// The function's pattern, anchored at both ends.
+    pattern: Regex,
+}
+
+impl Filter {
+    fn matches(&self, action: &Action) -> bool {
+        match self.field {
+            Field::Mnemonic => self.pattern.is_match(action.mnemonic),
+            Field::Inputs => action.inputs.iter().any(|i| self.pattern.is_match(i)),
+            Field::Outputs => action.outputs.iter().any(|o| self.pattern.is_match(o)),
+        }
+    }
+}
+
+/// Takes the filter functions off the outside of `ast`, returning them with the query of the targets inside.
+fn filters<'a, 'b>(
+    mut ast: &'b Spanned<Expr<'a>>,
+) -> Result<(Vec<Filter>, &'b Spanned<Expr<'a>>), String> {
+    let mut filters = Vec::new();
+    while let Expr::Function(name @ ("mnemonic" | "inputs" | "outputs"), args) = &ast.inner {
+        let field = match *name {
+            "mnemonic" => Field::Mnemonic,
+            "inputs" => Field::Inputs,
+            _ => Field::Outputs,
+        };
+        let [pattern, inner] = args.as_slice() else {
+            return Err(format!("{name}() takes a pattern and an expression"));
+        };
+        let Expr::String(pattern) = pattern.inner else {
+            return Err(format!("{name}() expects a regular expression"));
+        };
+        let pattern = Regex::new(&format!("^(?:{pattern})$"))
+            .map_err(|e| format!("Invalid regular expression {pattern:?}: {e}"))?;
+        filters.push(Filter { field, pattern });
+        ast = inner;
+    }
+    Ok((filters, ast))
+}
+
+/// The path of the file `name` in the package of `label`, under `root` in the execution root.
+fn path(root: &str, label: &Label<'_>, name: &str) -> String {
+    let workspace_root = label.workspace_root();
+    [root, &workspace_root, label.package(), name]
+        .into_iter()
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// The files the target `label`, as written in an attribute, stands for as an input of an action.
+async fn input_paths(
+    ctx: &QueryContext<'_>,
+    config: &Configuration,
+    label: &Label<'static>,
+) -> Result<Vec<String>, String> {
+    let package = ctx.package(label).await?;
+    let bin_dir = config.bin_dir();
+    Ok(match package.rules.get(label.name()) {
+        Some(rule) => output_names(rule)
+            .iter()
+            .map(|name| path(&bin_dir, label, name))
+            .collect(),
+        None if package.generating_rule(label.name()).is_some() => {
+            vec![path(&bin_dir, label, label.name())]
+        }
+        None => vec![path("", label, label.name())],
+    })
+}
+
+/// The names of the files `rule` generates in its package, for the rules that razel knows the actions of.
+fn output_names(rule: &Rule) -> Vec<String> {
+    match rule.rule_class.as_str() {
+        "genrule" => rule.outputs().map(String::from).collect(),
+        "genquery" => vec![rule.name.clone()],
+        _ => Vec::new(),
+    }
+}
+
+/// A piece of a genrule's `cmd`: text, or the name of a variable, `$x` or `$(name)`, that stands for something else.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Part<'a> {
+    Text(&'a str),
+    Variable(&'a str),
+}
+
+/// Splits `cmd` into text and variables.
+fn parts(cmd: &str) -> Result<Vec<Part<'_>>, String> {
+    let mut parts = Vec::new();
+    let mut rest = cmd;
+    while let Some(i) = rest.find('$') {
+        parts.push(Part::Text(&rest[..i]));
+        rest = &rest[i + 1..];
+        let (variable, after) = if let Some(inner) = rest.strip_prefix('(') {
+            let end = inner
+                .find(')')
+                .ok_or_else(|| format!("unterminated variable reference in {cmd:?}"))?;
+            (&inner[..end], &inner[end + 1..])
+        } else {
+            let len = rest
+                .chars()
+                .next()
+                .map(char::len_utf8)
+                .ok_or_else(|| format!("unterminated $ at the end of {cmd:?}"))?;
+            rest.split_at(len)
+        };
+        parts.push(Part::Variable(variable));
+        rest = after;
+    }
+    parts.push(Part::Text(rest));
+    Ok(parts)
+}
+
+/// The labels that the functions in `parts`, like `$(location //pkg:x)`, are given, as written.
+fn function_labels<'a>(parts: &[Part<'a>]) -> Vec<&'a str> {
+    parts
+        .iter()
+        .filter_map(|part| match part {
+            Part::Variable(variable) => variable.split_once(' ').map(|(_, label)| label.trim()),
+            Part::Text(_) => None,
+        })
+        .collect()
+}
+
+/// What the variables in a genrule's `cmd` stand for, with paths relative to the execution root, as Bazel expands them
+/// before running the command.
+struct GenruleVariables {
+    srcs: Vec<String>,
+    outs: Vec<String>,
+    /// The directory of the rule's package in `bin_dir`.
+    rule_dir: String,
+    bin_dir: String,
+    /// The files of the labels given to functions, like `$(location :tool)`, by the label as written.
+    locations: BTreeMap<String, Vec<String>>,
+    make_variables: BTreeMap<&'static str, String>,
+}
+
+impl GenruleVariables {
+    fn expand(&self, parts: &[Part<'_>]) -> Result<String, String> {
+        let mut expanded = String::new();
+        for part in parts {
+            match part {
+                Part::Text(text) => expanded.push_str(text),
+                Part::Variable(variable) => expanded.push_str(&self.value(variable)?),
+            }
+        }
+        Ok(expanded)
+    }
+
+    fn value(&self, variable: &str) -> Result<String, String> {
+        let single = |paths: &[String], what: &str| match paths {
+            [path] => Ok(path.clone()),
+            _ => Err(format!(
+                "$({variable}) needs exactly one {what}, but there are {}",
+                paths.len()
+            )),
+        };
+        if let Some((function, label)) = variable.split_once(' ') {
+            let paths = self.locations.get(label.trim()).ok_or_else(|| {
+                format!(
+                    "label {label:?} in $({function}) is not a declared prerequisite of this rule"
+                )
+            })?;
+            return match function {
+                "location" | "execpath" => single(paths, "file"),
+                "locations" | "execpaths" => Ok(paths.join(" ")),
+                "rootpath" => single(paths, "file").map(|path| self.rootpath(&path)),
+                "rootpaths" => Ok(paths
+                    .iter()
+                    .map(|path| self.rootpath(path))
+                    .collect::<Vec<_>>()
+                    .join(" ")),
+                _ => Err(format!("unknown function $({function})")),
+            };
+        }
+        match variable {
+            "$" => Ok("$".to_string()),
+            "@" => single(&self.outs, "output"),
+            "<" => single(&self.srcs, "source"),
+            "SRCS" => Ok(self.srcs.join(" ")),
+            "OUTS" => Ok(self.outs.join(" ")),
+            "RULEDIR" => Ok(self.rule_dir.clone()),
+            "@D" => Ok(match &self.outs[..] {
+                [out] => out.rsplit_once('/').map_or("", |(dir, _)| dir).to_string(),
+                _ => self.rule_dir.clone(),
+            }),
+            _ => self
+                .make_variables
+                .get(variable)
+                .cloned()
+                .ok_or_else(|| format!("$({variable}) not defined")),
+        }
+    }
+
+    /// `path`, relative to the execution root, relative to the runfiles of the main repository instead.
+    fn rootpath(&self, path: &str) -> String {
+        let path = path
+            .strip_prefix(&self.bin_dir)
+            .and_then(|path| path.strip_prefix('/'))
+            .unwrap_or(path);
+        match path.strip_prefix("external/") {
+            Some(path) => format!("../{path}"),
+            None => path.to_string(),
+        }
+    }
+}
+
+/// The actions of the target `label`.
+async fn actions(
+    ctx: &QueryContext<'_>,
+    config: &Configuration,
+    label: &Label<'static>,
+) -> Result<Vec<Action>, String> {
+    let package = ctx.package(label).await?;
+    let Some(rule) = package.rules.get(label.name()) else {
+        return Ok(Vec::new());
+    };
+    let bin_dir = config.bin_dir();
+    let outputs: Vec<_> = output_names(rule)
+        .iter()
+        .map(|name| path(&bin_dir, label, name))
+        .collect();
+    let action = match rule.rule_class.as_str() {
+        "genrule" => {
+            let mut inputs = Vec::new();
+            let mut srcs = Vec::new();
+            // The files of each prerequisite, which functions like `$(location)` may name.
+            let mut prerequisites = Vec::new();
+            for attr in ["srcs", "tools"] {
+                let written: Vec<_> = rule
+                    .attributes
+                    .get(attr)
+                    .map_or(&[][..], AttrValue::strings)
+                    .iter()
+                    .map(String::as_str)
+                    .collect();
+                for input in ctx.resolve_labels(label, &written).await? {
+                    let paths = input_paths(ctx, config, &input).await?;
+                    if attr == "srcs" {
+                        srcs.extend(paths.iter().cloned());
+                    }
+                    inputs.extend(paths.iter().cloned());
+                    prerequisites.push((input, paths));
+                }
+            }
+            let names: Vec<_> = rule.outputs().collect();
+            for (output, name) in ctx
+                .resolve_labels(label, &names)
+                .await?
+                .into_iter()
+                .zip(names)
+            {
+                prerequisites.push((output, vec![path(&bin_dir, label, name)]));
+            }
+
+            let cmd = match rule.attributes.get("cmd") {
+                Some(AttrValue::String(cmd)) => cmd.as_str(),
+                _ => "",
+            };
+            let failed = |reason: String| format!("genrule {label}: {reason}");
+            let parts = parts(cmd).map_err(failed)?;
+            let written = function_labels(&parts);
+            let mut locations = BTreeMap::new();
+            for (written, resolved) in written
+                .iter()
+                .zip(ctx.resolve_labels(label, &written).await?)
+            {
+                if let Some((_, paths)) = prerequisites.iter().find(|(label, _)| *label == resolved)
+                {
+                    locations.insert(written.to_string(), paths.clone());
+                }
+            }
+            let variables = GenruleVariables {
+                srcs,
+                outs: outputs.clone(),
+                rule_dir: path(&bin_dir, label, ""),
+                bin_dir: bin_dir.clone(),
+                locations,
+                make_variables: config.make_variables(),
+            };
+            let cmd = variables.expand(&parts).map_err(failed)?;
+            Action {
+                mnemonic: "Genrule",
+                description: format!("Executing genrule {label}"),
+                owner: label.clone(),
+                inputs,
+                outputs,
+                arguments: vec!["/bin/bash".to_string(), "-c".to_string(), cmd],
+            }
+        }
+        "genquery" => Action {
+            mnemonic: "GenQuery",
+            description: format!("Writing file {}", path("", label, label.name())),
+            owner: label.clone(),
+            inputs: Vec::new(),
+            outputs,
+            arguments: Vec::new(),
+        },
+        _ => return Ok(Vec::new()),
+    };
+    Ok(vec![action])
+}
+
+/// `action` in the format of `bazel aquery --output=text`.
+fn format_action(action: &Action, config: &Configuration) -> String {
+    let mut text = format!(
+        "action '{}'\n  Mnemonic: {}\n  Target: {}\n  Configuration: {}\n  Inputs: [{}]\n  Outputs: [{}]\n",
+        action.description,
+        action.mnemonic,
+        action.owner,
+        config.output_dir_mnemonic(),
+        action.inputs.join(", "),
+        action.outputs.join(", "),
+    );
+    if !action.arguments.is_empty() {
+        let arguments: Vec<_> = action
+            .arguments
+            .iter()
+            .map(|arg| shell_quote(arg))
+            .collect();
+        text += &format!("  Command Line: ({})\n", arguments.join(" "));
+    }
+    text + "\n"
+}
+
+/// The `action` that `--starlark:expr` sees: a struct with the `mnemonic`, the `owner` label, the `configuration`, and
+/// the lists of `inputs`, `outputs` and `arguments`.
+fn starlark_action<'v>(heap: Heap<'v>, action: &Action, config: &Configuration) -> Value<'v> {
+    let list = |strings: &[String]| heap.alloc(AllocList(strings.iter().map(String::as_str)));
+    heap.alloc(AllocStruct([
+        ("mnemonic", heap.alloc(action.mnemonic)),
+        ("owner", heap.alloc(action.owner.to_string())),
+        ("configuration", heap.alloc(config.output_dir_mnemonic())),
+        ("inputs", list(&action.inputs)),
+        ("outputs", list(&action.outputs)),
+        ("arguments", list(&action.arguments)),
+    ]))
+}
+
+/// The artifacts of `actions`, by path, with the indices of the action that produces each, if one of `actions` does,
+/// and of those that consume it.
+fn artifacts(actions: &[Action]) -> BTreeMap<&str, (Option<usize>, Vec<usize>)> {
+    let mut artifacts: BTreeMap<&str, (Option<usize>, Vec<usize>)> = BTreeMap::new();
+    for (i, action) in actions.iter().enumerate() {
+        for output in &action.outputs {
+            artifacts.entry(output.as_str()).or_default().0 = Some(i);
+        }
+        for input in &action.inputs {
+            artifacts.entry(input.as_str()).or_default().1.push(i);
+        }
+    }
+    artifacts
+}
+
+/// `actions` as a DOT graph, with an edge labeled with the artifact from the action that produces it to each action
+/// that consumes it.
+fn dot(actions: &[Action]) -> String {
+    let mut text = "digraph actions {\n  node [shape=box];\n".to_string();
+    for (i, action) in actions.iter().enumerate() {
+        let label = format!("{} {}", action.mnemonic, action.owner);
+        text += &format!("  a{i} [label={label:?}]\n");
+    }
+    for (path, (producer, consumers)) in artifacts(actions) {
+        let Some(producer) = producer else {
+            continue;
+        };
+        for consumer in consumers {
+            text += &format!("  a{producer} -> a{consumer} [label={path:?}]\n");
+        }
+    }
+    text + "}\n"
+}
+
+#[derive(Debug, Serialize)]
+struct JsonAction<'a> {
+    id: usize,
+    mnemonic: &'a str,
+    owner: String,
+    inputs: &'a [String],
+    outputs: &'a [String],
+    arguments: &'a [String],
+}
+
+#[derive(Debug, Serialize)]
+struct JsonArtifact<'a> {
+    path: &'a str,
+    /// The size of the artifact in bytes, if it is on disk.
+    size: Option<u64>,
+    /// The id of the action that produces the artifact, if it isn't a source file or produced by an action that
+    /// isn't printed.
+    producer: Option<usize>,
+    consumers: Vec<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct JsonGraph<'a> {
+    actions: Vec<JsonAction<'a>>,
+    artifacts: Vec<JsonArtifact<'a>>,
+}
+
+/// Where the artifact at `path`, relative to the execution root, is on disk: outputs are in the execution root, and
+/// source files in their repository.
+fn artifact_file(workspace: &Workspace, path: &str) -> PathBuf {
+    if path.starts_with("bazel-out/") {
+        workspace.output_base().join("execroot/_main").join(path)
+    } else if path.starts_with("external/") {
+        workspace.output_base().join(path)
+    } else {
+        workspace.path().join(path)
+    }
+}
+
+/// `actions` and the artifacts between them as JSON.
+async fn json(workspace: &Workspace, actions: &[Action]) -> String {
+    let mut graph = JsonGraph {
+        actions: actions
+            .iter()
+            .enumerate()
+            .map(|(id, action)| JsonAction {
+                id,
+                mnemonic: action.mnemonic,
+                owner: action.owner.to_string(),
+                inputs: &action.inputs,
+                outputs: &action.outputs,
+                arguments: &action.arguments,
+            })
+            .collect(),
+        artifacts: Vec::new(),
+    };
+    for (path, (producer, consumers)) in artifacts(actions) {
+        let size = tokio::fs::metadata(artifact_file(workspace, path))
+            .await
+            .ok()
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| metadata.len());
+        graph.artifacts.push(JsonArtifact {
+            path,
+            size,
+            producer,
+            consumers,
+        });
+    }
+    serde_json::to_string_pretty(&graph).expect("actions are serializable") + "\n"
+}
+
+/// `arg` quoted for a POSIX shell, if it needs to be.
+fn shell_quote(arg: &str) -> String {
+    if !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "/._-=:@+".contains(c))
+    {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Prints the actions of the targets `query` matches, that its filter functions keep, as `output`. `starlark_expr` is
+/// the expression to print with `Output::Starlark`.
+pub async fn aquery<W>(
+    out: &mut W,
+    workspace: Arc<Workspace>,
+    config: &Configuration,
+    query: &str,
+    universe: Universe,
+    output: Output,
+    starlark_expr: &str,
+) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let failed = |reason: String| {
+        Err(AnalysisError::QueryFailed { reason }).exit_code(ExitCode::AnalysisFailure)
+    };
+    let expr = OutputExpr::parse(starlark_expr, config.eval_limits)?;
+    let ast = query::parse(query)?;
+    let (filters, targets) = match filters(&ast) {
+        Ok(filters) => filters,
+        Err(reason) => return failed(reason),
+    };
+    let targets =
+        query::evaluate_ast(workspace.clone(), targets, universe, Edges::default()).await?;
+    let ctx = QueryContext::new(workspace.clone(), Vec::new(), Edges::default());
+    let mut all = Vec::new();
+    for label in targets {
+        let actions = match actions(&ctx, config, &label).await {
+            Ok(actions) => actions,
+            Err(reason) => return failed(reason),
+        };
+        for action in actions {
+            if !filters.iter().all(|filter| filter.matches(&action)) {
+                continue;
+            }
+            let text = match output {
+                Output::Text => format_action(&action, config),
+                Output::Starlark => {
+                    let subject = format!("the {} action of {}", action.mnemonic, action.owner);
+                    match expr.format(&subject, "action", |heap| {
+                        starlark_action(heap, &action, config)
+                    }) {
+                        Ok(line) => line + "\n",
+                        Err(reason) => return failed(reason),
+                    }
+                }
+                // The graph is printed once all the actions are known.
+                Output::Graph | Output::Json => {
+                    all.push(action);
+                    continue;
+                }
+            };
+            out.write_all(text.as_bytes()).await?;
+        }
+    }
+    match output {
+        Output::Graph => out.write_all(dot(&all).as_bytes()).await?,
+        Output::Json => {
+            out.write_all(json(&workspace, &all).await.as_bytes())
+                .await?
+        }
+        Output::Text | Output::Starlark => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bazel::label::{MAIN_REPO, Repo};
+
+    fn action() -> Action {
+        Action {
+            mnemonic: "Genrule",
+            description: "Executing genrule @@//pkg:gen".to_string(),
+            owner: Label::new(Repo::Canonical(MAIN_REPO), "pkg", "gen"),
+            inputs: vec!["pkg/in.txt".to_string()],
+            outputs: vec!["bazel-out/k8-fastbuild/bin/pkg/out.txt".to_string()],
+            arguments: Vec::new(),
+        }
+    }
+
+    fn filter(query: &str) -> Result<Vec<Filter>, String> {
+        let ast = query::parse(query).unwrap();
+        filters(&ast).map(|(filters, _)| filters)
+    }
+
+    #[test]
+    fn test_filters() {
+        let matches = |query: &str| filter(query).unwrap().iter().all(|f| f.matches(&action()));
+        assert!(matches("//pkg:gen"));
+        assert!(matches("mnemonic(Genrule, //pkg:gen)"));
+        assert!(!matches("mnemonic(Gen, //pkg:gen)"));
+        assert!(matches("mnemonic('Gen.*', //pkg:gen)"));
+        assert!(matches("inputs('.*\\.txt', //pkg:gen)"));
+        assert!(matches(
+            "outputs('.*/out.txt', inputs(pkg/in.txt, //pkg:gen))"
+        ));
+        assert!(!matches("outputs('.*/in.txt', //pkg:gen)"));
+        assert!(filter("mnemonic(Genrule)").is_err());
+        assert!(filter("mnemonic('(', //pkg:gen)").is_err());
+    }
+
+    #[test]
+    fn test_dot() {
+        let producer = action();
+        let consumer = Action {
+            description: "Executing genrule @@//pkg:use".to_string(),
+            owner: Label::new(Repo::Canonical(MAIN_REPO), "pkg", "use"),
+            inputs: producer.outputs.clone(),
+            outputs: vec!["bazel-out/k8-fastbuild/bin/pkg/use.txt".to_string()],
+            ..action()
+        };
+        assert_eq!(
+            dot(&[producer, consumer]),
+            "digraph actions {\n  node [shape=box];\n  a0 [label=\"Genrule @@//pkg:gen\"]\n  \
+             a1 [label=\"Genrule @@//pkg:use\"]\n  \
+             a0 -> a1 [label=\"bazel-out/k8-fastbuild/bin/pkg/out.txt\"]\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_genrule_variables() {
+        let variables = GenruleVariables {
+            srcs: vec!["pkg/in.txt".to_string()],
+            outs: vec!["bazel-out/k8-fastbuild/bin/pkg/out.txt".to_string()],
+            rule_dir: "bazel-out/k8-fastbuild/bin/pkg".to_string(),
+            bin_dir: "bazel-out/k8-fastbuild/bin".to_string(),
+            locations: BTreeMap::from([
+                (
+                    ":tool".to_string(),
+                    vec!["bazel-out/k8-fastbuild/bin/pkg/tool".to_string()],
+                ),
+                (
+                    "@dep//:files".to_string(),
+                    vec!["external/dep+/a".to_string(), "external/dep+/b".to_string()],
+                ),
+            ]),
+            make_variables: BTreeMap::from([("COMPILATION_MODE", "fastbuild".to_string())]),
+        };
+        let expand = |cmd: &str| parts(cmd).and_then(|parts| variables.expand(&parts));
+        assert_eq!(
+            expand("$(location :tool) $< > $@").unwrap(),
+            "bazel-out/k8-fastbuild/bin/pkg/tool pkg/in.txt > bazel-out/k8-fastbuild/bin/pkg/out.txt"
+        );
+        assert_eq!(
+            expand("cat $(SRCS) > $(OUTS) # $(@D) $(RULEDIR)").unwrap(),
+            "cat pkg/in.txt > bazel-out/k8-fastbuild/bin/pkg/out.txt \
+             # bazel-out/k8-fastbuild/bin/pkg bazel-out/k8-fastbuild/bin/pkg"
+        );
+        assert_eq!(
+            expand("$(rootpath :tool) $(rootpaths @dep//:files) $(COMPILATION_MODE) $$HOME")
+                .unwrap(),
+            "pkg/tool ../dep+/a ../dep+/b fastbuild $HOME"
+        );
+        assert_eq!(
+            expand("$(execpaths @dep//:files)").unwrap(),
+            "external/dep+/a external/dep+/b"
+        );
+        assert!(expand("$(location @dep//:files)").is_err());
+        assert!(expand("$(location :other)").is_err());
+        assert!(expand("$(NOPE)").is_err());
+        assert!(expand("echo $HOME").is_err());
+        assert!(expand("$(SRCS").is_err());
+        assert!(expand("trailing $").is_err());
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("/bin/bash"), "/bin/bash");
+        assert_eq!(shell_quote("echo hi > $@"), "'echo hi > $@'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+    }
+}
@@ -0,0 +1,648 @@
+use crate::bazel::repo::LocalFileStore;
+use std::io::Read;
+use std::path::Path;
+
+/// Extracts the zip archive at `path` into `dest`, preserving unix permissions, and returns a store for the extracted
+/// tree. Entries outside `strip_prefix` are skipped, and the prefix is removed from the remaining paths.
+pub async fn extract_zip(
+    path: impl AsRef<Path>,
+    strip_prefix: &str,
+    dest: impl AsRef<Path>,
+) -> anyhow::Result<LocalFileStore> {
+    let data = tokio::fs::read(path).await?;
+    let dest = dest.as_ref().to_path_buf();
+    let strip_prefix = strip_prefix.trim_matches('/').to_string();
+    let root = dest.clone();
+    tokio::task::spawn_blocking(move || unzip(&data, &strip_prefix, &root)).await??;
+    Ok(LocalFileStore::new(dest))
+}
+
+fn unzip(data: &[u8], strip_prefix: &str, root: &Path) -> anyhow::Result<()> {
+    let mut zip = zip::ZipArchive::new(std::io::Cursor::new(data))?;
+    std::fs::create_dir_all(root)?;
+    for index in 0..zip.len() {
+        let mut entry = zip.by_index(index)?;
+        let Some(name) = entry.enclosed_name() else {
+            return Err(crate::error::FetchError::UnsafeArchivePath {
+                entry: index.to_string(),
+            }
+            .into());
+        };
+        let Some(name) = name.to_str() else {
+            anyhow::bail!("Archive entry {name:?} is not valid unicode");
+        };
+        let name = name.trim_end_matches('/');
+
+        let path = if strip_prefix.is_empty() {
+            name
+        } else if let Some(rest) = name.strip_prefix(strip_prefix) {
+            // Skip the prefix directory itself, and siblings that merely share its name as a prefix.
+            match rest.strip_prefix('/') {
+                Some(rest) => rest,
+                None => continue,
+            }
+        } else {
+            continue;
+        };
+
+        // Archives need not contain explicit directory entries.
+        let target = root.join(path);
+        if entry.is_dir() {
+            std::fs::create_dir_all(&target)?;
+            continue;
+        }
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut out = std::fs::File::create(&target)?;
+        std::io::copy(&mut entry, &mut out)?;
+
+        #[cfg(unix)]
+        if let Some(mode) = entry.unix_mode() {
+            use std::os::unix::fs::PermissionsExt;
+            out.set_permissions(std::fs::Permissions::from_mode(mode & 0o777))?;
+        }
+    }
+    Ok(())
+}
+
+/// The kinds of archive that can be extracted, told apart by the extension of their URL as in Bazel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveType {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl ArchiveType {
+    /// The type named by `name`, an extension without its leading dot such as `tar.gz`, or `None` if it isn't one
+    /// razel extracts.
+    pub fn from_type(name: &str) -> Option<Self> {
+        match name {
+            "zip" | "jar" => Some(Self::Zip),
+            "tar" => Some(Self::Tar),
+            "tar.gz" | "tgz" => Some(Self::TarGz),
+            _ => None,
+        }
+    }
+
+    /// The type of the archive at `url` by its extension, ignoring any query or fragment.
+    pub fn from_url(url: &str) -> Option<Self> {
+        let path = url
+            .split(['?', '#'])
+            .next()
+            .unwrap_or(url)
+            .to_ascii_lowercase();
+        ["tar.gz", "tgz", "tar", "zip", "jar"]
+            .into_iter()
+            .find(|extension| path.ends_with(&format!(".{extension}")))
+            .and_then(Self::from_type)
+    }
+
+    /// Extracts the archive at `path` into `dest`, see `extract_zip` and `extract_tar`.
+    pub async fn extract(
+        self,
+        path: impl AsRef<Path>,
+        strip_prefix: &str,
+        dest: impl AsRef<Path>,
+    ) -> anyhow::Result<LocalFileStore> {
+        match self {
+            Self::Zip => extract_zip(path, strip_prefix, dest).await,
+            Self::Tar | Self::TarGz => {
+                extract_tar(path, self == Self::TarGz, strip_prefix, dest).await
+            }
+        }
+    }
+}
+
+/// Extracts the tar archive at `path`, gzipped if `gzip`, into `dest` as `extract_zip` does zip archives,
+/// and returns a store for the extracted tree. Entries outside `strip_prefix` are skipped, and the prefix is removed
+/// from the remaining paths.
+///
+/// Symlinks and hard links must point inside `dest`, and no entry is written through a symlink to outside it. Devices
+/// and FIFOs are skipped.
+pub async fn extract_tar(
+    path: impl AsRef<Path>,
+    gzip: bool,
+    strip_prefix: &str,
+    dest: impl AsRef<Path>,
+) -> anyhow::Result<LocalFileStore> {
+    let path = path.as_ref().to_path_buf();
+    let dest = dest.as_ref().to_path_buf();
+    let strip_prefix = strip_prefix.trim_matches('/').to_string();
+    let root = dest.clone();
+    tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+        let file = std::io::BufReader::new(std::fs::File::open(&path)?);
+        if gzip {
+            untar(
+                flate2::read::MultiGzDecoder::new(file),
+                &strip_prefix,
+                &root,
+            )
+        } else {
+            untar(file, &strip_prefix, &root)
+        }
+    })
+    .await??;
+    Ok(LocalFileStore::new(dest))
+}
+
+/// `name` with `strip_prefix` removed, or `None` if it's outside it. Fails if the path would leave the repository.
+fn strip_entry(name: &str, strip_prefix: &str) -> anyhow::Result<Option<String>> {
+    let name = name.trim_start_matches("./").trim_end_matches('/');
+    if !Path::new(name).components().all(|c| {
+        matches!(
+            c,
+            std::path::Component::Normal(_) | std::path::Component::CurDir
+        )
+    }) {
+        return Err(crate::error::FetchError::UnsafeArchivePath {
+            entry: name.to_string(),
+        }
+        .into());
+    }
+    if strip_prefix.is_empty() {
+        return Ok(Some(name.to_string()));
+    }
+    // Skip siblings that merely share the prefix's name as a prefix.
+    Ok(match name.strip_prefix(strip_prefix) {
+        Some("") => Some(String::new()),
+        Some(rest) => rest.strip_prefix('/').map(String::from),
+        None => None,
+    })
+}
+
+/// The path `link`, a symlink target relative to the directory `dir` of the symlink, relative to the repository root,
+/// or `None` if it points outside of it. Only looks at the path; see `check_inside` for the symlinks along it.
+fn resolve_link(dir: &Path, link: &str) -> Option<std::path::PathBuf> {
+    let mut resolved = dir.to_path_buf();
+    for component in Path::new(link).components() {
+        match component {
+            std::path::Component::Normal(name) => resolved.push(name),
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                if !resolved.pop() {
+                    return None;
+                }
+            }
+            std::path::Component::RootDir | std::path::Component::Prefix(_) => return None,
+        }
+    }
+    Some(resolved)
+}
+
+/// Fails unless `path`, or as much of it as exists, is inside `root` once the symlinks extracted so far are followed,
+/// so that nothing is created or read through a chain of symlinks leading outside.
+fn check_inside(root: &Path, path: &Path, entry: &str) -> anyhow::Result<()> {
+    let mut existing = path;
+    while !existing.exists() {
+        match existing.parent() {
+            Some(parent) => existing = parent,
+            None => break,
+        }
+    }
+    if !existing.canonicalize()?.starts_with(root) {
+        return Err(crate::error::FetchError::UnsafeArchivePath {
+            entry: entry.to_string(),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Creates the directory `dir` under `root` for the archive entry `entry`.
+fn create_dir_inside(root: &Path, dir: &Path, entry: &str) -> anyhow::Result<()> {
+    check_inside(root, dir, entry)?;
+    std::fs::create_dir_all(dir)?;
+    Ok(())
+}
+
+/// Creates the symlink `target` pointing at `link`.
+#[cfg(unix)]
+fn symlink(link: &str, target: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(link, target)
+}
+
+#[cfg(not(unix))]
+fn symlink(link: &str, target: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!(
+            "Can't create the symlink {} to {link} on this platform",
+            target.display()
+        ),
+    ))
+}
+
+fn untar(reader: impl Read, strip_prefix: &str, root: &Path) -> anyhow::Result<()> {
+    std::fs::create_dir_all(root)?;
+    let root = &root.canonicalize()?;
+    let mut symlinks = Vec::new();
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let kind = entry.header().entry_type();
+        // Global pax headers, such as the commit GitHub archives record, don't affect the files.
+        if kind.is_pax_global_extensions() {
+            continue;
+        }
+        let name = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+        let Some(path) = strip_entry(&name, strip_prefix)? else {
+            continue;
+        };
+        let target = root.join(&path);
+        if kind.is_dir() {
+            create_dir_inside(root, &target, &name)?;
+            continue;
+        }
+        if path.is_empty() {
+            // Only the directory `strip_prefix` names can stand for the root.
+            return Err(crate::error::FetchError::UnsafeArchivePath { entry: name }.into());
+        }
+        let link = entry
+            .link_name_bytes()
+            .map(|link| String::from_utf8_lossy(&link).into_owned())
+            .unwrap_or_default();
+        let unsafe_link = || crate::error::FetchError::UnsafeArchiveLink {
+            entry: name.clone(),
+            target: link.clone(),
+        };
+        if let Some(parent) = target.parent() {
+            create_dir_inside(root, parent, &name)?;
+        }
+        // An entry replaces an earlier one of the same name, rather than being written through it if it's a symlink.
+        if target.symlink_metadata().is_ok_and(|m| !m.is_dir()) {
+            std::fs::remove_file(&target)?;
+        }
+        if kind.is_file() || kind.is_contiguous() {
+            let mut out = std::fs::File::create(&target)?;
+            std::io::copy(&mut entry, &mut out)?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                let mode = entry.header().mode()? & 0o777;
+                out.set_permissions(std::fs::Permissions::from_mode(mode))?;
+            }
+        } else if kind.is_symlink() {
+            let dir = Path::new(&path).parent().unwrap_or(Path::new(""));
+            if resolve_link(dir, &link).is_none() {
+                return Err(unsafe_link().into());
+            }
+            symlink(&link, &target)?;
+            symlinks.push((target, name.clone(), link.clone()));
+        } else if kind.is_hard_link() {
+            // Hard links name another entry of the archive, which has been extracted already.
+            let Some(source) = strip_entry(&link, strip_prefix)?.filter(|s| !s.is_empty()) else {
+                return Err(unsafe_link().into());
+            };
+            let source = root.join(source);
+            check_inside(root, &source, &name)?;
+            std::fs::copy(source, &target)?;
+        }
+    }
+    // A symlink may only point outside through symlinks extracted after it.
+    for (symlink, entry, link) in symlinks {
+        if symlink
+            .canonicalize()
+            .is_ok_and(|resolved| !resolved.starts_with(root))
+        {
+            return Err(crate::error::FetchError::UnsafeArchiveLink {
+                entry,
+                target: link,
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+
+    fn make_zip(entries: &[(&str, &[u8], u32)]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        for (name, content, mode) in entries {
+            writer
+                .start_file(*name, SimpleFileOptions::default().unix_permissions(*mode))
+                .unwrap();
+            writer.write_all(content).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    /// A tar archive of `(name, type, content, mode)` entries, with GNU headers as GNU tar writes them. The content of a
+    /// symlink or hard link is its target.
+    fn make_tar(entries: &[(&str, u8, &[u8], u32)]) -> Vec<u8> {
+        let mut tar = Vec::new();
+        for &(name, kind, content, mode) in entries {
+            let mut header = [0u8; 512];
+            header[..name.len()].copy_from_slice(name.as_bytes());
+            header[100..108].copy_from_slice(format!("{mode:07o}\0").as_bytes());
+            let content = if kind == b'1' || kind == b'2' {
+                header[157..157 + content.len()].copy_from_slice(content);
+                &[]
+            } else {
+                content
+            };
+            header[124..136].copy_from_slice(format!("{:011o}\0", content.len()).as_bytes());
+            header[156] = kind;
+            header[257..265].copy_from_slice(b"ustar  \0");
+            header[148..156].fill(b' ');
+            let sum: u32 = header.iter().map(|&b| u32::from(b)).sum();
+            header[148..156].copy_from_slice(format!("{sum:06o}\0 ").as_bytes());
+            tar.extend_from_slice(&header);
+            tar.extend_from_slice(content);
+            tar.resize(tar.len().next_multiple_of(512), 0);
+        }
+        tar.resize(tar.len() + 1024, 0);
+        tar
+    }
+
+    #[tokio::test]
+    async fn test_extract_zip() {
+        let tmp = assert_fs::TempDir::new().unwrap();
+        let archive = tmp.path().join("rules.zip");
+        std::fs::write(
+            &archive,
+            make_zip(&[
+                ("rules-1.0/MODULE.bazel", b"module(name = \"rules\")", 0o644),
+                ("rules-1.0/pkg/sub/defs.bzl", b"x = 1", 0o644),
+                ("rules-1.0/bin/tool", b"#!/bin/sh", 0o755),
+                ("rules-1.0.txt", b"", 0o644),
+                ("other/ignored", b"", 0o644),
+            ]),
+        )
+        .unwrap();
+
+        let dest = tmp.path().join("out");
+        extract_zip(&archive, "rules-1.0", &dest).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dest.join("MODULE.bazel")).unwrap(),
+            "module(name = \"rules\")"
+        );
+        // Entries are deflated, so this is the decompressed content.
+        assert_eq!(
+            std::fs::read_to_string(dest.join("pkg/sub/defs.bzl")).unwrap(),
+            "x = 1"
+        );
+        let mut names: Vec<_> = std::fs::read_dir(&dest)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["MODULE.bazel", "bin", "pkg"]);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(dest.join("bin/tool"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o755);
+        }
+    }
+
+    #[test]
+    fn test_archive_type() {
+        assert_eq!(
+            ArchiveType::from_url("https://x/a-1.0.tar.gz?raw=1"),
+            Some(ArchiveType::TarGz)
+        );
+        assert_eq!(
+            ArchiveType::from_url("file:///a.TGZ"),
+            Some(ArchiveType::TarGz)
+        );
+        assert_eq!(
+            ArchiveType::from_url("https://x/a.tar"),
+            Some(ArchiveType::Tar)
+        );
+        assert_eq!(
+            ArchiveType::from_url("https://x/a.zip"),
+            Some(ArchiveType::Zip)
+        );
+        assert_eq!(ArchiveType::from_url("https://x/a.tar.xz"), None);
+        assert_eq!(ArchiveType::from_type("tgz"), Some(ArchiveType::TarGz));
+    }
+
+    #[tokio::test]
+    async fn test_extract_tar() {
+        let tmp = assert_fs::TempDir::new().unwrap();
+        let long_name = format!("rules-1.0/{}/defs.bzl", "d".repeat(120));
+        let tar = make_tar(&[
+            (
+                "pax_global_header",
+                b'g',
+                b"52 comment=0123456789012345678901234567890123456789\n",
+                0o666,
+            ),
+            ("rules-1.0/", b'5', b"", 0o755),
+            (
+                "rules-1.0/MODULE.bazel",
+                b'0',
+                b"module(name = \"rules\")",
+                0o644,
+            ),
+            // Setuid and setgid bits are dropped.
+            ("rules-1.0/bin/tool", b'0', b"#!/bin/sh", 0o6755),
+            // A GNU long name header names the entry after it.
+            (
+                "././@LongLink",
+                b'L',
+                format!("{long_name}\0").as_bytes(),
+                0o644,
+            ),
+            ("rules-1.0/truncated", b'0', b"x = 1", 0o644),
+            ("other/ignored", b'0', b"", 0o644),
+        ]);
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(&tar).unwrap();
+        let archive = tmp.path().join("rules.tar.gz");
+        std::fs::write(&archive, gz.finish().unwrap()).unwrap();
+
+        let dest = tmp.path().join("out");
+        extract_tar(&archive, true, "rules-1.0", &dest)
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dest.join("MODULE.bazel")).unwrap(),
+            "module(name = \"rules\")"
+        );
+        let long = dest.join(long_name.strip_prefix("rules-1.0/").unwrap());
+        assert_eq!(std::fs::read_to_string(long).unwrap(), "x = 1");
+        assert!(!dest.join("truncated").exists());
+        assert!(!dest.join("other").exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(dest.join("bin/tool"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o7777, 0o755);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_extract_tar_unsafe_path() {
+        let tmp = assert_fs::TempDir::new().unwrap();
+        let archive = tmp.path().join("evil.tar");
+        std::fs::write(&archive, make_tar(&[("../evil", b'0', b"", 0o644)])).unwrap();
+
+        let err = extract_tar(&archive, false, "", tmp.path().join("out"))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("unsafe path"), "{err:#}");
+        assert!(!tmp.path().join("evil").exists());
+    }
+
+    #[tokio::test]
+    async fn test_extract_tar_symlink() {
+        let tmp = assert_fs::TempDir::new().unwrap();
+        let archive = tmp.path().join("evil.tar");
+        // A file written through the symlink would land outside the repository.
+        std::fs::write(
+            &archive,
+            make_tar(&[
+                (
+                    "escape",
+                    b'2',
+                    tmp.path().to_str().unwrap().as_bytes(),
+                    0o777,
+                ),
+                ("escape/evil", b'0', b"", 0o644),
+            ]),
+        )
+        .unwrap();
+
+        let err = extract_tar(&archive, false, "", tmp.path().join("out"))
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("outside of the repository"),
+            "{err:#}"
+        );
+        assert!(!tmp.path().join("evil").exists());
+        assert!(!tmp.path().join("out/escape").exists());
+    }
+
+    async fn extract(
+        tmp: &Path,
+        strip_prefix: &str,
+        entries: &[(&str, u8, &[u8], u32)],
+    ) -> anyhow::Result<()> {
+        let archive = tmp.join("archive.tar");
+        std::fs::write(&archive, make_tar(entries)).unwrap();
+        extract_tar(&archive, false, strip_prefix, tmp.join("out"))
+            .await
+            .map(|_| ())
+    }
+
+    #[tokio::test]
+    async fn test_extract_tar_links() {
+        let tmp = assert_fs::TempDir::new().unwrap();
+        extract(
+            tmp.path(),
+            "rules-1.0",
+            &[
+                ("rules-1.0/lib/real.txt", b'0', b"real", 0o644),
+                ("rules-1.0/lib/link.txt", b'2', b"real.txt", 0o777),
+                ("rules-1.0/bin/up.txt", b'2', b"../lib/real.txt", 0o777),
+                ("rules-1.0/current", b'2', b"lib", 0o777),
+                ("rules-1.0/current/through.txt", b'0', b"through", 0o644),
+                ("rules-1.0/copy.txt", b'1', b"rules-1.0/lib/real.txt", 0o644),
+            ],
+        )
+        .await
+        .unwrap();
+        let out = tmp.path().join("out");
+        assert_eq!(
+            std::fs::read_link(out.join("lib/link.txt")).unwrap(),
+            Path::new("real.txt")
+        );
+        assert_eq!(
+            std::fs::read_to_string(out.join("bin/up.txt")).unwrap(),
+            "real"
+        );
+        assert_eq!(
+            std::fs::read_to_string(out.join("lib/through.txt")).unwrap(),
+            "through"
+        );
+        assert_eq!(
+            std::fs::read_to_string(out.join("copy.txt")).unwrap(),
+            "real"
+        );
+    }
+
+    /// Extracts `entries` next to a file outside of the repository, returning the error.
+    async fn outside(entries: &[(&str, u8, &[u8], u32)], strip_prefix: &str) -> String {
+        let tmp = assert_fs::TempDir::new().unwrap();
+        std::fs::write(tmp.path().join("secret"), "secret").unwrap();
+        let err = extract(tmp.path(), strip_prefix, entries)
+            .await
+            .unwrap_err();
+        assert!(!tmp.path().join("evil").exists());
+        format!("{err:#}")
+    }
+
+    #[tokio::test]
+    async fn test_extract_tar_unsafe_links() {
+        let err = outside(&[("up", b'2', b"dir/../../secret", 0o777)], "").await;
+        assert!(
+            err.contains("Archive entry up links to dir/../../secret, outside of the repository"),
+            "{err}"
+        );
+
+        // Each symlink points inside on its own, but not once they are both followed.
+        let err = outside(
+            &[
+                ("here", b'2', b".", 0o777),
+                ("parent", b'2', b"here/..", 0o777),
+            ],
+            "",
+        )
+        .await;
+        assert!(
+            err.contains("Archive entry parent links to here/.., outside of the repository"),
+            "{err}"
+        );
+        let err = outside(
+            &[
+                ("here", b'2', b".", 0o777),
+                ("parent", b'2', b"here/..", 0o777),
+                ("parent/evil", b'0', b"", 0o644),
+            ],
+            "",
+        )
+        .await;
+        assert!(
+            err.contains("Archive entry parent/evil has an unsafe path"),
+            "{err}"
+        );
+
+        let err = outside(
+            &[
+                ("other/secret.txt", b'0', b"secret", 0o644),
+                ("rules/copy.txt", b'1', b"other/secret.txt", 0o644),
+            ],
+            "rules",
+        )
+        .await;
+        assert!(
+            err.contains(
+                "Archive entry rules/copy.txt links to other/secret.txt, outside of the repository"
+            ),
+            "{err}"
+        );
+
+        // Only a directory can stand for the repository.
+        let err = outside(&[("rules", b'0', b"", 0o644)], "rules").await;
+        assert!(
+            err.contains("Archive entry rules has an unsafe path"),
+            "{err}"
+        );
+    }
+}
@@ -1,5 +1,8 @@
+use crate::bazel::mvs::compare_versions;
 use crate::bazel::package::{BoxFileStore, File, FileStore};
+use crate::error::{ResolutionError, StarlarkError};
 use crate::starlark::globals::module::{ModuleBuilder, ModuleExtra, RepoExtra};
+use crate::starlark::limits::EvalLimits;
 use allocative::Allocative;
 use starlark::environment::Module as StarlarkModule;
 use starlark::{
@@ -8,6 +11,7 @@ use starlark::{
     eval::Evaluator,
     syntax::{AstModule, Dialect},
 };
+use std::collections::BTreeMap;
 use std::{path::Path, sync::LazyLock};
 use tokio::io::AsyncReadExt;
 
@@ -17,6 +21,185 @@ pub struct BazelDep {
     pub version: String,
     pub repo_name: String,
     pub dev_dependency: bool,
+    /// The highest `compatibility_level` of the module this module works with, if higher than that of `version`.
+    pub max_compatibility_level: Option<u32>,
+}
+
+/// A module's use of a module extension, and the repositories it imports from it with `use_repo`.
+/// https://bazel.build/rules/lib/globals/module#use_extension
+#[derive(Debug, Clone, Allocative)]
+pub struct ExtensionUsage {
+    /// The label of the .bzl file defining the extension, relative to the module's repository.
+    pub extension_bzl_file: String,
+    pub extension_name: String,
+    /// The repositories imported, as the apparent name they are visible by and the name the extension gives them.
+    pub imports: Vec<(String, String)>,
+}
+
+/// The version of Bazel razel is compatible with, checked against the `bazel_compatibility` of each module.
+pub const BAZEL_COMPATIBILITY_VERSION: &str = "8.0.0";
+
+/// Splits a `bazel_compatibility` requirement into its operator, `>=`, `<=`, `>`, `<` or `-` to exclude a version,
+/// and its `<major>.<minor>.<patch>` version, or returns `None` if it isn't one.
+pub fn parse_bazel_compatibility(requirement: &str) -> Option<(&str, &str)> {
+    let split = [">=", "<=", ">", "<", "-"]
+        .into_iter()
+        .find_map(|op| Some((op, requirement.strip_prefix(op)?)));
+    let (op, version) = split?;
+    let parts: Vec<_> = version.split('.').collect();
+    let numeric = parts
+        .iter()
+        .all(|p| !p.is_empty() && p.bytes().all(|b| b.is_ascii_digit()));
+    (parts.len() == 3 && numeric).then_some((op, version))
+}
+
+/// The first of a module's `bazel_compatibility` requirements that Bazel `version` doesn't meet, if any.
+pub fn unmet_bazel_compatibility<'r>(requirements: &'r [String], version: &str) -> Option<&'r str> {
+    requirements
+        .iter()
+        .find(|requirement| {
+            let Some((op, required)) = parse_bazel_compatibility(requirement) else {
+                return true;
+            };
+            let ordering = compare_versions(version, required);
+            !match op {
+                ">=" => ordering.is_ge(),
+                "<=" => ordering.is_le(),
+                ">" => ordering.is_gt(),
+                "<" => ordering.is_lt(),
+                _ => ordering.is_ne(),
+            }
+        })
+        .map(String::as_str)
+}
+
+/// Patches an override applies to the files of a module once they're fetched.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Allocative)]
+pub struct Patches {
+    /// Labels of patch files in the main repository, applied in order.
+    pub patches: Vec<String>,
+    /// Commands run with `sh` in the module's directory after the patches.
+    pub patch_cmds: Vec<String>,
+    /// Leading path components to remove from the paths in the patches, as `patch -p` does.
+    pub patch_strip: usize,
+}
+
+/// `single_version_override`: the version of a module to use whatever version the dependency graph asks for, the
+/// registry to get it from, and patches for it. An empty version or registry leaves that as it would be.
+///
+/// See https://bazel.build/rules/lib/globals/module#single_version_override
+#[derive(Debug, Clone, Default, PartialEq, Eq, Allocative)]
+pub struct SingleVersionOverride {
+    pub version: String,
+    pub registry: String,
+    pub patches: Patches,
+}
+
+/// `multiple_version_override`: versions of a module that may be used side by side. Each module that depends on it
+/// gets the lowest of them at or above the version its `bazel_dep` asks for.
+///
+/// See https://bazel.build/rules/lib/globals/module#multiple_version_override
+#[derive(Debug, Clone, Default, PartialEq, Eq, Allocative)]
+pub struct MultipleVersionOverride {
+    pub versions: Vec<String>,
+    pub registry: String,
+}
+
+/// `archive_override`: a module's files come from an archive rather than a registry, whatever version is asked for.
+///
+/// See https://bazel.build/rules/lib/globals/module#archive_override
+#[derive(Debug, Clone, Default, PartialEq, Eq, Allocative)]
+pub struct ArchiveOverride {
+    /// The URLs of the archive, tried in order.
+    pub urls: Vec<String>,
+    /// The expected checksum of the archive, as a Subresource Integrity value, or empty not to check it.
+    pub integrity: String,
+    /// A directory in the archive to use as the module's files rather than all of it.
+    pub strip_prefix: String,
+    pub patches: Patches,
+}
+
+/// `git_override`: a module's files come from a commit of a git repository rather than a registry.
+///
+/// See https://bazel.build/rules/lib/globals/module#git_override
+#[derive(Debug, Clone, Default, PartialEq, Eq, Allocative)]
+pub struct GitOverride {
+    pub remote: String,
+    /// The commit to check out, or empty to check out `tag`.
+    pub commit: String,
+    pub tag: String,
+    pub init_submodules: bool,
+    pub patches: Patches,
+}
+
+/// `local_path_override`: a module's files are a directory on this machine, so a module and the modules using it can
+/// be worked on together.
+///
+/// See https://bazel.build/rules/lib/globals/module#local_path_override
+#[derive(Debug, Clone, Default, PartialEq, Eq, Allocative)]
+pub struct LocalPathOverride {
+    /// The directory, absolute or relative to the workspace.
+    pub path: String,
+}
+
+/// The root module's overrides, each by the name of the module it overrides. A module has at most one.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Allocative)]
+pub struct Overrides {
+    pub single_version: BTreeMap<String, SingleVersionOverride>,
+    pub multiple_version: BTreeMap<String, MultipleVersionOverride>,
+    pub archive: BTreeMap<String, ArchiveOverride>,
+    pub git: BTreeMap<String, GitOverride>,
+    pub local_path: BTreeMap<String, LocalPathOverride>,
+}
+
+impl Overrides {
+    pub fn contains(&self, module: &str) -> bool {
+        self.single_version.contains_key(module)
+            || self.multiple_version.contains_key(module)
+            || self.archive.contains_key(module)
+            || self.git.contains_key(module)
+            || self.local_path.contains_key(module)
+    }
+
+    /// The registries the overrides ask for, with the function that asked for each.
+    pub fn registries(&self) -> impl Iterator<Item = (&str, &'static str)> {
+        let single = self
+            .single_version
+            .values()
+            .map(|o| (o.registry.as_str(), "single_version_override"));
+        let multiple = self
+            .multiple_version
+            .values()
+            .map(|o| (o.registry.as_str(), "multiple_version_override"));
+        single
+            .chain(multiple)
+            .filter(|(registry, _)| !registry.is_empty())
+    }
+
+    /// The version of `module` to give a module whose `bazel_dep` asks for `version`, or `None` if no version the
+    /// overrides allow will do. Modules whose files don't come from a registry have the empty version, as in Bazel.
+    pub fn version(&self, module: &str, version: &str) -> Option<String> {
+        if self.archive.contains_key(module)
+            || self.git.contains_key(module)
+            || self.local_path.contains_key(module)
+        {
+            return Some(String::new());
+        }
+        if let Some(o) = self.single_version.get(module)
+            && !o.version.is_empty()
+        {
+            return Some(o.version.clone());
+        }
+        match self.multiple_version.get(module) {
+            Some(o) => o
+                .versions
+                .iter()
+                .filter(|allowed| compare_versions(allowed, version).is_ge())
+                .min_by(|a, b| compare_versions(a, b))
+                .cloned(),
+            None => Some(version.to_string()),
+        }
+    }
 }
 
 /// MODULE.bazel file
@@ -28,20 +211,22 @@ pub struct BazelDep {
 /// See https://bazel.build/external/module
 #[derive(Debug, Clone)]
 pub struct Module {
-    #[allow(dead_code)]
     pub name: String,
-    #[allow(dead_code)]
     pub version: String,
     pub repo_name: String,
+    /// Versions of a module at different compatibility levels can't be used in place of each other.
+    pub compatibility_level: u32,
+    /// The versions of Bazel the module works with, as requirements like `>=7.0.0`, see `unmet_bazel_compatibility`.
+    pub bazel_compatibility: Vec<String>,
     pub bazel_deps: Vec<BazelDep>,
-    #[allow(dead_code)]
-    pub archive_overrides: Vec<String>,
-    #[allow(dead_code)]
-    pub local_path_overrides: Vec<String>,
-    #[allow(dead_code)]
-    pub git_overrides: Vec<String>,
-    #[allow(dead_code)]
-    pub use_extensions: Vec<String>,
+    /// The root module's overrides.
+    pub overrides: Overrides,
+    /// The module extensions the module uses, see `RepoMapping`.
+    pub extension_usages: Vec<ExtensionUsage>,
+    /// The platforms the module registers to run actions on, see `crate::cquery`.
+    pub execution_platforms: Vec<String>,
+    /// The toolchains the module registers, as labels or target patterns.
+    pub toolchains: Vec<String>,
 }
 
 impl<'a> TryFrom<ModuleBuilder<'a>> for Module {
@@ -50,21 +235,25 @@ impl<'a> TryFrom<ModuleBuilder<'a>> for Module {
     fn try_from(value: ModuleBuilder<'a>) -> Result<Self, Self::Error> {
         let name = value
             .name
-            .ok_or_else(|| anyhow::anyhow!("Module name is required"))?;
+            .ok_or(ResolutionError::MissingModuleAttribute { attribute: "name" })?;
         let repo_name = value.repo_name.unwrap_or_else(|| name.clone());
         let version = value
             .version
-            .ok_or_else(|| anyhow::anyhow!("Module version is required"))?;
+            .ok_or(ResolutionError::MissingModuleAttribute {
+                attribute: "version",
+            })?;
 
         Ok(Self {
             name,
             version,
             repo_name,
+            compatibility_level: value.compatibility_level,
+            bazel_compatibility: value.bazel_compatibility,
             bazel_deps: value.bazel_deps,
-            archive_overrides: value.archive_overrides,
-            local_path_overrides: value.local_path_overrides,
-            git_overrides: value.git_overrides,
-            use_extensions: value.use_extensions,
+            overrides: value.overrides,
+            extension_usages: value.extension_usages,
+            execution_platforms: value.execution_platforms,
+            toolchains: value.toolchains,
         })
     }
 }
@@ -80,12 +269,17 @@ static MODULE_GLOBALS: LazyLock<Globals> = LazyLock::new(|| {
         .build()
 });
 
+/// Evaluates the MODULE.bazel at `path` in `files`, and the files it includes. `ignore_dev_dependency` only matters for
+/// the root module, as other modules' dev dependencies are always ignored.
 pub(crate) async fn eval_module(
     files: &BoxFileStore<'static>,
     path: &str,
     is_root: bool,
+    ignore_dev_dependency: bool,
+    limits: EvalLimits,
 ) -> anyhow::Result<Module> {
-    let mut builder = eval_module_include(files, path, is_root).await?;
+    let mut builder =
+        eval_module_include(files, path, is_root, ignore_dev_dependency, limits).await?;
 
     // TODO: parallelise parsing of includes.
 
@@ -99,7 +293,8 @@ pub(crate) async fn eval_module(
             anyhow::anyhow!("Invalid unicode in include path: {:?}", sub_path_buf)
         })?;
 
-        let sub_builder = eval_module_include(files, sub_path, is_root).await?;
+        let sub_builder =
+            eval_module_include(files, sub_path, is_root, ignore_dev_dependency, limits).await?;
         includes.extend(sub_builder.includes.clone());
         builder.merge(sub_builder);
     }
@@ -114,6 +309,8 @@ async fn eval_module_include(
     files: &BoxFileStore<'static>,
     path: &str,
     is_root: bool,
+    ignore_dev_dependency: bool,
+    limits: EvalLimits,
 ) -> anyhow::Result<ModuleBuilder<'static>> {
     let files_owned = files.clone();
 
@@ -121,7 +318,8 @@ async fn eval_module_include(
         ModuleExtra::new_root(files_owned)
     } else {
         ModuleExtra::new(files_owned)
-    };
+    }
+    .with_ignore_dev_dependency(ignore_dev_dependency);
 
     // Fetch file contents
     let file = files.read_file(path).await?;
@@ -129,17 +327,18 @@ async fn eval_module_include(
     (*file).open().await?.read_to_string(&mut content).await?;
 
     let ast: AstModule =
-        AstModule::parse(path, content, &DIALECT_MODULE).map_err(|e| e.into_anyhow())?;
+        AstModule::parse(path, content, &DIALECT_MODULE).map_err(StarlarkError::wrap)?;
 
     StarlarkModule::with_temp_heap(|module| {
         let mut eval = Evaluator::new(&module);
+        limits.install(&mut eval, path);
         eval.extra = Some(&bzl_module);
         eval.eval_module(ast, &MODULE_GLOBALS)?;
         Ok::<_, starlark::Error>(())
     })
-    .map_err(|e| e.into_anyhow())?;
+    .map_err(StarlarkError::wrap)?;
 
-    println!("MODULE.bazel defined module name {bzl_module:?}");
+    tracing::debug!("MODULE.bazel defined module name {bzl_module:?}");
 
     Ok(bzl_module.into_inner())
 }
@@ -172,7 +371,7 @@ pub(crate) async fn eval_repo(path: &Path) -> anyhow::Result<Module> {
 
     // TODO: update this to use FileStore if needed, or keeping Path is fine for now if it's separate
     let ast: AstModule =
-        AstModule::parse_file(path, &DIALECT_MODULE).map_err(|e| e.into_anyhow())?;
+        AstModule::parse_file(path, &DIALECT_MODULE).map_err(StarlarkError::wrap)?;
 
     StarlarkModule::with_temp_heap(|module| {
         let mut eval = Evaluator::new(&module);
@@ -180,7 +379,7 @@ pub(crate) async fn eval_repo(path: &Path) -> anyhow::Result<Module> {
         eval.eval_module(ast, &REPO_GLOBALS)?;
         Ok::<_, starlark::Error>(())
     })
-    .map_err(|e| e.into_anyhow())?;
+    .map_err(StarlarkError::wrap)?;
 
     todo!()
 }
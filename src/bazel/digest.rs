@@ -0,0 +1,181 @@
+//! Content digests of files, as the remote execution API's `Digest`: a lowercase hex hash and a size.
+//!
+//! Every digest razel computes or is handed goes through here, so a hash is always checked against the length of its
+//! digest function, hex is converted one way, and digests are compared without leaking where they differ.
+
+use crate::bazel::package::{Digest, DigestFunction};
+use std::io::{Error, ErrorKind};
+
+/// The length in bytes of the hashes `function` produces, if razel can compute them.
+pub fn hash_len(function: DigestFunction) -> Option<usize> {
+    match function {
+        DigestFunction::Md5 => Some(16),
+        DigestFunction::Sha256 => Some(32),
+        DigestFunction::Sha384 => Some(48),
+        DigestFunction::Sha512 => Some(64),
+        _ => None,
+    }
+}
+
+fn unsupported(function: DigestFunction) -> Error {
+    Error::new(
+        ErrorKind::Unsupported,
+        format!("Unsupported digest function {function:?}"),
+    )
+}
+
+/// The digest of `content` with `function`.
+pub fn compute(function: DigestFunction, content: &[u8]) -> Result<Digest, Error> {
+    use sha2::Digest as _;
+
+    let hash = match function {
+        DigestFunction::Md5 => md5::Md5::digest(content).to_vec(),
+        DigestFunction::Sha256 => sha2::Sha256::digest(content).to_vec(),
+        DigestFunction::Sha384 => sha2::Sha384::digest(content).to_vec(),
+        DigestFunction::Sha512 => sha2::Sha512::digest(content).to_vec(),
+        _ => return Err(unsupported(function)),
+    };
+    Ok(Digest {
+        hash: to_hex(&hash),
+        size_bytes: content.len() as i64,
+    })
+}
+
+/// `bytes` as lowercase hex, the form hashes take in a `Digest`.
+pub fn to_hex(bytes: &[u8]) -> String {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        hex.push(DIGITS[usize::from(b >> 4)] as char);
+        hex.push(DIGITS[usize::from(b & 0xf)] as char);
+    }
+    hex
+}
+
+/// The bytes `hex` encodes, which may be in either case.
+pub fn from_hex(hex: &str) -> Result<Vec<u8>, Error> {
+    let invalid = || Error::new(ErrorKind::InvalidData, format!("{hex:?} is not hex"));
+    if !hex.len().is_multiple_of(2) {
+        return Err(invalid());
+    }
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            let digit = |d: u8| (d as char).to_digit(16).ok_or_else(invalid);
+            Ok(((digit(pair[0])? << 4) | digit(pair[1])?) as u8)
+        })
+        .collect()
+}
+
+/// Checks that `digest` could have been produced by `function`: a lowercase hex hash of its length, and a size that
+/// isn't negative. Placeholders and hashes of another function are rejected before they can reach a cache.
+pub fn validate(digest: &Digest, function: DigestFunction) -> Result<(), Error> {
+    let len = hash_len(function).ok_or_else(|| unsupported(function))?;
+    let invalid = |reason: String| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Invalid {function:?} digest {}/{}: {reason}",
+                digest.hash, digest.size_bytes
+            ),
+        )
+    };
+    if digest.hash.len() != len * 2 {
+        return Err(invalid(format!(
+            "expected {} hex digits, got {}",
+            len * 2,
+            digest.hash.len()
+        )));
+    }
+    if !digest
+        .hash
+        .bytes()
+        .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    {
+        return Err(invalid("not lowercase hex".to_string()));
+    }
+    if digest.size_bytes < 0 {
+        return Err(invalid("negative size".to_string()));
+    }
+    Ok(())
+}
+
+/// Whether `a` and `b` are the same digest, taking as long to find out wherever they differ, so a cache keyed by
+/// digests can't be probed one hex digit at a time.
+pub fn equal(a: &Digest, b: &Digest) -> bool {
+    if a.hash.len() != b.hash.len() {
+        return false;
+    }
+    let hash_diff = a
+        .hash
+        .bytes()
+        .zip(b.hash.bytes())
+        .fold(0u8, |diff, (a, b)| diff | (a ^ b));
+    let size_diff = a.size_bytes ^ b.size_bytes;
+    std::hint::black_box(hash_diff) == 0 && size_diff == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compute_and_validate() {
+        for function in [
+            DigestFunction::Md5,
+            DigestFunction::Sha256,
+            DigestFunction::Sha384,
+            DigestFunction::Sha512,
+        ] {
+            let digest = compute(function, b"hello").unwrap();
+            assert_eq!(digest.size_bytes, 5);
+            validate(&digest, function).unwrap();
+        }
+        assert_eq!(
+            compute(DigestFunction::Md5, b"hello").unwrap().hash,
+            "5d41402abc4b2a76b9719d911017c592"
+        );
+
+        let sha256 = compute(DigestFunction::Sha256, b"hello").unwrap();
+        assert!(validate(&sha256, DigestFunction::Sha512).is_err());
+        let placeholder = Digest {
+            hash: "dummy_hash".to_string(),
+            size_bytes: 0,
+        };
+        assert!(validate(&placeholder, DigestFunction::Sha256).is_err());
+        let upper = Digest {
+            hash: sha256.hash.to_uppercase(),
+            ..sha256.clone()
+        };
+        assert!(validate(&upper, DigestFunction::Sha256).is_err());
+        assert_eq!(
+            compute(DigestFunction::Blake3, b"hello")
+                .unwrap_err()
+                .kind(),
+            ErrorKind::Unsupported
+        );
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(to_hex(&[0x00, 0x9f, 0xff]), "009fff");
+        assert_eq!(from_hex("009FfF").unwrap(), [0x00, 0x9f, 0xff]);
+        assert!(from_hex("abc").is_err());
+        assert!(from_hex("zz").is_err());
+    }
+
+    #[test]
+    fn test_equal() {
+        let a = compute(DigestFunction::Sha256, b"a").unwrap();
+        let b = compute(DigestFunction::Sha256, b"b").unwrap();
+        assert!(equal(&a, &a.clone()));
+        assert!(!equal(&a, &b));
+        assert!(!equal(
+            &a,
+            &Digest {
+                size_bytes: 2,
+                ..a.clone()
+            }
+        ));
+    }
+}
@@ -0,0 +1,807 @@
+//! Downloading of repository archives and toolchains, with checksum verification and a content-addressed cache.
+
+use crate::bazel::Configuration;
+use crate::bazel::digest;
+use crate::bazel::package::{Digest, DigestFunction};
+use crate::bazel::policy::DependencyPolicy;
+use crate::clock::Providers;
+use crate::error::FetchError;
+use base64::Engine;
+use futures::StreamExt;
+use sha2::{Digest as _, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::Instrument;
+use tracing_indicatif::span_ext::IndicatifSpanExt;
+
+/// How much of a local file to copy at a time, advancing the progress bar after each.
+const BLOCK_SIZE: usize = 64 * 1024;
+
+/// A checksum, written either as an SRI `integrity` string (`sha256-`, `sha384-` or `sha512-<base64>`) or as a hex
+/// sha256.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Integrity {
+    Sha256([u8; 32]),
+    Sha384([u8; 48]),
+    Sha512([u8; 64]),
+}
+
+impl Integrity {
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let invalid = || FetchError::InvalidChecksum {
+            checksum: s.to_string(),
+        };
+        let (function, hash) = if let Some((algorithm, b64)) = s.split_once('-') {
+            let function = match algorithm {
+                "sha256" => DigestFunction::Sha256,
+                "sha384" => DigestFunction::Sha384,
+                "sha512" => DigestFunction::Sha512,
+                _ => return Err(invalid().into()),
+            };
+            (
+                function,
+                base64::engine::general_purpose::STANDARD.decode(b64)?,
+            )
+        } else if s.len() == 64 {
+            (DigestFunction::Sha256, digest::from_hex(s)?)
+        } else {
+            return Err(invalid().into());
+        };
+        Ok(Self::from_hash(function, &hash).ok_or_else(invalid)?)
+    }
+
+    /// The checksum `hash` computed with `function`, if it's one of the functions checksums use and `hash` is of its
+    /// length.
+    fn from_hash(function: DigestFunction, hash: &[u8]) -> Option<Self> {
+        match function {
+            DigestFunction::Sha256 => hash.try_into().ok().map(Self::Sha256),
+            DigestFunction::Sha384 => hash.try_into().ok().map(Self::Sha384),
+            DigestFunction::Sha512 => hash.try_into().ok().map(Self::Sha512),
+            _ => None,
+        }
+    }
+
+    /// The function the checksum was computed with.
+    pub fn function(self) -> DigestFunction {
+        match self {
+            Self::Sha256(_) => DigestFunction::Sha256,
+            Self::Sha384(_) => DigestFunction::Sha384,
+            Self::Sha512(_) => DigestFunction::Sha512,
+        }
+    }
+
+    /// The name of the function in SRI strings and in the repository cache.
+    fn algorithm(self) -> &'static str {
+        match self {
+            Self::Sha256(_) => "sha256",
+            Self::Sha384(_) => "sha384",
+            Self::Sha512(_) => "sha512",
+        }
+    }
+
+    fn hash(&self) -> &[u8] {
+        match self {
+            Self::Sha256(hash) => hash,
+            Self::Sha384(hash) => hash,
+            Self::Sha512(hash) => hash,
+        }
+    }
+
+    pub fn to_sri(self) -> String {
+        format!(
+            "{}-{}",
+            self.algorithm(),
+            base64::engine::general_purpose::STANDARD.encode(self.hash())
+        )
+    }
+
+    pub fn to_hex(self) -> String {
+        digest::to_hex(self.hash())
+    }
+
+    /// Whether `content` has this checksum.
+    pub fn matches(self, content: &[u8]) -> anyhow::Result<bool> {
+        let actual = digest::compute(self.function(), content)?;
+        let expected = Digest {
+            hash: self.to_hex(),
+            size_bytes: actual.size_bytes,
+        };
+        Ok(digest::equal(&actual, &expected))
+    }
+}
+
+impl std::fmt::Display for Integrity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.to_sri())
+    }
+}
+
+/// Why downloading from a URL failed, which decides whether to try it again, or other URLs at all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, derive_more::Display)]
+pub enum FailureKind {
+    #[display("host not found")]
+    Dns,
+    #[display("TLS handshake failed")]
+    Tls,
+    #[display("connection failed")]
+    Connection,
+    #[display("timed out")]
+    Timeout,
+    #[display("not found")]
+    NotFound,
+    #[display("server error {_0}")]
+    ServerError(u16),
+    #[display("HTTP error {_0}")]
+    HttpError(u16),
+    #[display("checksum mismatch")]
+    ChecksumMismatch,
+    #[display("disk full")]
+    DiskFull,
+    #[display("I/O error")]
+    Io,
+}
+
+impl FailureKind {
+    /// Works out what kind of failure `error`, from fetching one URL, is.
+    pub fn classify(error: &anyhow::Error) -> Self {
+        for cause in error.chain() {
+            if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+                return match e.kind() {
+                    std::io::ErrorKind::StorageFull => FailureKind::DiskFull,
+                    std::io::ErrorKind::NotFound => FailureKind::NotFound,
+                    std::io::ErrorKind::TimedOut => FailureKind::Timeout,
+                    std::io::ErrorKind::ConnectionRefused
+                    | std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::HostUnreachable
+                    | std::io::ErrorKind::NetworkUnreachable => FailureKind::Connection,
+                    _ => FailureKind::Io,
+                };
+            }
+            if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+                if let Some(status) = e.status() {
+                    return match status.as_u16() {
+                        404 | 410 => FailureKind::NotFound,
+                        code @ (429 | 500..) => FailureKind::ServerError(code),
+                        code => FailureKind::HttpError(code),
+                    };
+                }
+                if e.is_timeout() {
+                    return FailureKind::Timeout;
+                }
+                if e.is_connect() {
+                    // The resolver and TLS errors underneath aren't exposed as types, only as messages.
+                    let message = std::iter::successors(Some(cause), |e| e.source())
+                        .map(|e| e.to_string().to_lowercase())
+                        .collect::<Vec<_>>()
+                        .join(": ");
+                    return if message.contains("dns") || message.contains("lookup address") {
+                        FailureKind::Dns
+                    } else if message.contains("certificate") || message.contains("tls") {
+                        FailureKind::Tls
+                    } else {
+                        FailureKind::Connection
+                    };
+                }
+            }
+        }
+        FailureKind::Io
+    }
+
+    /// Whether trying the same URL again might work, i.e. the failure is often transient.
+    pub fn retryable(self) -> bool {
+        matches!(
+            self,
+            FailureKind::Dns
+                | FailureKind::Connection
+                | FailureKind::Timeout
+                | FailureKind::ServerError(_)
+        )
+    }
+
+    /// Whether other URLs are still worth trying. A full disk fails them all the same.
+    pub fn try_mirrors(self) -> bool {
+        self != FailureKind::DiskFull
+    }
+
+    /// The name of the failure in error codes, see `crate::error::FetchError`.
+    pub fn code_name(self) -> &'static str {
+        match self {
+            FailureKind::Dns => "HOST_NOT_FOUND",
+            FailureKind::Tls => "TLS_FAILURE",
+            FailureKind::Connection => "CONNECTION_FAILED",
+            FailureKind::Timeout => "TIMEOUT",
+            FailureKind::NotFound => "NOT_FOUND",
+            FailureKind::ServerError(_) => "SERVER_ERROR",
+            FailureKind::HttpError(_) => "HTTP_ERROR",
+            FailureKind::ChecksumMismatch => "CHECKSUM_MISMATCH",
+            FailureKind::DiskFull => "DISK_FULL",
+            FailureKind::Io => "DOWNLOAD_FAILED",
+        }
+    }
+
+    /// What to do about the failure.
+    pub fn hint(self) -> &'static str {
+        match self {
+            FailureKind::Dns => "check the host name, and your DNS or proxy settings",
+            FailureKind::Tls => {
+                "the server's certificate could not be verified; check the system clock, and any proxy that \
+                 intercepts TLS"
+            }
+            FailureKind::Connection => "check your network connection and proxy settings",
+            FailureKind::Timeout => "the server is slow or unreachable; try again, or add a mirror",
+            FailureKind::NotFound => {
+                "nothing is at this URL; check it, and the registry or mirror it came from"
+            }
+            FailureKind::ServerError(_) => {
+                "the server had a problem; try again later, or add a mirror"
+            }
+            FailureKind::HttpError(_) => {
+                "the server refused the request; check the URL and any credentials"
+            }
+            FailureKind::ChecksumMismatch => {
+                "the file changed upstream or the checksum is wrong; if the new content is trusted, update the \
+                 integrity in MODULE.bazel or the lockfile"
+            }
+            FailureKind::DiskFull => "free up space for the output base and repository cache",
+            FailureKind::Io => "check the permissions of the output base and repository cache",
+        }
+    }
+}
+
+/// How long to wait before trying a URL for the `attempt`th time, doubling each time, plus up to half as long again
+/// picked by `providers` so that clients that failed together don't all retry together.
+fn backoff(attempt: u32, providers: &Providers) -> std::time::Duration {
+    let base = std::time::Duration::from_millis(100 << attempt.min(6));
+    base + providers.jitter(base / 2)
+}
+
+#[derive(Debug, Clone)]
+pub struct Downloader {
+    client: reqwest::Client,
+    /// Content-addressed cache of previous downloads, laid out like Bazel's `--repository_cache`.
+    repository_cache: Option<PathBuf>,
+    /// Directories of archives named for the last segment of their URL, used instead of downloading, as `--distdir`.
+    distdirs: Vec<PathBuf>,
+    /// Which URLs may be downloaded from.
+    policy: Arc<DependencyPolicy>,
+    /// How many more times to try a URL after a failure that may be transient.
+    retries: u32,
+    /// Where the jitter of retry backoff comes from.
+    providers: Providers,
+    /// Whether downloads must have an expected checksum, as set by `--experimental_repository_disable_download`.
+    require_checksum: bool,
+}
+
+impl Downloader {
+    pub fn new(repository_cache: Option<PathBuf>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            repository_cache,
+            distdirs: Vec::new(),
+            policy: Arc::default(),
+            retries: 0,
+            providers: Providers::default(),
+            require_checksum: false,
+        }
+    }
+
+    /// A downloader set up by the flags of `config`.
+    pub fn for_config(config: &Configuration) -> Self {
+        Self::new(config.repository_cache.clone())
+            .with_retries(config.repository_downloader_retries)
+            .with_require_checksum(config.repository_disable_download)
+    }
+
+    /// Tries URLs up to `retries` more times after failures that may be transient, as set by
+    /// `--experimental_repository_downloader_retries`.
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Jitters retry backoff with the random numbers of `providers`.
+    pub fn with_providers(mut self, providers: Providers) -> Self {
+        self.providers = providers;
+        self
+    }
+
+    /// Where the jitter of retry backoff and the names of temporary files come from.
+    pub fn providers(&self) -> &Providers {
+        &self.providers
+    }
+
+    /// Looks for downloads with a checksum in `distdirs` first, by the last segment of their URLs.
+    pub fn with_distdirs(mut self, distdirs: impl IntoIterator<Item = PathBuf>) -> Self {
+        self.distdirs = distdirs.into_iter().collect();
+        self
+    }
+
+    /// Restricts downloads to the URLs `policy` allows.
+    pub fn with_policy(mut self, policy: Arc<DependencyPolicy>) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Refuses to download anything without an expected checksum, so that only pinned, reproducible content is
+    /// fetched.
+    pub fn with_require_checksum(mut self, require_checksum: bool) -> Self {
+        self.require_checksum = require_checksum;
+        self
+    }
+
+    fn cache_path(&self, integrity: &Integrity) -> Option<PathBuf> {
+        self.repository_cache.as_ref().map(|dir| {
+            dir.join("content_addressable")
+                .join(integrity.algorithm())
+                .join(integrity.to_hex())
+                .join("file")
+        })
+    }
+
+    /// Whether `path` exists and has the checksum `integrity`.
+    async fn matches(path: &Path, integrity: &Integrity) -> anyhow::Result<bool> {
+        if !tokio::fs::try_exists(path).await? {
+            return Ok(false);
+        }
+        integrity.matches(&tokio::fs::read(path).await?)
+    }
+
+    /// The file in a distdir for one of `urls` with the checksum `integrity`, if any. Files with another checksum are
+    /// passed over, as in Bazel.
+    async fn in_distdir(
+        &self,
+        urls: &[&String],
+        integrity: &Integrity,
+    ) -> anyhow::Result<Option<PathBuf>> {
+        for dir in &self.distdirs {
+            for url in urls {
+                let url = url.split(['?', '#']).next().unwrap_or_default();
+                let Some(name) = url.rsplit('/').next().filter(|name| !name.is_empty()) else {
+                    continue;
+                };
+                let path = dir.join(name);
+                if Self::matches(&path, integrity).await? {
+                    return Ok(Some(path));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Copies the verified download `dest` into the cache.
+    async fn store(&self, dest: &Path, integrity: &Integrity) -> anyhow::Result<()> {
+        if let Some(cached) = self.cache_path(integrity) {
+            tokio::fs::create_dir_all(cached.parent().unwrap()).await?;
+            tokio::fs::copy(dest, &cached).await?;
+        }
+        Ok(())
+    }
+
+    /// Whether the download with checksum `integrity` is in the cache. A cached file whose content no longer matches
+    /// its checksum is ignored, so it's downloaded again.
+    async fn cached(&self, integrity: &Integrity) -> anyhow::Result<Option<PathBuf>> {
+        let Some(cached) = self.cache_path(integrity) else {
+            return Ok(None);
+        };
+        if !tokio::fs::try_exists(&cached).await? {
+            return Ok(None);
+        }
+        if !Self::matches(&cached, integrity).await? {
+            tracing::warn!("Ignoring corrupted cache entry {}", cached.display());
+            return Ok(None);
+        }
+        Ok(Some(cached))
+    }
+
+    /// Downloads the first of `urls` that succeeds into `dest`, and returns the checksum of its content.
+    ///
+    /// If `expected` is given, e.g. because it was pinned in the lockfile, the download must match it, and a cached
+    /// copy is used when available instead of going to the network.
+    pub async fn download(
+        &self,
+        urls: &[String],
+        dest: &Path,
+        expected: Option<&Integrity>,
+    ) -> anyhow::Result<Integrity> {
+        if expected.is_none() && self.require_checksum {
+            return Err(FetchError::ChecksumRequired {
+                dest: dest.to_path_buf(),
+                urls: urls.to_vec(),
+            }
+            .into());
+        }
+
+        // Denied URLs are never fetched, even when a mirror is allowed.
+        let requested_by = format!("download of {}", dest.display());
+        let mut denied = Vec::new();
+        let urls: Vec<&String> = urls
+            .iter()
+            .filter(|url| match self.policy.check_url(url, &requested_by) {
+                Ok(()) => true,
+                Err(violation) => {
+                    denied.push(violation);
+                    false
+                }
+            })
+            .collect();
+        if urls.is_empty()
+            && let Some(violation) = denied.into_iter().next()
+        {
+            return Err(violation.into());
+        }
+
+        if let Some(dir) = dest.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+
+        if let Some(expected) = expected
+            && let Some(cached) = self.cached(expected).await?
+        {
+            tokio::fs::copy(&cached, dest).await?;
+            return Ok(*expected);
+        }
+
+        if let Some(expected) = expected
+            && let Some(file) = self.in_distdir(&urls, expected).await?
+        {
+            tokio::fs::copy(&file, dest).await?;
+            self.store(dest, expected).await?;
+            return Ok(*expected);
+        }
+
+        // Without an expected checksum, the download's sha256 is returned, as in Bazel.
+        let function = expected.map_or(DigestFunction::Sha256, |expected| expected.function());
+        let mut errors = Vec::new();
+        let mut kinds = Vec::new();
+        for url in urls {
+            let mut attempt = 0;
+            let (kind, message) = loop {
+                let (kind, message) = match self.fetch(url, dest, function).await {
+                    Ok(actual) => {
+                        if let Some(expected) = expected
+                            && actual != *expected
+                        {
+                            (
+                                FailureKind::ChecksumMismatch,
+                                format!("expected {expected} but got {actual}"),
+                            )
+                        } else {
+                            self.store(dest, &actual).await?;
+                            return Ok(actual);
+                        }
+                    }
+                    Err(e) => (FailureKind::classify(&e), format!("{e:#}")),
+                };
+                if !kind.retryable() || attempt == self.retries {
+                    break (kind, message);
+                }
+                attempt += 1;
+                tracing::warn!("Retrying {url} after {kind}: {message}");
+                tokio::time::sleep(backoff(attempt, &self.providers)).await;
+            };
+            let tries = match attempt {
+                0 => String::new(),
+                n => format!(", tried {} times", n + 1),
+            };
+            errors.push(format!(
+                "{url}: {kind}{tries}: {message}\n    {}",
+                kind.hint()
+            ));
+            kinds.push(kind);
+            if !kind.try_mirrors() {
+                break;
+            }
+        }
+
+        let _ = tokio::fs::remove_file(dest).await;
+        let dest = dest.to_path_buf();
+        Err(if kinds.contains(&FailureKind::ChecksumMismatch) {
+            FetchError::ChecksumMismatch { dest, errors }
+        } else {
+            // A code more specific than DOWNLOAD_FAILED when every URL failed the same way.
+            let kind = kinds
+                .first()
+                .copied()
+                .filter(|first| kinds.iter().all(|kind| kind == first));
+            FetchError::DownloadFailed { dest, errors, kind }
+        }
+        .into())
+    }
+
+    /// Fetches a single URL into `dest`, reporting progress, and returns the checksum of what was written, computed
+    /// with `function`.
+    async fn fetch(
+        &self,
+        url: &str,
+        dest: &Path,
+        function: DigestFunction,
+    ) -> anyhow::Result<Integrity> {
+        let span = tracing::info_span!("download", url);
+        span.pb_set_message(&format!("Downloading {url}"));
+
+        async {
+            let mut hasher: Box<dyn sha2::digest::DynDigest + Send> = match function {
+                DigestFunction::Sha384 => Box::new(sha2::Sha384::new()),
+                DigestFunction::Sha512 => Box::new(sha2::Sha512::new()),
+                _ => Box::new(Sha256::new()),
+            };
+            let mut out = tokio::fs::File::create(dest).await?;
+
+            if let Some(path) = url.strip_prefix("file://") {
+                let mut file = tokio::fs::File::open(path).await?;
+                tracing::Span::current().pb_set_length(file.metadata().await?.len());
+                let mut block = vec![0; BLOCK_SIZE];
+                loop {
+                    let n = file.read(&mut block).await?;
+                    if n == 0 {
+                        break;
+                    }
+                    hasher.update(&block[..n]);
+                    out.write_all(&block[..n]).await?;
+                    tracing::Span::current().pb_inc(n as u64);
+                }
+            } else {
+                let response = self.client.get(url).send().await?.error_for_status()?;
+                if let Some(len) = response.content_length() {
+                    tracing::Span::current().pb_set_length(len);
+                }
+                let mut body = response.bytes_stream();
+                while let Some(chunk) = body.next().await {
+                    let chunk = chunk?;
+                    hasher.update(&chunk);
+                    out.write_all(&chunk).await?;
+                    tracing::Span::current().pb_inc(chunk.len() as u64);
+                }
+            }
+            out.flush().await?;
+
+            Ok(Integrity::from_hash(function, &hasher.finalize())
+                .expect("the hasher is of a checksum function"))
+        }
+        .instrument(span)
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    #[test]
+    fn test_integrity_formats() {
+        let hex = Integrity::parse(HELLO_SHA256).unwrap();
+        let sri = Integrity::parse(&hex.to_sri()).unwrap();
+        assert_eq!(hex, sri);
+        assert_eq!(sri.to_hex(), HELLO_SHA256);
+        assert!(Integrity::parse("sha384-abc").is_err());
+        assert!(Integrity::parse("sha256-AAAA").is_err());
+        assert!(Integrity::parse("md5-XUFAKrxLKna5cZ2REBfFkg==").is_err());
+
+        for (function, sri) in [
+            (
+                DigestFunction::Sha384,
+                "sha384-WeF0h3dEjGnea4ANejO7+5/xtGPkQ1TDVTvNucZm+pASWjx5+QOXvfX2oT3oKGhP",
+            ),
+            (
+                DigestFunction::Sha512,
+                "sha512-m3HSJL1i83hdltRq0+o9czGb+8KJDKra4t/3JRlnPKcjI8PZm6XBHXx6zG4UuMXaDEZjR1wuXDre9G9zvN7AQw==",
+            ),
+        ] {
+            let integrity = Integrity::parse(sri).unwrap();
+            assert_eq!(integrity.function(), function);
+            assert_eq!(integrity.to_sri(), sri);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_verifies_and_caches() {
+        let tmp = assert_fs::TempDir::new().unwrap();
+        let src = tmp.path().join("hello.txt");
+        std::fs::write(&src, "hello").unwrap();
+        let url = format!("file://{}", src.display());
+        let downloader = Downloader::new(Some(tmp.path().join("cache")));
+
+        let dest = tmp.path().join("out/first");
+        let integrity = downloader
+            .download(std::slice::from_ref(&url), &dest, None)
+            .await
+            .unwrap();
+        assert_eq!(integrity.to_hex(), HELLO_SHA256);
+        assert_eq!(std::fs::read_to_string(&dest).unwrap(), "hello");
+
+        // Registries also pin sha384 and sha512 checksums, which are cached by their own function.
+        let sha384 = Integrity::parse(
+            "sha384-WeF0h3dEjGnea4ANejO7+5/xtGPkQ1TDVTvNucZm+pASWjx5+QOXvfX2oT3oKGhP",
+        )
+        .unwrap();
+        let verified = downloader
+            .download(std::slice::from_ref(&url), &dest, Some(&sha384))
+            .await
+            .unwrap();
+        assert_eq!(verified, sha384);
+        assert!(
+            downloader
+                .cache_path(&sha384)
+                .unwrap()
+                .starts_with(tmp.path().join("cache/content_addressable/sha384"))
+        );
+
+        // A pinned checksum is served from the cache, even once the original is gone.
+        std::fs::remove_file(&src).unwrap();
+        let dest = tmp.path().join("out/second");
+        downloader
+            .download(std::slice::from_ref(&url), &dest, Some(&integrity))
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&dest).unwrap(), "hello");
+
+        // A corrupted cache entry isn't served.
+        let cached = downloader.cache_path(&integrity).unwrap();
+        std::fs::write(&cached, "corrupted").unwrap();
+        assert!(
+            downloader
+                .download(std::slice::from_ref(&url), &dest, Some(&integrity))
+                .await
+                .is_err()
+        );
+
+        std::fs::write(&src, "tampered").unwrap();
+        let err = Downloader::new(None)
+            .download(&[url], &tmp.path().join("out/third"), Some(&integrity))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("checksum mismatch"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_required_checksums() {
+        let tmp = assert_fs::TempDir::new().unwrap();
+        let src = tmp.path().join("hello.txt");
+        std::fs::write(&src, "hello").unwrap();
+        let url = format!("file://{}", src.display());
+
+        let err = Downloader::new(None)
+            .with_require_checksum(true)
+            .download(
+                std::slice::from_ref(&url),
+                &tmp.path().join("out/third"),
+                None,
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(
+            crate::error::error_code(&err).unwrap().to_string(),
+            "RAZEL_FETCH_CHECKSUM_REQUIRED"
+        );
+        assert!(
+            err.to_string()
+                .contains("--experimental_repository_disable_download"),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_distdir() {
+        let tmp = assert_fs::TempDir::new().unwrap();
+        let distdir = tmp.path().join("distdir");
+        std::fs::create_dir(&distdir).unwrap();
+        std::fs::write(distdir.join("hello.txt"), "hello").unwrap();
+        let downloader = Downloader::new(None).with_distdirs([distdir]);
+        let urls = ["https://example.invalid/files/hello.txt?raw=1".to_string()];
+        let dest = tmp.path().join("out/hello.txt");
+
+        let integrity = Integrity::parse(HELLO_SHA256).unwrap();
+        downloader
+            .download(&urls, &dest, Some(&integrity))
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&dest).unwrap(), "hello");
+
+        // Only a file with the expected checksum is used, and only if a checksum is expected at all.
+        let other = Integrity::parse(&"0".repeat(64)).unwrap();
+        assert!(
+            downloader
+                .download(&urls, &dest, Some(&other))
+                .await
+                .is_err()
+        );
+        assert!(downloader.download(&urls, &dest, None).await.is_err());
+    }
+
+    #[test]
+    fn test_backoff_is_jittered_deterministically() {
+        let a = Providers::fixed(std::time::UNIX_EPOCH, 7);
+        let b = Providers::fixed(std::time::UNIX_EPOCH, 7);
+        for attempt in 1..10 {
+            let base = std::time::Duration::from_millis(100 << attempt.min(6));
+            let delay = backoff(attempt, &a);
+            assert_eq!(delay, backoff(attempt, &b));
+            assert!(base <= delay && delay < base * 3 / 2, "{delay:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_failures_are_classified() {
+        let tmp = assert_fs::TempDir::new().unwrap();
+        let missing = format!("file://{}", tmp.path().join("missing.zip").display());
+        let err = Downloader::new(None)
+            .with_retries(3)
+            .download(
+                std::slice::from_ref(&missing),
+                &tmp.path().join("out"),
+                None,
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(
+            crate::error::error_code(&err).unwrap().to_string(),
+            "RAZEL_FETCH_NOT_FOUND"
+        );
+        // Not worth retrying, and the message says which URL was tried and what to do about it.
+        let message = err.to_string();
+        assert!(
+            message.contains(&format!("{missing}: not found: ")),
+            "{message}"
+        );
+        assert!(!message.contains("tried"), "{message}");
+        assert!(message.contains(FailureKind::NotFound.hint()), "{message}");
+
+        // Nothing listens on port 1, which may be transient, so it is retried.
+        let err = Downloader::new(None)
+            .with_retries(1)
+            .download(
+                &["http://127.0.0.1:1/a.zip".to_string(), missing],
+                &tmp.path().join("out"),
+                None,
+            )
+            .await
+            .unwrap_err();
+        let message = err.to_string();
+        assert!(
+            message.contains("http://127.0.0.1:1/a.zip: connection failed, tried 2 times: "),
+            "{message}"
+        );
+        assert_eq!(
+            crate::error::error_code(&err).unwrap().to_string(),
+            "RAZEL_FETCH_DOWNLOAD_FAILED"
+        );
+
+        let disk_full = anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::StorageFull))
+            .context("Failed to write out");
+        assert_eq!(FailureKind::classify(&disk_full), FailureKind::DiskFull);
+        assert!(!FailureKind::DiskFull.try_mirrors());
+        assert!(FailureKind::ServerError(503).retryable());
+        assert!(!FailureKind::HttpError(403).retryable());
+    }
+
+    #[tokio::test]
+    async fn test_download_respects_policy() {
+        let tmp = assert_fs::TempDir::new().unwrap();
+        std::fs::write(
+            tmp.path().join(crate::bazel::policy::POLICY_FILE),
+            r#"{"urls": {"deny": ["https://*"]}}"#,
+        )
+        .unwrap();
+        let policy = Arc::new(DependencyPolicy::load(tmp.path()).unwrap());
+        let err = Downloader::new(None)
+            .with_policy(policy)
+            .download(
+                &["https://example.com/a.tar.gz".to_string()],
+                &tmp.path().join("out"),
+                None,
+            )
+            .await
+            .unwrap_err();
+        let violation = err
+            .downcast::<crate::bazel::policy::PolicyViolation>()
+            .unwrap();
+        assert_eq!(
+            violation.reason,
+            "denied by urls.deny pattern \"https://*\""
+        );
+    }
+}
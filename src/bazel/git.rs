@@ -0,0 +1,218 @@
+use crate::error::FetchError;
+use std::path::Path;
+
+/// The revision of a remote repository to check out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Revision {
+    Commit(String),
+    Tag(String),
+}
+
+impl Revision {
+    /// What `git rev-parse` resolves to the commit, once it has been fetched.
+    fn rev(&self) -> String {
+        match self {
+            Revision::Commit(commit) => format!("{commit}^{{commit}}"),
+            Revision::Tag(tag) => format!("refs/tags/{tag}^{{commit}}"),
+        }
+    }
+
+    /// Checks that the revision can't be taken for an option of git: a commit must be a full hex object name, and a
+    /// tag mustn't start with `-`.
+    fn check(&self, remote: &str) -> Result<(), FetchError> {
+        let reason = match self {
+            Revision::Commit(commit)
+                if commit.len() != 40 || !commit.bytes().all(|b| b.is_ascii_hexdigit()) =>
+            {
+                format!("commit {commit:?} isn't a 40 character hex commit id")
+            }
+            Revision::Tag(tag) if tag.is_empty() || tag.starts_with('-') => {
+                format!("tag {tag:?} isn't a valid tag name")
+            }
+            _ => return Ok(()),
+        };
+        Err(FetchError::GitFailed {
+            remote: remote.to_string(),
+            reason,
+        })
+    }
+
+    /// The refspec fetching the revision, and keeping a ref to it so it isn't garbage collected.
+    fn refspec(&self) -> String {
+        match self {
+            Revision::Commit(commit) => format!("{commit}:refs/razel/{commit}"),
+            Revision::Tag(tag) => format!("refs/tags/{tag}:refs/tags/{tag}"),
+        }
+    }
+}
+
+/// Runs `git` with `args` in `dir`, returning what it prints. Failures are reported as failures to fetch `remote`.
+async fn git(dir: &Path, remote: &str, args: &[&str]) -> Result<String, FetchError> {
+    let failed = |reason: String| FetchError::GitFailed {
+        remote: remote.to_string(),
+        reason,
+    };
+    let output = tokio::process::Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .stdin(std::process::Stdio::null())
+        .output()
+        .await
+        .map_err(|e| failed(format!("failed to run git: {e}")))?;
+    if !output.status.success() {
+        return Err(failed(format!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Checks that neither `remote` nor `revision`, which come from MODULE.bazel, can be taken for options of git.
+fn check_args(remote: &str, revision: &Revision) -> Result<(), FetchError> {
+    if remote.is_empty() || remote.starts_with('-') {
+        return Err(FetchError::GitFailed {
+            remote: remote.to_string(),
+            reason: "it isn't a valid remote".to_string(),
+        });
+    }
+    revision.check(remote)
+}
+
+/// Fetches `revision` of `remote` into the bare repository `cache`, which is created if needed, unless it already
+/// has it. Only the revision itself is fetched if the server allows it, and its whole history otherwise.
+pub async fn fetch(cache: &Path, remote: &str, revision: &Revision) -> Result<(), FetchError> {
+    check_args(remote, revision)?;
+    if !cache.join("HEAD").exists() {
+        std::fs::create_dir_all(cache).map_err(|e| FetchError::GitFailed {
+            remote: remote.to_string(),
+            reason: format!("failed to create {}: {e}", cache.display()),
+        })?;
+        git(cache, remote, &["init", "--quiet", "--bare"]).await?;
+    }
+    let rev = revision.rev();
+    if git(
+        cache,
+        remote,
+        &["rev-parse", "--verify", "--quiet", "--end-of-options", &rev],
+    )
+    .await
+    .is_ok()
+    {
+        return Ok(());
+    }
+    let refspec = revision.refspec();
+    let shallow = git(
+        cache,
+        remote,
+        &["fetch", "--quiet", "--depth=1", "--", remote, &refspec],
+    )
+    .await;
+    if shallow.is_err() {
+        let mut args = vec!["fetch", "--quiet"];
+        // Earlier shallow fetches would cut the history short.
+        if cache.join("shallow").exists() {
+            args.push("--unshallow");
+        }
+        args.extend([
+            "--",
+            remote,
+            "+refs/heads/*:refs/heads/*",
+            "+refs/tags/*:refs/tags/*",
+        ]);
+        git(cache, remote, &args).await?;
+    }
+    git(
+        cache,
+        remote,
+        &["rev-parse", "--verify", "--quiet", "--end-of-options", &rev],
+    )
+    .await
+    .map_err(|_| FetchError::GitFailed {
+        remote: remote.to_string(),
+        reason: format!("it has no {revision:?}"),
+    })?;
+    Ok(())
+}
+
+/// Checks out `revision`, already fetched into `cache`, into `dest`, replacing anything there. The checkout borrows
+/// its objects from the cache rather than copying them. With `init_submodules`, submodules are checked out too,
+/// relative URLs being relative to `remote`.
+pub async fn checkout(
+    cache: &Path,
+    remote: &str,
+    revision: &Revision,
+    dest: &Path,
+    init_submodules: bool,
+) -> Result<(), FetchError> {
+    let io = |e: std::io::Error| FetchError::GitFailed {
+        remote: remote.to_string(),
+        reason: format!("failed to check out into {}: {e}", dest.display()),
+    };
+    check_args(remote, revision)?;
+    let commit = git(
+        cache,
+        remote,
+        &["rev-parse", "--verify", "--end-of-options", &revision.rev()],
+    )
+    .await?;
+    if dest.exists() {
+        std::fs::remove_dir_all(dest).map_err(io)?;
+    }
+    std::fs::create_dir_all(dest).map_err(io)?;
+    git(dest, remote, &["init", "--quiet"]).await?;
+    std::fs::write(
+        dest.join(".git/objects/info/alternates"),
+        format!("{}\n", cache.join("objects").display()),
+    )
+    .map_err(io)?;
+    git(dest, remote, &["remote", "add", "--", "origin", remote]).await?;
+    git(
+        dest,
+        remote,
+        &["checkout", "--quiet", "--detach", &commit, "--"],
+    )
+    .await?;
+    if init_submodules {
+        git(
+            dest,
+            remote,
+            &["submodule", "update", "--quiet", "--init", "--recursive"],
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_fetch_rejects_options() {
+        let tmp = assert_fs::TempDir::new().unwrap();
+        let cache = tmp.path().join("cache");
+        let commit = Revision::Commit("0".repeat(40));
+        for (remote, revision) in [
+            ("--upload-pack=touch /tmp/pwned", &commit),
+            (
+                "https://example.com/repo.git",
+                &Revision::Commit("--all".to_string()),
+            ),
+            (
+                "https://example.com/repo.git",
+                &Revision::Commit("abc123".to_string()),
+            ),
+            (
+                "https://example.com/repo.git",
+                &Revision::Tag("--all".to_string()),
+            ),
+        ] {
+            let err = fetch(&cache, remote, revision).await.unwrap_err();
+            assert!(matches!(err, FetchError::GitFailed { .. }), "{err}");
+        }
+        // Nothing was run.
+        assert!(!cache.exists());
+    }
+}
@@ -2,6 +2,7 @@
 
 #![allow(dead_code, unused)]
 
+use super::repo_mapping::RepoMapping;
 use chumsky::prelude::*;
 use std::{borrow::Cow, fmt, ops::Deref};
 
@@ -32,6 +33,13 @@ impl<'a> AsRef<str> for ApparentRepo<'a> {
     }
 }
 
+// Lets maps keyed by apparent names be looked up by a name of any lifetime. Hashes agree, as `Cow<str>` hashes as `str`.
+impl<'a> std::borrow::Borrow<str> for ApparentRepo<'a> {
+    fn borrow(&self) -> &str {
+        self.as_str()
+    }
+}
+
 impl<'a> fmt::Display for ApparentRepo<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "@{}", self.0)
@@ -293,6 +301,17 @@ impl<'a> Label<'a, Repo<'a>> {
         })
     }
 
+    /// Converts this label to a canonical label with `mapping`, the repo mapping of the repository it appears in.
+    /// Returns `None` if the mapping has no repository by its apparent name.
+    #[allow(clippy::wrong_self_convention)]
+    pub fn to_canonical<'m>(self, mapping: &'m RepoMapping<'_>) -> Option<CanonicalLabel<'m>>
+    where
+        'a: 'm,
+    {
+        let label: Label<'m, Repo<'m>> = self;
+        label.into_canonical(|apparent| mapping.get(apparent))
+    }
+
     pub fn into_owned(self) -> Label<'static, Repo<'static>> {
         Label {
             repo: self.repo.into_owned(),
@@ -549,6 +568,16 @@ pub enum TargetKind<'a> {
     AllTargets,
 }
 
+impl<'a> TargetKind<'a> {
+    pub fn into_owned(self) -> TargetKind<'static> {
+        match self {
+            TargetKind::Exact(t) => TargetKind::Exact(Cow::Owned(t.into_owned())),
+            TargetKind::AllRules => TargetKind::AllRules,
+            TargetKind::AllTargets => TargetKind::AllTargets,
+        }
+    }
+}
+
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
 pub struct TargetPattern<'a, R = Repo<'a>> {
     pub repo: R,
@@ -558,6 +587,17 @@ pub struct TargetPattern<'a, R = Repo<'a>> {
     pub include_subpackages: bool,
 }
 
+impl<'a> TargetPattern<'a, Repo<'a>> {
+    pub fn into_owned(self) -> TargetPattern<'static, Repo<'static>> {
+        TargetPattern {
+            repo: self.repo.into_owned(),
+            package: Cow::Owned(self.package.into_owned()),
+            target_kind: self.target_kind.into_owned(),
+            include_subpackages: self.include_subpackages,
+        }
+    }
+}
+
 impl<'a, R> TargetPattern<'a, R> {
     pub fn matches(&self, label: &Label<'a, R>) -> bool
     where
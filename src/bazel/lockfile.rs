@@ -0,0 +1,248 @@
+//! Reading and writing `MODULE.bazel.lock`.
+//!
+//! Only the parts razel uses are modelled; everything else is preserved as-is when the lockfile is rewritten, so the
+//! same lockfile can be shared with Bazel.
+
+use crate::clock::Providers;
+use crate::error::ResolutionError;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+pub const LOCKFILE_NAME: &str = "MODULE.bazel.lock";
+
+/// The lockfile version razel writes, matching Bazel 8.
+pub const LOCKFILE_VERSION: u64 = 18;
+
+/// The value of `--lockfile_mode`: whether resolving the module graph `update`s the lockfile, fails with an `error`
+/// when the lockfile doesn't match, or leaves it alone (`off`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LockfileMode {
+    Off,
+    #[default]
+    Update,
+    Error,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Lockfile {
+    pub lock_file_version: u64,
+    /// The checksum of each registry file used to resolve the module graph, by URL, or `not found` for files a registry
+    /// didn't have. This pins each module to the registry it came from, see `registry::Registries`, and records the
+    /// versions of modules resolved from registries, as the URLs of their `MODULE.bazel` files.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub registry_file_hashes: BTreeMap<String, String>,
+    /// Extension id (e.g. `@@rules_go+//go:extensions.bzl%go_sdk`), then evaluation key (`general`, or a key like
+    /// `os:linux,arch:amd64` for extensions whose result depends on the host), to the evaluation result.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub module_extensions: BTreeMap<String, BTreeMap<String, ExtensionEvalResult>>,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+/// The recorded result of evaluating a module extension.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExtensionEvalResult {
+    #[serde(default)]
+    pub bzl_transitive_digest: String,
+    #[serde(default)]
+    pub usages_digest: String,
+    /// The repositories the extension generated, keyed by their name within the extension.
+    #[serde(default)]
+    pub generated_repo_specs: BTreeMap<String, RepoSpec>,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+/// A repository rule invocation, e.g. the `http_archive` a toolchain extension creates for an SDK.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RepoSpec {
+    /// The repository rule, e.g. `@@bazel_tools//tools/build_defs/repo:http.bzl%http_archive`.
+    pub repo_rule_id: String,
+    #[serde(default)]
+    pub attributes: serde_json::Map<String, serde_json::Value>,
+}
+
+impl RepoSpec {
+    /// The rule name without its defining file, e.g. `http_archive`.
+    pub fn rule_name(&self) -> &str {
+        self.repo_rule_id
+            .rsplit_once('%')
+            .map_or(self.repo_rule_id.as_str(), |(_, name)| name)
+    }
+
+    /// The download URLs of this repository, from the `url` and `urls` attributes.
+    pub fn urls(&self) -> Vec<&str> {
+        let url = self.attributes.get("url").and_then(|v| v.as_str());
+        let urls = self
+            .attributes
+            .get("urls")
+            .and_then(|v| v.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|v| v.as_str());
+        url.into_iter().chain(urls).collect()
+    }
+
+    /// The pinned checksum of the download, as an SRI `integrity` string or a hex `sha256`.
+    pub fn checksum(&self) -> Option<&str> {
+        ["integrity", "sha256"]
+            .iter()
+            .find_map(|attr| self.attributes.get(*attr).and_then(|v| v.as_str()))
+            .filter(|s| !s.is_empty())
+    }
+}
+
+impl Lockfile {
+    pub fn new() -> Self {
+        Self {
+            lock_file_version: LOCKFILE_VERSION,
+            ..Default::default()
+        }
+    }
+
+    /// Reads the lockfile in `workspace_root`, or returns an empty one if there is none yet.
+    pub async fn load(workspace_root: &Path) -> anyhow::Result<Self> {
+        let path = workspace_root.join(LOCKFILE_NAME);
+        match tokio::fs::read(&path).await {
+            Ok(data) => Self::parse(&data).map_err(|e| {
+                ResolutionError::InvalidLockfile {
+                    path,
+                    reason: e.to_string(),
+                }
+                .into()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        Ok(serde_json::from_slice(data)?)
+    }
+
+    /// Writes the lockfile into `workspace_root`, formatted the way Bazel formats it.
+    ///
+    /// The lockfile is written next to where it goes under a name from `providers`, then moved into place, so that a
+    /// concurrent reader (or Bazel) never sees it half written.
+    pub async fn save(&self, workspace_root: &Path, providers: &Providers) -> anyhow::Result<()> {
+        let mut data = serde_json::to_vec_pretty(self)?;
+        data.push(b'\n');
+        let tmp = workspace_root.join(providers.temp_name(LOCKFILE_NAME));
+        tokio::fs::write(&tmp, data).await?;
+        if let Err(e) = tokio::fs::rename(&tmp, workspace_root.join(LOCKFILE_NAME)).await {
+            let _ = tokio::fs::remove_file(&tmp).await;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// How the checksums of the registry files `fetched` differ from the recorded ones, one line per file. Recorded
+    /// files that weren't fetched aren't changes, as a command only fetches the files it needs.
+    pub fn registry_file_changes(&self, fetched: &BTreeMap<String, String>) -> Vec<String> {
+        fetched
+            .iter()
+            .filter_map(|(url, hash)| match self.registry_file_hashes.get(url) {
+                Some(recorded) if recorded == hash => None,
+                Some(_) => Some(format!("registry file {url} has changed")),
+                None => Some(format!("registry file {url} is new")),
+            })
+            .collect()
+    }
+
+    /// The recorded results for extension `id`, one per evaluation key.
+    pub fn extension(&self, id: &str) -> Option<&BTreeMap<String, ExtensionEvalResult>> {
+        self.module_extensions.get(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GO_SDK: &str = "@@rules_go+//go:extensions.bzl%go_sdk";
+
+    #[test]
+    fn test_lockfile_round_trip_preserves_unknown_fields() {
+        let json = r#"{
+          "lockFileVersion": 18,
+          "registryFileHashes": {"https://bcr.bazel.build/bazel_registry.json": "abc"},
+          "moduleExtensions": {
+            "@@rules_go+//go:extensions.bzl%go_sdk": {
+              "os:linux,arch:amd64": {
+                "bzlTransitiveDigest": "d1",
+                "usagesDigest": "d2",
+                "recordedFileInputs": {},
+                "generatedRepoSpecs": {
+                  "go_sdk": {
+                    "repoRuleId": "@@bazel_tools//tools/build_defs/repo:http.bzl%http_archive",
+                    "attributes": {"url": "https://example.com/go.tar.gz", "sha256": "00ff"}
+                  }
+                }
+              }
+            }
+          }
+        }"#;
+        let lockfile = Lockfile::parse(json.as_bytes()).unwrap();
+        let result = &lockfile.extension(GO_SDK).unwrap()["os:linux,arch:amd64"];
+        let spec = &result.generated_repo_specs["go_sdk"];
+        assert_eq!(spec.rule_name(), "http_archive");
+        assert_eq!(spec.urls(), vec!["https://example.com/go.tar.gz"]);
+        assert_eq!(spec.checksum(), Some("00ff"));
+
+        let written = serde_json::to_value(&lockfile).unwrap();
+        assert_eq!(
+            written["registryFileHashes"]["https://bcr.bazel.build/bazel_registry.json"],
+            "abc"
+        );
+        assert_eq!(
+            written["moduleExtensions"][GO_SDK]["os:linux,arch:amd64"]["recordedFileInputs"],
+            serde_json::json!({})
+        );
+    }
+
+    #[test]
+    fn test_registry_file_changes() {
+        let mut lockfile = Lockfile::new();
+        lockfile.registry_file_hashes = BTreeMap::from([
+            ("https://r/a".to_string(), "1234".to_string()),
+            ("https://r/b".to_string(), "not found".to_string()),
+            ("https://r/c".to_string(), "5678".to_string()),
+        ]);
+
+        let fetched = BTreeMap::from([
+            ("https://r/a".to_string(), "1234".to_string()),
+            ("https://r/b".to_string(), "abcd".to_string()),
+            ("https://r/d".to_string(), "ef01".to_string()),
+        ]);
+        assert_eq!(
+            lockfile.registry_file_changes(&fetched),
+            vec![
+                "registry file https://r/b has changed",
+                "registry file https://r/d is new",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_save() {
+        let tmp = assert_fs::TempDir::new().unwrap();
+        let mut lockfile = Lockfile::load(tmp.path()).await.unwrap();
+        assert_eq!(lockfile.lock_file_version, LOCKFILE_VERSION);
+
+        lockfile.registry_file_hashes = BTreeMap::from([(
+            "https://bcr.bazel.build/modules/zlib/1.3/MODULE.bazel".to_string(),
+            "00ff".to_string(),
+        )]);
+        let providers = Providers::fixed(std::time::UNIX_EPOCH, 1);
+        lockfile.save(tmp.path(), &providers).await.unwrap();
+        // Only the lockfile is left behind.
+        assert_eq!(std::fs::read_dir(tmp.path()).unwrap().count(), 1);
+
+        let reloaded = Lockfile::load(tmp.path()).await.unwrap();
+        assert_eq!(reloaded, lockfile);
+    }
+}
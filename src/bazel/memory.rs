@@ -0,0 +1,507 @@
+use crate::bazel::package::{Digest, DigestFunction, DirEntry, File, FileStore};
+use futures::future::{BoxFuture, FutureExt};
+use std::collections::{BTreeMap, HashMap};
+use std::io::ErrorKind;
+use std::sync::{Arc, RwLock};
+#[cfg(test)]
+use std::time::{Duration, SystemTime};
+
+/// How many symlinks a path may go through before it is considered a loop, as in Linux.
+#[cfg(test)]
+const MAX_SYMLINK_HOPS: usize = 40;
+
+#[derive(Debug, Clone)]
+enum Node {
+    File {
+        content: Vec<u8>,
+        #[cfg(test)]
+        modified: SystemTime,
+    },
+    Directory,
+    /// Relative to the directory containing the link, or to the root of the store if it starts with `/`.
+    #[cfg(test)]
+    Symlink(String),
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Every file, directory and symlink, by its path without a leading or trailing slash. The root is implicit.
+    nodes: BTreeMap<String, Node>,
+    /// Errors to fail any access to these paths with.
+    failures: HashMap<String, ErrorKind>,
+    /// Counts writes, so each one gets a later modification time than the last.
+    #[cfg(test)]
+    clock: u64,
+}
+
+/// A `FileStore` held in memory, for tests and for files fetched on their own, such as a registry's `MODULE.bazel`.
+///
+/// Behaves like a local directory tree: directories exist explicitly (parents of added files are created
+/// implicitly), symlinks are followed, and reading something missing or of the wrong kind fails with the same
+/// `ErrorKind` as the OS would. Errors can be injected for specific paths with `fail`.
+///
+/// Clones share their contents, so a test can keep a clone to change files between "builds" of a workspace that
+/// reads from another.
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryFileStore {
+    state: Arc<RwLock<State>>,
+}
+
+/// Splits `path` into its components, ignoring empty and `.` ones so that `a//b/`, `./a/b` and `a/b` are the same.
+fn components(path: &str) -> impl DoubleEndedIterator<Item = &str> {
+    path.split('/').filter(|c| !c.is_empty() && *c != ".")
+}
+
+fn normalize(path: &str) -> String {
+    components(path).collect::<Vec<_>>().join("/")
+}
+
+fn parent(path: &str) -> Option<&str> {
+    if path.is_empty() {
+        return None;
+    }
+    Some(path.rsplit_once('/').map_or("", |(parent, _)| parent))
+}
+
+impl State {
+    #[cfg(test)]
+    fn tick(&mut self) -> SystemTime {
+        self.clock += 1;
+        SystemTime::UNIX_EPOCH + Duration::from_secs(self.clock)
+    }
+
+    fn check_failure(&self, path: &str) -> std::io::Result<()> {
+        match self.failures.get(path) {
+            Some(kind) => Err(std::io::Error::new(
+                *kind,
+                format!("Injected error: {path}"),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Follows the symlinks in every component of `path`, returning the path of what it refers to.
+    fn resolve(&self, path: &str) -> std::io::Result<String> {
+        self.check_failure(&normalize(path))?;
+        let mut pending: Vec<String> = components(path).rev().map(String::from).collect();
+        let mut resolved: Vec<String> = Vec::new();
+        #[cfg(test)]
+        let mut hops = 0;
+        while let Some(component) = pending.pop() {
+            if component == ".." {
+                resolved.pop();
+                continue;
+            }
+            resolved.push(component);
+            let current = resolved.join("/");
+            self.check_failure(&current)?;
+            #[cfg(test)]
+            if let Some(Node::Symlink(target)) = self.nodes.get(&current) {
+                hops += 1;
+                if hops > MAX_SYMLINK_HOPS {
+                    return Err(std::io::Error::other(format!(
+                        "Too many levels of symbolic links: {path}"
+                    )));
+                }
+                resolved.pop();
+                if target.starts_with('/') {
+                    resolved.clear();
+                }
+                pending.extend(components(target).rev().map(String::from));
+            }
+        }
+        Ok(resolved.join("/"))
+    }
+
+    fn get(&self, path: &str) -> std::io::Result<(String, Option<&Node>)> {
+        let resolved = self.resolve(path)?;
+        let node = if resolved.is_empty() {
+            Some(&Node::Directory)
+        } else {
+            self.nodes.get(&resolved)
+        };
+        Ok((resolved, node))
+    }
+
+    /// Creates `path` and its parents as directories, as `mkdir -p` would.
+    fn create_dir_all(&mut self, path: &str) -> std::io::Result<()> {
+        let Some(parent) = parent(path) else {
+            return Ok(());
+        };
+        self.create_dir_all(parent)?;
+        match self.nodes.get(path) {
+            None => {
+                self.nodes.insert(path.to_string(), Node::Directory);
+                Ok(())
+            }
+            Some(Node::Directory) => Ok(()),
+            #[cfg(test)]
+            Some(Node::Symlink(_)) => Ok(()),
+            Some(Node::File { .. }) => Err(std::io::Error::new(
+                ErrorKind::NotADirectory,
+                format!("Not a directory: {path}"),
+            )),
+        }
+    }
+
+    fn insert(&mut self, path: &str, node: Node) -> std::io::Result<()> {
+        let path = normalize(path);
+        if let Some(parent) = parent(&path) {
+            self.create_dir_all(parent)?;
+        }
+        if let Some(Node::Directory) = self.nodes.get(&path) {
+            return Err(std::io::Error::new(
+                ErrorKind::IsADirectory,
+                format!("Is a directory: {path}"),
+            ));
+        }
+        self.nodes.insert(path, node);
+        Ok(())
+    }
+}
+
+impl InMemoryFileStore {
+    /// Creates a store holding `files`, by path.
+    pub fn new(files: HashMap<String, Vec<u8>>) -> Self {
+        let store = Self::default();
+        for (path, content) in files {
+            store.write(&path, content).unwrap();
+        }
+        store
+    }
+
+    /// Creates or replaces the file at `path`, creating its parent directories.
+    pub fn write(&self, path: &str, content: impl Into<Vec<u8>>) -> std::io::Result<()> {
+        let mut state = self.state.write().unwrap();
+        #[cfg(test)]
+        let modified = state.tick();
+        state.insert(
+            path,
+            Node::File {
+                content: content.into(),
+                #[cfg(test)]
+                modified,
+            },
+        )
+    }
+
+    #[cfg(test)]
+    /// Creates the directory `path`, and its parents.
+    pub fn create_dir(&self, path: &str) -> std::io::Result<()> {
+        self.state.write().unwrap().create_dir_all(&normalize(path))
+    }
+
+    #[cfg(test)]
+    /// Creates a symlink at `path` pointing at `target`, which is relative to the directory containing the link, or to
+    /// the root of the store if it starts with `/`. The target need not exist.
+    pub fn symlink(&self, path: &str, target: &str) -> std::io::Result<()> {
+        self.state
+            .write()
+            .unwrap()
+            .insert(path, Node::Symlink(target.to_string()))
+    }
+
+    #[cfg(test)]
+    /// Removes the file or symlink at `path`, or the directory and everything in it.
+    pub fn remove(&self, path: &str) -> std::io::Result<()> {
+        let path = normalize(path);
+        let mut state = self.state.write().unwrap();
+        if state.nodes.remove(&path).is_none() {
+            return Err(std::io::Error::new(
+                ErrorKind::NotFound,
+                format!("File not found: {path}"),
+            ));
+        }
+        let prefix = format!("{path}/");
+        state.nodes.retain(|p, _| !p.starts_with(&prefix));
+        Ok(())
+    }
+
+    #[cfg(test)]
+    /// Makes any access to `path` fail with an error of `kind`, until `clear_failure` is called.
+    pub fn fail(&self, path: &str, kind: ErrorKind) {
+        self.state
+            .write()
+            .unwrap()
+            .failures
+            .insert(normalize(path), kind);
+    }
+
+    #[cfg(test)]
+    pub fn clear_failure(&self, path: &str) {
+        self.state
+            .write()
+            .unwrap()
+            .failures
+            .remove(&normalize(path));
+    }
+
+    #[cfg(test)]
+    /// Sets the modification time of the file at `path`. Otherwise each write is one second later than the last.
+    pub fn set_modified(&self, path: &str, time: SystemTime) -> std::io::Result<()> {
+        let mut state = self.state.write().unwrap();
+        let resolved = state.resolve(path)?;
+        match state.nodes.get_mut(&resolved) {
+            Some(Node::File { modified, .. }) => {
+                *modified = time;
+                Ok(())
+            }
+            _ => Err(std::io::Error::new(
+                ErrorKind::NotFound,
+                format!("File not found: {path}"),
+            )),
+        }
+    }
+
+    #[cfg(test)]
+    /// The modification time of the file at `path`.
+    pub fn modified(&self, path: &str) -> std::io::Result<SystemTime> {
+        let state = self.state.read().unwrap();
+        match state.get(path)? {
+            (_, Some(Node::File { modified, .. })) => Ok(*modified),
+            (_, Some(_)) => Err(std::io::Error::new(
+                ErrorKind::IsADirectory,
+                format!("Not a file: {path}"),
+            )),
+            (_, None) => Err(std::io::Error::new(
+                ErrorKind::NotFound,
+                format!("File not found: {path}"),
+            )),
+        }
+    }
+}
+
+impl FileStore for InMemoryFileStore {
+    type File = InMemoryFile;
+
+    fn read_file(&self, path: &str) -> BoxFuture<'_, Result<Self::File, std::io::Error>> {
+        let path = path.to_string();
+        async move {
+            // The lookup is deliberately delayed until the future executes, since it represents the "expensive"
+            // read_file operation.
+            let state = self.state.read().unwrap();
+            match state.get(&path)? {
+                (_, Some(Node::File { content, .. })) => Ok(InMemoryFile {
+                    content: content.clone(),
+                }),
+                (_, Some(_)) => Err(std::io::Error::new(
+                    ErrorKind::IsADirectory,
+                    format!("Not a file: {path}"),
+                )),
+                (_, None) => Err(std::io::Error::new(
+                    ErrorKind::NotFound,
+                    format!("File not found: {path}"),
+                )),
+            }
+        }
+        .boxed()
+    }
+
+    fn read_dir(&self, path: &str) -> BoxFuture<'_, Result<Vec<DirEntry>, std::io::Error>> {
+        let path = path.to_string();
+        async move {
+            let state = self.state.read().unwrap();
+            let dir = match state.get(&path)? {
+                (dir, Some(Node::Directory)) => dir,
+                (_, Some(_)) => {
+                    return Err(std::io::Error::new(
+                        ErrorKind::NotADirectory,
+                        format!("Not a directory: {path}"),
+                    ));
+                }
+                (_, None) => {
+                    return Err(std::io::Error::new(
+                        ErrorKind::NotFound,
+                        format!("Directory not found: {path}"),
+                    ));
+                }
+            };
+            let prefix = if dir.is_empty() {
+                String::new()
+            } else {
+                format!("{dir}/")
+            };
+            let mut entries = Vec::new();
+            for (child, node) in state.nodes.range(prefix.clone()..) {
+                let Some(name) = child.strip_prefix(&prefix) else {
+                    break;
+                };
+                if name.is_empty() || name.contains('/') {
+                    continue;
+                }
+                // Like `stat`, symlinks are reported as what they point to. Dangling ones look like files.
+                let is_dir = match node {
+                    Node::Directory => true,
+                    Node::File { .. } => false,
+                    #[cfg(test)]
+                    Node::Symlink(_) => matches!(state.get(child), Ok((_, Some(Node::Directory)))),
+                };
+                entries.push(if is_dir {
+                    DirEntry::Directory(name.to_string())
+                } else {
+                    DirEntry::File(name.to_string())
+                });
+            }
+            Ok(entries)
+        }
+        .boxed()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct InMemoryFile {
+    content: Vec<u8>,
+}
+
+impl File for InMemoryFile {
+    type AsyncRead = std::io::Cursor<Vec<u8>>;
+
+    fn open(&self) -> BoxFuture<'_, Result<Self::AsyncRead, std::io::Error>> {
+        let content = self.content.clone();
+        async move { Ok(std::io::Cursor::new(content)) }.boxed()
+    }
+
+    fn digest(
+        &self,
+        digest_function: DigestFunction,
+    ) -> BoxFuture<'_, Result<Digest, std::io::Error>> {
+        async move { crate::bazel::digest::compute(digest_function, &self.content) }.boxed()
+    }
+
+    fn is_executable(&self) -> BoxFuture<'_, Result<bool, std::io::Error>> {
+        async move { Ok(false) }.boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(entries: Vec<DirEntry>) -> Vec<String> {
+        entries
+            .into_iter()
+            .map(|e| match e {
+                DirEntry::File(name) => name,
+                DirEntry::Directory(name) => format!("{name}/"),
+            })
+            .collect()
+    }
+
+    async fn read(store: &InMemoryFileStore, path: &str) -> std::io::Result<String> {
+        use tokio::io::AsyncReadExt;
+
+        let mut content = String::new();
+        store
+            .read_file(path)
+            .await?
+            .open()
+            .await?
+            .read_to_string(&mut content)
+            .await?;
+        Ok(content)
+    }
+
+    #[tokio::test]
+    async fn test_read_dir() {
+        let store = InMemoryFileStore::new(HashMap::from([
+            ("a/b".to_string(), vec![]),
+            ("a/c".to_string(), vec![]),
+            ("a/d/e".to_string(), vec![]),
+            ("ab/f".to_string(), vec![]),
+            ("f".to_string(), vec![]),
+        ]));
+        store.create_dir("a/empty").unwrap();
+
+        assert_eq!(names(store.read_dir("").await.unwrap()), ["a/", "ab/", "f"]);
+        for path in ["a", "a/", "./a", "a//"] {
+            assert_eq!(
+                names(store.read_dir(path).await.unwrap()),
+                ["b", "c", "d/", "empty/"],
+                "Failed for path: {path}"
+            );
+        }
+        assert!(store.read_dir("a/empty").await.unwrap().is_empty());
+
+        let err = store.read_dir("nonexistent").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        let err = store.read_dir("f").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotADirectory);
+        let err = store.read_file("a").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::IsADirectory);
+        assert!(store.write("f/g", "").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_symlinks() {
+        let store = InMemoryFileStore::default();
+        store.write("pkg/real.txt", "content").unwrap();
+        store.symlink("pkg/link.txt", "real.txt").unwrap();
+        store.symlink("alias", "/pkg").unwrap();
+        store.symlink("up", "pkg/../pkg/link.txt").unwrap();
+        store.symlink("dangling", "missing").unwrap();
+        store.symlink("loop", "loop").unwrap();
+
+        assert_eq!(read(&store, "pkg/link.txt").await.unwrap(), "content");
+        assert_eq!(read(&store, "alias/link.txt").await.unwrap(), "content");
+        assert_eq!(read(&store, "up").await.unwrap(), "content");
+        assert_eq!(
+            names(store.read_dir("").await.unwrap()),
+            ["alias/", "dangling", "loop", "pkg/", "up"]
+        );
+        assert_eq!(
+            names(store.read_dir("alias").await.unwrap()),
+            ["link.txt", "real.txt"]
+        );
+        let err = read(&store, "dangling").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        let err = read(&store, "loop").await.unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Too many levels of symbolic links")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mutation_and_failures() {
+        let store = InMemoryFileStore::new(HashMap::from([("BUILD".to_string(), b"old".to_vec())]));
+        let view = store.clone();
+        let before = view.modified("BUILD").unwrap();
+
+        store.write("BUILD", "new").unwrap();
+        assert_eq!(read(&view, "BUILD").await.unwrap(), "new");
+        assert!(view.modified("BUILD").unwrap() > before);
+        store.set_modified("BUILD", before).unwrap();
+        assert_eq!(view.modified("BUILD").unwrap(), before);
+
+        store.fail("BUILD", ErrorKind::PermissionDenied);
+        let err = read(&view, "BUILD").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        store.fail("", ErrorKind::PermissionDenied);
+        let err = view.read_dir("").await.map(|_| ()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        store.clear_failure("BUILD");
+        store.clear_failure("");
+
+        store.remove("BUILD").unwrap();
+        let err = read(&view, "BUILD").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_digest() {
+        let store = InMemoryFileStore::default();
+        store.write("hello", "hello").unwrap();
+        let digest = store
+            .read_file("hello")
+            .await
+            .unwrap()
+            .digest(DigestFunction::Sha256)
+            .await
+            .unwrap();
+        assert_eq!(
+            digest.hash,
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        assert_eq!(digest.size_bytes, 5);
+    }
+}
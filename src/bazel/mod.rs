@@ -1,19 +1,383 @@
+pub(crate) mod archive;
 pub(crate) mod bzlmod;
+pub(crate) mod digest;
+pub(crate) mod download;
+pub(crate) mod git;
 pub(crate) mod label;
+pub(crate) mod lockfile;
+pub(crate) mod memory;
+pub(crate) mod module_graph;
+pub(crate) mod mvs;
 pub(crate) mod package;
+pub(crate) mod patch;
+pub(crate) mod policy;
+pub(crate) mod rc;
+pub(crate) mod registry;
 pub(crate) mod repo;
+pub(crate) mod repo_mapping;
 pub(crate) mod rule;
+pub(crate) mod visibility;
+
+/// The `--compilation_mode` (`-c`) a build is configured for.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Hash, clap::ValueEnum, derive_more::Display,
+)]
+pub enum CompilationMode {
+    #[default]
+    #[display("fastbuild")]
+    Fastbuild,
+    #[display("opt")]
+    Opt,
+    #[display("dbg")]
+    Dbg,
+}
+
+/// The value of `--experimental_output_paths`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputPaths {
+    /// Output paths include the configuration, e.g. `bazel-out/k8-opt/bin`.
+    #[default]
+    Off,
+    /// Actions that support it see `bazel-out/cfg/bin` instead. Not supported yet.
+    Strip,
+}
 
 #[derive(Debug)]
 #[allow(dead_code)]
 pub(crate) struct Configuration {
     pub ignore_dev_dependency: bool,
+    pub compilation_mode: CompilationMode,
+    /// The target CPU, named the way Bazel names it, e.g. `k8` or `darwin_arm64`.
+    pub cpu: String,
+    /// Carry on after errors where possible, see `Workspace::defer_error`.
+    pub keep_going: bool,
+    /// Print what each query took, see `query::QueryStats`.
+    pub query_stats: bool,
+    /// How many packages to load, repositories to fetch, or actions to run concurrently.
+    pub jobs: usize,
+    /// Directory (relative to the workspace root) holding vendored external repositories.
+    pub vendor_dir: Option<std::path::PathBuf>,
+    /// Registries to look up modules in, in order, see `registry::Registries`. Empty for the Bazel Central Registry.
+    pub registries: Vec<String>,
+    /// Yanked module versions to use anyway, see `registry::Registries::check_yanked`.
+    pub allow_yanked_versions: Vec<String>,
+    /// Content-addressed cache for downloads, see `download::Downloader`.
+    pub repository_cache: Option<std::path::PathBuf>,
+    /// Directories of archives to use instead of downloading them, see `download::Downloader::with_distdirs`.
+    pub distdir: Vec<std::path::PathBuf>,
+    /// How many times `download::Downloader` retries a URL after a transient failure.
+    pub repository_downloader_retries: u32,
+    /// Whether `download::Downloader` refuses downloads without a checksum.
+    pub repository_disable_download: bool,
+    /// Keep in-memory state around until the command finishes. When false, caches are leaked and the process exits
+    /// without tearing them down, which is all a throwaway CI runner needs.
+    pub keep_state_after_build: bool,
+    /// `--output_base`, if given; otherwise see `Configuration::output_base`.
+    pub output_base: Option<std::path::PathBuf>,
+    /// Directory holding the output bases of all workspaces, see `default_output_user_root`.
+    pub output_user_root: std::path::PathBuf,
+    /// File to write counters to after each command, see `crate::metrics`.
+    pub metrics_textfile: Option<std::path::PathBuf>,
+    /// File to write build events to, see `crate::bep`.
+    pub build_event_json_file: Option<std::path::PathBuf>,
+    /// File to explain why actions were executed in, see `crate::explain`.
+    pub explain: Option<std::path::PathBuf>,
+    /// Say which inputs changed and how command lines did in the `--explain` file.
+    pub verbose_explanations: bool,
+    /// Instrument tests for coverage. Implied by `razel coverage`; nothing runs tests yet.
+    pub collect_code_coverage: bool,
+    /// The values of `--define`.
+    pub defines: std::collections::BTreeMap<String, String>,
+    /// What to do about the root module's `bazel_dep`s whose versions weren't selected, see
+    /// `Workspace::check_direct_dependencies`.
+    pub check_direct_dependencies: mvs::CheckDirectDependencies,
+    /// Whether resolving the module graph updates or checks the lockfile, see `Workspace::update_lockfile`.
+    pub lockfile_mode: lockfile::LockfileMode,
+    /// Only let repositories see the repositories they declare, see `Workspace::resolve_repo`.
+    pub strict_repo_visibility: bool,
+    /// Whether BUILD and .bzl evaluation may read files outside of what they load and contain, see `sandbox`.
+    pub loading_sandbox: crate::starlark::sandbox::LoadingSandbox,
+    /// How much memory and how many statements each Starlark evaluation may use.
+    pub eval_limits: crate::starlark::limits::EvalLimits,
 }
 
 impl Configuration {
     pub(crate) fn from_flags(cli: &crate::Cli) -> Self {
         Self {
             ignore_dev_dependency: cli.ignore_dev_dependency,
+            compilation_mode: cli.compilation_mode,
+            cpu: host_cpu().to_string(),
+            keep_going: cli.keep_going,
+            query_stats: cli.experimental_query_stats,
+            jobs: cli.jobs.unwrap_or_else(default_jobs),
+            vendor_dir: cli.vendor_dir.clone(),
+            registries: cli.registry.clone(),
+            allow_yanked_versions: cli.allow_yanked_versions.clone(),
+            repository_cache: cli.repository_cache.clone(),
+            distdir: cli.distdir.clone(),
+            repository_downloader_retries: cli.experimental_repository_downloader_retries,
+            repository_disable_download: cli.experimental_repository_disable_download,
+            keep_state_after_build: cli.keep_state_after_build,
+            output_base: cli.output_base.clone(),
+            output_user_root: cli
+                .output_user_root
+                .clone()
+                .unwrap_or_else(default_output_user_root),
+            metrics_textfile: cli.metrics_textfile.clone(),
+            build_event_json_file: cli.build_event_json_file.clone(),
+            explain: cli.explain.clone(),
+            verbose_explanations: cli.verbose_explanations,
+            collect_code_coverage: cli.collect_code_coverage
+                || matches!(cli.command, crate::Commands::Coverage { .. }),
+            defines: cli.define.iter().cloned().collect(),
+            check_direct_dependencies: cli.check_direct_dependencies,
+            lockfile_mode: cli.lockfile_mode,
+            strict_repo_visibility: cli.strict_repo_visibility,
+            loading_sandbox: cli.loading_sandbox,
+            eval_limits: crate::starlark::limits::EvalLimits::new(
+                cli.max_starlark_heap_mb,
+                cli.max_starlark_steps,
+            ),
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl Configuration {
+    /// The directory all output for the workspace at `workspace_root` goes in.
+    ///
+    /// Unless set explicitly, each workspace gets its own directory under the output user root, named after the MD5
+    /// of its path as in Bazel, so that separate checkouts never share outputs.
+    pub fn output_base(&self, workspace_root: &std::path::Path) -> std::path::PathBuf {
+        use md5::Digest as _;
+
+        if let Some(output_base) = &self.output_base {
+            return output_base.clone();
         }
+        let digest = md5::Md5::digest(workspace_root.as_os_str().as_encoded_bytes());
+        self.output_user_root.join(digest::to_hex(&digest))
+    }
+
+    /// Identifies this configuration in output paths, e.g. `k8-opt`.
+    ///
+    /// Builds in different configurations write to different directories, so they don't clobber each other's outputs.
+    pub fn output_dir_mnemonic(&self) -> String {
+        format!("{}-{}", self.cpu, self.compilation_mode)
+    }
+
+    /// Directory for the outputs of rules, relative to the execution root, e.g. `bazel-out/k8-opt/bin`.
+    pub fn bin_dir(&self) -> String {
+        format!("bazel-out/{}/bin", self.output_dir_mnemonic())
+    }
+
+    /// Directory for generated sources. Like Bazel, this is the same as `bin_dir`.
+    pub fn genfiles_dir(&self) -> String {
+        self.bin_dir()
+    }
+
+    /// Directory for test results and logs, relative to the execution root.
+    pub fn testlogs_dir(&self) -> String {
+        format!("bazel-out/{}/testlogs", self.output_dir_mnemonic())
+    }
+
+    /// The predefined "Make" variables that describe this configuration, which `$(...)` in a `genrule`'s `cmd` may
+    /// refer to, see `crate::aquery`. Rules aren't analyzed yet, so there's no `ctx.var` to see them through.
+    pub fn make_variables(&self) -> std::collections::BTreeMap<&'static str, String> {
+        std::collections::BTreeMap::from([
+            ("BINDIR", self.bin_dir()),
+            ("COMPILATION_MODE", self.compilation_mode.to_string()),
+            ("GENDIR", self.genfiles_dir()),
+            ("TARGET_CPU", self.cpu.clone()),
+        ])
+    }
+
+    /// The constraint values of the platform being built for, derived from the CPU the way Bazel maps legacy `--cpu`
+    /// values to platforms, e.g. `@platforms//os:linux` and `@platforms//cpu:x86_64` for `k8`.
+    pub fn constraint_values(&self) -> Vec<String> {
+        cpu_constraint_values(&self.cpu)
+    }
+}
+
+/// The constraint values of the platform with the legacy `--cpu` value `cpu`, see `Configuration::constraint_values`.
+pub(crate) fn cpu_constraint_values(cpu: &str) -> Vec<String> {
+    let (os, cpu) = match cpu {
+        "k8" => ("linux", "x86_64"),
+        "piii" => ("linux", "x86_32"),
+        "darwin_x86_64" => ("osx", "x86_64"),
+        "darwin_arm64" => ("osx", "aarch64"),
+        "x64_windows" => ("windows", "x86_64"),
+        "arm64_windows" => ("windows", "aarch64"),
+        os @ ("freebsd" | "openbsd") => (os, "x86_64"),
+        cpu => ("linux", cpu),
+    };
+    vec![
+        format!("@platforms//os:{os}"),
+        format!("@platforms//cpu:{cpu}"),
+    ]
+}
+
+/// The default for `--output_user_root`: `razel/_razel_$USER` in the user's cache directory.
+pub(crate) fn default_output_user_root() -> std::path::PathBuf {
+    let cache_dir = std::env::var_os("XDG_CACHE_HOME")
+        .map(std::path::PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| std::path::Path::new(&home).join(".cache")))
+        .unwrap_or_else(std::env::temp_dir);
+    let user = std::env::var("USER").unwrap_or_else(|_| "unknown".to_string());
+    cache_dir.join("razel").join(format!("_razel_{user}"))
+}
+
+/// The host CPU, using Bazel's legacy `--cpu` names.
+pub(crate) fn host_cpu() -> &'static str {
+    match (std::env::consts::OS, std::env::consts::ARCH) {
+        ("linux", "x86_64") => "k8",
+        ("linux", "x86") => "piii",
+        ("linux", "powerpc64") => "ppc",
+        ("linux", "s390x") => "s390x",
+        ("macos", "x86_64") => "darwin_x86_64",
+        ("macos", "aarch64") => "darwin_arm64",
+        ("windows", "x86_64") => "x64_windows",
+        ("windows", "aarch64") => "arm64_windows",
+        ("freebsd", _) => "freebsd",
+        ("openbsd", _) => "openbsd",
+        (_, arch) => arch,
+    }
+}
+
+/// The default for `--jobs`: one job per available CPU.
+pub(crate) fn default_jobs() -> usize {
+    std::thread::available_parallelism().map_or(1, usize::from)
+}
+
+/// Parses a `--define` value, `NAME=VALUE`.
+pub(crate) fn parse_define(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_string(), value.to_string())),
+        _ => Err(format!("invalid --define value {s:?}, expected NAME=VALUE")),
+    }
+}
+
+/// Parses a `--jobs` value: a positive integer, `auto`, or `HOST_CPUS` optionally scaled like `HOST_CPUS*0.5`.
+pub(crate) fn parse_jobs(s: &str) -> Result<usize, String> {
+    let jobs = match s.strip_prefix("HOST_CPUS") {
+        Some("") => default_jobs(),
+        Some(rest) => {
+            let factor: f64 = rest
+                .strip_prefix('*')
+                .and_then(|f| f.parse().ok())
+                .ok_or_else(|| {
+                    format!("invalid --jobs value {s:?}, expected e.g. HOST_CPUS*0.5")
+                })?;
+            (default_jobs() as f64 * factor).ceil() as usize
+        }
+        None if s == "auto" => default_jobs(),
+        None => s.parse().map_err(|_| {
+            format!("invalid --jobs value {s:?}, expected an integer, auto or HOST_CPUS")
+        })?,
+    };
+    if jobs == 0 {
+        return Err(format!("--jobs must be at least 1, got {s:?}"));
+    }
+    Ok(jobs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(compilation_mode: CompilationMode) -> Configuration {
+        Configuration {
+            ignore_dev_dependency: false,
+            compilation_mode,
+            cpu: "k8".to_string(),
+            keep_going: false,
+            query_stats: false,
+            jobs: 1,
+            vendor_dir: None,
+            registries: Vec::new(),
+            allow_yanked_versions: Vec::new(),
+            repository_cache: None,
+            distdir: Vec::new(),
+            repository_downloader_retries: 5,
+            repository_disable_download: false,
+            keep_state_after_build: true,
+            output_base: None,
+            output_user_root: "/home/me/.cache/razel/_razel_me".into(),
+            metrics_textfile: None,
+            build_event_json_file: None,
+            explain: None,
+            verbose_explanations: false,
+            collect_code_coverage: false,
+            defines: std::collections::BTreeMap::new(),
+            check_direct_dependencies: mvs::CheckDirectDependencies::default(),
+            lockfile_mode: lockfile::LockfileMode::default(),
+            strict_repo_visibility: true,
+            loading_sandbox: crate::starlark::sandbox::LoadingSandbox::default(),
+            eval_limits: crate::starlark::limits::EvalLimits::default(),
+        }
+    }
+
+    #[test]
+    fn test_output_dirs() {
+        let opt = config(CompilationMode::Opt);
+        assert_eq!(opt.bin_dir(), "bazel-out/k8-opt/bin");
+        assert_eq!(opt.testlogs_dir(), "bazel-out/k8-opt/testlogs");
+        assert_eq!(opt.make_variables()["COMPILATION_MODE"], "opt");
+
+        // Different modes must not share output directories.
+        let fastbuild = config(CompilationMode::default());
+        assert_eq!(fastbuild.bin_dir(), "bazel-out/k8-fastbuild/bin");
+        assert_ne!(config(CompilationMode::Dbg).bin_dir(), fastbuild.bin_dir());
+    }
+
+    #[test]
+    fn test_parse_define_and_constraints() {
+        assert_eq!(
+            parse_define("foo=bar=baz"),
+            Ok(("foo".to_string(), "bar=baz".to_string()))
+        );
+        assert_eq!(parse_define("foo="), Ok(("foo".to_string(), String::new())));
+        assert!(parse_define("=bar").is_err());
+        assert!(parse_define("foo").is_err());
+
+        assert_eq!(
+            config(CompilationMode::Opt).constraint_values(),
+            ["@platforms//os:linux", "@platforms//cpu:x86_64"]
+        );
+    }
+
+    #[test]
+    fn test_parse_jobs() {
+        assert_eq!(parse_jobs("4"), Ok(4));
+        assert_eq!(parse_jobs("auto"), Ok(default_jobs()));
+        assert_eq!(parse_jobs("HOST_CPUS"), Ok(default_jobs()));
+        assert_eq!(parse_jobs("HOST_CPUS*2"), Ok(default_jobs() * 2));
+        assert!(parse_jobs("HOST_CPUS*0.0001").unwrap() >= 1);
+        assert!(parse_jobs("0").is_err());
+        assert!(parse_jobs("HOST_CPUS*0").is_err());
+        assert!(parse_jobs("many").is_err());
+        assert!(parse_jobs("HOST_CPUSx").is_err());
+    }
+
+    #[test]
+    fn test_output_base() {
+        let mut config = config(CompilationMode::Fastbuild);
+        let root = std::path::Path::new("/src/project");
+        let output_base = config.output_base(root);
+        assert_eq!(
+            output_base.parent().unwrap(),
+            std::path::Path::new("/home/me/.cache/razel/_razel_me")
+        );
+        // md5("/src/project")
+        assert_eq!(
+            output_base.file_name().unwrap(),
+            "023a715c6fdc9adc10768e9695ea4c12"
+        );
+        assert_ne!(
+            config.output_base(std::path::Path::new("/src/other")),
+            output_base
+        );
+
+        config.output_base = Some("/tmp/out".into());
+        assert_eq!(config.output_base(root), std::path::Path::new("/tmp/out"));
     }
 }
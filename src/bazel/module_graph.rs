@@ -0,0 +1,427 @@
+//! Questions about the resolved module graph: where it has cycles, how the root module reaches a module, and who asks
+//! for which version of it.
+//!
+//! Modules are named as Bazel names them in `mod` output, `<name>@<version>`, with the root module as `<root>`.
+
+use super::mvs::{self, compare_versions};
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
+use std::fmt;
+
+/// The name of the root module in the graph.
+pub const ROOT: &str = "<root>";
+
+/// The module and version of the repository of a module, `<name>+<version>`, or `None` for other repositories, such as
+/// those generated by module extensions.
+pub fn module_of_repo(canonical: &str) -> Option<(&str, &str)> {
+    canonical
+        .split_once('+')
+        .filter(|(_, version)| !version.contains('+'))
+}
+
+/// The repository of the module whose extension generated the repository `canonical`, named
+/// `<module's repository>+<extension name>+<name>`, or `None` for other repositories.
+pub fn extension_host_of_repo(canonical: &str) -> Option<&str> {
+    let mut parts = canonical.rsplitn(3, '+');
+    let (_name, _extension) = (parts.next()?, parts.next()?);
+    parts.next()
+}
+
+/// `module@version`, the key of a module in the graph.
+pub fn module_key(module: &str, version: &str) -> String {
+    format!("{module}@{version}")
+}
+
+fn name_of(key: &str) -> &str {
+    key.split_once('@').map_or(key, |(name, _)| name)
+}
+
+fn version_of(key: &str) -> &str {
+    key.split_once('@').map_or("", |(_, version)| version)
+}
+
+/// The modules the root module transitively depends on, and which each depends on directly.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModuleGraph {
+    deps: BTreeMap<String, BTreeSet<String>>,
+}
+
+/// A module that asks for some version of another one, for `ModuleGraph::requesters`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Requester {
+    pub module: String,
+    pub version: String,
+    /// Whether this is the highest version asked for, which is the one selected.
+    pub selected: bool,
+}
+
+/// Two versions of a module at different `compatibility_level`s, where the module asking for the one not selected
+/// doesn't accept the selected one's level, for `ModuleGraph::compatibility_conflict`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompatibilityConflict {
+    pub selected: String,
+    pub selected_level: u32,
+    /// A path from the root module to the selected version.
+    pub selected_path: Vec<String>,
+    pub conflicting: String,
+    pub conflicting_level: u32,
+    /// A path from the root module to the other version, through the module asking for it.
+    pub conflicting_path: Vec<String>,
+}
+
+impl fmt::Display for CompatibilityConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Paths end with the module asking for a version, then that version.
+        let selected_by = &self.selected_path[self.selected_path.len() - 2];
+        let requester = &self.conflicting_path[self.conflicting_path.len() - 2];
+        write!(
+            f,
+            "{selected_by} depends on {} with compatibility level {}, but {requester} depends on {} with \
+             compatibility level {}, which is different.\n  {}\n  {}\nRaise the max_compatibility_level of \
+             {requester}'s bazel_dep, or use a multiple_version_override",
+            self.selected,
+            self.selected_level,
+            self.conflicting,
+            self.conflicting_level,
+            self.selected_path.join(" -> "),
+            self.conflicting_path.join(" -> "),
+        )
+    }
+}
+
+impl ModuleGraph {
+    pub fn add_module(&mut self, module: String) {
+        self.deps.entry(module).or_default();
+    }
+
+    pub fn add_dep(&mut self, from: String, to: String) {
+        self.add_module(to.clone());
+        self.deps.entry(from).or_default().insert(to);
+    }
+
+    /// Every module in the graph, including the root.
+    pub fn modules(&self) -> impl Iterator<Item = &str> {
+        self.deps.keys().map(String::as_str)
+    }
+
+    /// The cycles in the graph, one for each set of modules that all depend on each other, as the shortest path from
+    /// the alphabetically first of them back to itself. Bzlmod allows cycles, but they make versions hard to reason
+    /// about.
+    pub fn cycles(&self) -> Vec<Vec<String>> {
+        let mut reported = HashSet::new();
+        let mut cycles = Vec::new();
+        for start in self.deps.keys() {
+            if reported.contains(start) {
+                continue;
+            }
+            if let Some(cycle) = self.shortest_cycle(start) {
+                reported.extend(self.strongly_connected(start));
+                cycles.push(cycle);
+            }
+        }
+        cycles
+    }
+
+    /// The modules that both reach `start` and are reached from it.
+    fn strongly_connected(&self, start: &str) -> BTreeSet<String> {
+        self.reachable(start)
+            .into_iter()
+            .filter(|module| self.reachable(module).contains(start))
+            .collect()
+    }
+
+    fn reachable(&self, start: &str) -> BTreeSet<String> {
+        let mut seen = BTreeSet::from([start.to_string()]);
+        let mut pending = vec![start];
+        while let Some(module) = pending.pop() {
+            for dep in self.deps.get(module).into_iter().flatten() {
+                if seen.insert(dep.clone()) {
+                    pending.push(dep);
+                }
+            }
+        }
+        seen
+    }
+
+    /// The shortest path from `start` back to itself, by breadth first search visiting dependencies in order of name.
+    fn shortest_cycle(&self, start: &str) -> Option<Vec<String>> {
+        let mut parents: BTreeMap<&str, &str> = BTreeMap::new();
+        let mut queue = VecDeque::from([start]);
+        while let Some(module) = queue.pop_front() {
+            for dep in self.deps.get(module).into_iter().flatten() {
+                if dep == start {
+                    let mut path = vec![dep.clone(), module.to_string()];
+                    let mut at = module;
+                    while let Some(&parent) = parents.get(at) {
+                        path.push(parent.to_string());
+                        at = parent;
+                    }
+                    path.reverse();
+                    return Some(path);
+                }
+                if !parents.contains_key(dep.as_str()) {
+                    parents.insert(dep, module);
+                    queue.push_back(dep);
+                }
+            }
+        }
+        None
+    }
+
+    /// Every path from the root module to any version of `module` that doesn't go through a module twice, in order.
+    pub fn all_paths(&self, module: &str) -> Vec<Vec<String>> {
+        let mut paths = Vec::new();
+        let mut path = vec![ROOT.to_string()];
+        self.extend_paths(module, &mut path, &mut paths);
+        paths
+    }
+
+    fn extend_paths(&self, module: &str, path: &mut Vec<String>, paths: &mut Vec<Vec<String>>) {
+        let last = path.last().expect("paths start at the root").clone();
+        for dep in self.deps.get(&last).into_iter().flatten() {
+            if path.contains(dep) {
+                continue;
+            }
+            path.push(dep.clone());
+            if name_of(dep) == module {
+                paths.push(path.clone());
+            } else {
+                self.extend_paths(module, path, paths);
+            }
+            path.pop();
+        }
+    }
+
+    /// The version minimal version selection picks for each module the root module still depends on once those are
+    /// selected, by name. Modules that only an unselected version of another module asks for are left out, as Bazel
+    /// prunes them.
+    pub fn resolve(&self) -> BTreeMap<String, String> {
+        let selected = mvs::select(self.modules().filter_map(|m| m.split_once('@')));
+        let mut resolved = BTreeMap::new();
+        let mut queue = VecDeque::from([ROOT.to_string()]);
+        while let Some(module) = queue.pop_front() {
+            for dep in self.deps.get(&module).into_iter().flatten() {
+                let name = name_of(dep);
+                let Some(version) = selected.get(name) else {
+                    continue;
+                };
+                if resolved.insert(name.to_string(), version.clone()).is_none() {
+                    queue.push_back(module_key(name, version));
+                }
+            }
+        }
+        resolved
+    }
+
+    /// The first module asked for at a version whose `compatibility_level` differs from that of the version selected,
+    /// by a module that doesn't accept the selected version's level: one between the level of the version it asks
+    /// for and its `max_compatibility_level`, if any. `levels` has the level of each module, and `max_levels` the
+    /// `max_compatibility_level`s, by the key of the module that gave them and the name of the dependency. Modules
+    /// that are `exempt`, such as those with a `multiple_version_override`, and modules whose level isn't known aren't
+    /// checked.
+    pub fn compatibility_conflict(
+        &self,
+        levels: &BTreeMap<String, u32>,
+        max_levels: &BTreeMap<(String, String), u32>,
+        exempt: impl Fn(&str) -> bool,
+    ) -> Option<CompatibilityConflict> {
+        let names: BTreeSet<_> = self.modules().filter(|m| *m != ROOT).map(name_of).collect();
+        for name in names.into_iter().filter(|name| !exempt(name)) {
+            let requesters = self.requesters(name);
+            let Some(selected) = requesters.first().map(|r| module_key(name, &r.version)) else {
+                continue;
+            };
+            let Some(&selected_level) = levels.get(&selected) else {
+                continue;
+            };
+            for requester in requesters.iter().filter(|r| !r.selected) {
+                let conflicting = module_key(name, &requester.version);
+                let Some(&level) = levels.get(&conflicting) else {
+                    continue;
+                };
+                let max = max_levels
+                    .get(&(requester.module.clone(), name.to_string()))
+                    .map_or(level, |max| (*max).max(level));
+                if (level..=max).contains(&selected_level) {
+                    continue;
+                }
+                let paths = self.all_paths(name);
+                let path_to = |key: &str, through: Option<&str>| {
+                    paths
+                        .iter()
+                        .find(|path| {
+                            path.last().is_some_and(|last| last == key)
+                                && through.is_none_or(|m| path[path.len() - 2] == m)
+                        })
+                        .cloned()
+                        .unwrap_or_else(|| vec![ROOT.to_string(), key.to_string()])
+                };
+                return Some(CompatibilityConflict {
+                    selected_path: path_to(&selected, None),
+                    selected,
+                    selected_level,
+                    conflicting_path: path_to(&conflicting, Some(&requester.module)),
+                    conflicting,
+                    conflicting_level: level,
+                });
+            }
+        }
+        None
+    }
+
+    /// The modules that depend on some version of `module` directly, by the version they ask for and then by name.
+    /// The requesters of the highest version, which minimal version selection picks, are marked `selected`.
+    pub fn requesters(&self, module: &str) -> Vec<Requester> {
+        let mut requesters: Vec<_> = self
+            .deps
+            .iter()
+            .flat_map(|(from, deps)| {
+                deps.iter()
+                    .filter(|dep| name_of(dep) == module)
+                    .map(move |dep| Requester {
+                        module: from.clone(),
+                        version: version_of(dep).to_string(),
+                        selected: false,
+                    })
+            })
+            .collect();
+        requesters.sort_by(|a, b| {
+            compare_versions(&b.version, &a.version).then_with(|| a.module.cmp(&b.module))
+        });
+        if let Some(highest) = requesters.first().map(|r| r.version.clone()) {
+            for requester in &mut requesters {
+                requester.selected = compare_versions(&requester.version, &highest).is_eq();
+            }
+        }
+        requesters
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph(edges: &[(&str, &str)]) -> ModuleGraph {
+        let mut graph = ModuleGraph::default();
+        for (from, to) in edges {
+            graph.add_dep(from.to_string(), to.to_string());
+        }
+        graph
+    }
+
+    #[test]
+    fn test_resolve() {
+        let graph = graph(&[
+            (ROOT, "a@1.0"),
+            (ROOT, "b@1.0"),
+            ("a@1.0", "c@1.0"),
+            ("b@1.0", "a@1.1"),
+            ("a@1.1", "d@2.0"),
+            ("d@2.0", "e@1.0"),
+        ]);
+        // c is only needed by a@1.0, which a@1.1 replaces.
+        assert_eq!(
+            graph.resolve(),
+            BTreeMap::from(
+                [("a", "1.1"), ("b", "1.0"), ("d", "2.0"), ("e", "1.0")]
+                    .map(|(m, v)| (m.to_string(), v.to_string()))
+            )
+        );
+    }
+
+    #[test]
+    fn test_cycles() {
+        let graph = graph(&[
+            (ROOT, "b@1"),
+            ("b@1", "c@1"),
+            ("c@1", "a@1"),
+            ("a@1", "b@1"),
+            ("c@1", "d@1"),
+            ("d@1", "d@1"),
+        ]);
+        assert_eq!(
+            graph.cycles(),
+            [vec!["a@1", "b@1", "c@1", "a@1"], vec!["d@1", "d@1"],]
+        );
+        assert!(
+            self::graph(&[(ROOT, "a@1"), ("a@1", "b@1")])
+                .cycles()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_all_paths_and_requesters() {
+        let graph = graph(&[
+            (ROOT, "a@1"),
+            (ROOT, "b@1"),
+            (ROOT, "lib@1.0"),
+            ("a@1", "lib@1.2"),
+            ("b@1", "a@1"),
+            ("b@1", "lib@1.2"),
+        ]);
+        assert_eq!(
+            graph.all_paths("lib"),
+            [
+                vec![ROOT, "a@1", "lib@1.2"],
+                vec![ROOT, "b@1", "a@1", "lib@1.2"],
+                vec![ROOT, "b@1", "lib@1.2"],
+                vec![ROOT, "lib@1.0"],
+            ]
+        );
+        let requesters: Vec<_> = graph
+            .requesters("lib")
+            .into_iter()
+            .map(|r| (r.module, r.version, r.selected))
+            .collect();
+        assert_eq!(
+            requesters,
+            [
+                ("a@1".to_string(), "1.2".to_string(), true),
+                ("b@1".to_string(), "1.2".to_string(), true),
+                (ROOT.to_string(), "1.0".to_string(), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compatibility_conflict() {
+        let graph = graph(&[
+            (ROOT, "a@1"),
+            (ROOT, "b@1"),
+            ("a@1", "lib@1.0"),
+            ("b@1", "lib@2.0"),
+        ]);
+        let levels = BTreeMap::from(
+            [("a@1", 0), ("b@1", 0), ("lib@1.0", 1), ("lib@2.0", 2)]
+                .map(|(m, level)| (m.to_string(), level)),
+        );
+        let conflict = graph
+            .compatibility_conflict(&levels, &BTreeMap::new(), |_| false)
+            .unwrap();
+        assert_eq!(
+            conflict,
+            CompatibilityConflict {
+                selected: "lib@2.0".to_string(),
+                selected_level: 2,
+                selected_path: vec![ROOT.to_string(), "b@1".to_string(), "lib@2.0".to_string()],
+                conflicting: "lib@1.0".to_string(),
+                conflicting_level: 1,
+                conflicting_path: vec![ROOT.to_string(), "a@1".to_string(), "lib@1.0".to_string()],
+            }
+        );
+        assert!(conflict.to_string().starts_with(
+            "b@1 depends on lib@2.0 with compatibility level 2, but a@1 depends on lib@1.0"
+        ));
+
+        // a accepts level 2, or lib has a multiple_version_override.
+        let max_levels = BTreeMap::from([(("a@1".to_string(), "lib".to_string()), 2)]);
+        assert_eq!(
+            graph.compatibility_conflict(&levels, &max_levels, |_| false),
+            None
+        );
+        assert_eq!(
+            graph.compatibility_conflict(&levels, &BTreeMap::new(), |m| m == "lib"),
+            None
+        );
+    }
+}
@@ -0,0 +1,193 @@
+//! Minimal version selection: of the versions of a module required anywhere in the dependency graph, the highest wins.
+//!
+//! `ModuleGraph::resolve` applies it to the whole graph, and repositories are created for the selected versions, so a
+//! module can see a later version of a dependency than its `bazel_dep` names. `--check_direct_dependencies` points out
+//! where the root module does. The root module's overrides change the version a `bazel_dep` asks for first, see
+//! `bzlmod::Overrides::version`.
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// The value of `--check_direct_dependencies`: what to do when the root module's `bazel_dep` asks for a different
+/// version than the one selected.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum CheckDirectDependencies {
+    Off,
+    #[default]
+    Warning,
+    Error,
+}
+
+/// Compares module versions as Bazel does: dot-separated release identifiers first, numbers numerically and before
+/// anything else, then a version with a `-prerelease` before the same version without one. `+build` metadata is
+/// ignored, and the empty version, used by overrides, is higher than all others.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    fn parts(version: &str) -> (Vec<&str>, Option<Vec<&str>>) {
+        let version = version.split_once('+').map_or(version, |(v, _)| v);
+        match version.split_once('-') {
+            Some((release, pre)) => (release.split('.').collect(), Some(pre.split('.').collect())),
+            None => (version.split('.').collect(), None),
+        }
+    }
+    fn identifier(a: &str, b: &str) -> Ordering {
+        match (a.parse::<u64>(), b.parse::<u64>()) {
+            (Ok(a), Ok(b)) => a.cmp(&b),
+            (Ok(_), Err(_)) => Ordering::Less,
+            (Err(_), Ok(_)) => Ordering::Greater,
+            (Err(_), Err(_)) => a.cmp(b),
+        }
+    }
+    fn identifiers(a: &[&str], b: &[&str]) -> Ordering {
+        a.iter()
+            .zip(b)
+            .map(|(a, b)| identifier(a, b))
+            .find(|o| o.is_ne())
+            .unwrap_or_else(|| a.len().cmp(&b.len()))
+    }
+
+    match (a.is_empty(), b.is_empty()) {
+        (true, true) => return Ordering::Equal,
+        (true, false) => return Ordering::Greater,
+        (false, true) => return Ordering::Less,
+        (false, false) => {}
+    }
+    let ((a_release, a_pre), (b_release, b_pre)) = (parts(a), parts(b));
+    identifiers(&a_release, &b_release).then_with(|| match (a_pre, b_pre) {
+        (None, None) => Ordering::Equal,
+        (None, Some(_)) => Ordering::Greater,
+        (Some(_), None) => Ordering::Less,
+        (Some(a), Some(b)) => identifiers(&a, &b),
+    })
+}
+
+/// The selected version of each module, given every `(module, version)` required in the graph.
+pub fn select<'a>(
+    requirements: impl IntoIterator<Item = (&'a str, &'a str)>,
+) -> BTreeMap<String, String> {
+    let mut selected = BTreeMap::<String, String>::new();
+    for (name, version) in requirements {
+        match selected.get_mut(name) {
+            Some(current) if compare_versions(version, current).is_gt() => {
+                *current = version.to_string();
+            }
+            Some(_) => {}
+            None => {
+                selected.insert(name.to_string(), version.to_string());
+            }
+        }
+    }
+    selected
+}
+
+/// A `bazel_dep` of the root module asking for a different version than the one selected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionDrift {
+    /// The name the root module sees the dependency by.
+    pub repo_name: String,
+    pub module: String,
+    pub requested: String,
+    pub selected: String,
+}
+
+impl VersionDrift {
+    /// The `bazel_dep` the root module's MODULE.bazel should have instead.
+    pub fn fix(&self) -> String {
+        if self.repo_name == self.module {
+            format!(
+                "bazel_dep(name = \"{}\", version = \"{}\")",
+                self.module, self.selected
+            )
+        } else {
+            format!(
+                "bazel_dep(name = \"{}\", version = \"{}\", repo_name = \"{}\")",
+                self.module, self.selected, self.repo_name
+            )
+        }
+    }
+}
+
+impl fmt::Display for VersionDrift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "For repository '{}', the root module requires module version {}@{}, but got {}@{} in the resolved \
+             dependency graph. Update MODULE.bazel to: {}",
+            self.repo_name,
+            self.module,
+            self.requested,
+            self.module,
+            self.selected,
+            self.fix()
+        )
+    }
+}
+
+/// The root module's direct dependencies, as `(repo_name, module, version)`, whose version isn't the one `selected`.
+pub fn direct_drift<'a>(
+    direct: impl IntoIterator<Item = (&'a str, &'a str, &'a str)>,
+    selected: &BTreeMap<String, String>,
+) -> Vec<VersionDrift> {
+    let mut drift: Vec<_> = direct
+        .into_iter()
+        .filter_map(|(repo_name, module, requested)| {
+            let selected = selected.get(module)?;
+            (selected != requested).then(|| VersionDrift {
+                repo_name: repo_name.to_string(),
+                module: module.to_string(),
+                requested: requested.to_string(),
+                selected: selected.clone(),
+            })
+        })
+        .collect();
+    drift.sort_by(|a, b| a.repo_name.cmp(&b.repo_name));
+    drift
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare_versions() {
+        let ordered = [
+            "0.9", "1.0-rc1", "1.0-rc2", "1.0", "1.0.1", "1.2", "1.10", "1.10a", "2.0", "",
+        ];
+        for (i, a) in ordered.iter().enumerate() {
+            for (j, b) in ordered.iter().enumerate() {
+                assert_eq!(compare_versions(a, b), i.cmp(&j), "{a:?} vs {b:?}");
+            }
+        }
+        assert_eq!(compare_versions("1.0+build", "1.0"), Ordering::Equal);
+    }
+
+    #[test]
+    fn test_select_and_drift() {
+        let selected = select([
+            ("rules_go", "0.50.1"),
+            ("rules_go", "0.41.0"),
+            ("rules_go", "0.51.0-rc1"),
+            ("zlib", "1.3"),
+        ]);
+        assert_eq!(selected["rules_go"], "0.51.0-rc1");
+
+        let drift = direct_drift(
+            [
+                ("io_bazel_rules_go", "rules_go", "0.41.0"),
+                ("zlib", "zlib", "1.3"),
+                ("local", "local", "1.0"),
+            ],
+            &selected,
+        );
+        assert_eq!(drift.len(), 1);
+        assert_eq!(
+            drift[0].fix(),
+            "bazel_dep(name = \"rules_go\", version = \"0.51.0-rc1\", repo_name = \"io_bazel_rules_go\")"
+        );
+        assert!(
+            drift[0]
+                .to_string()
+                .contains("requires module version rules_go@0.41.0, but got rules_go@0.51.0-rc1")
+        );
+    }
+}
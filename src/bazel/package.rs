@@ -7,6 +7,7 @@ use tokio::io;
 pub use bazel_remote_apis::build::bazel::remote::execution::v2::Digest;
 pub use bazel_remote_apis::build::bazel::remote::execution::v2::digest_function::Value as DigestFunction;
 
+#[derive(Debug)]
 pub enum DirEntry {
     File(String),
     Directory(String),
@@ -31,69 +32,15 @@ impl<F: FileStore> Package<F> {
         }
     }
 
+    /// All packages beneath this one, excluding this package itself.
     pub fn subpackages<'a>(&'a self) -> futures::stream::BoxStream<'a, anyhow::Result<Package<F>>>
     where
         F: Clone + 'a,
     {
-        Box::pin(async_stream::try_stream! {
-            let mut stack = vec![self.path.clone()];
-
-            while let Some(current_dir) = stack.pop() {
-                // Read the directory contents
-                let mut dir_entries = match self.filestore.read_dir(&current_dir).await {
-                    Ok(entries) => entries,
-                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
-                    Err(e) => {
-                        yield Err(anyhow::Error::from(e))?;
-                        continue;
-                    }
-                };
-
-                let mut subdirs = Vec::new();
-                let mut build_file_name = None;
-
-                for entry in dir_entries {
-                    match entry {
-                        DirEntry::Directory(name) => {
-                            let name_str = name.as_str();
-                            if name_str.starts_with('.') || name_str == "target" || name_str.starts_with("bazel-") {
-                                continue;
-                            }
-                            let next_path = if current_dir.is_empty() {
-                                name
-                            } else {
-                                format!("{}/{}", current_dir, name)
-                            };
-                            subdirs.push(next_path);
-                        }
-                        DirEntry::File(name) => {
-                            if name == "BUILD.bazel" {
-                                build_file_name = Some("BUILD.bazel".to_string());
-                            } else if name == "BUILD" && build_file_name.is_none() {
-                                build_file_name = Some("BUILD".to_string());
-                            }
-                        }
-                    }
-                }
-
-                // Add subdirectories to the stack to continue walking
-                // (We do this even if we found a BUILD file here, as Bazel //... walks past package boundaries)
-                stack.extend(subdirs);
-
-                // If this directory has a BUILD file, yield it as a subpackage (skip if it's the root package itself)
-                if let Some(bf) = build_file_name
-                    && current_dir != self.path
-                {
-                    let build_path = if current_dir.is_empty() { bf.clone() } else { format!("{}/{}", current_dir, bf) };
-                    match self.filestore.read_file(&build_path).await {
-                        Ok(file) => {
-                            yield Package::new(current_dir, bf, self.filestore.clone(), file);
-                        }
-                        Err(e) => yield Err(anyhow::Error::from(e))?,
-                    }
-                }
-            }
-        })
+        let root = self.path.clone();
+        packages_beneath(self.filestore.clone(), self.path.clone())
+            .filter(move |pkg| futures::future::ready(!matches!(pkg, Ok(pkg) if pkg.path == root)))
+            .boxed()
     }
 
     // TODO
@@ -111,10 +58,79 @@ impl<F: FileStore> Package<F> {
 }
 
 use futures::{
-    Stream,
+    Stream, StreamExt,
     future::{BoxFuture, FutureExt},
 };
 
+/// Walks the directory tree under `dir` (inclusive), yielding every package found.
+///
+/// `dir` itself need not be a package.
+pub fn packages_beneath<'a, F>(
+    filestore: F,
+    dir: String,
+) -> futures::stream::BoxStream<'a, anyhow::Result<Package<F>>>
+where
+    F: FileStore + Clone + 'a,
+{
+    Box::pin(async_stream::try_stream! {
+        let mut stack = vec![dir];
+
+        while let Some(current_dir) = stack.pop() {
+            // Read the directory contents
+            let mut dir_entries = match filestore.read_dir(&current_dir).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    yield Err(anyhow::Error::from(e))?;
+                    continue;
+                }
+            };
+
+            let mut subdirs = Vec::new();
+            let mut build_file_name = None;
+
+            for entry in dir_entries {
+                match entry {
+                    DirEntry::Directory(name) => {
+                        let name_str = name.as_str();
+                        if name_str.starts_with('.') || name_str == "target" || name_str.starts_with("bazel-") {
+                            continue;
+                        }
+                        let next_path = if current_dir.is_empty() {
+                            name
+                        } else {
+                            format!("{}/{}", current_dir, name)
+                        };
+                        subdirs.push(next_path);
+                    }
+                    DirEntry::File(name) => {
+                        if name == "BUILD.bazel" {
+                            build_file_name = Some("BUILD.bazel".to_string());
+                        } else if name == "BUILD" && build_file_name.is_none() {
+                            build_file_name = Some("BUILD".to_string());
+                        }
+                    }
+                }
+            }
+
+            // Add subdirectories to the stack to continue walking
+            // (We do this even if we found a BUILD file here, as Bazel //... walks past package boundaries)
+            stack.extend(subdirs);
+
+            // If this directory has a BUILD file, yield it as a package
+            if let Some(bf) = build_file_name {
+                let build_path = if current_dir.is_empty() { bf.clone() } else { format!("{}/{}", current_dir, bf) };
+                match filestore.read_file(&build_path).await {
+                    Ok(file) => {
+                        yield Package::new(current_dir, bf, filestore.clone(), file);
+                    }
+                    Err(e) => yield Err(anyhow::Error::from(e))?,
+                }
+            }
+        }
+    })
+}
+
 pub type BoxAsyncRead = Box<dyn io::AsyncRead + Unpin + Send>;
 pub type BoxFile<'a> = Box<DynFile<'a, BoxAsyncRead>>;
 pub type BoxFileStore<'a> = std::sync::Arc<DynFileStore<'a, BoxFile<'a>>>;
@@ -131,6 +147,9 @@ pub trait File: Send + Sync + std::fmt::Debug {
         &self,
         digest_function: DigestFunction,
     ) -> BoxFuture<'_, Result<Digest, std::io::Error>>;
+
+    /// Whether the file is executable, the only part of its mode Bazel keeps.
+    fn is_executable(&self) -> BoxFuture<'_, Result<bool, std::io::Error>>;
 }
 
 impl<'a, R: io::AsyncRead> std::fmt::Debug for DynFile<'a, R> {
@@ -182,7 +201,17 @@ where
         &self,
         digest_function: DigestFunction,
     ) -> BoxFuture<'_, Result<Digest, std::io::Error>> {
-        self.0.digest(digest_function).boxed()
+        async move {
+            // Every file razel reads is seen through here, so a bad digest never gets any further.
+            let digest = self.0.digest(digest_function).await?;
+            crate::bazel::digest::validate(&digest, digest_function)?;
+            Ok(digest)
+        }
+        .boxed()
+    }
+
+    fn is_executable(&self) -> BoxFuture<'_, Result<bool, std::io::Error>> {
+        self.0.is_executable()
     }
 }
 
@@ -223,3 +252,29 @@ where
         self.0.read_dir(path).boxed()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bazel::memory::InMemoryFileStore;
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn test_packages_beneath_non_package_dir() {
+        let store = InMemoryFileStore::new(HashMap::from([
+            ("BUILD.bazel".to_string(), vec![]),
+            ("a/b/BUILD".to_string(), vec![]),
+            ("a/c/BUILD.bazel".to_string(), vec![]),
+            ("a/c/d/BUILD.bazel".to_string(), vec![]),
+            ("a/c/d/.hidden/BUILD.bazel".to_string(), vec![]),
+            ("e/BUILD.bazel".to_string(), vec![]),
+        ]));
+
+        let mut paths: Vec<String> = packages_beneath(store, "a".to_string())
+            .map(|pkg| pkg.unwrap().path)
+            .collect()
+            .await;
+        paths.sort();
+        assert_eq!(paths, vec!["a/b", "a/c", "a/c/d"]);
+    }
+}
@@ -0,0 +1,349 @@
+//! Applying the `patches` of registry modules and overrides, without needing a `patch` binary on the host.
+//!
+//! This covers what patches to third party sources are made of: `diff -u` and `git diff` output changing, creating or
+//! deleting text files. Hunks must match exactly, though they may have moved, as `patch` allows without fuzz.
+
+use crate::error::FetchError;
+use std::path::{Component, Path, PathBuf};
+
+/// The changes a patch makes to one file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FilePatch {
+    /// The file before the change, or `None` if the patch creates it.
+    old: Option<String>,
+    /// The file after the change, or `None` if the patch deletes it.
+    new: Option<String>,
+    hunks: Vec<Hunk>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Hunk {
+    /// The line the hunk starts at in the old file, counting from 1, or 0 if the old file is empty.
+    old_start: usize,
+    /// The lines of the old file the hunk replaces, each with its line ending, if it has one.
+    old_lines: Vec<String>,
+    /// The lines it replaces them with.
+    new_lines: Vec<String>,
+}
+
+impl FilePatch {
+    /// The path of the file the patch changes.
+    fn path(&self) -> &str {
+        self.new.as_deref().or(self.old.as_deref()).unwrap_or("")
+    }
+}
+
+/// The path in a `---` or `+++` line, without `strip` leading components, or `None` for `/dev/null`.
+fn header_path(header: &str, strip: usize) -> Result<Option<String>, String> {
+    // Anything after a tab is a timestamp.
+    let path = header.split('\t').next().unwrap_or("").trim_end();
+    let path = path.trim_matches('"');
+    if path == "/dev/null" {
+        return Ok(None);
+    }
+    let components: Vec<_> = path.split('/').filter(|c| !c.is_empty()).collect();
+    if components.len() <= strip {
+        return Err(format!(
+            "can't strip {strip} components from {path}, which only has {}",
+            components.len()
+        ));
+    }
+    Ok(Some(components[strip..].join("/")))
+}
+
+/// The numbers in a hunk header, `@@ -old_start,old_len +new_start,new_len @@`, where a missing length is 1.
+fn hunk_header(line: &str) -> Option<(usize, usize, usize)> {
+    let ranges = line.strip_prefix("@@ -")?;
+    let (ranges, _) = ranges.split_once(" @@")?;
+    let (old, new) = ranges.split_once(" +")?;
+    let range = |range: &str| -> Option<(usize, usize)> {
+        match range.split_once(',') {
+            Some((start, len)) => Some((start.parse().ok()?, len.parse().ok()?)),
+            None => Some((range.parse().ok()?, 1)),
+        }
+    };
+    let (old_start, old_len) = range(old)?;
+    let (_, new_len) = range(new)?;
+    Some((old_start, old_len, new_len))
+}
+
+/// Parses the unified diff `patch`, removing `strip` leading components from the paths in it. Lines outside of file
+/// headers and hunks, such as `diff --git` lines and commit messages, are ignored.
+fn parse(patch: &str, strip: usize) -> Result<Vec<FilePatch>, String> {
+    let mut lines = patch.split_inclusive('\n').peekable();
+    let mut files = Vec::new();
+    while let Some(line) = lines.next() {
+        let Some(old) = line.strip_prefix("--- ") else {
+            continue;
+        };
+        let Some(new) = lines.next_if(|line| line.starts_with("+++ ")) else {
+            continue;
+        };
+        let mut file = FilePatch {
+            old: header_path(old, strip)?,
+            new: header_path(&new[4..], strip)?,
+            hunks: Vec::new(),
+        };
+        while let Some((old_start, mut old_len, mut new_len)) =
+            lines.peek().and_then(|line| hunk_header(line))
+        {
+            lines.next();
+            let mut hunk = Hunk {
+                old_start,
+                old_lines: Vec::new(),
+                new_lines: Vec::new(),
+            };
+            while old_len > 0 || new_len > 0 {
+                let line = lines.next().ok_or_else(|| {
+                    format!("the patch ends in the middle of a hunk of {}", file.path())
+                })?;
+                let (kind, text) = match line.chars().next() {
+                    // Some editors strip the trailing space from empty context lines.
+                    Some('\n' | '\r') | None => (' ', line),
+                    Some(kind) => (kind, &line[kind.len_utf8()..]),
+                };
+                match kind {
+                    ' ' if old_len > 0 && new_len > 0 => {
+                        old_len -= 1;
+                        new_len -= 1;
+                        hunk.old_lines.push(text.to_string());
+                        hunk.new_lines.push(text.to_string());
+                    }
+                    '-' if old_len > 0 => {
+                        old_len -= 1;
+                        hunk.old_lines.push(text.to_string());
+                    }
+                    '+' if new_len > 0 => {
+                        new_len -= 1;
+                        hunk.new_lines.push(text.to_string());
+                    }
+                    _ => {
+                        return Err(format!(
+                            "unexpected line in a hunk of {}: {}",
+                            file.path(),
+                            line.trim_end()
+                        ));
+                    }
+                }
+                if lines.next_if(|line| line.starts_with('\\')).is_some() {
+                    // `\ No newline at end of file`, about the line before.
+                    let no_newline = |lines: &mut Vec<String>| {
+                        if let Some(last) = lines.last_mut() {
+                            last.truncate(last.trim_end_matches(['\n', '\r']).len());
+                        }
+                    };
+                    if kind != '+' {
+                        no_newline(&mut hunk.old_lines);
+                    }
+                    if kind != '-' {
+                        no_newline(&mut hunk.new_lines);
+                    }
+                }
+            }
+            file.hunks.push(hunk);
+        }
+        files.push(file);
+    }
+    if files.is_empty() {
+        return Err("it doesn't change any files".to_string());
+    }
+    Ok(files)
+}
+
+/// `content` with `hunks` applied, in order. Each hunk is looked for where it says it starts, adjusted by how far the
+/// ones before it moved, and then ever further before and after that, but never before the end of the hunk before.
+fn apply_hunks(content: &str, hunks: &[Hunk]) -> Result<String, String> {
+    let lines: Vec<&str> = content.split_inclusive('\n').collect();
+    let mut result = String::with_capacity(content.len());
+    // The first line not yet copied to `result`.
+    let mut next = 0;
+    let mut offset: isize = 0;
+    for (i, hunk) in hunks.iter().enumerate() {
+        let matches = |at: usize| {
+            at + hunk.old_lines.len() <= lines.len()
+                && hunk
+                    .old_lines
+                    .iter()
+                    .zip(&lines[at..])
+                    .all(|(old, line)| old == line)
+        };
+        let expected = (hunk.old_start.saturating_sub(1) as isize + offset)
+            .clamp(next as isize, lines.len() as isize) as usize;
+        let at = (0..=lines.len())
+            .flat_map(|distance| [expected.checked_sub(distance), Some(expected + distance)])
+            .flatten()
+            .filter(|at| *at >= next && *at <= lines.len())
+            .find(|at| matches(*at))
+            .ok_or_else(|| {
+                format!(
+                    "hunk #{} doesn't match the file at line {}",
+                    i + 1,
+                    hunk.old_start
+                )
+            })?;
+        for line in &lines[next..at] {
+            result.push_str(line);
+        }
+        for line in &hunk.new_lines {
+            result.push_str(line);
+        }
+        next = at + hunk.old_lines.len();
+        offset = at as isize - hunk.old_start.saturating_sub(1) as isize;
+    }
+    for line in &lines[next..] {
+        result.push_str(line);
+    }
+    Ok(result)
+}
+
+/// `path` under `dir`, if it doesn't lead out of it.
+fn resolve(dir: &Path, path: &str) -> Option<PathBuf> {
+    Path::new(path)
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        .then(|| dir.join(path))
+}
+
+/// Applies the unified diff `patch`, named `name` in errors, to the files in `dir`, removing `strip` leading
+/// components from the paths in it, as `patch -p<strip>` does.
+///
+/// Every file is checked before any is written, so a patch that doesn't apply leaves `dir` as it was.
+pub fn apply(dir: &Path, name: &str, patch: &str, strip: usize) -> Result<(), FetchError> {
+    let failed = |file: &str, reason: String| FetchError::PatchFailed {
+        patch: name.to_string(),
+        file: Some(file.to_string()),
+        reason,
+    };
+    let files = parse(patch, strip).map_err(|reason| FetchError::PatchFailed {
+        patch: name.to_string(),
+        file: None,
+        reason,
+    })?;
+    let mut writes = Vec::with_capacity(files.len());
+    for file in &files {
+        let path = file.path();
+        let target = resolve(dir, path)
+            .ok_or_else(|| failed(path, "the path leads out of the repository".to_string()))?;
+        let content = match file.old {
+            Some(_) => std::fs::read_to_string(&target)
+                .map_err(|e| failed(path, format!("failed to read it: {e}")))?,
+            None if target.exists() => {
+                return Err(failed(
+                    path,
+                    "the patch creates it, but it already exists".to_string(),
+                ));
+            }
+            None => String::new(),
+        };
+        let patched = apply_hunks(&content, &file.hunks).map_err(|reason| failed(path, reason))?;
+        writes.push((target, file.new.is_some().then_some(patched)));
+    }
+    for (target, content) in writes {
+        let written = match content {
+            Some(content) => target
+                .parent()
+                .map_or(Ok(()), std::fs::create_dir_all)
+                .and_then(|()| std::fs::write(&target, content)),
+            None => std::fs::remove_file(&target),
+        };
+        written.map_err(|e| failed(&target.to_string_lossy(), e.to_string()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATCH: &str = "\
+diff --git a/src/lib.c b/src/lib.c
+--- a/src/lib.c\t2024-01-01 00:00:00
++++ b/src/lib.c\t2024-01-02 00:00:00
+@@ -1,3 +1,3 @@
+ int a;
+-int b;
++long b;
+ int c;
+@@ -7,2 +7,3 @@
+ int g;
+ int h;
++int i;
+--- /dev/null
++++ b/NEW
+@@ -0,0 +1 @@
++new
+\\ No newline at end of file
+--- a/OLD
++++ /dev/null
+@@ -1 +0,0 @@
+-old
+";
+
+    #[test]
+    fn test_parse() {
+        let files = parse(PATCH, 1).unwrap();
+        assert_eq!(files.len(), 3);
+        assert_eq!(files[0].path(), "src/lib.c");
+        assert_eq!(files[0].hunks.len(), 2);
+        assert_eq!(files[0].hunks[1].old_start, 7);
+        assert_eq!(
+            files[0].hunks[1].new_lines,
+            ["int g;\n", "int h;\n", "int i;\n"]
+        );
+        assert_eq!(files[1].old, None);
+        assert_eq!(files[1].hunks[0].new_lines, ["new"]);
+        assert_eq!(files[2].new, None);
+        assert_eq!(parse(PATCH, 0).unwrap()[0].path(), "b/src/lib.c");
+        assert!(parse(PATCH, 2).is_err());
+        assert!(parse("not a patch\n", 0).is_err());
+    }
+
+    #[test]
+    fn test_apply_hunks() {
+        let hunks = &parse(PATCH, 1).unwrap()[0].hunks;
+        let content = "int a;\nint b;\nint c;\nint d;\nint e;\nint f;\nint g;\nint h;\n";
+        assert_eq!(
+            apply_hunks(content, hunks).unwrap(),
+            "int a;\nlong b;\nint c;\nint d;\nint e;\nint f;\nint g;\nint h;\nint i;\n"
+        );
+
+        // Hunks are found where they moved to.
+        let moved = format!("// header\n{content}");
+        assert_eq!(
+            apply_hunks(&moved, hunks).unwrap(),
+            "// header\nint a;\nlong b;\nint c;\nint d;\nint e;\nint f;\nint g;\nint h;\nint i;\n"
+        );
+
+        let err = apply_hunks("int a;\nint x;\nint c;\n", hunks).unwrap_err();
+        assert!(err.contains("hunk #1"), "{err}");
+    }
+
+    #[test]
+    fn test_apply() {
+        let tmp = assert_fs::TempDir::new().unwrap();
+        std::fs::create_dir_all(tmp.path().join("src")).unwrap();
+        let content = "int a;\nint b;\nint c;\nint d;\nint e;\nint f;\nint g;\nint h;\n";
+        std::fs::write(tmp.path().join("src/lib.c"), content).unwrap();
+        std::fs::write(tmp.path().join("OLD"), "old\n").unwrap();
+
+        apply(tmp.path(), "fix.patch", PATCH, 1).unwrap();
+        assert!(
+            std::fs::read_to_string(tmp.path().join("src/lib.c"))
+                .unwrap()
+                .contains("long b;\n")
+        );
+        assert_eq!(
+            std::fs::read_to_string(tmp.path().join("NEW")).unwrap(),
+            "new"
+        );
+        assert!(!tmp.path().join("OLD").exists());
+
+        // Applying it again fails, without changing anything.
+        let err = apply(tmp.path(), "fix.patch", PATCH, 1).unwrap_err();
+        assert!(err.to_string().contains("fix.patch"), "{err}");
+        assert!(tmp.path().join("NEW").exists());
+
+        let escape = "--- a/../x\n+++ b/../x\n@@ -0,0 +1 @@\n+x\n";
+        assert!(apply(tmp.path(), "escape.patch", escape, 1).is_err());
+    }
+}
@@ -0,0 +1,231 @@
+//! Restrictions on which external dependencies a workspace may use.
+//!
+//! A `dependency_policy.json` in the workspace root lists the module names, registries and download URLs that are
+//! allowed or denied, e.g.:
+//!
+//! ```json
+//! {
+//!   "modules": { "allow": ["rules_*", "platforms"], "deny": ["rules_legacy"] },
+//!   "registries": { "allow": ["https://bcr.bazel.build"] },
+//!   "urls": { "allow": ["https://github.com/*", "https://mirror.example.com/*"] }
+//! }
+//! ```
+//!
+//! Patterns may contain `*` wildcards. A deny pattern always wins; when there are allow patterns, anything not matching
+//! one of them is denied too. The policy is checked while resolving `bazel_dep`s and before every download, so a
+//! change pulling in an unapproved dependency fails with an error naming the rule it broke.
+
+use crate::error::ResolutionError;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// The name of the policy file, in the workspace root.
+pub const POLICY_FILE: &str = "dependency_policy.json";
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rules {
+    #[serde(default)]
+    allow: Vec<String>,
+    #[serde(default)]
+    deny: Vec<String>,
+}
+
+impl Rules {
+    /// Describes why `value` is rejected, if it is.
+    fn violation(&self, section: &str, value: &str) -> Option<String> {
+        if let Some(pattern) = self.deny.iter().find(|p| matches(p, value)) {
+            return Some(format!("denied by {section}.deny pattern {pattern:?}"));
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|p| matches(p, value)) {
+            return Some(format!("not matched by any {section}.allow pattern"));
+        }
+        None
+    }
+}
+
+/// Whether `value` matches `pattern`, where `*` matches any sequence of characters.
+fn matches(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = value.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DependencyPolicy {
+    #[serde(default)]
+    modules: Rules,
+    #[serde(default)]
+    registries: Rules,
+    #[serde(default)]
+    urls: Rules,
+    /// The file the policy was read from, for error messages.
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+/// A dependency rejected by the `DependencyPolicy`, returned (inside an `anyhow::Error`) from resolution or fetching.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicyViolation {
+    /// What was rejected: `module`, `registry` or `URL`.
+    pub kind: &'static str,
+    pub value: String,
+    /// Which rule rejected it.
+    pub reason: String,
+    /// Where the dependency was requested.
+    pub requested_by: String,
+    pub policy_file: PathBuf,
+}
+
+impl std::fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Dependency policy violation: {} {:?} requested by {} is {} in {}",
+            self.kind,
+            self.value,
+            self.requested_by,
+            self.reason,
+            self.policy_file.display()
+        )
+    }
+}
+
+impl std::error::Error for PolicyViolation {}
+
+impl DependencyPolicy {
+    /// Reads the policy of the workspace at `workspace_root`. Without a policy file, everything is allowed.
+    pub fn load(workspace_root: &Path) -> anyhow::Result<Self> {
+        let path = workspace_root.join(POLICY_FILE);
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => anyhow::bail!("Failed to read {}: {e}", path.display()),
+        };
+        let mut policy: Self =
+            serde_json::from_str(&content).map_err(|e| ResolutionError::InvalidPolicy {
+                path: path.clone(),
+                reason: e.to_string(),
+            })?;
+        policy.path = Some(path);
+        Ok(policy)
+    }
+
+    fn check(
+        &self,
+        rules: &Rules,
+        section: &str,
+        kind: &'static str,
+        value: &str,
+        requested_by: &str,
+    ) -> Result<(), PolicyViolation> {
+        match (rules.violation(section, value), &self.path) {
+            (Some(reason), Some(path)) => Err(PolicyViolation {
+                kind,
+                value: value.to_string(),
+                reason,
+                requested_by: requested_by.to_string(),
+                policy_file: path.clone(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn check_module(&self, name: &str, requested_by: &str) -> Result<(), PolicyViolation> {
+        self.check(&self.modules, "modules", "module", name, requested_by)
+    }
+
+    #[allow(dead_code)]
+    pub fn check_registry(&self, url: &str, requested_by: &str) -> Result<(), PolicyViolation> {
+        self.check(
+            &self.registries,
+            "registries",
+            "registry",
+            url,
+            requested_by,
+        )
+    }
+
+    pub fn check_url(&self, url: &str, requested_by: &str) -> Result<(), PolicyViolation> {
+        self.check(&self.urls, "urls", "URL", url, requested_by)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("rules_*", "rules_go"));
+        assert!(matches("*", ""));
+        assert!(matches(
+            "https://*.example.com/*",
+            "https://a.example.com/x.tar.gz"
+        ));
+        assert!(matches("platforms", "platforms"));
+        assert!(!matches("platforms", "platforms2"));
+        assert!(!matches("a*a", "a"));
+        assert!(!matches("https://github.com/*", "http://github.com/x"));
+    }
+
+    #[test]
+    fn test_policy() {
+        let tmp = assert_fs::TempDir::new().unwrap();
+        assert!(
+            DependencyPolicy::load(tmp.path())
+                .unwrap()
+                .check_module("anything", "MODULE.bazel")
+                .is_ok()
+        );
+
+        std::fs::write(
+            tmp.path().join(POLICY_FILE),
+            r#"{
+                "modules": {"allow": ["rules_*", "platforms"], "deny": ["rules_legacy"]},
+                "urls": {"deny": ["http://*"]}
+            }"#,
+        )
+        .unwrap();
+        let policy = DependencyPolicy::load(tmp.path()).unwrap();
+        policy.check_module("rules_go", "MODULE.bazel").unwrap();
+        policy
+            .check_registry("https://any.registry", "MODULE.bazel")
+            .unwrap();
+        policy
+            .check_url("https://github.com/x", "MODULE.bazel")
+            .unwrap();
+
+        let err = policy
+            .check_module("rules_legacy", "bazel_dep in @@ MODULE.bazel")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Dependency policy violation: module \"rules_legacy\" requested by bazel_dep in @@ MODULE.bazel is \
+                 denied by modules.deny pattern \"rules_legacy\" in {}",
+                tmp.path().join(POLICY_FILE).display()
+            )
+        );
+        let err = policy.check_module("left_pad", "MODULE.bazel").unwrap_err();
+        assert_eq!(err.reason, "not matched by any modules.allow pattern");
+        assert!(policy.check_url("http://insecure", "download").is_err());
+
+        std::fs::write(tmp.path().join(POLICY_FILE), r#"{"module": {}}"#).unwrap();
+        assert!(DependencyPolicy::load(tmp.path()).is_err());
+    }
+}
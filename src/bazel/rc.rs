@@ -0,0 +1,481 @@
+//! Reading `.bazelrc` files.
+//!
+//! Each line of an rc file is `<command>[:<config>] <options...>`, or `import`/`try-import` of another rc file. The
+//! options for the running command (and the commands it inherits from) are inserted before those given on the command
+//! line, and `--config=<name>` is replaced by the options of the matching `<command>:<name>` lines.
+//! See https://bazel.build/run/bazelrc
+
+use std::path::{Path, PathBuf};
+
+/// Lines for these pseudo-commands apply to every command.
+const COMMON_COMMANDS: &[&str] = &["always", "common"];
+
+/// The commands whose options a command also picks up, most general first.
+fn parent_commands(command: &str) -> &'static [&'static str] {
+    match command {
+        "test" | "run" | "cquery" | "aquery" | "info" | "fetch" => &["build"],
+        "coverage" => &["build", "test"],
+        _ => &[],
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct RcLine {
+    command: String,
+    config: Option<String>,
+    options: Vec<String>,
+}
+
+/// The option lines of all rc files, in the order they were read.
+#[derive(Debug, Default)]
+pub struct RcOptions {
+    lines: Vec<RcLine>,
+}
+
+impl RcOptions {
+    /// Reads the default rc files: `.bazelrc` in the workspace root, then `~/.bazelrc`. Missing files are skipped.
+    pub fn load_default(workspace_root: Option<&Path>) -> anyhow::Result<Self> {
+        let mut rc = Self::default();
+        if let Some(root) = workspace_root {
+            rc.read_file(
+                &root.join(".bazelrc"),
+                workspace_root,
+                false,
+                &mut Vec::new(),
+            )?;
+        }
+        if let Some(home) = std::env::var_os("HOME") {
+            rc.read_file(
+                &Path::new(&home).join(".bazelrc"),
+                workspace_root,
+                false,
+                &mut Vec::new(),
+            )?;
+        }
+        Ok(rc)
+    }
+
+    /// Reads the rc file at `path`, following its imports. A missing file is an error only if `required`.
+    fn read_file(
+        &mut self,
+        path: &Path,
+        workspace_root: Option<&Path>,
+        required: bool,
+        importing: &mut Vec<PathBuf>,
+    ) -> anyhow::Result<()> {
+        if importing.iter().any(|p| p == path) {
+            let chain: Vec<String> = importing
+                .iter()
+                .chain([&path.to_path_buf()])
+                .map(|p| p.display().to_string())
+                .collect();
+            anyhow::bail!("Import loop detected: {}", chain.join(" -> "));
+        }
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !required => return Ok(()),
+            Err(e) => anyhow::bail!("Failed to read rc file {}: {e}", path.display()),
+        };
+
+        importing.push(path.to_path_buf());
+        for line in join_continuations(&content) {
+            let Some(words) = shlex::split(&line) else {
+                anyhow::bail!("Unterminated quote in {}: {line}", path.display());
+            };
+            let Some((command, args)) = words.split_first() else {
+                continue;
+            };
+
+            match command.as_str() {
+                "import" | "try-import" => {
+                    let [target] = args else {
+                        anyhow::bail!("Invalid import in {}: {line}", path.display());
+                    };
+                    let target = resolve_import(target, path, workspace_root)?;
+                    self.read_file(&target, workspace_root, command == "import", importing)?;
+                }
+                _ => {
+                    let (command, config) = match command.split_once(':') {
+                        Some((command, config)) => (command, Some(config.to_string())),
+                        None => (command.as_str(), None),
+                    };
+                    self.lines.push(RcLine {
+                        command: command.to_string(),
+                        config,
+                        options: args.to_vec(),
+                    });
+                }
+            }
+        }
+        importing.pop();
+        Ok(())
+    }
+
+    /// The options of the lines for `command`, or for `config` of `command`, ordered from the most general command to
+    /// the most specific.
+    fn options_for(&self, command: &str, config: Option<&str>) -> Vec<String> {
+        COMMON_COMMANDS
+            .iter()
+            .chain(parent_commands(command))
+            .chain([&command])
+            .flat_map(|cmd| {
+                self.lines
+                    .iter()
+                    .filter(move |l| l.command == *cmd && l.config.as_deref() == config)
+            })
+            .flat_map(|l| l.options.iter().cloned())
+            .collect()
+    }
+
+    /// The options of `startup` lines, which must come before the command.
+    pub fn startup_options(&self) -> Vec<String> {
+        self.options_for("startup", None)
+    }
+
+    /// Returns the options `command` runs with: those from rc files followed by `args`, with all `--config`s
+    /// replaced by the options they stand for.
+    pub fn expand(&self, command: &str, args: &[String]) -> anyhow::Result<Vec<String>> {
+        let mut options = self.options_for(command, None);
+        options.extend(args.iter().cloned());
+        let mut expanded = Vec::new();
+        self.expand_configs(command, options, &mut Vec::new(), &mut expanded)?;
+        Ok(expanded)
+    }
+
+    fn expand_configs(
+        &self,
+        command: &str,
+        options: Vec<String>,
+        expanding: &mut Vec<String>,
+        out: &mut Vec<String>,
+    ) -> anyhow::Result<()> {
+        let mut options = options.into_iter();
+        while let Some(option) = options.next() {
+            let name = match option.strip_prefix("--config") {
+                Some(rest) if rest.starts_with('=') => rest[1..].to_string(),
+                Some("") => options
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--config requires a value"))?,
+                _ => {
+                    out.push(option);
+                    continue;
+                }
+            };
+
+            if expanding.contains(&name) {
+                anyhow::bail!(
+                    "Config expansion has a cycle: {} -> {name}",
+                    expanding.join(" -> ")
+                );
+            }
+            if !self.lines.iter().any(|l| l.config.as_ref() == Some(&name)) {
+                anyhow::bail!("Config value '{name}' is not defined in any .rc file");
+            }
+            expanding.push(name.clone());
+            self.expand_configs(
+                command,
+                self.options_for(command, Some(&name)),
+                expanding,
+                out,
+            )?;
+            expanding.pop();
+        }
+        Ok(())
+    }
+}
+
+/// Joins lines ending in a backslash with the line that follows.
+fn join_continuations(content: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut current = String::new();
+    for line in content.lines() {
+        match line.strip_suffix('\\') {
+            Some(start) => current.push_str(start),
+            None => {
+                current.push_str(line);
+                lines.push(std::mem::take(&mut current));
+            }
+        }
+    }
+    if !current.is_empty() {
+        lines.push(current);
+    }
+    lines
+}
+
+/// Resolves the path of an `import`, expanding `%workspace%`. Relative paths are relative to the importing file.
+fn resolve_import(
+    target: &str,
+    importing_file: &Path,
+    workspace_root: Option<&Path>,
+) -> anyhow::Result<PathBuf> {
+    let target = match target.strip_prefix("%workspace%") {
+        Some(rest) => {
+            let Some(root) = workspace_root else {
+                anyhow::bail!("Cannot import {target} outside of a workspace");
+            };
+            root.join(rest.trim_start_matches('/'))
+        }
+        None => PathBuf::from(target),
+    };
+    Ok(match importing_file.parent() {
+        Some(dir) if target.is_relative() => dir.join(target),
+        _ => target,
+    })
+}
+
+/// Finds the root of the workspace containing `start_dir`, the same way `Workspace::new` does.
+pub fn find_workspace_root(start_dir: &Path) -> Option<PathBuf> {
+    let start_dir = std::path::absolute(start_dir).ok()?;
+    start_dir
+        .ancestors()
+        .find(|dir| dir.join("MODULE.bazel").exists() || dir.join("REPO.bazel").exists())
+        .map(Path::to_path_buf)
+}
+
+/// Inserts the rc file options for `command` into the command line `args` (including the program name): startup
+/// options ahead of those given on the command line, and command options right after the command name. `--config` is
+/// expanded. Arguments after `--` are passed on to something else, so they are left alone.
+pub fn expand_command_line(
+    rc: &RcOptions,
+    command: &str,
+    args: &[String],
+) -> anyhow::Result<Vec<String>> {
+    let Some(pos) = args
+        .iter()
+        .skip(1)
+        .position(|a| a == command)
+        .map(|p| p + 1)
+    else {
+        return Ok(args.to_vec());
+    };
+    let mut expanded = args[..1].to_vec();
+    expanded.extend(rc.startup_options());
+    expanded.extend(args[1..=pos].iter().cloned());
+    let rest = &args[pos + 1..];
+    let (options, passthrough) =
+        rest.split_at(rest.iter().position(|a| a == "--").unwrap_or(rest.len()));
+    expanded.extend(rc.expand(command, options)?);
+    expanded.extend(passthrough.iter().cloned());
+    Ok(expanded)
+}
+
+/// The long names of the options of `command` and its subcommands that take a `BOOL`.
+fn boolean_options(command: &clap::Command, names: &mut std::collections::HashSet<String>) {
+    for arg in command.get_arguments() {
+        if let (Some(long), Some([value_name])) = (arg.get_long(), arg.get_value_names())
+            && value_name.as_str() == "BOOL"
+        {
+            names.insert(long.to_string());
+        }
+    }
+    for subcommand in command.get_subcommands() {
+        boolean_options(subcommand, names);
+    }
+}
+
+/// Rewrites `--no<name>` as `--<name>=false` for the boolean options of `command`, as Bazel spells turning an option
+/// off. Arguments after `--` are passed on to something else, so they are left alone.
+pub fn expand_negations(command: &clap::Command, args: Vec<String>) -> Vec<String> {
+    let mut names = std::collections::HashSet::new();
+    boolean_options(command, &mut names);
+    let mut passthrough = false;
+    args.into_iter()
+        .map(|arg| {
+            passthrough |= arg == "--";
+            match arg.strip_prefix("--no") {
+                Some(name) if !passthrough && names.contains(name) => format!("--{name}=false"),
+                _ => arg,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(s: &[&str]) -> Vec<String> {
+        s.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_expand() {
+        let tmp = assert_fs::TempDir::new().unwrap();
+        std::fs::write(
+            tmp.path().join(".bazelrc"),
+            "# Shared settings\n\
+             common --keep_going\n\
+             build --jobs=4 \\\n  --config=fast\n\
+             build:fast -c opt\n\
+             test --config=ci\n\
+             test:ci --jobs=2\n\
+             build:ci --vendor_dir=\"third party\"\n\
+             query --jobs=1\n\
+             try-import %workspace%/user.bazelrc\n\
+             try-import %workspace%/missing.bazelrc\n",
+        )
+        .unwrap();
+        std::fs::write(
+            tmp.path().join("user.bazelrc"),
+            "build:mine --keep_going=false",
+        )
+        .unwrap();
+
+        let mut rc = RcOptions::default();
+        rc.read_file(
+            &tmp.path().join(".bazelrc"),
+            Some(tmp.path()),
+            true,
+            &mut Vec::new(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            rc.expand("build", &strings(&["//...", "--config=mine"]))
+                .unwrap(),
+            strings(&[
+                "--keep_going",
+                "--jobs=4",
+                "-c",
+                "opt",
+                "//...",
+                "--keep_going=false"
+            ])
+        );
+        assert_eq!(
+            rc.expand("test", &[]).unwrap(),
+            strings(&[
+                "--keep_going",
+                "--jobs=4",
+                "-c",
+                "opt",
+                "--vendor_dir=third party",
+                "--jobs=2"
+            ])
+        );
+        assert_eq!(
+            rc.expand("query", &strings(&["//..."])).unwrap(),
+            strings(&["--keep_going", "--jobs=1", "//..."])
+        );
+
+        let err = rc
+            .expand("build", &strings(&["--config", "nope"]))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Config value 'nope' is not defined in any .rc file"
+        );
+    }
+
+    #[test]
+    fn test_inherited_commands() {
+        let tmp = assert_fs::TempDir::new().unwrap();
+        std::fs::write(
+            tmp.path().join(".bazelrc"),
+            "build --jobs=4\n\
+             build:ci --keep_going\n\
+             test --jobs=2\n\
+             coverage --jobs=1\n",
+        )
+        .unwrap();
+        let mut rc = RcOptions::default();
+        rc.read_file(
+            &tmp.path().join(".bazelrc"),
+            Some(tmp.path()),
+            true,
+            &mut Vec::new(),
+        )
+        .unwrap();
+
+        // coverage inherits from test, and so from build.
+        assert_eq!(
+            rc.expand("coverage", &[]).unwrap(),
+            strings(&["--jobs=4", "--jobs=2", "--jobs=1"])
+        );
+        for command in ["cquery", "aquery", "info", "fetch"] {
+            assert_eq!(
+                rc.expand(command, &strings(&["--config=ci"])).unwrap(),
+                strings(&["--jobs=4", "--keep_going"]),
+                "{command}"
+            );
+        }
+        // query doesn't inherit from build.
+        assert_eq!(rc.expand("query", &[]).unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_config_and_import_cycles() {
+        let tmp = assert_fs::TempDir::new().unwrap();
+        let rc_path = tmp.path().join(".bazelrc");
+        std::fs::write(&rc_path, "build:a --config=b\nbuild:b --config=a\n").unwrap();
+        let mut rc = RcOptions::default();
+        rc.read_file(&rc_path, None, true, &mut Vec::new()).unwrap();
+        let err = rc.expand("build", &strings(&["--config=a"])).unwrap_err();
+        assert!(err.to_string().contains("a -> b -> a"), "{err}");
+
+        std::fs::write(&rc_path, "import other.bazelrc\n").unwrap();
+        std::fs::write(tmp.path().join("other.bazelrc"), "import .bazelrc\n").unwrap();
+        let err = RcOptions::default()
+            .read_file(&rc_path, None, true, &mut Vec::new())
+            .unwrap_err();
+        assert!(err.to_string().starts_with("Import loop detected"), "{err}");
+    }
+
+    #[test]
+    fn test_expand_command_line() {
+        let tmp = assert_fs::TempDir::new().unwrap();
+        let rc_path = tmp.path().join(".bazelrc");
+        std::fs::write(&rc_path, "build -c dbg\nstartup --output_base=/tmp/out\n").unwrap();
+        let mut rc = RcOptions::default();
+        rc.read_file(&rc_path, None, true, &mut Vec::new()).unwrap();
+
+        assert_eq!(
+            expand_command_line(
+                &rc,
+                "build",
+                &strings(&["razel", "--keep_going", "build", "//..."])
+            )
+            .unwrap(),
+            strings(&[
+                "razel",
+                "--output_base=/tmp/out",
+                "--keep_going",
+                "build",
+                "-c",
+                "dbg",
+                "//..."
+            ])
+        );
+    }
+
+    #[test]
+    fn test_expand_negations() {
+        let cli = <crate::Cli as clap::CommandFactory>::command();
+        assert_eq!(
+            expand_negations(
+                &cli,
+                strings(&[
+                    "razel",
+                    "--nobatch",
+                    "run",
+                    "--noshow_progress",
+                    "--nojobs",
+                    "//:bin",
+                    "--",
+                    "--nokeep_going"
+                ])
+            ),
+            strings(&[
+                "razel",
+                "--batch=false",
+                "run",
+                "--show_progress=false",
+                "--nojobs",
+                "//:bin",
+                "--",
+                "--nokeep_going"
+            ])
+        );
+    }
+}
@@ -0,0 +1,702 @@
+//! A client for Bazel registries, such as the Bazel Central Registry, from which the modules of the dependency graph
+//! are discovered and downloaded.
+//!
+//! A registry is a tree of files under a URL: `bazel_registry.json` with its mirrors, and for each module
+//! `modules/<name>/metadata.json` listing its versions, then `MODULE.bazel` and `source.json` for each version. Files
+//! are fetched with the `Downloader`, so `file://` registries work too. The files of a version never change once
+//! published, so they're kept in a local cache and only fetched once; the others are fetched once per invocation.
+//! Checksums from the lockfile's `registryFileHashes` are checked when given, and the checksum of every file fetched
+//! is recorded for writing it.
+//!
+//! With several registries, given by repeated `--registry` flags, a module comes from the first that has it. As the
+//! lockfile records which registries didn't have its `MODULE.bazel`, as `not found`, and the checksum of the one that
+//! did, a module stays pinned to the registry it was first found in without asking the others again.
+
+use crate::bazel::download::{Downloader, FailureKind, Integrity};
+use crate::bazel::policy::{DependencyPolicy, PolicyViolation};
+use crate::error::{FetchError, ResolutionError};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use sha2::{Digest as _, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// The Bazel Central Registry, used when no `--registry` is given.
+pub const BAZEL_CENTRAL_REGISTRY: &str = "https://bcr.bazel.build";
+
+/// What `registryFileHashes` records for a file a registry doesn't have.
+const NOT_FOUND: &str = "not found";
+
+/// `bazel_registry.json`, which registries may leave out.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct RegistryConfig {
+    /// URL prefixes to try archives at before their own URLs.
+    #[serde(default)]
+    pub mirrors: Vec<String>,
+    /// The directory `local_path` sources are relative to, itself relative to the registry.
+    #[serde(default)]
+    pub module_base_path: Option<String>,
+}
+
+/// `modules/<name>/metadata.json`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct Metadata {
+    #[serde(default)]
+    pub versions: Vec<String>,
+    /// Versions that shouldn't be used any more, with the reason.
+    #[serde(default)]
+    pub yanked_versions: BTreeMap<String, String>,
+}
+
+/// `modules/<name>/<version>/source.json`: where the files of a module version come from.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Source {
+    Archive {
+        url: String,
+        #[serde(default)]
+        mirror_urls: Vec<String>,
+        integrity: String,
+        #[serde(default)]
+        strip_prefix: String,
+        /// Patches from the registry's `patches` directory for the version, by file name, with their integrity.
+        #[serde(default)]
+        patches: BTreeMap<String, String>,
+        #[serde(default)]
+        patch_strip: u32,
+        /// Files from the registry's `overlay` directory for the version, by path, with their integrity.
+        #[serde(default)]
+        overlay: BTreeMap<String, String>,
+    },
+    GitRepository {
+        remote: String,
+        #[serde(default)]
+        commit: String,
+        #[serde(default)]
+        tag: String,
+        #[serde(default)]
+        strip_prefix: String,
+        #[serde(default)]
+        patches: BTreeMap<String, String>,
+        #[serde(default)]
+        patch_strip: u32,
+    },
+    LocalPath {
+        path: String,
+    },
+}
+
+pub struct Registry {
+    /// The URL of the registry, without a trailing `/`.
+    url: String,
+    /// Where the files of module versions are cached, by their path in the registry.
+    cache_dir: PathBuf,
+    downloader: Downloader,
+    /// Expected checksums of registry files by URL, from the lockfile.
+    hashes: BTreeMap<String, String>,
+    /// The files fetched so far by URL, `None` for those the registry doesn't have.
+    fetched: Mutex<HashMap<String, Option<Vec<u8>>>>,
+    /// The checksum of each immutable file fetched, by URL, in hex. Files that change as a registry does, like
+    /// `metadata.json`, aren't recorded, as in Bazel.
+    fetched_hashes: Mutex<BTreeMap<String, String>>,
+}
+
+impl Registry {
+    /// A client for the registry at `url`, caching files under `cache_root`, if `policy` allows the registry.
+    pub fn new(
+        url: &str,
+        cache_root: PathBuf,
+        downloader: Downloader,
+        policy: &DependencyPolicy,
+    ) -> Result<Self, PolicyViolation> {
+        policy.check_registry(url, "--registry")?;
+        let url = url.trim_end_matches('/').to_string();
+        let cache_dir = cache_root.join(crate::bazel::digest::to_hex(&Sha256::digest(&url)));
+        Ok(Self {
+            url,
+            cache_dir,
+            downloader,
+            hashes: BTreeMap::new(),
+            fetched: Mutex::new(HashMap::new()),
+            fetched_hashes: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// Checks files against the checksums `hashes`, by URL, as recorded in the lockfile.
+    pub fn with_file_hashes(mut self, hashes: BTreeMap<String, String>) -> Self {
+        self.hashes = hashes;
+        self
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// The checksums of the immutable files fetched so far, by URL, to record in the lockfile.
+    pub fn file_hashes(&self) -> BTreeMap<String, String> {
+        self.fetched_hashes.lock().unwrap().clone()
+    }
+
+    /// The file at `path` in the registry, or `None` if the registry doesn't have it. An `immutable` file is taken from
+    /// the cache if it's there.
+    async fn fetch(&self, path: &str, immutable: bool) -> anyhow::Result<Option<Vec<u8>>> {
+        let url = format!("{}/{path}", self.url);
+        if let Some(content) = self.fetched.lock().unwrap().get(&url) {
+            return Ok(content.clone());
+        }
+        let cached = self.cache_dir.join(path);
+        let expected = match self.hashes.get(&url).map(String::as_str) {
+            Some(NOT_FOUND) => None,
+            hash => hash.map(Integrity::parse).transpose()?,
+        };
+        let content = if self.hashes.get(&url).is_some_and(|hash| hash == NOT_FOUND) {
+            None
+        } else if immutable && let Some(content) = self.cached(&cached, expected).await? {
+            Some(content)
+        } else {
+            // Downloaded next to the cached file and renamed into place, so that an interrupted download or another
+            // invocation fetching the same file never leaves a partial one there.
+            let name = cached.file_name().unwrap_or_default().to_string_lossy();
+            let tmp = cached.with_file_name(self.downloader.providers().temp_name(&name));
+            match self
+                .downloader
+                .download(std::slice::from_ref(&url), &tmp, expected.as_ref())
+                .await
+            {
+                Ok(_) => {
+                    let content = tokio::fs::read(&tmp).await?;
+                    tokio::fs::rename(&tmp, &cached).await?;
+                    Some(content)
+                }
+                Err(e) if not_found(&e) => None,
+                Err(e) => return Err(e),
+            }
+        };
+        if immutable {
+            let hash = match &content {
+                Some(content) => crate::bazel::digest::to_hex(&Sha256::digest(content)),
+                None => NOT_FOUND.to_string(),
+            };
+            self.fetched_hashes
+                .lock()
+                .unwrap()
+                .insert(url.clone(), hash);
+        }
+        self.fetched.lock().unwrap().insert(url, content.clone());
+        Ok(content)
+    }
+
+    /// The content of the cached file at `path`, if there is one and it has the checksum `expected` from the lockfile.
+    /// One with another checksum is removed, to be fetched again.
+    async fn cached(
+        &self,
+        path: &std::path::Path,
+        expected: Option<Integrity>,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let content = match tokio::fs::read(path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if let Some(expected) = expected
+            && !expected.matches(&content)?
+        {
+            tracing::warn!("Ignoring corrupted cache entry {}", path.display());
+            tokio::fs::remove_file(path).await?;
+            return Ok(None);
+        }
+        Ok(Some(content))
+    }
+
+    async fn fetch_json<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        immutable: bool,
+    ) -> anyhow::Result<Option<T>> {
+        let Some(content) = self.fetch(path, immutable).await? else {
+            return Ok(None);
+        };
+        let value =
+            serde_json::from_slice(&content).map_err(|e| ResolutionError::InvalidRegistryFile {
+                url: format!("{}/{path}", self.url),
+                reason: e.to_string(),
+            })?;
+        Ok(Some(value))
+    }
+
+    fn not_found(&self, module: &str, version: Option<&str>) -> anyhow::Error {
+        ResolutionError::ModuleNotFound {
+            module: module.to_string(),
+            version: version.map(String::from),
+            registry: self.url.clone(),
+        }
+        .into()
+    }
+
+    /// The registry's `bazel_registry.json`, or the default if it has none.
+    pub async fn config(&self) -> anyhow::Result<RegistryConfig> {
+        Ok(self
+            .fetch_json("bazel_registry.json", false)
+            .await?
+            .unwrap_or_default())
+    }
+
+    /// The versions of `module` the registry has.
+    pub async fn metadata(&self, module: &str) -> anyhow::Result<Metadata> {
+        self.fetch_json(&format!("modules/{module}/metadata.json"), false)
+            .await?
+            .ok_or_else(|| self.not_found(module, None))
+    }
+
+    /// The `MODULE.bazel` of `module` at `version`.
+    pub async fn module_file(&self, module: &str, version: &str) -> anyhow::Result<Vec<u8>> {
+        self.fetch(&format!("modules/{module}/{version}/MODULE.bazel"), true)
+            .await?
+            .ok_or_else(|| self.not_found(module, Some(version)))
+    }
+
+    /// Where the files of `module` at `version` come from. The URLs of archives include the registry's mirrors, and
+    /// the paths of local sources are made absolute for `file://` registries.
+    pub async fn source(&self, module: &str, version: &str) -> anyhow::Result<Source> {
+        let path = format!("modules/{module}/{version}/source.json");
+        let Some(mut json) = self.fetch_json::<serde_json::Value>(&path, true).await? else {
+            return Err(self.not_found(module, Some(version)));
+        };
+        // Archives are the default type.
+        if let Some(object) = json.as_object_mut() {
+            object
+                .entry("type")
+                .or_insert_with(|| serde_json::Value::from("archive"));
+        }
+        let source =
+            serde_json::from_value(json).map_err(|e| ResolutionError::InvalidRegistryFile {
+                url: format!("{}/{path}", self.url),
+                reason: e.to_string(),
+            })?;
+        Ok(match source {
+            Source::Archive {
+                url,
+                mirror_urls,
+                integrity,
+                strip_prefix,
+                patches,
+                patch_strip,
+                overlay,
+            } => {
+                let config = self.config().await?;
+                let mut urls: Vec<String> = config
+                    .mirrors
+                    .iter()
+                    .filter_map(|mirror| mirror_url(mirror, &url))
+                    .collect();
+                urls.extend(mirror_urls);
+                Source::Archive {
+                    url,
+                    mirror_urls: urls,
+                    integrity,
+                    strip_prefix,
+                    patches,
+                    patch_strip,
+                    overlay,
+                }
+            }
+            Source::LocalPath { path } => match self.url.strip_prefix("file://") {
+                Some(root) if !path.starts_with('/') => {
+                    let base = self.config().await?.module_base_path.unwrap_or_default();
+                    let path = PathBuf::from(root).join(base).join(path);
+                    Source::LocalPath {
+                        path: path.display().to_string(),
+                    }
+                }
+                _ => Source::LocalPath { path },
+            },
+            source => source,
+        })
+    }
+
+    /// The URL of a file of the `patches` or `overlay` of a source, `kind`, for `module` at `version`.
+    pub fn file_url(&self, module: &str, version: &str, kind: &str, name: &str) -> String {
+        format!("{}/modules/{module}/{version}/{kind}/{name}", self.url)
+    }
+}
+
+/// The registries modules are looked up in, in order.
+pub struct Registries {
+    registries: Vec<Registry>,
+}
+
+impl Registries {
+    /// The registries at `urls`, or the Bazel Central Registry if there are none, checking files against `hashes`
+    /// from the lockfile.
+    pub fn new(
+        urls: &[String],
+        cache_root: PathBuf,
+        downloader: Downloader,
+        policy: &DependencyPolicy,
+        hashes: &BTreeMap<String, String>,
+    ) -> Result<Self, PolicyViolation> {
+        let default = [BAZEL_CENTRAL_REGISTRY.to_string()];
+        let urls = if urls.is_empty() { &default[..] } else { urls };
+        let registries = urls
+            .iter()
+            .map(|url| {
+                let registry = Registry::new(url, cache_root.clone(), downloader.clone(), policy)?;
+                let prefix = format!("{}/", registry.url());
+                let hashes = hashes
+                    .iter()
+                    .filter(|(url, _)| url.starts_with(&prefix))
+                    .map(|(url, hash)| (url.clone(), hash.clone()))
+                    .collect();
+                Ok(registry.with_file_hashes(hashes))
+            })
+            .collect::<Result<_, PolicyViolation>>()?;
+        Ok(Self { registries })
+    }
+
+    /// The first registry that has `module` at `version`.
+    pub async fn find(&self, module: &str, version: &str) -> anyhow::Result<&Registry> {
+        for registry in &self.registries {
+            let path = format!("modules/{module}/{version}/MODULE.bazel");
+            if registry.fetch(&path, true).await?.is_some() {
+                return Ok(registry);
+            }
+        }
+        Err(ResolutionError::ModuleNotFound {
+            module: module.to_string(),
+            version: Some(version.to_string()),
+            registry: self
+                .registries
+                .iter()
+                .map(Registry::url)
+                .collect::<Vec<_>>()
+                .join(", "),
+        }
+        .into())
+    }
+
+    /// Fails if the registry a module comes from yanked the version it `resolved` to, by name, unless `allowed`, from
+    /// `--allow_yanked_versions`, lists it as `<module>@<version>` or is `all`. Modules resolved to the empty version
+    /// come from overrides rather than registries, and aren't checked, nor are modules without a `metadata.json`.
+    pub async fn check_yanked(
+        &self,
+        resolved: &BTreeMap<String, String>,
+        allowed: &[String],
+    ) -> anyhow::Result<()> {
+        if allowed.iter().any(|a| a == "all") {
+            return Ok(());
+        }
+        let mut yanked = BTreeMap::new();
+        for (module, version) in resolved {
+            let key = format!("{module}@{version}");
+            if version.is_empty() || allowed.contains(&key) {
+                continue;
+            }
+            let metadata = match self.find(module, version).await?.metadata(module).await {
+                Ok(metadata) => metadata,
+                Err(e)
+                    if matches!(
+                        e.downcast_ref(),
+                        Some(ResolutionError::ModuleNotFound { .. })
+                    ) =>
+                {
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            if let Some(reason) = metadata.yanked_versions.get(version) {
+                yanked.insert(key, reason.clone());
+            }
+        }
+        if yanked.is_empty() {
+            return Ok(());
+        }
+        Err(ResolutionError::YankedVersions { yanked }.into())
+    }
+
+    /// The checksums of the files fetched from every registry, by URL, for the lockfile's `registryFileHashes`.
+    pub fn file_hashes(&self) -> BTreeMap<String, String> {
+        self.registries
+            .iter()
+            .flat_map(Registry::file_hashes)
+            .collect()
+    }
+}
+
+/// `url` at `mirror`, which serves URLs by their host and path, e.g. `https://mirror/github.com/...`.
+fn mirror_url(mirror: &str, url: &str) -> Option<String> {
+    let (_, rest) = url.split_once("://")?;
+    Some(format!("{}/{rest}", mirror.trim_end_matches('/')))
+}
+
+/// Whether fetching a file failed because there's nothing at its URL.
+fn not_found(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<FetchError>(),
+        Some(FetchError::DownloadFailed {
+            kind: Some(FailureKind::NotFound),
+            ..
+        })
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(root: &std::path::Path, cache: &std::path::Path) -> Registry {
+        let url = format!("file://{}", root.display());
+        Registry::new(
+            &url,
+            cache.to_path_buf(),
+            Downloader::new(None),
+            &DependencyPolicy::default(),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_registry() {
+        let tmp = assert_fs::TempDir::new().unwrap();
+        let root = tmp.path().join("registry");
+        let cache = tmp.path().join("cache");
+        let write = |path: &str, content: &str| {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        };
+        write(
+            "bazel_registry.json",
+            r#"{"mirrors": ["https://mirror.example/"], "module_base_path": "local"}"#,
+        );
+        write(
+            "modules/zlib/metadata.json",
+            r#"{"versions": ["1.2.13", "1.3"], "yanked_versions": {"1.2.13": "CVE"}}"#,
+        );
+        write(
+            "modules/zlib/1.3/MODULE.bazel",
+            "module(name = \"zlib\", version = \"1.3\")\n",
+        );
+        write(
+            "modules/zlib/1.3/source.json",
+            r#"{"url": "https://github.com/madler/zlib/zlib-1.3.tar.gz", "integrity": "sha256-x",
+                "strip_prefix": "zlib-1.3", "patches": {"build.patch": "sha256-y"}, "patch_strip": 1}"#,
+        );
+        write(
+            "modules/tool/1.0/source.json",
+            r#"{"type": "local_path", "path": "tool"}"#,
+        );
+
+        let registry = open(&root, &cache);
+        let metadata = registry.metadata("zlib").await.unwrap();
+        assert_eq!(metadata.versions, ["1.2.13", "1.3"]);
+        assert_eq!(metadata.yanked_versions["1.2.13"], "CVE");
+        assert!(
+            registry
+                .module_file("zlib", "1.3")
+                .await
+                .unwrap()
+                .starts_with(b"module(")
+        );
+        let Source::Archive {
+            mirror_urls,
+            patches,
+            ..
+        } = registry.source("zlib", "1.3").await.unwrap()
+        else {
+            panic!("zlib is an archive");
+        };
+        assert_eq!(
+            mirror_urls,
+            ["https://mirror.example/github.com/madler/zlib/zlib-1.3.tar.gz"]
+        );
+        assert_eq!(patches["build.patch"], "sha256-y");
+        assert_eq!(
+            registry.source("tool", "1.0").await.unwrap(),
+            Source::LocalPath {
+                path: root.join("local/tool").display().to_string()
+            }
+        );
+        assert!(
+            registry
+                .file_hashes()
+                .contains_key(&format!("{}/modules/zlib/1.3/MODULE.bazel", registry.url()))
+        );
+
+        let err = registry.metadata("absl").await.unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref::<ResolutionError>(),
+                Some(ResolutionError::ModuleNotFound { .. })
+            ),
+            "{err:#}"
+        );
+
+        // The files of a version come from the cache once fetched, but metadata is fetched again.
+        std::fs::remove_dir_all(root.join("modules/zlib")).unwrap();
+        let registry = open(&root, &cache);
+        assert!(registry.module_file("zlib", "1.3").await.is_ok());
+        assert!(registry.metadata("zlib").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_registry_cache_checked_against_lockfile() {
+        let tmp = assert_fs::TempDir::new().unwrap();
+        let root = tmp.path().join("registry");
+        let cache = tmp.path().join("cache");
+        let module_file = root.join("modules/zlib/1.3/MODULE.bazel");
+        std::fs::create_dir_all(module_file.parent().unwrap()).unwrap();
+        std::fs::write(&module_file, "module(name = \"zlib\")\n").unwrap();
+
+        let registry = open(&root, &cache);
+        registry.module_file("zlib", "1.3").await.unwrap();
+        let hashes = registry.file_hashes();
+        // Only the file itself is left in the cache, not the one it was downloaded to.
+        let cached = registry.cache_dir.join("modules/zlib/1.3");
+        let files: Vec<_> = std::fs::read_dir(&cached)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(files, ["MODULE.bazel"]);
+
+        // A cached file cut short is fetched again when the lockfile has its checksum.
+        std::fs::write(cached.join("MODULE.bazel"), "module(na").unwrap();
+        let registry = open(&root, &cache).with_file_hashes(hashes);
+        assert_eq!(
+            registry.module_file("zlib", "1.3").await.unwrap(),
+            b"module(name = \"zlib\")\n"
+        );
+        assert_eq!(
+            std::fs::read(cached.join("MODULE.bazel")).unwrap(),
+            b"module(name = \"zlib\")\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_registries() {
+        let tmp = assert_fs::TempDir::new().unwrap();
+        let url = |name: &str| format!("file://{}", tmp.path().join(name).display());
+        let write = |path: &str| {
+            let path = tmp.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "module()\n").unwrap();
+        };
+        write("private/modules/internal/1.0/MODULE.bazel");
+        write("public/modules/zlib/1.3/MODULE.bazel");
+        write("private/modules/zlib/1.3.1/MODULE.bazel");
+        write("public/modules/zlib/1.3.1/MODULE.bazel");
+        let open = |hashes: &BTreeMap<String, String>| {
+            Registries::new(
+                &[url("private"), url("public")],
+                tmp.path().join("cache"),
+                Downloader::new(None),
+                &DependencyPolicy::default(),
+                hashes,
+            )
+            .unwrap()
+        };
+
+        let registries = open(&BTreeMap::new());
+        let find = async |registries: &Registries, module: &str, version: &str| {
+            registries
+                .find(module, version)
+                .await
+                .map(|registry| registry.url().to_string())
+        };
+        assert_eq!(
+            find(&registries, "internal", "1.0").await.unwrap(),
+            url("private")
+        );
+        assert_eq!(
+            find(&registries, "zlib", "1.3").await.unwrap(),
+            url("public")
+        );
+        assert_eq!(
+            find(&registries, "zlib", "1.3.1").await.unwrap(),
+            url("private")
+        );
+        let err = find(&registries, "absl", "1.0").await.unwrap_err();
+        assert!(err.to_string().contains("absl@1.0 not found"), "{err:#}");
+
+        // The lockfile pins zlib 1.3 to the public registry, even once the private one has it too.
+        let hashes = registries.file_hashes();
+        assert_eq!(
+            hashes[&format!("{}/modules/zlib/1.3/MODULE.bazel", url("private"))],
+            "not found"
+        );
+        write("private/modules/zlib/1.3/MODULE.bazel");
+        let registries = open(&hashes);
+        assert_eq!(
+            find(&registries, "zlib", "1.3").await.unwrap(),
+            url("public")
+        );
+        assert_eq!(
+            open(&BTreeMap::new())
+                .find("zlib", "1.3")
+                .await
+                .unwrap()
+                .url(),
+            url("private")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_yanked() {
+        let tmp = assert_fs::TempDir::new().unwrap();
+        let root = tmp.path().join("registry");
+        for (path, content) in [
+            (
+                "modules/zlib/metadata.json",
+                r#"{"versions": ["1.2.13", "1.3"], "yanked_versions": {"1.2.13": "CVE-2022-37434"}}"#,
+            ),
+            ("modules/zlib/1.2.13/MODULE.bazel", "module()\n"),
+            ("modules/zlib/1.3/MODULE.bazel", "module()\n"),
+        ] {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        let registries = Registries::new(
+            &[format!("file://{}", root.display())],
+            tmp.path().join("cache"),
+            Downloader::new(None),
+            &DependencyPolicy::default(),
+            &BTreeMap::new(),
+        )
+        .unwrap();
+        let resolved = |version: &str| {
+            BTreeMap::from([
+                ("zlib".to_string(), version.to_string()),
+                ("overridden".to_string(), String::new()),
+            ])
+        };
+
+        assert!(registries.check_yanked(&resolved("1.3"), &[]).await.is_ok());
+        let err = registries
+            .check_yanked(&resolved("1.2.13"), &[])
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("zlib@1.2.13, for the reason: CVE-2022-37434"),
+            "{err:#}"
+        );
+        for allowed in ["zlib@1.2.13", "all"] {
+            assert!(
+                registries
+                    .check_yanked(&resolved("1.2.13"), &[allowed.to_string()])
+                    .await
+                    .is_ok()
+            );
+        }
+    }
+
+    #[test]
+    fn test_mirror_url() {
+        assert_eq!(
+            mirror_url("https://m/", "https://github.com/a/b.tgz").unwrap(),
+            "https://m/github.com/a/b.tgz"
+        );
+        assert_eq!(mirror_url("https://m", "b.tgz"), None);
+    }
+}
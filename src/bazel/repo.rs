@@ -4,11 +4,13 @@ use futures::future::{BoxFuture, FusedFuture, FutureExt};
 
 use crate::{
     bazel::{
-        label::{ApparentRepo, CanonicalLabel, CanonicalRepo, Label, MAIN_REPO},
+        label::{ApparentRepo, CanonicalLabel, CanonicalRepo, Label, MAIN_REPO, Repo, parse_label},
+        module_graph::extension_host_of_repo,
         package::{
             BoxFile, BoxFileStore, Digest, DigestFunction, DirEntry, DynFileStore, File, FileStore,
             Package,
         },
+        repo_mapping::RepoMapping,
     },
     workspace::Workspace,
 };
@@ -26,7 +28,7 @@ use tokio::io::AsyncReadExt;
 pub struct Repository<'a> {
     repo_name: ApparentRepo<'a>,
     canonical_name: CanonicalRepo<'a>,
-    repo_mapping: HashMap<ApparentRepo<'a>, CanonicalRepo<'a>>,
+    repo_mapping: RepoMapping<'a>,
     files: BoxFileStore<'a>,
     // TODO: include info from REPO.bazel, and use in read_package()
 }
@@ -40,32 +42,100 @@ impl<'a> Repository<'a> {
     where
         'a: 'static,
     {
+        if let Some(host) = extension_host_of_repo(canonical_name.as_str()) {
+            // A repository generated by a module extension has no MODULE.bazel, and sees what the module defining the
+            // extension sees.
+            let host = workspace
+                .repository(&CanonicalRepo::new(host.to_string()))
+                .await?;
+            return Ok(Self {
+                repo_name: ApparentRepo::new(""),
+                canonical_name,
+                repo_mapping: host.repo_mapping().clone(),
+                files,
+            });
+        }
+
         let is_root = canonical_name == MAIN_REPO;
         // Pass the file store to eval_module to handle reading MODULE.bazel and includes
-        let module = crate::bazel::bzlmod::eval_module(&files, "MODULE.bazel", is_root).await?;
+        let module = crate::bazel::bzlmod::eval_module(
+            &files,
+            "MODULE.bazel",
+            is_root,
+            workspace.ignore_dev_dependency(),
+            workspace.eval_limits(),
+        )
+        .await?;
 
         if is_root {
-            // TODO: copy overrides into Workspace
+            for (registry, function) in module.overrides.registries() {
+                workspace
+                    .dependency_policy()
+                    .check_registry(registry, &format!("{function} in MODULE.bazel"))?;
+            }
+            workspace.set_overrides(module.overrides.clone());
+            workspace.resolve(&module).await?;
         }
 
-        let mut repo_mapping = HashMap::with_capacity(module.bazel_deps.len());
+        let mut repo_mapping = RepoMapping::with_capacity(module.bazel_deps.len() + 1);
         for dep in module.bazel_deps {
             // TODO: this should go via a Workspace method so we can pick up overrides.
-
-            let canonical_name = CanonicalRepo::new(format!("{}+{}", dep.name, dep.version));
+            workspace.dependency_policy().check_module(
+                &dep.name,
+                &format!("bazel_dep in {canonical_name}//:MODULE.bazel"),
+            )?;
+
+            let version = workspace
+                .dep_version(&dep.name, &dep.version, &canonical_name)
+                .await?;
+            let canonical_name = CanonicalRepo::new(format!("{}+{version}", dep.name));
             repo_mapping.insert(
                 ApparentRepo::new(dep.repo_name.clone()),
                 canonical_name.clone(),
             );
 
             // Create Repository in Workspace (if it doesn't already exist)
-            // TODO: This bit should move into a method on Workspace
-            let repo = async {
-                anyhow::bail!("Not implemented");
-            };
-            workspace.add_repository(canonical_name, repo);
+            workspace.add_repository_if_absent(canonical_name.clone(), || {
+                workspace.fetch_repository(canonical_name)
+            });
         }
 
+        // A module can refer to itself by its own repo name, like any of its dependencies.
+        repo_mapping.insert(
+            ApparentRepo::new(module.repo_name.clone()),
+            canonical_name.clone(),
+        );
+        // Then the repositories it imports from module extensions, named for the extension as Bazel names them:
+        // `<canonical name of the extension's repository>+<extension name>+<repository name>`.
+        let mut imports = Vec::new();
+        for usage in &module.extension_usages {
+            let context = Label::new(Repo::Canonical(canonical_name.as_borrowed()), "", "");
+            let bzl_file = &usage.extension_bzl_file;
+            let label = parse_label(bzl_file, &context)
+                .map_err(|e| anyhow::anyhow!("Invalid extension label {bzl_file:?}: {e}"))?;
+            let extension_repo = label.to_canonical(&repo_mapping).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "use_extension in {canonical_name}//:MODULE.bazel refers to {bzl_file}, whose repository isn't visible from it"
+                )
+            })?;
+            let extension = format!(
+                "{}+{}",
+                extension_repo.repo().as_str(),
+                usage.extension_name
+            );
+            for (apparent, exported) in &usage.imports {
+                imports.push((
+                    ApparentRepo::new(apparent.clone()),
+                    CanonicalRepo::new(format!("{extension}+{exported}")),
+                ));
+            }
+        }
+        for (apparent, canonical) in imports {
+            repo_mapping.insert(apparent, canonical.clone());
+            workspace.add_repository_if_absent(canonical.clone(), || {
+                workspace.fetch_repository(canonical)
+            });
+        }
         let repo_name = ApparentRepo::new(module.repo_name);
 
         Ok(Self {
@@ -80,6 +150,11 @@ impl<'a> Repository<'a> {
         self.canonical_name.clone()
     }
 
+    /// The apparent repository names visible from this repository, and their canonical names.
+    pub fn repo_mapping(&self) -> &RepoMapping<'a> {
+        &self.repo_mapping
+    }
+
     /// Resolves an apparent repository name in this repository's mapping.
     pub fn resolve_repo<'repo>(
         &'repo self,
@@ -88,9 +163,7 @@ impl<'a> Repository<'a> {
     where
         'a: 'repo,
     {
-        self.repo_mapping
-            .get(apparent)
-            .map(CanonicalRepo::as_borrowed)
+        self.repo_mapping.get(apparent)
     }
 
     /// Resolves the apparent repository portion of a label in this repository's mapping.
@@ -98,7 +171,7 @@ impl<'a> Repository<'a> {
     where
         'a: 'repo,
     {
-        label.into_canonical(|apparent| self.resolve_repo(apparent))
+        label.to_canonical(&self.repo_mapping)
     }
 
     pub fn files(&self) -> &BoxFileStore<'a> {
@@ -186,7 +259,9 @@ impl<'a> Repository<'a> {
             format!("{}/{}", pkg.path, pkg.build_file_name)
         };
 
-        crate::starlark::eval::eval_build(workspace, self.clone(), &build_path).await
+        let rules = crate::starlark::eval::eval_build(workspace, self.clone(), &build_path).await?;
+        crate::metrics::METRICS.record_package_loaded(rules.len());
+        Ok(rules)
     }
 }
 
@@ -262,100 +337,38 @@ impl File for LocalFile {
 
     fn digest(
         &self,
-        _digest_function: DigestFunction,
+        digest_function: DigestFunction,
     ) -> BoxFuture<'_, Result<Digest, std::io::Error>> {
-        async move { todo!("Implement digest for LocalFile") }.boxed()
-    }
-}
-
-#[derive(Debug, Clone)]
-pub struct InMemoryFileStore {
-    files: HashMap<String, Vec<u8>>,
-}
-
-impl InMemoryFileStore {
-    pub fn new(files: HashMap<String, Vec<u8>>) -> Self {
-        Self { files }
-    }
-}
-
-impl FileStore for InMemoryFileStore {
-    type File = InMemoryFile;
-
-    fn read_file(&self, path: &str) -> BoxFuture<'_, Result<Self::File, std::io::Error>> {
-        let path_str = path.to_string();
         async move {
-            // This files.get() is deliberately delayed until the future executes, since it represents the "expensive" read_file operation.
-            let content = self.files.get(&path_str).cloned();
-            if let Some(content) = content {
-                Ok(InMemoryFile { content })
-            } else {
-                Err(std::io::Error::new(
-                    std::io::ErrorKind::NotFound,
-                    format!("File not found: {}", path_str),
-                ))
-            }
+            let content = fs::read(&self.path).await?;
+            crate::bazel::digest::compute(digest_function, &content)
         }
         .boxed()
     }
 
-    fn read_dir(&self, path: &str) -> BoxFuture<'_, Result<Vec<DirEntry>, std::io::Error>> {
-        let dir_path = std::path::PathBuf::from(path);
+    fn is_executable(&self) -> BoxFuture<'_, Result<bool, std::io::Error>> {
         async move {
-            let mut entries = HashMap::new();
-            for file_path_str in self.files.keys() {
-                if let Ok(stripped) = std::path::Path::new(file_path_str).strip_prefix(&dir_path) {
-                    let mut components = stripped.components();
-                    if let Some(std::path::Component::Normal(name)) = components.next() {
-                        let name_str = name.to_string_lossy().into_owned();
-                        if components.next().is_some() {
-                            entries.insert(name_str, true);
-                        } else {
-                            entries.entry(name_str).or_insert(false);
-                        }
-                    }
-                }
+            let metadata = fs::metadata(&self.path).await?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                Ok(metadata.permissions().mode() & 0o111 != 0)
+            }
+            #[cfg(not(unix))]
+            {
+                let _ = metadata;
+                Ok(false)
             }
-            Ok(entries
-                .into_iter()
-                .map(|(name, is_directory)| {
-                    if is_directory {
-                        DirEntry::Directory(name)
-                    } else {
-                        DirEntry::File(name)
-                    }
-                })
-                .collect())
         }
         .boxed()
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct InMemoryFile {
-    content: Vec<u8>,
-}
-
-impl File for InMemoryFile {
-    type AsyncRead = std::io::Cursor<Vec<u8>>;
-
-    fn open(&self) -> BoxFuture<'_, Result<Self::AsyncRead, std::io::Error>> {
-        let content = self.content.clone();
-        async move { Ok(std::io::Cursor::new(content)) }.boxed()
-    }
-
-    fn digest(
-        &self,
-        _digest_function: DigestFunction,
-    ) -> BoxFuture<'_, Result<Digest, std::io::Error>> {
-        async move { todo!("Implement digest for InMemoryFile") }.boxed()
-    }
-}
-
 #[cfg(test)]
 pub mod test {
     use super::*;
     use crate::bazel::label::Repo;
+    use crate::bazel::memory::InMemoryFileStore;
     use std::collections::HashMap;
     use tokio::io::{self};
 
@@ -368,7 +381,10 @@ pub mod test {
         let repo = Repository {
             repo_name: ApparentRepo::new("root"),
             canonical_name: MAIN_REPO,
-            repo_mapping: HashMap::from([(ApparentRepo::new("dep_alias"), canonical_dep.clone())]),
+            repo_mapping: RepoMapping::from_iter([(
+                ApparentRepo::new("dep_alias"),
+                canonical_dep.clone(),
+            )]),
             files,
         };
 
@@ -409,66 +425,6 @@ pub mod test {
         assert!(repo.resolve_label(unknown_label).is_none());
     }
 
-    #[tokio::test]
-    async fn test_in_memory_file_store_read_dir() {
-        let files = HashMap::from([
-            ("a/b".to_string(), vec![]),
-            ("a/c".to_string(), vec![]),
-            ("a/d/e".to_string(), vec![]),
-            ("f".to_string(), vec![]),
-        ]);
-
-        let store = InMemoryFileStore::new(files);
-
-        // Test root directory
-        let mut root_entries: Vec<String> = store
-            .read_dir("")
-            .await
-            .unwrap()
-            .into_iter()
-            .map(|e| match e {
-                DirEntry::File(s) => s,
-                DirEntry::Directory(s) => s,
-            })
-            .collect();
-        root_entries.sort();
-        assert_eq!(root_entries, vec!["a", "f"]);
-
-        // Test subdirectory "a" (with and without trailing slash)
-        for path in ["a", "a/"] {
-            let mut entries: Vec<String> = store
-                .read_dir(path)
-                .await
-                .unwrap()
-                .into_iter()
-                .map(|e| match e {
-                    DirEntry::File(s) => s,
-                    DirEntry::Directory(s) => s,
-                })
-                .collect();
-            entries.sort();
-            assert_eq!(entries, vec!["b", "c", "d"], "Failed for path: {path}");
-        }
-
-        // Test deeper subdirectory "a/d"
-        let mut ad_entries: Vec<String> = store
-            .read_dir("a/d")
-            .await
-            .unwrap()
-            .into_iter()
-            .map(|e| match e {
-                DirEntry::File(s) => s,
-                DirEntry::Directory(s) => s,
-            })
-            .collect();
-        ad_entries.sort();
-        assert_eq!(ad_entries, vec!["e"]);
-
-        // Test non-existent directory
-        let none_entries = store.read_dir("nonexistent").await.unwrap();
-        assert!(none_entries.is_empty());
-    }
-
     #[tokio::test]
     async fn test_type_erased_map() {
         // Create a map of type-erased FileStores
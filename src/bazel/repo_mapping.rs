@@ -0,0 +1,102 @@
+//! Which repository each apparent repository name refers to, as seen from one repository.
+//!
+//! Each module's mapping is built once its dependencies are resolved: the `repo_name` of each `bazel_dep` (the module
+//! name if it has none) maps to the canonical name of the dependency's repository, and the module's own `repo_name`
+//! to its own repository. Repositories imported from module extensions with `use_repo` map to the names the extension
+//! would generate them under, `<extension's repository>+<extension name>+<name>`. Labels are made canonical with it
+//! while loading, see `Label::to_canonical`.
+
+use super::label::{ApparentRepo, CanonicalRepo, MAIN_REPO};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RepoMapping<'a> {
+    repos: HashMap<ApparentRepo<'a>, CanonicalRepo<'a>>,
+}
+
+impl<'a> RepoMapping<'a> {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            repos: HashMap::with_capacity(capacity),
+        }
+    }
+
+    /// Makes `apparent` refer to `canonical`, replacing whatever it referred to before.
+    pub fn insert(&mut self, apparent: ApparentRepo<'a>, canonical: CanonicalRepo<'a>) {
+        self.repos.insert(apparent, canonical);
+    }
+
+    /// The repository `apparent` refers to, borrowed from the mapping. The empty name is the main repository, as in
+    /// `@//pkg:target`, from every repository.
+    pub fn get<'m>(&'m self, apparent: &ApparentRepo<'_>) -> Option<CanonicalRepo<'m>> {
+        if apparent.as_str().is_empty() {
+            return Some(MAIN_REPO);
+        }
+        self.repos
+            .get(apparent.as_str())
+            .map(CanonicalRepo::as_borrowed)
+    }
+
+    /// Every apparent name in the mapping, with the repository it refers to, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&ApparentRepo<'a>, &CanonicalRepo<'a>)> {
+        self.repos.iter()
+    }
+
+    /// The repositories the mapping refers to, once for each name that refers to them.
+    pub fn values(&self) -> impl Iterator<Item = &CanonicalRepo<'a>> {
+        self.repos.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.repos.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.repos.is_empty()
+    }
+}
+
+impl<'a> FromIterator<(ApparentRepo<'a>, CanonicalRepo<'a>)> for RepoMapping<'a> {
+    fn from_iter<I: IntoIterator<Item = (ApparentRepo<'a>, CanonicalRepo<'a>)>>(iter: I) -> Self {
+        Self {
+            repos: iter.into_iter().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bazel::label::{MAIN_REPO_ROOT, parse_label};
+
+    #[test]
+    fn test_to_canonical() {
+        let mapping: RepoMapping = [
+            (ApparentRepo::new("app"), MAIN_REPO),
+            (ApparentRepo::new("zlib"), CanonicalRepo::new("zlib+1.3")),
+            (ApparentRepo::new("z"), CanonicalRepo::new("zlib+1.3")),
+        ]
+        .into_iter()
+        .collect();
+        let canonical = |label: &str| {
+            parse_label(label, &MAIN_REPO_ROOT)
+                .unwrap()
+                .to_canonical(&mapping)
+                .map(|l| l.to_string())
+        };
+
+        assert_eq!(
+            canonical("@zlib//:zlib").as_deref(),
+            Some("@@zlib+1.3//:zlib")
+        );
+        assert_eq!(canonical("@z//:zlib").as_deref(), Some("@@zlib+1.3//:zlib"));
+        assert_eq!(canonical("@app//pkg:t").as_deref(), Some("@@//pkg:t"));
+        assert_eq!(canonical("@//pkg:t").as_deref(), Some("@@//pkg:t"));
+        assert_eq!(
+            canonical("@@other+1.0//:t").as_deref(),
+            Some("@@other+1.0//:t")
+        );
+        assert_eq!(canonical("@other//:t"), None);
+        assert_eq!(mapping.len(), 3);
+    }
+}
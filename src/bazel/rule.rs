@@ -1,8 +1,228 @@
 use allocative::Allocative;
+use sha2::{Digest as _, Sha256};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// The attributes of native rules whose values are labels of other targets.
+pub const LABEL_ATTRIBUTES: &[&str] = &["srcs", "hdrs", "deps", "data", "tools", "scope"];
+
+/// The label attributes whose targets are built for the execution platform, to run as part of the build.
+pub const TOOL_ATTRIBUTES: &[&str] = &["tools"];
+
+/// Attributes whose values are labels that only name targets, such as who may see the rule, without depending on
+/// them. Queries never follow them.
+pub const NODEP_ATTRIBUTES: &[&str] = &["visibility", "compatible_with", "restricted_to"];
+
+/// How a rule depends on a target named in one of its attributes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DepKind {
+    /// Written in an attribute like `srcs` or `deps`.
+    Explicit,
+    /// Written in an attribute of `TOOL_ATTRIBUTES`, and so built for the execution platform.
+    Tool,
+    /// Set by the rule itself rather than where it is declared: a private attribute, whose name starts with `_`.
+    Implicit,
+}
+
+/// Identifies the code that defines a rule class.
+///
+/// For a rule defined in Starlark this is the transitive digest of the .bzl file that defined it: its content and the
+/// digests of everything it loads. Native rules are defined by razel itself. Including it in configured target and
+/// action keys means that editing a rule implementation re-runs its actions even when no input file changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Allocative)]
+pub struct DefinitionDigest([u8; 32]);
+
+impl DefinitionDigest {
+    /// The digest of rules implemented natively, which only change with razel's version.
+    pub fn native() -> Self {
+        Self(Sha256::digest(concat!("razel native rules ", env!("CARGO_PKG_VERSION"))).into())
+    }
+
+    /// The transitive digest of the .bzl file `label` with `content`, which loads files with digests `loads`.
+    pub fn of_bzl<'a>(
+        label: &str,
+        content: &str,
+        loads: impl IntoIterator<Item = &'a DefinitionDigest>,
+    ) -> Self {
+        let mut hasher = Sha256::new();
+        for part in [label.as_bytes(), content.as_bytes()] {
+            hasher.update((part.len() as u64).to_le_bytes());
+            hasher.update(part);
+        }
+        for load in loads {
+            hasher.update(load.0);
+        }
+        Self(hasher.finalize().into())
+    }
+}
+
+impl fmt::Display for DefinitionDigest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&super::digest::to_hex(&self.0))
+    }
+}
+
+/// The value of a rule attribute, as written in the BUILD file.
+#[derive(Debug, Clone, PartialEq, Eq, Allocative)]
+pub enum AttrValue {
+    String(String),
+    /// A list of strings, such as labels.
+    List(Vec<String>),
+    /// A dict of strings, such as `config_setting.values`.
+    Dict(BTreeMap<String, String>),
+    /// Anything else, such as an int, a bool or a `select()`, in Starlark syntax.
+    Other(String),
+}
+
+impl AttrValue {
+    /// The strings in the value.
+    pub fn strings(&self) -> &[String] {
+        match self {
+            AttrValue::String(s) => std::slice::from_ref(s),
+            AttrValue::List(strings) => strings,
+            AttrValue::Dict(_) | AttrValue::Other(_) => &[],
+        }
+    }
+}
+
+/// Formats the value the way `bazel query` does for matching by `attr()`, e.g. `[//a, //b]` for a list.
+impl fmt::Display for AttrValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AttrValue::String(s) | AttrValue::Other(s) => f.write_str(s),
+            AttrValue::List(strings) => write!(f, "[{}]", strings.join(", ")),
+            AttrValue::Dict(entries) => {
+                let entries: Vec<_> = entries.iter().map(|(k, v)| format!("{k}={v}")).collect();
+                write!(f, "{{{}}}", entries.join(", "))
+            }
+        }
+    }
+}
 
 #[derive(Debug, Clone, Allocative)]
 pub struct Rule {
     pub rule_class: String,
     pub name: String,
-    // Add additional rule attributes here as needed later
+    /// The definition of `rule_class`.
+    pub definition: DefinitionDigest,
+    /// The attributes the rule was declared with, other than `name`.
+    pub attributes: BTreeMap<String, AttrValue>,
+    /// Where the rule was declared, as `file:line:column` with the file relative to the repository root. For a rule
+    /// declared by a macro, this is where the BUILD file calls the macro.
+    pub location: Option<String>,
+}
+
+impl Rule {
+    /// The labels of the targets the rule depends on, as written in the BUILD file, with how it depends on each: those
+    /// in `LABEL_ATTRIBUTES` (`srcs`, `deps`, ...) in that order, then those in private attributes.
+    pub fn deps(&self) -> impl Iterator<Item = (DepKind, &str)> {
+        let explicit = LABEL_ATTRIBUTES.iter().filter_map(|attr| {
+            let kind = if TOOL_ATTRIBUTES.contains(attr) {
+                DepKind::Tool
+            } else {
+                DepKind::Explicit
+            };
+            self.attributes.get(*attr).map(|value| (kind, value))
+        });
+        let implicit = self
+            .attributes
+            .iter()
+            .filter(|(name, _)| name.starts_with('_'))
+            .map(|(_, value)| (DepKind::Implicit, value));
+        explicit
+            .chain(implicit)
+            .flat_map(|(kind, value)| value.strings().iter().map(move |s| (kind, s.as_str())))
+    }
+
+    /// The files the rule declares it generates, in its `out` or `outs` attribute, relative to its package.
+    pub fn outputs(&self) -> impl Iterator<Item = &str> {
+        ["out", "outs"]
+            .iter()
+            .filter_map(|attr| self.attributes.get(*attr))
+            .flat_map(|value| value.strings())
+            .map(String::as_str)
+    }
+
+    /// The key of this target in `configuration` (e.g. `k8-fastbuild`), under which analysis results and the actions
+    /// they create are cached.
+    #[allow(dead_code)]
+    pub fn configured_target_key(&self, label: &str, configuration: &str) -> String {
+        let mut hasher = Sha256::new();
+        for part in [label, configuration, &self.rule_class] {
+            hasher.update((part.len() as u64).to_le_bytes());
+            hasher.update(part.as_bytes());
+        }
+        hasher.update(self.definition.0);
+        super::digest::to_hex(&hasher.finalize())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_definition_digest_is_transitive() {
+        let defs = DefinitionDigest::of_bzl("@@//:defs.bzl", "x = 1", []);
+        let rules = DefinitionDigest::of_bzl("@@//:rules.bzl", "load(':defs.bzl', 'x')", [&defs]);
+
+        let edited_defs = DefinitionDigest::of_bzl("@@//:defs.bzl", "x = 2", []);
+        let rules_after_edit =
+            DefinitionDigest::of_bzl("@@//:rules.bzl", "load(':defs.bzl', 'x')", [&edited_defs]);
+        assert_ne!(rules, rules_after_edit);
+
+        let rule = |definition| Rule {
+            rule_class: "my_rule".to_string(),
+            name: "t".to_string(),
+            definition,
+            attributes: BTreeMap::new(),
+            location: None,
+        };
+        assert_ne!(
+            rule(rules).configured_target_key("//:t", "k8-fastbuild"),
+            rule(rules_after_edit).configured_target_key("//:t", "k8-fastbuild")
+        );
+        assert_eq!(
+            rule(rules).configured_target_key("//:t", "k8-fastbuild"),
+            rule(rules).configured_target_key("//:t", "k8-fastbuild")
+        );
+        assert_ne!(
+            rule(rules).configured_target_key("//:t", "k8-fastbuild"),
+            rule(rules).configured_target_key("//:t", "k8-opt")
+        );
+    }
+
+    #[test]
+    fn test_deps_and_attribute_values() {
+        let list = |items: &[&str]| AttrValue::List(items.iter().map(|s| s.to_string()).collect());
+        let rule = Rule {
+            rule_class: "genrule".to_string(),
+            name: "t".to_string(),
+            definition: DefinitionDigest::native(),
+            attributes: BTreeMap::from([
+                ("srcs".to_string(), list(&[":a", "b.txt"])),
+                (
+                    "tools".to_string(),
+                    AttrValue::String("//tools:gen".to_string()),
+                ),
+                ("outs".to_string(), list(&["t.out"])),
+                ("stamp".to_string(), AttrValue::Other("True".to_string())),
+                ("_setup".to_string(), list(&["//tools:setup"])),
+                ("visibility".to_string(), list(&["//visibility:public"])),
+            ]),
+            location: None,
+        };
+        assert_eq!(
+            rule.deps().collect::<Vec<_>>(),
+            [
+                (DepKind::Explicit, ":a"),
+                (DepKind::Explicit, "b.txt"),
+                (DepKind::Tool, "//tools:gen"),
+                (DepKind::Implicit, "//tools:setup"),
+            ]
+        );
+        assert_eq!(rule.attributes["srcs"].to_string(), "[:a, b.txt]");
+        assert_eq!(rule.attributes["stamp"].to_string(), "True");
+        assert!(rule.attributes["stamp"].strings().is_empty());
+    }
 }
@@ -0,0 +1,104 @@
+//! Which packages may depend on a target.
+//!
+//! A rule's `visibility` attribute lists them: `//visibility:public` for every package, `//visibility:private` for
+//! none but its own, which is the default, and `//pkg:__pkg__` or `//pkg:__subpackages__` for a package, or a package
+//! and every package below it. A target is always visible to its own package. A generated file is visible where the
+//! rule that generates it is, and source files, which razel can't export with `exports_files`, are public.
+//!
+//! `package_group` isn't supported, so the labels of package groups let no other package see the target.
+
+use super::rule::{AttrValue, Rule};
+
+/// Packages a target is visible to. Packages are written as `@@repo//path`, so `@@//` is the root package of the main
+/// repository.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PackageSpec {
+    Public,
+    Package(String),
+    Subpackages(String),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Visibility {
+    specs: Vec<PackageSpec>,
+}
+
+impl Visibility {
+    pub fn public() -> Self {
+        Self {
+            specs: vec![PackageSpec::Public],
+        }
+    }
+
+    /// The visibility `rule` declares. The labels it lists are passed through `resolve`, to make them absolute.
+    pub fn from_rule(rule: &Rule, resolve: impl Fn(&str) -> String) -> Self {
+        let specs = rule
+            .attributes
+            .get("visibility")
+            .map_or(&[][..], AttrValue::strings)
+            .iter()
+            .filter_map(|label| {
+                let label = resolve(label);
+                let (package, name) = label.rsplit_once(':')?;
+                match (package.split_once("//")?.1, name) {
+                    ("visibility", "public") => Some(PackageSpec::Public),
+                    (_, "__pkg__") => Some(PackageSpec::Package(package.to_string())),
+                    (_, "__subpackages__") => Some(PackageSpec::Subpackages(package.to_string())),
+                    _ => None,
+                }
+            })
+            .collect();
+        Self { specs }
+    }
+
+    /// Whether targets in `package`, other than the target's own, may depend on the target.
+    pub fn allows(&self, package: &str) -> bool {
+        self.specs.iter().any(|spec| match spec {
+            PackageSpec::Public => true,
+            PackageSpec::Package(p) => p == package,
+            PackageSpec::Subpackages(p) if p.ends_with("//") => package.starts_with(p.as_str()),
+            PackageSpec::Subpackages(p) => package
+                .strip_prefix(p.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/')),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bazel::rule::DefinitionDigest;
+    use std::collections::BTreeMap;
+
+    fn visibility(labels: &[&str]) -> Visibility {
+        let rule = Rule {
+            rule_class: "filegroup".to_string(),
+            name: "t".to_string(),
+            definition: DefinitionDigest::native(),
+            attributes: BTreeMap::from([(
+                "visibility".to_string(),
+                AttrValue::List(labels.iter().map(|s| s.to_string()).collect()),
+            )]),
+            location: None,
+        };
+        Visibility::from_rule(&rule, |label| format!("@@{label}"))
+    }
+
+    #[test]
+    fn test_allows() {
+        assert!(visibility(&["//visibility:public"]).allows("@@dep+//x"));
+        assert!(!visibility(&["//visibility:private"]).allows("@@//a"));
+        assert!(!visibility(&[]).allows("@@//a"));
+
+        let pkg = visibility(&["//a:__pkg__"]);
+        assert!(pkg.allows("@@//a"));
+        assert!(!pkg.allows("@@//a/b"));
+
+        let subpackages = visibility(&["//a:__subpackages__", "//c:group"]);
+        assert!(subpackages.allows("@@//a"));
+        assert!(subpackages.allows("@@//a/b"));
+        assert!(!subpackages.allows("@@//ab"));
+        assert!(!subpackages.allows("@@//c"));
+        assert!(visibility(&["//:__subpackages__"]).allows("@@//x/y"));
+    }
+}
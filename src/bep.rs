@@ -0,0 +1,574 @@
+//! The Build Event Protocol, written as newline-delimited JSON for `--build_event_json_file`.
+//!
+//! Each line is one `BuildEvent` in the proto3 JSON mapping, so tools that consume Bazel's BEP (result stores, CI
+//! dashboards) can read razel's too.
+//! See https://github.com/bazelbuild/bazel/blob/master/src/main/java/com/google/devtools/build/lib/buildeventstream/proto/build_event_stream.proto
+
+use crate::bazel::Configuration;
+use crate::clock::Providers;
+use crate::error::{Subsystem, error_code};
+use crate::metrics::{BuildMetrics, BuildToolLogs, METRICS};
+use serde::Serialize;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigurationId {
+    pub id: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum BuildEventId {
+    Started {},
+    #[serde(rename_all = "camelCase")]
+    Progress {
+        opaque_count: u32,
+    },
+    TargetConfigured {
+        label: String,
+    },
+    TargetCompleted {
+        label: String,
+        configuration: ConfigurationId,
+    },
+    TestResult {
+        label: String,
+        run: u32,
+        shard: u32,
+        attempt: u32,
+        configuration: ConfigurationId,
+    },
+    BuildFinished {},
+    BuildMetrics {},
+    BuildToolLogs {},
+}
+
+/// Why an announced event was never posted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum AbortReason {
+    Unknown,
+    LoadingFailure,
+    AnalysisFailure,
+    /// `--noanalyze` left the target unanalyzed.
+    NoAnalyze,
+    /// `--nobuild` left the target unbuilt.
+    NoBuild,
+    /// The command ended without getting to the event.
+    Incomplete,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExitCode {
+    pub name: &'static str,
+    pub code: i32,
+}
+
+impl ExitCode {
+    pub const SUCCESS: Self = Self {
+        name: "SUCCESS",
+        code: 0,
+    };
+    pub const INTERNAL_ERROR: Self = Self {
+        name: "BLAZE_INTERNAL_ERROR",
+        code: 37,
+    };
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Payload {
+    #[serde(rename_all = "camelCase")]
+    Started {
+        uuid: String,
+        #[serde(serialize_with = "int64")]
+        start_time_millis: u64,
+        build_tool_version: String,
+        command: String,
+        working_directory: String,
+    },
+    Progress {
+        stdout: String,
+        stderr: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    Configured {
+        target_kind: String,
+    },
+    Completed {
+        success: bool,
+    },
+    Aborted {
+        reason: AbortReason,
+        /// The error, prefixed with its code, e.g. `RAZEL_FETCH_CHECKSUM_MISMATCH: Failed to download ...`.
+        description: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    Finished {
+        overall_success: bool,
+        exit_code: ExitCode,
+        #[serde(serialize_with = "int64")]
+        finish_time_millis: u64,
+    },
+    BuildMetrics(BuildMetrics),
+    BuildToolLogs(BuildToolLogs),
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildEvent {
+    pub id: BuildEventId,
+    /// Events announced by this one, which consumers can expect to follow.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<BuildEventId>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub last_message: bool,
+    #[serde(flatten)]
+    pub payload: Payload,
+}
+
+/// The proto3 JSON mapping encodes 64-bit integers as strings.
+fn int64<S: serde::Serializer>(value: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(value)
+}
+
+#[derive(Debug)]
+struct State {
+    out: std::io::BufWriter<std::fs::File>,
+    /// The events announced but not posted yet, in the order they were announced.
+    pending: Vec<BuildEventId>,
+    finished: bool,
+}
+
+/// Writes the build events of one invocation to a file.
+///
+/// If the stream is dropped before `finish` is called, e.g. because the command failed or crashed, a failed
+/// `BuildFinished` event is written so that consumers don't wait forever for the end of the stream.
+#[derive(Debug)]
+pub struct BuildEventStream {
+    state: Mutex<State>,
+    configuration: ConfigurationId,
+    /// Where the invocation id and event timestamps come from.
+    providers: Providers,
+}
+
+impl BuildEventStream {
+    /// Creates the file at `path` and writes the `BuildStarted` event for `command`.
+    pub fn create(
+        path: &Path,
+        command: &str,
+        configuration: &str,
+        providers: Providers,
+    ) -> anyhow::Result<Self> {
+        let file = std::fs::File::create(path).map_err(|e| {
+            anyhow::anyhow!(
+                "Failed to create --build_event_json_file {}: {e}",
+                path.display()
+            )
+        })?;
+        let stream = Self {
+            state: Mutex::new(State {
+                out: std::io::BufWriter::new(file),
+                pending: Vec::new(),
+                finished: false,
+            }),
+            configuration: ConfigurationId {
+                id: configuration.to_string(),
+            },
+            providers,
+        };
+        stream.write(BuildEvent {
+            id: BuildEventId::Started {},
+            children: vec![
+                BuildEventId::Progress { opaque_count: 0 },
+                BuildEventId::BuildFinished {},
+            ],
+            last_message: false,
+            payload: Payload::Started {
+                uuid: stream.providers.uuid(),
+                start_time_millis: stream.providers.now_millis(),
+                build_tool_version: format!("razel {}", env!("CARGO_PKG_VERSION")),
+                command: command.to_string(),
+                working_directory: std::env::current_dir()?.to_string_lossy().into_owned(),
+            },
+        })?;
+        Ok(stream)
+    }
+
+    /// Opens the stream requested by `--build_event_json_file`, if any.
+    pub fn from_config(
+        config: &Configuration,
+        command: &str,
+        providers: &Providers,
+    ) -> anyhow::Result<Option<Self>> {
+        config
+            .build_event_json_file
+            .as_deref()
+            .map(|path| {
+                Self::create(
+                    path,
+                    command,
+                    &config.output_dir_mnemonic(),
+                    providers.clone(),
+                )
+            })
+            .transpose()
+    }
+
+    fn write(&self, event: BuildEvent) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        Self::write_locked(&mut state, &event)
+    }
+
+    fn write_locked(state: &mut State, event: &BuildEvent) -> anyhow::Result<()> {
+        state.pending.retain(|id| *id != event.id);
+        state.pending.extend(event.children.iter().cloned());
+        serde_json::to_writer(&mut state.out, event)?;
+        state.out.write_all(b"\n")?;
+        // Consumers tail the file while the build runs, so don't hold events back.
+        state.out.flush()?;
+        Ok(())
+    }
+
+    /// Reports that `label`, a target of kind `target_kind` (e.g. `genrule rule`), was requested and configured.
+    /// Test rules announce the result of their single run, other targets their completion.
+    pub fn target_configured(&self, label: &str, target_kind: &str) -> anyhow::Result<()> {
+        let is_test = target_kind
+            .strip_suffix(" rule")
+            .is_some_and(|rule_class| rule_class.ends_with("_test"));
+        let mut children = vec![self.target_completed_id(label)];
+        if is_test {
+            children.push(self.test_result_id(label));
+        }
+        self.write(BuildEvent {
+            id: BuildEventId::TargetConfigured {
+                label: label.to_string(),
+            },
+            children,
+            last_message: false,
+            payload: Payload::Configured {
+                target_kind: target_kind.to_string(),
+            },
+        })
+    }
+
+    /// Reports the targets that target patterns expanded to, each with its kind.
+    pub fn targets_requested<L: std::fmt::Display>(
+        &self,
+        targets: &[L],
+        kinds: &[String],
+    ) -> anyhow::Result<()> {
+        for (label, kind) in targets.iter().zip(kinds) {
+            self.target_configured(&label.to_string(), kind)?;
+        }
+        Ok(())
+    }
+
+    /// Reports that `label` was built, or failed to be.
+    pub fn target_completed(&self, label: &str, success: bool) -> anyhow::Result<()> {
+        self.write(BuildEvent {
+            id: self.target_completed_id(label),
+            children: Vec::new(),
+            last_message: false,
+            payload: Payload::Completed { success },
+        })
+    }
+
+    /// Ends the stream of a command that failed with `error`. The progress event announced by `BuildStarted` will never
+    /// be posted, so it is reported as aborted with the code of the error, so that tools can tell failures apart, as
+    /// are the target and test events announced.
+    pub fn abort(&self, error: &anyhow::Error) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.finished {
+            return Ok(());
+        }
+        let code = error_code(error);
+        let reason = match code.map(|code| code.subsystem) {
+            Some(Subsystem::Loading | Subsystem::Resolution | Subsystem::Fetch) => {
+                AbortReason::LoadingFailure
+            }
+            Some(Subsystem::Analysis) => AbortReason::AnalysisFailure,
+            Some(Subsystem::Execution) | None => AbortReason::Unknown,
+        };
+        let description = match code {
+            Some(code) => format!("{code}: {error:#}"),
+            None => format!("{error:#}"),
+        };
+        let event = BuildEvent {
+            id: BuildEventId::Progress { opaque_count: 0 },
+            children: Vec::new(),
+            last_message: false,
+            payload: Payload::Aborted {
+                reason,
+                description: description.clone(),
+            },
+        };
+        Self::write_locked(&mut state, &event)?;
+        Self::skip_pending_locked(&mut state, reason, &description)?;
+        let exit_code = crate::exit_code::exit_code(error);
+        Self::finish_locked(
+            &mut state,
+            ExitCode {
+                name: exit_code.name(),
+                code: exit_code.code(),
+            },
+            self.providers.now_millis(),
+        )
+    }
+
+    /// Reports the target and test events announced so far, which the command won't get to, as aborted for `reason`.
+    pub fn skip_pending(&self, reason: AbortReason) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        Self::skip_pending_locked(&mut state, reason, "")
+    }
+
+    fn skip_pending_locked(
+        state: &mut State,
+        reason: AbortReason,
+        description: &str,
+    ) -> anyhow::Result<()> {
+        let (skipped, pending) = std::mem::take(&mut state.pending)
+            .into_iter()
+            .partition(|id| {
+                matches!(
+                    id,
+                    BuildEventId::TargetCompleted { .. } | BuildEventId::TestResult { .. }
+                )
+            });
+        state.pending = pending;
+        for id in skipped {
+            let event = BuildEvent {
+                id,
+                children: Vec::new(),
+                last_message: false,
+                payload: Payload::Aborted {
+                    reason,
+                    description: description.to_string(),
+                },
+            };
+            Self::write_locked(state, &event)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the `BuildFinished` event, followed by the metrics and tool logs that end the stream.
+    pub fn finish(&self, exit_code: ExitCode) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        Self::finish_locked(&mut state, exit_code, self.providers.now_millis())
+    }
+
+    /// Writes the events that end the stream, finishing at `finish_time_millis`, unless they were written already.
+    /// Announced events that were never posted are posted first: the progress event with no output, and target and
+    /// test events as incomplete, so that consumers don't wait for them.
+    fn finish_locked(
+        state: &mut State,
+        exit_code: ExitCode,
+        finish_time_millis: u64,
+    ) -> anyhow::Result<()> {
+        if state.finished {
+            return Ok(());
+        }
+        let progress: Vec<_> = state
+            .pending
+            .iter()
+            .filter(|id| matches!(id, BuildEventId::Progress { .. }))
+            .cloned()
+            .collect();
+        for id in progress {
+            let event = BuildEvent {
+                id,
+                children: Vec::new(),
+                last_message: false,
+                payload: Payload::Progress {
+                    stdout: String::new(),
+                    stderr: String::new(),
+                },
+            };
+            Self::write_locked(state, &event)?;
+        }
+        Self::skip_pending_locked(state, AbortReason::Incomplete, "")?;
+        state.finished = true;
+        let events = [
+            BuildEvent {
+                id: BuildEventId::BuildFinished {},
+                children: vec![
+                    BuildEventId::BuildMetrics {},
+                    BuildEventId::BuildToolLogs {},
+                ],
+                last_message: false,
+                payload: Payload::Finished {
+                    overall_success: exit_code.code == 0,
+                    exit_code,
+                    finish_time_millis,
+                },
+            },
+            BuildEvent {
+                id: BuildEventId::BuildMetrics {},
+                children: Vec::new(),
+                last_message: false,
+                payload: Payload::BuildMetrics(METRICS.build_metrics()),
+            },
+            BuildEvent {
+                id: BuildEventId::BuildToolLogs {},
+                children: Vec::new(),
+                last_message: true,
+                payload: Payload::BuildToolLogs(METRICS.build_tool_logs()),
+            },
+        ];
+        for event in &events {
+            Self::write_locked(state, event)?;
+        }
+        Ok(())
+    }
+
+    fn target_completed_id(&self, label: &str) -> BuildEventId {
+        BuildEventId::TargetCompleted {
+            label: label.to_string(),
+            configuration: self.configuration.clone(),
+        }
+    }
+
+    fn test_result_id(&self, label: &str) -> BuildEventId {
+        BuildEventId::TestResult {
+            label: label.to_string(),
+            run: 1,
+            shard: 1,
+            attempt: 1,
+            configuration: self.configuration.clone(),
+        }
+    }
+}
+
+impl Drop for BuildEventStream {
+    fn drop(&mut self) {
+        let finish_time_millis = self.providers.now_millis();
+        let state = self.state.get_mut().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = Self::finish_locked(state, ExitCode::INTERNAL_ERROR, finish_time_millis) {
+            log::error!("Failed to finish the build event stream: {e:#}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{ManualClock, SeededRng};
+    use std::sync::Arc;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn read_events(path: &Path) -> Vec<serde_json::Value> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_build_event_stream() {
+        let tmp = assert_fs::TempDir::new().unwrap();
+        let path = tmp.path().join("bep.json");
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_millis(1_000)));
+        let providers = Providers {
+            clock: clock.clone(),
+            rng: Arc::new(SeededRng::new(42)),
+        };
+        let stream = BuildEventStream::create(&path, "test", "k8-fastbuild", providers).unwrap();
+        stream.target_configured("//:t", "sh_test rule").unwrap();
+        stream.target_configured("//:b", "sh_binary rule").unwrap();
+        stream.target_completed("//:t", true).unwrap();
+        stream.skip_pending(AbortReason::NoBuild).unwrap();
+        clock.advance(Duration::from_millis(250));
+        stream.finish(ExitCode::SUCCESS).unwrap();
+
+        let events = read_events(&path);
+        assert_eq!(events.len(), 10);
+        assert_eq!(events[0]["id"], serde_json::json!({"started": {}}));
+        assert_eq!(events[0]["started"]["command"], "test");
+        assert_eq!(
+            events[0]["started"]["uuid"],
+            "bdd73226-2feb-4e95-a8ef-e333b266f103"
+        );
+        assert_eq!(events[0]["started"]["startTimeMillis"], "1000");
+        assert_eq!(events[1]["configured"]["targetKind"], "sh_test rule");
+        assert_eq!(events[1]["children"][1]["testResult"]["label"], "//:t");
+        // Only test rules announce a test result.
+        assert_eq!(
+            events[2]["children"],
+            serde_json::json!([{"targetCompleted": {
+                "label": "//:b",
+                "configuration": {"id": "k8-fastbuild"},
+            }}])
+        );
+        assert_eq!(
+            events[3]["id"]["targetCompleted"]["configuration"]["id"],
+            "k8-fastbuild"
+        );
+        assert_eq!(events[3]["completed"]["success"], true);
+        assert_eq!(events[4]["id"]["testResult"]["label"], "//:t");
+        assert_eq!(events[4]["aborted"]["reason"], "NO_BUILD");
+        assert_eq!(events[5]["id"]["targetCompleted"]["label"], "//:b");
+        assert_eq!(events[5]["aborted"]["reason"], "NO_BUILD");
+        assert_eq!(
+            events[6]["id"],
+            serde_json::json!({"progress": {"opaqueCount": 0}})
+        );
+        assert_eq!(events[7]["finished"]["overallSuccess"], true);
+        assert_eq!(events[7]["finished"]["exitCode"]["name"], "SUCCESS");
+        assert_eq!(events[7]["finished"]["finishTimeMillis"], "1250");
+        assert!(events[8]["buildMetrics"]["actionSummary"].is_object());
+        assert_eq!(events[9]["lastMessage"], true);
+        assert!(events[..9].iter().all(|e| e.get("lastMessage").is_none()));
+    }
+
+    #[test]
+    fn test_aborted_stream_reports_error_code() {
+        let tmp = assert_fs::TempDir::new().unwrap();
+        let path = tmp.path().join("bep.json");
+        let stream =
+            BuildEventStream::create(&path, "build", "k8-fastbuild", Providers::default()).unwrap();
+        let error = anyhow::Error::new(crate::error::FetchError::Unsupported {
+            repo: "rules_go+".to_string(),
+        })
+        .context("Failed to evaluate repo @@rules_go+");
+        stream.abort(&error).unwrap();
+        stream.finish(ExitCode::SUCCESS).unwrap();
+
+        let events = read_events(&path);
+        assert_eq!(
+            events[1]["id"],
+            serde_json::json!({"progress": {"opaqueCount": 0}})
+        );
+        assert_eq!(events[1]["aborted"]["reason"], "LOADING_FAILURE");
+        assert_eq!(
+            events[1]["aborted"]["description"],
+            "RAZEL_FETCH_UNSUPPORTED: Failed to evaluate repo @@rules_go+: Fetching external repository \
+             rules_go+ is not implemented"
+        );
+        assert_eq!(events[2]["finished"]["exitCode"]["code"], 48);
+        assert_eq!(
+            events[2]["finished"]["exitCode"]["name"],
+            "EXTERNAL_DEPS_ERROR"
+        );
+        assert_eq!(events.len(), 5);
+    }
+
+    #[test]
+    fn test_unfinished_stream_reports_failure() {
+        let tmp = assert_fs::TempDir::new().unwrap();
+        let path = tmp.path().join("bep.json");
+        drop(
+            BuildEventStream::create(&path, "build", "k8-fastbuild", Providers::default()).unwrap(),
+        );
+
+        let events = read_events(&path);
+        assert_eq!(events[1]["progress"]["stderr"], "");
+        assert_eq!(events[2]["finished"]["overallSuccess"], false);
+        assert_eq!(events[2]["finished"]["exitCode"]["code"], 37);
+        assert_eq!(events.last().unwrap()["lastMessage"], true);
+    }
+}
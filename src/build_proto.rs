@@ -0,0 +1,345 @@
+//! The `Target` messages of Bazel's `build.proto`, which `query --output=proto` and `--output=streamed_jsonproto`
+//! print, so tools that read Bazel's query output can read razel's.
+//!
+//! Only the fields razel has values for are declared. The JSON form follows the proto3 JSON mapping, with enums by
+//! name, as Bazel writes it.
+//! See https://github.com/bazelbuild/bazel/blob/master/src/main/protobuf/build.proto
+
+use crate::bazel::rule::{AttrValue, LABEL_ATTRIBUTES};
+use prost::Message;
+use serde::{Serialize, Serializer};
+
+/// All the targets a query returned, for `--output=proto`.
+#[derive(Clone, PartialEq, Message, Serialize)]
+pub struct QueryResult {
+    #[prost(message, repeated, tag = "1")]
+    pub target: Vec<Target>,
+}
+
+#[derive(Clone, PartialEq, Message, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Target {
+    #[prost(enumeration = "Discriminator", required, tag = "1")]
+    #[serde(serialize_with = "discriminator")]
+    pub r#type: i32,
+    #[prost(message, optional, tag = "2")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rule: Option<Rule>,
+    #[prost(message, optional, tag = "3")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_file: Option<SourceFile>,
+    #[prost(message, optional, tag = "4")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generated_file: Option<GeneratedFile>,
+}
+
+/// The kind of a `Target`, `Target.Discriminator` in build.proto.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+#[repr(i32)]
+pub enum Discriminator {
+    Rule = 1,
+    SourceFile = 2,
+    GeneratedFile = 3,
+}
+
+impl Discriminator {
+    fn name(self) -> &'static str {
+        match self {
+            Discriminator::Rule => "RULE",
+            Discriminator::SourceFile => "SOURCE_FILE",
+            Discriminator::GeneratedFile => "GENERATED_FILE",
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Message, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Rule {
+    #[prost(string, required, tag = "1")]
+    pub name: String,
+    #[prost(string, required, tag = "2")]
+    pub rule_class: String,
+    /// Where the rule is declared, as `path:line:column`.
+    #[prost(string, optional, tag = "3")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    #[prost(message, repeated, tag = "4")]
+    pub attribute: Vec<Attribute>,
+    /// The targets the rule depends on.
+    #[prost(string, repeated, tag = "5")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rule_input: Vec<String>,
+    /// The files the rule generates.
+    #[prost(string, repeated, tag = "6")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rule_output: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, Message, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SourceFile {
+    #[prost(string, required, tag = "1")]
+    pub name: String,
+    #[prost(string, optional, tag = "2")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Message, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GeneratedFile {
+    #[prost(string, required, tag = "1")]
+    pub name: String,
+    #[prost(string, required, tag = "2")]
+    pub generating_rule: String,
+    #[prost(string, optional, tag = "3")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Message, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Attribute {
+    #[prost(string, required, tag = "1")]
+    pub name: String,
+    #[prost(enumeration = "AttributeType", required, tag = "2")]
+    #[serde(serialize_with = "attribute_type")]
+    pub r#type: i32,
+    #[prost(int32, optional, tag = "3")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub int_value: Option<i32>,
+    #[prost(string, optional, tag = "5")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub string_value: Option<String>,
+    #[prost(string, repeated, tag = "6")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub string_list_value: Vec<String>,
+    #[prost(message, repeated, tag = "8")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub string_dict_value: Vec<StringDictEntry>,
+    #[prost(bool, optional, tag = "13")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub explicitly_specified: Option<bool>,
+    #[prost(bool, optional, tag = "14")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub boolean_value: Option<bool>,
+}
+
+/// The type of an `Attribute`, `Attribute.Discriminator` in build.proto.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+#[repr(i32)]
+pub enum AttributeType {
+    Integer = 1,
+    String = 2,
+    Label = 3,
+    Output = 4,
+    StringList = 5,
+    LabelList = 6,
+    OutputList = 7,
+    StringDict = 10,
+    Boolean = 14,
+    Unknown = 18,
+}
+
+impl AttributeType {
+    fn name(self) -> &'static str {
+        match self {
+            AttributeType::Integer => "INTEGER",
+            AttributeType::String => "STRING",
+            AttributeType::Label => "LABEL",
+            AttributeType::Output => "OUTPUT",
+            AttributeType::StringList => "STRING_LIST",
+            AttributeType::LabelList => "LABEL_LIST",
+            AttributeType::OutputList => "OUTPUT_LIST",
+            AttributeType::StringDict => "STRING_DICT",
+            AttributeType::Boolean => "BOOLEAN",
+            AttributeType::Unknown => "UNKNOWN",
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Message, Serialize)]
+pub struct StringDictEntry {
+    #[prost(string, required, tag = "1")]
+    pub key: String,
+    #[prost(string, required, tag = "2")]
+    pub value: String,
+}
+
+fn discriminator<S: Serializer>(value: &i32, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(Discriminator::try_from(*value).map_or("UNKNOWN", Discriminator::name))
+}
+
+fn attribute_type<S: Serializer>(value: &i32, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(AttributeType::try_from(*value).map_or("UNKNOWN", AttributeType::name))
+}
+
+impl Attribute {
+    /// The attribute `name` of a rule, declared with `value`, whose labels should already be resolved. Values razel
+    /// only has as Starlark source, such as a `select()`, are `UNKNOWN`, with the source as their string value.
+    pub fn new(name: &str, value: &AttrValue) -> Self {
+        let mut attribute = Attribute {
+            name: name.to_string(),
+            explicitly_specified: Some(true),
+            ..Attribute::default()
+        };
+        let is_label = LABEL_ATTRIBUTES.contains(&name);
+        let is_output = matches!(name, "out" | "outs");
+        let r#type = match value {
+            AttrValue::String(s) => {
+                attribute.string_value = Some(s.clone());
+                match (is_label, is_output) {
+                    (true, _) => AttributeType::Label,
+                    (_, true) => AttributeType::Output,
+                    _ => AttributeType::String,
+                }
+            }
+            AttrValue::List(strings) => {
+                attribute.string_list_value = strings.clone();
+                match (is_label, is_output) {
+                    (true, _) => AttributeType::LabelList,
+                    (_, true) => AttributeType::OutputList,
+                    _ => AttributeType::StringList,
+                }
+            }
+            AttrValue::Dict(entries) => {
+                attribute.string_dict_value = entries
+                    .iter()
+                    .map(|(key, value)| StringDictEntry {
+                        key: key.clone(),
+                        value: value.clone(),
+                    })
+                    .collect();
+                AttributeType::StringDict
+            }
+            AttrValue::Other(source) => match source.as_str() {
+                "True" | "False" => {
+                    attribute.boolean_value = Some(source == "True");
+                    AttributeType::Boolean
+                }
+                _ => match source.parse() {
+                    Ok(i) => {
+                        attribute.int_value = Some(i);
+                        AttributeType::Integer
+                    }
+                    Err(_) => {
+                        attribute.string_value = Some(source.clone());
+                        AttributeType::Unknown
+                    }
+                },
+            },
+        };
+        attribute.r#type = r#type as i32;
+        attribute
+    }
+}
+
+impl Target {
+    pub fn rule(rule: Rule) -> Self {
+        Target {
+            r#type: Discriminator::Rule as i32,
+            rule: Some(rule),
+            ..Target::default()
+        }
+    }
+
+    pub fn source_file(name: String, location: Option<String>) -> Self {
+        Target {
+            r#type: Discriminator::SourceFile as i32,
+            source_file: Some(SourceFile { name, location }),
+            ..Target::default()
+        }
+    }
+
+    pub fn generated_file(name: String, generating_rule: String, location: Option<String>) -> Self {
+        Target {
+            r#type: Discriminator::GeneratedFile as i32,
+            generated_file: Some(GeneratedFile {
+                name,
+                generating_rule,
+                location,
+            }),
+            ..Target::default()
+        }
+    }
+
+    /// The target as one line of `--output=streamed_jsonproto`.
+    pub fn json_line(&self) -> String {
+        let mut json = serde_json::to_string(self).expect("targets are serializable");
+        json.push('\n');
+        json
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attribute_types() {
+        let types = [
+            ("srcs", AttrValue::List(vec!["a.cc".to_string()])),
+            ("outs", AttrValue::List(vec!["a.out".to_string()])),
+            ("cmd", AttrValue::String("true".to_string())),
+            ("testonly", AttrValue::Other("True".to_string())),
+            ("shard_count", AttrValue::Other("4".to_string())),
+            ("copts", AttrValue::Other("select({})".to_string())),
+        ]
+        .map(|(name, value)| AttributeType::try_from(Attribute::new(name, &value).r#type).unwrap());
+        assert_eq!(
+            types,
+            [
+                AttributeType::LabelList,
+                AttributeType::OutputList,
+                AttributeType::String,
+                AttributeType::Boolean,
+                AttributeType::Integer,
+                AttributeType::Unknown,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_encoding() {
+        let target = Target::rule(Rule {
+            name: "//:a".to_string(),
+            rule_class: "genrule".to_string(),
+            location: None,
+            attribute: vec![Attribute::new(
+                "srcs",
+                &AttrValue::List(vec!["//:b".to_string()]),
+            )],
+            rule_input: vec!["//:b".to_string()],
+            rule_output: Vec::new(),
+        });
+        let result = QueryResult {
+            target: vec![
+                target.clone(),
+                Target::source_file("//:b".to_string(), None),
+            ],
+        };
+        assert_eq!(
+            QueryResult::decode(&*result.encode_to_vec()).unwrap(),
+            result
+        );
+
+        let json: serde_json::Value = serde_json::from_str(&target.json_line()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "type": "RULE",
+                "rule": {
+                    "name": "//:a",
+                    "ruleClass": "genrule",
+                    "attribute": [{
+                        "name": "srcs",
+                        "type": "LABEL_LIST",
+                        "stringListValue": ["//:b"],
+                        "explicitlySpecified": true,
+                    }],
+                    "ruleInput": ["//:b"],
+                }
+            })
+        );
+    }
+}
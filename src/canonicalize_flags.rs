@@ -0,0 +1,116 @@
+//! `razel canonicalize-flags`: the options a command would effectively run with, written one canonical way.
+//!
+//! The options from rc files are added and `--config`s expanded as for the command itself, and the result is parsed
+//! the same way too. Every option that ends up set is then printed once, as `--<long name>=<value>`, in order of name.
+//! An option given more than once keeps its last value, unless it is one that collects all of its values. Two flag
+//! lists that configure a command the same way so print the same, which is what a wrapper caching on it needs.
+
+use crate::bazel::rc::RcOptions;
+use clap::CommandFactory;
+use clap::parser::ValueSource;
+
+/// The canonical form of `flags` for `command`, with the options from `rc` included.
+pub fn canonicalize(
+    rc: &RcOptions,
+    command: &str,
+    flags: &[String],
+) -> anyhow::Result<Vec<String>> {
+    let mut cli = crate::Cli::command();
+    let options = crate::bazel::rc::expand_negations(&cli, rc.expand(command, flags)?);
+    let matches = cli.try_get_matches_from_mut(
+        ["razel", command]
+            .into_iter()
+            .map(String::from)
+            .chain(options),
+    )?;
+    let (_, matches) = matches.subcommand().expect("a command is required");
+    // The command has its own options and, now that it's been parsed, the global ones.
+    let subcommand = cli
+        .find_subcommand(command)
+        .expect("the command was just parsed");
+
+    let mut canonical = Vec::new();
+    for arg in subcommand.get_arguments() {
+        let id = arg.get_id().as_str();
+        if matches.value_source(id) != Some(ValueSource::CommandLine) {
+            continue;
+        }
+        let values: Vec<_> = matches
+            .get_raw(id)
+            .into_iter()
+            .flatten()
+            .map(|v| v.to_string_lossy().into_owned())
+            .collect();
+        let Some(long) = arg.get_long() else {
+            anyhow::bail!(
+                "canonicalize-flags only takes options, got {}",
+                values.join(" ")
+            );
+        };
+        let values = match arg.get_action() {
+            clap::ArgAction::Append => &values[..],
+            _ => &values[values.len().saturating_sub(1)..],
+        };
+        canonical.extend(values.iter().map(|value| format!("--{long}={value}")));
+    }
+    canonical.sort_by(|a, b| option_name(a).cmp(option_name(b)));
+    Ok(canonical)
+}
+
+fn option_name(option: &str) -> &str {
+    option.split_once('=').map_or(option, |(name, _)| name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(s: &[&str]) -> Vec<String> {
+        s.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_canonicalize() {
+        let rc = RcOptions::default();
+        assert_eq!(
+            canonicalize(
+                &rc,
+                "build",
+                &strings(&[
+                    "-c",
+                    "opt",
+                    "--keep_going",
+                    "-j",
+                    "4",
+                    "--compilation_mode=dbg",
+                    "--noshow_progress"
+                ])
+            )
+            .unwrap(),
+            strings(&[
+                "--compilation_mode=dbg",
+                "--jobs=4",
+                "--keep_going=true",
+                "--show_progress=false"
+            ])
+        );
+        // The same configuration, put differently.
+        assert_eq!(
+            canonicalize(
+                &rc,
+                "build",
+                &strings(&["--jobs=4", "--keep_going=true", "--compilation_mode", "dbg"])
+            )
+            .unwrap(),
+            strings(&["--compilation_mode=dbg", "--jobs=4", "--keep_going=true"])
+        );
+        assert_eq!(
+            canonicalize(&rc, "build", &[]).unwrap(),
+            Vec::<String>::new()
+        );
+
+        assert!(canonicalize(&rc, "build", &strings(&["--no_such_flag"])).is_err());
+        assert!(canonicalize(&rc, "build", &strings(&["//..."])).is_err());
+        assert!(canonicalize(&rc, "build", &strings(&["--config=ci"])).is_err());
+    }
+}
@@ -0,0 +1,203 @@
+//! Where razel gets the time and random numbers from.
+//!
+//! Invocation ids, the timestamps written to logs and the jitter added to retry backoff all come from a `Providers`,
+//! which defaults to the system clock and randomness seeded by the OS. Tests swap in a `ManualClock` and a
+//! `SeededRng`, so that what they check comes out the same on every run.
+
+use std::fmt::Debug;
+use std::hash::{BuildHasher as _, RandomState};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Tells the time.
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// Produces random numbers.
+pub trait Rng: Debug + Send + Sync {
+    fn next_u64(&self) -> u64;
+}
+
+/// The system's clock.
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only moves when told to, for tests.
+#[cfg(test)]
+#[derive(Debug)]
+pub struct ManualClock {
+    now: std::sync::Mutex<SystemTime>,
+}
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new(start: SystemTime) -> Self {
+        Self {
+            now: std::sync::Mutex::new(start),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+}
+
+/// Random numbers seeded by the OS, differing between processes.
+#[derive(Debug, Default)]
+pub struct OsRng {
+    keys: RandomState,
+    counter: AtomicU64,
+}
+
+impl Rng for OsRng {
+    fn next_u64(&self) -> u64 {
+        self.keys
+            .hash_one(self.counter.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// The same sequence of random numbers for the same seed (splitmix64), for tests.
+#[cfg(test)]
+#[derive(Debug)]
+pub struct SeededRng {
+    state: AtomicU64,
+}
+
+#[cfg(test)]
+impl SeededRng {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: AtomicU64::new(seed),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Rng for SeededRng {
+    fn next_u64(&self) -> u64 {
+        let mut z = self
+            .state
+            .fetch_add(0x9e3779b97f4a7c15, Ordering::Relaxed)
+            .wrapping_add(0x9e3779b97f4a7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// The clock and random numbers a component uses. Clones share them.
+#[derive(Debug, Clone)]
+pub struct Providers {
+    pub clock: Arc<dyn Clock>,
+    pub rng: Arc<dyn Rng>,
+}
+
+impl Default for Providers {
+    fn default() -> Self {
+        Self {
+            clock: Arc::new(SystemClock),
+            rng: Arc::new(OsRng::default()),
+        }
+    }
+}
+
+impl Providers {
+    /// A clock stopped at `start` and random numbers from `seed`, for tests.
+    #[cfg(test)]
+    pub fn fixed(start: SystemTime, seed: u64) -> Self {
+        Self {
+            clock: Arc::new(ManualClock::new(start)),
+            rng: Arc::new(SeededRng::new(seed)),
+        }
+    }
+
+    pub fn now(&self) -> SystemTime {
+        self.clock.now()
+    }
+
+    /// Milliseconds since the Unix epoch.
+    pub fn now_millis(&self) -> u64 {
+        self.now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64)
+    }
+
+    /// A random (v4) UUID.
+    pub fn uuid(&self) -> String {
+        let mut bytes = [0; 16];
+        bytes[..8].copy_from_slice(&self.rng.next_u64().to_be_bytes());
+        bytes[8..].copy_from_slice(&self.rng.next_u64().to_be_bytes());
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+        format!(
+            "{}-{}-{}-{}-{}",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        )
+    }
+
+    /// A random duration shorter than `max`, so that clients retrying at the same time spread out.
+    pub fn jitter(&self, max: Duration) -> Duration {
+        match max.as_nanos() as u64 {
+            0 => Duration::ZERO,
+            max => Duration::from_nanos(self.rng.next_u64() % max),
+        }
+    }
+
+    /// A file name starting with `prefix` that no other process picks, for temporary files and directories.
+    pub fn temp_name(&self, prefix: &str) -> String {
+        format!("{prefix}.{:016x}.tmp", self.rng.next_u64())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixed_providers() {
+        let start = UNIX_EPOCH + Duration::from_millis(1_700_000_000_000);
+        let a = Providers::fixed(start, 42);
+        let b = Providers::fixed(start, 42);
+        assert_eq!(a.now_millis(), 1_700_000_000_000);
+        let uuid = a.uuid();
+        assert_eq!(uuid, b.uuid());
+        assert_eq!(uuid.len(), 36);
+        assert_eq!(&uuid[14..15], "4");
+        let next = a.uuid();
+        assert_ne!(next, uuid);
+        assert_eq!(next, b.uuid());
+        assert_eq!(a.temp_name("x"), b.temp_name("x"));
+        assert!(a.jitter(Duration::from_millis(100)) < Duration::from_millis(100));
+        assert_eq!(a.jitter(Duration::ZERO), Duration::ZERO);
+
+        let clock = ManualClock::new(start);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(clock.now(), start + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_os_rng_differs() {
+        let providers = Providers::default();
+        assert_ne!(providers.uuid(), providers.uuid());
+    }
+}
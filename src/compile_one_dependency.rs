@@ -0,0 +1,138 @@
+//! `--compile_one_dependency`, which takes source files instead of targets and builds one target that depends on each,
+//! as editor integrations that build the current file do.
+//!
+//! A file is given by its path relative to the working directory, or by its label. The rule chosen is one in the
+//! file's own package that lists it in `srcs`, then any other rule of that package that depends on it, both in order of
+//! name, and only if there are none, the first rule anywhere in the main repository that does.
+
+use crate::error::LoadingError;
+use crate::generate::BUILD_FILE_NAMES;
+use crate::query::{self, Edges, Universe};
+use crate::workspace::Workspace;
+use std::path::Path;
+use std::sync::Arc;
+
+/// The label of the file at `path`, relative to the workspace, in the closest package that contains it.
+fn file_label(workspace: &Path, path: &str) -> Option<String> {
+    let path = path.trim_matches('/');
+    let mut package = path;
+    while let Some((parent, _)) = package.rsplit_once('/') {
+        package = parent;
+        if BUILD_FILE_NAMES
+            .iter()
+            .any(|name| workspace.join(package).join(name).is_file())
+        {
+            return Some(format!("//{package}:{}", &path[package.len() + 1..]));
+        }
+    }
+    BUILD_FILE_NAMES
+        .iter()
+        .any(|name| workspace.join(name).is_file())
+        .then(|| format!("//:{path}"))
+}
+
+/// The target patterns to build for the source files `files`, one target for each.
+pub async fn targets(workspace: &Arc<Workspace>, files: &[String]) -> anyhow::Result<Vec<String>> {
+    let mut targets = Vec::new();
+    for file in files {
+        let not_found = |reason: String| LoadingError::InvalidTargetPattern {
+            pattern: file.clone(),
+            reason,
+        };
+        let label = if file.starts_with("//") || file.starts_with('@') || file.starts_with(':') {
+            file.clone()
+        } else {
+            let path = match workspace.working_package() {
+                "" => file.clone(),
+                package => format!("{package}/{file}"),
+            };
+            file_label(workspace.path(), &path)
+                .ok_or_else(|| not_found("no package contains this file".to_string()))?
+        };
+        let (package, name) = label
+            .split_once(':')
+            .ok_or_else(|| not_found("expected the label of a file".to_string()))?;
+        // A source file only matches a target pattern as a target of its own once something refers to it, so it's
+        // picked out of the direct dependencies of the rules in `scope` instead.
+        let file = |scope: &str| {
+            let path = &package[package.find("//").unwrap_or(0)..];
+            let pattern = regex::escape(&format!("{path}:{name}"));
+            format!("filter('{pattern}$', deps({scope}, 1))")
+        };
+        let candidates = async |expr: String| {
+            query::evaluate_labels(
+                workspace.clone(),
+                &expr,
+                Universe::new(&[], false),
+                Edges::default(),
+            )
+            .await
+        };
+        let same_package = candidates(format!(
+            "same_pkg_direct_rdeps({})",
+            file(&format!("{package}:*"))
+        ))
+        .await?;
+        let mut chosen = None;
+        for candidate in &same_package {
+            let (_, rules) = workspace
+                .package_rules(
+                    &query::canonical_repo(candidate).map_err(anyhow::Error::msg)?,
+                    candidate.package(),
+                )
+                .await?;
+            let lists_it = rules.get(candidate.name()).is_some_and(|rule| {
+                rule.attributes.get("srcs").is_some_and(|srcs| {
+                    srcs.strings().iter().any(|src| {
+                        let src = src.rsplit_once(':').map_or(src.as_str(), |(_, name)| name);
+                        label.ends_with(&format!(":{src}"))
+                    })
+                })
+            });
+            if lists_it {
+                chosen = Some(candidate.clone());
+                break;
+            }
+        }
+        let chosen = match chosen.or_else(|| same_package.first().cloned()) {
+            Some(target) => target,
+            None => candidates(format!("rdeps(//..., {0}, 1) - {0}", file("//...")))
+                .await?
+                .into_iter()
+                .next()
+                .ok_or_else(|| {
+                    not_found(format!("Couldn't find dependency on target '{label}'"))
+                })?,
+        };
+        targets.push(chosen.to_string());
+    }
+    Ok(targets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_label() {
+        let tmp = assert_fs::TempDir::new().unwrap();
+        std::fs::create_dir_all(tmp.path().join("lib/src")).unwrap();
+        std::fs::write(tmp.path().join("BUILD.bazel"), "").unwrap();
+        std::fs::write(tmp.path().join("lib/BUILD"), "").unwrap();
+
+        assert_eq!(
+            file_label(tmp.path(), "lib/src/a.cc").as_deref(),
+            Some("//lib:src/a.cc")
+        );
+        assert_eq!(
+            file_label(tmp.path(), "lib/a.cc").as_deref(),
+            Some("//lib:a.cc")
+        );
+        assert_eq!(
+            file_label(tmp.path(), "main.cc").as_deref(),
+            Some("//:main.cc")
+        );
+        std::fs::remove_file(tmp.path().join("BUILD.bazel")).unwrap();
+        assert_eq!(file_label(tmp.path(), "docs/a.md"), None);
+    }
+}
@@ -0,0 +1,170 @@
+//! How razel shows progress on the console.
+//!
+//! On a terminal, running work gets progress bars that are redrawn in place. Anywhere else, such as a CI log, redrawing
+//! only leaves a mess of control codes behind, so each piece of work is written as a plain line when it starts, without
+//! colors unless asked for. `--curses`, `--color` and `--show_progress` override what's detected. Warnings and errors
+//! are written either way.
+//!
+//! A command run by the server writes to its client's stderr rather than the server's log: `capture_stderr` collects
+//! what it writes with `eprintln` or logs, for the server to send back.
+
+use crate::Cli;
+use std::future::Future;
+use std::io::{IsTerminal, Write};
+use std::sync::{Arc, Mutex};
+use tracing::{Level, Subscriber};
+use tracing_indicatif::IndicatifLayer;
+use tracing_subscriber::filter::filter_fn;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{Layer, fmt};
+
+tokio::task_local! {
+    /// What the command running on the server has written for its client's stderr.
+    static CLIENT_STDERR: Arc<Mutex<Vec<u8>>>;
+}
+
+/// Runs `command`, returning what it wrote to stderr alongside its output.
+pub async fn capture_stderr<F: Future>(command: F) -> (F::Output, Vec<u8>) {
+    let captured = Arc::new(Mutex::new(Vec::new()));
+    let output = CLIENT_STDERR.scope(captured.clone(), command).await;
+    let stderr = std::mem::take(&mut *captured.lock().unwrap());
+    (output, stderr)
+}
+
+/// Writes `line` to the stderr of the running command, see `Stderr`.
+pub fn eprintln(line: impl std::fmt::Display) {
+    let _ = writeln!(Stderr, "{line}");
+}
+
+/// The stderr of the running command: the client's for a command run by the server, otherwise this process's.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stderr;
+
+impl Write for Stderr {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match CLIENT_STDERR.try_with(|captured| captured.lock().unwrap().extend_from_slice(buf)) {
+            Ok(()) => Ok(buf.len()),
+            Err(_) => std::io::stderr().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stderr().flush()
+    }
+}
+
+/// The value of `--color` and `--curses`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum When {
+    Yes,
+    No,
+    /// Only if stderr is a terminal.
+    #[default]
+    Auto,
+}
+
+impl When {
+    fn enabled(self, terminal: bool) -> bool {
+        match self {
+            When::Yes => true,
+            When::No => false,
+            When::Auto => terminal,
+        }
+    }
+}
+
+/// What the console can be used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Console {
+    /// Whether to show progress at all.
+    pub progress: bool,
+    /// Whether progress can be redrawn in place, rather than written line by line.
+    pub curses: bool,
+    pub color: bool,
+}
+
+impl Console {
+    /// The console as configured by `cli`, for a stderr that is a terminal if `terminal`.
+    pub fn new(cli: &Cli, terminal: bool) -> Self {
+        Self {
+            progress: cli.show_progress,
+            curses: cli.curses.enabled(terminal),
+            color: cli.color.enabled(terminal),
+        }
+    }
+
+    /// The console as configured by `cli`, for this process's stderr.
+    pub fn detect(cli: &Cli) -> Self {
+        Self::new(cli, std::io::stderr().is_terminal())
+    }
+
+    /// The layer writing to the console: warnings and errors always, and progress unless `--noshow_progress`.
+    pub fn layer<S>(&self) -> Box<dyn Layer<S> + Send + Sync>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+    {
+        // Only our own spans show progress, not the runtime's internal resource spans (timers, semaphores, ...).
+        let ours = |meta: &tracing::Metadata<'_>| meta.target().starts_with("razel");
+        let progress = self.progress;
+        let lines = move |meta: &tracing::Metadata<'_>| {
+            let level = if progress { Level::INFO } else { Level::WARN };
+            ours(meta) && *meta.level() <= level
+        };
+        let messages = fmt::layer()
+            .with_ansi(self.color)
+            .with_target(false)
+            .without_time();
+        if self.curses && progress {
+            // Messages are written above the progress bars, which are redrawn below them.
+            let bars = IndicatifLayer::new();
+            let messages = messages
+                .with_writer(bars.get_stderr_writer())
+                .with_filter(filter_fn(move |meta| meta.is_event() && lines(meta)));
+            return bars.with_filter(filter_fn(ours)).and_then(messages).boxed();
+        }
+        messages
+            .with_writer(|| Stderr)
+            .with_span_events(FmtSpan::NEW)
+            .with_filter(filter_fn(lines))
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn console(args: &[&str], terminal: bool) -> Console {
+        let cli = Cli::parse_from(["razel"].iter().chain(args).chain(&["version"]));
+        Console::new(&cli, terminal)
+    }
+
+    #[test]
+    fn test_console_detection() {
+        let all = Console {
+            progress: true,
+            curses: true,
+            color: true,
+        };
+        assert_eq!(console(&[], true), all);
+        assert_eq!(
+            console(&[], false),
+            Console {
+                progress: true,
+                curses: false,
+                color: false,
+            }
+        );
+        assert_eq!(console(&["--curses=yes", "--color=yes"], false), all);
+        assert_eq!(
+            console(&["--color=no", "--show_progress=false"], true),
+            Console {
+                progress: false,
+                curses: true,
+                color: false,
+            }
+        );
+    }
+}
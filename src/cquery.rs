@@ -0,0 +1,478 @@
+//! `razel cquery`, which prints the targets a query matches as configured for the target platform. So far the only
+//! thing configuration decides is toolchain resolution, which `--output=toolchains` prints.
+//!
+//! Toolchains are resolved the way Bazel resolves them. The execution platforms are those the root module registers
+//! with `register_execution_platforms`, in order, then the host platform. The first execution platform that has a
+//! toolchain of every type the target needs is chosen, and with it the first toolchain of each type, in the order
+//! `register_toolchains` lists them, whose `exec_compatible_with` the execution platform has and whose
+//! `target_compatible_with` the target platform has.
+//!
+//! Constraint values are compared as they are written, after making labels relative to the declaring package
+//! absolute, like those `config_setting` compares.
+
+use crate::bazel::label::{Label, Repo};
+use crate::bazel::rule::{AttrValue, Rule};
+use crate::bazel::{Configuration, cpu_constraint_values, host_cpu};
+use crate::error::AnalysisError;
+use crate::exit_code::{ExitCode, WithExitCode};
+use crate::genquery::absolute;
+use crate::query::{self, Edges, Universe};
+use crate::starlark::expr::OutputExpr;
+use crate::workspace::Workspace;
+use starlark::values::dict::AllocDict;
+use starlark::values::list::AllocList;
+use starlark::values::structs::AllocStruct;
+use starlark::values::{Heap, Value};
+use std::sync::Arc;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// The label of the platform razel runs on, which is always an execution platform.
+const HOST_PLATFORM: &str = "@platforms//host";
+
+/// The value of `cquery --output`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Output {
+    /// Each target with its configuration.
+    #[default]
+    Label,
+    /// Each target with the execution platform and the toolchains resolved for it.
+    Toolchains,
+    /// The value of the `--starlark:expr` expression for each target.
+    Starlark,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Platform {
+    label: String,
+    constraint_values: Vec<String>,
+}
+
+impl Platform {
+    /// Whether the platform has every one of `constraint_values`.
+    fn satisfies(&self, constraint_values: &[String]) -> bool {
+        constraint_values
+            .iter()
+            .all(|c| self.constraint_values.contains(c))
+    }
+}
+
+/// A registered `toolchain` rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Toolchain {
+    label: String,
+    toolchain_type: String,
+    /// The target that implements the toolchain, its `toolchain` attribute.
+    implementation: String,
+    exec_compatible_with: Vec<String>,
+    target_compatible_with: Vec<String>,
+}
+
+/// The execution platform chosen for a target, and its toolchain of each type it needs.
+#[derive(Debug, PartialEq, Eq)]
+struct Resolved<'a> {
+    exec_platform: &'a Platform,
+    toolchains: Vec<(&'a str, &'a Toolchain)>,
+}
+
+/// The toolchain types native rules need, by rule class.
+fn toolchain_types(rule_class: &str) -> &'static [&'static str] {
+    match rule_class {
+        "cc_library" | "cc_binary" | "cc_test" => &["@bazel_tools//tools/cpp:toolchain_type"],
+        "sh_binary" | "sh_test" => &["@bazel_tools//tools/sh:toolchain_type"],
+        _ => &[],
+    }
+}
+
+/// Resolves toolchains of `types` for a target built for `target`, that can only run its actions on execution
+/// platforms with all of `exec_compatible_with`.
+fn resolve<'a>(
+    types: &[&'a str],
+    exec_compatible_with: &[String],
+    target: &Platform,
+    exec_platforms: &'a [Platform],
+    toolchains: &'a [Toolchain],
+) -> Result<Resolved<'a>, String> {
+    let find = |toolchain_type: &str, exec_platform: &Platform| {
+        toolchains.iter().find(|t| {
+            t.toolchain_type == toolchain_type
+                && exec_platform.satisfies(&t.exec_compatible_with)
+                && target.satisfies(&t.target_compatible_with)
+        })
+    };
+    let candidates: Vec<_> = exec_platforms
+        .iter()
+        .filter(|p| p.satisfies(exec_compatible_with))
+        .collect();
+    for &exec_platform in &candidates {
+        let found: Option<Vec<_>> = types
+            .iter()
+            .map(|&t| Some((t, find(t, exec_platform)?)))
+            .collect();
+        if let Some(toolchains) = found {
+            return Ok(Resolved {
+                exec_platform,
+                toolchains,
+            });
+        }
+    }
+    if candidates.is_empty() {
+        return Err(format!(
+            "no execution platform has all of exec_compatible_with = [{}]",
+            exec_compatible_with.join(", ")
+        ));
+    }
+    let missing: Vec<_> = types
+        .iter()
+        .filter(|&&t| candidates.iter().all(|p| find(t, p).is_none()))
+        .copied()
+        .collect();
+    Err(if missing.is_empty() {
+        format!(
+            "no one execution platform has toolchains of all of the types {} for {}",
+            types.join(", "),
+            target.label
+        )
+    } else {
+        format!(
+            "no registered toolchain of type {} is compatible with {} and an execution platform",
+            missing.join(", "),
+            target.label
+        )
+    })
+}
+
+/// The rule `label`, or `None` if it's a file.
+async fn rule(workspace: &Arc<Workspace>, label: &Label<'_>) -> anyhow::Result<Option<Rule>> {
+    let Repo::Canonical(repo) = &label.repo else {
+        anyhow::bail!("{label} is not in a canonical repository");
+    };
+    let (_, rules) = workspace
+        .package_rules(&repo.clone().into_owned(), label.package())
+        .await?;
+    Ok(rules.get(label.name()).cloned())
+}
+
+/// The label attribute `attr` of `rule`, whose label is `label`, in absolute form.
+fn labels(label: &Label<'_>, rule: &Rule, attr: &str) -> Vec<String> {
+    let repo = label.repo.to_string();
+    rule.attributes
+        .get(attr)
+        .map_or(&[][..], |value| value.strings())
+        .iter()
+        .map(|l| absolute(&repo, label.package(), l))
+        .collect()
+}
+
+async fn platform(workspace: &Arc<Workspace>, label: &Label<'_>) -> anyhow::Result<Platform> {
+    match rule(workspace, label).await? {
+        Some(rule) if rule.rule_class == "platform" => Ok(Platform {
+            label: label.to_string(),
+            constraint_values: labels(label, &rule, "constraint_values"),
+        }),
+        _ => Err(AnalysisError::QueryFailed {
+            reason: format!("{label} is not a platform"),
+        })
+        .exit_code(ExitCode::AnalysisFailure),
+    }
+}
+
+/// The platforms and toolchains toolchain resolution chooses from.
+struct Registry {
+    target: Platform,
+    exec_platforms: Vec<Platform>,
+    toolchains: Vec<Toolchain>,
+}
+
+impl Registry {
+    /// Loads the platforms and toolchains the root module registers. Targets that registered patterns match but that
+    /// aren't platforms or toolchains respectively, such as the constraints in the same package, are left out.
+    async fn load(
+        workspace: &Arc<Workspace>,
+        config: &Configuration,
+        platforms: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        let module = workspace.main_module().await?;
+        let target = match platforms {
+            Some(pattern) => {
+                let labels = workspace
+                    .expand_target_patterns(&[pattern.to_string()])
+                    .await?;
+                let [label] = &labels[..] else {
+                    return Err(AnalysisError::QueryFailed {
+                        reason: format!("--platforms={pattern} must name a single platform"),
+                    })
+                    .exit_code(ExitCode::AnalysisFailure);
+                };
+                platform(workspace, label).await?
+            }
+            None if config.cpu == host_cpu() => Platform {
+                label: HOST_PLATFORM.to_string(),
+                constraint_values: config.constraint_values(),
+            },
+            None => Platform {
+                label: format!("--cpu={}", config.cpu),
+                constraint_values: config.constraint_values(),
+            },
+        };
+
+        let mut exec_platforms = Vec::new();
+        for label in workspace
+            .expand_target_patterns(&module.execution_platforms)
+            .await?
+        {
+            if let Some(rule) = rule(workspace, &label).await?
+                && rule.rule_class == "platform"
+            {
+                exec_platforms.push(Platform {
+                    label: label.to_string(),
+                    constraint_values: labels(&label, &rule, "constraint_values"),
+                });
+            }
+        }
+        exec_platforms.push(Platform {
+            label: HOST_PLATFORM.to_string(),
+            constraint_values: cpu_constraint_values(host_cpu()),
+        });
+
+        let mut toolchains = Vec::new();
+        for label in workspace.expand_target_patterns(&module.toolchains).await? {
+            let Some(rule) = rule(workspace, &label).await? else {
+                continue;
+            };
+            if rule.rule_class != "toolchain" {
+                continue;
+            }
+            let single = |attr| labels(&label, &rule, attr).into_iter().next();
+            let (Some(toolchain_type), Some(implementation)) =
+                (single("toolchain_type"), single("toolchain"))
+            else {
+                return Err(AnalysisError::QueryFailed {
+                    reason: format!("toolchain {label} must set toolchain_type and toolchain"),
+                })
+                .exit_code(ExitCode::AnalysisFailure);
+            };
+            toolchains.push(Toolchain {
+                label: label.to_string(),
+                toolchain_type,
+                implementation,
+                exec_compatible_with: labels(&label, &rule, "exec_compatible_with"),
+                target_compatible_with: labels(&label, &rule, "target_compatible_with"),
+            });
+        }
+
+        Ok(Self {
+            target,
+            exec_platforms,
+            toolchains,
+        })
+    }
+}
+
+/// The value of an attribute razel only has as Starlark source, if it's a literal `--starlark:expr` can use as a
+/// value, otherwise the source.
+fn literal<'v>(heap: Heap<'v>, source: &str) -> Value<'v> {
+    match source {
+        "True" => Value::new_bool(true),
+        "False" => Value::new_bool(false),
+        "None" => Value::new_none(),
+        _ => match source.parse::<i32>() {
+            Ok(i) => heap.alloc(i),
+            Err(_) => heap.alloc(source),
+        },
+    }
+}
+
+/// The `target` that `--starlark:expr` sees: a struct with the `label`, the `kind` as `--output=label_kind` prints it,
+/// the `configuration`, which is `None` for source files, and for rules their attributes in `attr`.
+fn starlark_target<'v>(
+    heap: Heap<'v>,
+    label: &Label<'_>,
+    rule: Option<&Rule>,
+    config: &Configuration,
+) -> Value<'v> {
+    let Some(rule) = rule else {
+        return heap.alloc(AllocStruct([
+            ("label", heap.alloc(label.to_string())),
+            ("kind", heap.alloc("source file")),
+            ("configuration", Value::new_none()),
+        ]));
+    };
+    let attr = rule.attributes.iter().map(|(name, value)| {
+        let value = match value {
+            AttrValue::String(s) => heap.alloc(s.as_str()),
+            AttrValue::List(strings) => heap.alloc(AllocList(strings.iter().map(String::as_str))),
+            AttrValue::Dict(entries) => heap.alloc(AllocDict(entries.iter())),
+            AttrValue::Other(source) => literal(heap, source),
+        };
+        (name.as_str(), value)
+    });
+    heap.alloc(AllocStruct([
+        ("label", heap.alloc(label.to_string())),
+        ("kind", heap.alloc(format!("{} rule", rule.rule_class))),
+        ("configuration", heap.alloc(config.output_dir_mnemonic())),
+        ("attr", heap.alloc(AllocStruct(attr))),
+    ]))
+}
+
+/// Prints the targets `query` matches, sorted by label, each in the configuration for the target platform
+/// `platforms`, or the one `--cpu` describes. `starlark_expr` is the expression to print with `Output::Starlark`.
+#[allow(clippy::too_many_arguments)]
+pub async fn cquery<W>(
+    out: &mut W,
+    workspace: Arc<Workspace>,
+    config: &Configuration,
+    query: &str,
+    universe: Universe,
+    platforms: Option<&str>,
+    output: Output,
+    starlark_expr: &str,
+) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let expr = OutputExpr::parse(starlark_expr, config.eval_limits)?;
+    let targets =
+        query::evaluate_labels(workspace.clone(), query, universe, Edges::default()).await?;
+    let registry = match output {
+        Output::Label | Output::Starlark => None,
+        Output::Toolchains => Some(Registry::load(&workspace, config, platforms).await?),
+    };
+    let mut failed = Vec::new();
+    for label in targets {
+        let rule = rule(&workspace, &label).await?;
+        if output == Output::Starlark {
+            match expr.format(&label.to_string(), "target", |heap| {
+                starlark_target(heap, &label, rule.as_ref(), config)
+            }) {
+                Ok(line) => out.write_all(format!("{line}\n").as_bytes()).await?,
+                Err(reason) => {
+                    return Err(AnalysisError::QueryFailed { reason })
+                        .exit_code(ExitCode::AnalysisFailure);
+                }
+            }
+            continue;
+        }
+        let Some(rule) = rule else {
+            // Source files have no configuration.
+            out.write_all(format!("{label} (null)\n").as_bytes())
+                .await?;
+            continue;
+        };
+        let mut lines = format!("{label} ({})\n", config.output_dir_mnemonic());
+        if let Some(registry) = &registry {
+            let types = toolchain_types(&rule.rule_class);
+            let exec_compatible_with = labels(&label, &rule, "exec_compatible_with");
+            match resolve(
+                types,
+                &exec_compatible_with,
+                &registry.target,
+                &registry.exec_platforms,
+                &registry.toolchains,
+            ) {
+                Ok(resolved) => {
+                    lines += &format!(
+                        "  target platform: {}\n  execution platform: {}\n",
+                        registry.target.label, resolved.exec_platform.label
+                    );
+                    for (toolchain_type, toolchain) in resolved.toolchains {
+                        lines += &format!(
+                            "  {toolchain_type}: {} (from {})\n",
+                            toolchain.implementation, toolchain.label
+                        );
+                    }
+                }
+                Err(reason) => {
+                    lines += &format!("  toolchain resolution failed: {reason}\n");
+                    failed.push(label.to_string());
+                }
+            }
+        }
+        out.write_all(lines.as_bytes()).await?;
+    }
+    if !failed.is_empty() {
+        return Err(AnalysisError::QueryFailed {
+            reason: format!("toolchain resolution failed for {}", failed.join(", ")),
+        })
+        .exit_code(ExitCode::AnalysisFailure);
+    }
+
+    workspace
+        .check_deferred_errors()
+        .map_err(|e| e.context("The cquery result is partial, as some packages failed to load"))
+        .exit_code(ExitCode::PartialAnalysisFailure)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn platform(label: &str, constraint_values: &[&str]) -> Platform {
+        Platform {
+            label: label.to_string(),
+            constraint_values: constraint_values.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    fn toolchain(label: &str, exec: &[&str], target: &[&str]) -> Toolchain {
+        Toolchain {
+            label: label.to_string(),
+            toolchain_type: "//cc:toolchain_type".to_string(),
+            implementation: format!("{label}_impl"),
+            exec_compatible_with: exec.iter().map(|s| s.to_string()).collect(),
+            target_compatible_with: target.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        let exec_platforms = [
+            platform("//:remote", &["//os:linux", "//:remote"]),
+            platform("//:host", &["//os:macos"]),
+        ];
+        let toolchains = [
+            toolchain("//:cc_mac", &["//os:macos"], &[]),
+            toolchain("//:cc_linux", &["//os:linux"], &["//os:linux"]),
+        ];
+        let resolve = |types: &[&'static str], exec: &[&str], target: &Platform| {
+            let exec: Vec<_> = exec.iter().map(|s| s.to_string()).collect();
+            resolve(types, &exec, target, &exec_platforms, &toolchains).map(|r| {
+                (
+                    r.exec_platform.label.clone(),
+                    r.toolchains[0].1.label.clone(),
+                )
+            })
+        };
+        let linux = platform("//:linux", &["//os:linux"]);
+        let windows = platform("//:windows", &["//os:windows"]);
+
+        // The first execution platform with a toolchain of every type is chosen, with the first such toolchain.
+        assert_eq!(
+            resolve(&["//cc:toolchain_type"], &[], &linux).unwrap(),
+            ("//:remote".to_string(), "//:cc_linux".to_string())
+        );
+        assert_eq!(
+            resolve(&["//cc:toolchain_type"], &[], &windows).unwrap(),
+            ("//:host".to_string(), "//:cc_mac".to_string())
+        );
+        // A target can rule out execution platforms itself.
+        assert_eq!(
+            resolve(&["//cc:toolchain_type"], &["//os:macos"], &linux).unwrap(),
+            ("//:host".to_string(), "//:cc_mac".to_string())
+        );
+        // Targets that need no toolchains still get an execution platform.
+        let none = super::resolve(&[], &[], &linux, &exec_platforms, &toolchains).unwrap();
+        assert_eq!(none.exec_platform.label, "//:remote");
+        assert!(none.toolchains.is_empty());
+
+        let err = resolve(&["//sh:toolchain_type"], &[], &linux).unwrap_err();
+        assert!(
+            err.contains("no registered toolchain of type //sh:toolchain_type"),
+            "{err}"
+        );
+        let err = resolve(&["//cc:toolchain_type"], &["//os:windows"], &linux).unwrap_err();
+        assert!(
+            err.contains("exec_compatible_with = [//os:windows]"),
+            "{err}"
+        );
+    }
+}
@@ -0,0 +1,202 @@
+//! Reporting of dependency cycles between `.bzl` files or targets.
+//!
+//! A cycle is reported as the full path around it, with where each edge was declared and a hint about how it can be
+//! broken, laid out like Bazel's cycle reports.
+
+// Target edges are reported once analysis follows dependency attributes.
+#![allow(dead_code)]
+
+use std::fmt;
+
+/// How one node of the graph came to depend on the next.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EdgeKind {
+    /// A `load()` statement.
+    Load,
+    /// A label written in an attribute of a target in a BUILD file, e.g. `deps`.
+    Attribute(String),
+    /// A dependency added by the rule definition rather than the BUILD file, e.g. `_compiler` or a toolchain.
+    Implicit(String),
+}
+
+/// An edge of a dependency graph.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dependency {
+    pub kind: EdgeKind,
+    /// Where the dependency was declared, as `file:line:column`.
+    pub location: Option<String>,
+}
+
+impl Dependency {
+    pub fn load(location: impl Into<String>) -> Self {
+        Self {
+            kind: EdgeKind::Load,
+            location: Some(location.into()),
+        }
+    }
+}
+
+/// A dependency cycle, returned (inside an `anyhow::Error`) wherever one is detected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cycle {
+    /// The nodes around the cycle, starting from the alphabetically first so the report doesn't depend on the order
+    /// in which work happened to be scheduled.
+    nodes: Vec<String>,
+    /// `edges[i]` leads from `nodes[i]` to the next node, wrapping around at the end.
+    edges: Vec<Dependency>,
+}
+
+impl Cycle {
+    pub fn new(mut nodes: Vec<String>, mut edges: Vec<Dependency>) -> Self {
+        assert_eq!(
+            nodes.len(),
+            edges.len(),
+            "every node of a cycle has one outgoing edge"
+        );
+        if let Some(first) = (0..nodes.len()).min_by_key(|&i| &nodes[i]) {
+            nodes.rotate_left(first);
+            edges.rotate_left(first);
+        }
+        Self { nodes, edges }
+    }
+
+    pub fn nodes(&self) -> &[String] {
+        &self.nodes
+    }
+
+    pub fn is_load_cycle(&self) -> bool {
+        self.edges.iter().all(|e| e.kind == EdgeKind::Load)
+    }
+
+    /// A hint about which edges can be removed to break the cycle.
+    pub fn suggestion(&self) -> String {
+        if self.is_load_cycle() {
+            return "Move the definitions these files need from each other into a new .bzl file that none of them \
+                    loads, and load that instead."
+                .to_string();
+        }
+
+        let names = |implicit: bool| {
+            let mut names: Vec<&str> = self
+                .edges
+                .iter()
+                .filter_map(|e| match &e.kind {
+                    EdgeKind::Attribute(name) if !implicit => Some(name.as_str()),
+                    EdgeKind::Implicit(name) if implicit => Some(name.as_str()),
+                    _ => None,
+                })
+                .collect();
+            names.sort();
+            names.dedup();
+            names.join("`, `")
+        };
+        let (explicit, implicit) = (names(false), names(true));
+        match (explicit.is_empty(), implicit.is_empty()) {
+            (_, true) => format!(
+                "Every edge is written in a BUILD file (`{explicit}`): remove one of them at the locations shown."
+            ),
+            (true, false) => format!(
+                "Every edge is an implicit dependency (`{implicit}`) added by a rule or toolchain, so the cycle must \
+                 be broken in the rule definitions rather than in BUILD files."
+            ),
+            (false, false) => format!(
+                "The edges through `{implicit}` are implicit dependencies added by a rule or toolchain; break the \
+                 cycle by removing one of the explicit edges (`{explicit}`) instead."
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Cycle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let what = if self.is_load_cycle() {
+            ".bzl file loads"
+        } else {
+            "target dependencies"
+        };
+        writeln!(f, "Cycle detected in {what}:")?;
+        for (i, (node, edge)) in self.nodes.iter().zip(&self.edges).enumerate() {
+            let prefix = if i == 0 { ".-> " } else { "|   " };
+            writeln!(f, "{prefix}{node}")?;
+            let how = match &edge.kind {
+                EdgeKind::Load => "loads".to_string(),
+                EdgeKind::Attribute(attr) => format!("depends through `{attr}` on"),
+                EdgeKind::Implicit(attr) => format!("implicitly depends through `{attr}` on"),
+            };
+            let next = &self.nodes[(i + 1) % self.nodes.len()];
+            match &edge.location {
+                Some(location) => writeln!(f, "|     {location}: {how} {next}")?,
+                None => writeln!(f, "|     {how} {next}")?,
+            }
+        }
+        writeln!(f, "`-- {}", self.nodes[0])?;
+        write!(f, "{}", self.suggestion())
+    }
+}
+
+impl std::error::Error for Cycle {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_cycle() {
+        let cycle = Cycle::new(
+            vec!["@@//:b.bzl".to_string(), "@@//:a.bzl".to_string()],
+            vec![Dependency::load("b.bzl:1:1"), Dependency::load("a.bzl:2:1")],
+        );
+        assert_eq!(
+            cycle.to_string(),
+            "Cycle detected in .bzl file loads:\n\
+             .-> @@//:a.bzl\n\
+             |     a.bzl:2:1: loads @@//:b.bzl\n\
+             |   @@//:b.bzl\n\
+             |     b.bzl:1:1: loads @@//:a.bzl\n\
+             `-- @@//:a.bzl\n\
+             Move the definitions these files need from each other into a new .bzl file that none of them loads, \
+             and load that instead."
+        );
+    }
+
+    #[test]
+    fn test_target_cycle_suggestion() {
+        let edge = |kind| Dependency {
+            kind,
+            location: None,
+        };
+        let nodes = vec!["//a".to_string(), "//b".to_string(), "//c".to_string()];
+        let cycle = Cycle::new(
+            nodes.clone(),
+            vec![
+                edge(EdgeKind::Attribute("deps".to_string())),
+                edge(EdgeKind::Implicit("_compiler".to_string())),
+                edge(EdgeKind::Attribute("data".to_string())),
+            ],
+        );
+        assert!(
+            cycle
+                .to_string()
+                .contains("|     implicitly depends through `_compiler` on //c\n")
+        );
+        assert_eq!(
+            cycle.suggestion(),
+            "The edges through `_compiler` are implicit dependencies added by a rule or toolchain; break the cycle \
+             by removing one of the explicit edges (`data`, `deps`) instead."
+        );
+
+        let cycle = Cycle::new(
+            nodes,
+            vec![
+                edge(EdgeKind::Attribute("deps".to_string())),
+                edge(EdgeKind::Attribute("deps".to_string())),
+                edge(EdgeKind::Attribute("deps".to_string())),
+            ],
+        );
+        assert!(
+            cycle
+                .suggestion()
+                .starts_with("Every edge is written in a BUILD file (`deps`)")
+        );
+    }
+}
@@ -0,0 +1,71 @@
+//! `razel dump`: what the workspace holds in memory, for working out why a build sees stale or surprising state.
+//!
+//! Run against the server (`--batch=false`), this shows what earlier commands left behind, which is where staleness
+//! comes from; in a batch invocation, only what the dump itself needed.
+
+use crate::DumpArgs;
+use crate::workspace::{NodeState, Workspace};
+
+fn describe(state: &NodeState) -> String {
+    match state {
+        NodeState::Pending => "pending".to_string(),
+        NodeState::Done => "done".to_string(),
+        NodeState::Failed(e) => format!("failed: {}", e.0.root_cause()),
+    }
+}
+
+/// Writes the requested `sections` of `workspace`'s state as text.
+pub fn dump(workspace: &Workspace, sections: &DumpArgs) -> String {
+    let mut text = String::new();
+    if sections.packages {
+        let packages = workspace.package_snapshot();
+        text.push_str(&format!("Packages ({}):\n", packages.len()));
+        for (package, targets) in packages {
+            text.push_str(&format!("  {package}\n"));
+            for target in targets {
+                text.push_str(&format!("    {target}\n"));
+            }
+        }
+    }
+    if sections.repo_mappings {
+        text.push_str("Repository mappings:\n");
+        for (repo, (_, mapping)) in workspace.repository_snapshot() {
+            if mapping.is_empty() {
+                continue;
+            }
+            text.push_str(&format!("  {repo}\n"));
+            for (apparent, canonical) in mapping {
+                text.push_str(&format!("    @{apparent} -> {canonical}\n"));
+            }
+        }
+    }
+    if sections.action_graph {
+        // Targets are only loaded so far, never analyzed, so no actions exist to be dumped.
+        text.push_str("Action graph (0 actions):\n");
+    }
+    if sections.skyframe {
+        let repositories = workspace.repository_snapshot();
+        let bzls = workspace.bzl_snapshot();
+        let packages = workspace.package_snapshot();
+        text.push_str(&format!(
+            "Nodes ({}):\n",
+            repositories.len() + bzls.len() + packages.len()
+        ));
+        for (repo, (state, mapping)) in repositories {
+            text.push_str(&format!("  REPOSITORY:{repo} [{}]\n", describe(&state)));
+            for (_, canonical) in mapping {
+                text.push_str(&format!("    -> REPOSITORY:{canonical}\n"));
+            }
+        }
+        for (bzl, (state, loaded_by)) in bzls {
+            text.push_str(&format!("  BZL:{bzl} [{}]\n", describe(&state)));
+            for dependent in loaded_by {
+                text.push_str(&format!("    <- BZL:{dependent}\n"));
+            }
+        }
+        for package in packages.keys() {
+            text.push_str(&format!("  PACKAGE:{package} [done]\n"));
+        }
+    }
+    text
+}
@@ -0,0 +1,630 @@
+//! Stable, machine-readable codes for the ways a command can fail, e.g. `RAZEL_FETCH_CHECKSUM_MISMATCH`.
+//!
+//! Errors are `anyhow::Error`s as everywhere else, but the failures tooling may want to react to are raised as one of
+//! the per-subsystem enums below, each variant with its own code. `error_code` finds the code anywhere in an error's
+//! chain, so context added on the way up doesn't hide it. Codes are part of razel's interface: never change or reuse
+//! one, only add new ones.
+
+use crate::bazel::download::FailureKind;
+use crate::bazel::module_graph::CompatibilityConflict;
+use crate::bazel::mvs::VersionDrift;
+use crate::bazel::policy::PolicyViolation;
+use crate::cycle::Cycle;
+use crate::shared_error::SharedError;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+
+/// The part of razel a failure happened in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, derive_more::Display)]
+pub enum Subsystem {
+    /// Reading BUILD and .bzl files, and parsing labels and target patterns.
+    #[display("LOADING")]
+    Loading,
+    /// Working out which modules and repositories exist, and what they are called.
+    #[display("RESOLUTION")]
+    Resolution,
+    /// Downloading and extracting external repositories.
+    #[display("FETCH")]
+    Fetch,
+    #[display("ANALYSIS")]
+    Analysis,
+    /// Running actions, and tools such as BUILD file generators.
+    #[display("EXECUTION")]
+    Execution,
+}
+
+/// Identifies a kind of failure, displayed as `RAZEL_<SUBSYSTEM>_<NAME>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ErrorCode {
+    pub subsystem: Subsystem,
+    pub name: &'static str,
+}
+
+impl ErrorCode {
+    const fn new(subsystem: Subsystem, name: &'static str) -> Self {
+        Self { subsystem, name }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RAZEL_{}_{}", self.subsystem, self.name)
+    }
+}
+
+impl Serialize for ErrorCode {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[derive(Debug)]
+pub enum LoadingError {
+    InvalidLabel {
+        reason: String,
+    },
+    InvalidTargetPattern {
+        pattern: String,
+        reason: String,
+    },
+    /// Every running load waited on another for too long; `report` describes the wait graph.
+    Stalled {
+        report: String,
+    },
+    /// Evaluating `file` used more of a resource than `--max_starlark_*` allows; `limit` says which.
+    EvaluationLimitExceeded {
+        file: String,
+        line: usize,
+        limit: String,
+    },
+}
+
+impl LoadingError {
+    pub fn code(&self) -> ErrorCode {
+        let name = match self {
+            LoadingError::InvalidLabel { .. } => "INVALID_LABEL",
+            LoadingError::InvalidTargetPattern { .. } => "INVALID_TARGET_PATTERN",
+            LoadingError::Stalled { .. } => "STALLED",
+            LoadingError::EvaluationLimitExceeded { .. } => "EVALUATION_LIMIT_EXCEEDED",
+        };
+        ErrorCode::new(Subsystem::Loading, name)
+    }
+}
+
+impl fmt::Display for LoadingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadingError::InvalidLabel { reason } => write!(f, "Failed to parse label: {reason:?}"),
+            LoadingError::InvalidTargetPattern { pattern, reason } => {
+                write!(f, "Invalid target pattern {pattern:?}: {reason}")
+            }
+            LoadingError::Stalled { report } => f.write_str(report),
+            LoadingError::EvaluationLimitExceeded { file, line, limit } => {
+                write!(
+                    f,
+                    "evaluation exceeded limits in {file} at line {line}: {limit}"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for LoadingError {}
+
+#[derive(Debug)]
+pub enum ResolutionError {
+    /// No repository with this canonical name was defined.
+    UnknownRepository {
+        name: String,
+    },
+    /// The repository mapping of `from` has no repository by this apparent name.
+    RepositoryNotVisible {
+        apparent: String,
+        from: String,
+    },
+    MissingModuleAttribute {
+        attribute: &'static str,
+    },
+    InvalidLockfile {
+        path: PathBuf,
+        reason: String,
+    },
+    InvalidPolicy {
+        path: PathBuf,
+        reason: String,
+    },
+    /// `bazel_dep`s of the root module ask for other versions than the ones selected, with
+    /// `--check_direct_dependencies=error`.
+    DirectDependencyMismatch {
+        drift: Vec<VersionDrift>,
+    },
+    /// The registry has no such module, or no such version of it.
+    ModuleNotFound {
+        module: String,
+        version: Option<String>,
+        registry: String,
+    },
+    /// A file fetched from a registry isn't what the registry format says it should be.
+    InvalidRegistryFile {
+        url: String,
+        reason: String,
+    },
+    /// A module asks for a version of a module with a `multiple_version_override` above all those `allowed`.
+    NoAllowedVersion {
+        module: String,
+        version: String,
+        requested_by: String,
+        allowed: Vec<String>,
+    },
+    /// The module graph no longer resolves to the versions recorded in the lockfile, with `--lockfile_mode=error`.
+    LockfileOutdated {
+        path: PathBuf,
+        changes: Vec<String>,
+    },
+    /// Registries yanked versions the dependency graph resolves to, by `<module>@<version>`, with their reasons.
+    YankedVersions {
+        yanked: BTreeMap<String, String>,
+    },
+    /// A module's `bazel_compatibility` has a `requirement` the Bazel `version` razel is compatible with doesn't meet.
+    IncompatibleBazelVersion {
+        module: String,
+        requirement: String,
+        version: &'static str,
+    },
+    /// Versions of a module at different compatibility levels are asked for.
+    CompatibilityLevelConflict {
+        conflict: CompatibilityConflict,
+    },
+}
+
+impl ResolutionError {
+    pub fn code(&self) -> ErrorCode {
+        let name = match self {
+            ResolutionError::UnknownRepository { .. } => "UNKNOWN_REPOSITORY",
+            ResolutionError::RepositoryNotVisible { .. } => "REPOSITORY_NOT_VISIBLE",
+            ResolutionError::MissingModuleAttribute { .. } => "INVALID_MODULE",
+            ResolutionError::InvalidLockfile { .. } => "INVALID_LOCKFILE",
+            ResolutionError::InvalidPolicy { .. } => "INVALID_POLICY",
+            ResolutionError::DirectDependencyMismatch { .. } => "DIRECT_DEPENDENCY_MISMATCH",
+            ResolutionError::ModuleNotFound { .. } => "MODULE_NOT_FOUND",
+            ResolutionError::InvalidRegistryFile { .. } => "INVALID_REGISTRY_FILE",
+            ResolutionError::NoAllowedVersion { .. } => "NO_ALLOWED_VERSION",
+            ResolutionError::LockfileOutdated { .. } => "LOCKFILE_OUTDATED",
+            ResolutionError::YankedVersions { .. } => "YANKED_VERSION",
+            ResolutionError::IncompatibleBazelVersion { .. } => "INCOMPATIBLE_BAZEL_VERSION",
+            ResolutionError::CompatibilityLevelConflict { .. } => "COMPATIBILITY_LEVEL_CONFLICT",
+        };
+        ErrorCode::new(Subsystem::Resolution, name)
+    }
+}
+
+impl fmt::Display for ResolutionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResolutionError::UnknownRepository { name } => write!(f, "Unknown repository {name}"),
+            ResolutionError::RepositoryNotVisible { apparent, from } => {
+                write!(f, "No repository visible as {apparent} from {from}")
+            }
+            ResolutionError::MissingModuleAttribute { attribute } => {
+                write!(f, "Module {attribute} is required")
+            }
+            ResolutionError::InvalidLockfile { path, reason } => {
+                write!(f, "Failed to parse {}: {reason}", path.display())
+            }
+            ResolutionError::InvalidPolicy { path, reason } => {
+                write!(f, "Invalid dependency policy {}: {reason}", path.display())
+            }
+            ResolutionError::DirectDependencyMismatch { drift } => {
+                for d in drift {
+                    writeln!(f, "{d}")?;
+                }
+                f.write_str("Or pass --check_direct_dependencies=warning or =off")
+            }
+            ResolutionError::ModuleNotFound {
+                module,
+                version: Some(version),
+                registry,
+            } => write!(
+                f,
+                "Module {module}@{version} not found in registry {registry}"
+            ),
+            ResolutionError::ModuleNotFound {
+                module,
+                version: None,
+                registry,
+            } => write!(f, "Module {module} not found in registry {registry}"),
+            ResolutionError::InvalidRegistryFile { url, reason } => {
+                write!(f, "Invalid registry file {url}: {reason}")
+            }
+            ResolutionError::NoAllowedVersion {
+                module,
+                version,
+                requested_by,
+                allowed,
+            } => write!(
+                f,
+                "{requested_by} depends on {module}@{version}, but none of the versions \
+                 multiple_version_override allows is at or above it: {}",
+                allowed.join(", ")
+            ),
+            ResolutionError::LockfileOutdated { path, changes } => {
+                writeln!(f, "{} is out of date:", path.display())?;
+                for change in changes {
+                    writeln!(f, "  {change}")?;
+                }
+                f.write_str("Pass --lockfile_mode=update to update it")
+            }
+            ResolutionError::YankedVersions { yanked } => {
+                for (module, reason) in yanked {
+                    writeln!(
+                        f,
+                        "Yanked version detected in the resolved dependency graph: {module}, for the reason: {reason}"
+                    )?;
+                }
+                let modules: Vec<_> = yanked.keys().map(String::as_str).collect();
+                write!(
+                    f,
+                    "Depend on a newer version, or pass --allow_yanked_versions={} to use it anyway",
+                    modules.join(",")
+                )
+            }
+            ResolutionError::IncompatibleBazelVersion {
+                module,
+                requirement,
+                version,
+            } => write!(
+                f,
+                "Module {module} requires Bazel {requirement}, but razel is compatible with Bazel {version}"
+            ),
+            ResolutionError::CompatibilityLevelConflict { conflict } => write!(f, "{conflict}"),
+        }
+    }
+}
+
+impl std::error::Error for ResolutionError {}
+
+#[derive(Debug)]
+pub enum FetchError {
+    InvalidChecksum {
+        checksum: String,
+    },
+    /// Every URL failed, and at least one of them served something other than what was expected.
+    ChecksumMismatch {
+        dest: PathBuf,
+        errors: Vec<String>,
+    },
+    /// Every URL failed, the same `kind` of way if given.
+    DownloadFailed {
+        dest: PathBuf,
+        errors: Vec<String>,
+        kind: Option<FailureKind>,
+    },
+    /// An archive entry would be extracted outside of the repository, or in place of its root.
+    UnsafeArchivePath {
+        entry: String,
+    },
+    /// An archive entry is a symlink or hard link to `target`, which is outside of the repository.
+    UnsafeArchiveLink {
+        entry: String,
+        target: String,
+    },
+    /// The archive at `url` isn't of a type razel can extract.
+    UnsupportedArchive {
+        url: String,
+    },
+    /// The repository is neither vendored nor fetchable yet.
+    Unsupported {
+        repo: String,
+    },
+    /// The patch named `patch` doesn't apply, to `file` if it got as far as one.
+    PatchFailed {
+        patch: String,
+        file: Option<String>,
+        reason: String,
+    },
+    /// A download without an expected checksum, with `--experimental_repository_disable_download`.
+    ChecksumRequired {
+        dest: PathBuf,
+        urls: Vec<String>,
+    },
+    /// Fetching from or checking out the git repository `remote` failed.
+    GitFailed {
+        remote: String,
+        reason: String,
+    },
+}
+
+impl FetchError {
+    pub fn code(&self) -> ErrorCode {
+        let name = match self {
+            FetchError::InvalidChecksum { .. } => "INVALID_CHECKSUM",
+            FetchError::ChecksumMismatch { .. } => "CHECKSUM_MISMATCH",
+            FetchError::DownloadFailed { kind, .. } => {
+                kind.map_or("DOWNLOAD_FAILED", FailureKind::code_name)
+            }
+            FetchError::UnsafeArchivePath { .. } => "UNSAFE_ARCHIVE_PATH",
+            FetchError::UnsafeArchiveLink { .. } => "UNSAFE_ARCHIVE_LINK",
+            FetchError::UnsupportedArchive { .. } => "UNSUPPORTED_ARCHIVE",
+            FetchError::Unsupported { .. } => "UNSUPPORTED",
+            FetchError::PatchFailed { .. } => "PATCH_FAILED",
+            FetchError::ChecksumRequired { .. } => "CHECKSUM_REQUIRED",
+            FetchError::GitFailed { .. } => "GIT_FAILED",
+        };
+        ErrorCode::new(Subsystem::Fetch, name)
+    }
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::InvalidChecksum { checksum } => write!(
+                f,
+                "Unsupported checksum {checksum:?}, expected sha256-, sha384- or sha512-<base64>, or a hex sha256"
+            ),
+            FetchError::ChecksumMismatch { dest, errors }
+            | FetchError::DownloadFailed { dest, errors, .. } => write!(
+                f,
+                "Failed to download {}:\n  {}",
+                dest.display(),
+                errors.join("\n  ")
+            ),
+            FetchError::UnsafeArchivePath { entry } => {
+                write!(f, "Archive entry {entry} has an unsafe path")
+            }
+            FetchError::UnsafeArchiveLink { entry, target } => write!(
+                f,
+                "Archive entry {entry} links to {target}, outside of the repository"
+            ),
+            FetchError::UnsupportedArchive { url } => write!(
+                f,
+                "Unsupported archive type of {url}: razel extracts .zip, .jar, .tar, .tar.gz and .tgz archives"
+            ),
+            FetchError::Unsupported { repo } => {
+                write!(f, "Fetching external repository {repo} is not implemented")
+            }
+            FetchError::PatchFailed {
+                patch,
+                file: Some(file),
+                reason,
+            } => write!(f, "Failed to apply {patch} to {file}: {reason}"),
+            FetchError::PatchFailed {
+                patch,
+                file: None,
+                reason,
+            } => write!(f, "Failed to apply {patch}: {reason}"),
+            FetchError::ChecksumRequired { dest, urls } => write!(
+                f,
+                "Refusing to download {} from {} without a checksum, because of \
+                 --experimental_repository_disable_download; give its sha256 or integrity",
+                dest.display(),
+                urls.join(", ")
+            ),
+            FetchError::GitFailed { remote, reason } => {
+                write!(f, "Failed to fetch git repository {remote}: {reason}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for FetchError {}
+
+#[derive(Debug)]
+pub enum AnalysisError {
+    QueryFailed {
+        reason: String,
+    },
+    /// `what` came out differently when computed again from scratch, see `--experimental_check_determinism`.
+    Nondeterministic {
+        what: String,
+        difference: String,
+    },
+}
+
+impl AnalysisError {
+    pub fn code(&self) -> ErrorCode {
+        let name = match self {
+            AnalysisError::QueryFailed { .. } => "QUERY_FAILED",
+            AnalysisError::Nondeterministic { .. } => "NONDETERMINISTIC",
+        };
+        ErrorCode::new(Subsystem::Analysis, name)
+    }
+}
+
+impl fmt::Display for AnalysisError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AnalysisError::QueryFailed { reason } => write!(f, "Query evaluation error: {reason}"),
+            AnalysisError::Nondeterministic { what, difference } => write!(
+                f,
+                "{what} differs between two evaluations with different hash map orders: {difference}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AnalysisError {}
+
+#[derive(Debug)]
+pub enum ExecutionError {
+    GeneratorFailed { name: String, status: String },
+}
+
+impl ExecutionError {
+    pub fn code(&self) -> ErrorCode {
+        let name = match self {
+            ExecutionError::GeneratorFailed { .. } => "GENERATOR_FAILED",
+        };
+        ErrorCode::new(Subsystem::Execution, name)
+    }
+}
+
+impl fmt::Display for ExecutionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecutionError::GeneratorFailed { name, status } => {
+                write!(f, "Generator {name} failed: {status}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ExecutionError {}
+
+/// The code of a single error, not looking at its causes.
+fn own_code(error: &(dyn std::error::Error + 'static)) -> Option<ErrorCode> {
+    if let Some(e) = error.downcast_ref::<LoadingError>() {
+        return Some(e.code());
+    }
+    if let Some(e) = error.downcast_ref::<ResolutionError>() {
+        return Some(e.code());
+    }
+    if let Some(e) = error.downcast_ref::<FetchError>() {
+        return Some(e.code());
+    }
+    if let Some(e) = error.downcast_ref::<AnalysisError>() {
+        return Some(e.code());
+    }
+    if let Some(e) = error.downcast_ref::<ExecutionError>() {
+        return Some(e.code());
+    }
+    if error.is::<PolicyViolation>() {
+        return Some(ErrorCode::new(Subsystem::Resolution, "POLICY_VIOLATION"));
+    }
+    if let Some(cycle) = error.downcast_ref::<Cycle>() {
+        let subsystem = if cycle.is_load_cycle() {
+            Subsystem::Loading
+        } else {
+            Subsystem::Analysis
+        };
+        return Some(ErrorCode::new(subsystem, "CYCLE"));
+    }
+    None
+}
+
+/// A Starlark error that keeps the file span and call stack it was raised at, for `Diagnostic`.
+///
+/// `starlark::Error::into_anyhow` only keeps them in the message, so wrap errors with `StarlarkError::wrap` instead.
+#[derive(Debug)]
+pub struct StarlarkError(pub starlark::Error);
+
+impl StarlarkError {
+    pub fn wrap(error: starlark::Error) -> anyhow::Error {
+        anyhow::Error::new(StarlarkError(error))
+    }
+}
+
+impl fmt::Display for StarlarkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl std::error::Error for StarlarkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.0.kind().source()
+    }
+}
+
+/// Every error in `error`'s chain, looking through shared errors to the error each one wraps.
+fn causes(error: &anyhow::Error) -> Vec<&(dyn std::error::Error + 'static)> {
+    let mut causes = Vec::new();
+    for cause in error.chain() {
+        if let Some(shared) = cause.downcast_ref::<SharedError>() {
+            // The rest of the chain is the shared error's own, which starts with the error it wraps.
+            causes.extend(self::causes(&shared.0));
+            break;
+        }
+        causes.push(cause);
+    }
+    causes
+}
+
+/// The code of the outermost coded error in `error`'s chain, if any.
+pub fn error_code(error: &anyhow::Error) -> Option<ErrorCode> {
+    causes(error).into_iter().find_map(own_code)
+}
+
+/// An error as reported to tools, e.g. in JSON output.
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<ErrorCode>,
+    pub message: String,
+    /// Where in a Starlark file the error was raised, e.g. `defs.bzl:3:5-12`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    /// The Starlark calls leading up to the error, outermost first.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stack: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new(error: &anyhow::Error) -> Self {
+        let starlark = causes(error)
+            .into_iter()
+            .find_map(|cause| cause.downcast_ref::<StarlarkError>());
+        Self {
+            code: error_code(error),
+            message: format!("{error:#}"),
+            location: starlark
+                .and_then(|e| e.0.span())
+                .map(|span| span.to_string()),
+            stack: starlark
+                .map(|e| {
+                    e.0.call_stack()
+                        .frames
+                        .iter()
+                        .map(|frame| frame.to_string())
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_code() {
+        let mismatch = anyhow::Error::new(FetchError::ChecksumMismatch {
+            dest: "/tmp/rules_go.zip".into(),
+            errors: vec!["https://example.com/rules_go.zip: checksum mismatch".to_string()],
+        })
+        .context("Failed to fetch @@rules_go+");
+        assert_eq!(
+            error_code(&mismatch).unwrap().to_string(),
+            "RAZEL_FETCH_CHECKSUM_MISMATCH"
+        );
+
+        let shared = anyhow::Error::new(SharedError::from(anyhow::Error::new(
+            LoadingError::InvalidTargetPattern {
+                pattern: "//a:b:c".to_string(),
+                reason: "invalid target name".to_string(),
+            },
+        )))
+        .context("Failed to evaluate repo @@");
+        assert_eq!(
+            error_code(&shared).unwrap().to_string(),
+            "RAZEL_LOADING_INVALID_TARGET_PATTERN"
+        );
+
+        let failed = anyhow::Error::new(ExecutionError::GeneratorFailed {
+            name: "gazelle".to_string(),
+            status: "exit status: 1".to_string(),
+        })
+        .context("Failed to generate BUILD files");
+        let diagnostic = Diagnostic::new(&failed);
+        assert_eq!(
+            serde_json::to_value(&diagnostic).unwrap(),
+            serde_json::json!({
+                "code": "RAZEL_EXECUTION_GENERATOR_FAILED",
+                "message": "Failed to generate BUILD files: Generator gazelle failed: exit status: 1",
+            })
+        );
+        assert_eq!(error_code(&anyhow::anyhow!("Failed to load package")), None);
+    }
+}
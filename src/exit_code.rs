@@ -0,0 +1,171 @@
+//! Process exit codes, with the same numbers as Bazel's so that scripts and CI written for Bazel keep working.
+//!
+//! Errors are `anyhow::Error`s as everywhere else. Where the kind of failure is known, the error is tagged with
+//! `WithExitCode::exit_code`; `exit_code` then finds the tag anywhere in the error's chain, falling back to a build
+//! failure.
+
+use crate::error::{Subsystem, error_code};
+
+/// See <https://bazel.build/run/scripts#exit-codes>.
+// Some are only produced by parts of Bazel razel doesn't have yet, like running tests.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    Success,
+    /// The build, or loading the packages for any command, failed.
+    BuildFailure,
+    /// Bad or conflicting flags, target pattern files or .bazelrc options.
+    CommandLineError,
+    /// The build succeeded, but some tests failed or timed out.
+    TestsFailed,
+    /// For `query --keep_going`, some packages failed to load, so the result printed may be missing targets.
+    PartialAnalysisFailure,
+    /// The build succeeded, but no tests were found although testing was requested.
+    NoTestsFound,
+    /// The target of `run` couldn't be run.
+    RunFailure,
+    /// Analysis failed, or for `query`, evaluating the query did.
+    AnalysisFailure,
+    /// The command was interrupted, e.g. with Ctrl-C, and stopped cleanly.
+    Interrupted,
+    /// Another command holds the output base, and `--noblock_for_lock` was given.
+    LockHeld,
+    /// Executing on or talking to the remote execution or caching service failed.
+    RemoteError,
+    /// Something about the local machine prevented the command from running, e.g. the server not starting.
+    LocalEnvironmentalError,
+    /// razel itself failed, e.g. panicked.
+    InternalError,
+    /// External dependencies couldn't be resolved or fetched, or are forbidden by the dependency policy.
+    ExternalDepsError,
+}
+
+impl ExitCode {
+    pub fn code(self) -> i32 {
+        match self {
+            ExitCode::Success => 0,
+            ExitCode::BuildFailure => 1,
+            ExitCode::CommandLineError => 2,
+            ExitCode::TestsFailed | ExitCode::PartialAnalysisFailure => 3,
+            ExitCode::NoTestsFound => 4,
+            ExitCode::RunFailure => 6,
+            ExitCode::AnalysisFailure => 7,
+            ExitCode::Interrupted => 8,
+            ExitCode::LockHeld => 9,
+            ExitCode::RemoteError => 34,
+            ExitCode::LocalEnvironmentalError => 36,
+            ExitCode::InternalError => 37,
+            ExitCode::ExternalDepsError => 48,
+        }
+    }
+
+    /// Bazel's name for the exit code, as used in the Build Event Protocol.
+    pub fn name(self) -> &'static str {
+        match self {
+            ExitCode::Success => "SUCCESS",
+            ExitCode::BuildFailure => "BUILD_FAILURE",
+            ExitCode::CommandLineError => "COMMAND_LINE_ERROR",
+            ExitCode::TestsFailed => "TESTS_FAILED",
+            ExitCode::PartialAnalysisFailure => "PARTIAL_ANALYSIS_FAILURE",
+            ExitCode::NoTestsFound => "NO_TESTS_FOUND",
+            ExitCode::RunFailure => "RUN_FAILURE",
+            ExitCode::AnalysisFailure => "ANALYSIS_FAILURE",
+            ExitCode::Interrupted => "INTERRUPTED",
+            ExitCode::LockHeld => "LOCK_HELD_NOBLOCK_FOR_LOCK",
+            ExitCode::RemoteError => "REMOTE_ERROR",
+            ExitCode::LocalEnvironmentalError => "LOCAL_ENVIRONMENTAL_ERROR",
+            ExitCode::InternalError => "BLAZE_INTERNAL_ERROR",
+            ExitCode::ExternalDepsError => "EXTERNAL_DEPS_ERROR",
+        }
+    }
+}
+
+impl From<ExitCode> for std::process::ExitCode {
+    fn from(code: ExitCode) -> Self {
+        (code.code() as u8).into()
+    }
+}
+
+/// An error tagged with the exit code it should end the command with. It displays as the error it wraps, so tagging
+/// doesn't change error messages.
+#[derive(Debug)]
+pub struct ExitError {
+    pub code: ExitCode,
+    error: anyhow::Error,
+}
+
+impl std::fmt::Display for ExitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl std::error::Error for ExitError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
+
+pub trait WithExitCode<T> {
+    /// Tags an error with the exit code it should end the command with.
+    fn exit_code(self, code: ExitCode) -> anyhow::Result<T>;
+}
+
+impl<T, E: Into<anyhow::Error>> WithExitCode<T> for Result<T, E> {
+    fn exit_code(self, code: ExitCode) -> anyhow::Result<T> {
+        self.map_err(|error| {
+            ExitError {
+                code,
+                error: error.into(),
+            }
+            .into()
+        })
+    }
+}
+
+/// The exit code for a command failing with `error`.
+pub fn exit_code(error: &anyhow::Error) -> ExitCode {
+    for cause in error.chain() {
+        if let Some(e) = cause.downcast_ref::<ExitError>() {
+            return e.code;
+        }
+        if cause.is::<clap::Error>() {
+            return ExitCode::CommandLineError;
+        }
+    }
+    match error_code(error).map(|code| code.subsystem) {
+        Some(Subsystem::Resolution | Subsystem::Fetch) => ExitCode::ExternalDepsError,
+        _ => ExitCode::BuildFailure,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bazel::policy::PolicyViolation;
+
+    #[test]
+    fn test_exit_code() {
+        let plain = anyhow::anyhow!("Failed to load package");
+        assert_eq!(exit_code(&plain), ExitCode::BuildFailure);
+
+        let tagged = Err::<(), _>(anyhow::anyhow!("no such flag"))
+            .exit_code(ExitCode::CommandLineError)
+            .map_err(|e| e.context("Invalid .bazelrc"));
+        let err = tagged.unwrap_err();
+        assert_eq!(exit_code(&err), ExitCode::CommandLineError);
+        assert_eq!(format!("{err:#}"), "Invalid .bazelrc: no such flag");
+
+        let violation = anyhow::Error::new(PolicyViolation {
+            kind: "module",
+            value: "left_pad".to_string(),
+            reason: "denied".to_string(),
+            requested_by: "MODULE.bazel".to_string(),
+            policy_file: "dependency_policy.json".into(),
+        })
+        .context("Failed to resolve modules");
+        assert_eq!(exit_code(&violation), ExitCode::ExternalDepsError);
+        assert_eq!(ExitCode::Interrupted.code(), 8);
+        assert_eq!(ExitCode::PartialAnalysisFailure.code(), 3);
+    }
+}
@@ -0,0 +1,266 @@
+//! `--explain`: a log of why each action was executed rather than reused from the last build.
+//!
+//! The command line and input digests of every action that ran are remembered in the output base. When an action runs
+//! again, it is compared with the last run of the action producing the same primary output, and the difference is
+//! written to the explanation file in the same words Bazel uses. `--verbose_explanations` adds which inputs changed and
+//! how the command line did.
+
+use crate::bazel::Configuration;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// An action, as far as explaining its execution is concerned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExplainedAction {
+    /// The progress message of the action, e.g. `Compiling foo.cc`.
+    pub description: String,
+    /// The first output of the action, which identifies it from one build to the next.
+    pub primary_output: String,
+    pub command_line: Vec<String>,
+    /// The digest of each input, by its path relative to the execution root.
+    pub inputs: BTreeMap<String, String>,
+}
+
+/// What was recorded about an action the last time it ran.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct ActionRecord {
+    command_line: Vec<String>,
+    inputs: BTreeMap<String, String>,
+}
+
+/// Why an action was executed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reason {
+    /// The action never ran before, e.g. because this is the first build.
+    New,
+    CommandLineChanged {
+        old: Vec<String>,
+        new: Vec<String>,
+    },
+    InputsChanged {
+        changed: Vec<String>,
+        added: Vec<String>,
+        removed: Vec<String>,
+    },
+}
+
+impl Reason {
+    /// Why an action `current` runs, given how it `previous`ly ran. `None` if nothing changed.
+    fn between(previous: Option<&ActionRecord>, current: &ExplainedAction) -> Option<Self> {
+        let Some(previous) = previous else {
+            return Some(Reason::New);
+        };
+        if previous.command_line != current.command_line {
+            return Some(Reason::CommandLineChanged {
+                old: previous.command_line.clone(),
+                new: current.command_line.clone(),
+            });
+        }
+        let mut changed = Vec::new();
+        let mut added = Vec::new();
+        for (path, digest) in &current.inputs {
+            match previous.inputs.get(path) {
+                None => added.push(path.clone()),
+                Some(old) if old != digest => changed.push(path.clone()),
+                Some(_) => {}
+            }
+        }
+        let removed: Vec<_> = previous
+            .inputs
+            .keys()
+            .filter(|path| !current.inputs.contains_key(*path))
+            .cloned()
+            .collect();
+        if changed.is_empty() && added.is_empty() && removed.is_empty() {
+            return None;
+        }
+        Some(Reason::InputsChanged {
+            changed,
+            added,
+            removed,
+        })
+    }
+
+    fn describe(&self, verbose: bool) -> String {
+        match self {
+            Reason::New => "no entry in the cache (action is new).".to_string(),
+            Reason::CommandLineChanged { .. } if !verbose => {
+                "action command has changed.".to_string()
+            }
+            Reason::CommandLineChanged { old, new } => format!(
+                "action command has changed.\n  Old command: {}\n  New command: {}",
+                old.join(" "),
+                new.join(" ")
+            ),
+            Reason::InputsChanged { .. } if !verbose => "One of the files has changed.".to_string(),
+            Reason::InputsChanged {
+                changed,
+                added,
+                removed,
+            } => {
+                let lines: Vec<_> = changed
+                    .iter()
+                    .map(|path| format!("Digest of input file {path} changed."))
+                    .chain(
+                        added
+                            .iter()
+                            .map(|path| format!("Input file {path} was added.")),
+                    )
+                    .chain(
+                        removed
+                            .iter()
+                            .map(|path| format!("Input file {path} was removed.")),
+                    )
+                    .collect();
+                lines.join("\n  ")
+            }
+        }
+    }
+}
+
+struct State {
+    out: std::io::BufWriter<std::fs::File>,
+    /// Every action that ran, in this build or an earlier one, by primary output.
+    history: BTreeMap<String, ActionRecord>,
+}
+
+/// Writes the `--explain` file for a build.
+pub struct Explainer {
+    state: Mutex<State>,
+    verbose: bool,
+    history_path: PathBuf,
+}
+
+impl Explainer {
+    /// Creates the explanation file at `path`, comparing actions with the history kept at `history_path`.
+    pub fn create(path: &Path, verbose: bool, history_path: PathBuf) -> anyhow::Result<Self> {
+        let file = std::fs::File::create(path).map_err(|e| {
+            anyhow::anyhow!("Failed to create --explain file {}: {e}", path.display())
+        })?;
+        // A missing or unreadable history just makes every action new.
+        let history = std::fs::read(&history_path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+        Ok(Self {
+            state: Mutex::new(State {
+                out: std::io::BufWriter::new(file),
+                history,
+            }),
+            verbose,
+            history_path,
+        })
+    }
+
+    /// Opens the explanation file requested by `--explain`, if any.
+    pub fn from_config(config: &Configuration, output_base: &Path) -> anyhow::Result<Option<Self>> {
+        config
+            .explain
+            .as_deref()
+            .map(|path| {
+                Self::create(
+                    path,
+                    config.verbose_explanations,
+                    output_base.join("action_history.json"),
+                )
+            })
+            .transpose()
+    }
+
+    /// Records that `action` was executed, explaining why if it differs from its last run.
+    pub fn action_executed(&self, action: &ExplainedAction) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(reason) = Reason::between(state.history.get(&action.primary_output), action) {
+            writeln!(
+                state.out,
+                "Executing action '{}': {}",
+                action.description,
+                reason.describe(self.verbose)
+            )?;
+        }
+        state.history.insert(
+            action.primary_output.clone(),
+            ActionRecord {
+                command_line: action.command_line.clone(),
+                inputs: action.inputs.clone(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Flushes the explanation file, and saves the actions that ran for the next build to compare with.
+    pub fn finish(&self) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.out.flush()?;
+        if let Some(dir) = self.history_path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&self.history_path, serde_json::to_vec(&state.history)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn action(command: &str, inputs: &[(&str, &str)]) -> ExplainedAction {
+        ExplainedAction {
+            description: "Compiling foo.cc".to_string(),
+            primary_output: "bazel-out/k8-fastbuild/bin/foo.o".to_string(),
+            command_line: command.split(' ').map(String::from).collect(),
+            inputs: inputs
+                .iter()
+                .map(|(path, digest)| (path.to_string(), digest.to_string()))
+                .collect(),
+        }
+    }
+
+    fn build(dir: &Path, verbose: bool, actions: &[ExplainedAction]) -> String {
+        let path = dir.join("explain.log");
+        let explainer = Explainer::create(&path, verbose, dir.join("history.json")).unwrap();
+        for action in actions {
+            explainer.action_executed(action).unwrap();
+        }
+        explainer.finish().unwrap();
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn test_explain() {
+        let dir = assert_fs::TempDir::new().unwrap();
+        let first = action("gcc -c foo.cc", &[("foo.cc", "1"), ("foo.h", "1")]);
+        assert_eq!(
+            build(dir.path(), false, std::slice::from_ref(&first)),
+            "Executing action 'Compiling foo.cc': no entry in the cache (action is new).\n"
+        );
+        // Running an unchanged action again, e.g. because its outputs were deleted, needs no explanation.
+        assert_eq!(build(dir.path(), false, &[first]), "");
+
+        let edited = action("gcc -c foo.cc", &[("foo.cc", "2"), ("bar.h", "1")]);
+        assert_eq!(
+            build(dir.path(), false, std::slice::from_ref(&edited)),
+            "Executing action 'Compiling foo.cc': One of the files has changed.\n"
+        );
+        let optimized = action("gcc -O2 -c foo.cc", &[("foo.cc", "3"), ("foo.h", "1")]);
+        assert_eq!(
+            build(dir.path(), true, &[optimized]),
+            "Executing action 'Compiling foo.cc': action command has changed.\n  \
+             Old command: gcc -c foo.cc\n  New command: gcc -O2 -c foo.cc\n"
+        );
+        assert_eq!(
+            build(dir.path(), true, &[edited]),
+            "Executing action 'Compiling foo.cc': action command has changed.\n  \
+             Old command: gcc -O2 -c foo.cc\n  New command: gcc -c foo.cc\n"
+        );
+        let verbose = action("gcc -c foo.cc", &[("foo.cc", "4"), ("foo.h", "1")]);
+        assert_eq!(
+            build(dir.path(), true, &[verbose]),
+            "Executing action 'Compiling foo.cc': Digest of input file foo.cc changed.\n  \
+             Input file foo.h was added.\n  Input file bar.h was removed.\n"
+        );
+    }
+}
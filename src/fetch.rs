@@ -0,0 +1,40 @@
+use crate::bazel::label::{ApparentRepo, CanonicalRepo};
+use crate::error::ResolutionError;
+use crate::workspace::Workspace;
+use std::marker::Unpin;
+use std::sync::Arc;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Fetches each of `repos` without loading any of their targets, and prints the directory it is in.
+///
+/// A repository is named by its canonical name, `@@name`, or by the name the main repository sees it as, `@name` or
+/// just `name`.
+pub async fn fetch<W>(
+    out: &mut W,
+    workspace: Arc<Workspace>,
+    repos: &[String],
+) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let main_repo = workspace.main_repo().await?;
+    for repo in repos {
+        let canonical = match repo.strip_prefix("@@") {
+            Some(canonical) => CanonicalRepo::new(canonical.to_string()),
+            None => {
+                let apparent = ApparentRepo::new(repo.strip_prefix('@').unwrap_or(repo));
+                workspace
+                    .resolve_repo(&main_repo, &apparent)
+                    .ok_or_else(|| ResolutionError::RepositoryNotVisible {
+                        apparent: apparent.to_string(),
+                        from: "the main repository".to_string(),
+                    })?
+            }
+        };
+        workspace.repository(&canonical).await?;
+        let dir = workspace.repository_dir(&canonical).await?;
+        out.write_all(format!("Fetched {canonical} into {}\n", dir.display()).as_bytes())
+            .await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,358 @@
+//! `razel generate`: keeping BUILD files in sync with the code they describe, in the style of Gazelle.
+//!
+//! Generators are external programs, listed in `generators.json` in the workspace root:
+//!
+//! ```json
+//! { "generators": [{ "name": "go", "command": ["tools/gen_go_build", "--prefix=example.com/m"] }] }
+//! ```
+//!
+//! Each one is run in the workspace root and sent a `GenerateRequest` on stdin, describing every directory of the
+//! workspace with its files and current BUILD file. It answers on stdout with a `GenerateResponse` holding the new
+//! content of each BUILD file it wants to change. Generators run in the order listed, each seeing the edits of those
+//! before it.
+
+use crate::bazel::package::{BoxFileStore, DirEntry, File, FileStore};
+use crate::error::ExecutionError;
+use crate::workspace::Workspace;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::marker::Unpin;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The name of the generator configuration, in the workspace root.
+pub const GENERATORS_FILE: &str = "generators.json";
+
+/// The BUILD file names a generator may write, in the order Bazel looks for them.
+pub(crate) const BUILD_FILE_NAMES: &[&str] = &["BUILD.bazel", "BUILD"];
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GeneratorConfig {
+    name: String,
+    /// The program and its arguments. A relative program path containing a `/` is relative to the workspace root.
+    command: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GeneratorsFile {
+    generators: Vec<GeneratorConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct BuildFile {
+    name: String,
+    content: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct PackageInfo {
+    /// The directory, relative to the workspace root; empty for the root itself.
+    path: String,
+    /// The names of the files directly in the directory, sorted.
+    files: Vec<String>,
+    /// The BUILD file, if the directory has one.
+    build_file: Option<BuildFile>,
+}
+
+#[derive(Debug, Serialize)]
+struct GenerateRequest<'a> {
+    version: u32,
+    packages: &'a [PackageInfo],
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BuildFileEdit {
+    /// The BUILD file to write, relative to the workspace root.
+    path: String,
+    content: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GenerateResponse {
+    #[serde(default)]
+    edits: Vec<BuildFileEdit>,
+}
+
+/// Whether a directory is never part of the source tree, e.g. the output symlinks or version control metadata.
+fn ignored_dir(name: &str) -> bool {
+    name.starts_with("bazel-") || name.starts_with('.')
+}
+
+/// Lists every directory of the source tree with its files, taking `edits` to BUILD files into account.
+async fn list_packages(
+    files: &BoxFileStore<'static>,
+    edits: &BTreeMap<String, String>,
+) -> anyhow::Result<Vec<PackageInfo>> {
+    let mut packages = Vec::new();
+    let mut stack = vec![String::new()];
+    let join = |dir: &str, name: &str| {
+        if dir.is_empty() {
+            name.to_string()
+        } else {
+            format!("{dir}/{name}")
+        }
+    };
+
+    while let Some(dir) = stack.pop() {
+        let mut names = Vec::new();
+        for entry in files.read_dir(&dir).await? {
+            match entry {
+                DirEntry::File(name) => names.push(name),
+                DirEntry::Directory(name) if !ignored_dir(&name) => stack.push(join(&dir, &name)),
+                DirEntry::Directory(_) => {}
+            }
+        }
+        for name in BUILD_FILE_NAMES {
+            if edits.contains_key(&join(&dir, name)) && !names.iter().any(|n| n == name) {
+                names.push(name.to_string());
+            }
+        }
+        names.sort();
+
+        let mut build_file = None;
+        for name in BUILD_FILE_NAMES {
+            if !names.iter().any(|n| n == name) {
+                continue;
+            }
+            let path = join(&dir, name);
+            let content = match edits.get(&path) {
+                Some(content) => content.clone(),
+                None => {
+                    let file = files.read_file(&path).await?;
+                    let mut content = String::new();
+                    (*file).open().await?.read_to_string(&mut content).await?;
+                    content
+                }
+            };
+            build_file = Some(BuildFile {
+                name: name.to_string(),
+                content,
+            });
+            break;
+        }
+
+        packages.push(PackageInfo {
+            path: dir,
+            files: names,
+            build_file,
+        });
+    }
+
+    packages.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(packages)
+}
+
+/// Checks that a generator only writes BUILD files inside the workspace.
+fn validate_edit(generator: &str, path: &str) -> anyhow::Result<()> {
+    let p = Path::new(path);
+    let inside = p.is_relative()
+        && p.components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)));
+    let is_build_file = p
+        .file_name()
+        .is_some_and(|name| BUILD_FILE_NAMES.iter().any(|b| name == *b));
+    if !inside || !is_build_file {
+        anyhow::bail!(
+            "Generator {generator} tried to write {path:?}, which is not a BUILD file in the workspace"
+        );
+    }
+    Ok(())
+}
+
+/// Runs one generator on `packages`, returning its edits.
+async fn run_generator(
+    root: &Path,
+    generator: &GeneratorConfig,
+    packages: &[PackageInfo],
+) -> anyhow::Result<Vec<BuildFileEdit>> {
+    let Some((program, args)) = generator.command.split_first() else {
+        anyhow::bail!("Generator {} has an empty command", generator.name);
+    };
+    let program = if program.contains('/') {
+        root.join(program)
+    } else {
+        program.into()
+    };
+
+    let mut child = tokio::process::Command::new(&program)
+        .args(args)
+        .current_dir(root)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .map_err(|e| {
+            anyhow::anyhow!(
+                "Failed to start generator {} ({}): {e}",
+                generator.name,
+                program.display()
+            )
+        })?;
+
+    let request = serde_json::to_vec(&GenerateRequest {
+        version: 1,
+        packages,
+    })?;
+    let mut stdin = child.stdin.take().unwrap();
+    let write = async move {
+        stdin.write_all(&request).await?;
+        stdin.shutdown().await
+    };
+    let (written, output) = tokio::join!(write, child.wait_with_output());
+    let output = output?;
+    if !output.status.success() {
+        return Err(ExecutionError::GeneratorFailed {
+            name: generator.name.clone(),
+            status: output.status.to_string(),
+        }
+        .into());
+    }
+    // A generator may exit without reading its input, which is fine if it succeeded.
+    if let Err(e) = written
+        && e.kind() != std::io::ErrorKind::BrokenPipe
+    {
+        return Err(e.into());
+    }
+
+    let response: GenerateResponse = serde_json::from_slice(&output.stdout)
+        .map_err(|e| anyhow::anyhow!("Invalid response from generator {}: {e}", generator.name))?;
+    for edit in &response.edits {
+        validate_edit(&generator.name, &edit.path)?;
+    }
+    Ok(response.edits)
+}
+
+/// Runs the configured generators (or only those named in `only`) and writes the BUILD files they change.
+///
+/// With `check`, nothing is written, and the command fails if any BUILD file is out of date.
+pub async fn generate<W>(
+    out: &mut W,
+    workspace: Arc<Workspace>,
+    only: &[String],
+    check: bool,
+) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let root = workspace.path();
+    let config_path = root.join(GENERATORS_FILE);
+    let content = tokio::fs::read_to_string(&config_path)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to read {}: {e}", config_path.display()))?;
+    let generators: GeneratorsFile = serde_json::from_str(&content)
+        .map_err(|e| anyhow::anyhow!("Invalid {}: {e}", config_path.display()))?;
+    for name in only {
+        if !generators.generators.iter().any(|g| &g.name == name) {
+            anyhow::bail!("No generator named {name} in {}", config_path.display());
+        }
+    }
+
+    let main_repo = workspace.main_repo().await?;
+    let files = main_repo.files();
+    let original = list_packages(files, &BTreeMap::new()).await?;
+    let mut edits = BTreeMap::new();
+    for generator in &generators.generators {
+        if !only.is_empty() && !only.contains(&generator.name) {
+            continue;
+        }
+        let packages = list_packages(files, &edits).await?;
+        for edit in run_generator(root, generator, &packages).await? {
+            edits.insert(edit.path, edit.content);
+        }
+    }
+
+    let current = |path: &str| {
+        let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+        original
+            .iter()
+            .find(|p| p.path == dir)
+            .and_then(|p| p.build_file.as_ref())
+            .filter(|b| b.name == name)
+            .map(|b| b.content.as_str())
+    };
+    let changed: Vec<(&String, &String)> = edits
+        .iter()
+        .filter(|(path, content)| current(path) != Some(content.as_str()))
+        .collect();
+
+    for (path, content) in &changed {
+        if check {
+            out.write_all(format!("{path} is out of date\n").as_bytes())
+                .await?;
+            continue;
+        }
+        let dest = root.join(path);
+        if let Some(dir) = dest.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        tokio::fs::write(&dest, content).await?;
+        out.write_all(format!("Updated {path}\n").as_bytes())
+            .await?;
+    }
+
+    if check && !changed.is_empty() {
+        out.flush().await?;
+        anyhow::bail!(
+            "{} BUILD files are out of date, run `razel generate` to update them",
+            changed.len()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bazel::memory::InMemoryFileStore;
+    use crate::bazel::package::{DynFileStore, TypeErasingFileStore};
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn test_list_packages() {
+        let files: BoxFileStore<'static> = Arc::from(DynFileStore::new_box(Box::new(
+            TypeErasingFileStore(InMemoryFileStore::new(HashMap::from([
+                ("MODULE.bazel".to_string(), b"".to_vec()),
+                ("pkg/BUILD".to_string(), b"old".to_vec()),
+                ("pkg/main.go".to_string(), b"".to_vec()),
+                ("pkg/sub/lib.go".to_string(), b"".to_vec()),
+                ("bazel-out/gen.go".to_string(), b"".to_vec()),
+            ]))),
+        )));
+
+        let edits = BTreeMap::from([("pkg/sub/BUILD.bazel".to_string(), "new".to_string())]);
+        let packages = list_packages(&files, &edits).await.unwrap();
+        let summary: Vec<(&str, Vec<&str>, Option<&str>)> = packages
+            .iter()
+            .map(|p| {
+                (
+                    p.path.as_str(),
+                    p.files.iter().map(String::as_str).collect(),
+                    p.build_file.as_ref().map(|b| b.content.as_str()),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("", vec!["MODULE.bazel"], None),
+                ("pkg", vec!["BUILD", "main.go"], Some("old")),
+                ("pkg/sub", vec!["BUILD.bazel", "lib.go"], Some("new")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_edit() {
+        validate_edit("go", "pkg/BUILD.bazel").unwrap();
+        validate_edit("go", "BUILD").unwrap();
+        assert!(validate_edit("go", "pkg/main.go").is_err());
+        assert!(validate_edit("go", "../other/BUILD").is_err());
+        assert!(validate_edit("go", "/etc/BUILD").is_err());
+    }
+}
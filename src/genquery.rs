@@ -0,0 +1,258 @@
+//! The `genquery` rule, which runs a query as part of the build and writes the result to a file, for other rules to
+//! use.
+//!
+//! The query's universe is the rule's `scope`, which the rule depends on, so the result only changes when something
+//! the rule depends on does. Results outside of the transitive closure of `scope` fail the rule, or with
+//! `strict = False` are left out with a warning.
+
+use crate::analysis::Analysis;
+use crate::bazel::Configuration;
+use crate::bazel::label::{Label, Repo};
+use crate::bazel::rule::{AttrValue, Rule};
+use crate::console;
+use crate::error::AnalysisError;
+use crate::exit_code::{ExitCode, WithExitCode};
+use crate::explain::{ExplainedAction, Explainer};
+use crate::query::{self, Edges, OrderOutput, Output, Universe};
+use crate::workspace::Workspace;
+use clap::ValueEnum;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// What a genquery's `opts` set, which are flags of the query command.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Options {
+    edges: Edges,
+    order: OrderOutput,
+    output: Output,
+}
+
+fn parse_opts(opts: &[String]) -> Result<Options, String> {
+    let mut options = Options::default();
+    for opt in opts {
+        let (flag, value) = match opt.split_once('=') {
+            Some((flag, value)) => (flag, Some(value)),
+            None => (opt.as_str(), None),
+        };
+        match (flag, value) {
+            ("--output", Some(value)) => options.output = Output::from_str(value, false)?,
+            ("--order_output", Some(value)) => options.order = OrderOutput::from_str(value, false)?,
+            ("--implicit_deps" | "--noimplicit_deps", None) => {
+                options.edges.implicit = flag == "--implicit_deps";
+            }
+            ("--tool_deps" | "--notool_deps", None) => options.edges.tool = flag == "--tool_deps",
+            _ => return Err(format!("unsupported genquery option {opt}")),
+        }
+    }
+    Ok(options)
+}
+
+/// `label`, as written in an attribute of a rule in `package` of the repository `repo`, e.g. `@@`, in absolute form.
+pub(crate) fn absolute(repo: &str, package: &str, label: &str) -> String {
+    if label.starts_with('@') {
+        label.to_string()
+    } else if label.starts_with("//") {
+        format!("{repo}{label}")
+    } else {
+        let name = label.strip_prefix(':').unwrap_or(label);
+        format!("{repo}//{package}:{name}")
+    }
+}
+
+/// The result of the genquery `rule`, whose label is `label`.
+pub async fn evaluate(
+    workspace: Arc<Workspace>,
+    label: &Label<'_>,
+    rule: &Rule,
+) -> anyhow::Result<Vec<u8>> {
+    let failed = |reason: String| {
+        Err(AnalysisError::QueryFailed {
+            reason: format!("genquery {label}: {reason}"),
+        })
+        .exit_code(ExitCode::AnalysisFailure)
+    };
+    let Some(AttrValue::String(expression)) = rule.attributes.get("expression") else {
+        return failed("expression must be a string".to_string());
+    };
+    let repo = match &label.repo {
+        Repo::Canonical(repo) => repo.to_string(),
+        Repo::Apparent(_) => return failed("not in a canonical repository".to_string()),
+    };
+    let scope: Vec<_> = rule
+        .attributes
+        .get("scope")
+        .map_or(&[][..], AttrValue::strings)
+        .iter()
+        .map(|target| absolute(&repo, label.package(), target))
+        .collect();
+    if scope.is_empty() {
+        return failed("scope must name at least one target".to_string());
+    }
+    let opts = rule
+        .attributes
+        .get("opts")
+        .map_or(&[][..], AttrValue::strings);
+    let options = match parse_opts(opts) {
+        Ok(options) => options,
+        Err(e) => return failed(e),
+    };
+    let strict =
+        !matches!(rule.attributes.get("strict"), Some(AttrValue::Other(s)) if s == "False");
+
+    let universe = Universe::Patterns(scope.clone());
+    let quoted: Vec<_> = scope.iter().map(|target| format!("'{target}'")).collect();
+    let closure = format!("deps(set({}))", quoted.join(" "));
+    let mut outside = Vec::new();
+    query::query(
+        &mut outside,
+        workspace.clone(),
+        &format!("({expression}) except {closure}"),
+        universe.clone(),
+        options.edges,
+        OrderOutput::Auto,
+        Output::Label,
+    )
+    .await?;
+    let mut expression = expression.clone();
+    if !outside.is_empty() {
+        let outside = String::from_utf8_lossy(&outside);
+        let outside: Vec<_> = outside.lines().collect();
+        if strict {
+            return failed(format!(
+                "the result includes targets outside of the scope: {}",
+                outside.join(", ")
+            ));
+        }
+        console::eprintln(format_args!(
+            "WARNING: genquery {label}: leaving out targets outside of the scope: {}",
+            outside.join(", ")
+        ));
+        expression = format!("({expression}) intersect {closure}");
+    }
+
+    let mut result = Vec::new();
+    query::query(
+        &mut result,
+        workspace,
+        &expression,
+        universe,
+        options.edges,
+        options.order,
+        options.output,
+    )
+    .await?;
+    Ok(result)
+}
+
+/// What `--explain` compares from one run of the genquery `rule` to the next: the query it runs, as a command line of
+/// the query command. A genquery reads no files.
+fn explained_action(rule: &Rule, primary_output: String) -> ExplainedAction {
+    let strings = |attr| {
+        rule.attributes
+            .get(attr)
+            .map_or(&[][..], AttrValue::strings)
+    };
+    let expression = match rule.attributes.get("expression") {
+        Some(AttrValue::String(expression)) => expression.clone(),
+        _ => String::new(),
+    };
+    let command_line = ["query".to_string()]
+        .into_iter()
+        .chain(std::iter::once(format!(
+            "--universe_scope={}",
+            strings("scope").join(",")
+        )))
+        .chain(strings("opts").iter().cloned())
+        .chain(std::iter::once(expression))
+        .collect();
+    ExplainedAction {
+        description: format!("Writing file {primary_output}"),
+        primary_output,
+        command_line,
+        inputs: Default::default(),
+    }
+}
+
+/// A genquery that was built, and its output, relative to the execution root.
+pub struct Built {
+    pub label: Label<'static>,
+    pub output: PathBuf,
+}
+
+/// Builds the genqueries among `labels`, writing the result `analysis` worked out for each to its output in `bin_dir`
+/// under the execution root, and telling `explainer` about each. Returns the genqueries built, and the other labels,
+/// which razel can't build yet.
+pub async fn build(
+    workspace: &Arc<Workspace>,
+    config: &Configuration,
+    explainer: Option<&Explainer>,
+    analysis: &Analysis,
+    labels: Vec<Label<'static>>,
+) -> anyhow::Result<(Vec<Built>, Vec<Label<'static>>)> {
+    let execroot = workspace.output_base().join("execroot/_main");
+    let mut built = Vec::new();
+    let mut rest = Vec::new();
+    for label in labels {
+        let (Repo::Canonical(repo), Some(genquery)) = (&label.repo, analysis.genquery(&label))
+        else {
+            rest.push(label);
+            continue;
+        };
+        crate::metrics::METRICS.record_action_created();
+        crate::metrics::METRICS.record_action_executed(false);
+        let mut relative = PathBuf::from(config.bin_dir());
+        if !repo.as_str().is_empty() {
+            relative.push("external");
+            relative.push(repo.as_str());
+        }
+        relative.push(label.package());
+        relative.push(label.name());
+        if let Some(explainer) = explainer {
+            explainer.action_executed(&explained_action(
+                &genquery.rule,
+                relative.to_string_lossy().into_owned(),
+            ))?;
+        }
+        let path = execroot.join(&relative);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(&path, &genquery.result).await?;
+        built.push(Built {
+            label,
+            output: relative,
+        });
+    }
+    Ok((built, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_opts() {
+        let opts =
+            |opts: &[&str]| parse_opts(&opts.iter().map(|s| s.to_string()).collect::<Vec<_>>());
+        assert_eq!(opts(&[]).unwrap(), Options::default());
+        let options = opts(&[
+            "--output=label_kind",
+            "--order_output=full",
+            "--notool_deps",
+        ])
+        .unwrap();
+        assert_eq!(options.output, Output::LabelKind);
+        assert_eq!(options.order, OrderOutput::Full);
+        assert!(!options.edges.tool && options.edges.implicit);
+        assert!(opts(&["--output=nonesuch"]).is_err());
+        assert!(opts(&["--keep_going"]).is_err());
+    }
+
+    #[test]
+    fn test_absolute() {
+        assert_eq!(absolute("@@", "pkg", ":a"), "@@//pkg:a");
+        assert_eq!(absolute("@@", "pkg", "a"), "@@//pkg:a");
+        assert_eq!(absolute("@@dep+1.0", "pkg", "//lib:c"), "@@dep+1.0//lib:c");
+        assert_eq!(absolute("@@", "pkg", "@other//:x"), "@other//:x");
+    }
+}
@@ -0,0 +1,207 @@
+//! `--format=json`: the outcome of a command as a single JSON object on stdout instead of text, for scripts.
+//!
+//! The object is written whether the command succeeds or fails, so a script only has one place to look. Errors are
+//! included as `Diagnostic`s, with their codes; they are still printed to stderr, and the exit code is the same as
+//! without `--format=json`.
+
+use crate::bazel::Configuration;
+use crate::bazel::label::Label;
+use crate::bep::ExitCode;
+use crate::clock::Providers;
+use crate::error::Diagnostic;
+use serde::Serialize;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// The value of `--format`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// How far a target got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+// Tests don't run yet, so they are never reported as failed or passed.
+#[allow(dead_code)]
+pub enum TargetStatus {
+    Loaded,
+    Built,
+    Failed,
+    Passed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TargetResult {
+    pub label: String,
+    pub status: TargetStatus,
+    /// The files the target produced, relative to the execution root.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub outputs: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Timings {
+    pub wall_time_millis: u64,
+}
+
+/// What a command reports with `--format=json`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CommandResult {
+    pub command: String,
+    pub success: bool,
+    pub exit_code: ExitCode,
+    pub targets: Vec<TargetResult>,
+    pub output_base: String,
+    /// Where outputs are written, relative to the execution root.
+    pub output_directory: String,
+    pub testlogs_directory: String,
+    pub timings: Timings,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<Diagnostic>,
+}
+
+/// Collects the `CommandResult` of a command while it runs.
+#[derive(Debug)]
+pub struct Report {
+    result: CommandResult,
+    providers: Providers,
+    start: SystemTime,
+}
+
+impl Report {
+    /// Starts timing `command`, which runs with `config` in `output_base`.
+    pub fn new(
+        command: &str,
+        config: &Configuration,
+        output_base: &Path,
+        providers: Providers,
+    ) -> Self {
+        Self {
+            result: CommandResult {
+                command: command.to_string(),
+                success: false,
+                exit_code: ExitCode::INTERNAL_ERROR,
+                targets: Vec::new(),
+                output_base: output_base.to_string_lossy().into_owned(),
+                output_directory: config.bin_dir(),
+                testlogs_directory: config.testlogs_dir(),
+                timings: Timings {
+                    wall_time_millis: 0,
+                },
+                errors: Vec::new(),
+            },
+            start: providers.now(),
+            providers,
+        }
+    }
+
+    pub fn add_targets(&mut self, labels: &[Label<'_>], status: TargetStatus) {
+        self.result
+            .targets
+            .extend(labels.iter().map(|label| TargetResult {
+                label: label.to_string(),
+                status,
+                outputs: Vec::new(),
+            }));
+    }
+
+    /// Records that the target `label`, added before, was built, producing `outputs`, relative to the execution root.
+    pub fn target_built(&mut self, label: &Label<'_>, outputs: Vec<String>) {
+        let label = label.to_string();
+        if let Some(target) = self.result.targets.iter_mut().find(|t| t.label == label) {
+            target.status = TargetStatus::Built;
+            target.outputs = outputs;
+        }
+    }
+
+    /// Records how the command ended, and returns the JSON to write out.
+    pub fn finish<T>(&mut self, outcome: &anyhow::Result<T>) -> String {
+        let result = &mut self.result;
+        match outcome {
+            Ok(_) => {
+                result.success = true;
+                result.exit_code = ExitCode::SUCCESS;
+            }
+            Err(e) => {
+                let code = crate::exit_code::exit_code(e);
+                result.success = false;
+                result.exit_code = ExitCode {
+                    name: code.name(),
+                    code: code.code(),
+                };
+                result.errors.push(Diagnostic::new(e));
+            }
+        }
+        result.timings.wall_time_millis = self
+            .providers
+            .now()
+            .duration_since(self.start)
+            .map_or(0, |d| d.as_millis() as u64);
+        let mut json = serde_json::to_string_pretty(result).expect("results are serializable");
+        json.push('\n');
+        json
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{ManualClock, SeededRng};
+    use std::sync::Arc;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_report() {
+        let config = Configuration::from_flags(&<crate::Cli as clap::Parser>::parse_from([
+            "razel", "build",
+        ]));
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH));
+        let providers = Providers {
+            clock: clock.clone(),
+            rng: Arc::new(SeededRng::new(0)),
+        };
+        let mut report = Report::new("build", &config, Path::new("/out"), providers);
+        let label =
+            crate::bazel::label::parse_label("//pkg:t", &crate::bazel::label::MAIN_REPO_ROOT)
+                .unwrap();
+        let built =
+            crate::bazel::label::parse_label("//pkg:q", &crate::bazel::label::MAIN_REPO_ROOT)
+                .unwrap();
+        report.add_targets(&[label, built.clone()], TargetStatus::Loaded);
+        report.target_built(&built, vec!["bazel-out/k8-fastbuild/bin/pkg/q".to_string()]);
+        clock.advance(Duration::from_millis(42));
+
+        let outcome: anyhow::Result<()> =
+            Err(anyhow::Error::new(crate::error::FetchError::Unsupported {
+                repo: "rules_go+".to_string(),
+            }));
+        let json: serde_json::Value = serde_json::from_str(&report.finish(&outcome)).unwrap();
+        assert_eq!(json["command"], "build");
+        assert_eq!(json["success"], false);
+        assert_eq!(
+            json["exitCode"],
+            serde_json::json!({"name": "EXTERNAL_DEPS_ERROR", "code": 48})
+        );
+        assert_eq!(
+            json["targets"],
+            serde_json::json!([
+                {"label": "@@//pkg:t", "status": "LOADED"},
+                {
+                    "label": "@@//pkg:q",
+                    "status": "BUILT",
+                    "outputs": ["bazel-out/k8-fastbuild/bin/pkg/q"],
+                },
+            ])
+        );
+        assert_eq!(json["outputBase"], "/out");
+        assert_eq!(json["outputDirectory"], config.bin_dir());
+        assert_eq!(json["timings"]["wallTimeMillis"], 42);
+        assert_eq!(json["errors"][0]["code"], "RAZEL_FETCH_UNSUPPORTED");
+    }
+}
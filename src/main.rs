@@ -1,27 +1,116 @@
 use crate::bazel::Configuration;
-use clap::{Parser, Subcommand};
+use crate::exit_code::{ExitCode, WithExitCode};
+use crate::workspace::Workspace;
+use clap::{Args, Parser, Subcommand};
 use fastrace::collector::ConsoleReporter;
+use futures::FutureExt;
+use futures::future::BoxFuture;
 use std::sync::Arc;
-use tokio::io::AsyncWriteExt;
-use tracing_indicatif::IndicatifLayer;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+mod analysis;
+mod analyze_profile;
+mod aquery;
 mod bazel;
+mod bep;
+mod build_proto;
+mod canonicalize_flags;
+mod clock;
+mod compile_one_dependency;
+mod console;
+mod cquery;
+mod cycle;
+mod dump;
+mod error;
+mod exit_code;
+mod explain;
+mod fetch;
+mod generate;
+mod genquery;
+mod json_output;
+mod metrics;
+mod mod_command;
+mod output_lock;
+mod profile;
 mod query;
+mod repl;
+mod scheduler;
+mod server;
 mod shared_error;
 mod starlark;
 pub mod stream_tee;
+mod vendor;
+mod watch;
+mod watchdog;
 mod workspace;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = true)]
 #[command(rename_all = "snake_case")]
+#[command(args_override_self = true)]
 pub struct Cli {
     #[command(subcommand)]
     pub command: Commands,
 
-    /// Whether to ignore dev dependencies
+    /// Startup option: the directory all output of this workspace goes in [default: a directory named after the hash
+    /// of the workspace path, under --output_user_root]
+    #[arg(long, value_name = "PATH")]
+    pub output_base: Option<std::path::PathBuf>,
+
+    /// Startup option: the directory holding the output bases of all workspaces [default: ~/.cache/razel/_razel_$USER]
+    #[arg(long, value_name = "PATH")]
+    pub output_user_root: Option<std::path::PathBuf>,
+
+    /// Startup option: run the command in this process. With --batch=false, commands are sent to a long-lived server
+    /// for the output base, which keeps the loaded workspace in memory between commands
+    #[arg(
+        long,
+        require_equals = true,
+        default_value_t = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        value_name = "BOOL",
+        action = clap::ArgAction::Set
+    )]
+    pub batch: bool,
+
+    /// Startup option: how long the server waits for a command before it shuts itself down
+    #[arg(long, default_value_t = 3 * 60 * 60, value_name = "SECONDS")]
+    pub max_idle_secs: u64,
+
+    /// Startup option: when another command is running in the same output base, wait for it to finish. With
+    /// --noblock_for_lock, fail straight away instead
+    #[arg(
+        long,
+        require_equals = true,
+        default_value_t = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        value_name = "BOOL",
+        action = clap::ArgAction::Set
+    )]
+    pub block_for_lock: bool,
+
+    /// `background` for commands nobody is waiting on, such as an IDE's queries: with the server, they stop loading
+    /// packages while an `interactive` command runs
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t,
+        value_name = "PRIORITY"
+    )]
+    pub priority: scheduler::Priority,
+
+    /// Use the options of the `<command>:<NAME>` lines in .bazelrc files
+    #[arg(long, global = true, value_name = "NAME")]
+    pub config: Vec<String>,
+
+    /// Ignore the `dev_dependency = True` declarations of the root module's MODULE.bazel, as those of other modules
+    /// always are
     #[arg(
         long,
         global = true,
@@ -31,6 +120,236 @@ pub struct Cli {
         value_name = "BOOL"
     )]
     pub ignore_dev_dependency: bool,
+
+    /// Build mode: `fastbuild`, `opt` (optimized) or `dbg` (with debug info)
+    #[arg(
+        long,
+        short = 'c',
+        global = true,
+        value_enum,
+        default_value_t,
+        value_name = "MODE"
+    )]
+    pub compilation_mode: bazel::CompilationMode,
+
+    /// Output path scheme: `strip` leaves the configuration out of the output paths of actions that declare
+    /// `supports-path-mapping`, so they hit the same cache entries in every configuration. Only `off` is supported yet
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t,
+        value_name = "SCHEME"
+    )]
+    pub experimental_output_paths: bazel::OutputPaths,
+
+    /// What happens when evaluating a BUILD or .bzl file reads a file it neither loads nor contains: `enforce` fails,
+    /// `audit` only warns
+    #[arg(long, global = true, value_enum, default_value_t, value_name = "MODE")]
+    pub loading_sandbox: crate::starlark::sandbox::LoadingSandbox,
+
+    /// The most memory, in MiB, evaluating one BUILD, .bzl or MODULE.bazel file may use, or 0 for no limit
+    #[arg(long, global = true, default_value_t = 1024, value_name = "MB")]
+    pub max_starlark_heap_mb: usize,
+
+    /// The most statements evaluating one BUILD, .bzl or MODULE.bazel file may execute, or 0 for no limit
+    #[arg(long, global = true, default_value_t = 100_000_000, value_name = "N")]
+    pub max_starlark_steps: u64,
+
+    /// Continue as much as possible after an error, reporting all errors at the end
+    #[arg(
+        long,
+        short = 'k',
+        global = true,
+        require_equals = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        value_name = "BOOL"
+    )]
+    pub keep_going: bool,
+
+    /// After a query, print to stderr how many packages it loaded, how many targets it visited, how often it found a
+    /// package already loaded, and how long each query function took, to find out why a query is slow
+    #[arg(
+        long,
+        global = true,
+        require_equals = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        value_name = "BOOL"
+    )]
+    pub experimental_query_stats: bool,
+
+    /// Define a variable, `NAME=VALUE`, for `config_setting`s to match with `define_values`. May be repeated; the last
+    /// value of a name wins. Nothing matches `config_setting`s yet
+    #[arg(long, global = true, value_name = "NAME=VALUE", value_parser = bazel::parse_define)]
+    pub define: Vec<(String, String)>,
+
+    /// When a `bazel_dep` of the root module asks for a different version than the one selected from the whole
+    /// dependency graph: `off`, print a `warning`, or fail with an `error`
+    #[arg(long, global = true, value_enum, default_value_t, value_name = "MODE")]
+    pub check_direct_dependencies: bazel::mvs::CheckDirectDependencies,
+
+    /// Whether to `update` MODULE.bazel.lock with the versions the module graph resolves to, fail with an `error` when
+    /// they differ from the recorded ones, or leave the lockfile alone (`off`)
+    #[arg(long, global = true, value_enum, default_value_t, value_name = "MODE")]
+    pub lockfile_mode: bazel::lockfile::LockfileMode,
+
+    /// Only let a repository refer to the modules it declares with `bazel_dep`. With --nostrict_repo_visibility, an
+    /// undeclared name falls back to a module of that name anywhere in the dependency graph, with a warning, to help
+    /// migrate code that relies on transitive dependencies
+    #[arg(
+        long,
+        global = true,
+        require_equals = true,
+        default_value_t = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        value_name = "BOOL",
+        action = clap::ArgAction::Set
+    )]
+    pub strict_repo_visibility: bool,
+
+    /// Number of concurrent jobs: an integer, `auto`, or `HOST_CPUS*<factor>` [default: auto]
+    #[arg(long, short = 'j', global = true, value_name = "N", value_parser = bazel::parse_jobs)]
+    pub jobs: Option<usize>,
+
+    /// Directory containing vendored external repositories, relative to the workspace root
+    #[arg(long, global = true, value_name = "PATH")]
+    pub vendor_dir: Option<std::path::PathBuf>,
+
+    /// Write a log of every remote cache/execution gRPC call to this file. Not supported yet
+    #[arg(long, global = true, value_name = "PATH")]
+    pub experimental_remote_grpc_log: Option<std::path::PathBuf>,
+
+    /// How many times to rerun the actions whose outputs the remote cache evicted before they were used, and retry,
+    /// before failing the build. Not supported yet [default: 5]
+    #[arg(long, global = true, value_name = "N")]
+    pub experimental_remote_cache_eviction_retries: Option<u32>,
+
+    /// A registry to look up modules in, such as `file:///srv/registry` for an air-gapped setup. May be repeated;
+    /// registries are searched in order [default: https://bcr.bazel.build]
+    #[arg(long, global = true, value_name = "URL")]
+    pub registry: Vec<String>,
+
+    /// Module versions to use even though their registry yanked them, as `<module>@<version>`, or `all`. May be
+    /// repeated or comma-separated
+    #[arg(
+        long,
+        global = true,
+        value_delimiter = ',',
+        value_name = "MODULE@VERSION"
+    )]
+    pub allow_yanked_versions: Vec<String>,
+
+    /// Directory caching downloaded archives, addressed by their checksum
+    #[arg(long, global = true, value_name = "PATH")]
+    pub repository_cache: Option<std::path::PathBuf>,
+
+    /// A directory to look for archives in before downloading them, by the last segment of their URL. Only used for
+    /// downloads with a checksum. May be repeated; relative paths are relative to the workspace root
+    #[arg(long, global = true, value_name = "PATH")]
+    pub distdir: Vec<std::path::PathBuf>,
+
+    /// How many more times to try a download after a failure that may be transient, such as a timeout or a server error
+    #[arg(long, global = true, value_name = "N", default_value_t = 5)]
+    pub experimental_repository_downloader_retries: u32,
+
+    /// Refuse to download files whose checksum isn't given, so that every download is pinned
+    #[arg(
+        long,
+        global = true,
+        require_equals = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        value_name = "BOOL"
+    )]
+    pub experimental_repository_disable_download: bool,
+
+    /// Write Build Event Protocol events to this file, as newline-delimited JSON
+    #[arg(long, global = true, value_name = "PATH")]
+    pub build_event_json_file: Option<std::path::PathBuf>,
+
+    /// Write counters of actions, fetches, CAS traffic and phase durations to this file after the command, in the
+    /// Prometheus text format, e.g. for node_exporter's textfile collector
+    #[arg(long, global = true, value_name = "PATH")]
+    pub metrics_textfile: Option<std::path::PathBuf>,
+
+    /// Write a Chrome trace of the invocation to this file, for chrome://tracing or https://ui.perfetto.dev
+    #[arg(long, global = true, value_name = "PATH")]
+    pub profile: Option<std::path::PathBuf>,
+
+    /// Write why each action was executed, rather than reused from the last build, to this file
+    #[arg(long, global = true, value_name = "PATH")]
+    pub explain: Option<std::path::PathBuf>,
+
+    /// Say which inputs changed and how command lines did in the --explain file
+    #[arg(
+        long,
+        global = true,
+        require_equals = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        value_name = "BOOL"
+    )]
+    pub verbose_explanations: bool,
+
+    /// Instrument tests for coverage, and collect what each test covered into its testlogs directory. Not supported
+    /// yet
+    #[arg(
+        long,
+        global = true,
+        require_equals = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        value_name = "BOOL"
+    )]
+    pub collect_code_coverage: bool,
+
+    /// Show the progress of the command on stderr
+    #[arg(
+        long,
+        global = true,
+        require_equals = true,
+        default_value_t = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        value_name = "BOOL",
+        action = clap::ArgAction::Set
+    )]
+    pub show_progress: bool,
+
+    /// Redraw progress in place, rather than writing a line as each step starts: `yes`, `no`, or `auto` to do so if
+    /// stderr is a terminal
+    #[arg(long, global = true, value_enum, default_value_t, value_name = "WHEN")]
+    pub curses: console::When,
+
+    /// Use colors in the output: `yes`, `no`, or `auto` to do so if stderr is a terminal
+    #[arg(long, global = true, value_enum, default_value_t, value_name = "WHEN")]
+    pub color: console::When,
+
+    /// Write the outcome of `build` and `test` to stdout as `text`, or as a `json` object for scripts
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t,
+        value_name = "FORMAT"
+    )]
+    pub format: json_output::OutputFormat,
+
+    /// Retain caches meant for incremental use. Set to false on ephemeral CI runners to save memory and shutdown time
+    #[arg(
+        long,
+        global = true,
+        require_equals = true,
+        default_value_t = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        value_name = "BOOL",
+        action = clap::ArgAction::Set
+    )]
+    pub keep_state_after_build: bool,
 }
 
 #[derive(Subcommand)]
@@ -38,13 +357,409 @@ pub enum Commands {
     /// Prints version information
     Version,
     /// Builds the specified targets
-    Build { targets: Vec<String> },
+    Build {
+        #[command(flatten)]
+        targets: TargetPatternArgs,
+
+        /// Keep running after the build, and build again whenever a file in the workspace changes
+        #[arg(
+            long,
+            require_equals = true,
+            default_missing_value = "true",
+            num_args(0..=1),
+            value_name = "BOOL"
+        )]
+        watch: bool,
+
+        /// Run the actions that build the targets. With --nobuild, stop once the targets are analyzed, to check that
+        /// they and their dependencies are well-formed
+        #[arg(
+            long,
+            require_equals = true,
+            default_value_t = true,
+            default_missing_value = "true",
+            num_args(0..=1),
+            value_name = "BOOL",
+            action = clap::ArgAction::Set
+        )]
+        build: bool,
+
+        /// Analyze the targets. With --noanalyze, stop once they are loaded, which implies --nobuild
+        #[arg(
+            long,
+            require_equals = true,
+            default_value_t = true,
+            default_missing_value = "true",
+            num_args(0..=1),
+            value_name = "BOOL",
+            action = clap::ArgAction::Set
+        )]
+        analyze: bool,
+    },
     /// Tests the specified targets
-    Test { targets: Vec<String> },
-    /// Runs the specified target
+    Test {
+        #[command(flatten)]
+        targets: TargetPatternArgs,
+    },
+    /// Tests the specified targets with coverage instrumentation, and merges what they covered into an lcov report.
+    /// Not supported yet: the targets are loaded, but no tests are run
+    Coverage {
+        #[command(flatten)]
+        targets: TargetPatternArgs,
+    },
+    /// Not supported yet: nothing is built to run
     Run { target: String },
     /// Queries for information about the build graph
-    Query { query: String },
+    #[command(rename_all = "snake_case")]
+    Query {
+        #[arg(required_unless_present = "query_file")]
+        query: Option<String>,
+
+        /// Read the query expression from this file, or from stdin if it's `-`, instead of the command line, for
+        /// expressions too long for the shell
+        #[arg(long, value_name = "FILE", conflicts_with = "query")]
+        query_file: Option<std::path::PathBuf>,
+
+        /// Comma-separated target patterns whose transitive closure functions without an explicit universe, such as
+        /// `allrdeps`, search; a pattern prefixed with `-` removes the targets it matches [default: //...]
+        #[arg(long, value_delimiter = ',', value_name = "PATTERNS")]
+        universe_scope: Vec<String>,
+
+        /// Without --universe_scope, take the universe from the target patterns in the query expression, rather than
+        /// loading the whole workspace
+        #[arg(
+            long,
+            require_equals = true,
+            default_missing_value = "true",
+            num_args(0..=1),
+            value_name = "BOOL"
+        )]
+        infer_universe_scope: bool,
+
+        /// Follow the dependencies a rule sets itself, in private attributes, rather than only those written where
+        /// it's declared
+        #[arg(
+            long,
+            require_equals = true,
+            default_value_t = true,
+            default_missing_value = "true",
+            num_args(0..=1),
+            value_name = "BOOL",
+            action = clap::ArgAction::Set
+        )]
+        implicit_deps: bool,
+
+        /// Follow dependencies on tools built for the execution platform, such as a genrule's `tools`
+        #[arg(
+            long,
+            require_equals = true,
+            default_value_t = true,
+            default_missing_value = "true",
+            num_args(0..=1),
+            value_name = "BOOL",
+            action = clap::ArgAction::Set
+        )]
+        tool_deps: bool,
+
+        /// The order to print the results in: as they're found (`no`), each target before its dependencies and
+        /// otherwise as found (`deps`), sorted by label (`auto`), or each target before its dependencies and otherwise by
+        /// label (`full`). Only `no` prints results before the whole query has been evaluated
+        #[arg(long, value_enum, default_value_t, value_name = "ORDER")]
+        order_output: query::OrderOutput,
+
+        /// How to print the results: a `label` per line, a GraphViz DOT `graph` of them and the dependencies between
+        /// them, e.g. to pipe into `dot -Tsvg`, Bazel's build.proto `Target` messages, as a binary `proto` or one
+        /// JSON object per line with `streamed_jsonproto`, the same targets as `xml`, the rules as `build` file
+        /// declarations after macro expansion, each label with its kind (`label_kind`), or with its kind and `location`
+        #[arg(long, value_enum, default_value_t, value_name = "FORMAT")]
+        output: query::Output,
+        /// Debugging aid: evaluate the query twice, the second time from scratch so every hash map iterates in a
+        /// different order, and fail if the output differs, to find nondeterminism before it shows up as cache misses
+        /// between machines
+        #[arg(
+            long,
+            require_equals = true,
+            default_missing_value = "true",
+            num_args(0..=1),
+            value_name = "BOOL"
+        )]
+        experimental_check_determinism: bool,
+    },
+    /// Queries for the targets a query matches as configured for the target platform, such as the toolchains they
+    /// resolve
+    #[command(rename_all = "snake_case")]
+    Cquery {
+        query: String,
+
+        /// Comma-separated target patterns to evaluate the query in [default: //...]
+        #[arg(long, value_delimiter = ',', value_name = "PATTERNS")]
+        universe_scope: Vec<String>,
+
+        /// The platform to build for, rather than the one --cpu describes
+        #[arg(long, value_name = "LABEL")]
+        platforms: Option<String>,
+
+        /// How to print the results: each `label` with its configuration, with the execution platform and
+        /// `toolchains` resolved for it, or the value of --starlark:expr for it (`starlark`)
+        #[arg(long, value_enum, default_value_t, value_name = "FORMAT")]
+        output: cquery::Output,
+
+        /// With --output=starlark, the Starlark expression to print for each target, which has the `label`, `kind`,
+        /// `configuration` and the rule's attributes in `attr`, e.g. `target.attr.srcs`
+        #[arg(
+            long = "starlark:expr",
+            value_name = "EXPR",
+            default_value = "str(target.label)"
+        )]
+        starlark_expr: String,
+    },
+    /// Prints the actions that build the targets a query matches, which `mnemonic(PATTERN, expr)`, `inputs(PATTERN,
+    /// expr)` and `outputs(PATTERN, expr)` filter
+    #[command(rename_all = "snake_case")]
+    Aquery {
+        query: String,
+
+        /// Comma-separated target patterns to evaluate the query in [default: //...]
+        #[arg(long, value_delimiter = ',', value_name = "PATTERNS")]
+        universe_scope: Vec<String>,
+
+        /// How to print the actions: as `text`, as the value of --starlark:expr for each (`starlark`), or as a graph
+        /// of the actions and the artifacts between them, in DOT (`graph`) or JSON (`json`)
+        #[arg(long, value_enum, default_value_t, value_name = "FORMAT")]
+        output: aquery::Output,
+
+        /// With --output=starlark, the Starlark expression to print for each action, which has the `mnemonic`, `owner`,
+        /// `configuration`, `inputs`, `outputs` and `arguments`, e.g. `' '.join(action.outputs)`
+        #[arg(
+            long = "starlark:expr",
+            value_name = "EXPR",
+            default_value = "action.mnemonic + ' ' + action.owner"
+        )]
+        starlark_expr: String,
+    },
+    /// Copies all external repositories into the directory given by --vendor_dir
+    Vendor,
+    /// Fetches external repositories without building or loading any of their targets, printing where each one is
+    Fetch {
+        /// A repository to fetch, by its apparent name from the main repository (`@name`) or its canonical name
+        /// (`@@name`)
+        #[arg(long = "repo", required = true, value_name = "REPO")]
+        repos: Vec<String>,
+    },
+    /// Runs the BUILD file generators configured in generators.json and applies their edits
+    Generate {
+        /// Only run the generators with these names
+        generators: Vec<String>,
+
+        /// Don't write anything, and fail if any BUILD file is out of date
+        #[arg(
+            long,
+            require_equals = true,
+            default_missing_value = "true",
+            num_args(0..=1),
+            value_name = "BOOL"
+        )]
+        check: bool,
+    },
+    /// Inspects the external dependency graph
+    Mod {
+        #[command(subcommand)]
+        command: ModCommands,
+    },
+    /// Evaluates Starlark interactively, with the BUILD and .bzl globals and commands to load workspace files
+    Repl,
+    /// Stops the server for this workspace's output base, if one is running
+    Shutdown,
+    /// Runs the server that commands are sent to with --batch=false
+    #[command(hide = true)]
+    Server,
+    /// Prints a completion script for the given shell, e.g. `source <(razel completions bash)`
+    Completions { shell: clap_complete::Shell },
+    /// Prints the options a command runs with in canonical form, one per line, including those from .bazelrc files
+    CanonicalizeFlags {
+        /// The command the options are for
+        #[arg(long = "for_command", default_value = "build", value_name = "COMMAND")]
+        for_command: String,
+        /// The options, after `--`, e.g. `razel canonicalize-flags -- --config=ci -c opt`
+        #[arg(last = true, value_name = "OPTIONS")]
+        flags: Vec<String>,
+    },
+    /// Dumps the workspace state held in memory, e.g. by the server, for debugging
+    Dump {
+        #[command(flatten)]
+        sections: DumpArgs,
+    },
+    /// Summarizes a profile written by --profile: the time taken by each phase, the critical path and the slowest
+    /// actions
+    AnalyzeProfile {
+        #[arg(value_name = "PROFILE")]
+        path: std::path::PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+#[command(rename_all = "snake_case")]
+pub enum ModCommands {
+    /// Shows the tree of modules the main module depends on
+    Graph {
+        /// Also say how the repository of each module was fetched, or why fetching it failed
+        #[arg(
+            long,
+            require_equals = true,
+            default_missing_value = "true",
+            num_args(0..=1),
+            value_name = "BOOL"
+        )]
+        verbose: bool,
+
+        /// Instead of the tree, list the cycles in the module graph, which bzlmod allows but which make it hard to
+        /// tell which version of a module wins
+        #[arg(
+            long,
+            require_equals = true,
+            default_missing_value = "true",
+            num_args(0..=1),
+            value_name = "BOOL"
+        )]
+        cycles: bool,
+    },
+    /// Shows every path from the root module to the given modules
+    AllPaths {
+        /// Module names, e.g. `rules_go`
+        #[arg(required = true)]
+        modules: Vec<String>,
+    },
+    /// Shows which modules depend on the given modules, and the version each asks for, marking those that asked for
+    /// the version selected
+    Explain {
+        /// Module names, e.g. `rules_go`
+        #[arg(required = true)]
+        modules: Vec<String>,
+    },
+    /// Shows the repositories generated by module extensions, and the downloads pinned for them, as recorded in the
+    /// lockfile by Bazel. razel doesn't evaluate module extensions, so it doesn't record them itself
+    ShowExtension {
+        /// Extensions, as `<bzl file label>%<extension name>`
+        #[arg(required = true)]
+        extensions: Vec<String>,
+    },
+}
+
+#[derive(Args)]
+#[command(rename_all = "snake_case")]
+pub struct DumpArgs {
+    /// The packages evaluated so far, and their targets
+    #[arg(
+        long,
+        require_equals = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        value_name = "BOOL"
+    )]
+    pub packages: bool,
+
+    /// How each repository evaluated so far maps the repository names it uses to canonical ones
+    #[arg(
+        long,
+        require_equals = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        value_name = "BOOL"
+    )]
+    pub repo_mappings: bool,
+
+    /// The actions of the targets analyzed so far
+    #[arg(
+        long,
+        require_equals = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        value_name = "BOOL"
+    )]
+    pub action_graph: bool,
+
+    /// Every memoized node, i.e. repository, .bzl file and package, with its state and its edges
+    #[arg(
+        long,
+        require_equals = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        value_name = "BOOL"
+    )]
+    pub skyframe: bool,
+}
+
+/// `labels` for the progress messages of build and test, separated by spaces.
+fn label_list(labels: &[bazel::label::Label<'_>]) -> String {
+    labels
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[derive(Args)]
+#[command(rename_all = "snake_case")]
+pub struct TargetPatternArgs {
+    /// Target patterns. A pattern prefixed with `-` removes the targets it matches from those before it, and must come
+    /// after `--`
+    pub targets: Vec<String>,
+
+    /// Read target patterns from this file, one per line, instead of from the command line
+    #[arg(
+        long = "target_pattern_file",
+        value_name = "PATH",
+        conflicts_with = "targets"
+    )]
+    pub target_pattern_file: Option<std::path::PathBuf>,
+
+    /// Take source files, by path or label, instead of target patterns, and build one target that depends on each,
+    /// preferring a rule of the file's own package that lists it in `srcs`
+    #[arg(
+        long,
+        require_equals = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        value_name = "BOOL"
+    )]
+    pub compile_one_dependency: bool,
+}
+
+impl TargetPatternArgs {
+    /// The target patterns given on the command line or via `--target_pattern_file`.
+    pub async fn patterns(&self) -> anyhow::Result<Vec<String>> {
+        let Some(path) = &self.target_pattern_file else {
+            return Ok(self.targets.clone());
+        };
+        let content = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| {
+                anyhow::anyhow!(
+                    "Failed to read --target_pattern_file {}: {e}",
+                    path.display()
+                )
+            })
+            .exit_code(ExitCode::CommandLineError)?;
+        Ok(content
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(String::from)
+            .collect())
+    }
+
+    /// The targets to build or test: those the target patterns match, or with `--compile_one_dependency`, a target
+    /// for each source file given.
+    pub async fn labels(
+        &self,
+        workspace: &Arc<workspace::Workspace>,
+    ) -> anyhow::Result<Vec<bazel::label::Label<'static>>> {
+        let mut patterns = self.patterns().await?;
+        if self.compile_one_dependency {
+            patterns = compile_one_dependency::targets(workspace, &patterns).await?;
+        }
+        workspace.expand_target_patterns(&patterns).await
+    }
 }
 
 #[test]
@@ -53,47 +768,704 @@ fn verify_cli() {
     Cli::command().debug_assert();
 }
 
+/// Returns the command line, with the options from .bazelrc files added.
+fn expand_command_line() -> anyhow::Result<Vec<String>> {
+    use clap::CommandFactory;
+
+    let cli = Cli::command();
+    let args = bazel::rc::expand_negations(&cli, std::env::args().collect());
+    let matches = cli.clone().get_matches_from(&args);
+    let Some(command) = matches.subcommand_name() else {
+        return Ok(args);
+    };
+    let root = bazel::rc::find_workspace_root(std::path::Path::new("."));
+    let rc = bazel::rc::RcOptions::load_default(root.as_deref())?;
+    // rc files may turn options off the same way.
+    let args = bazel::rc::expand_command_line(&rc, command, &args)?;
+    Ok(bazel::rc::expand_negations(&cli, args))
+}
+
+/// Fails on flags that are accepted for compatibility with Bazel but that nothing acts on yet, rather than ignoring them.
+fn reject_unimplemented_flags(cli: &Cli) -> anyhow::Result<()> {
+    if cli.experimental_remote_grpc_log.is_some() {
+        return Err(anyhow::anyhow!(
+            "--experimental_remote_grpc_log is not supported yet: there is no remote cache or executor to log calls to"
+        ))
+        .exit_code(ExitCode::CommandLineError);
+    }
+    if cli.experimental_remote_cache_eviction_retries.is_some() {
+        return Err(anyhow::anyhow!(
+            "--experimental_remote_cache_eviction_retries is not supported yet: there is no remote cache to evict blobs"
+        ))
+        .exit_code(ExitCode::CommandLineError);
+    }
+    if cli.experimental_output_paths == bazel::OutputPaths::Strip {
+        return Err(anyhow::anyhow!(
+            "--experimental_output_paths=strip is not supported yet: no actions are spawned to map paths for"
+        ))
+        .exit_code(ExitCode::CommandLineError);
+    }
+    Ok(())
+}
+
+/// Provides the workspace a command runs in: a new one for a command run in-process, or one kept by the server.
+type WorkspaceSource = dyn Fn() -> BoxFuture<'static, anyhow::Result<Arc<Workspace>>> + Send + Sync;
+
+/// Runs a command, other than the ones managing the server, writing its output to `out`.
+async fn run_command<W>(
+    cli: &Cli,
+    config: Arc<Configuration>,
+    providers: &clock::Providers,
+    open_workspace: &WorkspaceSource,
+    out: &mut W,
+) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    reject_unimplemented_flags(cli)?;
+    match &cli.command {
+        Commands::Version => {
+            // The version is automatically handled by clap if --version is passed.
+            // This explicit subcommand can be used if `razel version` is preferred.
+            out.write_all(format!("Razel version: {}\n", env!("CARGO_PKG_VERSION")).as_bytes())
+                .await?;
+        }
+        Commands::Build {
+            targets,
+            watch,
+            build,
+            analyze,
+        } => {
+            if *watch && cli.format == json_output::OutputFormat::Json {
+                return Err(anyhow::anyhow!(
+                    "--format=json reports a single build, so it can't be used with --watch"
+                ))
+                .exit_code(ExitCode::CommandLineError);
+            }
+            let bep = bep::BuildEventStream::from_config(&config, "build", providers)?;
+            // A failure from here on ends the stream with its own exit code, rather than as an internal error.
+            let result = async {
+                let mut workspace = open_workspace().await?;
+                prepare_workspace(&workspace, &cli.command).await?;
+                let mut report = (cli.format == json_output::OutputFormat::Json).then(|| {
+                    json_output::Report::new(
+                        "build",
+                        &config,
+                        workspace.output_base(),
+                        providers.clone(),
+                    )
+                });
+                let explainer = explain::Explainer::from_config(&config, workspace.output_base())?;
+                let mut watcher = watch
+                    .then(|| watch::FileWatcher::new(workspace.path()))
+                    .transpose()?;
+                loop {
+                    let result = async {
+                        let loading = std::time::Instant::now();
+                        let labels = targets.labels(&workspace).await?;
+                        metrics::METRICS.record_phase("loading", loading.elapsed());
+                        workspace.release_loading_state();
+                        if let Some(bep) = &bep {
+                            let kinds = query::target_kinds(workspace.clone(), &labels).await?;
+                            bep.targets_requested(&labels, &kinds)?;
+                        }
+                        if let Some(report) = &mut report {
+                            report.add_targets(&labels, json_output::TargetStatus::Loaded);
+                        } else {
+                            let text = format!(
+                                "Building targets: {}\nOutput directory: {}\nOutput base: {}\n",
+                                label_list(&labels),
+                                config.bin_dir(),
+                                workspace.output_base().display()
+                            );
+                            out.write_all(text.as_bytes()).await?;
+                            out.flush().await?;
+                        }
+                        workspace.check_deferred_errors()?;
+                        let analyzed = if *analyze {
+                            let analysis = std::time::Instant::now();
+                            let analyzed = analysis::analyze(&workspace, &labels).await?;
+                            metrics::METRICS.record_phase("analysis", analysis.elapsed());
+                            Some(analyzed)
+                        } else {
+                            None
+                        };
+                        if let Some(analyzed) = analyzed.filter(|_| *build) {
+                            let (built, rest) = genquery::build(
+                                &workspace,
+                                &config,
+                                explainer.as_ref(),
+                                &analyzed,
+                                labels.clone(),
+                            )
+                            .await?;
+                            let execroot = workspace.output_base().join("execroot/_main");
+                            for genquery::Built { label, output } in built {
+                                if let Some(bep) = &bep {
+                                    bep.target_completed(&label.to_string(), true)?;
+                                }
+                                if let Some(report) = &mut report {
+                                    let output = output.to_string_lossy().into_owned();
+                                    report.target_built(&label, vec![output]);
+                                } else {
+                                    let text = format!(
+                                        "Target {label} up-to-date:\n  {}\n",
+                                        execroot.join(output).display()
+                                    );
+                                    out.write_all(text.as_bytes()).await?;
+                                }
+                            }
+                            if !rest.is_empty() {
+                                return Err(anyhow::anyhow!(
+                                    "razel build is not supported yet for {}: only genquery targets are built",
+                                    label_list(&rest)
+                                ))
+                                .exit_code(ExitCode::CommandLineError);
+                            }
+                        } else {
+                            let (phase, flag, reason) = if *analyze {
+                                ("Analysis", "--nobuild", bep::AbortReason::NoBuild)
+                            } else {
+                                ("Loading", "--noanalyze", bep::AbortReason::NoAnalyze)
+                            };
+                            if let Some(bep) = &bep {
+                                bep.skip_pending(reason)?;
+                            }
+                            if report.is_none() {
+                                let text = format!(
+                                    "{phase} succeeded for {} targets, not building them because of {flag}\n",
+                                    labels.len()
+                                );
+                                out.write_all(text.as_bytes()).await?;
+                            }
+                        }
+                        anyhow::Ok(labels)
+                    }
+                    .await;
+
+                    if let Some(explainer) = &explainer {
+                        explainer.finish()?;
+                    }
+                    let Some(watcher) = &mut watcher else {
+                        // A successful build is reported once its targets are built.
+                        if let (Err(_), Some(report)) = (&result, &mut report) {
+                            out.write_all(report.finish(&result).as_bytes()).await?;
+                            out.flush().await?;
+                        }
+                        result?;
+                        if let Some(report) = &mut report {
+                            out.write_all(report.finish(&anyhow::Ok(())).as_bytes())
+                                .await?;
+                            out.flush().await?;
+                        }
+                        if let Some(bep) = &bep {
+                            bep.finish(bep::ExitCode::SUCCESS)?;
+                        }
+                        break;
+                    };
+                    // A broken build is reported, and then fixed by the next change.
+                    if let Err(e) = result {
+                        eprintln!("Error: {e:?}");
+                    }
+                    out.write_all(b"Watching for changes...\n").await?;
+                    out.flush().await?;
+                    let changes = watcher.changes().await?;
+                    let text = if changes.resets_workspace() {
+                        workspace = open_workspace().await?;
+                        "Module files changed, reloading the workspace\n".to_string()
+                    } else {
+                        let invalidated = workspace.invalidate_bzl_files(&changes.bzl_files());
+                        format!(
+                            "{} files changed, {invalidated} .bzl files to reload\n",
+                            changes.paths.len()
+                        )
+                    };
+                    out.write_all(text.as_bytes()).await?;
+                }
+                anyhow::Ok(())
+            }
+            .await;
+            if let (Err(e), Some(bep)) = (&result, &bep) {
+                bep.abort(e)?;
+            }
+            result?;
+        }
+        Commands::Test { targets } | Commands::Coverage { targets } => {
+            let command = match &cli.command {
+                Commands::Coverage { .. } => "coverage",
+                _ => "test",
+            };
+            let bep = bep::BuildEventStream::from_config(&config, command, providers)?;
+            let result = async {
+                let workspace = open_workspace().await?;
+                prepare_workspace(&workspace, &cli.command).await?;
+                let mut report = (cli.format == json_output::OutputFormat::Json).then(|| {
+                    json_output::Report::new(
+                        command,
+                        &config,
+                        workspace.output_base(),
+                        providers.clone(),
+                    )
+                });
+                let explainer = explain::Explainer::from_config(&config, workspace.output_base())?;
+                let result = async {
+                    let loading = std::time::Instant::now();
+                    let labels = targets.labels(&workspace).await?;
+                    metrics::METRICS.record_phase("loading", loading.elapsed());
+                    workspace.release_loading_state();
+                    if let Some(bep) = &bep {
+                        let kinds = query::target_kinds(workspace.clone(), &labels).await?;
+                        bep.targets_requested(&labels, &kinds)?;
+                    }
+                    if let Some(report) = &mut report {
+                        report.add_targets(&labels, json_output::TargetStatus::Loaded);
+                    } else {
+                        out.write_all(
+                            format!("Testing targets: {}\n", label_list(&labels)).as_bytes(),
+                        )
+                        .await?;
+                        out.flush().await?;
+                    }
+                    workspace.check_deferred_errors()?;
+                    anyhow::Ok(labels)
+                }
+                .await;
+                let result: anyhow::Result<()> = result.and_then(|labels| {
+                    if labels.is_empty() {
+                        return Err(anyhow::anyhow!(
+                            "No test targets were found, yet testing was requested"
+                        ))
+                        .exit_code(ExitCode::NoTestsFound);
+                    }
+                    if let Some(explainer) = &explainer {
+                        explainer.finish()?;
+                    }
+                    // Loading succeeded, but nothing runs tests yet, so there are no results (or coverage) to report.
+                    Err(anyhow::anyhow!(
+                        "razel {command} is not supported yet: tests are loaded, but not run"
+                    ))
+                    .exit_code(ExitCode::CommandLineError)
+                });
+                if let Some(report) = &mut report {
+                    out.write_all(report.finish(&result).as_bytes()).await?;
+                    out.flush().await?;
+                }
+                result
+            }
+            .await;
+            if let (Err(e), Some(bep)) = (&result, &bep) {
+                bep.abort(e)?;
+            }
+            result?;
+        }
+        Commands::Run { target } => {
+            return Err(anyhow::anyhow!(
+                "razel run is not supported yet: {target} can't be built to run it"
+            ))
+            .exit_code(ExitCode::CommandLineError);
+        }
+        Commands::AnalyzeProfile { path } => {
+            let trace = tokio::fs::read_to_string(path)
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to read profile {}: {e}", path.display()))?;
+            out.write_all(analyze_profile::analyze(&trace)?.as_bytes())
+                .await?;
+        }
+        Commands::Completions { shell } => {
+            use clap::CommandFactory;
+            let mut script = Vec::new();
+            clap_complete::generate(*shell, &mut Cli::command(), "razel", &mut script);
+            out.write_all(&script).await?;
+        }
+        Commands::Query {
+            query: query_str,
+            query_file,
+            universe_scope,
+            infer_universe_scope,
+            implicit_deps,
+            tool_deps,
+            order_output,
+            output,
+            experimental_check_determinism,
+        } => {
+            let query_str = match query_file {
+                Some(path) => read_query_file(path)
+                    .await
+                    .exit_code(ExitCode::CommandLineError)?,
+                None => query_str
+                    .clone()
+                    .expect("clap requires a query or --query_file"),
+            };
+            let universe = query::Universe::new(universe_scope, *infer_universe_scope);
+            let edges = query::Edges {
+                implicit: *implicit_deps,
+                tool: *tool_deps,
+            };
+            let workspace = open_workspace().await?;
+            prepare_workspace(&workspace, &cli.command).await?;
+            let start = std::time::Instant::now();
+            let stats = if *experimental_check_determinism {
+                let mut first = Vec::new();
+                let stats = query::query(
+                    &mut first,
+                    workspace.clone(),
+                    &query_str,
+                    universe.clone(),
+                    edges,
+                    *order_output,
+                    *output,
+                )
+                .await?;
+                let fresh =
+                    Workspace::new(workspace.path(), config.clone(), providers.clone()).await?;
+                let mut second = Vec::new();
+                query::query(
+                    &mut second,
+                    fresh,
+                    &query_str,
+                    universe,
+                    edges,
+                    *order_output,
+                    *output,
+                )
+                .await?;
+                query::check_deterministic(&first, &second)?;
+                out.write_all(&first).await?;
+                stats
+            } else {
+                query::query(
+                    out,
+                    workspace,
+                    &query_str,
+                    universe,
+                    edges,
+                    *order_output,
+                    *output,
+                )
+                .await?
+            };
+            if config.query_stats {
+                console::eprintln(stats.report().trim_end());
+            }
+            metrics::METRICS.record_phase("query", start.elapsed());
+        }
+        Commands::Cquery {
+            query: query_str,
+            universe_scope,
+            platforms,
+            output,
+            starlark_expr,
+        } => {
+            let workspace = open_workspace().await?;
+            prepare_workspace(&workspace, &cli.command).await?;
+            let start = std::time::Instant::now();
+            cquery::cquery(
+                out,
+                workspace,
+                &config,
+                query_str,
+                query::Universe::new(universe_scope, false),
+                platforms.as_deref(),
+                *output,
+                starlark_expr,
+            )
+            .await?;
+            metrics::METRICS.record_phase("query", start.elapsed());
+        }
+        Commands::Aquery {
+            query: query_str,
+            universe_scope,
+            output,
+            starlark_expr,
+        } => {
+            let workspace = open_workspace().await?;
+            prepare_workspace(&workspace, &cli.command).await?;
+            let start = std::time::Instant::now();
+            aquery::aquery(
+                out,
+                workspace,
+                &config,
+                query_str,
+                query::Universe::new(universe_scope, false),
+                *output,
+                starlark_expr,
+            )
+            .await?;
+            metrics::METRICS.record_phase("query", start.elapsed());
+        }
+        Commands::Vendor => {
+            vendor::vendor(out, open_workspace().await?).await?;
+        }
+        Commands::Fetch { repos } => {
+            fetch::fetch(out, open_workspace().await?, repos).await?;
+        }
+        Commands::Generate { generators, check } => {
+            generate::generate(out, open_workspace().await?, generators, *check).await?;
+        }
+        Commands::Dump { sections } => {
+            if !(sections.packages
+                || sections.repo_mappings
+                || sections.action_graph
+                || sections.skyframe)
+            {
+                return Err(anyhow::anyhow!(
+                    "Nothing to dump, pass one or more of --packages, --repo_mappings, --action_graph or --skyframe"
+                ))
+                .exit_code(ExitCode::CommandLineError);
+            }
+            let text = dump::dump(&*open_workspace().await?, sections);
+            out.write_all(text.as_bytes()).await?;
+        }
+        Commands::Mod {
+            command: ModCommands::Graph { verbose, cycles },
+        } => {
+            let workspace = open_workspace().await?;
+            prepare_workspace(&workspace, &cli.command).await?;
+            if *cycles {
+                mod_command::cycles(out, workspace).await?;
+            } else {
+                mod_command::graph(out, workspace, *verbose).await?;
+            }
+        }
+        Commands::Mod {
+            command: ModCommands::AllPaths { modules },
+        } => {
+            mod_command::all_paths(out, open_workspace().await?, modules).await?;
+        }
+        Commands::Mod {
+            command: ModCommands::Explain { modules },
+        } => {
+            mod_command::explain(out, open_workspace().await?, modules).await?;
+        }
+        Commands::Mod {
+            command: ModCommands::ShowExtension { extensions },
+        } => {
+            mod_command::show_extension(out, open_workspace().await?, extensions).await?;
+        }
+        Commands::Repl
+        | Commands::Server
+        | Commands::Shutdown
+        | Commands::CanonicalizeFlags { .. } => {
+            unreachable!("handled by main, never sent to the server")
+        }
+    }
+    Ok(())
+}
+
+/// Checks the module graph as every command that resolves it does, then brings the lockfile up to date. Read-only
+/// commands run alongside others without the output base lock, so they only check it, with `--lockfile_mode=error`.
+async fn prepare_workspace(workspace: &Workspace, command: &Commands) -> anyhow::Result<()> {
+    workspace.check_compatibility().await?;
+    workspace.check_direct_dependencies().await?;
+    workspace.update_lockfile(!server::read_only(command)).await
+}
+
+/// The query expression in `path`, or on stdin if it's `-`, for `--query_file`.
+async fn read_query_file(path: &std::path::Path) -> anyhow::Result<String> {
+    let mut query = String::new();
+    if path == std::path::Path::new("-") {
+        tokio::io::stdin()
+            .read_to_string(&mut query)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to read the query from stdin: {e}"))?;
+    } else {
+        query = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to read query file {}: {e}", path.display()))?;
+    }
+    Ok(query)
+}
+
+/// Runs a command in this process, other than the ones sent to the server.
+async fn run_local_command<W>(
+    cli: &Cli,
+    config: Arc<Configuration>,
+    providers: clock::Providers,
+    stdout: &mut W,
+) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    match &cli.command {
+        Commands::Server => {
+            server::serve(config, providers, Duration::from_secs(cli.max_idle_secs)).await?;
+        }
+        Commands::Repl => {
+            use std::io::IsTerminal;
+            let workspace = Workspace::new(".", config, providers).await?;
+            let runtime = tokio::runtime::Handle::current();
+            tokio::task::spawn_blocking(move || {
+                let stdin = std::io::stdin();
+                let prompt = stdin.is_terminal();
+                repl::repl(
+                    stdin.lock(),
+                    &mut std::io::stdout(),
+                    workspace,
+                    prompt,
+                    runtime,
+                )
+            })
+            .await??;
+        }
+        Commands::Shutdown => {
+            if let Some(root) = bazel::rc::find_workspace_root(std::path::Path::new(".")) {
+                server::shutdown(&config.output_base(&root)).await?;
+            }
+        }
+        Commands::CanonicalizeFlags { for_command, flags } => {
+            let root = bazel::rc::find_workspace_root(std::path::Path::new("."));
+            let rc = bazel::rc::RcOptions::load_default(root.as_deref())?;
+            let mut text = String::new();
+            for flag in canonicalize_flags::canonicalize(&rc, for_command, flags)? {
+                text.push_str(&flag);
+                text.push('\n');
+            }
+            stdout.write_all(text.as_bytes()).await?;
+        }
+        _ => {
+            let workspace_config = config.clone();
+            let workspace_providers = providers.clone();
+            let workspace = move || {
+                let config = workspace_config.clone();
+                let providers = workspace_providers.clone();
+                async move { Ok(Workspace::new(".", config, providers).await?) }.boxed()
+            };
+            let metrics_textfile = config.metrics_textfile.clone();
+            let result = crate::workspace::with_deferred_errors(run_command(
+                cli, config, &providers, &workspace, stdout,
+            ))
+            .await;
+            if let Some(path) = metrics_textfile {
+                write_metrics(&path);
+            }
+            result?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes `--metrics_textfile`, whether or not the command succeeded. Failing to is only logged, as the command's
+/// outcome matters more.
+fn write_metrics(path: &std::path::Path) {
+    if let Err(e) = metrics::METRICS.write_textfile(path) {
+        log::error!("Failed to write --metrics_textfile {}: {e}", path.display());
+    }
+}
+
+/// Takes the lock on the output base of the workspace, unless the command doesn't use it.
+async fn lock_output_base(
+    cli: &Cli,
+    config: &Configuration,
+) -> anyhow::Result<Option<output_lock::OutputBaseLock>> {
+    // The server runs commands for clients, which hold the lock while they wait.
+    let uses_output_base = !matches!(
+        cli.command,
+        Commands::Version
+            | Commands::Server
+            | Commands::Completions { .. }
+            | Commands::CanonicalizeFlags { .. }
+            | Commands::AnalyzeProfile { .. }
+    );
+    // Read-only commands sent to the server run alongside others there, sharing their workspace.
+    let alongside = !cli.batch && server::dispatchable(cli) && server::read_only(&cli.command);
+    let Some(root) = (uses_output_base && !alongside)
+        .then(|| bazel::rc::find_workspace_root(std::path::Path::new(".")))
+        .flatten()
+    else {
+        return Ok(None);
+    };
+    let lock = output_lock::OutputBaseLock::acquire(&config.output_base(&root), cli.block_for_lock)
+        .await?;
+    Ok(Some(lock))
+}
+
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
+async fn main() -> std::process::ExitCode {
     let mut stdout = tokio::io::stdout();
 
-    let cli = Cli::parse();
+    let args = match expand_command_line() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            return ExitCode::CommandLineError.into();
+        }
+    };
+    let cli = Cli::parse_from(&args);
 
     let config = Arc::new(Configuration::from_flags(&cli));
+    // The system clock and OS randomness, for everything the command does.
+    let providers = clock::Providers::default();
 
     fastrace::set_reporter(ConsoleReporter, fastrace::collector::Config::default());
 
-    let console_layer = console_subscriber::spawn();
+    // The console subscriber retains the history of every task, which nobody will inspect on a throwaway runner. The
+    // server doesn't serve one either: the client that starts it holds the console's port.
+    let console_layer = (config.keep_state_after_build && !matches!(cli.command, Commands::Server))
+        .then(console_subscriber::spawn);
+    let (profile_layer, profile_writer) = cli.profile.clone().map(profile::profile).unzip();
 
     tracing_subscriber::registry()
         .with(console_layer)
-        .with(IndicatifLayer::new())
+        .with(profile_layer)
+        .with(console::Console::detect(&cli).layer())
         .init();
 
-    match &cli.command {
-        Commands::Version => {
-            // The version is automatically handled by clap if --version is passed.
-            // This explicit subcommand can be used if `razel version` is preferred.
-            println!("Razel version: {}", env!("CARGO_PKG_VERSION"));
-        }
-        Commands::Build { targets } => {
-            println!("Building targets: {targets:?}");
-            unimplemented!("Build command is not yet implemented.");
+    // Held until the process exits.
+    let _lock = match lock_output_base(&cli, &config).await {
+        Ok(lock) => lock,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            return exit_code::exit_code(&e).into();
         }
-        Commands::Test { targets } => {
-            println!("Testing targets: {targets:?}");
-            unimplemented!("Test command is not yet implemented.");
+    };
+
+    if !cli.batch && server::dispatchable(&cli) {
+        let output_base = bazel::rc::find_workspace_root(std::path::Path::new("."))
+            .map(|root| config.output_base(&root));
+        if let Some(output_base) = output_base {
+            let code = match server::dispatch(&output_base, &args[1..], cli.max_idle_secs).await {
+                Ok(code) => code,
+                Err(e) => {
+                    eprintln!("Error: {e:?}");
+                    ExitCode::LocalEnvironmentalError.code()
+                }
+            };
+            drop(profile_writer);
+            std::process::exit(code);
         }
-        Commands::Run { target } => {
-            println!("Running target: {target}");
-            unimplemented!("Run command is not yet implemented.");
+    }
+
+    let command = std::panic::AssertUnwindSafe(run_local_command(
+        &cli,
+        config.clone(),
+        providers,
+        &mut stdout,
+    ))
+    .catch_unwind();
+    let result = tokio::select! {
+        result = command => result,
+        _ = tokio::signal::ctrl_c() => {
+            Ok(Err(anyhow::anyhow!("Interrupted")).exit_code(ExitCode::Interrupted))
         }
-        Commands::Query { query: query_str } => {
-            query::query(&mut stdout, config, query_str).await?;
+    };
+    let code = match result {
+        Ok(Ok(())) => ExitCode::Success,
+        Ok(Err(e)) => {
+            let _ = stdout.flush().await;
+            eprintln!("Error: {e:?}");
+            exit_code::exit_code(&e)
         }
-    }
+        // The panic hook has already printed the message.
+        Err(_) => ExitCode::InternalError,
+    };
 
     fastrace::flush();
-    stdout.flush().await?;
-    Ok(())
+    let _ = stdout.flush().await;
+    drop(profile_writer);
+
+    if !config.keep_state_after_build {
+        // Skip dropping the workspace caches and shutting down the runtime; the OS reclaims it all at once.
+        std::process::exit(code.code());
+    }
+    code.into()
 }
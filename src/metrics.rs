@@ -0,0 +1,386 @@
+//! Global per-invocation counters, reported as the Build Event Protocol `BuildMetrics` and `BuildToolLogs` events.
+//!
+//! See https://github.com/bazelbuild/bazel/blob/master/src/main/java/com/google/devtools/build/lib/buildeventstream/proto/build_event_stream.proto
+//!
+//! They are also exported in the Prometheus text format, to `--metrics_textfile` for node_exporter's textfile
+//! collector, and at `/metrics` on the server. The server's counters cover every command it has run since it started.
+
+use base64::Engine;
+use serde::{Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+/// Counters for the current invocation.
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+#[derive(Debug)]
+pub struct Metrics {
+    start: Instant,
+    actions_created: AtomicU64,
+    actions_executed: AtomicU64,
+    remote_cache_hits: AtomicU64,
+    /// Traffic to and from the remote CAS, which stays 0 until razel has a remote cache to talk to.
+    cas_bytes_uploaded: AtomicU64,
+    cas_bytes_downloaded: AtomicU64,
+    packages_loaded: AtomicU64,
+    targets_loaded: AtomicU64,
+    repositories_fetched: AtomicU64,
+    /// Time spent in each phase of a command, e.g. `loading`, summed over commands.
+    phases: Mutex<BTreeMap<&'static str, Duration>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            actions_created: AtomicU64::new(0),
+            actions_executed: AtomicU64::new(0),
+            remote_cache_hits: AtomicU64::new(0),
+            cas_bytes_uploaded: AtomicU64::new(0),
+            cas_bytes_downloaded: AtomicU64::new(0),
+            packages_loaded: AtomicU64::new(0),
+            targets_loaded: AtomicU64::new(0),
+            repositories_fetched: AtomicU64::new(0),
+            phases: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Records a successfully evaluated package containing `targets` targets.
+    pub fn record_package_loaded(&self, targets: usize) {
+        self.packages_loaded.fetch_add(1, Ordering::Relaxed);
+        self.targets_loaded
+            .fetch_add(targets as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_action_created(&self) {
+        self.actions_created.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records an action that ran to completion, either locally/remotely or by being served from the remote cache.
+    pub fn record_action_executed(&self, remote_cache_hit: bool) {
+        self.actions_executed.fetch_add(1, Ordering::Relaxed);
+        if remote_cache_hit {
+            self.remote_cache_hits.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records an external repository made available, whether downloaded or vendored.
+    pub fn record_repository_fetched(&self) {
+        self.repositories_fetched.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_phase(&self, phase: &'static str, duration: Duration) {
+        *self.phases.lock().unwrap().entry(phase).or_default() += duration;
+    }
+
+    /// The counters in the Prometheus text exposition format.
+    pub fn prometheus(&self) -> String {
+        let load = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        let executed = load(&self.actions_executed);
+        let cached = load(&self.remote_cache_hits);
+        let counters = [
+            (
+                "razel_actions_executed_total",
+                "Actions run, not counting those served from the remote cache.",
+                executed - cached,
+            ),
+            (
+                "razel_actions_cached_total",
+                "Actions served from the remote cache.",
+                cached,
+            ),
+            (
+                "razel_repositories_fetched_total",
+                "External repositories made available, whether downloaded or vendored.",
+                load(&self.repositories_fetched),
+            ),
+            (
+                "razel_cas_uploaded_bytes_total",
+                "Bytes uploaded to the remote CAS.",
+                load(&self.cas_bytes_uploaded),
+            ),
+            (
+                "razel_cas_downloaded_bytes_total",
+                "Bytes downloaded from the remote CAS.",
+                load(&self.cas_bytes_downloaded),
+            ),
+            (
+                "razel_packages_loaded_total",
+                "Packages loaded.",
+                load(&self.packages_loaded),
+            ),
+        ];
+        let mut text = String::new();
+        for (name, help, value) in counters {
+            let _ = write!(
+                text,
+                "# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}\n"
+            );
+        }
+        let name = "razel_phase_seconds_total";
+        let _ = write!(
+            text,
+            "# HELP {name} Time spent in each phase of a command.\n# TYPE {name} counter\n"
+        );
+        for (phase, duration) in self.phases.lock().unwrap().iter() {
+            let _ = writeln!(
+                text,
+                "{name}{{phase=\"{phase}\"}} {:.3}",
+                duration.as_secs_f64()
+            );
+        }
+        text
+    }
+
+    /// Writes `prometheus` to `path`. The file is replaced all at once, so a collector never reads half of it.
+    pub fn write_textfile(&self, path: &Path) -> std::io::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, self.prometheus())?;
+        std::fs::rename(&tmp, path)
+    }
+
+    /// Snapshot of the counters, in the shape of the BEP `BuildMetrics` event.
+    pub fn build_metrics(&self) -> BuildMetrics {
+        let load = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        let peak_memory = peak_memory_bytes().unwrap_or(0);
+        BuildMetrics {
+            action_summary: ActionSummary {
+                actions_created: load(&self.actions_created),
+                actions_executed: load(&self.actions_executed),
+                remote_cache_hits: load(&self.remote_cache_hits),
+            },
+            memory_metrics: MemoryMetrics {
+                used_heap_size_post_build: peak_memory,
+                peak_post_gc_heap_size: peak_memory,
+            },
+            target_metrics: TargetMetrics {
+                targets_loaded: load(&self.targets_loaded),
+            },
+            package_metrics: PackageMetrics {
+                packages_loaded: load(&self.packages_loaded),
+            },
+            timing_metrics: TimingMetrics {
+                wall_time_in_ms: self.start.elapsed().as_millis() as u64,
+            },
+            network_metrics: NetworkMetrics {
+                system_network_stats: SystemNetworkStats {
+                    bytes_sent: load(&self.cas_bytes_uploaded),
+                    bytes_recv: load(&self.cas_bytes_downloaded),
+                },
+            },
+        }
+    }
+
+    /// The logs attached to the BEP `BuildToolLogs` event.
+    pub fn build_tool_logs(&self) -> BuildToolLogs {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let executed = self.actions_executed.load(Ordering::Relaxed);
+        let cached = self.remote_cache_hits.load(Ordering::Relaxed);
+        BuildToolLogs {
+            log: vec![
+                LogFile::new("elapsed time", format!("{elapsed:.6}")),
+                LogFile::new(
+                    "process stats",
+                    format!(
+                        "{executed} processes: {cached} remote cache hit, {} remote.",
+                        executed - cached
+                    ),
+                ),
+            ],
+        }
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Peak resident set size of this process, if the platform exposes it.
+fn peak_memory_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmHWM:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+/// The proto3 JSON mapping encodes 64-bit integers as strings.
+fn int64<S: Serializer>(value: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(value)
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildMetrics {
+    pub action_summary: ActionSummary,
+    pub memory_metrics: MemoryMetrics,
+    pub target_metrics: TargetMetrics,
+    pub package_metrics: PackageMetrics,
+    pub timing_metrics: TimingMetrics,
+    pub network_metrics: NetworkMetrics,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ActionSummary {
+    #[serde(serialize_with = "int64")]
+    pub actions_created: u64,
+    #[serde(serialize_with = "int64")]
+    pub actions_executed: u64,
+    #[serde(serialize_with = "int64")]
+    pub remote_cache_hits: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MemoryMetrics {
+    #[serde(serialize_with = "int64")]
+    pub used_heap_size_post_build: u64,
+    #[serde(serialize_with = "int64")]
+    pub peak_post_gc_heap_size: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TargetMetrics {
+    #[serde(serialize_with = "int64")]
+    pub targets_loaded: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PackageMetrics {
+    #[serde(serialize_with = "int64")]
+    pub packages_loaded: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimingMetrics {
+    #[serde(serialize_with = "int64")]
+    pub wall_time_in_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NetworkMetrics {
+    pub system_network_stats: SystemNetworkStats,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SystemNetworkStats {
+    #[serde(serialize_with = "int64")]
+    pub bytes_sent: u64,
+    #[serde(serialize_with = "int64")]
+    pub bytes_recv: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildToolLogs {
+    pub log: Vec<LogFile>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LogFile {
+    pub name: String,
+    /// Encoded as base64, per the proto3 JSON mapping of `bytes`.
+    pub contents: String,
+}
+
+impl LogFile {
+    pub fn new(name: impl Into<String>, contents: impl AsRef<[u8]>) -> Self {
+        Self {
+            name: name.into(),
+            contents: base64::engine::general_purpose::STANDARD.encode(contents),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_metrics() {
+        let metrics = Metrics::new();
+        metrics.record_package_loaded(3);
+        metrics.record_package_loaded(2);
+        metrics.record_action_created();
+        metrics.record_action_created();
+        metrics.record_action_executed(true);
+        metrics.record_action_executed(false);
+
+        let json = serde_json::to_value(metrics.build_metrics()).unwrap();
+        assert_eq!(json["actionSummary"]["actionsCreated"], "2");
+        assert_eq!(json["actionSummary"]["actionsExecuted"], "2");
+        assert_eq!(json["actionSummary"]["remoteCacheHits"], "1");
+        assert_eq!(json["packageMetrics"]["packagesLoaded"], "2");
+        assert_eq!(json["targetMetrics"]["targetsLoaded"], "5");
+        assert_eq!(
+            json["networkMetrics"]["systemNetworkStats"]["bytesSent"],
+            "0"
+        );
+        assert_eq!(
+            json["networkMetrics"]["systemNetworkStats"]["bytesRecv"],
+            "0"
+        );
+    }
+
+    #[test]
+    fn test_build_tool_logs() {
+        let metrics = Metrics::new();
+        metrics.record_action_executed(true);
+        let logs = metrics.build_tool_logs();
+        let stats = logs.log.iter().find(|l| l.name == "process stats").unwrap();
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(&stats.contents)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(decoded).unwrap(),
+            "1 processes: 1 remote cache hit, 0 remote."
+        );
+    }
+
+    #[test]
+    fn test_prometheus() {
+        let metrics = Metrics::new();
+        metrics.record_action_executed(true);
+        metrics.record_action_executed(false);
+        metrics.record_action_executed(false);
+        metrics.record_repository_fetched();
+        metrics.record_phase("loading", Duration::from_millis(1500));
+        metrics.record_phase("loading", Duration::from_millis(250));
+
+        let text = metrics.prometheus();
+        assert!(text.contains("# TYPE razel_actions_executed_total counter\n"));
+        assert!(
+            text.contains("\nrazel_actions_executed_total 2\n"),
+            "{text}"
+        );
+        assert!(text.contains("\nrazel_actions_cached_total 1\n"), "{text}");
+        assert!(
+            text.contains("\nrazel_repositories_fetched_total 1\n"),
+            "{text}"
+        );
+        assert!(
+            text.contains("\nrazel_cas_downloaded_bytes_total 0\n"),
+            "{text}"
+        );
+        assert!(
+            text.contains("\nrazel_phase_seconds_total{phase=\"loading\"} 1.750\n"),
+            "{text}"
+        );
+
+        let tmp = assert_fs::TempDir::new().unwrap();
+        let path = tmp.path().join("razel.prom");
+        metrics.write_textfile(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), text);
+    }
+}
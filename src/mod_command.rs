@@ -0,0 +1,228 @@
+use crate::bazel::label::{CanonicalRepo, MAIN_REPO, MAIN_REPO_ROOT, parse_label};
+use crate::bazel::lockfile::Lockfile;
+use crate::bazel::module_graph::module_of_repo;
+use crate::bazel::repo::Repository;
+use crate::error::error_code;
+use crate::workspace::Workspace;
+use std::collections::HashSet;
+use std::marker::Unpin;
+use std::sync::Arc;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Implements `razel mod graph`, printing the tree of modules the main module depends on, as `<name>@<version>`.
+///
+/// A module already shown higher up the tree is marked `(*)` rather than expanded again. With `--verbose`, each
+/// dependency also says whether its repository was vendored or fetched, or why fetching it failed.
+pub async fn graph<W>(out: &mut W, workspace: Arc<Workspace>, verbose: bool) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let root = workspace.main_module().await?;
+    let mut text = format!("<root> ({}@{})\n", root.name, root.version);
+    let mut seen = HashSet::from([MAIN_REPO]);
+    // Each entry is a module still to print, the indentation of its line, and whether it is the last of its siblings.
+    let mut stack: Vec<(CanonicalRepo<'static>, String, bool)> = Vec::new();
+    let main_repo = workspace.main_repo().await?;
+    push_deps(&mut stack, &main_repo, "");
+    while let Some((name, indent, last)) = stack.pop() {
+        let branch = if last { "└───" } else { "├───" };
+        text.push_str(&format!("{indent}{branch}{}", module_key(&name)));
+        let child_indent = format!("{indent}{}", if last { "    " } else { "│   " });
+        if !seen.insert(name.clone()) {
+            text.push_str(" (*)\n");
+            continue;
+        }
+        let repo = workspace.repository(&name).await;
+        if verbose {
+            let status = match &repo {
+                Ok(_) => match workspace.vendor_dir() {
+                    Some(dir) if tokio::fs::try_exists(dir.join(name.as_str())).await? => {
+                        "vendored".to_string()
+                    }
+                    _ => "fetched".to_string(),
+                },
+                Err(e) => match error_code(e) {
+                    Some(code) => format!("fetch failed, {code}: {}", e.root_cause()),
+                    None => format!("fetch failed: {}", e.root_cause()),
+                },
+            };
+            text.push_str(&format!(" ({status})"));
+        }
+        text.push('\n');
+        if let Ok(repo) = repo {
+            push_deps(&mut stack, &repo, &child_indent);
+        }
+    }
+    out.write_all(text.as_bytes()).await?;
+    Ok(())
+}
+
+/// Implements `razel mod graph --cycles`, printing each cycle in the module graph as the path around it.
+pub async fn cycles<W>(out: &mut W, workspace: Arc<Workspace>) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let cycles = workspace.module_graph().await?.cycles();
+    let text = if cycles.is_empty() {
+        "No cycles in the module graph\n".to_string()
+    } else {
+        let mut text = format!("Found {} cycle(s) in the module graph:\n", cycles.len());
+        for cycle in cycles {
+            text.push_str(&format!("  {}\n", cycle.join(" -> ")));
+        }
+        text
+    };
+    out.write_all(text.as_bytes()).await?;
+    Ok(())
+}
+
+/// Implements `razel mod all_paths <module>...`, printing every path from the root module to any version of each
+/// module, one per line.
+pub async fn all_paths<W>(
+    out: &mut W,
+    workspace: Arc<Workspace>,
+    modules: &[String],
+) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let graph = workspace.module_graph().await?;
+    let mut text = String::new();
+    for module in modules {
+        let paths = graph.all_paths(module);
+        if paths.is_empty() {
+            anyhow::bail!("No module named {module:?} in the dependency graph");
+        }
+        text.push_str(&format!("## {module}:\n"));
+        for path in paths {
+            text.push_str(&format!("  {}\n", path.join(" -> ")));
+        }
+    }
+    out.write_all(text.as_bytes()).await?;
+    Ok(())
+}
+
+/// Implements `razel mod explain <module>...`, printing which modules depend on each module and the version each
+/// asks for, highest first. The highest is the version selected, so its requesters are what pull it in.
+pub async fn explain<W>(
+    out: &mut W,
+    workspace: Arc<Workspace>,
+    modules: &[String],
+) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let graph = workspace.module_graph().await?;
+    let mut text = String::new();
+    for module in modules {
+        let requesters = graph.requesters(module);
+        let Some(selected) = requesters.first() else {
+            anyhow::bail!("No module named {module:?} in the dependency graph");
+        };
+        text.push_str(&format!(
+            "## {module} (selected {module}@{}):\n",
+            selected.version
+        ));
+        for requester in &requesters {
+            let mark = if requester.selected {
+                " (selected)"
+            } else {
+                ""
+            };
+            text.push_str(&format!(
+                "  {} -> {module}@{}{mark}\n",
+                requester.module, requester.version
+            ));
+        }
+    }
+    out.write_all(text.as_bytes()).await?;
+    Ok(())
+}
+
+/// Pushes the modules `repo` depends on onto `stack`, so that they are popped in order of name.
+fn push_deps(
+    stack: &mut Vec<(CanonicalRepo<'static>, String, bool)>,
+    repo: &Repository<'static>,
+    indent: &str,
+) {
+    let mut deps: Vec<_> = repo
+        .repo_mapping()
+        .values()
+        // Only modules, not the repositories of module extensions.
+        .filter(|&dep| *dep != repo.canonical_name() && module_of_repo(dep.as_str()).is_some())
+        .cloned()
+        .collect();
+    deps.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+    deps.dedup();
+    let last = deps.len().saturating_sub(1);
+    for (i, dep) in deps.into_iter().enumerate().rev() {
+        stack.push((dep, indent.to_string(), i == last));
+    }
+}
+
+/// A module's canonical repository name, e.g. `rules_go+0.50.1`, written as Bazel writes modules: `rules_go@0.50.1`.
+fn module_key(repo: &CanonicalRepo) -> String {
+    match repo.as_str().split_once('+') {
+        Some((name, version)) => format!("{name}@{version}"),
+        None => repo.to_string(),
+    }
+}
+
+/// Implements `razel mod show_extension <extension>...`, printing the repositories each module extension generated
+/// and the downloads pinned for them in the lockfile. razel doesn't evaluate module extensions, so only evaluations
+/// recorded by Bazel, in a lockfile shared with it, are shown.
+///
+/// Extensions are given as `<bzl file label>%<extension name>`, e.g. `@rules_go//go:extensions.bzl%go_sdk`.
+pub async fn show_extension<W>(
+    out: &mut W,
+    workspace: Arc<Workspace>,
+    extensions: &[String],
+) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let lockfile = Lockfile::load(workspace.path()).await?;
+
+    for extension in extensions {
+        let id = canonical_extension_id(&workspace, extension).await?;
+        let results = lockfile.extension(&id).ok_or_else(|| {
+            anyhow::anyhow!(
+                "No evaluation of extension {id} is recorded in the lockfile. razel doesn't evaluate module \
+                 extensions, so only those Bazel recorded are shown"
+            )
+        })?;
+
+        let mut text = format!("## {extension}:\n");
+        for (key, result) in results {
+            text.push_str(&format!("\nFetched repositories ({key}):\n"));
+            for (name, spec) in &result.generated_repo_specs {
+                text.push_str(&format!("  - {name} ({})\n", spec.rule_name()));
+                for url in spec.urls() {
+                    text.push_str(&format!("      url: {url}\n"));
+                }
+                if let Some(checksum) = spec.checksum() {
+                    text.push_str(&format!("      integrity: {checksum}\n"));
+                }
+            }
+        }
+        text.push('\n');
+        out.write_all(text.as_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+/// Resolves an extension reference as seen from the main repository to the id used as its lockfile key.
+async fn canonical_extension_id(workspace: &Workspace, extension: &str) -> anyhow::Result<String> {
+    let (bzl_file, name) = extension.rsplit_once('%').ok_or_else(|| {
+        anyhow::anyhow!("Invalid extension {extension:?}, expected <bzl file label>%<name>")
+    })?;
+
+    let label = parse_label(bzl_file, &MAIN_REPO_ROOT)
+        .map_err(|e| anyhow::anyhow!("Invalid label {bzl_file:?}: {e}"))?;
+    let main_repo = workspace.main_repo().await?;
+    let canonical = label
+        .to_canonical(main_repo.repo_mapping())
+        .ok_or_else(|| anyhow::anyhow!("Unknown repository in {bzl_file:?}"))?;
+    Ok(format!("{canonical}%{name}"))
+}
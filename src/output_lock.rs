@@ -0,0 +1,116 @@
+//! The lock on an output base, so that only one command at a time uses it.
+//!
+//! Two commands running in the same output base at once would overwrite each other's outputs and cache entries. So a
+//! command takes the lock on `<output_base>/lock` before it starts and holds it until it exits, when the OS releases
+//! it even if the command crashed. The lock file holds the pid of the command holding it, to tell the others waiting.
+
+use crate::exit_code::{ExitCode, WithExitCode};
+use std::fs::TryLockError;
+use std::io::{Seek, Write};
+use std::path::Path;
+use std::time::Duration;
+
+pub const LOCK_FILE: &str = "lock";
+
+/// How often to check whether the lock has been released.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Holds the lock on an output base until dropped.
+#[derive(Debug)]
+pub struct OutputBaseLock {
+    _file: std::fs::File,
+}
+
+/// The pid of the command holding the lock at `path`, if it has written it yet.
+fn holder(path: &Path) -> Option<u32> {
+    std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+fn describe(holder: Option<u32>) -> String {
+    holder.map_or("another command".to_string(), |pid| format!("pid {pid}"))
+}
+
+impl OutputBaseLock {
+    /// Takes the lock on `output_base`. While another command holds it, waits for the lock to be released if `block`,
+    /// and fails with `ExitCode::LockHeld` otherwise.
+    pub async fn acquire(output_base: &Path, block: bool) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(output_base)?;
+        let path = output_base.join(LOCK_FILE);
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(|e| anyhow::anyhow!("Failed to open {}: {e}", path.display()))?;
+
+        let mut waiting_for = None;
+        loop {
+            match file.try_lock() {
+                Ok(()) => break,
+                Err(TryLockError::WouldBlock) => {}
+                Err(TryLockError::Error(e)) => {
+                    anyhow::bail!("Failed to lock {}: {e}", path.display())
+                }
+            }
+            let held_by = holder(&path);
+            if !block {
+                return Err(anyhow::anyhow!(
+                    "Output base {} is in use by {}, and --block_for_lock=false",
+                    output_base.display(),
+                    describe(held_by)
+                ))
+                .exit_code(ExitCode::LockHeld);
+            }
+            if waiting_for != Some(held_by) {
+                eprintln!(
+                    "Another command is running in output base {}, waiting for lock held by {}...",
+                    output_base.display(),
+                    describe(held_by)
+                );
+                waiting_for = Some(held_by);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+
+        file.set_len(0)?;
+        file.rewind()?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(Self { _file: file })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_output_base_lock() {
+        let tmp = assert_fs::TempDir::new().unwrap();
+        let output_base = tmp.path().join("output_base");
+        let lock = OutputBaseLock::acquire(&output_base, false).await.unwrap();
+        assert_eq!(
+            holder(&output_base.join(LOCK_FILE)),
+            Some(std::process::id())
+        );
+
+        let err = OutputBaseLock::acquire(&output_base, false)
+            .await
+            .unwrap_err();
+        assert_eq!(crate::exit_code::exit_code(&err), ExitCode::LockHeld);
+        assert!(
+            err.to_string()
+                .contains(&format!("in use by pid {}", std::process::id())),
+            "{err}"
+        );
+
+        let waiting = tokio::spawn({
+            let output_base = output_base.clone();
+            async move { OutputBaseLock::acquire(&output_base, true).await }
+        });
+        tokio::time::sleep(POLL_INTERVAL * 2).await;
+        assert!(!waiting.is_finished());
+        drop(lock);
+        waiting.await.unwrap().unwrap();
+    }
+}
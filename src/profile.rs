@@ -0,0 +1,225 @@
+//! `--profile`: a Chrome trace of the invocation.
+//!
+//! Every `tracing` span razel records (loading packages and .bzl files, fetching repositories, downloads, ...) becomes
+//! a pair of async begin/end events in the `trace_event` format, which `chrome://tracing` and https://ui.perfetto.dev
+//! can display. Spans are grouped into one track per top-level span, so concurrent work shows up side by side.
+//! See https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU
+
+use serde::Serialize;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tracing::span;
+use tracing_subscriber::Layer;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+#[derive(Debug, Clone, Serialize)]
+struct TraceEvent {
+    name: String,
+    cat: &'static str,
+    ph: &'static str,
+    /// Microseconds since the start of the invocation.
+    ts: u64,
+    pid: u32,
+    tid: u64,
+    id: String,
+    #[serde(skip_serializing_if = "serde_json::Map::is_empty")]
+    args: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Trace<'a> {
+    trace_events: &'a [TraceEvent],
+    display_time_unit: &'static str,
+}
+
+/// Stored in each span's extensions while it is open.
+struct OpenSpan {
+    start: u64,
+    /// The top-level span this span belongs to, which determines its track.
+    root: u64,
+    args: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Default)]
+struct FieldVisitor(serde_json::Map<String, serde_json::Value>);
+
+impl tracing::field::Visit for FieldVisitor {
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &tracing::field::Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{value:?}").into());
+    }
+}
+
+#[derive(Debug)]
+struct Recorder {
+    start: Instant,
+    events: Mutex<Vec<TraceEvent>>,
+}
+
+impl Recorder {
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_micros() as u64
+    }
+}
+
+/// A `tracing` layer recording spans for the profile.
+pub struct ProfileLayer {
+    recorder: Arc<Recorder>,
+}
+
+impl<S> Layer<S> for ProfileLayer
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let root = span
+            .parent()
+            .and_then(|parent| parent.extensions().get::<OpenSpan>().map(|p| p.root))
+            .unwrap_or(id.into_u64());
+        let mut fields = FieldVisitor::default();
+        attrs.record(&mut fields);
+        span.extensions_mut().insert(OpenSpan {
+            start: self.recorder.now(),
+            root,
+            args: fields.0,
+        });
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id)
+            && let Some(open) = span.extensions_mut().get_mut::<OpenSpan>()
+        {
+            let mut fields = FieldVisitor(std::mem::take(&mut open.args));
+            values.record(&mut fields);
+            open.args = fields.0;
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(open) = span.extensions_mut().remove::<OpenSpan>() else {
+            return;
+        };
+        let end = self.recorder.now();
+        let event = |ph, ts, args| TraceEvent {
+            name: span.name().to_string(),
+            cat: "razel",
+            ph,
+            ts,
+            pid: std::process::id(),
+            tid: open.root,
+            id: format!("{:#x}", open.root),
+            args,
+        };
+        let begin = event("b", open.start, open.args.clone());
+        let end = event("e", end, serde_json::Map::new());
+        self.recorder.events.lock().unwrap().extend([begin, end]);
+    }
+}
+
+/// Writes the profile when dropped, so that failed invocations, which often are the interesting ones, still produce
+/// one.
+pub struct ProfileWriter {
+    path: PathBuf,
+    recorder: Arc<Recorder>,
+}
+
+impl ProfileWriter {
+    fn write(&self) -> anyhow::Result<()> {
+        let mut events = self.recorder.events.lock().unwrap().clone();
+        events.sort_by_key(|e| e.ts);
+        let trace = Trace {
+            trace_events: &events,
+            display_time_unit: "ms",
+        };
+        let file = std::fs::File::create(&self.path)?;
+        serde_json::to_writer(std::io::BufWriter::new(file), &trace)?;
+        Ok(())
+    }
+}
+
+impl Drop for ProfileWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.write() {
+            log::error!("Failed to write --profile {}: {e:#}", self.path.display());
+        }
+    }
+}
+
+/// Creates a layer recording spans, and the writer that saves them to `path`.
+pub fn profile(path: PathBuf) -> (ProfileLayer, ProfileWriter) {
+    let recorder = Arc::new(Recorder {
+        start: Instant::now(),
+        events: Mutex::new(Vec::new()),
+    });
+    (
+        ProfileLayer {
+            recorder: recorder.clone(),
+        },
+        ProfileWriter { path, recorder },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_profile() {
+        let tmp = assert_fs::TempDir::new().unwrap();
+        let path = tmp.path().join("profile.json");
+        let (layer, writer) = profile(path.clone());
+
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let outer = tracing::info_span!("load package", package = "foo");
+            let _entered = outer.enter();
+            tracing::info_span!("load bzl", label = "//foo:defs.bzl").in_scope(|| {});
+        });
+        drop(writer);
+
+        let trace: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        let events = trace["traceEvents"].as_array().unwrap();
+        let phases: Vec<(&str, &str)> = events
+            .iter()
+            .map(|e| (e["name"].as_str().unwrap(), e["ph"].as_str().unwrap()))
+            .collect();
+        assert_eq!(phases.len(), 4);
+        assert!(phases.contains(&("load package", "b")));
+        assert!(phases.contains(&("load bzl", "e")));
+
+        let begin = events
+            .iter()
+            .find(|e| e["name"] == "load bzl" && e["ph"] == "b")
+            .unwrap();
+        assert_eq!(begin["args"]["label"], "//foo:defs.bzl");
+        // Nested spans share the track of their top-level span.
+        assert!(events.iter().all(|e| e["id"] == begin["id"]));
+    }
+}
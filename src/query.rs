@@ -1,13 +1,23 @@
-use crate::bazel::Configuration;
-use crate::bazel::label::{Label, MAIN_REPO_ROOT, Repo};
+use crate::bazel::label::{CanonicalLabel, CanonicalRepo, Label, MAIN_REPO, Repo, parse_label};
+use crate::bazel::rule::{AttrValue, DepKind, LABEL_ATTRIBUTES, NODEP_ATTRIBUTES, Rule};
+use crate::bazel::visibility::Visibility;
+use crate::build_proto;
+use crate::error::AnalysisError;
+use crate::exit_code::{ExitCode, WithExitCode};
+use crate::genquery::absolute;
 use crate::stream_tee::{StreamTee, StreamTeeExt};
 use crate::workspace::Workspace;
 use chumsky::prelude::*;
 use chumsky::span::{SimpleSpan, Spanned};
+use futures::future::try_join_all;
 use futures::stream::{self, BoxStream, StreamExt};
-use std::collections::HashMap;
+use prost::Message;
+use regex::Regex;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::marker::Unpin;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::io::AsyncWrite;
 use tokio::io::AsyncWriteExt;
 
@@ -18,17 +28,1001 @@ pub type QueryStream<'a> = BoxStream<'a, QueryResult<'a>>;
 pub struct QueryContext<'a> {
     pub workspace: Arc<Workspace>,
     pub variables: HashMap<&'a str, StreamTee<QueryStream<'a>>>,
+    /// The packages loaded by functions following dependencies, shared by the whole query.
+    graph: Arc<TargetGraph>,
+    /// The target patterns of the universe searched by functions without an explicit one.
+    universe: Arc<[String]>,
+    edges: Edges,
+    stats: Arc<QueryStats>,
 }
 
 impl<'a> QueryContext<'a> {
-    pub fn new(workspace: Arc<Workspace>) -> Self {
+    pub fn new(workspace: Arc<Workspace>, universe: Vec<String>, edges: Edges) -> Self {
         Self {
             workspace,
             variables: HashMap::new(),
+            graph: Arc::default(),
+            universe: universe.into(),
+            edges,
+            stats: Arc::default(),
         }
     }
 }
 
+/// Which of a rule's dependencies functions following them, such as `deps` and `rdeps`, see: explicit ones always,
+/// and tool and implicit ones unless `--notool_deps` or `--noimplicit_deps` leave them out. Labels in attributes such
+/// as `visibility` are never dependencies, see `NODEP_ATTRIBUTES`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edges {
+    pub implicit: bool,
+    pub tool: bool,
+}
+
+impl Default for Edges {
+    fn default() -> Self {
+        Self {
+            implicit: true,
+            tool: true,
+        }
+    }
+}
+
+impl Edges {
+    fn follows(self, kind: DepKind) -> bool {
+        match kind {
+            DepKind::Explicit => true,
+            DepKind::Tool => self.tool,
+            DepKind::Implicit => self.implicit,
+        }
+    }
+}
+
+/// The targets whose transitive closure functions without an explicit universe, such as `allrdeps`, search.
+///
+/// The whole workspace by default, which can take a long time to load in a large one. A universe inferred from the
+/// query only loads the packages the query names and what they depend on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Universe {
+    Patterns(Vec<String>),
+    /// The target patterns written in the query expression.
+    Inferred,
+}
+
+impl Universe {
+    /// The universe given by `--universe_scope` and `--infer_universe_scope`.
+    pub fn new(scope: &[String], infer: bool) -> Self {
+        if !scope.is_empty() {
+            Universe::Patterns(scope.to_vec())
+        } else if infer {
+            Universe::Inferred
+        } else {
+            Universe::Patterns(vec!["//...".to_string()])
+        }
+    }
+
+    /// The target patterns of the universe of the query `ast`.
+    fn patterns(self, ast: &Spanned<Expr<'_>>) -> Vec<String> {
+        match self {
+            Universe::Patterns(patterns) => patterns,
+            Universe::Inferred => ast
+                .inner
+                .target_literals()
+                .into_iter()
+                .map(str::to_string)
+                .collect(),
+        }
+    }
+}
+
+/// A package as query functions see it.
+pub(crate) struct LoadedPackage {
+    build_file_name: String,
+    pub(crate) rules: HashMap<String, Rule>,
+    /// Why the package failed to load. With `--keep_going` it stays in the graph as a placeholder without targets, and
+    /// the targets of it that other targets depend on are left out of the result.
+    error: Option<String>,
+}
+
+impl LoadedPackage {
+    /// The rule that declares the output file `name`, if any does.
+    pub(crate) fn generating_rule(&self, name: &str) -> Option<&Rule> {
+        self.rules
+            .values()
+            .find(|rule| rule.outputs().any(|out| out == name))
+    }
+}
+
+/// The packages a query has looked into, so that each is only loaded once.
+#[derive(Default)]
+struct TargetGraph {
+    packages: Mutex<HashMap<(CanonicalRepo<'static>, String), Arc<LoadedPackage>>>,
+}
+
+/// What evaluating a query took, for `--experimental_query_stats`.
+#[derive(Debug, Default)]
+pub struct QueryStats {
+    packages_loaded: AtomicU64,
+    /// Packages asked for again, which came from the query's `TargetGraph`.
+    package_cache_hits: AtomicU64,
+    /// Targets whose dependencies were looked up.
+    targets_visited: AtomicU64,
+    /// How many times each function was applied, and the time it took, including the functions in its arguments.
+    functions: Mutex<BTreeMap<String, (u64, Duration)>>,
+}
+
+impl QueryStats {
+    fn record_function(&self, name: &str, elapsed: Duration) {
+        let mut functions = self.functions.lock().unwrap();
+        let (calls, total) = functions.entry(name.to_string()).or_default();
+        *calls += 1;
+        *total += elapsed;
+    }
+
+    /// The statistics as `--experimental_query_stats` prints them.
+    pub fn report(&self) -> String {
+        let loaded = self.packages_loaded.load(Ordering::Relaxed);
+        let hits = self.package_cache_hits.load(Ordering::Relaxed);
+        let lookups = loaded + hits;
+        let hit_rate = if lookups == 0 {
+            0.0
+        } else {
+            100.0 * hits as f64 / lookups as f64
+        };
+        let mut report = format!(
+            "Query statistics:\n  packages loaded: {loaded}\n  package cache hits: {hits} of {lookups} \
+             ({hit_rate:.1}%)\n  targets visited: {}\n",
+            self.targets_visited.load(Ordering::Relaxed)
+        );
+        for (name, (calls, total)) in self.functions.lock().unwrap().iter() {
+            report += &format!("  {name}: {calls} calls, {:.3}s\n", total.as_secs_f64());
+        }
+        report
+    }
+}
+
+fn from_canonical(label: CanonicalLabel<'static>) -> Label<'static> {
+    Label::new(Repo::Canonical(label.repo), label.package, label.target)
+}
+
+/// The path of the main repository's file `label`, relative to the workspace root.
+fn workspace_path(label: &CanonicalLabel<'_>) -> Option<String> {
+    if label.repo != MAIN_REPO {
+        return None;
+    }
+    Some(match label.package() {
+        "" => label.name().to_string(),
+        package => format!("{package}/{}", label.name()),
+    })
+}
+
+/// `files` and the `.bzl` files they load, transitively, following the loads in `graph`.
+fn load_closure(
+    graph: &HashMap<CanonicalLabel<'static>, Vec<CanonicalLabel<'static>>>,
+    files: Vec<CanonicalLabel<'static>>,
+) -> Vec<CanonicalLabel<'static>> {
+    let mut seen: HashSet<_> = files.iter().cloned().collect();
+    let mut closure = files;
+    let mut next = 0;
+    while let Some(file) = closure.get(next) {
+        let loads = graph.get(file).into_iter().flatten();
+        let new: Vec<_> = loads
+            .filter(|&load| seen.insert(load.clone()))
+            .cloned()
+            .collect();
+        closure.extend(new);
+        next += 1;
+    }
+    closure
+}
+
+fn strings_of(labels: Vec<Label<'_>>) -> Vec<String> {
+    labels.iter().map(Label::to_string).collect()
+}
+
+/// Whether the rule attribute `name` holds labels, which are resolved relative to the rule.
+fn is_label_attribute(name: &str) -> bool {
+    LABEL_ATTRIBUTES.contains(&name)
+        || NODEP_ATTRIBUTES.contains(&name)
+        || matches!(name, "out" | "outs")
+}
+
+/// `s` as a Starlark string literal.
+fn starlark_string(s: &str) -> String {
+    format!(
+        "\"{}\"",
+        s.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    )
+}
+
+fn starlark_list(strings: &[String]) -> String {
+    let items: Vec<_> = strings.iter().map(|s| starlark_string(s)).collect();
+    format!("[{}]", items.join(", "))
+}
+
+/// The canonical repo of `label`, which all labels produced by evaluation have.
+pub(crate) fn canonical_repo(label: &Label<'_>) -> Result<CanonicalRepo<'static>, String> {
+    match &label.repo {
+        Repo::Canonical(r) => Ok(r.clone().into_owned()),
+        Repo::Apparent(r) => Err(format!("{label} is not in a canonical repo ({r})")),
+    }
+}
+
+impl QueryContext<'_> {
+    /// The targets matched by the universe's patterns, which like those of `build` may remove targets matched by
+    /// earlier ones with `-`, e.g. `//...,-//experimental/...`.
+    async fn universe(&self) -> Result<Vec<Label<'static>>, String> {
+        self.workspace
+            .expand_target_patterns(&self.universe)
+            .await
+            .map_err(|e| format!("{e:#}"))
+    }
+
+    /// The package of the target `label`.
+    pub(crate) async fn package(&self, label: &Label<'_>) -> Result<Arc<LoadedPackage>, String> {
+        let key = (canonical_repo(label)?, label.package().to_string());
+        if let Some(package) = self.graph.packages.lock().unwrap().get(&key) {
+            self.stats
+                .package_cache_hits
+                .fetch_add(1, Ordering::Relaxed);
+            return Ok(package.clone());
+        }
+        self.stats.packages_loaded.fetch_add(1, Ordering::Relaxed);
+        // Loaded without holding the lock so packages load concurrently; a package requested twice at once is
+        // evaluated twice, with the same result.
+        // With --keep_going, a package that fails to load is a placeholder, so the query carries on without its targets.
+        let package = match self.workspace.package_rules(&key.0, &key.1).await {
+            Ok((build_file_name, rules)) => LoadedPackage {
+                build_file_name,
+                rules,
+                error: None,
+            },
+            Err(e) => {
+                let error = format!("{e:#}");
+                self.workspace
+                    .defer_error(e)
+                    .map_err(|e| format!("{e:#}"))?;
+                LoadedPackage {
+                    build_file_name: String::new(),
+                    rules: HashMap::new(),
+                    error: Some(error),
+                }
+            }
+        };
+        let package = Arc::new(package);
+        self.graph
+            .packages
+            .lock()
+            .unwrap()
+            .insert(key, package.clone());
+        Ok(package)
+    }
+
+    /// Whether `label` is in a package that failed to load, which `--keep_going` leaves in the graph as a placeholder.
+    /// Only looks at the packages already loaded, as a placeholder is only made when loading, so that checking doesn't
+    /// count towards the package cache hits of `--experimental_query_stats`.
+    fn is_placeholder(&self, label: &Label<'_>) -> bool {
+        let Ok(repo) = canonical_repo(label) else {
+            return false;
+        };
+        let key = (repo, label.package().to_string());
+        self.graph
+            .packages
+            .lock()
+            .unwrap()
+            .get(&key)
+            .is_some_and(|package| package.error.is_some())
+    }
+
+    /// `labels` without those in packages that failed to load.
+    fn healthy(&self, mut labels: Vec<Label<'static>>) -> Vec<Label<'static>> {
+        labels.retain(|label| !self.is_placeholder(label));
+        labels
+    }
+
+    /// The packages that failed to load, as `@@repo//package`, sorted.
+    fn broken_packages(&self) -> Vec<String> {
+        let mut broken: Vec<_> = self
+            .graph
+            .packages
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, package)| package.error.is_some())
+            .map(|((repo, package), _)| format!("{repo}//{package}"))
+            .collect();
+        broken.sort();
+        broken
+    }
+
+    /// The kind of the target `label`, e.g. `genrule rule`. Targets that aren't rules or their outputs are taken to be
+    /// source files.
+    async fn kind(&self, label: &Label<'_>) -> Result<String, String> {
+        let package = self.package(label).await?;
+        Ok(match package.rules.get(label.name()) {
+            Some(rule) => format!("{} rule", rule.rule_class),
+            None if package.generating_rule(label.name()).is_some() => "generated file".to_string(),
+            None => "source file".to_string(),
+        })
+    }
+
+    /// Where `label` is declared, as `path:line:column`: the call declaring a rule, the call declaring the rule that
+    /// generates a file, or the start of a source file. Paths are absolute, with those in external repositories under
+    /// `--vendor_dir` or `<output_base>/external`.
+    async fn location(&self, label: &Label<'_>) -> Result<String, String> {
+        let package = self.package(label).await?;
+        let rule = package
+            .rules
+            .get(label.name())
+            .or_else(|| package.generating_rule(label.name()));
+        let in_package = |name: &str| match label.package() {
+            "" => name.to_string(),
+            dir => format!("{dir}/{name}"),
+        };
+        let location = match rule {
+            Some(rule) => rule
+                .location
+                .clone()
+                .unwrap_or_else(|| format!("{}:1:1", in_package(&package.build_file_name))),
+            None => format!("{}:1:1", in_package(label.name())),
+        };
+        let root = self
+            .workspace
+            .repository_dir(&canonical_repo(label)?)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(root.join(location).to_string_lossy().into_owned())
+    }
+
+    /// The BUILD file of the package of `label`.
+    async fn build_file(&self, label: &Label<'_>) -> Result<CanonicalLabel<'static>, String> {
+        let package = self.package(label).await?;
+        Ok(CanonicalLabel::new(
+            canonical_repo(label)?,
+            label.package().to_string(),
+            package.build_file_name.clone(),
+        ))
+    }
+
+    /// `label` as a build.proto `Target`: a rule with its attributes, with their labels resolved, a file one of the
+    /// rules of its package generates, or otherwise a source file. Each has its `location`, as `path:line:column`.
+    async fn target(&self, label: &Label<'_>) -> Result<build_proto::Target, String> {
+        let package = self.package(label).await?;
+        let location = Some(self.location(label).await?);
+        if let Some(rule) = package.rules.get(label.name()) {
+            let mut attribute = vec![build_proto::Attribute::new(
+                "name",
+                &AttrValue::String(rule.name.clone()),
+            )];
+            attribute.extend(
+                self.resolved_attributes(label, rule)
+                    .await?
+                    .iter()
+                    .map(|(name, value)| build_proto::Attribute::new(name, value)),
+            );
+            let outputs: Vec<_> = rule.outputs().collect();
+            return Ok(build_proto::Target::rule(build_proto::Rule {
+                name: label.to_string(),
+                rule_class: rule.rule_class.clone(),
+                location,
+                attribute,
+                rule_input: strings_of(self.direct_deps(label).await?),
+                rule_output: strings_of(self.resolve_labels(label, &outputs).await?),
+            }));
+        }
+        Ok(match package.generating_rule(label.name()) {
+            Some(rule) => {
+                let rule_label = self
+                    .resolve_labels(label, &[&format!(":{}", rule.name)])
+                    .await?;
+                build_proto::Target::generated_file(
+                    label.to_string(),
+                    rule_label[0].to_string(),
+                    location,
+                )
+            }
+            None => build_proto::Target::source_file(label.to_string(), location),
+        })
+    }
+
+    /// The attributes of `rule`, the target `label`, with the labels in them resolved, e.g. `:x` to `@@//pkg:x`.
+    async fn resolved_attributes<'r>(
+        &self,
+        label: &Label<'_>,
+        rule: &'r Rule,
+    ) -> Result<Vec<(&'r str, AttrValue)>, String> {
+        let mut attributes = Vec::new();
+        for (name, value) in &rule.attributes {
+            let value = match value {
+                AttrValue::String(s) if is_label_attribute(name) => AttrValue::String(
+                    self.resolve_labels(label, &[s.as_str()]).await?[0].to_string(),
+                ),
+                AttrValue::List(strings) if is_label_attribute(name) => {
+                    let strings: Vec<_> = strings.iter().map(String::as_str).collect();
+                    AttrValue::List(strings_of(self.resolve_labels(label, &strings).await?))
+                }
+                value => value.clone(),
+            };
+            attributes.push((name.as_str(), value));
+        }
+        Ok(attributes)
+    }
+
+    /// The rule `label` as `--output=build` prints it: a comment with where it is declared, as `path:line:column`, then
+    /// the rule with its labels resolved. `None` if `label` isn't a rule.
+    async fn build_syntax(&self, label: &Label<'_>) -> Result<Option<String>, String> {
+        let package = self.package(label).await?;
+        let Some(rule) = package.rules.get(label.name()) else {
+            return Ok(None);
+        };
+        let mut build = format!(
+            "# {}\n{}(\n  name = {},\n",
+            self.location(label).await?,
+            rule.rule_class,
+            starlark_string(&rule.name)
+        );
+        for (name, value) in self.resolved_attributes(label, rule).await? {
+            let value = match &value {
+                AttrValue::String(s) => starlark_string(s),
+                AttrValue::List(strings) => starlark_list(strings),
+                AttrValue::Dict(entries) => {
+                    let entries: Vec<_> = entries
+                        .iter()
+                        .map(|(k, v)| format!("{}: {}", starlark_string(k), starlark_string(v)))
+                        .collect();
+                    format!("{{{}}}", entries.join(", "))
+                }
+                AttrValue::Other(source) => source.clone(),
+            };
+            build += &format!("  {name} = {value},\n");
+        }
+        build += ")\n\n";
+        Ok(Some(build))
+    }
+
+    /// The targets `label` depends on directly.
+    async fn direct_deps(&self, label: &Label<'_>) -> Result<Vec<Label<'static>>, String> {
+        self.stats.targets_visited.fetch_add(1, Ordering::Relaxed);
+        let package = self
+            .package(label)
+            .await
+            .map_err(|e| format!("Failed to load {label}: {e}"))?;
+        match package.rules.get(label.name()) {
+            Some(rule) => {
+                let deps: Vec<_> = rule
+                    .deps()
+                    .filter(|(kind, _)| self.edges.follows(*kind))
+                    .map(|(_, dep)| dep)
+                    .collect();
+                self.resolve_labels(label, &deps).await
+            }
+            // An output file depends on the rule that generates it, a source file on nothing.
+            None => Ok(package
+                .generating_rule(label.name())
+                .map(|rule| {
+                    Label::new(label.repo().clone(), label.package(), &rule.name).into_owned()
+                })
+                .into_iter()
+                .collect()),
+        }
+    }
+
+    /// The targets of `labels`, as written in an attribute of the target `context`.
+    pub(crate) async fn resolve_labels(
+        &self,
+        context: &Label<'_>,
+        labels: &[&str],
+    ) -> Result<Vec<Label<'static>>, String> {
+        let repo = canonical_repo(context)?;
+        let repository = self
+            .workspace
+            .repository(&repo)
+            .await
+            .map_err(|e| format!("{e:#}"))?;
+        let relative_to = CanonicalLabel::new(repo, context.package(), context.name());
+        labels
+            .iter()
+            .map(|&label| -> Result<Label<'static>, String> {
+                let parsed = parse_label(label, &relative_to)
+                    .map_err(|e| format!("Invalid label {label:?} in {context}: {e}"))?;
+                let resolved = parsed
+                    .into_canonical(|r| self.workspace.resolve_repo(&repository, r))
+                    .ok_or_else(|| format!("{label} is not visible from {context}"))?;
+                Ok(from_canonical(resolved.into_owned()))
+            })
+            .collect()
+    }
+
+    /// Which packages may depend on the target `label`, see `visibility`.
+    async fn visibility(&self, label: &Label<'_>) -> Result<Visibility, String> {
+        let package = self.package(label).await?;
+        let rule = package
+            .rules
+            .get(label.name())
+            .or_else(|| package.generating_rule(label.name()));
+        let repo = label.repo.to_string();
+        Ok(rule.map_or_else(Visibility::public, |rule| {
+            Visibility::from_rule(rule, |l| absolute(&repo, label.package(), l))
+        }))
+    }
+
+    /// The targets in the package of `label`, itself included, as `:*` matches them.
+    async fn package_targets(&self, label: &Label<'_>) -> Result<Vec<Label<'static>>, String> {
+        let pattern = format!("{}//{}:*", label.repo, label.package());
+        expand_target_pattern(self.workspace.clone(), &pattern)
+            .map(|label| label.map(|l| l.into_owned()))
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect()
+    }
+
+    /// `roots` and the targets they depend on, up to `depth` edges away.
+    async fn transitive_deps(
+        &self,
+        roots: Vec<Label<'static>>,
+        depth: Option<i64>,
+    ) -> Result<Vec<Label<'static>>, String> {
+        let mut seen: HashSet<Label<'static>> = roots.iter().cloned().collect();
+        let mut result = roots.clone();
+        let mut frontier = roots;
+        let mut level = 0;
+        while !frontier.is_empty() && depth.is_none_or(|depth| level < depth) {
+            let deps = try_join_all(frontier.iter().map(|label| self.direct_deps(label))).await?;
+            frontier = deps
+                .into_iter()
+                .flatten()
+                .filter(|dep| seen.insert(dep.clone()))
+                .collect();
+            result.extend(frontier.iter().cloned());
+            level += 1;
+        }
+        Ok(result)
+    }
+
+    /// A shortest path from one of `from` to one of `to`, following dependencies, from start to end. Empty if there is
+    /// no such path.
+    async fn some_path(
+        &self,
+        from: Vec<Label<'static>>,
+        to: Vec<Label<'static>>,
+    ) -> Result<Vec<Label<'static>>, String> {
+        let ends: HashSet<Label<'static>> = to.into_iter().collect();
+        let mut parents: HashMap<Label<'static>, Option<Label<'static>>> =
+            from.iter().map(|label| (label.clone(), None)).collect();
+        let mut frontier = from;
+        while !frontier.is_empty() {
+            if let Some(end) = frontier.iter().find(|&label| ends.contains(label)) {
+                let mut path = vec![end.clone()];
+                while let Some(Some(parent)) = parents.get(path.last().expect("path is not empty"))
+                {
+                    path.push(parent.clone());
+                }
+                path.reverse();
+                return Ok(path);
+            }
+            let deps = try_join_all(frontier.iter().map(|label| self.direct_deps(label))).await?;
+            let mut next = Vec::new();
+            for (label, deps) in frontier.iter().zip(deps) {
+                for dep in deps {
+                    if !parents.contains_key(&dep) {
+                        parents.insert(dep.clone(), Some(label.clone()));
+                        next.push(dep);
+                    }
+                }
+            }
+            frontier = next;
+        }
+        Ok(Vec::new())
+    }
+
+    /// Every target on a path from one of `from` to one of `to`, in the order `deps(from)` visits them.
+    async fn all_paths(
+        &self,
+        from: Vec<Label<'static>>,
+        to: Vec<Label<'static>>,
+    ) -> Result<Vec<Label<'static>>, String> {
+        let reachable = self.transitive_deps(from.clone(), None).await?;
+        let on_path: HashSet<Label<'static>> = self
+            .reverse_deps(from, to, None)
+            .await?
+            .into_iter()
+            .collect();
+        Ok(reachable
+            .into_iter()
+            .filter(|label| on_path.contains(label))
+            .collect())
+    }
+
+    /// The targets in the transitive closure of `universe` that depend on any of `targets`, up to `depth` edges away,
+    /// including the targets themselves.
+    async fn reverse_deps(
+        &self,
+        universe: Vec<Label<'static>>,
+        targets: Vec<Label<'static>>,
+        depth: Option<i64>,
+    ) -> Result<Vec<Label<'static>>, String> {
+        let universe = self.transitive_deps(universe, None).await?;
+        let deps = try_join_all(universe.iter().map(|label| self.direct_deps(label))).await?;
+        let mut dependents: HashMap<&Label<'static>, Vec<&Label<'static>>> = HashMap::new();
+        for (label, deps) in universe.iter().zip(&deps) {
+            for dep in deps {
+                dependents.entry(dep).or_default().push(label);
+            }
+        }
+
+        let in_universe: HashSet<&Label<'static>> = universe.iter().collect();
+        let mut frontier: Vec<&Label<'static>> = targets
+            .iter()
+            .filter_map(|label| in_universe.get(label).copied())
+            .collect();
+        let mut seen: HashSet<&Label<'static>> = frontier.iter().copied().collect();
+        let mut result: Vec<Label<'static>> = frontier.iter().map(|&label| label.clone()).collect();
+        let mut level = 0;
+        while !frontier.is_empty() && depth.is_none_or(|depth| level < depth) {
+            frontier = frontier
+                .iter()
+                .flat_map(|label| dependents.get(label).into_iter().flatten().copied())
+                .filter(|&label| seen.insert(label))
+                .collect();
+            result.extend(frontier.iter().map(|&label| label.clone()));
+            level += 1;
+        }
+        Ok(result)
+    }
+}
+
+/// The targets matching the target pattern `s`. With `--keep_going`, packages that fail to load are passed to `defer_error`
+/// and the targets of the others still returned.
+fn expand_target_pattern(ws: Arc<Workspace>, s: &str) -> QueryStream<'_> {
+    let fut = async move {
+        match ws.parse_target_pattern(s) {
+            Ok(pattern) => ws
+                .expand_pattern(pattern)
+                .filter_map(move |res| {
+                    let result = match res {
+                        Ok(label) => Some(Ok(label)),
+                        Err(e) => ws.defer_error(e).err().map(|e| Err(format!("{e:#}"))),
+                    };
+                    std::future::ready(result)
+                })
+                .boxed(),
+            Err(e) => stream::once(async move { Err(format!("{e:#}")) }).boxed(),
+        }
+    };
+    stream::once(fut).flatten().boxed()
+}
+
+/// Evaluates `expr` to a set of labels, ordered as `order` says, for `--order_output` other than `no`.
+async fn evaluate_ordered<'a>(
+    expr: &Spanned<Expr<'a>>,
+    ctx: &QueryContext<'a>,
+    order: OrderOutput,
+) -> Result<Vec<Label<'static>>, String> {
+    let mut labels = evaluate(expr, ctx).await?;
+    if order == OrderOutput::Auto {
+        labels.sort_by_cached_key(ToString::to_string);
+        return Ok(labels);
+    }
+    let index: HashMap<_, _> = labels.iter().enumerate().map(|(i, l)| (l, i)).collect();
+    let mut deps = Vec::with_capacity(labels.len());
+    for label in &labels {
+        let direct = ctx.direct_deps(label).await?;
+        deps.push(
+            direct
+                .iter()
+                .filter_map(|dep| index.get(dep).copied())
+                .collect(),
+        );
+    }
+    let names: Option<Vec<_>> =
+        (order == OrderOutput::Full).then(|| labels.iter().map(ToString::to_string).collect());
+    Ok(topological_order(&deps, names.as_deref())
+        .into_iter()
+        .map(|i| labels[i].clone())
+        .collect())
+}
+
+/// Evaluates `expr` to a set of labels, without duplicates, in the order they were produced.
+async fn evaluate<'a>(
+    expr: &Spanned<Expr<'a>>,
+    ctx: &QueryContext<'a>,
+) -> Result<Vec<Label<'static>>, String> {
+    let mut seen = HashSet::new();
+    let mut labels = Vec::new();
+    let mut results = expr.inner.eval(ctx);
+    while let Some(label) = results.next().await {
+        let label = label?.into_owned();
+        if seen.insert(label.clone()) {
+            labels.push(label);
+        }
+    }
+    Ok(labels)
+}
+
+/// The word `expr`, which is `what`, rather than a target pattern.
+fn word_arg<'a>(expr: &Spanned<Expr<'a>>, what: &str) -> Result<&'a str, String> {
+    match expr.inner {
+        Expr::String(word) => Ok(word),
+        _ => Err(format!("Expected {what}")),
+    }
+}
+
+fn regex_arg(expr: &Spanned<Expr<'_>>) -> Result<Regex, String> {
+    // A number is parsed as one, but is a word too, as in `attr(shard_count, 4, expr)`.
+    let pattern = match expr.inner {
+        Expr::Int(n) => n.to_string(),
+        _ => word_arg(expr, "a regular expression")?.to_string(),
+    };
+    Regex::new(&pattern).map_err(|e| format!("Invalid regular expression {pattern:?}: {e}"))
+}
+
+fn depth_arg(expr: &Spanned<Expr<'_>>) -> Result<i64, String> {
+    match expr.inner {
+        Expr::Int(depth) if depth >= 0 => Ok(depth),
+        _ => Err("Expected a non-negative depth".to_string()),
+    }
+}
+
+/// The `.bzl` files each file loaded so far loads.
+fn load_graph(
+    workspace: &Workspace,
+) -> HashMap<CanonicalLabel<'static>, Vec<CanonicalLabel<'static>>> {
+    let mut graph: HashMap<_, Vec<_>> = HashMap::new();
+    for (from, to) in workspace.load_graph() {
+        graph.entry(from).or_default().push(to);
+    }
+    for loads in graph.values_mut() {
+        loads.sort_by_key(ToString::to_string);
+    }
+    graph
+}
+
+/// Evaluates the query function `name` applied to `args`.
+async fn function<'a>(
+    ctx: &QueryContext<'a>,
+    name: &str,
+    args: &[Spanned<Expr<'a>>],
+) -> Result<Vec<Label<'static>>, String> {
+    match (name, args) {
+        ("set", patterns) => {
+            let mut labels = Vec::new();
+            for pattern in patterns {
+                labels.extend(evaluate(pattern, ctx).await?);
+            }
+            Ok(labels)
+        }
+        ("deps", [x]) => ctx.transitive_deps(evaluate(x, ctx).await?, None).await,
+        ("deps", [x, depth]) => {
+            let depth = depth_arg(depth)?;
+            ctx.transitive_deps(evaluate(x, ctx).await?, Some(depth))
+                .await
+        }
+        ("rdeps", [universe, x]) => {
+            ctx.reverse_deps(
+                evaluate(universe, ctx).await?,
+                evaluate(x, ctx).await?,
+                None,
+            )
+            .await
+        }
+        ("rdeps", [universe, x, depth]) => {
+            let depth = depth_arg(depth)?;
+            ctx.reverse_deps(
+                evaluate(universe, ctx).await?,
+                evaluate(x, ctx).await?,
+                Some(depth),
+            )
+            .await
+        }
+        ("allrdeps", [x]) => {
+            ctx.reverse_deps(ctx.universe().await?, evaluate(x, ctx).await?, None)
+                .await
+        }
+        ("allrdeps", [x, depth]) => {
+            let depth = depth_arg(depth)?;
+            ctx.reverse_deps(ctx.universe().await?, evaluate(x, ctx).await?, Some(depth))
+                .await
+        }
+        ("somepath", [from, to]) => {
+            ctx.some_path(evaluate(from, ctx).await?, evaluate(to, ctx).await?)
+                .await
+        }
+        ("allpaths", [from, to]) => {
+            ctx.all_paths(evaluate(from, ctx).await?, evaluate(to, ctx).await?)
+                .await
+        }
+        ("kind", [pattern, x]) => {
+            let pattern = regex_arg(pattern)?;
+            let mut matching = Vec::new();
+            for label in evaluate(x, ctx).await? {
+                if pattern.is_match(&ctx.kind(&label).await?) {
+                    matching.push(label);
+                }
+            }
+            Ok(matching)
+        }
+        ("filter", [pattern, x]) => {
+            let pattern = regex_arg(pattern)?;
+            let mut labels = evaluate(x, ctx).await?;
+            labels.retain(|label| pattern.is_match(&label.to_string()));
+            Ok(labels)
+        }
+        ("attr", [attr, pattern, x]) => {
+            let attr = word_arg(attr, "an attribute name")?;
+            let pattern = regex_arg(pattern)?;
+            let mut matching = Vec::new();
+            for label in evaluate(x, ctx).await? {
+                let package = ctx.package(&label).await?;
+                let Some(rule) = package.rules.get(label.name()) else {
+                    continue;
+                };
+                let value = match attr {
+                    "name" => Some(rule.name.clone()),
+                    attr => rule.attributes.get(attr).map(ToString::to_string),
+                };
+                if value.is_some_and(|value| pattern.is_match(&value)) {
+                    matching.push(label);
+                }
+            }
+            Ok(matching)
+        }
+        ("labels", [attr, x]) => {
+            let attr = word_arg(attr, "an attribute name")?;
+            let mut seen = HashSet::new();
+            let mut labels = Vec::new();
+            for label in evaluate(x, ctx).await? {
+                let package = ctx.package(&label).await?;
+                let Some(value) = package
+                    .rules
+                    .get(label.name())
+                    .and_then(|rule| rule.attributes.get(attr))
+                else {
+                    continue;
+                };
+                let written: Vec<&str> = value.strings().iter().map(String::as_str).collect();
+                for resolved in ctx.resolve_labels(&label, &written).await? {
+                    if seen.insert(resolved.clone()) {
+                        labels.push(resolved);
+                    }
+                }
+            }
+            Ok(labels)
+        }
+        ("visible", [predicate, x]) => {
+            let packages: HashSet<String> = evaluate(predicate, ctx)
+                .await?
+                .iter()
+                .map(|label| format!("{}//{}", label.repo, label.package()))
+                .collect();
+            let mut visible = Vec::new();
+            for label in evaluate(x, ctx).await? {
+                let own = format!("{}//{}", label.repo, label.package());
+                let visibility = ctx.visibility(&label).await?;
+                if packages
+                    .iter()
+                    .all(|package| *package == own || visibility.allows(package))
+                {
+                    visible.push(label);
+                }
+            }
+            Ok(visible)
+        }
+        ("siblings", [x]) => {
+            let mut packages = HashSet::new();
+            let mut siblings = Vec::new();
+            for label in evaluate(x, ctx).await? {
+                if packages.insert((label.repo.clone(), label.package().to_string())) {
+                    siblings.extend(ctx.package_targets(&label).await?);
+                }
+            }
+            Ok(siblings)
+        }
+        ("same_pkg_direct_rdeps", [x]) => {
+            let targets = evaluate(x, ctx).await?;
+            let wanted: HashSet<&Label<'static>> = targets.iter().collect();
+            let mut packages = HashSet::new();
+            let mut rdeps = Vec::new();
+            for label in &targets {
+                if !packages.insert((label.repo.clone(), label.package().to_string())) {
+                    continue;
+                }
+                for sibling in ctx.package_targets(label).await? {
+                    let deps = ctx.direct_deps(&sibling).await?;
+                    if deps.iter().any(|dep| {
+                        wanted.contains(dep)
+                            && dep.repo == sibling.repo
+                            && dep.package() == sibling.package()
+                    }) {
+                        rdeps.push(sibling);
+                    }
+                }
+            }
+            Ok(rdeps)
+        }
+        ("tests", [x]) => {
+            let mut tests = Vec::new();
+            for label in evaluate(x, ctx).await? {
+                let package = ctx.package(&label).await?;
+                if package
+                    .rules
+                    .get(label.name())
+                    .is_some_and(|rule| rule.rule_class.ends_with("_test"))
+                {
+                    tests.push(label);
+                }
+            }
+            Ok(tests)
+        }
+        ("buildfiles", [x]) => {
+            let mut build_files = Vec::new();
+            for label in evaluate(x, ctx).await? {
+                let build_file = ctx.build_file(&label).await?;
+                if !build_files.contains(&build_file) {
+                    build_files.push(build_file);
+                }
+            }
+            let graph = load_graph(&ctx.workspace);
+            Ok(load_closure(&graph, build_files)
+                .into_iter()
+                .map(from_canonical)
+                .collect())
+        }
+        ("rbuildfiles", paths @ [_, ..]) => {
+            let paths = paths
+                .iter()
+                .map(|path| word_arg(path, "a path"))
+                .collect::<Result<HashSet<_>, _>>()?;
+            let universe = ctx.transitive_deps(ctx.universe().await?, None).await?;
+            let mut build_files = Vec::new();
+            for label in &universe {
+                let build_file = ctx.build_file(label).await?;
+                if !build_files.contains(&build_file) {
+                    build_files.push(build_file);
+                }
+            }
+            let graph = load_graph(&ctx.workspace);
+            Ok(build_files
+                .into_iter()
+                .filter(|build_file| {
+                    load_closure(&graph, vec![build_file.clone()])
+                        .iter()
+                        .filter_map(workspace_path)
+                        .any(|path| paths.contains(path.as_str()))
+                })
+                .map(from_canonical)
+                .collect())
+        }
+        (
+            "deps" | "rdeps" | "allrdeps" | "somepath" | "allpaths" | "kind" | "filter" | "attr"
+            | "labels" | "tests" | "buildfiles" | "rbuildfiles",
+            _,
+        ) => Err(format!(
+            "Wrong number of arguments to {name}({})",
+            args.len()
+        )),
+        _ => Err(format!("Unknown query function {name}")),
+    }
+}
+
+/// Turns the result of evaluating a function or set operation into a stream.
+fn labels_stream<'a>(
+    labels: impl Future<Output = Result<Vec<Label<'static>>, String>> + Send + 'a,
+) -> QueryStream<'a> {
+    stream::once(labels)
+        .flat_map(|result| match result {
+            Ok(labels) => stream::iter(
+                labels
+                    .into_iter()
+                    .map(|label| -> QueryResult<'a> { Ok(label) }),
+            )
+            .boxed(),
+            Err(e) => stream::once(async move { Err(e) }).boxed(),
+        })
+        .boxed()
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Expr<'a> {
     String(&'a str),
@@ -126,8 +1120,25 @@ pub fn parser<'a>() -> impl Parser<'a, &'a str, Spanned<Expr<'a>>, extra::Err<Ri
 
         let pattern = unquoted_word.map(Expr::String);
 
+        // A set of target patterns separated by spaces, e.g. `set(//foo //bar:baz)`, as written by `--output=label`.
+        let set = text::keyword("set")
+            .ignore_then(
+                quoted_string
+                    .or(pattern)
+                    .map_with(|ast, e| Spanned {
+                        inner: ast,
+                        span: e.span(),
+                    })
+                    .padded()
+                    .repeated()
+                    .collect::<Vec<_>>()
+                    .delimited_by(just('(').padded(), just(')')),
+            )
+            .map(|words| Expr::Function("set", words));
+
         let atom = choice((
             paren_expr,
+            set,
             function,
             let_binding,
             variable,
@@ -140,72 +1151,72 @@ pub fn parser<'a>() -> impl Parser<'a, &'a str, Spanned<Expr<'a>>, extra::Err<Ri
             span: e.span(),
         });
 
-        // Intersect operator has higher precedence
-        let op_intersect = choice((just("^"), text::keyword("intersect")))
-            .padded()
-            .to(SetOp::Intersect);
-
-        let intersection =
-            atom.clone()
-                .foldl(op_intersect.then(atom).repeated(), |left, (op, right)| {
-                    let span = SimpleSpan::from(left.span.start..right.span.end);
-                    Spanned {
-                        inner: Expr::SetOp(op, Box::new(left), Box::new(right)),
-                        span,
-                    }
-                });
-
-        // Union / Difference have lower precedence
-        let op_union = choice((just("+"), text::keyword("union")))
-            .padded()
-            .to(SetOp::Union);
-
-        let op_diff = choice((just("-"), text::keyword("except")))
-            .padded()
-            .to(SetOp::Difference);
+        // The set operators all have the same precedence, and associate to the left, as in Bazel: `x ^ y + z` is
+        // `(x ^ y) + z`.
+        let set_operator = choice((
+            choice((just("^"), text::keyword("intersect"))).to(SetOp::Intersect),
+            choice((just("+"), text::keyword("union"))).to(SetOp::Union),
+            choice((just("-"), text::keyword("except"))).to(SetOp::Difference),
+        ))
+        .padded();
 
-        let set_op = intersection.clone().foldl(
-            choice((op_union, op_diff)).then(intersection).repeated(),
-            |left, (op, right)| {
+        let set_op = atom
+            .clone()
+            .foldl(set_operator.then(atom).repeated(), |left, (op, right)| {
                 let span = SimpleSpan::from(left.span.start..right.span.end);
                 Spanned {
                     inner: Expr::SetOp(op, Box::new(left), Box::new(right)),
                     span,
                 }
-            },
-        );
+            });
 
         set_op
     })
 }
 
 impl<'a> Expr<'a> {
-    pub fn eval(&self, ctx: &QueryContext<'a>) -> QueryStream<'a> {
+    /// The target patterns written in this expression, leaving out the regular expressions, attribute names and paths
+    /// given to functions.
+    pub fn target_literals(&self) -> Vec<&'a str> {
         match self {
-            &Expr::String(s) => {
-                let ws = ctx.workspace.clone();
-                let fut = async move {
-                    match crate::bazel::label::parse_target_pattern(s, &MAIN_REPO_ROOT) {
-                        Ok(pattern) => ws
-                            .expand_pattern(pattern)
-                            .map(|res| match res {
-                                Ok(label) => Ok(label),
-                                Err(e) => Err(e.to_string()),
-                            })
-                            .boxed(),
-                        Err(e) => stream::once(async move { Err(e.to_string()) }).boxed(),
-                    }
+            &Expr::String(s) => vec![s],
+            Expr::Int(_) | Expr::Variable(_) => Vec::new(),
+            Expr::Function(name, args) => {
+                let words = match *name {
+                    "attr" => 2,
+                    "kind" | "filter" | "labels" => 1,
+                    "rbuildfiles" => args.len(),
+                    _ => 0,
                 };
-                stream::once(fut).flatten().boxed()
+                args.iter()
+                    .skip(words)
+                    .flat_map(|arg| arg.inner.target_literals())
+                    .collect()
             }
+            Expr::SetOp(_, left, right) | Expr::Let(_, left, right) => {
+                let mut literals = left.inner.target_literals();
+                literals.extend(right.inner.target_literals());
+                literals
+            }
+        }
+    }
+
+    pub fn eval(&self, ctx: &QueryContext<'a>) -> QueryStream<'a> {
+        match self {
+            &Expr::String(s) => expand_target_pattern(ctx.workspace.clone(), s),
             Expr::Int(_) => {
                 stream::once(async { Err("Int not supported out of function context".to_string()) })
                     .boxed()
             }
-            Expr::Function(name, _args) => {
-                // Return an empty or unimplemented error stream for now
-                let err_msg = format!("Function {} not fully implemented", name);
-                stream::once(async move { Err(err_msg) }).boxed()
+            Expr::Function(name, args) => {
+                let ctx = ctx.clone();
+                let (name, args) = (*name, args.clone());
+                labels_stream(async move {
+                    let start = Instant::now();
+                    let result = function(&ctx, name, &args).await;
+                    ctx.stats.record_function(name, start.elapsed());
+                    result
+                })
             }
             Expr::Let(name, val, body) => {
                 // Evaluate the let value stream
@@ -254,54 +1265,529 @@ impl<'a> Expr<'a> {
 
                 l_mapped.chain(r_filtered).boxed()
             }
-            Expr::SetOp(SetOp::Intersect, _, _) => {
-                // Intersect requires knowing contents of right.
-                stream::once(async move { Err("Intersect not yet implemented".to_string()) })
-                    .boxed()
+            Expr::SetOp(op @ (SetOp::Intersect | SetOp::Difference), left, right) => {
+                // Both need all of the right-hand side before anything on the left can be decided.
+                let ctx = ctx.clone();
+                let (op, left, right) = (*op, left.clone(), right.clone());
+                labels_stream(async move {
+                    let right: HashSet<_> = evaluate(&right, &ctx).await?.into_iter().collect();
+                    let mut labels = evaluate(&left, &ctx).await?;
+                    labels.retain(|label| right.contains(label) == (op == SetOp::Intersect));
+                    Ok(labels)
+                })
             }
-            Expr::SetOp(SetOp::Difference, _, _) => {
-                stream::once(async move { Err("Difference not yet implemented".to_string()) })
-                    .boxed()
+        }
+    }
+}
+
+/// The value of `--output`: how query results are printed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Output {
+    /// One label per line, as each is found.
+    #[default]
+    Label,
+    /// A GraphViz DOT graph of the results and the dependencies between them.
+    Graph,
+    /// A `QueryResult` message of Bazel's build.proto, with every target.
+    Proto,
+    /// Each target as a build.proto `Target` message in JSON, one per line.
+    #[value(name = "streamed_jsonproto")]
+    StreamedJsonproto,
+    /// The targets as an XML document, in the schema of Bazel's `--output=xml`.
+    Xml,
+    /// The rules as they'd be declared in a BUILD file, with labels resolved, after macros have run.
+    Build,
+    /// Each label with its kind, e.g. `genrule rule //:a`.
+    #[value(name = "label_kind")]
+    LabelKind,
+    /// Each label with its kind and where it's declared, as `path:line:column`, for editors to jump to.
+    Location,
+}
+
+/// The value of `--order_output`: the order query results are printed in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OrderOutput {
+    /// As the query produces them, printing each as soon as it's found.
+    No,
+    /// Each target before its dependencies, and otherwise as the query produces them.
+    Deps,
+    /// Sorted by label, except with `--output=graph`, which is ordered as with `full`.
+    #[default]
+    Auto,
+    /// Each target before its dependencies, and otherwise by label, so the order only depends on the graph.
+    Full,
+}
+
+impl OrderOutput {
+    /// The order to print results in as `output`.
+    fn for_output(self, output: Output) -> Self {
+        match (self, output) {
+            (OrderOutput::Auto, Output::Graph) => OrderOutput::Full,
+            (order, _) => order,
+        }
+    }
+}
+
+/// A topological order of the nodes `0..deps.len()`, where `deps[i]` are the nodes `i` depends on: each node comes
+/// before its dependencies, except within cycles, and otherwise in order, or in order of `names` if given.
+///
+/// This is the reverse of the order in which a depth first search finishes with the nodes, starting from each in turn
+/// and following dependencies, both last first.
+fn topological_order(deps: &[Vec<usize>], names: Option<&[String]>) -> Vec<usize> {
+    let by_name = |mut nodes: Vec<usize>| {
+        if let Some(names) = names {
+            nodes.sort_by(|a, b| names[*a].cmp(&names[*b]));
+        }
+        nodes
+    };
+    let mut visited = vec![false; deps.len()];
+    let mut finished = Vec::with_capacity(deps.len());
+    for start in by_name((0..deps.len()).collect()).into_iter().rev() {
+        if visited[start] {
+            continue;
+        }
+        visited[start] = true;
+        // The nodes being visited, with the dependencies of each left to visit.
+        let mut stack = vec![(start, by_name(deps[start].clone()))];
+        while let Some((node, remaining)) = stack.last_mut() {
+            match remaining.pop() {
+                Some(dep) if !visited[dep] => {
+                    visited[dep] = true;
+                    stack.push((dep, by_name(deps[dep].clone())));
+                }
+                Some(_) => {}
+                None => {
+                    finished.push(*node);
+                    stack.pop();
+                }
             }
         }
     }
+    finished.reverse();
+    finished
+}
+
+/// Checks that two evaluations of the same query, `first` and `second`, printed the same, for
+/// `--experimental_check_determinism`.
+pub fn check_deterministic(first: &[u8], second: &[u8]) -> anyhow::Result<()> {
+    if first == second {
+        return Ok(());
+    }
+    let mut first_lines = first.split(|b| *b == b'\n');
+    let mut second_lines = second.split(|b| *b == b'\n');
+    let mut line = 1;
+    let difference = loop {
+        match (first_lines.next(), second_lines.next()) {
+            (Some(a), Some(b)) if a == b => line += 1,
+            (a, b) => {
+                let show = |l: Option<&[u8]>| {
+                    l.map_or("end of output".to_string(), |l| {
+                        format!("{:?}", String::from_utf8_lossy(l))
+                    })
+                };
+                break format!("line {line} is {} then {}", show(a), show(b));
+            }
+        }
+    };
+    Err(AnalysisError::Nondeterministic {
+        what: "Query output".to_string(),
+        difference,
+    })
+    .exit_code(ExitCode::AnalysisFailure)
 }
 
-pub async fn query<W>(out: &mut W, _config: Arc<Configuration>, query: &str) -> anyhow::Result<()>
+/// Parses the query expression `query`.
+pub(crate) fn parse(query: &str) -> anyhow::Result<Spanned<Expr<'_>>> {
+    parser()
+        .parse(query)
+        .into_result()
+        .map_err(|errs| {
+            anyhow::anyhow!(
+                "Failed to parse query: {}\nSee https://bazel.build/reference/query for syntax",
+                errs.into_iter()
+                    .map(|e| e.to_string())
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            )
+        })
+        .exit_code(ExitCode::CommandLineError)
+}
+
+/// The targets `query` matches, sorted by label, for commands that print more about each of them than `query` does.
+pub async fn evaluate_labels(
+    workspace: Arc<Workspace>,
+    query: &str,
+    universe: Universe,
+    edges: Edges,
+) -> anyhow::Result<Vec<Label<'static>>> {
+    evaluate_ast(workspace, &parse(query)?, universe, edges).await
+}
+
+/// The targets the parsed query `ast` matches, in order.
+pub async fn evaluate_ast(
+    workspace: Arc<Workspace>,
+    ast: &Spanned<Expr<'_>>,
+    universe: Universe,
+    edges: Edges,
+) -> anyhow::Result<Vec<Label<'static>>> {
+    let ctx = QueryContext::new(workspace, universe.patterns(ast), edges);
+    evaluate_ordered(ast, &ctx, OrderOutput::Auto)
+        .await
+        .map_err(|reason| AnalysisError::QueryFailed { reason })
+        .exit_code(ExitCode::AnalysisFailure)
+}
+
+/// The targets `labels` depend on, themselves included, in order, each with the package it belongs to. Every package
+/// is loaded once, by the query that follows the dependencies.
+pub(crate) async fn deps_closure(
+    workspace: Arc<Workspace>,
+    labels: &[Label<'static>],
+) -> anyhow::Result<Vec<(Label<'static>, Arc<LoadedPackage>)>> {
+    let targets: Vec<_> = labels.iter().map(ToString::to_string).collect();
+    let quoted: Vec<_> = targets.iter().map(|target| format!("'{target}'")).collect();
+    let query = format!("deps(set({}))", quoted.join(" "));
+    let ast = parse(&query)?;
+    let ctx = QueryContext::new(workspace, targets, Edges::default());
+    let failed =
+        |reason| Err(AnalysisError::QueryFailed { reason }).exit_code(ExitCode::AnalysisFailure);
+    let closure = match evaluate_ordered(&ast, &ctx, OrderOutput::Auto).await {
+        Ok(closure) => closure,
+        Err(reason) => return failed(reason),
+    };
+    let mut packages = Vec::with_capacity(closure.len());
+    for label in closure {
+        match ctx.package(&label).await {
+            Ok(package) => packages.push((label, package)),
+            Err(reason) => return failed(reason),
+        }
+    }
+    Ok(packages)
+}
+
+/// The kind of each of `labels`, as `--output=label_kind` prints it, e.g. `genrule rule` or `source file`. Every
+/// package is loaded once.
+pub(crate) async fn target_kinds(
+    workspace: Arc<Workspace>,
+    labels: &[Label<'static>],
+) -> anyhow::Result<Vec<String>> {
+    let ctx = QueryContext::new(workspace, Vec::new(), Edges::default());
+    let mut kinds = Vec::with_capacity(labels.len());
+    for label in labels {
+        kinds.push(ctx.kind(label).await.map_err(anyhow::Error::msg)?);
+    }
+    Ok(kinds)
+}
+
+/// Prints the targets `query` matches as `output`, and returns what evaluating it took.
+pub async fn query<W>(
+    out: &mut W,
+    workspace: Arc<Workspace>,
+    query: &str,
+    universe: Universe,
+    edges: Edges,
+    order: OrderOutput,
+    output: Output,
+) -> anyhow::Result<Arc<QueryStats>>
 where
     W: AsyncWrite + Unpin,
 {
-    let workspace = Workspace::new(".").await?;
-
     // Construct repos from bzlmod declarations
     // Global Map of Canonical name -> FusedFuture<dyn Repo>
     // Each repo (including _main) needs a Map of repo name -> Canonical name
 
-    let ast = parser().parse(query).into_result().map_err(|errs| {
-        anyhow::anyhow!(
-            "Failed to parse query: {}\nSee https://bazel.build/reference/query for syntax",
-            errs.into_iter()
-                .map(|e| e.to_string())
-                .collect::<Vec<_>>()
-                .join("\n")
-        )
-    })?;
+    let ast = parse(query)?;
+    let universe = universe.patterns(&ast);
 
-    // Evaluate the query!
-    let mut result_stream = ast.inner.eval(&QueryContext::new(workspace.clone()));
+    let failed = |reason: String| {
+        Err(AnalysisError::QueryFailed { reason }).exit_code(ExitCode::AnalysisFailure)
+    };
+    let ctx = QueryContext::new(workspace.clone(), universe, edges);
+    // Unless the results are printed as they're found, they're all found first and put in order.
+    let ordered = match order.for_output(output) {
+        OrderOutput::No => None,
+        order => match evaluate_ordered(&ast, &ctx, order).await {
+            Ok(labels) => Some(ctx.healthy(labels)),
+            Err(e) => return failed(e),
+        },
+    };
+    // Targets of packages that failed to load are left out, and the packages listed at the end.
+    let results = || match &ordered {
+        Some(labels) => labels_stream(std::future::ready(Ok(labels.clone()))),
+        None => ast
+            .inner
+            .eval(&ctx)
+            .filter_map(|result| async {
+                match result {
+                    Ok(label) if ctx.is_placeholder(&label) => None,
+                    result => Some(result),
+                }
+            })
+            .boxed(),
+    };
+    let all = || async {
+        match &ordered {
+            Some(labels) => Ok(labels.clone()),
+            None => Ok(ctx.healthy(evaluate(&ast, &ctx).await?)),
+        }
+    };
+    match output {
+        Output::Label => {
+            // Evaluate the query!
+            let mut result_stream = results();
 
-    while let Some(res) = result_stream.next().await {
-        match res {
-            Ok(label) => {
-                out.write_all(format!("{}\n", label).as_bytes()).await?;
+            while let Some(res) = result_stream.next().await {
+                match res {
+                    Ok(label) => {
+                        out.write_all(format!("{}\n", label).as_bytes()).await?;
+                    }
+                    Err(e) => return failed(e),
+                }
             }
-            Err(e) => {
-                return Err(anyhow::anyhow!("Query evaluation error: {}", e));
+        }
+        Output::Graph => {
+            let labels = match all().await {
+                Ok(labels) => labels,
+                Err(e) => return failed(e),
+            };
+            let index: HashMap<_, _> = labels.iter().enumerate().map(|(i, l)| (l, i)).collect();
+            let mut edges = Vec::new();
+            for (i, label) in labels.iter().enumerate() {
+                let deps = match ctx.direct_deps(label).await {
+                    Ok(deps) => deps,
+                    Err(e) => return failed(e),
+                };
+                edges.extend(deps.iter().filter_map(|dep| Some((i, *index.get(dep)?))));
+            }
+            out.write_all(dot(&labels, &edges).as_bytes()).await?;
+        }
+        Output::Proto | Output::Xml => {
+            let labels = match all().await {
+                Ok(labels) => labels,
+                Err(e) => return failed(e),
+            };
+            let mut result = build_proto::QueryResult::default();
+            for label in &labels {
+                match ctx.target(label).await {
+                    Ok(target) => result.target.push(target),
+                    Err(e) => return failed(e),
+                }
+            }
+            if output == Output::Proto {
+                out.write_all(&result.encode_to_vec()).await?;
+            } else {
+                out.write_all(xml(&result.target).as_bytes()).await?;
+            }
+        }
+        Output::StreamedJsonproto => {
+            let mut result_stream = results();
+            while let Some(res) = result_stream.next().await {
+                match res {
+                    Ok(label) => match ctx.target(&label).await {
+                        Ok(target) => out.write_all(target.json_line().as_bytes()).await?,
+                        Err(e) => return failed(e),
+                    },
+                    Err(e) => return failed(e),
+                }
             }
         }
+        Output::LabelKind | Output::Location => {
+            let mut result_stream = results();
+            while let Some(res) = result_stream.next().await {
+                let label = match res {
+                    Ok(label) => label,
+                    Err(e) => return failed(e),
+                };
+                let kind = match ctx.kind(&label).await {
+                    Ok(kind) => kind,
+                    Err(e) => return failed(e),
+                };
+                let line = if output == Output::Location {
+                    match ctx.location(&label).await {
+                        Ok(location) => format!("{location}: {kind} {label}\n"),
+                        Err(e) => return failed(e),
+                    }
+                } else {
+                    format!("{kind} {label}\n")
+                };
+                out.write_all(line.as_bytes()).await?;
+            }
+        }
+        Output::Build => {
+            let mut result_stream = results();
+            while let Some(res) = result_stream.next().await {
+                match res {
+                    Ok(label) => match ctx.build_syntax(&label).await {
+                        Ok(Some(rule)) => out.write_all(rule.as_bytes()).await?,
+                        Ok(None) => {}
+                        Err(e) => return failed(e),
+                    },
+                    Err(e) => return failed(e),
+                }
+            }
+        }
+    }
+
+    let broken = ctx.broken_packages();
+    if !broken.is_empty() {
+        log::error!(
+            "Targets of these packages are missing from the result, as they failed to load:\n  {}",
+            broken.join("\n  ")
+        );
+    }
+    workspace
+        .check_deferred_errors()
+        .map_err(|e| e.context("The query result is partial, as some packages failed to load"))
+        .exit_code(ExitCode::PartialAnalysisFailure)?;
+    Ok(ctx.stats.clone())
+}
+
+/// `targets` as an XML document, laid out as Bazel's `--output=xml` lays them out, for tools that parse it. The
+/// targets are those `--output=proto` prints, see `QueryContext::target`, so they have the same resolved labels and
+/// locations.
+///
+/// Attributes razel only has as Starlark source, such as a `select()`, are left out.
+fn xml(targets: &[build_proto::Target]) -> String {
+    use build_proto::AttributeType;
+
+    fn escape(s: &str) -> String {
+        s.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+            .replace('\'', "&apos;")
+    }
+    fn location(location: &Option<String>) -> String {
+        location
+            .as_ref()
+            .map_or_else(String::new, |l| format!(" location=\"{}\"", escape(l)))
+    }
+
+    let mut xml = String::from(
+        "<?xml version=\"1.1\" encoding=\"UTF-8\" standalone=\"no\"?>\n<query version=\"2\">\n",
+    );
+    for target in targets {
+        if let Some(rule) = &target.rule {
+            xml += &format!(
+                "    <rule class=\"{}\"{} name=\"{}\">\n",
+                escape(&rule.rule_class),
+                location(&rule.location),
+                escape(&rule.name)
+            );
+            for attribute in &rule.attribute {
+                let name = escape(&attribute.name);
+                let (element, list) = match AttributeType::try_from(attribute.r#type) {
+                    Ok(AttributeType::String) => ("string", false),
+                    Ok(AttributeType::Label) => ("label", false),
+                    Ok(AttributeType::Output) => ("output", false),
+                    Ok(AttributeType::StringList) => ("string", true),
+                    Ok(AttributeType::LabelList) => ("label", true),
+                    Ok(AttributeType::OutputList) => ("output", true),
+                    Ok(AttributeType::Integer) => {
+                        let value = attribute.int_value.unwrap_or_default();
+                        xml += &format!("        <int name=\"{name}\" value=\"{value}\"/>\n");
+                        continue;
+                    }
+                    Ok(AttributeType::Boolean) => {
+                        let value = attribute.boolean_value.unwrap_or_default();
+                        xml += &format!("        <boolean name=\"{name}\" value=\"{value}\"/>\n");
+                        continue;
+                    }
+                    Ok(AttributeType::StringDict) => {
+                        xml += &format!("        <dict name=\"{name}\">\n");
+                        for entry in &attribute.string_dict_value {
+                            xml += &format!(
+                                "            <pair>\n                <string value=\"{}\"/>\n                \
+                                 <string value=\"{}\"/>\n            </pair>\n",
+                                escape(&entry.key),
+                                escape(&entry.value)
+                            );
+                        }
+                        xml += "        </dict>\n";
+                        continue;
+                    }
+                    Ok(AttributeType::Unknown) | Err(_) => continue,
+                };
+                if list {
+                    xml += &format!("        <list name=\"{name}\">\n");
+                    for value in &attribute.string_list_value {
+                        xml += &format!("            <{element} value=\"{}\"/>\n", escape(value));
+                    }
+                    xml += "        </list>\n";
+                } else {
+                    let value = attribute.string_value.as_deref().unwrap_or_default();
+                    xml += &format!(
+                        "        <{element} name=\"{name}\" value=\"{}\"/>\n",
+                        escape(value)
+                    );
+                }
+            }
+            for input in &rule.rule_input {
+                xml += &format!("        <rule-input name=\"{}\"/>\n", escape(input));
+            }
+            for output in &rule.rule_output {
+                xml += &format!("        <rule-output name=\"{}\"/>\n", escape(output));
+            }
+            xml += "    </rule>\n";
+        } else if let Some(file) = &target.generated_file {
+            xml += &format!(
+                "    <generated-file generating-rule=\"{}\"{} name=\"{}\"/>\n",
+                escape(&file.generating_rule),
+                location(&file.location),
+                escape(&file.name)
+            );
+        } else if let Some(file) = &target.source_file {
+            xml += &format!(
+                "    <source-file{} name=\"{}\"/>\n",
+                location(&file.location),
+                escape(&file.name)
+            );
+        }
+    }
+    xml += "</query>\n";
+    xml
+}
+
+/// `labels` and the `edges` between them, as indices into `labels`, as a GraphViz DOT graph.
+///
+/// Nodes with the same dependencies and dependents are factored into one node listing all their labels, as Bazel's
+/// `--output=graph` does, which keeps e.g. the many source files of a library from swamping the graph.
+fn dot(labels: &[Label<'_>], edges: &[(usize, usize)]) -> String {
+    let mut deps = vec![BTreeSet::new(); labels.len()];
+    let mut rdeps = vec![BTreeSet::new(); labels.len()];
+    for &(from, to) in edges {
+        deps[from].insert(to);
+        rdeps[to].insert(from);
+    }
+
+    // The nodes, in order of their first label, and which node each label went in.
+    let mut nodes: Vec<Vec<usize>> = Vec::new();
+    let mut node_of = vec![0; labels.len()];
+    let mut by_neighbours = HashMap::new();
+    for i in 0..labels.len() {
+        let node = *by_neighbours
+            .entry((&deps[i], &rdeps[i]))
+            .or_insert_with(|| {
+                nodes.push(Vec::new());
+                nodes.len() - 1
+            });
+        nodes[node].push(i);
+        node_of[i] = node;
     }
 
-    Ok(())
+    let name = |node: &[usize]| {
+        let labels: Vec<_> = node.iter().map(|&i| labels[i].to_string()).collect();
+        format!("\"{}\"", labels.join("\\n"))
+    };
+    let mut text = "digraph mygraph {\n  node [shape=box];\n".to_string();
+    for node in &nodes {
+        text.push_str(&format!("  {}\n", name(node)));
+        let targets: BTreeSet<_> = deps[node[0]].iter().map(|&dep| node_of[dep]).collect();
+        for target in targets {
+            text.push_str(&format!("  {} -> {}\n", name(node), name(&nodes[target])));
+        }
+    }
+    text.push_str("}\n");
+    text
 }
 
 #[cfg(test)]
@@ -360,20 +1846,47 @@ mod tests {
 
     #[test]
     fn test_precedence() {
-        match parse("//a + //b ^ //c") {
-            Expr::SetOp(SetOp::Union, left, right) => {
-                assert_eq!(left.inner, Expr::String("//a"));
-                match &right.inner {
+        // All set operators bind equally tightly, from the left.
+        match parse("//a + //b ^ //c except //d") {
+            Expr::SetOp(SetOp::Difference, left, right) => {
+                assert_eq!(right.inner, Expr::String("//d"));
+                match &left.inner {
                     Expr::SetOp(SetOp::Intersect, inner_left, inner_right) => {
-                        assert_eq!(inner_left.inner, Expr::String("//b"));
+                        let Expr::SetOp(SetOp::Union, a, b) = &inner_left.inner else {
+                            panic!("Expected innermost union");
+                        };
+                        assert_eq!(
+                            (&a.inner, &b.inner),
+                            (&Expr::String("//a"), &Expr::String("//b"))
+                        );
                         assert_eq!(inner_right.inner, Expr::String("//c"));
                     }
                     _ => panic!("Expected inner intersect"),
                 }
             }
-            _ => panic!("Expected outer union"),
+            _ => panic!("Expected outer difference"),
+        }
+    }
+
+    #[test]
+    fn test_set() {
+        match parse("set(//a '//b:c' @@foo+1.0//:d ) - //a") {
+            Expr::SetOp(SetOp::Difference, left, _) => match &left.inner {
+                Expr::Function("set", words) => assert_eq!(
+                    words.iter().map(|w| w.inner.clone()).collect::<Vec<_>>(),
+                    [
+                        Expr::String("//a"),
+                        Expr::String("//b:c"),
+                        Expr::String("@@foo+1.0//:d")
+                    ]
+                ),
+                other => panic!("Expected set, got {other:?}"),
+            },
+            other => panic!("Expected difference, got {other:?}"),
         }
+        assert_eq!(parse("set()"), Expr::Function("set", Vec::new()));
     }
+
     #[test]
     fn test_int_argument_in_function() {
         if let Expr::Function(name, args) = parse("deps(//foo, 7)") {
@@ -400,6 +1913,32 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_target_literals() {
+        assert_eq!(
+            parse("allrdeps(//lib:c) ^ kind(cc_.*, deps(//:a, 2)) - filter(test, //x/...)")
+                .target_literals(),
+            ["//lib:c", "//:a", "//x/..."]
+        );
+        assert_eq!(
+            parse("let v = //a in $v + //b").target_literals(),
+            ["//a", "//b"]
+        );
+    }
+
+    #[test]
+    fn test_universe() {
+        assert_eq!(
+            Universe::new(&[], false),
+            Universe::Patterns(vec!["//...".to_string()])
+        );
+        assert_eq!(Universe::new(&[], true), Universe::Inferred);
+        assert_eq!(
+            Universe::new(&["//a/...".to_string()], true),
+            Universe::Patterns(vec!["//a/...".to_string()])
+        );
+    }
+
     #[test]
     fn test_unquoted_word_rules() {
         // May not start with - or *
@@ -419,4 +1958,89 @@ mod tests {
             Expr::String("@@foo+bar")
         );
     }
+
+    #[test]
+    fn test_dot() {
+        let labels: Vec<_> = ["//:app", "//lib:a.cc", "//lib:b.cc", "//lib:a", "//:data"]
+            .iter()
+            .map(|l| parse_label(l, &crate::bazel::label::MAIN_REPO_ROOT).unwrap())
+            .collect();
+        let edges = [(0, 3), (0, 4), (3, 1), (3, 2)];
+        assert_eq!(
+            dot(&labels, &edges),
+            "digraph mygraph {\n  node [shape=box];\n  \"@@//:app\"\n  \"@@//:app\" -> \"@@//lib:a\"\n  \
+             \"@@//:app\" -> \"@@//:data\"\n  \"@@//lib:a.cc\\n@@//lib:b.cc\"\n  \"@@//lib:a\"\n  \
+             \"@@//lib:a\" -> \"@@//lib:a.cc\\n@@//lib:b.cc\"\n  \"@@//:data\"\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_check_deterministic() {
+        check_deterministic(b"//:a\n//:b\n", b"//:a\n//:b\n").unwrap();
+        let err = check_deterministic(b"//:a\n//:b\n", b"//:a\n//:c\n//:b\n").unwrap_err();
+        assert!(
+            format!("{err:#}").contains(r#"line 2 is "//:b" then "//:c""#),
+            "{err:#}"
+        );
+        let err = check_deterministic(b"//:a", b"").unwrap_err();
+        assert!(
+            format!("{err:#}").contains(r#"line 1 is "//:a" then """#),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn test_topological_order() {
+        let deps = [vec![2, 1], vec![2], vec![], vec![0]];
+        assert_eq!(topological_order(&deps, None), [3, 0, 1, 2]);
+        let names = ["d", "c", "b", "a"].map(String::from);
+        assert_eq!(topological_order(&deps, Some(&names)), [3, 0, 1, 2]);
+
+        // Nodes with no dependencies between them are in order, or by name.
+        let unrelated = [vec![], vec![], vec![]];
+        assert_eq!(topological_order(&unrelated, None), [0, 1, 2]);
+        let names = ["b", "c", "a"].map(String::from);
+        assert_eq!(topological_order(&unrelated, Some(&names)), [2, 0, 1]);
+
+        // Each node in a cycle is still listed once.
+        let cycle = [vec![1], vec![0]];
+        assert_eq!(topological_order(&cycle, None), [1, 0]);
+    }
+
+    #[test]
+    fn test_xml() {
+        let rule = build_proto::Rule {
+            name: "@@//:a".to_string(),
+            rule_class: "genrule".to_string(),
+            location: Some("/ws/BUILD.bazel:1:1".to_string()),
+            attribute: vec![
+                build_proto::Attribute::new("cmd", &AttrValue::String("a < b".to_string())),
+                build_proto::Attribute::new("srcs", &AttrValue::List(vec!["@@//:b".to_string()])),
+                build_proto::Attribute::new("stamp", &AttrValue::Other("1".to_string())),
+                build_proto::Attribute::new("copts", &AttrValue::Other("select({})".to_string())),
+            ],
+            rule_input: vec!["@@//:b".to_string()],
+            rule_output: Vec::new(),
+        };
+        let targets = [
+            build_proto::Target::rule(rule),
+            build_proto::Target::source_file("@@//:b".to_string(), Some("/ws/b:1:1".to_string())),
+        ];
+        assert_eq!(
+            xml(&targets),
+            r#"<?xml version="1.1" encoding="UTF-8" standalone="no"?>
+<query version="2">
+    <rule class="genrule" location="/ws/BUILD.bazel:1:1" name="@@//:a">
+        <string name="cmd" value="a &lt; b"/>
+        <list name="srcs">
+            <label value="@@//:b"/>
+        </list>
+        <int name="stamp" value="1"/>
+        <rule-input name="@@//:b"/>
+    </rule>
+    <source-file location="/ws/b:1:1" name="@@//:b"/>
+</query>
+"#
+        );
+    }
 }
@@ -0,0 +1,304 @@
+//! `razel repl`: evaluating Starlark interactively, for prototyping macros and poking at labels.
+//!
+//! Input is evaluated in one module that lives for the whole session, with both the BUILD and the .bzl globals
+//! available. `load()` statements work as in a BUILD file in the workspace root, and a few commands starting with `:`
+//! bring workspace files into scope:
+//!
+//! - `:load <label>` evaluates a .bzl file and imports all of its public symbols.
+//! - `:package <label>` loads a package and binds `targets` to a dict from target names to rule classes.
+//!
+//! Inputs continue over several lines while brackets are open, and blocks (`def`, `if`, ...) end at an empty line.
+
+use crate::bazel::label::{CanonicalLabel, Label, parse_label};
+use crate::bazel::repo::Repository;
+use crate::starlark::eval::{HashMapFileLoader, eval_bzl_recursive};
+use crate::starlark::globals::build::{BuildExtra, build_globals};
+use crate::starlark::globals::bzl::bzl_globals;
+use crate::workspace::Workspace;
+use starlark::PrintHandler;
+use starlark::environment::{FrozenModule, Globals, GlobalsBuilder, LibraryExtension, Module};
+use starlark::eval::Evaluator;
+use starlark::syntax::{AstModule, Dialect};
+use starlark::values::dict::AllocDict;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::sync::Arc;
+use tokio::runtime::Handle;
+
+/// The Starlark dialect of BUILD and .bzl files, plus top-level `if` and `for`, which are handy interactively.
+const DIALECT_REPL: Dialect = Dialect {
+    enable_top_level_stmt: true,
+    ..Dialect::Standard
+};
+
+const HELP: &str = "\
+Enter Starlark statements or expressions, or one of:
+  :load <label>     import the public symbols of a .bzl file
+  :package <label>  load a package, binding `targets` to its targets
+  :help             show this message
+  :quit             exit (or Ctrl-D)
+";
+
+/// Collects the output of `print()`, which is written out along with the result of the input.
+#[derive(Default)]
+struct CollectingPrintHandler(RefCell<String>);
+
+impl PrintHandler for CollectingPrintHandler {
+    fn println(&self, text: &str) -> starlark::Result<()> {
+        let mut output = self.0.borrow_mut();
+        output.push_str(text);
+        output.push('\n');
+        Ok(())
+    }
+}
+
+/// Tracks open brackets and blocks to decide whether an input continues on the next line.
+fn is_complete(source: &str) -> bool {
+    let mut depth = 0i32;
+    let mut quote = None;
+    let mut escaped = false;
+    let mut in_block = false;
+    let mut last_line_blank = true;
+
+    for line in source.lines() {
+        let mut code_end = line.len();
+        for (i, c) in line.char_indices() {
+            match quote {
+                Some(_) if escaped => escaped = false,
+                Some(_) if c == '\\' => escaped = true,
+                Some(q) if c == q => quote = None,
+                Some(_) => {}
+                None => match c {
+                    '\'' | '"' => quote = Some(c),
+                    '(' | '[' | '{' => depth += 1,
+                    ')' | ']' | '}' => depth -= 1,
+                    '#' => {
+                        code_end = i;
+                        break;
+                    }
+                    _ => {}
+                },
+            }
+        }
+        let code = line[..code_end].trim_end();
+        if depth <= 0 && quote.is_none() && code.ends_with(':') {
+            in_block = true;
+        }
+        last_line_blank = code.trim().is_empty();
+    }
+
+    depth <= 0 && quote.is_none() && (!in_block || last_line_blank)
+}
+
+struct Session<'v> {
+    workspace: Arc<Workspace>,
+    repo: Arc<Repository<'static>>,
+    /// Runs the async workspace operations, as the module can't be held across `.await`s.
+    runtime: Handle,
+    module: Module<'v>,
+    globals: Globals,
+    extra: BuildExtra,
+}
+
+impl Session<'_> {
+    /// The label that labels typed in the session are relative to: a file in the root package of the main repo.
+    fn context(&self) -> CanonicalLabel<'static> {
+        Label::new(self.repo.canonical_name(), "", "<repl>")
+    }
+
+    fn load_bzl(&self, label: &str) -> anyhow::Result<FrozenModule> {
+        let context = self.context();
+        let parsed = parse_label(label, &context)
+            .map_err(|e| anyhow::anyhow!("Failed to parse label {label:?}: {e}"))?;
+        let canonical = parsed
+            .into_canonical(|r| self.workspace.resolve_repo(&self.repo, r))
+            .ok_or_else(|| anyhow::anyhow!("Cannot resolve repo mapping for {label:?}"))?
+            .into_owned();
+        let load = eval_bzl_recursive(self.workspace.clone(), self.repo.clone(), canonical);
+        Ok(self.runtime.block_on(load)?.module)
+    }
+
+    /// Evaluates `source`, appending what `print()` printed and the `repr` of the value of a final expression to
+    /// `output`.
+    fn eval(&self, source: &str, output: &mut String) -> anyhow::Result<()> {
+        let ast = AstModule::parse("<repl>", source.to_string(), &DIALECT_REPL)
+            .map_err(|e| e.into_anyhow())?;
+        let mut loaded = HashMap::new();
+        for load in ast.loads() {
+            let module_id = load.module_id.to_string();
+            let module = self.load_bzl(&module_id)?;
+            loaded.insert(module_id, module);
+        }
+
+        let loader = HashMapFileLoader { modules: &loaded };
+        let printed = CollectingPrintHandler::default();
+        let result = {
+            let mut eval = Evaluator::new(&self.module);
+            eval.set_loader(&loader);
+            eval.set_print_handler(&printed);
+            eval.extra = Some(&self.extra);
+            eval.eval_module(ast, &self.globals)
+                .map(|value| (!value.is_none()).then(|| value.to_repr()))
+        };
+
+        output.push_str(&printed.0.into_inner());
+        if let Some(value) = result.map_err(|e| e.into_anyhow())? {
+            output.push_str(&value);
+            output.push('\n');
+        }
+        Ok(())
+    }
+
+    fn load_command(&self, label: &str) -> anyhow::Result<String> {
+        let module = self.load_bzl(label)?;
+        self.module.import_public_symbols(&module);
+        let mut names: Vec<String> = module
+            .names()
+            .map(|name| name.as_str().to_string())
+            .filter(|name| !name.starts_with('_'))
+            .collect();
+        names.sort();
+        Ok(format!("Loaded {label}: {}\n", names.join(", ")))
+    }
+
+    fn package_command(&self, label: &str) -> anyhow::Result<String> {
+        let context = self.context();
+        let parsed = parse_label(label, &context)
+            .map_err(|e| anyhow::anyhow!("Failed to parse label {label:?}: {e}"))?;
+        let (package, rules) = self.runtime.block_on(async {
+            let package = self.repo.read_package(parsed.package()).await?;
+            let rules = self
+                .repo
+                .eval_package(&package, self.workspace.clone())
+                .await?;
+            anyhow::Ok((package, rules))
+        })?;
+
+        let mut targets: Vec<(String, String)> = rules
+            .into_values()
+            .map(|rule| (rule.name, rule.rule_class))
+            .collect();
+        targets.sort();
+        let mut output = String::new();
+        for (name, rule_class) in &targets {
+            output.push_str(&format!("//{}:{name} ({rule_class})\n", package.path));
+        }
+        let dict = self.module.heap().alloc(AllocDict(targets));
+        self.module.set("targets", dict);
+        Ok(output)
+    }
+
+    /// Evaluates `source` and writes its output, reporting errors without ending the session.
+    fn eval_and_print(&self, source: &str, out: &mut impl Write) -> anyhow::Result<()> {
+        let mut output = String::new();
+        let result = self.eval(source, &mut output);
+        out.write_all(output.as_bytes())?;
+        out.flush()?;
+        if let Err(e) = result {
+            eprintln!("Error: {e:#}");
+        }
+        Ok(())
+    }
+}
+
+/// Runs a REPL reading from `input` until it ends or `:quit`, writing results to `out` and errors to stderr. With
+/// `prompt`, prompts are written before each line, for interactive use.
+///
+/// This blocks, running workspace operations on `runtime`, so it must not be called from an async task.
+pub fn repl(
+    input: impl BufRead,
+    out: &mut impl Write,
+    workspace: Arc<Workspace>,
+    prompt: bool,
+    runtime: Handle,
+) -> anyhow::Result<()> {
+    // BUILD and .bzl files don't get `print()`, but it's the obvious way to look at things interactively.
+    let mut globals = GlobalsBuilder::extended_by(&[LibraryExtension::Print]);
+    build_globals(&mut globals);
+    // Defined in both, with the .bzl version being the one worth calling.
+    bzl_globals(&mut globals);
+    let repo = runtime.block_on(workspace.main_repo())?;
+
+    Module::with_temp_heap(|module| {
+        let session = Session {
+            workspace,
+            repo,
+            runtime,
+            module,
+            globals: globals.build(),
+            extra: BuildExtra {
+                rules: RefCell::new(HashMap::new()),
+            },
+        };
+
+        let mut lines = input.lines();
+        let mut source = String::new();
+        loop {
+            if prompt {
+                out.write_all(if source.is_empty() { b">>> " } else { b"... " })?;
+                out.flush()?;
+            }
+            let Some(line) = lines.next().transpose()? else {
+                break;
+            };
+            if source.is_empty() {
+                let trimmed = line.trim();
+                if trimmed.is_empty() {
+                    continue;
+                }
+                if let Some(command) = trimmed.strip_prefix(':') {
+                    let (command, arg) = command
+                        .split_once(char::is_whitespace)
+                        .map(|(c, a)| (c, a.trim()))
+                        .unwrap_or((command, ""));
+                    let result = match command {
+                        "quit" | "q" => break,
+                        "help" | "h" => Ok(HELP.to_string()),
+                        "load" => session.load_command(arg),
+                        "package" => session.package_command(arg),
+                        _ => Err(anyhow::anyhow!("Unknown command :{command}, see :help")),
+                    };
+                    match result {
+                        Ok(output) => out.write_all(output.as_bytes())?,
+                        Err(e) => eprintln!("Error: {e:#}"),
+                    }
+                    out.flush()?;
+                    continue;
+                }
+            }
+
+            source.push_str(&line);
+            source.push('\n');
+            if is_complete(&source) {
+                session.eval_and_print(&source, out)?;
+                source.clear();
+            }
+        }
+
+        // The last block doesn't need a blank line after it when the input ends.
+        if !source.trim().is_empty() {
+            session.eval_and_print(&source, out)?;
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_complete() {
+        assert!(is_complete("x = 1\n"));
+        assert!(is_complete("d = {\"a\": 1}\n"));
+        assert!(!is_complete("x = [\n"));
+        assert!(is_complete("x = [\n1,\n]\n"));
+        assert!(!is_complete("def f():\n"));
+        assert!(!is_complete("def f():\n  return 1\n"));
+        assert!(is_complete("def f():\n  return 1\n\n"));
+        assert!(is_complete("s = \"(:\"  # [\n"));
+        assert!(!is_complete("s = 'it\\'s\n"));
+        assert!(!is_complete("d = {\n  \"a\":\n"));
+    }
+}
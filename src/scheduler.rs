@@ -0,0 +1,155 @@
+//! Priorities of the commands the server runs at the same time, so an IDE's background queries don't slow down the
+//! build someone is waiting on.
+//!
+//! Each command runs with the `--priority` it was given. While any `interactive` command runs, or waits for its turn to
+//! run, `background` ones stop before loading each package until it finishes. The packages they already started carry
+//! on. Each command loads its own packages, so waiting never holds up an interactive one; what commands sharing a
+//! workspace do share is its `.bzl` files and repositories, which never wait, so one a background command started is
+//! finished just as quickly when an interactive command needs it too.
+
+use std::cell::Cell;
+use std::sync::LazyLock;
+use tokio::sync::{Mutex, MutexGuard, watch};
+
+/// The value of `--priority`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Priority {
+    /// Someone is waiting for the command.
+    #[default]
+    Interactive,
+    /// Nobody is waiting for the command, so it gives way to interactive ones.
+    Background,
+}
+
+/// The commands running in this process.
+pub static SCHEDULER: LazyLock<Scheduler> = LazyLock::new(Scheduler::new);
+
+tokio::task_local! {
+    static PRIORITY: Cell<Priority>;
+}
+
+#[derive(Debug)]
+pub struct Scheduler {
+    /// How many interactive commands are running or waiting to.
+    interactive: watch::Sender<usize>,
+}
+
+impl Scheduler {
+    fn new() -> Self {
+        Self {
+            interactive: watch::Sender::new(0),
+        }
+    }
+
+    /// Runs `command` with `priority`, which work it does finds with `yield_to_interactive`.
+    pub async fn run<F: Future>(&self, priority: Priority, command: F) -> F::Output {
+        let _running = (priority == Priority::Interactive).then(|| {
+            self.interactive.send_modify(|n| *n += 1);
+            InteractiveCommand(self)
+        });
+        PRIORITY.scope(Cell::new(priority), command).await
+    }
+
+    /// Takes `mutex`, which commands wait for their turn with, in order of priority: a background command only takes
+    /// it once no interactive command is running or waiting.
+    ///
+    /// Interactive commands that start waiting afterwards only run once the background command holding `mutex` is
+    /// done, so it inherits their priority and stops giving way to them for the rest of the command.
+    pub async fn lock<'a, T>(&self, mutex: &'a Mutex<T>) -> MutexGuard<'a, T> {
+        self.yield_to_interactive().await;
+        let guard = mutex.lock().await;
+        let _ = PRIORITY.try_with(|p| p.set(Priority::Interactive));
+        guard
+    }
+
+    /// Waits until no interactive command is running, if called from a background one.
+    pub async fn yield_to_interactive(&self) {
+        if PRIORITY.try_with(Cell::get) != Ok(Priority::Background) {
+            return;
+        }
+        // The sender lives as long as the scheduler, so this can't fail.
+        let _ = self.interactive.subscribe().wait_for(|n| *n == 0).await;
+    }
+}
+
+/// Counts an interactive command as running until dropped.
+struct InteractiveCommand<'a>(&'a Scheduler);
+
+impl Drop for InteractiveCommand<'_> {
+    fn drop(&mut self) {
+        self.0.interactive.send_modify(|n| *n -= 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_background_yields_to_interactive() {
+        let scheduler = Scheduler::new();
+        let (started, wait_started) = tokio::sync::oneshot::channel();
+        let (finish, wait_finish) = tokio::sync::oneshot::channel::<()>();
+        let interactive = scheduler.run(Priority::Interactive, async {
+            started.send(()).unwrap();
+            // Interactive work never waits.
+            scheduler.yield_to_interactive().await;
+            wait_finish.await.unwrap();
+        });
+        let background = async {
+            wait_started.await.unwrap();
+            let yielded = scheduler.run(Priority::Background, scheduler.yield_to_interactive());
+            assert!(
+                tokio::time::timeout(Duration::from_millis(50), yielded)
+                    .await
+                    .is_err()
+            );
+            finish.send(()).unwrap();
+        };
+        tokio::join!(interactive, background);
+
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            scheduler.run(Priority::Background, scheduler.yield_to_interactive()),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_lock_prefers_interactive() {
+        let scheduler = Scheduler::new();
+        let mutex = Mutex::new(());
+        let (queued, wait_queued) = tokio::sync::oneshot::channel();
+        let (finish, wait_finish) = tokio::sync::oneshot::channel::<()>();
+        // An interactive command waits for the one holding the lock.
+        let held = mutex.lock().await;
+        let interactive = scheduler.run(Priority::Interactive, async {
+            queued.send(()).unwrap();
+            let _turn = scheduler.lock(&mutex).await;
+            wait_finish.await.unwrap();
+        });
+        let background = async {
+            wait_queued.await.unwrap();
+            let turn = scheduler.run(Priority::Background, async {
+                let _turn = scheduler.lock(&mutex).await;
+                // Holding the lock, it no longer gives way.
+                scheduler.yield_to_interactive().await;
+            });
+            tokio::pin!(turn);
+            // The background command waits behind the interactive one, even once the lock is free.
+            drop(held);
+            assert!(
+                tokio::time::timeout(Duration::from_millis(50), &mut turn)
+                    .await
+                    .is_err()
+            );
+            finish.send(()).unwrap();
+            tokio::time::timeout(Duration::from_secs(5), turn)
+                .await
+                .unwrap();
+        };
+        tokio::join!(interactive, background);
+    }
+}
@@ -0,0 +1,606 @@
+//! The razel server, which commands are sent to with `--batch=false`.
+//!
+//! Loading a workspace is most of the work of a typical command, and its results stay valid until files change. A
+//! long-lived server per output base keeps the `Workspace` of each directory and configuration in memory, watching its
+//! files to invalidate only what changed, while the `razel` client just forwards its command line and prints the
+//! result. This is the client/server split Bazel uses.
+//!
+//! The server speaks gRPC on localhost, at the address written to `<output_base>/server/command_port`. A client that
+//! finds no server there starts one in the background. Any local user can connect to the port, so like Bazel's, every
+//! request carries the random cookie the server writes to `<output_base>/server/request_cookie`, which only the owner
+//! of the output base can read.
+
+use crate::bazel::Configuration;
+use crate::bazel::rc::find_workspace_root;
+use crate::clock::Providers;
+use crate::exit_code::{ExitCode, exit_code};
+use crate::scheduler::{Priority, SCHEDULER};
+use crate::watch::FileWatcher;
+use crate::workspace::Workspace;
+use crate::{Cli, Commands};
+use clap::Parser;
+use futures::FutureExt;
+use futures::future::BoxFuture;
+use prost::Message;
+use std::collections::HashMap;
+use std::io::Write as _;
+use std::os::unix::fs::OpenOptionsExt as _;
+use std::os::unix::process::CommandExt as _;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tonic::codegen::{Context, Poll, Service, http};
+use tonic::transport::Channel;
+
+const SERVICE: &str = "razel.CommandServer";
+const RUN: &str = "/razel.CommandServer/Run";
+const SHUTDOWN: &str = "/razel.CommandServer/Shutdown";
+/// Serves the counters of `crate::metrics` to Prometheus, over plain HTTP, to requests that present the request cookie
+/// as a bearer token, e.g. with Prometheus's `authorization: {credentials_file: <output_base>/server/request_cookie}`.
+const METRICS: &str = "/metrics";
+
+/// How long a client waits for the server it started to accept connections.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, PartialEq, Message)]
+pub struct RunRequest {
+    /// The command line after the program name, with the options from .bazelrc files already added.
+    #[prost(string, repeated, tag = "1")]
+    pub args: Vec<String>,
+    /// The client's working directory, which determines the workspace and relative target patterns.
+    #[prost(string, tag = "2")]
+    pub cwd: String,
+    /// The server's request cookie.
+    #[prost(string, tag = "3")]
+    pub cookie: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct RunResponse {
+    #[prost(bytes = "vec", tag = "1")]
+    pub stdout: Vec<u8>,
+    #[prost(bytes = "vec", tag = "2")]
+    pub stderr: Vec<u8>,
+    #[prost(int32, tag = "3")]
+    pub exit_code: i32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ShutdownRequest {
+    /// The server's request cookie.
+    #[prost(string, tag = "1")]
+    pub cookie: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ShutdownResponse {}
+
+/// The directory through which clients find the server of an output base.
+struct ServerDir(PathBuf);
+
+impl ServerDir {
+    fn new(output_base: &Path) -> Self {
+        Self(output_base.join("server"))
+    }
+
+    fn command_port(&self) -> PathBuf {
+        self.0.join("command_port")
+    }
+
+    fn request_cookie(&self) -> PathBuf {
+        self.0.join("request_cookie")
+    }
+
+    fn log(&self) -> PathBuf {
+        self.0.join("server.log")
+    }
+}
+
+/// A connection to the server of an output base, with the cookie its requests must carry.
+struct Client {
+    grpc: tonic::client::Grpc<Channel>,
+    cookie: String,
+}
+
+/// Whether the command of `cli` is sent to the server. Commands managing the server itself, interactive ones like `repl`
+/// or `build --watch`, those that read the client's files or stdin, like `canonicalize-flags` with its rc files or
+/// `query --query_file`, and those writing a `--profile`, which records the spans of the process it's given to, always
+/// run in the client.
+pub fn dispatchable(cli: &Cli) -> bool {
+    cli.profile.is_none()
+        && !matches!(
+            &cli.command,
+            Commands::Server
+                | Commands::Shutdown
+                | Commands::Repl
+                | Commands::Build { watch: true, .. }
+                | Commands::CanonicalizeFlags { .. }
+                | Commands::Query {
+                    query_file: Some(_),
+                    ..
+                }
+        )
+}
+
+/// Makes the paths of `cli` that are relative to the directory the command was run in, i.e. the files it reads and
+/// writes, relative to the client's directory `cwd` rather than the server's.
+fn rebase_paths(cli: &mut Cli, cwd: &Path) {
+    let input = match &mut cli.command {
+        Commands::Build { targets, .. }
+        | Commands::Test { targets }
+        | Commands::Coverage { targets } => targets.target_pattern_file.as_mut(),
+        Commands::AnalyzeProfile { path } => Some(path),
+        _ => None,
+    };
+    for path in [
+        cli.metrics_textfile.as_mut(),
+        cli.explain.as_mut(),
+        cli.build_event_json_file.as_mut(),
+        cli.repository_cache.as_mut(),
+        input,
+    ]
+    .into_iter()
+    .flatten()
+    {
+        *path = cwd.join(&*path);
+    }
+}
+
+/// Whether `command` only reads the workspace. With the server, such commands don't wait for a running command, see
+/// `State::run`, so the client doesn't take the output base lock for them either.
+pub fn read_only(command: &Commands) -> bool {
+    matches!(command, Commands::Query { .. } | Commands::Dump { .. })
+}
+
+struct CachedWorkspace {
+    workspace: Arc<Workspace>,
+    watcher: FileWatcher,
+}
+
+struct State {
+    /// The secret every request must carry, see `ServerDir::request_cookie`.
+    cookie: String,
+    /// Workspaces by the directory a command ran in and the configuration it ran with.
+    workspaces: tokio::sync::Mutex<HashMap<(PathBuf, String), CachedWorkspace>>,
+    /// Held while a command runs. Commands run one at a time, as they share workspaces and process-wide metrics, except
+    /// that read-only ones run alongside. Waiting commands take it in order of priority, see `Scheduler::lock`.
+    running: tokio::sync::Mutex<()>,
+    last_active: std::sync::Mutex<Instant>,
+    shutdown: Notify,
+    /// The clock and random numbers of the commands the server runs.
+    providers: Providers,
+}
+
+impl State {
+    /// Returns the workspace for `cwd` and `config`, reusing the one from an earlier command unless module files
+    /// changed since. With `--keep_state_after_build=false` the workspace is neither reused nor kept for later commands.
+    ///
+    /// With `alongside`, for a command running alongside another, a workspace already in use is returned as it is,
+    /// without applying changes to files: the other command is using it too, and a build shouldn't see its graph
+    /// change half-way through. This is the live workspace rather than a copy, so what the other command loads from
+    /// now on is seen as well. The changes are applied for the next command that isn't read-only.
+    fn open_workspace(
+        self: &Arc<Self>,
+        cwd: PathBuf,
+        config: Arc<Configuration>,
+        alongside: bool,
+    ) -> BoxFuture<'static, anyhow::Result<Arc<Workspace>>> {
+        let state = self.clone();
+        async move {
+            if !config.keep_state_after_build {
+                return Ok(Workspace::new(&cwd, config, state.providers.clone()).await?);
+            }
+            let key = (cwd.clone(), format!("{config:?}"));
+            let mut workspaces = state.workspaces.lock().await;
+            let reusable = match workspaces.get_mut(&key) {
+                Some(cached) if alongside => Some(cached.workspace.clone()),
+                Some(cached) => {
+                    let changes = cached.watcher.pending_changes()?;
+                    if changes.resets_workspace() {
+                        None
+                    } else {
+                        cached.workspace.invalidate_bzl_files(&changes.bzl_files());
+                        Some(cached.workspace.clone())
+                    }
+                }
+                None => None,
+            };
+            if let Some(workspace) = reusable {
+                return Ok(workspace);
+            }
+
+            let workspace = Workspace::new(&cwd, config, state.providers.clone()).await?;
+            let watcher = FileWatcher::new(workspace.path())?;
+            workspaces.insert(
+                key,
+                CachedWorkspace {
+                    workspace: workspace.clone(),
+                    watcher,
+                },
+            );
+            Ok(workspace)
+        }
+        .boxed()
+    }
+
+    async fn run(self: Arc<Self>, request: RunRequest) -> RunResponse {
+        let args = std::iter::once("razel".to_string()).chain(request.args);
+        let cli = Cli::try_parse_from(args);
+        // The command takes its priority before waiting for its turn, so background commands give way to an
+        // interactive one that waits.
+        let priority = cli.as_ref().map_or(Priority::default(), |cli| cli.priority);
+        SCHEDULER
+            .run(priority, self.run_in_turn(cli, request.cwd, priority))
+            .await
+    }
+
+    async fn run_in_turn(
+        self: Arc<Self>,
+        cli: Result<Cli, clap::Error>,
+        cwd: String,
+        priority: Priority,
+    ) -> RunResponse {
+        // A read-only command doesn't wait for the running one, so that a query isn't stuck behind a long build. It
+        // shares the running command's workspace, without applying changes to files, and keeps its own `--keep_going`
+        // errors. A background one never takes the lock, so that it can't hold up an interactive command either.
+        let running = if cli.as_ref().is_ok_and(|cli| read_only(&cli.command)) {
+            match priority {
+                Priority::Interactive => self.running.try_lock().ok(),
+                Priority::Background => None,
+            }
+        } else {
+            Some(SCHEDULER.lock(&self.running).await)
+        };
+        let alongside = running.is_none();
+        *self.last_active.lock().unwrap() = Instant::now();
+
+        let mut stdout = Vec::new();
+        let (stderr, exit_code) = match cli {
+            // Includes --help, which is printed to stdout.
+            Err(e) if !e.use_stderr() => {
+                stdout = e.render().to_string().into_bytes();
+                (Vec::new(), e.exit_code())
+            }
+            Err(e) => (e.render().to_string().into_bytes(), e.exit_code()),
+            Ok(mut cli) => {
+                let cwd = PathBuf::from(cwd);
+                rebase_paths(&mut cli, &cwd);
+                let config = Arc::new(Configuration::from_flags(&cli));
+                let metrics_textfile = config.metrics_textfile.clone();
+                let state = self.clone();
+                let workspace_config = config.clone();
+                let open_workspace =
+                    move || state.open_workspace(cwd.clone(), workspace_config.clone(), alongside);
+                let command = crate::workspace::with_deferred_errors(crate::run_command(
+                    &cli,
+                    config,
+                    &self.providers,
+                    &open_workspace,
+                    &mut stdout,
+                ));
+                // Warnings and log messages go to the client, followed by the error if the command failed.
+                let (result, mut stderr) = crate::console::capture_stderr(async {
+                    let result = std::panic::AssertUnwindSafe(command).catch_unwind().await;
+                    if let Some(path) = metrics_textfile {
+                        crate::write_metrics(&path);
+                    }
+                    result
+                })
+                .await;
+                let (error, exit_code) = match result {
+                    Ok(Ok(())) => (String::new(), 0),
+                    Ok(Err(e)) => (format!("Error: {e:?}\n"), exit_code(&e).code()),
+                    Err(panic) => (
+                        format!("{}\n", panic_message(&*panic)),
+                        ExitCode::InternalError.code(),
+                    ),
+                };
+                stderr.extend_from_slice(error.as_bytes());
+                (stderr, exit_code)
+            }
+        };
+
+        *self.last_active.lock().unwrap() = Instant::now();
+        drop(running);
+        RunResponse {
+            stdout,
+            stderr,
+            exit_code,
+        }
+    }
+
+    /// Fails unless `cookie` is the server's request cookie.
+    fn check_cookie(&self, cookie: &str) -> Result<(), tonic::Status> {
+        use subtle::ConstantTimeEq;
+
+        // In constant time, so how long the check takes doesn't tell how much of a guess is right.
+        if bool::from(cookie.as_bytes().ct_eq(self.cookie.as_bytes())) {
+            Ok(())
+        } else {
+            Err(tonic::Status::permission_denied(
+                "The request cookie doesn't match this server's",
+            ))
+        }
+    }
+
+    /// Whether the server has had nothing to do for `max_idle`.
+    fn idle_for(&self, max_idle: Duration) -> bool {
+        self.running.try_lock().is_ok() && self.last_active.lock().unwrap().elapsed() >= max_idle
+    }
+}
+
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("The command panicked")
+}
+
+struct RunMethod(Arc<State>);
+
+impl tonic::server::UnaryService<RunRequest> for RunMethod {
+    type Response = RunResponse;
+    type Future = BoxFuture<'static, Result<tonic::Response<RunResponse>, tonic::Status>>;
+
+    fn call(&mut self, request: tonic::Request<RunRequest>) -> Self::Future {
+        let state = self.0.clone();
+        async move {
+            let request = request.into_inner();
+            state.check_cookie(&request.cookie)?;
+            Ok(tonic::Response::new(state.run(request).await))
+        }
+        .boxed()
+    }
+}
+
+struct ShutdownMethod(Arc<State>);
+
+impl tonic::server::UnaryService<ShutdownRequest> for ShutdownMethod {
+    type Response = ShutdownResponse;
+    type Future = BoxFuture<'static, Result<tonic::Response<ShutdownResponse>, tonic::Status>>;
+
+    fn call(&mut self, request: tonic::Request<ShutdownRequest>) -> Self::Future {
+        let checked = self.0.check_cookie(&request.get_ref().cookie);
+        if checked.is_ok() {
+            self.0.shutdown.notify_one();
+        }
+        async move {
+            checked?;
+            Ok(tonic::Response::new(ShutdownResponse {}))
+        }
+        .boxed()
+    }
+}
+
+/// The `razel.CommandServer` gRPC service, written out by hand as there are only two methods.
+#[derive(Clone)]
+struct CommandService(Arc<State>);
+
+impl tonic::server::NamedService for CommandService {
+    const NAME: &'static str = SERVICE;
+}
+
+impl<B> Service<http::Request<B>> for CommandService
+where
+    B: tonic::codegen::Body<Data = tonic::codegen::Bytes> + Send + 'static,
+    B::Error: Into<tonic::codegen::StdError> + Send,
+{
+    type Response = http::Response<tonic::body::Body>;
+    type Error = std::convert::Infallible;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let state = self.0.clone();
+        async move {
+            Ok(match request.uri().path() {
+                RUN => {
+                    let mut grpc = tonic::server::Grpc::new(tonic_prost::ProstCodec::default());
+                    grpc.unary(RunMethod(state), request).await
+                }
+                SHUTDOWN => {
+                    let mut grpc = tonic::server::Grpc::new(tonic_prost::ProstCodec::default());
+                    grpc.unary(ShutdownMethod(state), request).await
+                }
+                METRICS => {
+                    let token = request
+                        .headers()
+                        .get(http::header::AUTHORIZATION)
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(|value| value.strip_prefix("Bearer "))
+                        .unwrap_or_default();
+                    let response = http::Response::builder();
+                    match state.check_cookie(token.trim()) {
+                        Ok(()) => response
+                            .header(http::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+                            .body(tonic::body::Body::new(crate::metrics::METRICS.prometheus())),
+                        Err(_) => response
+                            .status(http::StatusCode::UNAUTHORIZED)
+                            .body(tonic::body::Body::empty()),
+                    }
+                    .expect("the response is valid")
+                }
+                path => tonic::Status::unimplemented(format!("Unknown method {path}")).into_http(),
+            })
+        }
+        .boxed()
+    }
+}
+
+/// A new request cookie: 32 bytes from the OS's cryptographically secure random number generator, in hex. It's all that
+/// keeps other local users out, so unlike the ids in `crate::clock` it mustn't be predictable.
+fn new_cookie() -> anyhow::Result<String> {
+    let mut bytes = [0; 32];
+    getrandom::fill(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+/// Runs the server for the workspace containing the current directory until it is shut down, or has been idle for
+/// `max_idle`.
+pub async fn serve(
+    config: Arc<Configuration>,
+    providers: Providers,
+    max_idle: Duration,
+) -> anyhow::Result<()> {
+    let root = find_workspace_root(Path::new("."))
+        .ok_or_else(|| anyhow::anyhow!("The server must be started in a workspace"))?;
+    let dir = ServerDir::new(&config.output_base(&root));
+    tokio::fs::create_dir_all(&dir.0).await?;
+
+    // Written before the address, so a client that finds the server can always read it.
+    let cookie = new_cookie()?;
+    let tmp = dir.0.join("request_cookie.tmp");
+    let _ = std::fs::remove_file(&tmp);
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp)?
+        .write_all(cookie.as_bytes())?;
+    tokio::fs::rename(&tmp, dir.request_cookie()).await?;
+
+    let incoming = tonic::transport::server::TcpIncoming::bind(([127, 0, 0, 1], 0).into())?;
+    // Renamed into place, so a client never reads a partly written address.
+    let tmp = dir.0.join("command_port.tmp");
+    tokio::fs::write(&tmp, incoming.local_addr()?.to_string()).await?;
+    tokio::fs::rename(&tmp, dir.command_port()).await?;
+
+    let state = Arc::new(State {
+        cookie,
+        workspaces: tokio::sync::Mutex::new(HashMap::new()),
+        running: tokio::sync::Mutex::new(()),
+        last_active: std::sync::Mutex::new(Instant::now()),
+        shutdown: Notify::new(),
+        providers,
+    });
+    let idle = state.clone();
+    let stop = async move {
+        loop {
+            tokio::select! {
+                _ = idle.shutdown.notified() => break,
+                _ = tokio::time::sleep(max_idle.min(Duration::from_secs(1))) => {
+                    if idle.idle_for(max_idle) {
+                        break;
+                    }
+                }
+            }
+        }
+    };
+
+    // HTTP/1 is for scraping `/metrics`; commands are sent over HTTP/2. The gRPC routes only take paths under the
+    // service's name, so `/metrics` is routed to the same service separately.
+    let routes = tonic::service::Routes::new(CommandService(state.clone()))
+        .into_axum_router()
+        .route_service(METRICS, CommandService(state));
+    tonic::transport::Server::builder()
+        .accept_http1(true)
+        .add_routes(routes.into())
+        .serve_with_incoming_shutdown(incoming, stop)
+        .await?;
+    let _ = tokio::fs::remove_file(dir.command_port()).await;
+    let _ = tokio::fs::remove_file(dir.request_cookie()).await;
+    Ok(())
+}
+
+async fn connect(dir: &ServerDir) -> Option<Client> {
+    let addr = tokio::fs::read_to_string(dir.command_port()).await.ok()?;
+    let cookie = tokio::fs::read_to_string(dir.request_cookie()).await.ok()?;
+    let channel = tonic::transport::Endpoint::from_shared(format!("http://{}", addr.trim()))
+        .ok()?
+        .connect()
+        .await
+        .ok()?;
+    Some(Client {
+        grpc: tonic::client::Grpc::new(channel),
+        cookie,
+    })
+}
+
+/// Starts a server for `output_base` in the background, and connects to it once it is up.
+async fn start_server(output_base: &Path, max_idle_secs: u64) -> anyhow::Result<Client> {
+    let root =
+        find_workspace_root(Path::new(".")).ok_or_else(|| anyhow::anyhow!("Not in a workspace"))?;
+    let dir = ServerDir::new(output_base);
+    std::fs::create_dir_all(&dir.0)?;
+    let log = std::fs::File::create(dir.log())?;
+    let _ = std::fs::remove_file(dir.command_port());
+
+    std::process::Command::new(std::env::current_exe()?)
+        .arg(format!("--output_base={}", output_base.display()))
+        .arg(format!("--max_idle_secs={max_idle_secs}"))
+        .arg("server")
+        .current_dir(root)
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        // Keeps the server out of the client's process group, so it survives e.g. Ctrl-C in the terminal.
+        .process_group(0)
+        .spawn()?;
+
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    while Instant::now() < deadline {
+        if let Some(client) = connect(&dir).await {
+            return Ok(client);
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    anyhow::bail!(
+        "The server didn't start within {STARTUP_TIMEOUT:?}, see {}",
+        dir.log().display()
+    )
+}
+
+/// Runs the command line `args` (without the program name) on the server for `output_base`, starting one if needed,
+/// and prints its output. Returns the exit code of the command.
+pub async fn dispatch(
+    output_base: &Path,
+    args: &[String],
+    max_idle_secs: u64,
+) -> anyhow::Result<i32> {
+    let dir = ServerDir::new(output_base);
+    let Client { mut grpc, cookie } = match connect(&dir).await {
+        Some(client) => client,
+        None => start_server(output_base, max_idle_secs).await?,
+    };
+
+    let request = RunRequest {
+        args: args.to_vec(),
+        cwd: std::env::current_dir()?.to_string_lossy().into_owned(),
+        cookie,
+    };
+    grpc.ready().await?;
+    let response = grpc
+        .unary(
+            tonic::Request::new(request),
+            http::uri::PathAndQuery::from_static(RUN),
+            tonic_prost::ProstCodec::<RunRequest, RunResponse>::default(),
+        )
+        .await?
+        .into_inner();
+
+    std::io::stdout().write_all(&response.stdout)?;
+    std::io::stdout().flush()?;
+    std::io::stderr().write_all(&response.stderr)?;
+    Ok(response.exit_code)
+}
+
+/// Stops the server for `output_base`, if one is running.
+pub async fn shutdown(output_base: &Path) -> anyhow::Result<()> {
+    let Some(Client { mut grpc, cookie }) = connect(&ServerDir::new(output_base)).await else {
+        return Ok(());
+    };
+    grpc.ready().await?;
+    grpc.unary(
+        tonic::Request::new(ShutdownRequest { cookie }),
+        http::uri::PathAndQuery::from_static(SHUTDOWN),
+        tonic_prost::ProstCodec::<ShutdownRequest, ShutdownResponse>::default(),
+    )
+    .await?;
+    Ok(())
+}
@@ -1,9 +1,33 @@
+//! Errors of work that many callers wait on, such as evaluating a repository or loading a `.bzl` file.
+//!
+//! Every caller gets a clone of the same error, which keeps the whole of the original: its chain, the Starlark call
+//! stack and file span of a `crate::error::StarlarkError`, and its error code. Since they are all one failure, `dedup`
+//! lets it be reported once rather than once per dependent.
+
 use std::fmt;
 use std::sync::Arc;
 
 #[derive(Debug, Clone)]
 pub struct SharedError(pub Arc<anyhow::Error>);
 
+impl SharedError {
+    /// The innermost shared error in `error`'s chain, i.e. the failure it has in common with other dependents.
+    pub fn root(error: &anyhow::Error) -> Option<&SharedError> {
+        let shared = error
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<SharedError>())?;
+        Some(Self::root(&shared.0).unwrap_or(shared))
+    }
+
+    /// Whether `a` and `b` are reports of the same shared failure.
+    pub fn same_root(a: &anyhow::Error, b: &anyhow::Error) -> bool {
+        match (Self::root(a), Self::root(b)) {
+            (Some(a), Some(b)) => Arc::ptr_eq(&a.0, &b.0),
+            _ => false,
+        }
+    }
+}
+
 impl fmt::Display for SharedError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         fmt::Display::fmt(&self.0, f)
@@ -21,3 +45,87 @@ impl From<anyhow::Error> for SharedError {
         SharedError(Arc::new(err))
     }
 }
+
+/// Groups the `errors` that are reports of the same shared failure, keeping the first of each group and its size.
+pub fn dedup(errors: Vec<anyhow::Error>) -> Vec<(anyhow::Error, usize)> {
+    let mut groups: Vec<(anyhow::Error, usize)> = Vec::new();
+    for error in errors {
+        match groups
+            .iter_mut()
+            .find(|(first, _)| SharedError::same_root(first, &error))
+        {
+            Some((_, count)) => *count += 1,
+            None => groups.push((error, 1)),
+        }
+    }
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{Diagnostic, FetchError, StarlarkError, error_code};
+
+    #[test]
+    fn test_dedup() {
+        let broken = SharedError::from(
+            anyhow::Error::new(FetchError::Unsupported {
+                repo: "rules_go+".to_string(),
+            })
+            .context("Failed to evaluate repo @@rules_go+"),
+        );
+        // A .bzl file that failed because of the repository, shared in turn by the packages that load it.
+        let bzl = SharedError::from(
+            anyhow::Error::new(broken.clone()).context("Failed to load @@rules_go+//go:def.bzl"),
+        );
+        let errors = vec![
+            anyhow::Error::new(bzl.clone()).context("Failed to load package @@//a"),
+            anyhow::anyhow!("Invalid target pattern"),
+            anyhow::Error::new(broken.clone()).context("Failed to load package @@//b"),
+            anyhow::Error::new(bzl).context("Failed to load package @@//c"),
+        ];
+        assert!(SharedError::root(&errors[1]).is_none());
+        assert!(Arc::ptr_eq(
+            &SharedError::root(&errors[0]).unwrap().0,
+            &broken.0
+        ));
+
+        let groups = dedup(errors);
+        let summary: Vec<_> = groups
+            .iter()
+            .map(|(error, count)| (format!("{error:#}"), *count))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (
+                    "Failed to load package @@//a: Failed to load @@rules_go+//go:def.bzl: \
+                     Failed to evaluate repo @@rules_go+: \
+                     Fetching external repository rules_go+ is not implemented"
+                        .to_string(),
+                    3
+                ),
+                ("Invalid target pattern".to_string(), 1),
+            ]
+        );
+        // However deep the sharing, the code of the original failure is still there.
+        assert_eq!(
+            error_code(&groups[0].0).unwrap().to_string(),
+            "RAZEL_FETCH_UNSUPPORTED"
+        );
+
+        // Nor is where in which .bzl file a Starlark error was raised.
+        let syntax_error = starlark::syntax::AstModule::parse(
+            "defs.bzl",
+            "def f(:\n".to_string(),
+            &starlark::syntax::Dialect::Standard,
+        )
+        .unwrap_err();
+        let shared = SharedError::from(
+            StarlarkError::wrap(syntax_error).context("Failed to load @@//:defs.bzl"),
+        );
+        let error = anyhow::Error::new(shared).context("Failed to load package @@//a");
+        let location = Diagnostic::new(&error).location.unwrap();
+        assert!(location.starts_with("defs.bzl:1:"), "{location}");
+    }
+}
@@ -1,23 +1,27 @@
 use crate::bazel::label::{CanonicalLabel, Label};
-use crate::bazel::package::File;
+use crate::bazel::package::{File, FileStore};
 use crate::bazel::repo::Repository;
-use crate::bazel::rule::Rule;
+use crate::bazel::rule::{DefinitionDigest, Rule};
+use crate::cycle::Dependency;
+use crate::error::{LoadingError, ResolutionError, StarlarkError};
+use crate::starlark::sandbox::SandboxedFileStore;
 use crate::workspace::Workspace;
 use futures::future::{BoxFuture, FutureExt};
 use starlark::environment::{FrozenModule, Module as StarlarkModule};
 use starlark::eval::{Evaluator, FileLoader};
-use starlark::syntax::{AstModule, Dialect};
+use starlark::syntax::{AstLoad, AstModule, Dialect};
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::io::AsyncReadExt;
+use tracing::Instrument;
 
 const DIALECT_BUILD: Dialect = Dialect {
     enable_load: true,
     ..Dialect::Standard
 };
 
-struct HashMapFileLoader<'a> {
-    modules: &'a HashMap<String, FrozenModule>,
+pub(crate) struct HashMapFileLoader<'a> {
+    pub(crate) modules: &'a HashMap<String, FrozenModule>,
 }
 
 impl<'a> FileLoader for HashMapFileLoader<'a> {
@@ -28,94 +32,144 @@ impl<'a> FileLoader for HashMapFileLoader<'a> {
     }
 }
 
+/// Where a `load()` statement is written, as `file:line:column`.
+fn load_location(load: &AstLoad) -> String {
+    let begin = load.span.resolve_span().begin;
+    format!(
+        "{}:{}:{}",
+        load.span.filename(),
+        begin.line + 1,
+        begin.column + 1
+    )
+}
+
+/// An evaluated .bzl file.
+#[derive(Clone)]
+pub struct LoadedBzl {
+    pub module: FrozenModule,
+    /// Changes whenever this file or anything it loads changes, see `DefinitionDigest`.
+    pub transitive_digest: DefinitionDigest,
+}
+
 /// Recursively load, parse and freeze a starlark dependency
 #[async_recursion::async_recursion]
 pub async fn eval_bzl_recursive(
     workspace: Arc<Workspace>,
     repo: Arc<Repository<'static>>,
     label: CanonicalLabel<'static>,
-) -> anyhow::Result<FrozenModule> {
+) -> anyhow::Result<LoadedBzl> {
     let r = {
         let workspace_clone = workspace.clone();
         let repo_clone = repo.clone();
         let label_clone = label.clone();
-        workspace.get_or_add_bzl(label.clone(), move || async move {
-            if repo_clone.canonical_name() != label_clone.repo {
-                anyhow::bail!(
-                    "eval_bzl_recursive cross-repo load unimplemented for {:?}",
-                    label_clone
+        workspace.get_or_add_bzl(label.clone(), move || {
+            let span = tracing::info_span!("load bzl", label = %label_clone);
+            async move {
+                if repo_clone.canonical_name() != label_clone.repo {
+                    anyhow::bail!(
+                        "eval_bzl_recursive cross-repo load unimplemented for {:?}",
+                        label_clone
+                    );
+                }
+
+                let _running = workspace_clone.wait_graph().start(label_clone.to_string());
+
+                let package = label_clone.package();
+                let target = label_clone.name();
+
+                let path = if package.is_empty() {
+                    target.to_string()
+                } else {
+                    format!("{}/{}", package, target)
+                };
+
+                let files = SandboxedFileStore::for_bzl_file(
+                    repo_clone.files().clone(),
+                    workspace_clone.loading_sandbox(),
+                    &path,
                 );
-            }
+                let file = files.read_file(&path).await?;
+                let mut content = String::new();
+                (*file).open().await?.read_to_string(&mut content).await?;
+
+                let loads: Vec<(String, String)> = {
+                    let ast = AstModule::parse(&path, content.clone(), &DIALECT_BUILD)
+                        .map_err(StarlarkError::wrap)?;
+                    ast.loads()
+                        .into_iter()
+                        .map(|l| (l.module_id.to_string(), load_location(&l)))
+                        .collect()
+                };
 
-            let package = label_clone.package();
-            let target = label_clone.name();
-
-            let path = if package.is_empty() {
-                target.to_string()
-            } else {
-                format!("{}/{}", package, target)
-            };
-
-            let file = repo_clone.read_file(&path).await?;
-            let mut content = String::new();
-            (*file).open().await?.read_to_string(&mut content).await?;
-
-            let loads: Vec<String> = {
-                let ast = AstModule::parse(&path, content.clone(), &DIALECT_BUILD)
-                    .map_err(|e| e.into_anyhow())?;
-                ast.loads()
-                    .into_iter()
-                    .map(|l| l.module_id.to_string())
-                    .collect()
-            };
-
-            let mut loaded_modules = HashMap::new();
-            let mut futures: Vec<BoxFuture<'static, anyhow::Result<FrozenModule>>> = Vec::new();
-            let mut module_ids = Vec::new();
-
-            for load_str in &loads {
-                let load_label = crate::bazel::label::parse_label(load_str, &label_clone)
-                    .map_err(|e| anyhow::anyhow!("Failed to parse label: {:?}", e.to_string()))?;
-                let canonical_load = repo_clone.resolve_label(load_label).ok_or_else(|| {
-                    anyhow::anyhow!("Cannot resolve repo mapping for {:?}", load_str)
-                })?;
-
-                futures.push(
-                    eval_bzl_recursive(
+                let mut loaded_modules = HashMap::new();
+                let mut futures: Vec<BoxFuture<'static, anyhow::Result<LoadedBzl>>> = Vec::new();
+                let mut module_ids = Vec::new();
+
+                for (load_str, location) in &loads {
+                    let load_label = crate::bazel::label::parse_label(load_str, &label_clone)
+                        .map_err(|e| LoadingError::InvalidLabel {
+                            reason: e.to_string(),
+                        })?;
+                    let canonical_load = load_label
+                        .into_canonical(|r| workspace_clone.resolve_repo(&repo_clone, r))
+                        .ok_or_else(|| ResolutionError::RepositoryNotVisible {
+                            apparent: load_str.to_string(),
+                            from: label_clone.to_string(),
+                        })?;
+
+                    workspace_clone
+                        .record_bzl_load(label_clone.clone(), canonical_load.clone().into_owned());
+                    let graph = workspace_clone.wait_graph().clone();
+                    let (from, to) = (label_clone.to_string(), canonical_load.to_string());
+                    let dependency = Dependency::load(location);
+                    let load = eval_bzl_recursive(
                         workspace_clone.clone(),
                         repo_clone.clone(),
                         canonical_load.into_owned(),
-                    )
-                    .boxed(),
+                    );
+                    futures.push(
+                        async move { graph.wait(&from, &to, dependency, load).await }.boxed(),
+                    );
+                    module_ids.push(load_str.clone());
+                }
+
+                let results = futures::future::try_join_all(futures).await?;
+                let transitive_digest = DefinitionDigest::of_bzl(
+                    &label_clone.to_string(),
+                    &content,
+                    results.iter().map(|loaded| &loaded.transitive_digest),
                 );
-                module_ids.push(load_str.clone());
-            }
+                for (module_id, loaded) in module_ids.into_iter().zip(results) {
+                    loaded_modules.insert(module_id, loaded.module);
+                }
 
-            let results = futures::future::try_join_all(futures).await?;
-            for (module_id, frozen) in module_ids.into_iter().zip(results) {
-                loaded_modules.insert(module_id, frozen);
-            }
+                let globals = super::globals::bzl::bzl_globals_builder().build();
+                let limits = workspace_clone.eval_limits();
 
-            let globals = super::globals::bzl::bzl_globals_builder().build();
-
-            let frozen_module = StarlarkModule::with_temp_heap(
-                |starlark_module| -> anyhow::Result<FrozenModule> {
-                    {
-                        let loader = HashMapFileLoader {
-                            modules: &loaded_modules,
-                        };
-                        let mut eval = Evaluator::new(&starlark_module);
-                        eval.set_loader(&loader);
-                        let ast = AstModule::parse(&path, content, &DIALECT_BUILD)
-                            .map_err(|e| e.into_anyhow())?;
-                        eval.eval_module(ast, &globals)
-                            .map_err(|e| e.into_anyhow())?;
-                    }
-                    starlark_module.freeze().map_err(anyhow::Error::from)
-                },
-            )?;
-
-            Ok(frozen_module)
+                let frozen_module = StarlarkModule::with_temp_heap(
+                    |starlark_module| -> anyhow::Result<FrozenModule> {
+                        {
+                            let loader = HashMapFileLoader {
+                                modules: &loaded_modules,
+                            };
+                            let mut eval = Evaluator::new(&starlark_module);
+                            limits.install(&mut eval, &label_clone.to_string());
+                            eval.set_loader(&loader);
+                            let ast = AstModule::parse(&path, content, &DIALECT_BUILD)
+                                .map_err(StarlarkError::wrap)?;
+                            eval.eval_module(ast, &globals)
+                                .map_err(StarlarkError::wrap)?;
+                        }
+                        starlark_module.freeze().map_err(anyhow::Error::from)
+                    },
+                )?;
+
+                Ok(LoadedBzl {
+                    module: frozen_module,
+                    transitive_digest,
+                })
+            }
+            .instrument(span)
         })
     };
 
@@ -141,43 +195,64 @@ pub async fn eval_build(
         target.to_string(),
     );
 
-    let file = repo.read_file(path).await?;
+    let _running = workspace.wait_graph().start(context_label.to_string());
+
+    let files = SandboxedFileStore::for_build_file(
+        repo.files().clone(),
+        workspace.loading_sandbox(),
+        package,
+        path,
+    );
+    let file = files.read_file(path).await?;
     let mut content = String::new();
     (*file).open().await?.read_to_string(&mut content).await?;
 
-    let loads: Vec<String> = {
+    let loads: Vec<(String, String)> = {
         let ast =
-            AstModule::parse(path, content.clone(), &DIALECT_BUILD).map_err(|e| e.into_anyhow())?;
+            AstModule::parse(path, content.clone(), &DIALECT_BUILD).map_err(StarlarkError::wrap)?;
         ast.loads()
             .into_iter()
-            .map(|l| l.module_id.to_string())
+            .map(|l| (l.module_id.to_string(), load_location(&l)))
             .collect()
     };
 
     let mut loaded_modules = HashMap::new();
-    let mut futures: Vec<BoxFuture<'static, anyhow::Result<FrozenModule>>> = Vec::new();
+    let mut futures: Vec<BoxFuture<'static, anyhow::Result<LoadedBzl>>> = Vec::new();
     let mut module_ids = Vec::new();
 
-    for load_str in &loads {
-        let load_label = crate::bazel::label::parse_label(load_str, &context_label)
-            .map_err(|e| anyhow::anyhow!("Failed to parse label: {:?}", e.to_string()))?;
-        let canonical_load = repo
-            .resolve_label(load_label)
-            .ok_or_else(|| anyhow::anyhow!("Cannot resolve repo mapping for {:?}", load_str))?;
+    for (load_str, location) in &loads {
+        let load_label =
+            crate::bazel::label::parse_label(load_str, &context_label).map_err(|e| {
+                LoadingError::InvalidLabel {
+                    reason: e.to_string(),
+                }
+            })?;
+        let canonical_load = load_label
+            .into_canonical(|r| workspace.resolve_repo(&repo, r))
+            .ok_or_else(|| ResolutionError::RepositoryNotVisible {
+                apparent: load_str.to_string(),
+                from: context_label.to_string(),
+            })?;
 
-        futures.push(
-            eval_bzl_recursive(workspace.clone(), repo.clone(), canonical_load.into_owned())
-                .boxed(),
+        workspace.record_bzl_load(
+            context_label.clone().into_owned(),
+            canonical_load.clone().into_owned(),
         );
+        let graph = workspace.wait_graph().clone();
+        let (from, to) = (context_label.to_string(), canonical_load.to_string());
+        let dependency = Dependency::load(location);
+        let load = eval_bzl_recursive(workspace.clone(), repo.clone(), canonical_load.into_owned());
+        futures.push(async move { graph.wait(&from, &to, dependency, load).await }.boxed());
         module_ids.push(load_str.clone());
     }
 
     let results = futures::future::try_join_all(futures).await?;
-    for (module_id, frozen) in module_ids.into_iter().zip(results) {
-        loaded_modules.insert(module_id, frozen);
+    for (module_id, loaded) in module_ids.into_iter().zip(results) {
+        loaded_modules.insert(module_id, loaded.module);
     }
 
     let globals = super::globals::build::build_globals_builder().build();
+    let limits = workspace.eval_limits();
 
     let extra = crate::starlark::globals::build::BuildExtra {
         rules: std::cell::RefCell::new(HashMap::new()),
@@ -188,6 +263,7 @@ pub async fn eval_build(
             modules: &loaded_modules,
         };
         let mut eval = Evaluator::new(&starlark_module);
+        limits.install(&mut eval, &context_label.to_string());
         eval.set_loader(&loader);
         eval.extra = Some(&extra);
 
@@ -195,7 +271,7 @@ pub async fn eval_build(
         eval.eval_module(ast, &globals)?;
         Ok::<_, starlark::Error>(())
     })
-    .map_err(|e| e.into_anyhow())?;
+    .map_err(StarlarkError::wrap)?;
 
     Ok(extra.rules.into_inner())
 }
@@ -0,0 +1,79 @@
+//! `--starlark:expr`, the Starlark expression `cquery` and `aquery` print for each target or action with
+//! `--output=starlark`, for output formats razel has no formatter of its own for.
+//!
+//! The expression can only compute: it sees the standard globals, none of which do I/O, and the one value it is
+//! evaluated for. `def`, `lambda` and `load` are not allowed, and evaluation is held to `--max_starlark_heap_mb` and
+//! `--max_starlark_steps`, like that of a BUILD file.
+
+use crate::exit_code::{ExitCode, WithExitCode};
+use crate::starlark::limits::EvalLimits;
+use starlark::environment::{Globals, Module};
+use starlark::eval::Evaluator;
+use starlark::syntax::{AstModule, Dialect};
+use starlark::values::{Heap, Value};
+
+/// The name evaluation errors give the expression.
+const NAME: &str = "--starlark:expr";
+
+/// A parsed `--starlark:expr`.
+pub struct OutputExpr {
+    ast: AstModule,
+    limits: EvalLimits,
+}
+
+impl OutputExpr {
+    /// Parses the expression `source`, which is evaluated within `limits`.
+    pub fn parse(source: &str, limits: EvalLimits) -> anyhow::Result<Self> {
+        let dialect = Dialect {
+            enable_def: false,
+            enable_lambda: false,
+            enable_load: false,
+            ..Dialect::Standard
+        };
+        let ast = AstModule::parse(NAME, source.to_string(), &dialect)
+            .map_err(|e| anyhow::anyhow!("Failed to parse {NAME}: {e}"))
+            .exit_code(ExitCode::CommandLineError)?;
+        Ok(Self { ast, limits })
+    }
+
+    /// Evaluates the expression with `name` bound to what `value` allocates, and formats the result as Bazel prints
+    /// it: strings as they are, and other values as `str()` formats them. Errors name `subject`, what the expression
+    /// is evaluated for.
+    pub fn format(
+        &self,
+        subject: &str,
+        name: &str,
+        value: impl for<'v> FnOnce(Heap<'v>) -> Value<'v>,
+    ) -> Result<String, String> {
+        Module::with_temp_heap(|module| {
+            module.set(name, value(module.heap()));
+            let mut eval = Evaluator::new(&module);
+            self.limits.install(&mut eval, NAME);
+            eval.eval_module(self.ast.clone(), &Globals::standard())
+                .map(|value| value.to_str())
+                .map_err(|e| format!("{NAME} failed for {subject}: {e}"))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_expr() {
+        let format = |source: &str| {
+            OutputExpr::parse(source, EvalLimits::default())
+                .unwrap()
+                .format("//:a", "x", |heap| heap.alloc("a"))
+        };
+        assert_eq!(format("x").unwrap(), "a");
+        assert_eq!(format("[x, len(x)]").unwrap(), "[\"a\", 1]");
+        let err = format("y").unwrap_err();
+        assert!(err.starts_with("--starlark:expr failed for //:a"), "{err}");
+
+        for source in ["(lambda: x)()", "load('//:a.bzl', 'y')", "def f(): pass"] {
+            assert!(OutputExpr::parse(source, EvalLimits::default()).is_err());
+        }
+    }
+}
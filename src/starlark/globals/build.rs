@@ -1,13 +1,15 @@
-use crate::bazel::rule::Rule;
+use crate::bazel::rule::{AttrValue, DefinitionDigest, Rule};
 use starlark::any::ProvidesStaticType;
 use starlark::collections::SmallMap;
 use starlark::environment::GlobalsBuilder;
 use starlark::eval::Evaluator;
 use starlark::starlark_module;
 use starlark::values::Value;
+use starlark::values::dict::DictRef;
+use starlark::values::list::ListRef;
 use starlark::values::none::NoneType;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 #[derive(Debug, ProvidesStaticType)]
 pub(crate) struct BuildExtra {
@@ -20,6 +22,69 @@ pub(crate) fn build_globals_builder() -> GlobalsBuilder {
     b
 }
 
+/// Records the rule `name` of class `rule_class`, declared with `kwargs`, if `eval` is evaluating a BUILD file.
+fn declare(eval: &Evaluator, name: &str, rule_class: &str, kwargs: &SmallMap<&str, Value>) {
+    let Some(extra) = eval
+        .extra
+        .as_ref()
+        .and_then(|e| e.downcast_ref::<BuildExtra>())
+    else {
+        return;
+    };
+    // The outermost call is the one in the BUILD file, even for a rule a macro declares.
+    let location = eval
+        .call_stack()
+        .frames
+        .iter()
+        .find_map(|frame| frame.location.as_ref())
+        .map(|span| {
+            let begin = span.resolve_span().begin;
+            format!(
+                "{}:{}:{}",
+                span.filename(),
+                begin.line + 1,
+                begin.column + 1
+            )
+        });
+    extra.rules.borrow_mut().insert(
+        name.to_string(),
+        Rule {
+            name: name.to_string(),
+            rule_class: rule_class.to_string(),
+            definition: DefinitionDigest::native(),
+            attributes: attributes(kwargs),
+            location,
+        },
+    );
+}
+
+/// The attributes in `kwargs`, other than `name`.
+fn attributes(kwargs: &SmallMap<&str, Value>) -> BTreeMap<String, AttrValue> {
+    kwargs
+        .iter()
+        .map(|(name, value)| {
+            let value = if let Some(s) = value.unpack_str() {
+                AttrValue::String(s.to_string())
+            } else if let Some(strings) = ListRef::from_value(*value).and_then(|list| {
+                list.iter()
+                    .map(|v| v.unpack_str().map(str::to_string))
+                    .collect::<Option<Vec<_>>>()
+            }) {
+                AttrValue::List(strings)
+            } else if let Some(entries) = DictRef::from_value(*value).and_then(|dict| {
+                dict.iter()
+                    .map(|(k, v)| Some((k.unpack_str()?.to_string(), v.unpack_str()?.to_string())))
+                    .collect::<Option<BTreeMap<_, _>>>()
+            }) {
+                AttrValue::Dict(entries)
+            } else {
+                AttrValue::Other(value.to_repr())
+            };
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
 #[starlark_module]
 pub(crate) fn build_globals(builder: &mut GlobalsBuilder) {
     fn rule(
@@ -33,106 +98,127 @@ pub(crate) fn build_globals(builder: &mut GlobalsBuilder) {
 
     fn genrule(
         #[starlark(require = named)] name: &str,
-        #[starlark(kwargs)] _kwargs: SmallMap<&str, Value>,
+        #[starlark(kwargs)] kwargs: SmallMap<&str, Value>,
         eval: &mut Evaluator,
     ) -> starlark::Result<NoneType> {
-        if let Some(extra) = eval
-            .extra
-            .as_ref()
-            .and_then(|e| e.downcast_ref::<BuildExtra>())
-        {
-            extra.rules.borrow_mut().insert(
-                name.to_string(),
-                Rule {
-                    name: name.to_string(),
-                    rule_class: "genrule".to_string(),
-                },
-            );
-        }
+        declare(eval, name, "genrule", &kwargs);
         Ok(NoneType)
     }
 
     fn cc_library(
         #[starlark(require = named)] name: &str,
-        #[starlark(kwargs)] _kwargs: SmallMap<&str, Value>,
+        #[starlark(kwargs)] kwargs: SmallMap<&str, Value>,
         eval: &mut Evaluator,
     ) -> starlark::Result<NoneType> {
-        if let Some(extra) = eval
-            .extra
-            .as_ref()
-            .and_then(|e| e.downcast_ref::<BuildExtra>())
-        {
-            extra.rules.borrow_mut().insert(
-                name.to_string(),
-                Rule {
-                    name: name.to_string(),
-                    rule_class: "cc_library".to_string(),
-                },
-            );
-        }
+        declare(eval, name, "cc_library", &kwargs);
         Ok(NoneType)
     }
 
     fn cc_binary(
         #[starlark(require = named)] name: &str,
-        #[starlark(kwargs)] _kwargs: SmallMap<&str, Value>,
+        #[starlark(kwargs)] kwargs: SmallMap<&str, Value>,
         eval: &mut Evaluator,
     ) -> starlark::Result<NoneType> {
-        if let Some(extra) = eval
-            .extra
-            .as_ref()
-            .and_then(|e| e.downcast_ref::<BuildExtra>())
-        {
-            extra.rules.borrow_mut().insert(
-                name.to_string(),
-                Rule {
-                    name: name.to_string(),
-                    rule_class: "cc_binary".to_string(),
-                },
-            );
-        }
+        declare(eval, name, "cc_binary", &kwargs);
         Ok(NoneType)
     }
 
     fn filegroup(
         #[starlark(require = named)] name: &str,
-        #[starlark(kwargs)] _kwargs: SmallMap<&str, Value>,
+        #[starlark(kwargs)] kwargs: SmallMap<&str, Value>,
         eval: &mut Evaluator,
     ) -> starlark::Result<NoneType> {
-        if let Some(extra) = eval
-            .extra
-            .as_ref()
-            .and_then(|e| e.downcast_ref::<BuildExtra>())
-        {
-            extra.rules.borrow_mut().insert(
-                name.to_string(),
-                Rule {
-                    name: name.to_string(),
-                    rule_class: "filegroup".to_string(),
-                },
-            );
-        }
+        declare(eval, name, "filegroup", &kwargs);
         Ok(NoneType)
     }
 
     fn sh_binary(
         #[starlark(require = named)] name: &str,
-        #[starlark(kwargs)] _kwargs: SmallMap<&str, Value>,
+        #[starlark(kwargs)] kwargs: SmallMap<&str, Value>,
+        eval: &mut Evaluator,
+    ) -> starlark::Result<NoneType> {
+        declare(eval, name, "sh_binary", &kwargs);
+        Ok(NoneType)
+    }
+
+    fn sh_test(
+        #[starlark(require = named)] name: &str,
+        #[starlark(kwargs)] kwargs: SmallMap<&str, Value>,
+        eval: &mut Evaluator,
+    ) -> starlark::Result<NoneType> {
+        declare(eval, name, "sh_test", &kwargs);
+        Ok(NoneType)
+    }
+
+    fn genquery(
+        #[starlark(require = named)] name: &str,
+        #[starlark(kwargs)] kwargs: SmallMap<&str, Value>,
+        eval: &mut Evaluator,
+    ) -> starlark::Result<NoneType> {
+        declare(eval, name, "genquery", &kwargs);
+        Ok(NoneType)
+    }
+
+    fn platform(
+        #[starlark(require = named)] name: &str,
+        #[starlark(kwargs)] kwargs: SmallMap<&str, Value>,
+        eval: &mut Evaluator,
+    ) -> starlark::Result<NoneType> {
+        declare(eval, name, "platform", &kwargs);
+        Ok(NoneType)
+    }
+
+    fn constraint_setting(
+        #[starlark(require = named)] name: &str,
+        #[starlark(kwargs)] kwargs: SmallMap<&str, Value>,
+        eval: &mut Evaluator,
+    ) -> starlark::Result<NoneType> {
+        declare(eval, name, "constraint_setting", &kwargs);
+        Ok(NoneType)
+    }
+
+    fn constraint_value(
+        #[starlark(require = named)] name: &str,
+        #[starlark(kwargs)] kwargs: SmallMap<&str, Value>,
+        eval: &mut Evaluator,
+    ) -> starlark::Result<NoneType> {
+        declare(eval, name, "constraint_value", &kwargs);
+        Ok(NoneType)
+    }
+
+    fn toolchain_type(
+        #[starlark(require = named)] name: &str,
+        #[starlark(kwargs)] kwargs: SmallMap<&str, Value>,
+        eval: &mut Evaluator,
+    ) -> starlark::Result<NoneType> {
+        declare(eval, name, "toolchain_type", &kwargs);
+        Ok(NoneType)
+    }
+
+    fn toolchain(
+        #[starlark(require = named)] name: &str,
+        #[starlark(kwargs)] kwargs: SmallMap<&str, Value>,
+        eval: &mut Evaluator,
+    ) -> starlark::Result<NoneType> {
+        declare(eval, name, "toolchain", &kwargs);
+        Ok(NoneType)
+    }
+
+    fn config_setting(
+        #[starlark(require = named)] name: &str,
+        #[starlark(kwargs)] kwargs: SmallMap<&str, Value>,
+        eval: &mut Evaluator,
+    ) -> starlark::Result<NoneType> {
+        declare(eval, name, "config_setting", &kwargs);
+        Ok(NoneType)
+    }
+
+    fn config_setting_group(
+        #[starlark(require = named)] name: &str,
+        #[starlark(kwargs)] kwargs: SmallMap<&str, Value>,
         eval: &mut Evaluator,
     ) -> starlark::Result<NoneType> {
-        if let Some(extra) = eval
-            .extra
-            .as_ref()
-            .and_then(|e| e.downcast_ref::<BuildExtra>())
-        {
-            extra.rules.borrow_mut().insert(
-                name.to_string(),
-                Rule {
-                    name: name.to_string(),
-                    rule_class: "sh_binary".to_string(),
-                },
-            );
-        }
+        declare(eval, name, "config_setting_group", &kwargs);
         Ok(NoneType)
     }
 }
@@ -1,4 +1,7 @@
-use crate::bazel::bzlmod::BazelDep;
+use crate::bazel::bzlmod::{
+    ArchiveOverride, BazelDep, ExtensionUsage, GitOverride, LocalPathOverride,
+    MultipleVersionOverride, Overrides, Patches, SingleVersionOverride, parse_bazel_compatibility,
+};
 use crate::bazel::package::BoxFileStore;
 use allocative::Allocative;
 use derive_more::Display;
@@ -6,7 +9,7 @@ use starlark::any::ProvidesStaticType;
 use starlark::collections::SmallMap;
 use starlark::environment::GlobalsBuilder;
 use starlark::eval::Evaluator;
-use starlark::values::list::UnpackList;
+use starlark::values::list::{ListRef, UnpackList};
 use starlark::values::none::{NoneOr, NoneType};
 use starlark::values::tuple::UnpackTuple;
 use starlark::values::{NoSerialize, StarlarkValue, Value, starlark_value};
@@ -14,8 +17,13 @@ use starlark::{starlark_module, starlark_simple_value};
 use std::default::Default;
 use std::sync::{Mutex, MutexGuard};
 
+/// What `use_extension` returns, for `use_repo` to import repositories with.
 #[derive(Debug, Display, ProvidesStaticType, NoSerialize, Allocative)]
-struct ModuleExtensionProxy;
+#[display("module_extension_proxy")]
+struct ModuleExtensionProxy {
+    /// The index of the usage in `ModuleBuilder::extension_usages`, or `None` if the usage is ignored.
+    usage: Option<usize>,
+}
 starlark_simple_value!(ModuleExtensionProxy);
 
 #[starlark_value(type = "module_extension_proxy")]
@@ -35,12 +43,16 @@ pub(crate) struct ModuleBuilder<'a> {
     pub(crate) name: Option<String>,
     pub(crate) version: Option<String>,
     pub(crate) repo_name: Option<String>,
+    pub(crate) compatibility_level: u32,
+    pub(crate) bazel_compatibility: Vec<String>,
     pub(crate) bazel_deps: Vec<BazelDep>,
-    pub(crate) archive_overrides: Vec<String>,
-    pub(crate) local_path_overrides: Vec<String>,
-    pub(crate) git_overrides: Vec<String>,
-    pub(crate) use_extensions: Vec<String>,
+    pub(crate) overrides: Overrides,
+    pub(crate) extension_usages: Vec<ExtensionUsage>,
     pub(crate) includes: Vec<String>,
+    /// Labels of `register_execution_platforms`, in order.
+    pub(crate) execution_platforms: Vec<String>,
+    /// Labels or target patterns of `register_toolchains`, in order.
+    pub(crate) toolchains: Vec<String>,
     #[allocative(skip)]
     pub(crate) files: BoxFileStore<'a>,
 }
@@ -53,23 +65,43 @@ impl<'a> ModuleBuilder<'a> {
             name: None,
             version: None,
             repo_name: None,
+            compatibility_level: 0,
+            bazel_compatibility: Vec::new(),
             bazel_deps: Vec::new(),
-            archive_overrides: Vec::new(),
-            local_path_overrides: Vec::new(),
-            git_overrides: Vec::new(),
-            use_extensions: Vec::new(),
+            overrides: Overrides::default(),
+            extension_usages: Vec::new(),
             includes: Vec::new(),
+            execution_platforms: Vec::new(),
+            toolchains: Vec::new(),
             files,
         }
     }
 
     pub(crate) fn merge(&mut self, other: ModuleBuilder) {
         self.bazel_deps.extend(other.bazel_deps);
-        self.archive_overrides.extend(other.archive_overrides);
-        self.local_path_overrides.extend(other.local_path_overrides);
-        self.git_overrides.extend(other.git_overrides);
-        self.use_extensions.extend(other.use_extensions);
+        self.overrides
+            .single_version
+            .extend(other.overrides.single_version);
+        self.overrides
+            .multiple_version
+            .extend(other.overrides.multiple_version);
+        self.overrides.archive.extend(other.overrides.archive);
+        self.overrides.git.extend(other.overrides.git);
+        self.overrides.local_path.extend(other.overrides.local_path);
+        self.extension_usages.extend(other.extension_usages);
         self.includes.extend(other.includes);
+        self.execution_platforms.extend(other.execution_platforms);
+        self.toolchains.extend(other.toolchains);
+    }
+
+    /// Fails if `module` already has an override, as a module can only have one.
+    fn check_not_overridden(&self, module: &str) -> starlark::Result<()> {
+        if self.overrides.contains(module) {
+            return Err(starlark::Error::new_native(anyhow::anyhow!(
+                "The module {module} has multiple overrides"
+            )));
+        }
+        Ok(())
     }
 }
 
@@ -89,7 +121,7 @@ impl ModuleExtra {
         )))
     }
 
-    #[allow(dead_code)]
+    /// Ignores `dev_dependency = True` declarations even in the root module, as `--ignore_dev_dependency` asks.
     pub fn with_ignore_dev_dependency(self, ignore_dev_dependency: bool) -> Self {
         self.0.lock().unwrap().ignore_dev_dependency = ignore_dev_dependency;
         self
@@ -111,22 +143,71 @@ impl ModuleExtra {
     }
 }
 
+/// The `patch_strip` argument of an override, which can't be negative.
+fn unpack_patch_strip(patch_strip: i32) -> starlark::Result<usize> {
+    usize::try_from(patch_strip).map_err(|_| {
+        starlark::Error::new_native(anyhow::anyhow!(
+            "patch_strip must not be negative, got {patch_strip}"
+        ))
+    })
+}
+
+/// A `compatibility_level` argument, which can't be negative.
+fn unpack_compatibility_level(name: &str, level: i32) -> starlark::Result<u32> {
+    u32::try_from(level).map_err(|_| {
+        starlark::Error::new_native(anyhow::anyhow!("{name} must not be negative, got {level}"))
+    })
+}
+
 #[allow(unused)] // for now
 #[starlark_module]
 pub(crate) fn module_bazel(builder: &mut GlobalsBuilder) {
     /// Declares that a module depends on an archive at a remote location.
     /// https://bazel.build/rules/lib/globals/module#archive_override
+    #[allow(clippy::too_many_arguments)]
     fn archive_override(
         module_name: &str,
-        #[starlark(kwargs)] kwargs: Value,
+        urls: Value,
+        #[starlark(default = "")] integrity: &str,
+        #[starlark(default = "")] strip_prefix: &str,
+        #[starlark(default=UnpackList::default())] patches: UnpackList<String>,
+        #[starlark(default=UnpackList::default())] patch_cmds: UnpackList<String>,
+        #[starlark(default = 0)] patch_strip: i32,
         eval: &mut Evaluator,
     ) -> starlark::Result<NoneType> {
-        let _ = kwargs;
         let mut bzl_module = ModuleExtra::from_eval(eval).builder();
-        if bzl_module.is_root_module {
-            bzl_module.archive_overrides.push(module_name.to_string());
-            todo!();
+        if !bzl_module.is_root_module {
+            // Only the root module's overrides count.
+            return Ok(NoneType);
         }
+        let urls = match urls.unpack_str() {
+            Some(url) => vec![url.to_string()],
+            None => ListRef::from_value(urls)
+                .and_then(|list| {
+                    list.iter()
+                        .map(|url| url.unpack_str().map(str::to_string))
+                        .collect::<Option<Vec<_>>>()
+                })
+                .ok_or_else(|| {
+                    starlark::Error::new_native(anyhow::anyhow!(
+                        "urls must be a string or a list of strings, got {urls}"
+                    ))
+                })?,
+        };
+        bzl_module.check_not_overridden(module_name)?;
+        bzl_module.overrides.archive.insert(
+            module_name.to_string(),
+            ArchiveOverride {
+                urls,
+                integrity: integrity.to_string(),
+                strip_prefix: strip_prefix.to_string(),
+                patches: Patches {
+                    patches: patches.items,
+                    patch_cmds: patch_cmds.items,
+                    patch_strip: unpack_patch_strip(patch_strip)?,
+                },
+            },
+        );
         Ok(NoneType)
     }
 
@@ -140,9 +221,12 @@ pub(crate) fn module_bazel(builder: &mut GlobalsBuilder) {
         #[starlark(default = false)] dev_dependency: bool,
         eval: &mut Evaluator,
     ) -> starlark::Result<NoneType> {
-        let _ = max_compatibility_level;
+        // -1, the default, means no more than the level of `version`.
+        let max_compatibility_level = (max_compatibility_level != -1)
+            .then(|| unpack_compatibility_level("max_compatibility_level", max_compatibility_level))
+            .transpose()?;
         let mut bzl_module = ModuleExtra::from_eval(eval).builder();
-        if bzl_module.is_root_module || (dev_dependency && !bzl_module.ignore_dev_dependency) {
+        if !dev_dependency || (bzl_module.is_root_module && !bzl_module.ignore_dev_dependency) {
             let repo_name = match repo_name {
                 NoneOr::None => name,
                 NoneOr::Other(s) => {
@@ -158,6 +242,7 @@ pub(crate) fn module_bazel(builder: &mut GlobalsBuilder) {
                 version: version.to_string(),
                 repo_name: repo_name.to_string(),
                 dev_dependency,
+                max_compatibility_level,
             });
         }
         Ok(NoneType)
@@ -165,17 +250,43 @@ pub(crate) fn module_bazel(builder: &mut GlobalsBuilder) {
 
     /// Declares that a module depends on a commit from a git repository.
     /// https://bazel.build/rules/lib/globals/module#git_override
+    #[allow(clippy::too_many_arguments)]
     fn git_override(
         module_name: &str,
-        #[starlark(kwargs)] kwargs: Value,
+        remote: &str,
+        #[starlark(default = "")] commit: &str,
+        #[starlark(default = "")] tag: &str,
+        #[starlark(default=UnpackList::default())] patches: UnpackList<String>,
+        #[starlark(default=UnpackList::default())] patch_cmds: UnpackList<String>,
+        #[starlark(default = 0)] patch_strip: i32,
+        #[starlark(default = false)] init_submodules: bool,
         eval: &mut Evaluator,
     ) -> starlark::Result<NoneType> {
-        let _ = kwargs;
         let mut bzl_module = ModuleExtra::from_eval(eval).builder();
-        if bzl_module.is_root_module {
-            bzl_module.git_overrides.push(module_name.to_string());
-            todo!();
+        if !bzl_module.is_root_module {
+            // Only the root module's overrides count.
+            return Ok(NoneType);
         }
+        if commit.is_empty() == tag.is_empty() {
+            return Err(starlark::Error::new_native(anyhow::anyhow!(
+                "git_override of {module_name} needs exactly one of commit and tag"
+            )));
+        }
+        bzl_module.check_not_overridden(module_name)?;
+        bzl_module.overrides.git.insert(
+            module_name.to_string(),
+            GitOverride {
+                remote: remote.to_string(),
+                commit: commit.to_string(),
+                tag: tag.to_string(),
+                init_submodules,
+                patches: Patches {
+                    patches: patches.items,
+                    patch_cmds: patch_cmds.items,
+                    patch_strip: unpack_patch_strip(patch_strip)?,
+                },
+            },
+        );
         Ok(NoneType)
     }
 
@@ -211,12 +322,18 @@ pub(crate) fn module_bazel(builder: &mut GlobalsBuilder) {
         path: &str,
         eval: &mut Evaluator,
     ) -> starlark::Result<NoneType> {
-        let _ = module_name;
-        let _ = path;
-        let bzl_module = ModuleExtra::from_eval(eval).builder();
-        if bzl_module.is_root_module {
-            todo!();
+        let mut bzl_module = ModuleExtra::from_eval(eval).builder();
+        if !bzl_module.is_root_module {
+            // Only the root module's overrides count.
+            return Ok(NoneType);
         }
+        bzl_module.check_not_overridden(module_name)?;
+        bzl_module.overrides.local_path.insert(
+            module_name.to_string(),
+            LocalPathOverride {
+                path: path.to_string(),
+            },
+        );
         Ok(NoneType)
     }
 
@@ -228,8 +345,17 @@ pub(crate) fn module_bazel(builder: &mut GlobalsBuilder) {
         #[starlark(default=UnpackList::default())] bazel_compatibility: UnpackList<String>,
         eval: &mut Evaluator,
     ) -> starlark::Result<NoneType> {
-        let _ = compatibility_level;
-        let _ = bazel_compatibility;
+        let compatibility_level =
+            unpack_compatibility_level("compatibility_level", compatibility_level)?;
+        if let Some(requirement) = bazel_compatibility
+            .items
+            .iter()
+            .find(|r| parse_bazel_compatibility(r).is_none())
+        {
+            return Err(starlark::Error::new_native(anyhow::anyhow!(
+                "Invalid bazel_compatibility {requirement:?}: expected >=, <=, >, < or - then a version like 7.0.0"
+            )));
+        }
         let mut bzl_module = ModuleExtra::from_eval(eval).builder();
         if bzl_module.name.is_some() {
             return Err(starlark::Error::new_native(anyhow::anyhow!(
@@ -238,23 +364,40 @@ pub(crate) fn module_bazel(builder: &mut GlobalsBuilder) {
         }
         bzl_module.name = Some(name);
         bzl_module.version = Some(version);
-        bzl_module.repo_name = Some(repo_name);
+        // Without a repo_name, the module is known by its name.
+        bzl_module.repo_name = (!repo_name.is_empty()).then_some(repo_name);
+        bzl_module.compatibility_level = compatibility_level;
+        bzl_module.bazel_compatibility = bazel_compatibility.items;
         Ok(NoneType)
     }
 
+    /// Lets several versions of a module be used side by side, each module that depends on it getting the lowest
+    /// of `versions` at or above the one it asks for.
+    /// https://bazel.build/rules/lib/globals/module#multiple_version_override
     fn multiple_version_override(
         module_name: &str,
-        versions: UnpackList<&str>,
+        versions: UnpackList<String>,
         #[starlark(default = "")] registry: &str,
         eval: &mut Evaluator,
     ) -> starlark::Result<NoneType> {
-        let _ = module_name;
-        let _ = versions;
-        let _ = registry;
-        let bzl_module = ModuleExtra::from_eval(eval).builder();
-        if bzl_module.is_root_module {
-            todo!();
+        let mut bzl_module = ModuleExtra::from_eval(eval).builder();
+        if !bzl_module.is_root_module {
+            // Only the root module's overrides count.
+            return Ok(NoneType);
+        }
+        if versions.items.len() < 2 {
+            return Err(starlark::Error::new_native(anyhow::anyhow!(
+                "multiple_version_override of {module_name} needs at least two versions"
+            )));
         }
+        bzl_module.check_not_overridden(module_name)?;
+        bzl_module.overrides.multiple_version.insert(
+            module_name.to_string(),
+            MultipleVersionOverride {
+                versions: versions.items,
+                registry: registry.to_string(),
+            },
+        );
         Ok(NoneType)
     }
 
@@ -275,50 +418,62 @@ pub(crate) fn module_bazel(builder: &mut GlobalsBuilder) {
     }
 
     fn register_execution_platforms(
-        #[starlark(default = false)] dev_dependency: bool,
         #[starlark(args)] platform_labels: UnpackTuple<&str>,
+        #[starlark(require = named, default = false)] dev_dependency: bool,
         eval: &mut Evaluator,
     ) -> starlark::Result<NoneType> {
-        let _ = platform_labels;
-        let bzl_module = ModuleExtra::from_eval(eval).builder();
-        if bzl_module.is_root_module || (dev_dependency && !bzl_module.ignore_dev_dependency) {
-            todo!();
+        let mut bzl_module = ModuleExtra::from_eval(eval).builder();
+        if !dev_dependency || (bzl_module.is_root_module && !bzl_module.ignore_dev_dependency) {
+            bzl_module
+                .execution_platforms
+                .extend(platform_labels.items.iter().map(|s| s.to_string()));
         }
         Ok(NoneType)
     }
 
     fn register_toolchains(
-        #[starlark(default = false)] dev_dependency: bool,
         #[starlark(args)] toolchain_labels: UnpackTuple<&str>,
+        #[starlark(require = named, default = false)] dev_dependency: bool,
         eval: &mut Evaluator,
     ) -> starlark::Result<NoneType> {
-        let _ = toolchain_labels;
-        let bzl_module = ModuleExtra::from_eval(eval).builder();
-        if bzl_module.is_root_module || (dev_dependency && !bzl_module.ignore_dev_dependency) {
-            todo!();
+        let mut bzl_module = ModuleExtra::from_eval(eval).builder();
+        if !dev_dependency || (bzl_module.is_root_module && !bzl_module.ignore_dev_dependency) {
+            bzl_module
+                .toolchains
+                .extend(toolchain_labels.items.iter().map(|s| s.to_string()));
         }
         Ok(NoneType)
     }
 
+    /// Uses one version of a module whatever versions the dependency graph asks for, from `registry`, with patches.
+    /// https://bazel.build/rules/lib/globals/module#single_version_override
     fn single_version_override(
         module_name: &str,
         #[starlark(default = "")] version: &str,
         #[starlark(default = "")] registry: &str,
-        #[starlark(default=UnpackList::default())] patches: UnpackList<&str>,
-        #[starlark(default=UnpackList::default())] patch_cmds: UnpackList<&str>,
+        #[starlark(default=UnpackList::default())] patches: UnpackList<String>,
+        #[starlark(default=UnpackList::default())] patch_cmds: UnpackList<String>,
         #[starlark(default = 0)] patch_strip: i32,
         eval: &mut Evaluator,
     ) -> starlark::Result<NoneType> {
-        let _ = module_name;
-        let _ = version;
-        let _ = registry;
-        let _ = patches;
-        let _ = patch_cmds;
-        let _ = patch_strip;
-        let bzl_module = ModuleExtra::from_eval(eval).builder();
-        if bzl_module.is_root_module {
-            todo!();
+        let mut bzl_module = ModuleExtra::from_eval(eval).builder();
+        if !bzl_module.is_root_module {
+            // Only the root module's overrides count.
+            return Ok(NoneType);
         }
+        bzl_module.check_not_overridden(module_name)?;
+        bzl_module.overrides.single_version.insert(
+            module_name.to_string(),
+            SingleVersionOverride {
+                version: version.to_string(),
+                registry: registry.to_string(),
+                patches: Patches {
+                    patches: patches.items,
+                    patch_cmds: patch_cmds.items,
+                    patch_strip: unpack_patch_strip(patch_strip)?,
+                },
+            },
+        );
         Ok(NoneType)
     }
 
@@ -330,31 +485,50 @@ pub(crate) fn module_bazel(builder: &mut GlobalsBuilder) {
         #[starlark(default = false)] dev_dependency: bool,
         #[starlark(default = false)] isolate: bool,
         eval: &mut Evaluator,
-    ) -> starlark::Result<NoneOr<ModuleExtensionProxy>> {
-        let _ = extension_bzl_file;
-        let _ = extension_name;
-        let bzl_module = ModuleExtra::from_eval(eval).builder();
-        if !bzl_module.is_root_module && (!dev_dependency || bzl_module.ignore_dev_dependency) {
+    ) -> starlark::Result<ModuleExtensionProxy> {
+        let mut bzl_module = ModuleExtra::from_eval(eval).builder();
+        // Other modules' dev usages are ignored, as are the root module's with --ignore_dev_dependency.
+        if dev_dependency && (!bzl_module.is_root_module || bzl_module.ignore_dev_dependency) {
             // "usage of module extension is ignored"
-            return Ok(NoneOr::None);
+            return Ok(ModuleExtensionProxy { usage: None });
         }
         if isolate {
-            todo!()
+            return Err(starlark::Error::new_native(anyhow::anyhow!(
+                "use_extension of {extension_name} with isolate = True is not supported yet"
+            )));
         }
-        todo!();
+        bzl_module.extension_usages.push(ExtensionUsage {
+            extension_bzl_file: extension_bzl_file.to_string(),
+            extension_name: extension_name.to_string(),
+            imports: Vec::new(),
+        });
+        Ok(ModuleExtensionProxy {
+            usage: Some(bzl_module.extension_usages.len() - 1),
+        })
     }
 
+    /// Imports repositories generated by a module extension, by the name the extension gives them or, as keyword
+    /// arguments, by another apparent name.
+    /// https://bazel.build/rules/lib/globals/module#use_repo
     fn use_repo(
         extension_proxy: &ModuleExtensionProxy,
         #[starlark(args)] args: UnpackTuple<&str>,
         #[starlark(kwargs)] kwargs: SmallMap<&str, &str>,
         eval: &mut Evaluator,
     ) -> starlark::Result<NoneType> {
-        let _ = extension_proxy;
-        let _ = args;
-        let _ = kwargs;
-        let _ = eval;
-        todo!();
+        let Some(usage) = extension_proxy.usage else {
+            return Ok(NoneType);
+        };
+        let mut bzl_module = ModuleExtra::from_eval(eval).builder();
+        let imports = args
+            .items
+            .into_iter()
+            .map(|name| (name, name))
+            .chain(kwargs);
+        bzl_module.extension_usages[usage].imports.extend(
+            imports.map(|(apparent, exported)| (apparent.to_string(), exported.to_string())),
+        );
+        Ok(NoneType)
     }
 
     fn use_repo_rule(
@@ -0,0 +1,125 @@
+//! Limits on the resources one Starlark evaluation (of a BUILD, .bzl or MODULE.bazel file) may use, so a pathological
+//! macro fails its own package instead of taking the whole server down with it.
+//!
+//! The limits are checked before every statement, which costs a little, so evaluation is only instrumented when a
+//! limit is set.
+
+use crate::error::LoadingError;
+use starlark::codemap::FileSpanRef;
+use starlark::eval::{BeforeStmtFunc, BeforeStmtFuncDyn, Evaluator};
+
+/// The limits for each evaluation, from `--max_starlark_heap_mb` and `--max_starlark_steps`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EvalLimits {
+    /// The most bytes the evaluation's heap may hold.
+    pub max_heap_bytes: Option<usize>,
+    /// The most statements the evaluation may execute, counting each time a loop or function body runs one.
+    pub max_steps: Option<u64>,
+}
+
+impl EvalLimits {
+    /// The limits the flags ask for, where 0 is no limit.
+    pub fn new(max_heap_mb: usize, max_steps: u64) -> Self {
+        Self {
+            max_heap_bytes: (max_heap_mb > 0).then_some(max_heap_mb << 20),
+            max_steps: (max_steps > 0).then_some(max_steps),
+        }
+    }
+
+    /// Makes `eval` fail as soon as it goes over the limits, naming `file`, e.g. `//pkg:BUILD`, in the error. Must be
+    /// called before `eval` evaluates anything.
+    pub fn install<'e>(&self, eval: &mut Evaluator<'_, '_, 'e>, file: &str) {
+        if self.max_heap_bytes.is_none() && self.max_steps.is_none() {
+            return;
+        }
+        eval.before_stmt_for_dap(BeforeStmtFunc::from_dyn(Box::new(LimitChecker {
+            limits: *self,
+            file: file.to_string(),
+            steps: 0,
+        })));
+    }
+}
+
+struct LimitChecker {
+    limits: EvalLimits,
+    file: String,
+    steps: u64,
+}
+
+impl<'e> BeforeStmtFuncDyn<'e> for LimitChecker {
+    fn call<'v>(
+        &mut self,
+        span: FileSpanRef,
+        _continued: bool,
+        eval: &mut Evaluator<'v, '_, 'e>,
+    ) -> starlark::Result<()> {
+        self.steps += 1;
+        let limit = if let Some(max) = self.limits.max_steps.filter(|max| self.steps > *max) {
+            format!("executed more than {max} statements (--max_starlark_steps)")
+        } else if let Some(max) = self
+            .limits
+            .max_heap_bytes
+            .filter(|max| eval.heap().allocated_bytes() > *max)
+        {
+            format!("heap grew past {} MiB (--max_starlark_heap_mb)", max >> 20)
+        } else {
+            return Ok(());
+        };
+        Err(starlark::Error::new_native(anyhow::Error::new(
+            LoadingError::EvaluationLimitExceeded {
+                file: self.file.clone(),
+                line: span.resolve_span().begin.line + 1,
+                limit,
+            },
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use starlark::environment::{Globals, Module};
+    use starlark::syntax::{AstModule, Dialect};
+
+    fn eval(limits: EvalLimits, source: &str) -> Result<(), String> {
+        Module::with_temp_heap(|module| {
+            let mut eval = Evaluator::new(&module);
+            limits.install(&mut eval, "//pkg:BUILD");
+            let ast =
+                AstModule::parse("pkg/BUILD", source.to_string(), &Dialect::Standard).unwrap();
+            eval.eval_module(ast, &Globals::standard())
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        })
+    }
+
+    const LOOP: &str = "
+def f():
+    x = []
+    for i in range(100000):
+        x.append(str(i) * 100)
+
+f()
+";
+
+    #[test]
+    fn test_limits() {
+        eval(EvalLimits::default(), LOOP).unwrap();
+        eval(EvalLimits::new(1024, 1_000_000), LOOP).unwrap();
+
+        let steps = eval(EvalLimits::new(0, 1000), LOOP).unwrap_err();
+        assert!(
+            steps.contains("evaluation exceeded limits in //pkg:BUILD at line 5"),
+            "{steps}"
+        );
+        assert!(steps.contains("more than 1000 statements"), "{steps}");
+
+        let heap = eval(EvalLimits::new(1, 0), LOOP).unwrap_err();
+        assert!(
+            heap.contains(
+                "evaluation exceeded limits in //pkg:BUILD at line 5: heap grew past 1 MiB"
+            ),
+            "{heap}"
+        );
+    }
+}
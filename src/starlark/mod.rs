@@ -2,4 +2,7 @@
 
 pub(crate) mod builtins;
 pub(crate) mod eval;
+pub(crate) mod expr;
 pub(crate) mod globals;
+pub(crate) mod limits;
+pub(crate) mod sandbox;
@@ -0,0 +1,191 @@
+//! Keeping the loading phase hermetic: evaluating a BUILD or .bzl file may only read the file itself and, for a BUILD
+//! file, files within its package, as `glob()` does. The files it loads are read by their own evaluation.
+//!
+//! Evaluation reads files through a `SandboxedFileStore`, which checks every read against what the file being
+//! evaluated may see. With `--loading_sandbox=audit` a read outside of it is only reported, to find out what would
+//! break before enforcing it. Nothing in the BUILD or .bzl globals reaches the network; fetching is only for
+//! repository rules and module extensions.
+
+use crate::bazel::package::{BoxFile, BoxFileStore, DirEntry, FileStore};
+use crate::console;
+use futures::FutureExt;
+use futures::future::BoxFuture;
+use std::io::{Error, ErrorKind};
+
+/// The value of `--loading_sandbox`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LoadingSandbox {
+    /// Reads outside of what a file may see fail.
+    #[default]
+    Enforce,
+    /// Reads outside of what a file may see are reported with a warning, and allowed.
+    Audit,
+    /// Evaluation may read anything in the repository.
+    Off,
+}
+
+/// The files of a repository, as seen by the evaluation of one of its BUILD or .bzl files.
+#[derive(Debug)]
+pub struct SandboxedFileStore {
+    inner: BoxFileStore<'static>,
+    mode: LoadingSandbox,
+    /// The file being evaluated, relative to the repository root.
+    file: String,
+    /// For a BUILD file, its package, whose files it may read.
+    package: Option<String>,
+}
+
+impl SandboxedFileStore {
+    /// The files the BUILD file `path`, of `package`, may read.
+    pub fn for_build_file(
+        inner: BoxFileStore<'static>,
+        mode: LoadingSandbox,
+        package: &str,
+        path: &str,
+    ) -> Self {
+        Self {
+            inner,
+            mode,
+            file: path.to_string(),
+            package: Some(package.to_string()),
+        }
+    }
+
+    /// The files the .bzl file `path` may read: only itself.
+    pub fn for_bzl_file(inner: BoxFileStore<'static>, mode: LoadingSandbox, path: &str) -> Self {
+        Self {
+            inner,
+            mode,
+            file: path.to_string(),
+            package: None,
+        }
+    }
+
+    fn in_package(&self, path: &str) -> bool {
+        match self.package.as_deref() {
+            None => false,
+            Some("") => true,
+            Some(package) => path
+                .strip_prefix(package)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/')),
+        }
+    }
+
+    /// Checks that reading `path` is allowed, warning about it or failing if not, depending on the mode.
+    fn check(&self, path: &str, is_dir: bool) -> Result<(), Error> {
+        let allowed = self.mode == LoadingSandbox::Off
+            || self.in_package(path)
+            || (!is_dir && path == self.file);
+        if allowed {
+            return Ok(());
+        }
+        let message = format!(
+            "evaluating {} read {path}, which it neither loads nor contains",
+            self.file
+        );
+        if self.mode == LoadingSandbox::Audit {
+            console::eprintln(format_args!(
+                "WARNING: {message} (allowed by --loading_sandbox=audit)"
+            ));
+            return Ok(());
+        }
+        Err(Error::new(ErrorKind::PermissionDenied, message))
+    }
+}
+
+impl FileStore for SandboxedFileStore {
+    type File = BoxFile<'static>;
+
+    fn read_file(&self, path: &str) -> BoxFuture<'_, Result<Self::File, Error>> {
+        let path = path.to_string();
+        async move {
+            self.check(&path, false)?;
+            self.inner.read_file(&path).await
+        }
+        .boxed()
+    }
+
+    fn read_dir(&self, path: &str) -> BoxFuture<'_, Result<Vec<DirEntry>, Error>> {
+        let path = path.to_string();
+        async move {
+            self.check(&path, true)?;
+            self.inner.read_dir(&path).await
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bazel::memory::InMemoryFileStore;
+    use crate::bazel::package::{DynFileStore, TypeErasingFileStore};
+    use std::sync::Arc;
+
+    fn files() -> BoxFileStore<'static> {
+        let store = InMemoryFileStore::default();
+        for path in [
+            "pkg/BUILD",
+            "pkg/a.txt",
+            "pkg/sub/b.txt",
+            "pkgs/c.txt",
+            "defs.bzl",
+        ] {
+            store.write(path, "").unwrap();
+        }
+        Arc::from(DynFileStore::new_box(Box::new(TypeErasingFileStore(store))))
+    }
+
+    #[tokio::test]
+    async fn test_build_file_sandbox() {
+        let sandbox = SandboxedFileStore::for_build_file(
+            files(),
+            LoadingSandbox::Enforce,
+            "pkg",
+            "pkg/BUILD",
+        );
+        sandbox.read_file("pkg/BUILD").await.unwrap();
+        sandbox.read_file("pkg/sub/b.txt").await.unwrap();
+        sandbox.read_dir("pkg").await.unwrap();
+        let err = sandbox.read_file("pkgs/c.txt").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        assert!(sandbox.read_file("defs.bzl").await.is_err());
+        assert!(sandbox.read_dir("").await.is_err());
+
+        let audit =
+            SandboxedFileStore::for_build_file(files(), LoadingSandbox::Audit, "pkg", "pkg/BUILD");
+        audit.read_file("pkgs/c.txt").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bzl_file_sandbox() {
+        let sandbox =
+            SandboxedFileStore::for_bzl_file(files(), LoadingSandbox::Enforce, "defs.bzl");
+        sandbox.read_file("defs.bzl").await.unwrap();
+        assert!(sandbox.read_file("pkg/a.txt").await.is_err());
+        assert!(sandbox.read_dir("pkg").await.is_err());
+
+        let off = SandboxedFileStore::for_bzl_file(files(), LoadingSandbox::Off, "defs.bzl");
+        off.read_file("pkg/a.txt").await.unwrap();
+    }
+
+    #[test]
+    fn test_globals_have_no_io() {
+        let build = crate::starlark::globals::build::build_globals_builder().build();
+        let bzl = crate::starlark::globals::bzl::bzl_globals_builder().build();
+        for name in build.names().chain(bzl.names()) {
+            assert!(
+                ![
+                    "download",
+                    "download_and_extract",
+                    "execute",
+                    "read",
+                    "which",
+                    "getenv"
+                ]
+                .contains(&name.as_str()),
+                "{name} does IO, which BUILD and .bzl files must not"
+            );
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use crate::bazel::label::{CanonicalRepo, MAIN_REPO};
+use crate::bazel::module_graph::extension_host_of_repo;
+use crate::bazel::package::{BoxFileStore, DirEntry, File, FileStore};
+use crate::workspace::Workspace;
+use std::collections::BTreeSet;
+use std::marker::Unpin;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Materializes every external repository the main repository depends on, directly or transitively, into
+/// `--vendor_dir`.
+///
+/// Subsequent invocations with the same `--vendor_dir` resolve those repositories from the vendor directory instead of
+/// fetching them. Modules with a `local_path_override` are always used where they are, so they aren't vendored, and
+/// neither are repositories of module extensions, which razel can't generate.
+pub async fn vendor<W>(out: &mut W, workspace: Arc<Workspace>) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let vendor_dir = workspace
+        .vendor_dir()
+        .ok_or_else(|| anyhow::anyhow!("--vendor_dir must be set for the vendor command"))?;
+
+    for name in resolved_repos(&workspace).await? {
+        let dest = vendor_dir.join(name.as_str());
+        if tokio::fs::try_exists(&dest).await? {
+            out.write_all(format!("{name} is already vendored\n").as_bytes())
+                .await?;
+            continue;
+        }
+        if workspace.is_local_path(&name) {
+            out.write_all(format!("{name} has a local_path_override, skipping\n").as_bytes())
+                .await?;
+            continue;
+        }
+        if extension_host_of_repo(name.as_str()).is_some() {
+            out.write_all(
+                format!("{name} is generated by a module extension, skipping\n").as_bytes(),
+            )
+            .await?;
+            continue;
+        }
+
+        let repo = workspace.repository(&name).await?;
+        vendor_file_store(repo.files(), &dest).await?;
+        out.write_all(format!("Vendored {name} into {}\n", dest.display()).as_bytes())
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// The canonical names of every external repository reachable from the main repository through repository mappings,
+/// sorted.
+async fn resolved_repos(workspace: &Workspace) -> anyhow::Result<Vec<CanonicalRepo<'static>>> {
+    let mut seen = BTreeSet::from([MAIN_REPO.as_str().to_string()]);
+    let mut stack = vec![MAIN_REPO];
+    let mut repos = Vec::new();
+    while let Some(name) = stack.pop() {
+        // Repositories of module extensions see what their host module sees, which is already visited, and may not
+        // exist to be evaluated.
+        if extension_host_of_repo(name.as_str()).is_some() {
+            continue;
+        }
+        let repo = workspace.repository(&name).await?;
+        for dep in repo.repo_mapping().values() {
+            if seen.insert(dep.as_str().to_string()) {
+                let dep = dep.clone().into_owned();
+                repos.push(dep.clone());
+                stack.push(dep);
+            }
+        }
+    }
+    repos.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+    Ok(repos)
+}
+
+/// Copies the contents of a `FileStore` into the vendor directory `dest`, which must not exist yet.
+///
+/// The files are copied into a temporary directory next to `dest` first, then renamed into place, so `dest` only
+/// ever exists complete: repositories are used from the vendor directory whenever their directory exists, and an
+/// interrupted `razel vendor` mustn't leave half a repository behind.
+async fn vendor_file_store(files: &BoxFileStore<'static>, dest: &Path) -> anyhow::Result<()> {
+    let name = dest.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = dest.with_file_name(format!(".{name}.tmp"));
+    if tokio::fs::try_exists(&tmp).await? {
+        // Left behind by an interrupted run.
+        tokio::fs::remove_dir_all(&tmp).await?;
+    }
+    copy_file_store(files, &tmp).await?;
+    tokio::fs::rename(&tmp, dest).await?;
+    Ok(())
+}
+
+/// Recursively copies the contents of a `FileStore` into the local directory `dest`, keeping executable files
+/// executable.
+pub async fn copy_file_store(files: &BoxFileStore<'static>, dest: &Path) -> anyhow::Result<()> {
+    let mut stack = vec![String::new()];
+
+    while let Some(dir) = stack.pop() {
+        tokio::fs::create_dir_all(dest.join(&dir)).await?;
+
+        for entry in files.read_dir(&dir).await? {
+            let (name, is_dir) = match entry {
+                DirEntry::File(name) => (name, false),
+                DirEntry::Directory(name) => (name, true),
+            };
+            let path = if dir.is_empty() {
+                name
+            } else {
+                format!("{dir}/{name}")
+            };
+
+            if is_dir {
+                stack.push(path);
+            } else {
+                let file = files.read_file(&path).await?;
+                let mut reader = (*file).open().await?;
+                let mut writer = tokio::fs::File::create(dest.join(&path)).await?;
+                tokio::io::copy(&mut reader, &mut writer).await?;
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::PermissionsExt;
+                    let mode = if file.is_executable().await? {
+                        0o755
+                    } else {
+                        0o644
+                    };
+                    writer
+                        .set_permissions(std::fs::Permissions::from_mode(mode))
+                        .await?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bazel::memory::InMemoryFileStore;
+    use crate::bazel::package::{DynFileStore, TypeErasingFileStore};
+    use std::collections::HashMap;
+
+    fn dep_files() -> BoxFileStore<'static> {
+        Arc::from(DynFileStore::new_box(Box::new(TypeErasingFileStore(
+            InMemoryFileStore::new(HashMap::from([
+                (
+                    "MODULE.bazel".to_string(),
+                    b"module(name = \"dep\")".to_vec(),
+                ),
+                ("pkg/BUILD.bazel".to_string(), b"".to_vec()),
+                ("pkg/sub/data.txt".to_string(), b"data".to_vec()),
+            ])),
+        ))))
+    }
+
+    #[tokio::test]
+    async fn test_copy_file_store() {
+        let files = dep_files();
+        let tmp = assert_fs::TempDir::new().unwrap();
+        copy_file_store(&files, tmp.path()).await.unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(tmp.path().join("MODULE.bazel")).unwrap(),
+            "module(name = \"dep\")"
+        );
+        assert!(tmp.path().join("pkg/BUILD.bazel").is_file());
+        assert_eq!(
+            std::fs::read_to_string(tmp.path().join("pkg/sub/data.txt")).unwrap(),
+            "data"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_vendor_file_store() {
+        let tmp = assert_fs::TempDir::new().unwrap();
+        let dest = tmp.path().join("dep+1.0");
+        // What an interrupted run left behind is replaced.
+        std::fs::create_dir_all(tmp.path().join(".dep+1.0.tmp/stale")).unwrap();
+
+        vendor_file_store(&dep_files(), &dest).await.unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(dest.join("pkg/sub/data.txt")).unwrap(),
+            "data"
+        );
+        assert!(!dest.join("stale").exists());
+        assert!(!tmp.path().join(".dep+1.0.tmp").exists());
+    }
+}
@@ -0,0 +1,165 @@
+//! `--watch`: rebuilding whenever the workspace changes.
+//!
+//! After each build, razel waits for files in the workspace to change and works out how much of the previous state is
+//! still valid: an edited `.bzl` file invalidates only itself and the files loading it, while BUILD and source files
+//! are read afresh by every build anyway. Changes to module files reset the whole workspace, as they can change the
+//! external repositories and their mappings.
+
+use notify::{EventKind, RecursiveMode, Watcher as _};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// How long the workspace must be quiet before a batch of changes is acted on, so that e.g. saving several files or
+/// a `git checkout` triggers one rebuild rather than many.
+const DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// The files changed since the last build, relative to the workspace root.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Changes {
+    pub paths: BTreeSet<String>,
+}
+
+impl Changes {
+    /// Whether the workspace has to be set up again from scratch.
+    pub fn resets_workspace(&self) -> bool {
+        self.paths.iter().any(|path| {
+            let name = path.rsplit('/').next().unwrap_or(path);
+            matches!(
+                name,
+                "MODULE.bazel" | "REPO.bazel" | "dependency_policy.json"
+            ) || name.ends_with(".MODULE.bazel")
+        })
+    }
+
+    /// The changed `.bzl` files, which have to be evaluated again along with every file that loads them.
+    pub fn bzl_files(&self) -> BTreeSet<String> {
+        self.paths
+            .iter()
+            .filter(|path| path.ends_with(".bzl"))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Whether a change to `path` (relative to the workspace root) can't affect the build, e.g. because razel itself
+/// wrote it.
+fn ignored(path: &Path) -> bool {
+    path.components().next().is_some_and(|c| {
+        let c = c.as_os_str().to_string_lossy();
+        c.starts_with("bazel-") || c == ".git"
+    })
+}
+
+/// Watches the files of a workspace.
+pub struct FileWatcher {
+    root: PathBuf,
+    // Stops watching when dropped.
+    _watcher: notify::RecommendedWatcher,
+    events: mpsc::UnboundedReceiver<notify::Result<notify::Event>>,
+}
+
+impl FileWatcher {
+    pub fn new(root: &Path) -> anyhow::Result<Self> {
+        let (tx, events) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            let _ = tx.send(event);
+        })?;
+        watcher.watch(root, RecursiveMode::Recursive)?;
+        Ok(Self {
+            root: root.to_path_buf(),
+            _watcher: watcher,
+            events,
+        })
+    }
+
+    /// Waits for the next batch of relevant changes.
+    pub async fn changes(&mut self) -> anyhow::Result<Changes> {
+        let mut changes = Changes::default();
+        while changes.paths.is_empty() {
+            let Some(event) = self.events.recv().await else {
+                anyhow::bail!("File watcher stopped");
+            };
+            self.record(event?, &mut changes);
+            // Gather whatever follows closely behind, e.g. the other files written by the same save.
+            while let Ok(Some(event)) = tokio::time::timeout(DEBOUNCE, self.events.recv()).await {
+                self.record(event?, &mut changes);
+            }
+        }
+        Ok(changes)
+    }
+
+    /// Returns the changes seen since the last call, without waiting for more.
+    pub fn pending_changes(&mut self) -> anyhow::Result<Changes> {
+        let mut changes = Changes::default();
+        while let Ok(event) = self.events.try_recv() {
+            self.record(event?, &mut changes);
+        }
+        Ok(changes)
+    }
+
+    fn record(&self, event: notify::Event, changes: &mut Changes) {
+        if !matches!(
+            event.kind,
+            EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+        ) {
+            return;
+        }
+        for path in event.paths {
+            if let Ok(relative) = path.strip_prefix(&self.root)
+                && !ignored(relative)
+            {
+                changes
+                    .paths
+                    .insert(relative.to_string_lossy().into_owned());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn changes(paths: &[&str]) -> Changes {
+        Changes {
+            paths: paths.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_classify_changes() {
+        let edit = changes(&["pkg/BUILD.bazel", "pkg/defs.bzl", "pkg/main.c"]);
+        assert!(!edit.resets_workspace());
+        assert_eq!(
+            edit.bzl_files().into_iter().collect::<Vec<_>>(),
+            ["pkg/defs.bzl"]
+        );
+        assert!(changes(&["MODULE.bazel"]).resets_workspace());
+        assert!(changes(&["deps/go.MODULE.bazel"]).resets_workspace());
+
+        assert!(ignored(Path::new("bazel-out/k8-fastbuild/bin/x")));
+        assert!(ignored(Path::new(".git/index")));
+        assert!(!ignored(Path::new("src/bazel-ish.bzl")));
+    }
+
+    #[tokio::test]
+    async fn test_watcher_reports_changes() {
+        let tmp = assert_fs::TempDir::new().unwrap();
+        let root = tmp.path().canonicalize().unwrap();
+        std::fs::create_dir(root.join("pkg")).unwrap();
+        let mut watcher = FileWatcher::new(&root).unwrap();
+
+        std::fs::create_dir(root.join(".git")).unwrap();
+        std::fs::write(root.join(".git/HEAD"), "ref").unwrap();
+        std::fs::write(root.join("pkg/defs.bzl"), "x = 1").unwrap();
+
+        let changes = tokio::time::timeout(Duration::from_secs(10), watcher.changes())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(changes.paths.contains("pkg/defs.bzl"), "{changes:?}");
+        assert!(changes.paths.iter().all(|p| !p.starts_with(".git")));
+    }
+}
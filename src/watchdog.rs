@@ -0,0 +1,285 @@
+//! Detection of work that can never finish.
+//!
+//! Loading (and later, action execution) is a graph of tasks awaiting each other's results. A dependency cycle, e.g.
+//! `a.bzl` loading `b.bzl` loading `a.bzl`, would otherwise make a task await itself and hang silently. Every such wait
+//! is recorded in a `WaitGraph`, which rejects a wait that closes a cycle, and a watchdog fails all waits when every
+//! running task is blocked for too long.
+
+use crate::cycle::{Cycle, Dependency};
+use crate::error::LoadingError;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+#[derive(Debug, Default)]
+struct State {
+    /// Tasks that have started and not yet finished.
+    running: BTreeSet<String>,
+    /// For each task, the tasks it is currently waiting on, with the number of waits on each and why it waits.
+    waits: BTreeMap<String, BTreeMap<String, (usize, Dependency)>>,
+    /// Incremented on every change, so the watchdog can tell whether anything happened between two checks.
+    generation: u64,
+    /// Set once the watchdog has given up, describing the wait graph at that point.
+    stall_report: Option<String>,
+}
+
+impl State {
+    /// The path of waits leading from `from` to `to`, if there is one.
+    fn path(&self, from: &str, to: &str) -> Option<Vec<String>> {
+        let mut stack = vec![vec![from.to_string()]];
+        let mut seen = BTreeSet::new();
+        while let Some(path) = stack.pop() {
+            let last = path.last().unwrap();
+            if last == to {
+                return Some(path);
+            }
+            if !seen.insert(last.clone()) {
+                continue;
+            }
+            for next in self.waits.get(last).into_iter().flat_map(|w| w.keys()) {
+                let mut next_path = path.clone();
+                next_path.push(next.clone());
+                stack.push(next_path);
+            }
+        }
+        None
+    }
+
+    /// Whether there are running tasks and all of them are waiting on something.
+    fn all_blocked(&self) -> bool {
+        !self.running.is_empty() && self.running.iter().all(|t| self.waits.contains_key(t))
+    }
+
+    fn dump(&self) -> String {
+        let mut out = String::new();
+        for task in &self.running {
+            let _ = writeln!(out, "  {task}");
+            for target in self.waits.get(task).into_iter().flat_map(|w| w.keys()) {
+                let status = if self.running.contains(target) {
+                    ""
+                } else {
+                    " (not running)"
+                };
+                let _ = writeln!(out, "    waiting on {target}{status}");
+            }
+        }
+        out
+    }
+}
+
+/// Records which tasks are running and which are waiting on each other.
+#[derive(Debug, Default)]
+pub struct WaitGraph {
+    state: Mutex<State>,
+    stalled: Notify,
+}
+
+impl WaitGraph {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Marks `task` as running until the returned guard is dropped.
+    pub fn start(self: &Arc<Self>, task: impl Into<String>) -> TaskGuard {
+        let task = task.into();
+        let mut state = self.state.lock().unwrap();
+        state.running.insert(task.clone());
+        state.generation += 1;
+        TaskGuard {
+            graph: self.clone(),
+            task,
+        }
+    }
+
+    /// Awaits `result`, the outcome of task `to`, on behalf of task `from`, which depends on it through `dependency`.
+    ///
+    /// Fails with a `Cycle` straight away if `to` is already (transitively) waiting on `from`, and fails with a dump of
+    /// the wait graph if the watchdog finds that nothing can make progress.
+    pub async fn wait<T>(
+        &self,
+        from: &str,
+        to: &str,
+        dependency: Dependency,
+        result: impl Future<Output = anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        let stalled = self.stalled.notified();
+        {
+            let mut state = self.state.lock().unwrap();
+            if let Some(report) = &state.stall_report {
+                return Err(LoadingError::Stalled {
+                    report: report.clone(),
+                }
+                .into());
+            }
+            if let Some(path) = state.path(to, from) {
+                let mut nodes = vec![from.to_string()];
+                let mut edges = vec![dependency];
+                for pair in path.windows(2) {
+                    nodes.push(pair[0].clone());
+                    edges.push(state.waits[&pair[0]][&pair[1]].1.clone());
+                }
+                return Err(Cycle::new(nodes, edges).into());
+            }
+            state
+                .waits
+                .entry(from.to_string())
+                .or_default()
+                .entry(to.to_string())
+                .or_insert((0, dependency))
+                .0 += 1;
+            state.generation += 1;
+        }
+        let _edge = WaitGuard {
+            graph: self,
+            from,
+            to,
+        };
+
+        tokio::select! {
+            result = result => result,
+            _ = stalled => {
+                let state = self.state.lock().unwrap();
+                Err(LoadingError::Stalled {
+                    report: state.stall_report.clone().unwrap_or_default(),
+                }
+                .into())
+            }
+        }
+    }
+
+    /// Spawns a watchdog that checks the graph every `period`, and fails all waits once every running task has been
+    /// blocked, with nothing changing, for a whole period.
+    ///
+    /// The watchdog stops when the graph is dropped.
+    pub fn spawn_watchdog(self: &Arc<Self>, period: Duration) {
+        let graph: Weak<Self> = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut last_blocked = None;
+            loop {
+                tokio::time::sleep(period).await;
+                let Some(graph) = graph.upgrade() else {
+                    return;
+                };
+                let mut state = graph.state.lock().unwrap();
+                if !state.all_blocked() {
+                    last_blocked = None;
+                    continue;
+                }
+                if last_blocked != Some(state.generation) {
+                    last_blocked = Some(state.generation);
+                    continue;
+                }
+
+                let report = format!(
+                    "No progress for {period:?}: every running task is waiting on another.\nWait graph:\n{}",
+                    state.dump()
+                );
+                tracing::error!("{report}");
+                state.stall_report = Some(report);
+                graph.stalled.notify_waiters();
+                return;
+            }
+        });
+    }
+}
+
+/// Keeps a task marked as running; see `WaitGraph::start`.
+#[derive(Debug)]
+pub struct TaskGuard {
+    graph: Arc<WaitGraph>,
+    task: String,
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        let mut state = self.graph.state.lock().unwrap();
+        state.running.remove(&self.task);
+        state.generation += 1;
+    }
+}
+
+struct WaitGuard<'a> {
+    graph: &'a WaitGraph,
+    from: &'a str,
+    to: &'a str,
+}
+
+impl Drop for WaitGuard<'_> {
+    fn drop(&mut self) {
+        let mut state = self.graph.state.lock().unwrap();
+        if let Some(waits) = state.waits.get_mut(self.from) {
+            if let Some((count, _)) = waits.get_mut(self.to) {
+                *count -= 1;
+                if *count == 0 {
+                    waits.remove(self.to);
+                }
+            }
+            if waits.is_empty() {
+                state.waits.remove(self.from);
+            }
+        }
+        state.generation += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_wait_rejects_cycle() {
+        let graph = WaitGraph::new();
+        let _a = graph.start("a");
+        let _b = graph.start("b");
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+
+        let a_waits_on_b = graph.wait("a", "b", Dependency::load("a:1:1"), async {
+            rx.await?;
+            Ok(())
+        });
+        let b_waits_on_a = async {
+            tokio::task::yield_now().await;
+            let err = graph
+                .wait("b", "a", Dependency::load("b:1:1"), async { Ok(()) })
+                .await
+                .unwrap_err();
+            let _ = tx.send(());
+            err
+        };
+
+        let (a, err) = tokio::join!(a_waits_on_b, b_waits_on_a);
+        a.unwrap();
+        let cycle = err.downcast::<Cycle>().unwrap();
+        assert_eq!(cycle.nodes(), ["a", "b"]);
+        assert!(
+            cycle.to_string().contains("|     b:1:1: loads a\n"),
+            "{cycle}"
+        );
+        assert!(graph.state.lock().unwrap().waits.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_watchdog_reports_stall() {
+        let graph = WaitGraph::new();
+        graph.spawn_watchdog(Duration::from_millis(20));
+        let _a = graph.start("a");
+
+        let err = graph
+            .wait(
+                "a",
+                "b",
+                Dependency::load("a:1:1"),
+                futures::future::pending::<anyhow::Result<()>>(),
+            )
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("every running task is waiting"), "{err}");
+        assert!(
+            err.contains("  a\n    waiting on b (not running)\n"),
+            "{err}"
+        );
+    }
+}
@@ -1,27 +1,148 @@
-use crate::bazel::label::{CanonicalRepo, Label, MAIN_REPO, Repo, TargetPattern};
-use crate::bazel::package::{BoxFileStore, DynFileStore};
+use crate::bazel::Configuration;
+use crate::bazel::archive::ArchiveType;
+use crate::bazel::bzlmod::{
+    self, ArchiveOverride, BAZEL_COMPATIBILITY_VERSION, GitOverride, Overrides, Patches,
+};
+use crate::bazel::download::{Downloader, Integrity};
+use crate::bazel::git::Revision;
+use crate::bazel::label::{
+    ApparentRepo, CanonicalRepo, Label, MAIN_REPO, MAIN_REPO_ROOT, Repo, TargetKind, TargetPattern,
+    parse_label,
+};
+use crate::bazel::lockfile::{LOCKFILE_NAME, LOCKFILE_VERSION, Lockfile, LockfileMode};
+use crate::bazel::memory::InMemoryFileStore;
+use crate::bazel::module_graph::{self, ModuleGraph};
+use crate::bazel::mvs::{self, CheckDirectDependencies};
+use crate::bazel::package::{BoxFileStore, DynFileStore, FileStore, Package, packages_beneath};
+use crate::bazel::policy::DependencyPolicy;
+use crate::bazel::registry::{Registries, Registry, Source};
 use crate::bazel::repo::{LocalFileStore, Repository};
-use crate::shared_error::SharedError;
+use crate::bazel::rule::Rule;
+use crate::clock::Providers;
+use crate::console;
+use crate::error::{FetchError, LoadingError, ResolutionError};
+use crate::scheduler::SCHEDULER;
+use crate::shared_error::{self, SharedError};
+use crate::starlark::eval::LoadedBzl;
+use crate::starlark::limits::EvalLimits;
+use crate::starlark::sandbox::LoadingSandbox;
+use crate::watchdog::WaitGraph;
 use futures::TryFutureExt;
 use futures::future::{BoxFuture, Shared};
-use futures::stream::{FuturesUnordered, Stream};
+use futures::stream::{BoxStream, FuturesUnordered, Stream};
 use futures::{FutureExt, StreamExt};
-use std::collections::HashMap;
+use sha2::{Digest as _, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, RwLock};
-
-use starlark::environment::FrozenModule;
+use std::sync::{Arc, Mutex, RwLock};
+use tracing::Instrument;
 
 type RepositoryFuture = Shared<BoxFuture<'static, Result<Arc<Repository<'static>>, SharedError>>>;
-type FrozenModuleFuture = Shared<BoxFuture<'static, Result<FrozenModule, SharedError>>>;
+type FrozenModuleFuture = Shared<BoxFuture<'static, Result<LoadedBzl, SharedError>>>;
+/// How long every load may be blocked, with nothing changing, before loading is considered stuck.
+const WATCHDOG_PERIOD: std::time::Duration = std::time::Duration::from_secs(10);
+
+type EvaluatedPackage = (Package<BoxFileStore<'static>>, HashMap<String, Rule>);
+
+tokio::task_local! {
+    /// Errors deferred by `--keep_going` in the command being run, reported together once it has done everything it
+    /// can.
+    static DEFERRED_ERRORS: Arc<Mutex<Vec<anyhow::Error>>>;
+}
+
+/// Runs `command` with its own list of errors deferred by `--keep_going`, see `Workspace::defer_error`. Commands that
+/// share a workspace on the server don't report or take each other's errors.
+pub async fn with_deferred_errors<F: Future>(command: F) -> F::Output {
+    DEFERRED_ERRORS.scope(Arc::default(), command).await
+}
 
 /// The environment shared by all Bazel commands run in the same main repository. It encompasses the main repo and the set of all defined external repos.
 ///
 /// Most interactions with this codebase start from the Workspace.
 pub struct Workspace {
     path: PathBuf,
+    /// The package containing the directory the command was run from, used to resolve relative target patterns.
+    working_package: String,
+    config: Arc<Configuration>,
+    /// Where outputs, fetched repositories and other state of this workspace are kept.
+    output_base: PathBuf,
     repositories: RwLock<HashMap<CanonicalRepo<'static>, RepositoryFuture>>,
     loaded_deps: RwLock<HashMap<crate::bazel::label::CanonicalLabel<'static>, FrozenModuleFuture>>,
+    /// For each `.bzl` file, the files that load it (`.bzl` and BUILD files), so that an edit invalidates exactly what
+    /// depends on it.
+    bzl_dependents: Mutex<
+        HashMap<
+            crate::bazel::label::CanonicalLabel<'static>,
+            HashSet<crate::bazel::label::CanonicalLabel<'static>>,
+        >,
+    >,
+    /// Limits concurrent repository fetches to `--jobs`.
+    fetch_permits: tokio::sync::Semaphore,
+    /// Tracks which loads are waiting on which, to report cycles and stalls instead of hanging.
+    wait_graph: Arc<WaitGraph>,
+    /// Which external dependencies this workspace may use.
+    dependency_policy: Arc<DependencyPolicy>,
+    /// The targets of each package evaluated so far, by package, e.g. `@@//foo`. Only kept for `razel dump`, and not
+    /// with `--keep_state_after_build=false`.
+    evaluated_packages: Mutex<BTreeMap<String, Vec<String>>>,
+    /// The undeclared repositories each repository has been let see with `--nostrict_repo_visibility`, so that each
+    /// is only warned about once.
+    visibility_warnings: Mutex<HashSet<(CanonicalRepo<'static>, String)>>,
+    /// The root module's overrides, set once the main repository is evaluated.
+    overrides: RwLock<Overrides>,
+    /// The registries modules without overrides come from, set up when the first is fetched.
+    registries: tokio::sync::OnceCell<Registries>,
+    /// The module graph, discovered when the main repository is evaluated.
+    resolution: tokio::sync::OnceCell<Resolution>,
+    /// The clock and random numbers of downloads and lockfile writes.
+    providers: Providers,
+}
+
+/// The module graph and the version of each module selected from it, see `Workspace::resolve`.
+struct Resolution {
+    graph: ModuleGraph,
+    /// The modules of the graph whose `MODULE.bazel` could be evaluated, by key.
+    modules: BTreeMap<String, bzlmod::Module>,
+    /// The version selected for each module, by name, see `ModuleGraph::resolve`.
+    selected: BTreeMap<String, String>,
+}
+
+/// How far a memoized piece of work, such as evaluating a repository, has got.
+#[derive(Debug, Clone)]
+pub enum NodeState {
+    /// Requested, but not finished yet.
+    Pending,
+    Done,
+    Failed(SharedError),
+}
+
+impl NodeState {
+    fn of<T>(result: Option<&Result<T, SharedError>>) -> Self {
+        match result {
+            None => NodeState::Pending,
+            Some(Ok(_)) => NodeState::Done,
+            Some(Err(e)) => NodeState::Failed(e.clone()),
+        }
+    }
+}
+
+/// The files of the package at `path` that its `rules` generate or refer to, by name.
+fn package_files(
+    repo: &CanonicalRepo<'_>,
+    path: &str,
+    rules: &HashMap<String, Rule>,
+) -> BTreeSet<String> {
+    let context = Label::new(Repo::Canonical(repo.as_borrowed()), path, "");
+    let mut files = BTreeSet::new();
+    for rule in rules.values() {
+        files.extend(rule.outputs().map(str::to_string));
+        files.extend(rule.deps().filter_map(|(_, dep)| {
+            let label = parse_label(dep, &context).ok()?;
+            (label.repo == context.repo && label.package == path).then(|| label.target.into_owned())
+        }));
+    }
+    files.retain(|name| !rules.contains_key(name));
+    files
 }
 
 async fn any_exists(file1: impl AsRef<Path>, file2: impl AsRef<Path>) -> std::io::Result<bool> {
@@ -40,8 +161,13 @@ async fn any_exists(file1: impl AsRef<Path>, file2: impl AsRef<Path>) -> std::io
 }
 
 impl Workspace {
-    pub async fn new(start_dir: impl AsRef<Path>) -> Result<Arc<Self>, std::io::Error> {
-        let mut current_dir = std::path::absolute(start_dir)?;
+    pub(crate) async fn new(
+        start_dir: impl AsRef<Path>,
+        config: Arc<Configuration>,
+        providers: Providers,
+    ) -> Result<Arc<Self>, std::io::Error> {
+        let start_dir = std::path::absolute(start_dir)?;
+        let mut current_dir = start_dir.clone();
 
         loop {
             if any_exists(
@@ -61,11 +187,36 @@ impl Workspace {
             }
         }
 
+        let working_package = start_dir
+            .strip_prefix(&current_dir)
+            .unwrap_or(Path::new(""))
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        let dependency_policy =
+            DependencyPolicy::load(&current_dir).map_err(std::io::Error::other)?;
+
         let ws = Arc::new(Workspace {
+            output_base: config.output_base(&current_dir),
             path: current_dir.clone(),
+            working_package,
+            fetch_permits: tokio::sync::Semaphore::new(config.jobs),
+            config,
             repositories: RwLock::new(HashMap::new()),
             loaded_deps: RwLock::new(HashMap::new()),
+            bzl_dependents: Mutex::new(HashMap::new()),
+            wait_graph: WaitGraph::new(),
+            dependency_policy: Arc::new(dependency_policy),
+            evaluated_packages: Mutex::new(BTreeMap::new()),
+            visibility_warnings: Mutex::new(HashSet::new()),
+            overrides: RwLock::new(Overrides::default()),
+            registries: tokio::sync::OnceCell::new(),
+            resolution: tokio::sync::OnceCell::new(),
+            providers,
         });
+        ws.wait_graph.spawn_watchdog(WATCHDOG_PERIOD);
 
         // Create the main repository
         // Use Box to implement FileStore for BoxedFileStore
@@ -78,31 +229,797 @@ impl Workspace {
         Ok(ws)
     }
 
-    #[allow(dead_code)]
+    pub fn wait_graph(&self) -> &Arc<WaitGraph> {
+        &self.wait_graph
+    }
+
+    pub fn dependency_policy(&self) -> &Arc<DependencyPolicy> {
+        &self.dependency_policy
+    }
+
     pub fn path(&self) -> &Path {
         &self.path
     }
 
+    /// The package of the working directory, relative to the workspace.
+    pub fn working_package(&self) -> &str {
+        &self.working_package
+    }
+
+    pub fn output_base(&self) -> &Path {
+        &self.output_base
+    }
+
     #[allow(dead_code)]
     pub async fn main_repo(&self) -> anyhow::Result<Arc<Repository<'static>>> {
+        self.repository(&MAIN_REPO).await
+    }
+
+    /// Returns the repository with the given canonical name, evaluating it if necessary.
+    pub async fn repository(
+        &self,
+        name: &CanonicalRepo<'static>,
+    ) -> anyhow::Result<Arc<Repository<'static>>> {
         let repo_future = self
             .repositories
             .read()
             .unwrap()
-            .get(&MAIN_REPO)
-            .expect("Main repo not found")
+            .get(name)
+            .ok_or_else(|| ResolutionError::UnknownRepository {
+                name: name.to_string(),
+            })?
             .clone();
 
         // Avoid holding the lock while awaiting
         repo_future
             .await
-            .map_err(|e| anyhow::Error::new(e).context("Failed to evaluate main repo"))
+            .map_err(|e| anyhow::Error::new(e).context(format!("Failed to evaluate repo {name}")))
+    }
+
+    /// The directory that external repositories are vendored into, if any.
+    pub fn vendor_dir(&self) -> Option<PathBuf> {
+        self.config
+            .vendor_dir
+            .as_ref()
+            .map(|dir| self.path.join(dir))
+    }
+
+    /// How strictly BUILD and .bzl evaluation is kept to the files it loads and contains.
+    pub fn loading_sandbox(&self) -> LoadingSandbox {
+        self.config.loading_sandbox
+    }
+
+    /// Whether the root module's dev dependencies are ignored, as other modules' always are.
+    pub fn ignore_dev_dependency(&self) -> bool {
+        self.config.ignore_dev_dependency
+    }
+
+    /// How much memory and how many statements each Starlark evaluation may use.
+    pub fn eval_limits(&self) -> EvalLimits {
+        self.config.eval_limits
+    }
+
+    /// Returns a future that materializes the external repository `name`.
+    ///
+    /// Modules with a `local_path_override` and repositories found in the vendor directory are used as-is, without
+    /// touching the network. Modules with an `archive_override` are downloaded and extracted into the output base, and those with a `git_override` are
+    /// checked out there. Other modules come from the first registry that has them, see `fetch_from_registry`.
+    pub fn fetch_repository(
+        self: &Arc<Self>,
+        name: CanonicalRepo<'static>,
+    ) -> BoxFuture<'static, anyhow::Result<Repository<'static>>> {
+        let ws = self.clone();
+        let span = tracing::info_span!("fetch repository", repo = %name);
+        async move {
+            let files = {
+                let _permit = ws.fetch_permits.acquire().await?;
+                ws.materialize_repository(&name).await?
+            };
+            crate::metrics::METRICS.record_repository_fetched();
+            Repository::new(ws, name, files).await
+        }
+        .instrument(span)
+        .boxed()
+    }
+
+    /// Returns a future that creates the repository `name` from files already fetched.
+    fn new_repository(
+        self: &Arc<Self>,
+        name: CanonicalRepo<'static>,
+        files: BoxFileStore<'static>,
+    ) -> BoxFuture<'static, anyhow::Result<Repository<'static>>> {
+        Repository::new(self.clone(), name, files).boxed()
+    }
+
+    /// Makes the files of external repository `name` available, e.g. by downloading and extracting it.
+    async fn materialize_repository(
+        &self,
+        name: &CanonicalRepo<'static>,
+    ) -> anyhow::Result<BoxFileStore<'static>> {
+        if let Some(dir) = self.local_path(name) {
+            if !tokio::fs::try_exists(dir.join("MODULE.bazel")).await? {
+                anyhow::bail!(
+                    "local_path_override of {name} points to {}, which has no MODULE.bazel",
+                    dir.display()
+                );
+            }
+            return Ok(std::sync::Arc::from(DynFileStore::new_box(Box::new(
+                crate::bazel::package::TypeErasingFileStore(LocalFileStore::new(dir)),
+            ))));
+        }
+        if let Some(vendor_dir) = self.vendor_dir() {
+            let repo_dir = vendor_dir.join(name.as_str());
+            if tokio::fs::try_exists(&repo_dir).await? {
+                return Ok(std::sync::Arc::from(DynFileStore::new_box(Box::new(
+                    crate::bazel::package::TypeErasingFileStore(LocalFileStore::new(repo_dir)),
+                ))));
+            }
+        }
+
+        if let Some((module, version)) = module_graph::module_of_repo(name.as_str()) {
+            let (archive, git) = {
+                let overrides = self.overrides.read().unwrap();
+                (
+                    overrides.archive.get(module).cloned(),
+                    overrides.git.get(module).cloned(),
+                )
+            };
+            let dir = match (archive, git) {
+                (Some(archive), _) => self.fetch_archive(name, module, &archive).await?,
+                (_, Some(git)) => self.fetch_git(name, module, &git).await?,
+                _ => self.fetch_from_registry(name, module, version).await?,
+            };
+            return Ok(std::sync::Arc::from(DynFileStore::new_box(Box::new(
+                crate::bazel::package::TypeErasingFileStore(LocalFileStore::new(dir)),
+            ))));
+        }
+
+        Err(FetchError::Unsupported {
+            repo: name.to_string(),
+        }
+        .into())
+    }
+
+    /// Whether the repository `name` is of a module with a `local_path_override`.
+    pub fn is_local_path(&self, name: &CanonicalRepo<'_>) -> bool {
+        self.local_path(name).is_some()
+    }
+
+    /// The directory of the repository `name`, if it's of a module with a `local_path_override`.
+    fn local_path(&self, name: &CanonicalRepo<'_>) -> Option<PathBuf> {
+        let (module, _) = module_graph::module_of_repo(name.as_str())?;
+        let overrides = self.overrides.read().unwrap();
+        Some(self.path.join(&overrides.local_path.get(module)?.path))
+    }
+
+    /// Downloads the archive of an `archive_override` of `module`, checking its integrity if given, and extracts it
+    /// into the directory of the repository `name`, which it replaces, then patches it.
+    async fn fetch_archive(
+        &self,
+        name: &CanonicalRepo<'static>,
+        module: &str,
+        archive: &ArchiveOverride,
+    ) -> anyhow::Result<PathBuf> {
+        let dir = self
+            .download_archive(
+                name,
+                &archive.urls,
+                &archive.integrity,
+                &archive.strip_prefix,
+            )
+            .await?;
+        self.patch_repository(module, &dir, &archive.patches)
+            .await?;
+        Ok(dir)
+    }
+
+    /// Downloads the archive at the first of `urls` that works, checking its `integrity` if given, and extracts it
+    /// into the directory of the repository `name`, which it replaces. The type of archive comes from the extension
+    /// of the first URL, see `ArchiveType::from_url`.
+    async fn download_archive(
+        &self,
+        name: &CanonicalRepo<'static>,
+        urls: &[String],
+        integrity: &str,
+        strip_prefix: &str,
+    ) -> anyhow::Result<PathBuf> {
+        let url = urls.first().map_or("", String::as_str);
+        let archive_type =
+            ArchiveType::from_url(url).ok_or_else(|| FetchError::UnsupportedArchive {
+                url: url.to_string(),
+            })?;
+        let expected = match integrity {
+            "" => None,
+            integrity => Some(Integrity::parse(integrity)?),
+        };
+        let external = self.output_base.join("external");
+        let dir = external.join(name.as_str());
+        let download = external.join(format!(".{}.download", name.as_str()));
+        tokio::fs::create_dir_all(&external).await?;
+        self.downloader()
+            .download(urls, &download, expected.as_ref())
+            .await?;
+        if tokio::fs::try_exists(&dir).await? {
+            tokio::fs::remove_dir_all(&dir).await?;
+        }
+        let extracted = archive_type.extract(&download, strip_prefix, &dir).await;
+        tokio::fs::remove_file(&download).await?;
+        extracted?;
+        Ok(dir)
+    }
+
+    /// Downloads files from the network, as the flags and the dependency policy allow.
+    fn downloader(&self) -> Downloader {
+        Downloader::for_config(&self.config)
+            .with_providers(self.providers.clone())
+            .with_distdirs(self.config.distdir.iter().map(|dir| self.path.join(dir)))
+            .with_policy(self.dependency_policy.clone())
+    }
+
+    /// Where downloads and git repositories are cached across workspaces, as `--repository_cache` says.
+    fn repository_cache(&self) -> PathBuf {
+        self.config
+            .repository_cache
+            .clone()
+            .unwrap_or_else(|| self.output_base.join("cache/repos"))
+    }
+
+    /// The registries `--registry` lists, in order, or the Bazel Central Registry. Unless `--lockfile_mode=off`, the
+    /// registry file hashes of the lockfile keep each module coming from the registry it was first found in.
+    async fn registries(&self) -> anyhow::Result<&Registries> {
+        self.registries
+            .get_or_try_init(|| async {
+                let hashes = match self.config.lockfile_mode {
+                    LockfileMode::Off => BTreeMap::new(),
+                    _ => Lockfile::load(&self.path).await?.registry_file_hashes,
+                };
+                Ok(Registries::new(
+                    &self.config.registries,
+                    self.repository_cache().join("registry"),
+                    self.downloader(),
+                    &self.dependency_policy,
+                    &hashes,
+                )?)
+            })
+            .await
+    }
+
+    /// Fetches `module` at `version` from the first registry that has it into the directory of the repository `name`,
+    /// which it replaces: the source its `source.json` names, with the registry's patches and overlay files, and the
+    /// registry's `MODULE.bazel` in place of the source's own, as Bazel does. Local paths are used where they are.
+    async fn fetch_from_registry(
+        &self,
+        name: &CanonicalRepo<'static>,
+        module: &str,
+        version: &str,
+    ) -> anyhow::Result<PathBuf> {
+        let registry = self.registries().await?.find(module, version).await?;
+        let (dir, patches, patch_strip) = match registry.source(module, version).await? {
+            Source::LocalPath { path } => {
+                let dir = self.path.join(path);
+                if !tokio::fs::try_exists(dir.join("MODULE.bazel")).await? {
+                    anyhow::bail!(
+                        "The local_path source of {module}@{version} is {}, which has no MODULE.bazel",
+                        dir.display()
+                    );
+                }
+                return Ok(dir);
+            }
+            Source::Archive {
+                url,
+                mut mirror_urls,
+                integrity,
+                strip_prefix,
+                patches,
+                patch_strip,
+                overlay,
+            } => {
+                mirror_urls.push(url);
+                let dir = self
+                    .download_archive(name, &mirror_urls, &integrity, &strip_prefix)
+                    .await?;
+                for (path, integrity) in &overlay {
+                    if !Path::new(path)
+                        .components()
+                        .all(|c| matches!(c, std::path::Component::Normal(_)))
+                    {
+                        return Err(FetchError::UnsafeArchivePath {
+                            entry: path.clone(),
+                        }
+                        .into());
+                    }
+                    let url = registry.file_url(module, version, "overlay", path);
+                    self.downloader()
+                        .download(&[url], &dir.join(path), Some(&Integrity::parse(integrity)?))
+                        .await?;
+                }
+                (dir, patches, patch_strip)
+            }
+            Source::GitRepository {
+                remote,
+                commit,
+                tag,
+                strip_prefix,
+                patches,
+                patch_strip,
+            } => {
+                if !strip_prefix.is_empty() {
+                    anyhow::bail!(
+                        "The git_repository source of {module}@{version} has a strip_prefix, which isn't supported yet"
+                    );
+                }
+                let git = GitOverride {
+                    remote,
+                    commit,
+                    tag,
+                    ..GitOverride::default()
+                };
+                (
+                    self.fetch_git(name, module, &git).await?,
+                    patches,
+                    patch_strip,
+                )
+            }
+        };
+        self.apply_registry_patches(registry, module, version, &dir, &patches, patch_strip)
+            .await?;
+        tokio::fs::write(
+            dir.join("MODULE.bazel"),
+            registry.module_file(module, version).await?,
+        )
+        .await?;
+        Ok(dir)
+    }
+
+    /// Applies the `patches` a registry has for `module` at `version`, by file name with their integrity, to its
+    /// files in `dir`.
+    async fn apply_registry_patches(
+        &self,
+        registry: &Registry,
+        module: &str,
+        version: &str,
+        dir: &Path,
+        patches: &BTreeMap<String, String>,
+        patch_strip: u32,
+    ) -> anyhow::Result<()> {
+        let download = self
+            .output_base
+            .join("external")
+            .join(format!(".{module}+{version}.patch"));
+        for (patch, integrity) in patches {
+            let url = registry.file_url(module, version, "patches", patch);
+            self.downloader()
+                .download(&[url], &download, Some(&Integrity::parse(integrity)?))
+                .await?;
+            let content = tokio::fs::read_to_string(&download).await;
+            tokio::fs::remove_file(&download).await?;
+            crate::bazel::patch::apply(dir, patch, &content?, patch_strip as usize)?;
+        }
+        Ok(())
+    }
+
+    /// The directory holding the files of the repository `name`: the workspace for the main repository, the directory
+    /// of its `local_path_override`, its directory in `--vendor_dir` if it's vendored, and
+    /// `<output_base>/external/<name>` otherwise.
+    pub async fn repository_dir(&self, name: &CanonicalRepo<'_>) -> std::io::Result<PathBuf> {
+        if *name == MAIN_REPO {
+            return Ok(self.path.clone());
+        }
+        if let Some(dir) = self.local_path(name) {
+            return Ok(dir);
+        }
+        if let Some(vendor_dir) = self.vendor_dir() {
+            let repo_dir = vendor_dir.join(name.as_str());
+            if tokio::fs::try_exists(&repo_dir).await? {
+                return Ok(repo_dir);
+            }
+        }
+        Ok(self.output_base.join("external").join(name.as_str()))
+    }
+
+    /// Records the root module's overrides. The main repository is evaluated before any other, so every other
+    /// module's dependencies see them.
+    pub fn set_overrides(&self, overrides: Overrides) {
+        *self.overrides.write().unwrap() = overrides;
+    }
+
+    /// The version of `module` to use where the `bazel_dep` of `requested_by` asks for `version`, as the root
+    /// module's overrides say.
+    pub fn module_version(
+        &self,
+        module: &str,
+        version: &str,
+        requested_by: &CanonicalRepo,
+    ) -> Result<String, ResolutionError> {
+        let overrides = self.overrides.read().unwrap();
+        overrides
+            .version(module, version)
+            .ok_or_else(|| ResolutionError::NoAllowedVersion {
+                module: module.to_string(),
+                version: version.to_string(),
+                requested_by: requested_by.to_string(),
+                allowed: overrides.multiple_version[module].versions.clone(),
+            })
+    }
+
+    /// The version of `module` to create the repository of where the `bazel_dep` of `requested_by` asks for
+    /// `version`: the one selected from the module graph, or the one asked for with a `multiple_version_override`.
+    pub async fn dep_version(
+        &self,
+        module: &str,
+        version: &str,
+        requested_by: &CanonicalRepo<'_>,
+    ) -> anyhow::Result<String> {
+        let version = self.module_version(module, version, requested_by)?;
+        if self
+            .overrides
+            .read()
+            .unwrap()
+            .multiple_version
+            .contains_key(module)
+        {
+            return Ok(version);
+        }
+        let resolution = self.resolution().await?;
+        Ok(resolution.selected.get(module).cloned().unwrap_or(version))
+    }
+
+    /// Discovers the module graph from `root`, the root module, by evaluating the `MODULE.bazel` of each version of
+    /// each module asked for, then selects a version of each module with minimal version selection. Done once, by the
+    /// main repository, before any other repository is created.
+    ///
+    /// Modules whose `MODULE.bazel` can't be fetched or evaluated are in the graph without their dependencies, as the
+    /// command fails on them later if it needs them. Fails if a registry yanked a selected version, unless
+    /// `--allow_yanked_versions` allows it.
+    pub async fn resolve(self: &Arc<Self>, root: &bzlmod::Module) -> anyhow::Result<()> {
+        let resolution = self
+            .resolution
+            .get_or_init(|| async {
+                let mut graph = ModuleGraph::default();
+                graph.add_module(module_graph::ROOT.to_string());
+                let mut modules = BTreeMap::from([(module_graph::ROOT.to_string(), root.clone())]);
+                let mut frontier = vec![(module_graph::ROOT.to_string(), MAIN_REPO)];
+                while !frontier.is_empty() {
+                    let mut next: Vec<(String, String, String)> = Vec::new();
+                    for (from, repo) in frontier {
+                        for dep in &modules[&from].bazel_deps {
+                            // Repository::new reports modules the policy forbids and versions no override allows.
+                            if self.dependency_policy.check_module(&dep.name, "").is_err() {
+                                continue;
+                            }
+                            let Ok(version) = self.module_version(&dep.name, &dep.version, &repo)
+                            else {
+                                continue;
+                            };
+                            let to = module_graph::module_key(&dep.name, &version);
+                            if to == from {
+                                continue;
+                            }
+                            graph.add_dep(from.clone(), to.clone());
+                            if !modules.contains_key(&to)
+                                && !next.iter().any(|(key, ..)| *key == to)
+                            {
+                                next.push((to, dep.name.clone(), version));
+                            }
+                        }
+                    }
+                    let evaluated = futures::future::join_all(
+                        next.iter()
+                            .map(|(_, module, version)| self.eval_dep_module(module, version)),
+                    )
+                    .await;
+                    frontier = Vec::new();
+                    for ((key, module, version), evaluated) in next.into_iter().zip(evaluated) {
+                        if let Ok(evaluated) = evaluated {
+                            modules.insert(key.clone(), evaluated);
+                            frontier.push((key, CanonicalRepo::new(format!("{module}+{version}"))));
+                        }
+                    }
+                }
+                let selected = graph.resolve();
+                Resolution {
+                    graph,
+                    modules,
+                    selected,
+                }
+            })
+            .await;
+
+        let mut from_registry = BTreeMap::new();
+        for (module, version) in &resolution.selected {
+            let name = CanonicalRepo::new(format!("{module}+{version}"));
+            if resolution
+                .modules
+                .contains_key(&module_graph::module_key(module, version))
+                && self.comes_from_registry(&name, module).await?
+            {
+                from_registry.insert(module.clone(), version.clone());
+            }
+        }
+        let registries = self.registries().await?;
+        registries
+            .check_yanked(&from_registry, &self.config.allow_yanked_versions)
+            .await?;
+        // As in Bazel, where the selected modules' files come from is part of the resolution the lockfile records.
+        for (module, version) in &from_registry {
+            registries
+                .find(module, version)
+                .await?
+                .source(module, version)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// The module graph, resolved by the main repository.
+    async fn resolution(&self) -> anyhow::Result<&Resolution> {
+        if self.resolution.get().is_none() {
+            self.main_repo().await?;
+        }
+        self.resolution
+            .get()
+            .ok_or_else(|| anyhow::anyhow!("The module graph wasn't resolved"))
+    }
+
+    /// Evaluates the `MODULE.bazel` of `module` at `version`. Only that file is fetched from a registry, while modules
+    /// fetched whole anyway, with an override or from the vendor directory, get their repository made from what's
+    /// fetched here.
+    async fn eval_dep_module(
+        self: &Arc<Self>,
+        module: &str,
+        version: &str,
+    ) -> anyhow::Result<bzlmod::Module> {
+        let name = CanonicalRepo::new(format!("{module}+{version}"));
+        let files = {
+            let _permit = self.fetch_permits.acquire().await?;
+            if self.comes_from_registry(&name, module).await? {
+                let registry = self.registries().await?.find(module, version).await?;
+                let content = registry.module_file(module, version).await?;
+                std::sync::Arc::from(DynFileStore::new_box(Box::new(
+                    crate::bazel::package::TypeErasingFileStore(InMemoryFileStore::new(
+                        HashMap::from([("MODULE.bazel".to_string(), content)]),
+                    )),
+                )))
+            } else {
+                let files = self.materialize_repository(&name).await?;
+                crate::metrics::METRICS.record_repository_fetched();
+                self.add_repository_if_absent(name.clone(), || {
+                    self.new_repository(name, files.clone())
+                });
+                files
+            }
+        };
+        bzlmod::eval_module(
+            &files,
+            "MODULE.bazel",
+            false,
+            self.ignore_dev_dependency(),
+            self.eval_limits(),
+        )
+        .await
+    }
+
+    /// Whether the repository `name` of `module` comes from a registry, rather than from an override or the vendor
+    /// directory, see `materialize_repository`.
+    async fn comes_from_registry(
+        &self,
+        name: &CanonicalRepo<'_>,
+        module: &str,
+    ) -> std::io::Result<bool> {
+        if self.is_local_path(name) {
+            return Ok(false);
+        }
+        if let Some(vendor_dir) = self.vendor_dir()
+            && tokio::fs::try_exists(vendor_dir.join(name.as_str())).await?
+        {
+            return Ok(false);
+        }
+        let overrides = self.overrides.read().unwrap();
+        Ok(!overrides.archive.contains_key(module) && !overrides.git.contains_key(module))
+    }
+
+    /// Fetches the revision of the repository of a `git_override` of `module` into the repository cache, checks it
+    /// out into the directory of the repository `name`, which it replaces, then patches it.
+    async fn fetch_git(
+        &self,
+        name: &CanonicalRepo<'static>,
+        module: &str,
+        git: &GitOverride,
+    ) -> anyhow::Result<PathBuf> {
+        self.dependency_policy
+            .check_url(&git.remote, &format!("git_override of {module}"))?;
+        let revision = if git.commit.is_empty() {
+            Revision::Tag(git.tag.clone())
+        } else {
+            Revision::Commit(git.commit.clone())
+        };
+        let cache = self
+            .repository_cache()
+            .join("git")
+            .join(crate::bazel::digest::to_hex(&Sha256::digest(&git.remote)));
+        crate::bazel::git::fetch(&cache, &git.remote, &revision).await?;
+        let dir = self.output_base.join("external").join(name.as_str());
+        crate::bazel::git::checkout(&cache, &git.remote, &revision, &dir, git.init_submodules)
+            .await?;
+        self.patch_repository(module, &dir, &git.patches).await?;
+        Ok(dir)
+    }
+
+    /// Applies `patches` from an override of the module `module` to its files, fetched into `dir`: each patch file,
+    /// a label in the main repository, then each command, run with `sh` in `dir`.
+    async fn patch_repository(
+        &self,
+        module: &str,
+        dir: &Path,
+        patches: &Patches,
+    ) -> anyhow::Result<()> {
+        for patch in &patches.patches {
+            let label = parse_label(patch, &MAIN_REPO_ROOT)
+                .map_err(|e| anyhow::anyhow!("Invalid patch label {patch:?}: {e}"))?;
+            if !label.repo().clone().into_name().is_empty() {
+                anyhow::bail!(
+                    "The patch {patch} of module {module} must be in the main repository"
+                );
+            }
+            let path = self.path.join(label.package()).join(label.name());
+            let content = tokio::fs::read_to_string(&path)
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to read patch {}: {e}", path.display()))?;
+            crate::bazel::patch::apply(dir, patch, &content, patches.patch_strip)?;
+        }
+        for cmd in &patches.patch_cmds {
+            let status = tokio::process::Command::new("sh")
+                .arg("-c")
+                .arg(cmd)
+                .current_dir(dir)
+                .status()
+                .await?;
+            if !status.success() {
+                return Err(FetchError::PatchFailed {
+                    patch: cmd.clone(),
+                    file: None,
+                    reason: format!("the command failed with {status}"),
+                }
+                .into());
+            }
+        }
+        Ok(())
     }
 
-    #[allow(dead_code)]
     pub async fn main_module(&self) -> anyhow::Result<crate::bazel::bzlmod::Module> {
         let repo = self.main_repo().await?;
-        crate::bazel::bzlmod::eval_module(repo.files(), "MODULE.bazel", true).await
+        crate::bazel::bzlmod::eval_module(
+            repo.files(),
+            "MODULE.bazel",
+            true,
+            self.ignore_dev_dependency(),
+            self.eval_limits(),
+        )
+        .await
+    }
+
+    /// Compares the versions the root module's `bazel_dep`s ask for with the ones selected from the whole dependency
+    /// graph, and warns or fails as `--check_direct_dependencies` says.
+    ///
+    /// This evaluates every module in the graph. Modules that can't be fetched only contribute their own version, as
+    /// the command fails on them later if it needs them.
+    pub async fn check_direct_dependencies(&self) -> anyhow::Result<()> {
+        if self.config.check_direct_dependencies == CheckDirectDependencies::Off {
+            return Ok(());
+        }
+        // A broken MODULE.bazel is reported by whatever the command does next.
+        let Ok(resolution) = self.resolution().await else {
+            return Ok(());
+        };
+        let overrides = self.overrides.read().unwrap().clone();
+        let direct: Vec<_> = resolution.modules[module_graph::ROOT]
+            .bazel_deps
+            .iter()
+            // Each module gets its own version of a module with a `multiple_version_override`.
+            .filter(|dep| !overrides.multiple_version.contains_key(&dep.name))
+            .filter_map(|dep| {
+                let version = overrides.version(&dep.name, &dep.version)?;
+                Some((dep.repo_name.as_str(), dep.name.as_str(), version))
+            })
+            .collect();
+        let direct = direct
+            .iter()
+            .map(|(repo_name, module, version)| (*repo_name, *module, version.as_str()));
+        let selected = &resolution.selected;
+        let drift = mvs::direct_drift(direct, selected);
+        if drift.is_empty() {
+            return Ok(());
+        }
+        if self.config.check_direct_dependencies == CheckDirectDependencies::Error {
+            return Err(ResolutionError::DirectDependencyMismatch { drift }.into());
+        }
+        for d in drift {
+            console::eprintln(format_args!("WARNING: {d}"));
+        }
+        Ok(())
+    }
+
+    /// Fails if a module of the dependency graph doesn't work with the version of Bazel razel is compatible with, or if
+    /// versions of a module at different compatibility levels are asked for, see
+    /// `ModuleGraph::compatibility_conflict`.
+    ///
+    /// Modules that can't be fetched aren't checked, as the command fails on them later if it needs them.
+    pub async fn check_compatibility(&self) -> anyhow::Result<()> {
+        // A broken MODULE.bazel is reported by whatever the command does next.
+        let Ok(resolution) = self.resolution().await else {
+            return Ok(());
+        };
+        let mut levels = BTreeMap::new();
+        let mut max_levels = BTreeMap::new();
+        for (key, module) in &resolution.modules {
+            if let Some(requirement) = bzlmod::unmet_bazel_compatibility(
+                &module.bazel_compatibility,
+                BAZEL_COMPATIBILITY_VERSION,
+            ) {
+                return Err(ResolutionError::IncompatibleBazelVersion {
+                    module: key.clone(),
+                    requirement: requirement.to_string(),
+                    version: BAZEL_COMPATIBILITY_VERSION,
+                }
+                .into());
+            }
+            levels.insert(key.clone(), module.compatibility_level);
+            for dep in &module.bazel_deps {
+                if let Some(max) = dep.max_compatibility_level {
+                    max_levels.insert((key.clone(), dep.name.clone()), max);
+                }
+            }
+        }
+        let overrides = self.overrides.read().unwrap().clone();
+        match resolution
+            .graph
+            .compatibility_conflict(&levels, &max_levels, |module| {
+                overrides.multiple_version.contains_key(module)
+            }) {
+            Some(conflict) => Err(ResolutionError::CompatibilityLevelConflict { conflict }.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Records the checksums of the registry files fetched to resolve the dependency graph in the lockfile, or fails if
+    /// they differ from the recorded ones, as `--lockfile_mode` says. As in Bazel, a module resolving to another version
+    /// shows as the `MODULE.bazel` of that version being new. The lockfile is only written when it changes, and never
+    /// unless `write` is set.
+    ///
+    /// Registry file hashes that weren't fetched this time and extension results are kept as recorded.
+    pub async fn update_lockfile(&self, write: bool) -> anyhow::Result<()> {
+        if self.config.lockfile_mode == LockfileMode::Off {
+            return Ok(());
+        }
+        // A broken MODULE.bazel is reported by whatever the command does next.
+        if self.resolution().await.is_err() {
+            return Ok(());
+        }
+        let hashes = self.registries().await?.file_hashes();
+        let mut lockfile = Lockfile::load(&self.path).await?;
+        let changes = lockfile.registry_file_changes(&hashes);
+        if changes.is_empty() {
+            return Ok(());
+        }
+        if self.config.lockfile_mode == LockfileMode::Error {
+            return Err(ResolutionError::LockfileOutdated {
+                path: self.path.join(LOCKFILE_NAME),
+                changes,
+            }
+            .into());
+        }
+        if !write {
+            return Ok(());
+        }
+        lockfile.lock_file_version = LOCKFILE_VERSION;
+        lockfile.registry_file_hashes.extend(hashes);
+        lockfile.save(&self.path, &self.providers).await
+    }
+
+    /// The graph of the modules the main module transitively depends on, at the versions each asks for. Modules whose
+    /// `MODULE.bazel` fails to fetch are in the graph, without their dependencies.
+    pub async fn module_graph(&self) -> anyhow::Result<ModuleGraph> {
+        Ok(self.resolution().await?.graph.clone())
     }
 
     pub fn add_repository<Fut>(&self, repo: CanonicalRepo<'static>, f: Fut)
@@ -117,13 +1034,33 @@ impl Workspace {
             .insert(repo, f.boxed().shared());
     }
 
+    /// Like `add_repository`, but leaves an already-registered repository untouched.
+    pub fn add_repository_if_absent<Fut>(
+        &self,
+        repo: CanonicalRepo<'static>,
+        f: impl FnOnce() -> Fut,
+    ) where
+        Fut: IntoFuture<Output = Result<Repository<'static>, anyhow::Error>>,
+        Fut::IntoFuture: Send + 'static,
+    {
+        let mut repositories = self.repositories.write().unwrap();
+        if repositories.contains_key(&repo) {
+            return;
+        }
+        let f = f()
+            .into_future()
+            .map_ok(Arc::new)
+            .map_err(SharedError::from);
+        repositories.insert(repo, f.boxed().shared());
+    }
+
     pub fn get_or_add_bzl<Fut>(
         &self,
         label: crate::bazel::label::CanonicalLabel<'static>,
         f: impl FnOnce() -> Fut,
     ) -> FrozenModuleFuture
     where
-        Fut: IntoFuture<Output = Result<FrozenModule, anyhow::Error>>,
+        Fut: IntoFuture<Output = Result<LoadedBzl, anyhow::Error>>,
         Fut::IntoFuture: Send + 'static,
     {
         // First, check with read lock
@@ -146,48 +1083,374 @@ impl Workspace {
         future
     }
 
+    /// Resolves the apparent repository name `apparent` as seen from `from`.
+    ///
+    /// A repository only sees the modules it declares with `bazel_dep`, itself, and the main repository as `@`, see
+    /// `RepoMapping`. With `--nostrict_repo_visibility`, another module by that name from anywhere in the dependency
+    /// graph is accepted too, at its highest version, with a warning, to help migrate BUILD and .bzl files that rely
+    /// on transitive dependencies.
+    pub fn resolve_repo(
+        &self,
+        from: &Repository<'static>,
+        apparent: &ApparentRepo<'_>,
+    ) -> Option<CanonicalRepo<'static>> {
+        if let Some(canonical) = from.resolve_repo(apparent) {
+            return Some(canonical.into_owned());
+        }
+        if self.config.strict_repo_visibility {
+            return None;
+        }
+        let canonical = self
+            .repositories
+            .read()
+            .unwrap()
+            .keys()
+            .filter_map(|repo| {
+                let (module, version) = repo.as_str().split_once('+')?;
+                (module == apparent.as_str()).then_some((repo, version))
+            })
+            .max_by(|(_, a), (_, b)| mvs::compare_versions(a, b))
+            .map(|(repo, _)| repo.clone())?;
+        let first = self
+            .visibility_warnings
+            .lock()
+            .unwrap()
+            .insert((from.canonical_name(), apparent.as_str().to_string()));
+        if first {
+            let from = if from.canonical_name() == MAIN_REPO {
+                "the main repository".to_string()
+            } else {
+                from.canonical_name().to_string()
+            };
+            console::eprintln(format_args!(
+                "WARNING: {from} refers to {apparent}, which it doesn't declare with bazel_dep; using {canonical} \
+                 because of --nostrict_repo_visibility"
+            ));
+        }
+        Some(canonical)
+    }
+
+    /// Records that the `.bzl` or BUILD file `from` loads `to`.
+    pub fn record_bzl_load(
+        &self,
+        from: crate::bazel::label::CanonicalLabel<'static>,
+        to: crate::bazel::label::CanonicalLabel<'static>,
+    ) {
+        self.bzl_dependents
+            .lock()
+            .unwrap()
+            .entry(to)
+            .or_default()
+            .insert(from);
+    }
+
+    /// Forgets the main repository's `.bzl` files at `paths` (relative to the workspace root) and every file that
+    /// transitively loads them, so they are evaluated again on their next load. Returns how many were forgotten.
+    pub fn invalidate_bzl_files(&self, paths: &BTreeSet<String>) -> usize {
+        let mut deps = self.loaded_deps.write().unwrap();
+        let dependents = self.bzl_dependents.lock().unwrap();
+        let mut stale: Vec<_> = deps
+            .keys()
+            .filter(|label| {
+                let path = match label.package() {
+                    "" => label.name().to_string(),
+                    package => format!("{package}/{}", label.name()),
+                };
+                label.repo == MAIN_REPO && paths.contains(&path)
+            })
+            .cloned()
+            .collect();
+        let mut invalidated = 0;
+        while let Some(label) = stale.pop() {
+            if deps.remove(&label).is_some() {
+                invalidated += 1;
+                stale.extend(dependents.get(&label).into_iter().flatten().cloned());
+            }
+        }
+        invalidated
+    }
+
+    /// Every load recorded so far, as pairs of the loading file and the `.bzl` file it loads.
+    pub fn load_graph(
+        &self,
+    ) -> Vec<(
+        crate::bazel::label::CanonicalLabel<'static>,
+        crate::bazel::label::CanonicalLabel<'static>,
+    )> {
+        self.bzl_dependents
+            .lock()
+            .unwrap()
+            .iter()
+            .flat_map(|(to, dependents)| dependents.iter().map(|from| (from.clone(), to.clone())))
+            .collect()
+    }
+
+    /// Every repository requested so far, how far its evaluation has got, and the repositories it maps to if done.
+    pub fn repository_snapshot(
+        &self,
+    ) -> BTreeMap<String, (NodeState, Vec<(String, CanonicalRepo<'static>)>)> {
+        self.repositories
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, future)| {
+                let result = future.peek();
+                let mut mapping: Vec<_> = match result {
+                    Some(Ok(repo)) => repo
+                        .repo_mapping()
+                        .iter()
+                        .map(|(apparent, canonical)| {
+                            (apparent.as_str().to_string(), canonical.clone())
+                        })
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                mapping.sort_by(|a, b| a.0.cmp(&b.0));
+                (name.to_string(), (NodeState::of(result), mapping))
+            })
+            .collect()
+    }
+
+    /// Every `.bzl` file loaded so far, how far its evaluation has got, and the files that load it.
+    pub fn bzl_snapshot(&self) -> BTreeMap<String, (NodeState, BTreeSet<String>)> {
+        let dependents = self.bzl_dependents.lock().unwrap();
+        self.loaded_deps
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(label, future)| {
+                let loaded_by = dependents
+                    .get(label)
+                    .into_iter()
+                    .flatten()
+                    .map(|l| l.to_string())
+                    .collect();
+                (label.to_string(), (NodeState::of(future.peek()), loaded_by))
+            })
+            .collect()
+    }
+
+    /// The targets of each package evaluated so far.
+    pub fn package_snapshot(&self) -> BTreeMap<String, Vec<String>> {
+        self.evaluated_packages.lock().unwrap().clone()
+    }
+
+    /// Frees the `.bzl` modules loaded so far, once loading has finished and nothing else will be loaded.
+    ///
+    /// Only done with `--keep_state_after_build=false`, since a later load would have to evaluate them again.
+    pub fn release_loading_state(&self) {
+        if !self.config.keep_state_after_build {
+            self.loaded_deps.write().unwrap().clear();
+        }
+    }
+
+    /// Handles a failure in one part of the command.
+    ///
+    /// With `--keep_going` the error is recorded for the command being run, see `with_deferred_errors`, and `Ok`
+    /// returned so the caller can carry on with the remaining work, otherwise the error is returned as-is.
+    pub fn defer_error(&self, err: anyhow::Error) -> anyhow::Result<()> {
+        if !self.config.keep_going {
+            return Err(err);
+        }
+        let Ok(deferred) = DEFERRED_ERRORS.try_with(Arc::clone) else {
+            // Not run as a command, so nothing would report it later.
+            return Err(err);
+        };
+        let mut deferred = deferred.lock().unwrap();
+        // A failure shared by many dependents, e.g. a broken .bzl file, only needs explaining once.
+        if deferred.iter().any(|e| SharedError::same_root(e, &err)) {
+            log::error!("{err} (for the same reason as above)");
+        } else {
+            log::error!("{err:#}");
+        }
+        deferred.push(err);
+        Ok(())
+    }
+
+    /// Fails with every error deferred so far by `defer_error`, if any.
+    ///
+    /// Errors that come down to the same shared failure are reported together, with their root cause given once.
+    pub fn check_deferred_errors(&self) -> anyhow::Result<()> {
+        let errors = DEFERRED_ERRORS
+            .try_with(|deferred| std::mem::take(&mut *deferred.lock().unwrap()))
+            .unwrap_or_default();
+        let n = errors.len();
+        let mut groups = shared_error::dedup(errors);
+        match n {
+            0 => Ok(()),
+            1 => Err(groups.remove(0).0),
+            n => {
+                let list = groups
+                    .iter()
+                    .map(|(e, count)| match count {
+                        1 => format!("  {e:#}"),
+                        count => format!(
+                            "  {e:#}\n    ({} more failed for the same reason)",
+                            count - 1
+                        ),
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                Err(anyhow::anyhow!("{n} errors occurred:\n{list}"))
+            }
+        }
+    }
+
+    /// Parses a target pattern, resolving relative patterns against the package of the working directory.
+    pub fn parse_target_pattern(&self, s: &str) -> anyhow::Result<TargetPattern<'static>> {
+        let context = Label::new(
+            Repo::Canonical(MAIN_REPO),
+            self.working_package.as_str(),
+            "",
+        );
+        crate::bazel::label::parse_target_pattern(s, &context)
+            .map(TargetPattern::into_owned)
+            .map_err(|e| {
+                LoadingError::InvalidTargetPattern {
+                    pattern: s.to_string(),
+                    reason: e.to_string(),
+                }
+                .into()
+            })
+    }
+
+    /// Parses and expands a list of target patterns, as given on the command line of `build`, `test` etc.
+    ///
+    /// Patterns are applied in order, and a pattern prefixed with `-` removes the targets it matches from the
+    /// result so far, e.g. `//... -//experimental/...`.
+    /// Each label is returned once, in the order it was first matched.
+    ///
+    /// Packages that fail to load are passed to `defer_error`, so with `--keep_going` the targets of the remaining
+    /// packages are still returned.
+    pub async fn expand_target_patterns(
+        self: &Arc<Self>,
+        patterns: &[String],
+    ) -> anyhow::Result<Vec<Label<'static>>> {
+        let mut labels: Vec<Label<'static>> = Vec::new();
+        for pattern in patterns {
+            if let Some(negative) = pattern.strip_prefix('-') {
+                let pattern = self.parse_target_pattern(negative)?;
+                let pattern = self.canonicalize_pattern(pattern).await?;
+                labels.retain(|label| !pattern.matches(label));
+                continue;
+            }
+
+            let mut seen: HashSet<Label<'static>> = labels.iter().cloned().collect();
+            let pattern = match self.parse_target_pattern(pattern) {
+                Ok(pattern) => pattern,
+                Err(e) => {
+                    self.defer_error(e)?;
+                    continue;
+                }
+            };
+            let mut expanded = std::pin::pin!(self.expand_pattern(pattern));
+            while let Some(label) = expanded.next().await {
+                match label {
+                    Ok(label) => {
+                        if seen.insert(label.clone()) {
+                            labels.push(label);
+                        }
+                    }
+                    Err(e) => self.defer_error(e)?,
+                }
+            }
+        }
+        Ok(labels)
+    }
+
+    /// Resolves the repository of a target pattern to its canonical name, as seen from the main repository.
+    pub async fn canonicalize_pattern<'a>(
+        &self,
+        mut pattern: TargetPattern<'a>,
+    ) -> anyhow::Result<TargetPattern<'a>> {
+        let canonical = match &pattern.repo {
+            Repo::Canonical(_) => return Ok(pattern),
+            Repo::Apparent(r) if r.as_str().is_empty() => MAIN_REPO,
+            Repo::Apparent(r) => {
+                self.resolve_repo(&*self.main_repo().await?, r)
+                    .ok_or_else(|| ResolutionError::RepositoryNotVisible {
+                        apparent: r.to_string(),
+                        from: "the main repository".to_string(),
+                    })?
+            }
+        };
+        pattern.repo = Repo::Canonical(canonical);
+        Ok(pattern)
+    }
+
     /// Expand pattern into a stream of Labels
+    ///
+    /// A package that fails to evaluate yields an error, and the stream then continues with the next package.
+    /// Up to `--jobs` packages are loaded concurrently.
     pub fn expand_pattern<'a>(
         self: &Arc<Self>,
         pattern: TargetPattern<'a>,
     ) -> impl Stream<Item = anyhow::Result<Label<'a>>> + 'a {
         let ws = self.clone();
 
-        let labels_stream = async_stream::try_stream! {
-            let repo = ws.main_repo().await?;
+        let labels_stream = async_stream::stream! {
+            let repo_and_pattern = async {
+                let pattern = ws.canonicalize_pattern(pattern).await?;
+                let canonical = match &pattern.repo {
+                    Repo::Canonical(r) => r.clone().into_owned(),
+                    Repo::Apparent(_) => unreachable!("pattern repo was canonicalized"),
+                };
+                anyhow::Ok((ws.repository(&canonical).await?, canonical, pattern))
+            };
+            let (repo, canonical, pattern) = match repo_and_pattern.await {
+                Ok(r) => r,
+                Err(e) => {
+                    yield Err(e);
+                    return;
+                }
+            };
 
-            let package_path = pattern.package.as_ref();
-            let root_pkg = repo.read_package(package_path).await?;
+            let package_path = pattern.package.to_string();
+            let packages = if pattern.include_subpackages {
+                packages_beneath(repo.files().clone(), package_path)
+            } else {
+                let pkg = repo.read_package(&package_path).await.map_err(anyhow::Error::from);
+                futures::stream::once(async move { pkg }).boxed()
+            };
 
-            // Evaluate the root package
-            let rules = repo.eval_package(&root_pkg, ws.clone()).await?;
-            for rule_name in rules.into_keys() {
-                let label: Label<'a> = Label::new(
-                    Repo::Canonical(repo.canonical_name()),
-                    root_pkg.path.clone(),
-                    rule_name,
-                );
+            let evaluated = ws.eval_packages(repo.clone(), packages);
 
-                if pattern.matches(&label) {
-                    yield label;
+            for await result in evaluated {
+                let (pkg, rules) = match result {
+                    Ok(r) => r,
+                    Err(e) => {
+                        yield Err(e);
+                        continue;
+                    }
+                };
+                // `:all` matches only rules, and `:*` the files they generate or refer to as well. A single target may
+                // also name a file that exists in the package.
+                let mut names: BTreeSet<String> = rules.keys().cloned().collect();
+                match &pattern.target_kind {
+                    TargetKind::AllRules => {}
+                    TargetKind::AllTargets => names.extend(package_files(&canonical, &pkg.path, &rules)),
+                    TargetKind::Exact(name) if !names.contains(name.as_ref()) => {
+                        let path = match pkg.path.as_str() {
+                            "" => name.to_string(),
+                            dir => format!("{dir}/{name}"),
+                        };
+                        if package_files(&canonical, &pkg.path, &rules).contains(name.as_ref())
+                            || pkg.filestore.read_file(&path).await.is_ok()
+                        {
+                            names.insert(name.to_string());
+                        }
+                    }
+                    TargetKind::Exact(_) => {}
                 }
-            }
+                for name in names {
+                    let label: Label<'a> = Label::new(
+                        Repo::Canonical(canonical.clone()),
+                        pkg.path.clone(),
+                        name,
+                    );
 
-            if pattern.include_subpackages {
-                let mut subpackages = root_pkg.subpackages();
-                while let Some(pkg) = subpackages.next().await {
-                    let pkg = pkg?;
-                    let rules = repo.eval_package(&pkg, ws.clone()).await?;
-                    for rule_name in rules.into_keys() {
-                        let label: Label<'a> = Label::new(
-                            Repo::Canonical(repo.canonical_name()),
-                            pkg.path.clone(),
-                            rule_name,
-                        );
-
-                        if pattern.matches(&label) {
-                            yield label;
-                        }
+                    if pattern.matches(&label) {
+                        yield Ok(label);
                     }
                 }
             }
@@ -195,4 +1458,65 @@ impl Workspace {
 
         labels_stream
     }
+
+    /// The name of the BUILD file of `package` in `repo`, and its rules by name.
+    pub async fn package_rules(
+        self: &Arc<Self>,
+        repo: &CanonicalRepo<'static>,
+        package: &str,
+    ) -> anyhow::Result<(String, HashMap<String, Rule>)> {
+        let repo = self.repository(repo).await?;
+        let pkg = repo
+            .read_package(package)
+            .await
+            .map_err(anyhow::Error::from);
+        let mut evaluated = self.eval_packages(repo, futures::stream::once(async { pkg }).boxed());
+        let (pkg, rules) = evaluated
+            .next()
+            .await
+            .expect("one package in, one result out")?;
+        Ok((pkg.build_file_name, rules))
+    }
+
+    /// Evaluates up to `--jobs` packages concurrently, yielding the results in the order of `packages`.
+    fn eval_packages(
+        self: &Arc<Self>,
+        repo: Arc<Repository<'static>>,
+        packages: BoxStream<'static, anyhow::Result<Package<BoxFileStore<'static>>>>,
+    ) -> BoxStream<'static, anyhow::Result<EvaluatedPackage>> {
+        let ws = self.clone();
+        packages
+            .map(move |pkg| {
+                let repo = repo.clone();
+                let ws = ws.clone();
+                async move {
+                    let pkg = pkg?;
+                    SCHEDULER.yield_to_interactive().await;
+                    let span = tracing::info_span!(
+                        "load package",
+                        package = %format_args!("{}//{}", repo.canonical_name(), pkg.path)
+                    );
+                    match repo.eval_package(&pkg, ws.clone()).instrument(span).await {
+                        Ok(rules) => {
+                            if ws.config.keep_state_after_build {
+                                let mut targets: Vec<String> = rules.keys().cloned().collect();
+                                targets.sort();
+                                ws.evaluated_packages.lock().unwrap().insert(
+                                    format!("{}//{}", repo.canonical_name(), pkg.path),
+                                    targets,
+                                );
+                            }
+                            Ok((pkg, rules))
+                        }
+                        Err(e) => Err(e.context(format!(
+                            "Failed to load package {}//{}",
+                            repo.canonical_name(),
+                            pkg.path
+                        ))),
+                    }
+                }
+            })
+            .buffered(self.config.jobs)
+            .boxed()
+    }
 }
@@ -0,0 +1,674 @@
+use assert_cmd::Command;
+use predicates::prelude::*; // Used for writing assertions
+
+#[test]
+fn test_build_negative_pattern() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir("examples/basic");
+
+    // As in Bazel, negative patterns only come after `--`, so they're never taken for flags.
+    cmd.args(["build", "--noanalyze", "--", "//...", "-//nested/..."]);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("@@//:hello_world"))
+        .stdout(predicate::str::contains("//nested:hello_world_nested").not())
+        .stdout(predicate::str::contains(
+            "Loading succeeded for 1 targets, not building them because of --noanalyze",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn test_build_rejects_flags_after_targets() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir("examples/basic");
+
+    cmd.args(["build", "//:hello_world", "--bogus_flag"]);
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "unexpected argument '--bogus_flag'",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_build_target_pattern_file() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = assert_fs::TempDir::new()?;
+    let patterns = tmp.path().join("patterns.txt");
+    std::fs::write(
+        &patterns,
+        "# Everything except the root package\n//...\n\n-//:all\n",
+    )?;
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir("examples/basic");
+
+    cmd.arg("build").arg("--target_pattern_file").arg(&patterns);
+    cmd.assert().stdout(predicate::str::contains(
+        "Building targets: @@//nested:hello_world_nested\n",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_build_target_pattern_file_conflicts_with_targets() -> Result<(), Box<dyn std::error::Error>>
+{
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir("examples/basic");
+
+    cmd.arg("build")
+        .arg("--target_pattern_file")
+        .arg("patterns.txt")
+        .arg("//...");
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("cannot be used with"));
+
+    Ok(())
+}
+
+fn broken_workspace() -> Result<assert_fs::TempDir, Box<dyn std::error::Error>> {
+    let tmp = assert_fs::TempDir::new()?;
+    std::fs::write(tmp.path().join("MODULE.bazel"), "module(name = \"broken\")")?;
+    for pkg in ["good", "bad"] {
+        std::fs::create_dir(tmp.path().join(pkg))?;
+    }
+    std::fs::write(
+        tmp.path().join("good/BUILD.bazel"),
+        "genrule(name = \"ok\", outs = [\"ok.txt\"], cmd = \"touch $@\")",
+    )?;
+    std::fs::write(tmp.path().join("bad/BUILD.bazel"), "genrule(name = ")?;
+    Ok(tmp)
+}
+
+#[test]
+fn test_build_stops_at_broken_package() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = broken_workspace()?;
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir(tmp.path());
+
+    cmd.arg("build").arg("//...");
+    cmd.assert()
+        .failure()
+        .stdout(predicate::str::contains("Building targets").not())
+        .stderr(predicate::str::contains("Failed to load package @@//bad"));
+
+    Ok(())
+}
+
+#[test]
+fn test_build_keep_going() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = broken_workspace()?;
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir(tmp.path());
+
+    cmd.arg("build")
+        .arg("--keep_going")
+        .arg("//...")
+        .arg("//missing:all");
+    cmd.assert()
+        .failure()
+        .stdout(predicate::str::contains("//good:ok"))
+        .stderr(predicate::str::contains("2 errors occurred"))
+        .stderr(predicate::str::contains("Failed to load package @@//bad"));
+
+    Ok(())
+}
+
+#[test]
+fn test_build_json_output() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = broken_workspace()?;
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir(tmp.path());
+
+    cmd.args(["build", "--keep_going", "--format=json", "//..."]);
+    let output = cmd.assert().failure().get_output().stdout.clone();
+    let result: serde_json::Value = serde_json::from_slice(&output)?;
+    assert_eq!(result["command"], "build");
+    assert_eq!(result["success"], false);
+    assert_eq!(result["exitCode"]["name"], "BUILD_FAILURE");
+    assert!(
+        result["targets"][0]["label"]
+            .as_str()
+            .unwrap()
+            .ends_with("//good:ok")
+    );
+    assert_eq!(result["targets"][0]["status"], "LOADED");
+    assert!(
+        result["errors"][0]["message"]
+            .as_str()
+            .unwrap()
+            .contains("Failed to load package @@//bad")
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_build_compilation_mode_output_dir() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir("examples/basic");
+
+    cmd.arg("build").arg("-c").arg("opt").arg("//:hello_world");
+    cmd.assert().stdout(predicate::str::is_match(
+        r"Output directory: bazel-out/\S+-opt/bin",
+    )?);
+
+    Ok(())
+}
+
+#[test]
+fn test_build_reports_load_cycle() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = assert_fs::TempDir::new()?;
+    std::fs::write(tmp.path().join("MODULE.bazel"), "module(name = \"cycle\")")?;
+    std::fs::write(tmp.path().join("a.bzl"), "load(\":b.bzl\", \"b\")\na = 1")?;
+    std::fs::write(tmp.path().join("b.bzl"), "load(\":a.bzl\", \"a\")\nb = 1")?;
+    std::fs::write(tmp.path().join("BUILD.bazel"), "load(\":a.bzl\", \"a\")")?;
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir(tmp.path());
+
+    cmd.arg("build").arg("//...");
+    cmd.timeout(std::time::Duration::from_secs(30))
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Cycle detected in .bzl file loads:",
+        ))
+        .stderr(predicate::str::contains(".-> @@//:a.bzl"))
+        .stderr(predicate::str::contains(
+            "|     a.bzl:1:6: loads @@//:b.bzl",
+        ))
+        .stderr(predicate::str::contains(
+            "|     b.bzl:1:6: loads @@//:a.bzl",
+        ))
+        .stderr(predicate::str::contains("`-- @@//:a.bzl"));
+
+    Ok(())
+}
+
+#[test]
+fn test_build_bazelrc_config() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = assert_fs::TempDir::new()?;
+    std::fs::write(tmp.path().join("MODULE.bazel"), "module(name = \"rc\")")?;
+    std::fs::write(tmp.path().join("BUILD.bazel"), "")?;
+    std::fs::write(
+        tmp.path().join(".bazelrc"),
+        "import %workspace%/ci.bazelrc\nbuild -c dbg\n",
+    )?;
+    std::fs::write(tmp.path().join("ci.bazelrc"), "build:ci -c opt\n")?;
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir(tmp.path()).env("HOME", tmp.path());
+
+    cmd.arg("build").arg("//...");
+    cmd.assert().stdout(predicate::str::is_match(
+        r"Output directory: bazel-out/\S+-dbg/bin",
+    )?);
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir(tmp.path()).env("HOME", tmp.path());
+
+    cmd.arg("build").arg("--config=ci").arg("//...");
+    cmd.assert().stdout(predicate::str::is_match(
+        r"Output directory: bazel-out/\S+-opt/bin",
+    )?);
+
+    Ok(())
+}
+
+#[test]
+fn test_build_output_base_startup_option() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = assert_fs::TempDir::new()?;
+    let output_base = tmp.path().join("out");
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir("examples/basic");
+
+    cmd.arg(format!("--output_base={}", output_base.display()))
+        .arg("build")
+        .arg("//:hello_world");
+    cmd.assert().stdout(predicate::str::contains(format!(
+        "Output base: {}",
+        output_base.display()
+    )));
+
+    // Startup options must come before the command.
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir("examples/basic");
+
+    cmd.arg("query")
+        .arg(format!("--output_base={}", output_base.display()))
+        .arg("//:hello_world");
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("unexpected argument"));
+
+    Ok(())
+}
+
+#[test]
+fn test_build_event_json_file() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = assert_fs::TempDir::new()?;
+    let bep_file = tmp.path().join("bep.json");
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir("examples/basic");
+
+    cmd.arg("build")
+        .arg(format!("--build_event_json_file={}", bep_file.display()))
+        .arg("//:hello_world");
+    cmd.assert().failure();
+
+    let events: Vec<serde_json::Value> = std::fs::read_to_string(&bep_file)?
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()?;
+    assert_eq!(events[0]["started"]["command"], "build");
+    assert!(events.iter().any(|e| {
+        e["id"]["targetConfigured"]["label"]
+            .as_str()
+            .is_some_and(|l| l.ends_with("//:hello_world"))
+    }));
+    let finished = events.iter().find(|e| e["finished"].is_object()).unwrap();
+    // The build fails as not supported, and the stream says so rather than reporting an internal error.
+    assert_eq!(
+        finished["finished"]["exitCode"]["name"],
+        "COMMAND_LINE_ERROR"
+    );
+    assert!(events.iter().any(|e| {
+        e["id"]["targetCompleted"]["label"]
+            .as_str()
+            .is_some_and(|l| l.ends_with("//:hello_world"))
+            && e["aborted"].is_object()
+    }));
+    assert_eq!(events.last().unwrap()["lastMessage"], true);
+
+    Ok(())
+}
+
+#[test]
+fn test_build_event_json_file_action_summary() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = assert_fs::TempDir::new()?;
+    std::fs::write(tmp.path().join("MODULE.bazel"), "module(name = \"gq\")")?;
+    std::fs::write(
+        tmp.path().join("BUILD.bazel"),
+        "filegroup(name = \"b\")\ngenquery(name = \"q\", expression = \"//:b\", scope = [\":b\"])\n",
+    )?;
+    let bep_file = tmp.path().join("bep.json");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("razel"))
+        .current_dir(tmp.path())
+        .arg(format!(
+            "--output_base={}",
+            tmp.path().join("out").display()
+        ))
+        .arg("build")
+        .arg(format!("--build_event_json_file={}", bep_file.display()))
+        .arg("//:q")
+        .assert()
+        .success();
+
+    let events: Vec<serde_json::Value> = std::fs::read_to_string(&bep_file)?
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()?;
+    let metrics = events
+        .iter()
+        .find_map(|e| e["buildMetrics"].as_object())
+        .expect("a buildMetrics event");
+    // int64 fields are strings in the proto3 JSON mapping.
+    assert_eq!(metrics["actionSummary"]["actionsCreated"], "1");
+    assert_eq!(metrics["actionSummary"]["actionsExecuted"], "1");
+    assert!(events.iter().any(|e| {
+        e["id"]["targetCompleted"]["label"] == "@@//:q" && e["completed"]["success"] == true
+    }));
+
+    Ok(())
+}
+
+#[test]
+fn test_build_dependency_policy() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = assert_fs::TempDir::new()?;
+    std::fs::write(
+        tmp.path().join("MODULE.bazel"),
+        "module(name = \"policy\")\nbazel_dep(name = \"left_pad\", version = \"1.0\")\n",
+    )?;
+    std::fs::write(tmp.path().join("BUILD.bazel"), "")?;
+    std::fs::write(
+        tmp.path().join("dependency_policy.json"),
+        r#"{"modules": {"allow": ["rules_*"]}}"#,
+    )?;
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir(tmp.path());
+
+    cmd.arg("build").arg("//...");
+    cmd.assert()
+        .code(48)
+        .stderr(predicate::str::contains(
+            "Dependency policy violation: module \"left_pad\" requested by bazel_dep in @@//:MODULE.bazel is not \
+             matched by any modules.allow pattern",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn test_build_watch_reloads_changed_bzl() -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::BufRead;
+
+    let tmp = assert_fs::TempDir::new()?;
+    std::fs::write(tmp.path().join("MODULE.bazel"), "module(name = \"watch\")")?;
+    std::fs::write(tmp.path().join("defs.bzl"), "x = 1")?;
+    std::fs::write(
+        tmp.path().join("BUILD.bazel"),
+        r#"load(":defs.bzl", "x")
+filegroup(name = "b")
+genquery(name = "a", expression = "//:b", scope = [":b"])
+"#,
+    )?;
+    let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin!("razel"))
+        .current_dir(tmp.path())
+        .args(["build", "--watch", "//:a"])
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::null())
+        .spawn()?;
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let stdout = child.stdout.take().unwrap();
+    std::thread::spawn(move || {
+        for line in std::io::BufReader::new(stdout).lines() {
+            let _ = tx.send(line.unwrap());
+        }
+    });
+    let wait_for = |expected: &str| loop {
+        let line = rx
+            .recv_timeout(std::time::Duration::from_secs(30))
+            .unwrap_or_else(|_| panic!("timed out waiting for {expected:?}"));
+        if line == expected {
+            break;
+        }
+    };
+
+    // Each build runs the actions, the first one included.
+    wait_for("Target @@//:a up-to-date:");
+    wait_for("Watching for changes...");
+    std::fs::write(tmp.path().join("defs.bzl"), "x = 2")?;
+    wait_for("1 files changed, 1 .bzl files to reload");
+    wait_for("Building targets: @@//:a");
+    wait_for("Target @@//:a up-to-date:");
+    child.kill()?;
+    child.wait()?;
+
+    Ok(())
+}
+
+#[test]
+fn test_build_genquery() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = assert_fs::TempDir::new()?;
+    std::fs::write(tmp.path().join("MODULE.bazel"), "module(name = \"gq\")")?;
+    std::fs::create_dir(tmp.path().join("lib"))?;
+    std::fs::write(
+        tmp.path().join("lib/BUILD.bazel"),
+        "cc_library(name = \"c\", srcs = [\"c.cc\"])\ncc_library(name = \"other\")\n",
+    )?;
+    std::fs::write(
+        tmp.path().join("BUILD.bazel"),
+        r#"genquery(name = "deps", expression = "deps(//lib:c)", scope = ["//lib:c"])
+genquery(name = "outside", expression = "//lib:other", scope = ["//lib:c"])
+genquery(name = "lenient", expression = "//lib:c + //lib:other", scope = ["//lib:c"], strict = False)
+"#,
+    )?;
+    let output_base = tmp.path().join("out");
+    let build = |target: &str| {
+        let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+        cmd.current_dir(tmp.path())
+            .arg(format!("--output_base={}", output_base.display()))
+            .args(["build", target]);
+        cmd
+    };
+
+    let output = build("//:deps")
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let stdout = String::from_utf8(output)?;
+    assert!(stdout.contains("Target @@//:deps up-to-date:"), "{stdout}");
+    let path = stdout.lines().last().unwrap().trim();
+    assert!(path.starts_with(&*output_base.to_string_lossy()), "{path}");
+    assert_eq!(std::fs::read_to_string(path)?, "@@//lib:c\n@@//lib:c.cc\n");
+
+    build("//:outside")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "genquery @@//:outside: the result includes targets outside of the scope: @@//lib:other",
+        ));
+
+    let output = build("//:lenient").assert().success().get_output().clone();
+    assert!(String::from_utf8(output.stderr)?.contains("WARNING: genquery @@//:lenient"));
+    let stdout = String::from_utf8(output.stdout)?;
+    let path = stdout.lines().last().unwrap().trim();
+    assert_eq!(std::fs::read_to_string(path)?, "@@//lib:c\n");
+
+    // Only genquery targets are built so far.
+    build("//lib:c")
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains(
+            "razel build is not supported yet for @@//lib:c",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn test_build_genquery_json_output() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = assert_fs::TempDir::new()?;
+    std::fs::write(tmp.path().join("MODULE.bazel"), "module(name = \"gq\")")?;
+    std::fs::write(
+        tmp.path().join("BUILD.bazel"),
+        "filegroup(name = \"b\")\ngenquery(name = \"q\", expression = \"//:b\", scope = [\":b\"])\n",
+    )?;
+    let output_base = tmp.path().join("out");
+
+    let output = Command::new(assert_cmd::cargo::cargo_bin!("razel"))
+        .current_dir(tmp.path())
+        .arg(format!("--output_base={}", output_base.display()))
+        .args(["build", "--format=json", "//:q"])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    // Nothing but the report is written to stdout.
+    let result: serde_json::Value = serde_json::from_slice(&output)?;
+    assert_eq!(result["success"], true);
+    assert_eq!(result["targets"][0]["label"], "@@//:q");
+    assert_eq!(result["targets"][0]["status"], "BUILT");
+    let output = result["targets"][0]["outputs"][0].as_str().unwrap();
+    assert!(
+        output.starts_with("bazel-out/") && output.ends_with("/bin/q"),
+        "{output}"
+    );
+    assert_eq!(
+        std::fs::read_to_string(output_base.join("execroot/_main").join(output))?,
+        "@@//:b\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_build_metrics_textfile() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = assert_fs::TempDir::new()?;
+    std::fs::write(tmp.path().join("MODULE.bazel"), "module(name = \"gq\")")?;
+    std::fs::write(
+        tmp.path().join("BUILD.bazel"),
+        "filegroup(name = \"b\")\ngenquery(name = \"q\", expression = \"//:b\", scope = [\":b\"])\n",
+    )?;
+
+    Command::new(assert_cmd::cargo::cargo_bin!("razel"))
+        .current_dir(tmp.path())
+        .arg(format!(
+            "--output_base={}",
+            tmp.path().join("out").display()
+        ))
+        .args(["build", "--metrics_textfile=metrics.prom", "//:q"])
+        .assert()
+        .success();
+    // The genquery is the one action the build ran.
+    let textfile = std::fs::read_to_string(tmp.path().join("metrics.prom"))?;
+    assert!(
+        textfile.contains("\nrazel_actions_executed_total 1\n"),
+        "{textfile}"
+    );
+    assert!(
+        textfile.contains("\nrazel_actions_cached_total 0\n"),
+        "{textfile}"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_build_explain() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = assert_fs::TempDir::new()?;
+    std::fs::write(
+        tmp.path().join("MODULE.bazel"),
+        "module(name = \"explain\")",
+    )?;
+    let write_build = |expression: &str| {
+        std::fs::write(
+            tmp.path().join("BUILD.bazel"),
+            format!(
+                "filegroup(name = \"b\")\ngenquery(name = \"q\", expression = \"{expression}\", scope = [\":b\"])\n"
+            ),
+        )
+    };
+    let output_base = tmp.path().join("out");
+    let build = |args: &[&str]| -> Result<String, Box<dyn std::error::Error>> {
+        Command::new(assert_cmd::cargo::cargo_bin!("razel"))
+            .current_dir(tmp.path())
+            .arg(format!("--output_base={}", output_base.display()))
+            .arg("build")
+            .arg("--explain=explain.log")
+            .args(args)
+            .arg("//:q")
+            .assert()
+            .success();
+        Ok(std::fs::read_to_string(tmp.path().join("explain.log"))?)
+    };
+
+    write_build("//:b")?;
+    let explanation = build(&[])?;
+    assert!(
+        explanation.contains("Executing action 'Writing file bazel-out/")
+            && explanation.contains("/bin/q': no entry in the cache (action is new)."),
+        "{explanation}"
+    );
+    // Nothing changed, so nothing to explain.
+    assert_eq!(build(&[])?, "");
+
+    write_build("deps(//:b)")?;
+    let explanation = build(&["--verbose_explanations"])?;
+    assert!(
+        explanation.contains(
+            "action command has changed.\n  Old command: query --universe_scope=:b //:b\n"
+        ) && explanation.contains("  New command: query --universe_scope=:b deps(//:b)\n"),
+        "{explanation}"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_build_nobuild_and_noanalyze() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = assert_fs::TempDir::new()?;
+    std::fs::write(tmp.path().join("MODULE.bazel"), "module(name = \"check\")")?;
+    std::fs::write(
+        tmp.path().join("BUILD.bazel"),
+        r#"genquery(name = "q", expression = "//:q", scope = [":q"])
+cc_library(name = "broken", deps = ["//missing:lib"])
+"#,
+    )?;
+    let output_base = tmp.path().join("out");
+    let build = |args: &[&str]| {
+        let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+        cmd.current_dir(tmp.path())
+            .arg(format!("--output_base={}", output_base.display()))
+            .arg("build")
+            .args(args);
+        cmd
+    };
+
+    build(&["--nobuild", "//:q"]).assert().success().stdout(
+        predicate::str::contains(
+            "Analysis succeeded for 1 targets, not building them because of --nobuild",
+        )
+        .and(predicate::str::contains("up-to-date").not()),
+    );
+    // Loading doesn't follow dependencies, so only analysis finds the missing one.
+    build(&["--noanalyze", "//:broken"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "Loading succeeded for 1 targets, not building them because of --noanalyze",
+        ));
+    build(&["--nobuild", "//:broken"])
+        .assert()
+        .code(7)
+        .stderr(predicate::str::contains("//missing"));
+
+    Ok(())
+}
+
+#[test]
+fn test_build_compile_one_dependency() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = assert_fs::TempDir::new()?;
+    std::fs::write(tmp.path().join("MODULE.bazel"), "module(name = \"one\")")?;
+    std::fs::create_dir_all(tmp.path().join("lib"))?;
+    std::fs::write(
+        tmp.path().join("lib/BUILD.bazel"),
+        r#"filegroup(name = "all", srcs = [":util"])
+cc_library(name = "util", srcs = ["util.cc"], hdrs = ["util.h"])
+"#,
+    )?;
+    std::fs::write(
+        tmp.path().join("BUILD.bazel"),
+        "filegroup(name = \"docs\", srcs = [\"README.md\"])\n",
+    )?;
+    let output_base = tmp.path().join("out");
+    let build = |dir: &str, args: &[&str]| {
+        let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+        cmd.current_dir(tmp.path().join(dir))
+            .arg(format!("--output_base={}", output_base.display()))
+            .arg("build")
+            .arg("--noanalyze")
+            .arg("--compile_one_dependency")
+            .args(args);
+        cmd
+    };
+
+    // The rule that lists the file in `srcs` wins over others of its package that depend on it.
+    build("", &["lib/util.cc"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("@@//lib:util"));
+    build("lib", &["util.cc"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("@@//lib:util"));
+    build("", &["//:README.md"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("@@//:docs"));
+    build("", &["lib/other.cc"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Couldn't find dependency on target '//lib:other.cc'",
+        ));
+
+    Ok(())
+}
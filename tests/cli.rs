@@ -24,3 +24,119 @@ fn test_razel_version_flag() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+#[test]
+fn test_razel_completions() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+
+    cmd.arg("completions").arg("bash");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("_razel()"))
+        .stdout(predicate::str::contains("show_extension"))
+        .stdout(predicate::str::contains("--keep_going"));
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.arg("completions").arg("tcsh");
+    cmd.assert().failure();
+
+    Ok(())
+}
+
+#[test]
+fn test_razel_canonicalize_flags() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = assert_fs::TempDir::new()?;
+    std::fs::write(tmp.path().join("MODULE.bazel"), "module(name = \"rc\")")?;
+    std::fs::write(
+        tmp.path().join(".bazelrc"),
+        "build --keep_going\nbuild:ci -c opt --jobs=8\n",
+    )?;
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir(tmp.path()).env("HOME", tmp.path());
+
+    cmd.args([
+        "canonicalize-flags",
+        "--for_command=test",
+        "--",
+        "--config=ci",
+        "-j",
+        "4",
+    ]);
+    cmd.assert()
+        .success()
+        .stdout("--compilation_mode=opt\n--jobs=4\n--keep_going=true\n");
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir(tmp.path()).env("HOME", tmp.path());
+    cmd.args(["canonicalize-flags", "--", "--config=missing"]);
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "Config value 'missing' is not defined",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_razel_console_flags() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = assert_fs::TempDir::new()?;
+    std::fs::write(tmp.path().join("MODULE.bazel"), "module(name = \"rc\")")?;
+    std::fs::write(tmp.path().join(".bazelrc"), "common --noshow_progress\n")?;
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir(tmp.path()).env("HOME", tmp.path());
+
+    cmd.args(["version", "--color=no", "--curses=auto"]);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(env!("CARGO_PKG_VERSION")));
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir(tmp.path()).env("HOME", tmp.path());
+    cmd.args(["version", "--color=sometimes"]);
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("--color"));
+
+    Ok(())
+}
+
+#[test]
+fn test_razel_noblock_for_lock() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = assert_fs::TempDir::new()?;
+    std::fs::write(tmp.path().join("MODULE.bazel"), "module(name = \"locked\")")?;
+    let output_base = tmp.path().join("output_base");
+    std::fs::create_dir_all(&output_base)?;
+    let lock = std::fs::File::create(output_base.join("lock"))?;
+    lock.lock()?;
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir(tmp.path()).env("HOME", tmp.path());
+    cmd.arg(format!("--output_base={}", output_base.display()))
+        .args(["--noblock_for_lock", "query", "//..."]);
+    cmd.assert()
+        .code(9)
+        .stderr(predicate::str::contains("--block_for_lock=false"));
+
+    Ok(())
+}
+
+#[test]
+fn test_razel_unimplemented_flags() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = assert_fs::TempDir::new()?;
+    std::fs::write(tmp.path().join("MODULE.bazel"), "module(name = \"flags\")")?;
+
+    for flag in [
+        "--experimental_remote_grpc_log=grpc.log",
+        "--experimental_remote_cache_eviction_retries=1",
+        "--experimental_output_paths=strip",
+    ] {
+        let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+        cmd.current_dir(tmp.path()).env("HOME", tmp.path());
+        cmd.args(["version", flag]);
+        let name = flag.split('=').next().unwrap();
+        cmd.assert().code(2).stderr(
+            predicate::str::contains(name).and(predicate::str::contains("is not supported yet")),
+        );
+    }
+
+    Ok(())
+}
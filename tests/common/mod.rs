@@ -0,0 +1,253 @@
+//! Shared helpers for the integration tests: fixture workspaces and registries, running razel in isolation, and golden
+//! files.
+//!
+//! Golden files live in `tests/golden/`. After a change that is meant to alter razel's output, run the tests with
+//! `RAZEL_UPDATE_GOLDEN=1` to rewrite them, and review the diff.
+
+// Each test crate uses a different subset of these.
+#![allow(dead_code)]
+
+use assert_cmd::Command;
+use std::path::{Path, PathBuf};
+
+/// A workspace in a temporary directory, with its own output base, so tests can change it freely and never share
+/// state with each other or with the user's builds.
+pub struct TestWorkspace {
+    root: assert_fs::TempDir,
+    output_base: assert_fs::TempDir,
+}
+
+fn copy_dir(from: &Path, to: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        // The convenience symlinks of a build run in the example itself.
+        if name.to_string_lossy().starts_with("bazel-") {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &to.join(&name))?;
+        } else {
+            std::fs::copy(entry.path(), to.join(&name))?;
+        }
+    }
+    Ok(())
+}
+
+impl TestWorkspace {
+    /// An empty workspace, with just a MODULE.bazel declaring `module_name`.
+    pub fn new(module_name: &str) -> Self {
+        let workspace = Self {
+            root: assert_fs::TempDir::new().unwrap(),
+            output_base: assert_fs::TempDir::new().unwrap(),
+        };
+        workspace.write(
+            "MODULE.bazel",
+            &format!("module(name = \"{module_name}\")\n"),
+        );
+        workspace
+    }
+
+    /// A copy of the fixture workspace `examples/<name>`.
+    pub fn example(name: &str) -> Self {
+        let workspace = Self {
+            root: assert_fs::TempDir::new().unwrap(),
+            output_base: assert_fs::TempDir::new().unwrap(),
+        };
+        let example = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("examples")
+            .join(name);
+        copy_dir(&example, workspace.path()).unwrap();
+        workspace
+    }
+
+    pub fn path(&self) -> &Path {
+        self.root.path()
+    }
+
+    pub fn output_base(&self) -> &Path {
+        self.output_base.path()
+    }
+
+    /// Writes `content` to the file at `path`, relative to the workspace root, creating directories as needed.
+    pub fn write(&self, path: &str, content: &str) -> &Self {
+        let path = self.path().join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+        self
+    }
+
+    pub fn read(&self, path: &str) -> String {
+        std::fs::read_to_string(self.path().join(path)).unwrap()
+    }
+
+    /// A razel command running in the workspace root with the workspace's output base. Backtraces and progress lines
+    /// are turned off, so error output is the same whatever the environment of the test run.
+    pub fn razel(&self) -> Command {
+        let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+        cmd.current_dir(self.path())
+            .arg(format!("--output_base={}", self.output_base().display()))
+            .arg("--noshow_progress")
+            .env("RUST_BACKTRACE", "0")
+            .env("RUST_LIB_BACKTRACE", "0")
+            // Each run's tokio console listens on a port of its own, so runs at the same time don't collide.
+            .env("TOKIO_CONSOLE_BIND", "127.0.0.1:0");
+        cmd
+    }
+
+    /// Runs razel with `args` to completion.
+    pub fn run(&self, args: &[&str]) -> Outcome {
+        let output = self.razel().args(args).output().unwrap();
+        Outcome {
+            command: format!("razel {}", args.join(" ")),
+            code: output.status.code(),
+            stdout: self.normalize(&String::from_utf8_lossy(&output.stdout)),
+            stderr: self.normalize(&String::from_utf8_lossy(&output.stderr)),
+        }
+    }
+
+    /// Replaces the paths that differ between test runs with placeholders.
+    pub fn normalize(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for (path, placeholder) in [
+            (self.output_base(), "$OUTPUT_BASE"),
+            (self.path(), "$WORKSPACE"),
+        ] {
+            let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+            for path in [canonical.as_path(), path] {
+                text = text.replace(&*path.to_string_lossy(), placeholder);
+            }
+        }
+        text
+    }
+
+    /// Lists the files under `dir` (relative to the workspace root) with their contents, for comparing against a
+    /// golden file.
+    pub fn tree(&self, dir: &str) -> String {
+        fn walk(dir: &Path, files: &mut Vec<PathBuf>) {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    walk(&path, files);
+                } else {
+                    files.push(path);
+                }
+            }
+        }
+
+        let mut files = Vec::new();
+        walk(&self.path().join(dir), &mut files);
+        files.sort();
+        let mut tree = String::new();
+        for file in files {
+            let relative = file.strip_prefix(self.path()).unwrap();
+            tree.push_str(&format!("=== {}\n", relative.display()));
+            tree.push_str(&String::from_utf8_lossy(&std::fs::read(&file).unwrap()));
+        }
+        tree
+    }
+}
+
+/// A module registry in a temporary directory, laid out as the Bazel Central Registry is, for `--registry`. Modules
+/// added with `add_module` have `local_path` sources in the registry's `src/` directory, so fetching them stays offline.
+pub struct TestRegistry {
+    root: assert_fs::TempDir,
+}
+
+impl TestRegistry {
+    /// A registry without any modules.
+    pub fn new() -> Self {
+        Self {
+            root: assert_fs::TempDir::new().unwrap(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        self.root.path()
+    }
+
+    /// The URL `--registry` takes for this registry.
+    pub fn url(&self) -> String {
+        format!("file://{}", self.path().display())
+    }
+
+    /// Writes `content` to the file at `path`, relative to the registry root, creating directories as needed.
+    pub fn write(&self, path: &str, content: &str) -> &Self {
+        let path = self.path().join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+        self
+    }
+
+    /// Adds `name@version`, declaring the dependencies `deps` (`bazel_dep` lines) after its `module()`. Its source is
+    /// `src/<name>-<version>` in the registry, holding the same `MODULE.bazel` and `files`, as (path, content) pairs.
+    pub fn add_module(
+        &self,
+        name: &str,
+        version: &str,
+        deps: &str,
+        files: &[(&str, &str)],
+    ) -> &Self {
+        let module = format!("module(name = \"{name}\", version = \"{version}\")\n{deps}");
+        let src = format!("src/{name}-{version}");
+        self.write(&format!("modules/{name}/{version}/MODULE.bazel"), &module);
+        self.write(
+            &format!("modules/{name}/{version}/source.json"),
+            &format!(r#"{{"type": "local_path", "path": "{src}"}}"#),
+        );
+        self.write(&format!("{src}/MODULE.bazel"), &module);
+        for (path, content) in files {
+            self.write(&format!("{src}/{path}"), content);
+        }
+        self
+    }
+}
+
+/// The result of running razel, with paths normalized.
+pub struct Outcome {
+    pub command: String,
+    /// The exit code, or `None` if razel was killed by a signal.
+    pub code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl Outcome {
+    /// The whole outcome as text, for comparing against a golden file.
+    pub fn snapshot(&self) -> String {
+        let code = match self.code {
+            Some(code) => code.to_string(),
+            None => "killed".to_string(),
+        };
+        format!(
+            "$ {}\nexit code: {code}\n--- stdout\n{}--- stderr\n{}",
+            self.command, self.stdout, self.stderr
+        )
+    }
+}
+
+/// Checks `actual` against `tests/golden/<name>.txt`, or rewrites the golden file with `RAZEL_UPDATE_GOLDEN=1`.
+pub fn assert_golden(name: &str, actual: &str) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(format!("{name}.txt"));
+    if std::env::var_os("RAZEL_UPDATE_GOLDEN").is_some_and(|v| v == "1") {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, actual).unwrap();
+        return;
+    }
+    let expected = std::fs::read_to_string(&path).unwrap_or_else(|e| {
+        panic!(
+            "Failed to read {}: {e}\nRun with RAZEL_UPDATE_GOLDEN=1 to create it",
+            path.display()
+        )
+    });
+    if expected != actual {
+        panic!(
+            "Output differs from {}, run with RAZEL_UPDATE_GOLDEN=1 to update it\n\
+             --- expected\n{expected}\n--- actual\n{actual}",
+            path.display()
+        );
+    }
+}
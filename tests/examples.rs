@@ -0,0 +1,230 @@
+//! End-to-end scenarios over the example workspaces in `examples/`, one test per subsystem an example exercises.
+
+mod common;
+
+use common::TestWorkspace;
+
+/// Runs razel with `args` in `workspace`, checks that it succeeds, and returns what it wrote to stdout.
+fn stdout(workspace: &TestWorkspace, args: &[&str]) -> String {
+    let outcome = workspace.run(args);
+    assert_eq!(outcome.code, Some(0), "{}", outcome.snapshot());
+    outcome.stdout
+}
+
+/// Builds the single-output `target` in `workspace`, and returns the content of its output.
+fn build_output(workspace: &TestWorkspace, target: &str) -> String {
+    let stdout = stdout(workspace, &["build", target]);
+    let path = stdout.lines().last().unwrap().trim();
+    let path = path.replace("$OUTPUT_BASE", &workspace.output_base().to_string_lossy());
+    std::fs::read_to_string(path).unwrap()
+}
+
+#[test]
+fn test_genrules_query_deps() {
+    let workspace = TestWorkspace::example("genrules");
+    assert_eq!(
+        stdout(&workspace, &["query", "deps(//:shout)"]),
+        "@@//:greeting\n@@//:name.txt\n@@//:shout\n"
+    );
+}
+
+#[test]
+fn test_genrules_build_genquery() {
+    let workspace = TestWorkspace::example("genrules");
+    assert_eq!(
+        build_output(&workspace, "//:shout_deps"),
+        "@@//:greeting\n@@//:name.txt\n@@//:shout\n"
+    );
+}
+
+#[test]
+fn test_genrules_aquery_graph() {
+    let workspace = TestWorkspace::example("genrules");
+    let graph = stdout(&workspace, &["aquery", "--output=graph", "deps(//:shout)"]);
+    assert!(graph.starts_with("digraph actions {"), "{graph}");
+    assert!(graph.contains("greeting.txt"), "{graph}");
+
+    // Command lines are printed with the paths the genrule's variables stand for.
+    let text = stdout(&workspace, &["aquery", "//:greeting"]);
+    assert!(
+        text.contains(
+            "  Command Line: (/bin/bash -c 'echo Hello, $(cat name.txt) > bazel-out/k8-fastbuild/bin/greeting.txt')\n"
+        ),
+        "{text}"
+    );
+}
+
+#[test]
+fn test_external_module_graph() {
+    let workspace = TestWorkspace::example("external");
+    assert_eq!(
+        stdout(&workspace, &["mod", "graph"]),
+        "<root> (external-example@0.1)\n└───greeting@\n"
+    );
+}
+
+#[test]
+fn test_external_query_across_repos() {
+    // The archive of the greeting module is found in the example's distdir, so this runs offline.
+    let workspace = TestWorkspace::example("external");
+    assert_eq!(
+        stdout(&workspace, &["query", "deps(//:message)"]),
+        "@@//:message\n@@greeting+//:hello.txt\n@@greeting+//:text\n"
+    );
+    assert_eq!(
+        build_output(&workspace, "//:message_deps"),
+        "@@//:message\n@@greeting+//:hello.txt\n@@greeting+//:text\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(workspace.output_base().join("external/greeting+/hello.txt"))
+            .unwrap(),
+        "Hello from the greeting module\n"
+    );
+}
+
+// Module extensions aren't evaluated yet, so the repositories come from the example's hand-written vendor directory:
+// these cover use_extension and use_repo, not running extensions.bzl.
+#[test]
+fn test_extensions_query_across_repos() {
+    let workspace = TestWorkspace::example("extensions");
+    let deps = "@@+tools+formatter//:formatter\n\
+                @@+tools+formatter//:run.sh\n\
+                @@+tools+linter//:linter\n\
+                @@+tools+linter//:run.sh\n\
+                @@//:checked\n\
+                @@//:input.txt\n";
+    assert_eq!(stdout(&workspace, &["query", "deps(//:checked)"]), deps);
+    assert_eq!(build_output(&workspace, "//:checked_deps"), deps);
+}
+
+#[test]
+fn test_extensions_use_repo_names() {
+    let workspace = TestWorkspace::example("extensions");
+    assert_eq!(
+        stdout(&workspace, &["query", "@lint//:linter"]),
+        "@@+tools+linter//:linter\n"
+    );
+
+    // The linter is only visible by the name use_repo gives it.
+    let outcome = workspace.run(&["query", "@linter//:linter"]);
+    assert_ne!(outcome.code, Some(0), "{}", outcome.snapshot());
+    assert!(
+        outcome
+            .stderr
+            .contains("No repository visible as @linter from the main repository"),
+        "{}",
+        outcome.snapshot()
+    );
+}
+
+#[test]
+fn test_tests_query() {
+    let workspace = TestWorkspace::example("tests");
+    assert_eq!(
+        stdout(&workspace, &["query", "tests(//...)"]),
+        "@@//:sharded_test\n@@//:smoke_test\n"
+    );
+    assert_eq!(
+        stdout(&workspace, &["query", "attr(shard_count, 4, tests(//...))"]),
+        "@@//:sharded_test\n"
+    );
+}
+
+#[test]
+fn test_tests_not_run() {
+    let workspace = TestWorkspace::example("tests");
+    for command in ["test", "coverage"] {
+        let outcome = workspace.run(&[command, "//..."]);
+        assert_eq!(outcome.code, Some(2), "{}", outcome.snapshot());
+        assert!(
+            outcome
+                .stderr
+                .contains(&format!("razel {command} is not supported yet")),
+            "{}",
+            outcome.snapshot()
+        );
+    }
+}
+
+#[test]
+fn test_tests_not_run_build_event_json_file() {
+    let workspace = TestWorkspace::example("tests");
+    let outcome = workspace.run(&["test", "--build_event_json_file=bep.json", "//..."]);
+    assert_eq!(outcome.code, Some(2), "{}", outcome.snapshot());
+    let events: Vec<serde_json::Value> = workspace
+        .read("bep.json")
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let configured = |label: &str| {
+        events
+            .iter()
+            .find(|e| e["id"]["targetConfigured"]["label"] == label)
+            .unwrap()
+    };
+    // Only test rules announce a test result.
+    assert_eq!(
+        configured("@@//:smoke_test")["children"]
+            .as_array()
+            .unwrap()
+            .len(),
+        2
+    );
+    assert_eq!(
+        configured("@@//:scripts")["configured"]["targetKind"],
+        "filegroup rule"
+    );
+    assert_eq!(
+        configured("@@//:scripts")["children"]
+            .as_array()
+            .unwrap()
+            .len(),
+        1
+    );
+    // Every announced event is posted, and the stream ends with the exit code razel exits with.
+    let ids: Vec<_> = events.iter().map(|e| &e["id"]).collect();
+    for child in events
+        .iter()
+        .filter_map(|e| e["children"].as_array())
+        .flatten()
+    {
+        assert!(ids.contains(&child), "{child} is never posted");
+    }
+    let finished = events.iter().find(|e| e["finished"].is_object()).unwrap();
+    assert_eq!(finished["finished"]["exitCode"]["code"], 2);
+}
+
+#[test]
+fn test_run_not_supported() {
+    let workspace = TestWorkspace::example("basic");
+    let outcome = workspace.run(&["run", "//:hello_world"]);
+    assert_eq!(outcome.code, Some(2), "{}", outcome.snapshot());
+    assert!(
+        outcome
+            .stderr
+            .contains("razel run is not supported yet: //:hello_world"),
+        "{}",
+        outcome.snapshot()
+    );
+}
+
+#[test]
+fn test_tests_not_run_json() {
+    let workspace = TestWorkspace::example("tests");
+    for command in ["test", "coverage"] {
+        let outcome = workspace.run(&[command, "--format=json", "//..."]);
+        assert_eq!(outcome.code, Some(2), "{}", outcome.snapshot());
+        let result: serde_json::Value = serde_json::from_str(&outcome.stdout).unwrap();
+        assert_eq!(result["command"], command);
+        assert_eq!(result["success"], false);
+        assert_eq!(result["exitCode"]["name"], "COMMAND_LINE_ERROR");
+        assert!(
+            result["errors"][0]["message"]
+                .as_str()
+                .unwrap()
+                .contains("is not supported yet"),
+            "{}",
+            outcome.snapshot()
+        );
+    }
+}
@@ -0,0 +1,52 @@
+use assert_cmd::Command;
+use predicates::prelude::*; // Used for writing assertions
+
+#[test]
+fn test_generate_applies_build_edits() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp = assert_fs::TempDir::new()?;
+    std::fs::write(tmp.path().join("MODULE.bazel"), "module(name = \"gen\")")?;
+    std::fs::create_dir_all(tmp.path().join("pkg"))?;
+    std::fs::write(tmp.path().join("pkg/main.go"), "package main")?;
+    std::fs::create_dir_all(tmp.path().join("tools"))?;
+    let generator = tmp.path().join("tools/gen.sh");
+    std::fs::write(
+        &generator,
+        "#!/bin/sh\n\
+         grep -q '\"path\":\"pkg\",\"files\":\\[[^]]*\"main.go\"' || exit 1\n\
+         printf '%s\\n' '{\"edits\": [{\"path\": \"pkg/BUILD.bazel\", \"content\": \"filegroup(name = \\\"main\\\")\\n\"}]}'\n",
+    )?;
+    std::fs::set_permissions(&generator, std::fs::Permissions::from_mode(0o755))?;
+    std::fs::write(
+        tmp.path().join("generators.json"),
+        r#"{"generators": [{"name": "go", "command": ["tools/gen.sh"]}]}"#,
+    )?;
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir(tmp.path());
+    cmd.args(["generate", "--check"]);
+    cmd.assert()
+        .failure()
+        .stdout(predicate::str::contains("pkg/BUILD.bazel is out of date"));
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir(tmp.path());
+    cmd.arg("generate");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Updated pkg/BUILD.bazel"));
+    assert_eq!(
+        std::fs::read_to_string(tmp.path().join("pkg/BUILD.bazel"))?,
+        "filegroup(name = \"main\")\n"
+    );
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir(tmp.path());
+    cmd.args(["generate", "--check", "go"]);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("out of date").not());
+
+    Ok(())
+}
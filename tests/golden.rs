@@ -0,0 +1,71 @@
+mod common;
+
+use common::{TestWorkspace, assert_golden};
+
+#[test]
+fn test_golden_query_basic() {
+    let workspace = TestWorkspace::example("basic");
+    assert_golden(
+        "query_basic",
+        &workspace.run(&["query", "//..."]).snapshot(),
+    );
+}
+
+#[test]
+fn test_golden_query_loads() {
+    let workspace = TestWorkspace::example("loads");
+    let mut snapshot = workspace.run(&["query", "//..."]).snapshot();
+    snapshot.push_str(&workspace.run(&["query", "//tools:all"]).snapshot());
+    assert_golden("query_loads", &snapshot);
+}
+
+#[test]
+fn test_golden_build_load_error() {
+    let workspace = TestWorkspace::example("loads");
+    workspace.write("defs/prefix.bzl", "PREFIX = undefined\n");
+    assert_golden(
+        "build_load_error",
+        &workspace.run(&["build", "//:all"]).snapshot(),
+    );
+}
+
+#[test]
+fn test_golden_usage_error() {
+    let workspace = TestWorkspace::example("basic");
+    assert_golden("usage_error", &workspace.run(&["query"]).snapshot());
+}
+
+#[test]
+fn test_golden_exit_codes() {
+    let workspace = TestWorkspace::example("basic");
+    let mut snapshot = workspace.run(&["query", "deps("]).snapshot();
+    snapshot.push_str(&workspace.run(&["query", "nonesuch(//...)"]).snapshot());
+    snapshot.push_str(&workspace.run(&["test", "--", "-//..."]).snapshot());
+    assert_golden("exit_codes", &snapshot);
+}
+
+#[test]
+fn test_golden_generate() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let workspace = TestWorkspace::example("loads");
+    workspace
+        .write(
+            "tools/gen.sh",
+            "#!/bin/sh\n\
+             printf '%s\\n' '{\"edits\": [{\"path\": \"tools/BUILD.bazel\", \"content\": \"cc_binary(name = \\\"gen\\\")\\n\"}]}'\n",
+        )
+        .write(
+            "generators.json",
+            r#"{"generators": [{"name": "tools", "command": ["tools/gen.sh"]}]}"#,
+        );
+    std::fs::set_permissions(
+        workspace.path().join("tools/gen.sh"),
+        std::fs::Permissions::from_mode(0o755),
+    )
+    .unwrap();
+
+    let mut snapshot = workspace.run(&["generate"]).snapshot();
+    snapshot.push_str(&workspace.tree("tools"));
+    assert_golden("generate", &snapshot);
+}
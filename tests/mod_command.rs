@@ -0,0 +1,1214 @@
+mod common;
+
+use assert_cmd::Command;
+use common::{TestRegistry, TestWorkspace};
+use predicates::prelude::*; // Used for writing assertions
+
+#[test]
+fn test_mod_show_extension() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = assert_fs::TempDir::new()?;
+    std::fs::write(tmp.path().join("MODULE.bazel"), "module(name = \"sdk\")")?;
+    std::fs::write(
+        tmp.path().join("MODULE.bazel.lock"),
+        r#"{
+          "lockFileVersion": 18,
+          "moduleExtensions": {
+            "@@//:ext.bzl%toolchains": {
+              "general": {
+                "bzlTransitiveDigest": "",
+                "usagesDigest": "",
+                "generatedRepoSpecs": {
+                  "go_sdk": {
+                    "repoRuleId": "@@bazel_tools//tools/build_defs/repo:http.bzl%http_archive",
+                    "attributes": {
+                      "urls": ["https://example.com/go.tar.gz"],
+                      "integrity": "sha256-LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ="
+                    }
+                  }
+                }
+              }
+            }
+          }
+        }"#,
+    )?;
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir(tmp.path());
+
+    cmd.arg("mod")
+        .arg("show_extension")
+        .arg("//:ext.bzl%toolchains");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("  - go_sdk (http_archive)"))
+        .stdout(predicate::str::contains(
+            "url: https://example.com/go.tar.gz",
+        ))
+        .stdout(predicate::str::contains(
+            "integrity: sha256-LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=",
+        ));
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir(tmp.path());
+
+    cmd.arg("mod").arg("show_extension").arg("//:ext.bzl%other");
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("No evaluation of extension"));
+
+    Ok(())
+}
+
+#[test]
+fn test_mod_graph() -> Result<(), Box<dyn std::error::Error>> {
+    let workspace = TestWorkspace::new("app");
+    workspace
+        .write(
+            "MODULE.bazel",
+            "module(name = \"app\", version = \"1.0\")\n\
+             bazel_dep(name = \"dep\", version = \"1.0\")\n\
+             bazel_dep(name = \"missing\", version = \"2.0\")\n",
+        )
+        .write(
+            "vendor/dep+1.0/MODULE.bazel",
+            "module(name = \"dep\", version = \"1.0\")\n\
+             bazel_dep(name = \"missing\", version = \"2.0\")\n",
+        );
+    // An empty registry has no other modules.
+    let registry = TestRegistry::new().url();
+
+    workspace
+        .razel()
+        .args([
+            "mod",
+            "graph",
+            "--vendor_dir=vendor",
+            &format!("--registry={registry}"),
+        ])
+        .assert()
+        .success()
+        .stdout(
+            "<root> (app@1.0)\n\
+             ├───dep@1.0\n\
+             │   └───missing@2.0\n\
+             └───missing@2.0 (*)\n",
+        );
+
+    workspace
+        .razel()
+        .args([
+            "mod",
+            "graph",
+            "--verbose",
+            "--vendor_dir=vendor",
+            &format!("--registry={registry}"),
+        ])
+        .assert()
+        .success()
+        .stdout(format!(
+            "<root> (app@1.0)\n\
+             ├───dep@1.0 (vendored)\n\
+             │   └───missing@2.0 (fetch failed, RAZEL_RESOLUTION_MODULE_NOT_FOUND: \
+             Module missing@2.0 not found in registry {registry})\n\
+             └───missing@2.0 (*)\n",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn test_check_direct_dependencies() -> Result<(), Box<dyn std::error::Error>> {
+    let workspace = TestWorkspace::new("app");
+    workspace
+        .write(
+            "MODULE.bazel",
+            "module(name = \"app\", version = \"1.0\")\n\
+             bazel_dep(name = \"dep\", version = \"1.0\")\n\
+             bazel_dep(name = \"lib\", version = \"1.0\")\n",
+        )
+        .write(
+            "vendor/dep+1.0/MODULE.bazel",
+            "module(name = \"dep\", version = \"1.0\")\n\
+             bazel_dep(name = \"lib\", version = \"1.2\")\n",
+        );
+    // lib isn't in the (empty) registry, so each module only contributes its own version.
+    let registry = format!("--registry={}", TestRegistry::new().url());
+    let mod_graph = |args: &[&str]| {
+        let mut cmd = workspace.razel();
+        cmd.args(["mod", "graph", "--vendor_dir=vendor", &registry])
+            .args(args);
+        cmd
+    };
+    let warning = "WARNING: For repository 'lib', the root module requires module version lib@1.0, but got \
+                   lib@1.2 in the resolved dependency graph. Update MODULE.bazel to: \
+                   bazel_dep(name = \"lib\", version = \"1.2\")";
+
+    mod_graph(&[])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(warning));
+    mod_graph(&["--check_direct_dependencies=error"])
+        .assert()
+        .code(48)
+        .stderr(predicate::str::contains("lib@1.0, but got lib@1.2"));
+    mod_graph(&["--check_direct_dependencies=off"])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("WARNING").not());
+
+    Ok(())
+}
+
+#[test]
+fn test_selected_version() -> Result<(), Box<dyn std::error::Error>> {
+    let workspace = TestWorkspace::new("app");
+    workspace.write(
+        "MODULE.bazel",
+        "module(name = \"app\", version = \"1.0\")\n\
+         bazel_dep(name = \"dep\", version = \"1.0\")\n\
+         bazel_dep(name = \"lib\", version = \"1.0\")\n",
+    );
+    for (repo, module) in [
+        (
+            "dep+1.0",
+            "module(name = \"dep\", version = \"1.0\")\n\
+             bazel_dep(name = \"lib\", version = \"1.2\")\n",
+        ),
+        ("lib+1.0", "module(name = \"lib\", version = \"1.0\")\n"),
+        ("lib+1.2", "module(name = \"lib\", version = \"1.2\")\n"),
+    ] {
+        workspace.write(&format!("vendor/{repo}/MODULE.bazel"), module);
+    }
+    workspace
+        .write("vendor/lib+1.2/BUILD.bazel", "filegroup(name = \"x\")\n")
+        .write(
+            "vendor/dep+1.0/BUILD.bazel",
+            "filegroup(name = \"x\", srcs = [\"@lib//:x\"])\n",
+        );
+    let registry = format!("--registry={}", TestRegistry::new().url());
+    let razel = |args: &[&str]| {
+        let mut cmd = workspace.razel();
+        cmd.args(args).args([
+            "--vendor_dir=vendor",
+            &registry,
+            "--check_direct_dependencies=off",
+        ]);
+        cmd
+    };
+
+    // The root module and dep both see the version minimal version selection picks.
+    for (pattern, expected) in [
+        ("@lib//:x", "@@lib+1.2//:x\n"),
+        ("deps(@dep//:x)", "@@dep+1.0//:x\n@@lib+1.2//:x\n"),
+    ] {
+        razel(&["query", pattern])
+            .assert()
+            .success()
+            .stdout(expected);
+    }
+
+    razel(&["mod", "graph"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("lib@1.0").not());
+
+    Ok(())
+}
+
+#[test]
+fn test_mod_graph_analysis() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = assert_fs::TempDir::new()?;
+    std::fs::write(
+        tmp.path().join("MODULE.bazel"),
+        "module(name = \"app\", version = \"1.0\")\n\
+         bazel_dep(name = \"a\", version = \"1.0\")\n\
+         bazel_dep(name = \"lib\", version = \"1.0\")\n",
+    )?;
+    for (module, deps) in [
+        ("a+1.0", "bazel_dep(name = \"b\", version = \"1.0\")\n"),
+        (
+            "b+1.0",
+            "bazel_dep(name = \"a\", version = \"1.0\")\n\
+             bazel_dep(name = \"lib\", version = \"1.2\")\n",
+        ),
+        ("lib+1.0", ""),
+        ("lib+1.2", ""),
+    ] {
+        let dir = tmp.path().join("vendor").join(module);
+        std::fs::create_dir_all(&dir)?;
+        let (name, version) = module.split_once('+').unwrap();
+        std::fs::write(
+            dir.join("MODULE.bazel"),
+            format!("module(name = \"{name}\", version = \"{version}\")\n{deps}"),
+        )?;
+    }
+    let razel = |args: &[&str]| {
+        let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+        cmd.current_dir(tmp.path());
+        cmd.args(["mod"])
+            .args(args)
+            .args(["--vendor_dir=vendor", "--check_direct_dependencies=off"]);
+        cmd
+    };
+
+    razel(&["graph", "--cycles"]).assert().success().stdout(
+        "Found 1 cycle(s) in the module graph:\n  \
+         a@1.0 -> b@1.0 -> a@1.0\n",
+    );
+    razel(&["all_paths", "lib"]).assert().success().stdout(
+        "## lib:\n  \
+         <root> -> a@1.0 -> b@1.0 -> lib@1.2\n  \
+         <root> -> lib@1.0\n",
+    );
+    razel(&["explain", "lib"]).assert().success().stdout(
+        "## lib (selected lib@1.2):\n  \
+         b@1.0 -> lib@1.2 (selected)\n  \
+         <root> -> lib@1.0\n",
+    );
+    razel(&["explain", "nothing"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("No module named \"nothing\""));
+
+    Ok(())
+}
+
+#[test]
+fn test_fetch_repo() -> Result<(), Box<dyn std::error::Error>> {
+    let workspace = TestWorkspace::new("app");
+    workspace
+        .write(
+            "MODULE.bazel",
+            "module(name = \"app\", version = \"1.0\")\n\
+             bazel_dep(name = \"dep\", version = \"1.0\")\n\
+             bazel_dep(name = \"missing\", version = \"2.0\")\n",
+        )
+        .write(
+            "vendor/dep+1.0/MODULE.bazel",
+            "module(name = \"dep\", version = \"1.0\")\n",
+        );
+    let dep_dir = workspace.path().join("vendor/dep+1.0");
+    // An empty registry has no other modules.
+    let registry = TestRegistry::new().url();
+    let fetch = |repo: &str| {
+        let mut cmd = workspace.razel();
+        cmd.args([
+            "fetch",
+            "--vendor_dir=vendor",
+            &format!("--registry={registry}"),
+            &format!("--repo={repo}"),
+        ]);
+        cmd
+    };
+
+    for repo in ["@dep", "dep", "@@dep+1.0"] {
+        fetch(repo)
+            .assert()
+            .success()
+            .stdout(format!("Fetched @@dep+1.0 into {}\n", dep_dir.display()));
+    }
+    fetch("@other")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "No repository visible as @other from the main repository",
+        ));
+    fetch("missing")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(format!(
+            "Module missing@2.0 not found in registry {registry}"
+        )));
+
+    Ok(())
+}
+
+#[test]
+fn test_single_version_override() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = assert_fs::TempDir::new()?;
+    std::fs::write(
+        tmp.path().join("MODULE.bazel"),
+        "module(name = \"app\", version = \"1.0\")\n\
+         bazel_dep(name = \"a\", version = \"1.0\")\n\
+         bazel_dep(name = \"lib\", version = \"1.0\")\n\
+         single_version_override(module_name = \"lib\", version = \"1.1\")\n",
+    )?;
+    for (module, deps) in [
+        ("a+1.0", "bazel_dep(name = \"lib\", version = \"1.2\")\n"),
+        ("lib+1.1", ""),
+    ] {
+        let dir = tmp.path().join("vendor").join(module);
+        std::fs::create_dir_all(&dir)?;
+        let (name, version) = module.split_once('+').unwrap();
+        std::fs::write(
+            dir.join("MODULE.bazel"),
+            format!("module(name = \"{name}\", version = \"{version}\")\n{deps}"),
+        )?;
+    }
+
+    // Every module gets lib@1.1, even where a higher version is asked for, and the root module's isn't drift.
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir(tmp.path());
+    cmd.args(["mod", "graph", "--vendor_dir=vendor"]);
+    cmd.assert()
+        .success()
+        .stdout(
+            "<root> (app@1.0)\n\
+             ├───a@1.0\n\
+             │   └───lib@1.1\n\
+             └───lib@1.1 (*)\n",
+        )
+        .stderr(predicate::str::contains("WARNING").not());
+
+    std::fs::write(
+        tmp.path().join("MODULE.bazel"),
+        "module(name = \"app\", version = \"1.0\")\n\
+         bazel_dep(name = \"lib\", version = \"1.0\")\n\
+         single_version_override(module_name = \"lib\", version = \"1.1\")\n\
+         single_version_override(module_name = \"lib\", patches = [\"//:fix.patch\"])\n",
+    )?;
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir(tmp.path());
+    cmd.args(["mod", "graph", "--vendor_dir=vendor"]);
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "The module lib has multiple overrides",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_multiple_version_override() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = assert_fs::TempDir::new()?;
+    std::fs::write(
+        tmp.path().join("MODULE.bazel"),
+        "module(name = \"app\", version = \"1.0\")\n\
+         bazel_dep(name = \"a\", version = \"1.0\")\n\
+         bazel_dep(name = \"lib\", version = \"1.0\")\n\
+         multiple_version_override(module_name = \"lib\", versions = [\"1.0\", \"2.0\"])\n",
+    )?;
+    for (module, deps) in [
+        ("a+1.0", "bazel_dep(name = \"lib\", version = \"1.5\")\n"),
+        ("lib+1.0", ""),
+        ("lib+2.0", ""),
+    ] {
+        let dir = tmp.path().join("vendor").join(module);
+        std::fs::create_dir_all(&dir)?;
+        let (name, version) = module.split_once('+').unwrap();
+        std::fs::write(
+            dir.join("MODULE.bazel"),
+            format!("module(name = \"{name}\", version = \"{version}\")\n{deps}"),
+        )?;
+    }
+
+    // a gets the lowest allowed version at or above the one it asks for, and the root module keeps its own.
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir(tmp.path());
+    cmd.args(["mod", "graph", "--vendor_dir=vendor"]);
+    cmd.assert()
+        .success()
+        .stdout(
+            "<root> (app@1.0)\n\
+             ├───a@1.0\n\
+             │   └───lib@2.0\n\
+             └───lib@1.0\n",
+        )
+        .stderr(predicate::str::contains("WARNING").not());
+
+    std::fs::write(
+        tmp.path().join("vendor/a+1.0/MODULE.bazel"),
+        "module(name = \"a\", version = \"1.0\")\n\
+         bazel_dep(name = \"lib\", version = \"3.0\")\n",
+    )?;
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir(tmp.path());
+    cmd.args(["fetch", "--vendor_dir=vendor", "--repo=@a"]);
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "@@a+1.0 depends on lib@3.0, but none of the versions multiple_version_override allows is at or \
+         above it: 1.0, 2.0",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_archive_override() -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Write;
+
+    let tmp = assert_fs::TempDir::new()?;
+    let archive = tmp.path().join("lib.zip");
+    let mut zip = zip::ZipWriter::new(std::fs::File::create(&archive)?);
+    for (path, content) in [
+        (
+            "lib-1.0/MODULE.bazel",
+            "module(name = \"lib\", version = \"1.0\")\n",
+        ),
+        ("lib-1.0/data.txt", "old\n"),
+    ] {
+        zip.start_file(path, zip::write::SimpleFileOptions::default())?;
+        zip.write_all(content.as_bytes())?;
+    }
+    zip.finish()?;
+
+    let main = tmp.path().join("main");
+    std::fs::create_dir(&main)?;
+    std::fs::write(
+        main.join("fix.patch"),
+        "--- a/data.txt\n+++ b/data.txt\n@@ -1 +1 @@\n-old\n+new\n",
+    )?;
+    let module = |integrity: &str| {
+        format!(
+            "module(name = \"app\", version = \"1.0\")\n\
+             bazel_dep(name = \"lib\", version = \"1.0\")\n\
+             archive_override(\n    \
+                 module_name = \"lib\",\n    \
+                 urls = [\"file://{}\"],\n    \
+                 integrity = \"{integrity}\",\n    \
+                 strip_prefix = \"lib-1.0\",\n    \
+                 patches = [\"//:fix.patch\"],\n    \
+                 patch_strip = 1,\n\
+             )\n",
+            archive.display()
+        )
+    };
+    std::fs::write(main.join("MODULE.bazel"), module(""))?;
+    let output_base = tmp.path().join("out");
+    let fetch = || {
+        let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+        cmd.current_dir(&main).args([
+            &format!("--output_base={}", output_base.display()),
+            "fetch",
+            "--repo=@lib",
+        ]);
+        cmd
+    };
+
+    // Overridden modules have the empty version.
+    let dir = output_base.join("external/lib+");
+    fetch()
+        .assert()
+        .success()
+        .stdout(format!("Fetched @@lib+ into {}\n", dir.display()));
+    assert_eq!(std::fs::read_to_string(dir.join("data.txt"))?, "new\n");
+
+    std::fs::write(
+        main.join("MODULE.bazel"),
+        module("sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="),
+    )?;
+    fetch().assert().failure().stderr(predicate::str::contains(
+        "expected sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU= but got",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_archive_override_tarball() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = assert_fs::TempDir::new()?;
+    let src = tmp.path().join("lib-1.0");
+    std::fs::create_dir_all(src.join("bin"))?;
+    std::fs::write(
+        src.join("MODULE.bazel"),
+        "module(name = \"lib\", version = \"1.0\")\n",
+    )?;
+    std::fs::write(src.join("BUILD.bazel"), "filegroup(name = \"data\")\n")?;
+    std::fs::write(src.join("bin/tool"), "#!/bin/sh\n")?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(src.join("bin/tool"), std::fs::Permissions::from_mode(0o755))?;
+    }
+    let status = std::process::Command::new("tar")
+        .args(["-czf", "lib.tar.gz", "lib-1.0"])
+        .current_dir(tmp.path())
+        .status()
+        .expect("failed to run tar");
+    assert!(status.success(), "tar failed");
+
+    let main = tmp.path().join("main");
+    std::fs::create_dir(&main)?;
+    let output_base = tmp.path().join("out");
+    let razel = |url: &str, args: &[&str]| {
+        std::fs::write(
+            main.join("MODULE.bazel"),
+            format!(
+                "module(name = \"app\", version = \"1.0\")\n\
+                 bazel_dep(name = \"lib\", version = \"1.0\")\n\
+                 archive_override(\n    \
+                     module_name = \"lib\",\n    \
+                     urls = [\"{url}\"],\n    \
+                     strip_prefix = \"lib-1.0\",\n\
+                 )\n",
+            ),
+        )
+        .unwrap();
+        let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+        cmd.current_dir(&main)
+            .arg(format!("--output_base={}", output_base.display()))
+            .args(args);
+        cmd
+    };
+
+    let url = format!("file://{}", tmp.path().join("lib.tar.gz").display());
+    let dir = output_base.join("external/lib+");
+    razel(&url, &["fetch", "--repo=@lib"])
+        .assert()
+        .success()
+        .stdout(format!("Fetched @@lib+ into {}\n", dir.display()));
+    assert_eq!(
+        std::fs::read_to_string(dir.join("bin/tool"))?,
+        "#!/bin/sh\n"
+    );
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(dir.join("bin/tool"))?
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o755);
+    }
+    razel(&url, &["query", "@lib//:all"])
+        .assert()
+        .success()
+        .stdout("@@lib+//:data\n");
+
+    let url = format!("file://{}", tmp.path().join("lib.tar.xz").display());
+    razel(&url, &["fetch", "--repo=@lib"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(format!(
+            "Unsupported archive type of {url}"
+        )));
+
+    Ok(())
+}
+
+#[test]
+fn test_registry() -> Result<(), Box<dyn std::error::Error>> {
+    use base64::Engine;
+    use sha2::Digest;
+    use std::io::Write;
+
+    let b64 = |hash: &[u8]| base64::engine::general_purpose::STANDARD.encode(hash);
+    let integrity = |content: &[u8]| format!("sha256-{}", b64(&sha2::Sha256::digest(content)));
+    let registry = TestRegistry::new();
+    let archive = registry.path().join("lib-1.0.zip");
+    let mut zip = zip::ZipWriter::new(std::fs::File::create(&archive)?);
+    for (path, content) in [
+        ("lib-1.0/MODULE.bazel", "module(name = \"lib\")\n"),
+        ("lib-1.0/data.txt", "old\n"),
+    ] {
+        zip.start_file(path, zip::write::SimpleFileOptions::default())?;
+        zip.write_all(content.as_bytes())?;
+    }
+    zip.finish()?;
+
+    let patch = "--- a/data.txt\n+++ b/data.txt\n@@ -1 +1 @@\n-old\n+new\n";
+    let overlay = "filegroup(name = \"data\", srcs = [\"data.txt\"])\n";
+    // Registries pin sources with sha384 and sha512 as well as sha256.
+    let source = format!(
+        r#"{{"url": "file://{}", "integrity": "sha512-{}", "strip_prefix": "lib-1.0",
+            "patches": {{"fix.patch": "{}"}}, "patch_strip": 1,
+            "overlay": {{"BUILD.bazel": "sha384-{}"}}}}"#,
+        archive.display(),
+        b64(&sha2::Sha512::digest(std::fs::read(&archive)?)),
+        integrity(patch.as_bytes()),
+        b64(&sha2::Sha384::digest(overlay)),
+    );
+    registry
+        .write(
+            "modules/lib/1.0/MODULE.bazel",
+            "module(name = \"lib\", version = \"1.0\")\n",
+        )
+        .write("modules/lib/1.0/source.json", &source)
+        .write("modules/lib/1.0/patches/fix.patch", patch)
+        .write("modules/lib/1.0/overlay/BUILD.bazel", overlay);
+
+    let workspace = TestWorkspace::new("app");
+    workspace.write(
+        "MODULE.bazel",
+        "module(name = \"app\", version = \"1.0\")\n\
+         bazel_dep(name = \"lib\", version = \"1.0\")\n",
+    );
+    let razel = |args: &[&str]| {
+        let mut cmd = workspace.razel();
+        cmd.arg(args[0])
+            .arg(format!("--registry={}", registry.url()))
+            .args(&args[1..]);
+        cmd
+    };
+
+    let dir = workspace.output_base().join("external/lib+1.0");
+    razel(&["fetch", "--repo=@lib"])
+        .assert()
+        .success()
+        .stdout(format!("Fetched @@lib+1.0 into {}\n", dir.display()));
+    assert_eq!(std::fs::read_to_string(dir.join("data.txt"))?, "new\n");
+    // The registry's MODULE.bazel replaces the archive's.
+    assert_eq!(
+        std::fs::read_to_string(dir.join("MODULE.bazel"))?,
+        "module(name = \"lib\", version = \"1.0\")\n"
+    );
+    razel(&["query", "@lib//:all"])
+        .assert()
+        .success()
+        .stdout("@@lib+1.0//:data\n");
+
+    Ok(())
+}
+
+#[test]
+fn test_registries() -> Result<(), Box<dyn std::error::Error>> {
+    let (private, public) = (TestRegistry::new(), TestRegistry::new());
+    for (registry, target) in [(&private, "private"), (&public, "public")] {
+        registry.add_module(
+            "lib",
+            "1.0",
+            "",
+            &[("BUILD.bazel", &format!("filegroup(name = \"{target}\")\n"))],
+        );
+    }
+    let workspace = TestWorkspace::new("app");
+    workspace.write(
+        "MODULE.bazel",
+        "module(name = \"app\", version = \"1.0\")\n\
+         bazel_dep(name = \"lib\", version = \"1.0\")\n",
+    );
+    let query = |lockfile_mode: &str| {
+        let mut cmd = workspace.razel();
+        cmd.args([
+            "query",
+            &format!("--registry={}", private.url()),
+            &format!("--registry={}", public.url()),
+            &format!("--lockfile_mode={lockfile_mode}"),
+            "@lib//:all",
+        ]);
+        cmd
+    };
+
+    // A module comes from the first registry that has it...
+    query("off")
+        .assert()
+        .success()
+        .stdout("@@lib+1.0//:private\n");
+
+    // ...unless the lockfile pins it to another.
+    workspace.write(
+        "MODULE.bazel.lock",
+        &format!(
+            r#"{{"lockFileVersion": 18, "registryFileHashes": {{"{}/modules/lib/1.0/MODULE.bazel": "not found"}}}}"#,
+            private.url()
+        ),
+    );
+    query("update")
+        .assert()
+        .success()
+        .stdout("@@lib+1.0//:public\n");
+    query("off")
+        .assert()
+        .success()
+        .stdout("@@lib+1.0//:private\n");
+
+    Ok(())
+}
+
+#[test]
+fn test_yanked_versions() -> Result<(), Box<dyn std::error::Error>> {
+    let registry = TestRegistry::new();
+    registry
+        .write(
+            "modules/lib/metadata.json",
+            r#"{"versions": ["1.0"], "yanked_versions": {"1.0": "CVE-1"}}"#,
+        )
+        .add_module(
+            "lib",
+            "1.0",
+            "",
+            &[("BUILD.bazel", "filegroup(name = \"x\")\n")],
+        );
+    let workspace = TestWorkspace::new("app");
+    workspace.write(
+        "MODULE.bazel",
+        "module(name = \"app\", version = \"1.0\")\n\
+         bazel_dep(name = \"lib\", version = \"1.0\")\n",
+    );
+    let query = |args: &[&str]| {
+        let mut cmd = workspace.razel();
+        cmd.args([
+            "query",
+            &format!("--registry={}", registry.url()),
+            "--lockfile_mode=off",
+        ]);
+        cmd.args(args).arg("@lib//:all");
+        cmd
+    };
+
+    query(&[]).assert().failure().stderr(predicate::str::contains(
+        "Yanked version detected in the resolved dependency graph: lib@1.0, for the reason: CVE-1",
+    ));
+    for allowed in ["lib@1.0", "all"] {
+        query(&[&format!("--allow_yanked_versions={allowed}")])
+            .assert()
+            .success()
+            .stdout("@@lib+1.0//:x\n");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_git_override() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = assert_fs::TempDir::new()?;
+    let remote = tmp.path().join("lib");
+    std::fs::create_dir(&remote)?;
+    let git = |args: &[&str]| {
+        let output = std::process::Command::new("git")
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .current_dir(&remote)
+            .output()
+            .expect("failed to run git");
+        assert!(output.status.success(), "git {args:?} failed");
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    };
+    git(&["init", "-q"]);
+    std::fs::write(
+        remote.join("MODULE.bazel"),
+        "module(name = \"lib\", version = \"1.0\")\n",
+    )?;
+    std::fs::write(remote.join("data.txt"), "first\n")?;
+    git(&["add", "."]);
+    git(&["commit", "-q", "-m", "first"]);
+    let first = git(&["rev-parse", "HEAD"]);
+    std::fs::write(remote.join("data.txt"), "second\n")?;
+    git(&["commit", "-q", "-am", "second"]);
+    git(&["tag", "v2"]);
+    std::fs::write(remote.join("data.txt"), "third\n")?;
+    git(&["commit", "-q", "-am", "third"]);
+
+    let main = tmp.path().join("main");
+    std::fs::create_dir(&main)?;
+    let output_base = tmp.path().join("out");
+    let dir = output_base.join("external/lib+");
+    let fetch = |revision: &str| {
+        std::fs::write(
+            main.join("MODULE.bazel"),
+            format!(
+                "module(name = \"app\", version = \"1.0\")\n\
+                 bazel_dep(name = \"lib\", version = \"1.0\")\n\
+                 git_override(\n    \
+                     module_name = \"lib\",\n    \
+                     remote = \"file://{}\",\n    \
+                     {revision},\n    \
+                     patch_cmds = [\"echo patched >> data.txt\"],\n\
+                 )\n",
+                remote.display()
+            ),
+        )
+        .unwrap();
+        let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+        cmd.current_dir(&main).args([
+            &format!("--output_base={}", output_base.display()),
+            "fetch",
+            "--repo=@lib",
+        ]);
+        cmd
+    };
+
+    fetch("tag = \"v2\"")
+        .assert()
+        .success()
+        .stdout(format!("Fetched @@lib+ into {}\n", dir.display()));
+    assert_eq!(
+        std::fs::read_to_string(dir.join("data.txt"))?,
+        "second\npatched\n"
+    );
+
+    fetch(&format!("commit = \"{first}\"")).assert().success();
+    assert_eq!(
+        std::fs::read_to_string(dir.join("data.txt"))?,
+        "first\npatched\n"
+    );
+
+    fetch("tag = \"v3\"")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Failed to fetch git repository"));
+
+    Ok(())
+}
+
+#[test]
+fn test_local_path_override() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = assert_fs::TempDir::new()?;
+    let main = tmp.path().join("main");
+    let lib = tmp.path().join("lib");
+    std::fs::create_dir(&main)?;
+    std::fs::create_dir(&lib)?;
+    std::fs::write(
+        main.join("MODULE.bazel"),
+        "module(name = \"app\", version = \"1.0\")\n\
+         bazel_dep(name = \"lib\", version = \"1.0\")\n\
+         local_path_override(module_name = \"lib\", path = \"../lib\")\n",
+    )?;
+    std::fs::write(
+        lib.join("MODULE.bazel"),
+        "module(name = \"lib\", version = \"1.0\")\n",
+    )?;
+    std::fs::write(
+        lib.join("BUILD.bazel"),
+        "filegroup(name = \"a\", visibility = [\"//visibility:public\"])\n",
+    )?;
+    let razel = |args: &[&str]| {
+        let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+        cmd.current_dir(&main)
+            .arg(format!(
+                "--output_base={}",
+                tmp.path().join("out").display()
+            ))
+            .args(args);
+        cmd
+    };
+
+    razel(&["fetch", "--repo=@lib"])
+        .assert()
+        .success()
+        .stdout(format!(
+            "Fetched @@lib+ into {}\n",
+            main.join("../lib").display()
+        ));
+    razel(&["query", "@lib//:all"])
+        .assert()
+        .success()
+        .stdout("@@lib+//:a\n");
+
+    // Changes to the module are seen right away, as nothing is copied.
+    std::fs::write(
+        lib.join("BUILD.bazel"),
+        "filegroup(name = \"a\")\nfilegroup(name = \"b\")\n",
+    )?;
+    razel(&["query", "@lib//:all"])
+        .assert()
+        .success()
+        .stdout("@@lib+//:a\n@@lib+//:b\n");
+
+    std::fs::remove_file(lib.join("MODULE.bazel"))?;
+    razel(&["query", "@lib//:all"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("which has no MODULE.bazel"));
+
+    Ok(())
+}
+
+#[test]
+fn test_lockfile_mode() -> Result<(), Box<dyn std::error::Error>> {
+    let registry = TestRegistry::new();
+    registry
+        .add_module("lib", "1.0", "", &[])
+        .add_module("lib", "1.1", "", &[]);
+    let url = registry.url();
+    let workspace = TestWorkspace::new("app");
+    let lockfile = workspace.path().join("MODULE.bazel.lock");
+    let razel = |mode: &str| {
+        let mut cmd = workspace.razel();
+        cmd.args(["mod", "graph", &format!("--registry={url}"), mode]);
+        cmd
+    };
+    let depend_on = |version: &str| {
+        workspace.write(
+            "MODULE.bazel",
+            &format!(
+                "module(name = \"app\", version = \"1.0\")\n\
+                 bazel_dep(name = \"lib\", version = \"{version}\")\n"
+            ),
+        );
+    };
+
+    depend_on("1.0");
+    razel("--lockfile_mode=update").assert().success();
+    let recorded: serde_json::Value = serde_json::from_slice(&std::fs::read(&lockfile)?)?;
+    assert_eq!(recorded["lockFileVersion"], 18);
+    // The resolved version is recorded through the registry files it was resolved from, as Bazel records it.
+    let fields: Vec<_> = recorded.as_object().unwrap().keys().cloned().collect();
+    assert_eq!(fields, ["lockFileVersion", "registryFileHashes"]);
+    assert!(
+        recorded["registryFileHashes"]
+            .get(format!("{url}/modules/lib/1.0/MODULE.bazel"))
+            .is_some()
+    );
+    let recorded = std::fs::read(&lockfile)?;
+
+    depend_on("1.1");
+    razel("--lockfile_mode=error")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("MODULE.bazel.lock is out of date"))
+        .stderr(predicate::str::contains(format!(
+            "registry file {url}/modules/lib/1.1/MODULE.bazel is new"
+        )));
+    razel("--lockfile_mode=off").assert().success();
+    assert_eq!(std::fs::read(&lockfile)?, recorded);
+    // query is read-only and runs without the output base lock, so it leaves the lockfile alone.
+    workspace
+        .razel()
+        .args([
+            "query",
+            &format!("--registry={url}"),
+            "--lockfile_mode=update",
+            "@lib//...",
+        ])
+        .assert()
+        .success();
+    assert_eq!(std::fs::read(&lockfile)?, recorded);
+
+    razel("--lockfile_mode=update").assert().success();
+    razel("--lockfile_mode=error").assert().success();
+
+    Ok(())
+}
+
+#[test]
+fn test_lockfile_registry_file_hashes() -> Result<(), Box<dyn std::error::Error>> {
+    let registry = TestRegistry::new();
+    registry
+        .write(
+            "modules/lib/metadata.json",
+            r#"{"versions": ["1.0"], "yanked_versions": {}}"#,
+        )
+        .add_module("lib", "1.0", "", &[]);
+    let workspace = TestWorkspace::new("app");
+    workspace.write(
+        "MODULE.bazel",
+        "module(name = \"app\", version = \"1.0\")\n\
+         bazel_dep(name = \"lib\", version = \"1.0\")\n",
+    );
+    let url = registry.url();
+    let razel = |mode: &str| {
+        let mut cmd = workspace.razel();
+        cmd.args(["mod", "graph", &format!("--registry={url}"), mode]);
+        cmd
+    };
+
+    razel("--lockfile_mode=error")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(format!(
+            "registry file {url}/modules/lib/1.0/MODULE.bazel is new"
+        )));
+    razel("--lockfile_mode=update").assert().success();
+    razel("--lockfile_mode=error").assert().success();
+    let recorded: serde_json::Value = serde_json::from_str(&workspace.read("MODULE.bazel.lock"))?;
+    let hashes: Vec<_> = recorded["registryFileHashes"]
+        .as_object()
+        .unwrap()
+        .keys()
+        .cloned()
+        .collect();
+    // metadata.json changes as versions are added, so only the files of the version are recorded.
+    assert_eq!(
+        hashes,
+        [
+            format!("{url}/modules/lib/1.0/MODULE.bazel"),
+            format!("{url}/modules/lib/1.0/source.json"),
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_compatibility() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = assert_fs::TempDir::new()?;
+    let write_module = |dir: &std::path::Path, content: &str| {
+        std::fs::create_dir_all(dir)?;
+        std::fs::write(dir.join("MODULE.bazel"), content)
+    };
+    let mod_graph = || {
+        let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+        cmd.current_dir(tmp.path());
+        cmd.args(["mod", "graph", "--vendor_dir=vendor", "--lockfile_mode=off"]);
+        cmd
+    };
+
+    write_module(
+        tmp.path(),
+        "module(name = \"app\", version = \"1.0\", bazel_compatibility = [\">=7.0.0\", \"<1000.0.0\"])\n",
+    )?;
+    mod_graph().assert().success();
+    write_module(
+        tmp.path(),
+        "module(name = \"app\", version = \"1.0\", bazel_compatibility = [\">=1000.0.0\"])\n",
+    )?;
+    mod_graph()
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Module <root> requires Bazel >=1000.0.0",
+        ));
+    write_module(
+        tmp.path(),
+        "module(name = \"app\", version = \"1.0\", bazel_compatibility = [\"7\"])\n",
+    )?;
+    mod_graph()
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Invalid bazel_compatibility \"7\"",
+        ));
+
+    // a and b ask for versions of lib at different compatibility levels.
+    let vendor = tmp.path().join("vendor");
+    write_module(
+        &vendor.join("lib+1.0"),
+        "module(name = \"lib\", version = \"1.0\", compatibility_level = 1)\n",
+    )?;
+    write_module(
+        &vendor.join("lib+2.0"),
+        "module(name = \"lib\", version = \"2.0\", compatibility_level = 2)\n",
+    )?;
+    write_module(
+        &vendor.join("b+1.0"),
+        "module(name = \"b\", version = \"1.0\")\n\
+         bazel_dep(name = \"lib\", version = \"2.0\")\n",
+    )?;
+    write_module(
+        tmp.path(),
+        "module(name = \"app\", version = \"1.0\")\n\
+         bazel_dep(name = \"a\", version = \"1.0\")\n\
+         bazel_dep(name = \"b\", version = \"1.0\")\n",
+    )?;
+    let depend_on_lib = |max_compatibility_level: i32| {
+        write_module(
+            &vendor.join("a+1.0"),
+            &format!(
+                "module(name = \"a\", version = \"1.0\")\n\
+                 bazel_dep(name = \"lib\", version = \"1.0\", max_compatibility_level = {max_compatibility_level})\n"
+            ),
+        )
+    };
+    depend_on_lib(-1)?;
+    mod_graph()
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "b@1.0 depends on lib@2.0 with compatibility level 2, but a@1.0 depends on lib@1.0 with compatibility \
+             level 1, which is different.",
+        ))
+        .stderr(predicate::str::contains("<root> -> a@1.0 -> lib@1.0"));
+    depend_on_lib(2)?;
+    mod_graph().assert().success();
+
+    Ok(())
+}
+
+#[test]
+fn test_dev_dependency() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = assert_fs::TempDir::new()?;
+    std::fs::write(
+        tmp.path().join("MODULE.bazel"),
+        "module(name = \"app\", version = \"1.0\")\n\
+         bazel_dep(name = \"a\", version = \"1.0\")\n\
+         bazel_dep(name = \"tool\", version = \"1.0\", dev_dependency = True)\n",
+    )?;
+    // a's dev dependency isn't vendored, as nothing but a's own tests would need it.
+    for (module, deps) in [
+        (
+            "a+1.0",
+            "bazel_dep(name = \"testing\", version = \"1.0\", dev_dependency = True)\n",
+        ),
+        ("tool+1.0", ""),
+    ] {
+        let dir = tmp.path().join("vendor").join(module);
+        std::fs::create_dir_all(&dir)?;
+        let (name, version) = module.split_once('+').unwrap();
+        std::fs::write(
+            dir.join("MODULE.bazel"),
+            format!("module(name = \"{name}\", version = \"{version}\")\n{deps}"),
+        )?;
+    }
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir(tmp.path());
+    cmd.args(["mod", "graph", "--vendor_dir=vendor"]);
+    cmd.assert().success().stdout(
+        "<root> (app@1.0)\n\
+         ├───a@1.0\n\
+         └───tool@1.0\n",
+    );
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir(tmp.path());
+    cmd.args([
+        "mod",
+        "graph",
+        "--vendor_dir=vendor",
+        "--ignore_dev_dependency",
+    ]);
+    cmd.assert().success().stdout(
+        "<root> (app@1.0)\n\
+         └───a@1.0\n",
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_vendor() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // app depends on a, which depends on b.
+    let registry = TestRegistry::new();
+    registry
+        .add_module(
+            "a",
+            "1.0",
+            "bazel_dep(name = \"b\", version = \"1.0\")\n",
+            &[],
+        )
+        .add_module(
+            "b",
+            "1.0",
+            "",
+            &[("tool/run.sh", "#!/bin/sh\n"), ("tool/data.txt", "data\n")],
+        );
+    std::fs::set_permissions(
+        registry.path().join("src/b-1.0/tool/run.sh"),
+        std::fs::Permissions::from_mode(0o755),
+    )?;
+    let workspace = TestWorkspace::new("app");
+    workspace.write(
+        "MODULE.bazel",
+        "module(name = \"app\", version = \"1.0\")\n\
+         bazel_dep(name = \"a\", version = \"1.0\")\n",
+    );
+
+    let vendor = workspace.path().join("vendor");
+    workspace
+        .razel()
+        .args([
+            "vendor",
+            &format!("--registry={}", registry.url()),
+            "--vendor_dir=vendor",
+        ])
+        .assert()
+        .success()
+        .stdout(format!(
+            "Vendored @@a+1.0 into {}\nVendored @@b+1.0 into {}\n",
+            vendor.join("a+1.0").display(),
+            vendor.join("b+1.0").display(),
+        ));
+
+    let mut entries: Vec<_> = std::fs::read_dir(&vendor)?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<Result<_, _>>()?;
+    entries.sort();
+    assert_eq!(entries, ["a+1.0", "b+1.0"]);
+    let mode = |path: &str| -> std::io::Result<u32> {
+        Ok(std::fs::metadata(vendor.join(path))?.permissions().mode() & 0o777)
+    };
+    assert_eq!(mode("b+1.0/tool/run.sh")?, 0o755);
+    assert_eq!(mode("b+1.0/tool/data.txt")?, 0o644);
+
+    Ok(())
+}
@@ -1,4 +1,7 @@
+mod common;
+
 use assert_cmd::Command;
+use common::TestWorkspace;
 use predicates::prelude::*; // Used for writing assertions
 
 #[test]
@@ -52,3 +55,1031 @@ fn test_query_dots() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+#[test]
+fn test_query_subpackages_pattern() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir("examples/basic");
+
+    cmd.arg("query").arg("//nested/...");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("//nested:hello_world_nested"))
+        .stdout(predicate::str::contains("//:hello_world\n").not());
+
+    Ok(())
+}
+
+#[test]
+fn test_query_relative_to_working_directory() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir("examples/basic/nested");
+
+    cmd.arg("query").arg(":all");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("//nested:hello_world_nested"))
+        .stdout(predicate::str::contains("//:hello_world\n").not());
+
+    Ok(())
+}
+
+#[test]
+fn test_query_without_keeping_state() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir("examples/basic");
+
+    cmd.arg("--keep_state_after_build=false")
+        .arg("query")
+        .arg("//...");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("//nested:hello_world_nested"));
+
+    Ok(())
+}
+
+#[test]
+fn test_query_profile() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = assert_fs::TempDir::new()?;
+    let profile = tmp.path().join("profile.json");
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.current_dir("examples/basic");
+
+    cmd.arg(format!("--profile={}", profile.display()))
+        .arg("query")
+        .arg("//...");
+    cmd.assert().success();
+
+    let trace: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&profile)?)?;
+    let events = trace["traceEvents"].as_array().unwrap();
+    assert!(
+        events
+            .iter()
+            .any(|e| e["name"] == "load package" && e["args"]["package"] == "@@//nested"),
+        "{trace}"
+    );
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("razel"));
+    cmd.arg("analyze-profile").arg(&profile);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("=== CRITICAL PATH"))
+        .stdout(predicate::str::contains("load package ("));
+
+    Ok(())
+}
+
+/// A workspace where `//:a` depends on `//:b`, which depends on `//lib:c`, and `//:d` also depends on `//:b`.
+fn graph_workspace() -> TestWorkspace {
+    let workspace = TestWorkspace::new("graph");
+    workspace
+        .write(
+            "BUILD.bazel",
+            r#"genrule(name = "a", srcs = [":b", "a.txt"], outs = ["a.out"], cmd = "")
+genrule(name = "b", srcs = ["//lib:c"], outs = ["b.out"], cmd = "")
+filegroup(name = "d", srcs = [":b"])
+"#,
+        )
+        .write(
+            "lib/BUILD.bazel",
+            "cc_library(name = \"c\", srcs = [\"c.cc\"])\n",
+        );
+    workspace
+}
+
+fn query_lines(workspace: &TestWorkspace, query: &str) -> Vec<String> {
+    let outcome = workspace.run(&["query", query]);
+    assert_eq!(outcome.code, Some(0), "{}", outcome.snapshot());
+    outcome.stdout.lines().map(str::to_string).collect()
+}
+
+#[test]
+fn test_query_deps() {
+    let workspace = graph_workspace();
+    assert_eq!(
+        query_lines(&workspace, "deps(//:a)"),
+        [
+            "@@//:a",
+            "@@//:a.txt",
+            "@@//:b",
+            "@@//lib:c",
+            "@@//lib:c.cc"
+        ]
+    );
+    assert_eq!(
+        query_lines(&workspace, "deps(//:a, 1)"),
+        ["@@//:a", "@@//:a.txt", "@@//:b"]
+    );
+}
+
+#[test]
+fn test_query_deps_through_output_files() {
+    let workspace = TestWorkspace::new("outputs");
+    workspace.write(
+        "BUILD.bazel",
+        r#"genrule(name = "a", srcs = ["in.txt"], outs = ["a.txt"], cmd = "")
+genrule(name = "b", srcs = ["a.txt"], outs = ["b.txt"], cmd = "")
+"#,
+    );
+    assert_eq!(
+        query_lines(&workspace, "deps(//:b)"),
+        ["@@//:a", "@@//:a.txt", "@@//:b", "@@//:in.txt"]
+    );
+    assert_eq!(
+        query_lines(&workspace, "deps(//:b.txt, 1)"),
+        ["@@//:b", "@@//:b.txt"]
+    );
+    assert_eq!(
+        query_lines(&workspace, "somepath(//:b, //:in.txt)"),
+        ["@@//:a", "@@//:a.txt", "@@//:b", "@@//:in.txt"]
+    );
+    let mut rdeps = query_lines(&workspace, "rdeps(//..., //:in.txt)");
+    rdeps.sort();
+    assert_eq!(rdeps, ["@@//:a", "@@//:a.txt", "@@//:b", "@@//:in.txt"]);
+}
+
+#[test]
+fn test_query_rdeps() {
+    let workspace = graph_workspace();
+    let mut rdeps = query_lines(&workspace, "rdeps(//..., //lib:c)");
+    rdeps.sort();
+    assert_eq!(rdeps, ["@@//:a", "@@//:b", "@@//:d", "@@//lib:c"]);
+
+    let mut rdeps = query_lines(&workspace, "rdeps(//:a, //lib:c, 1)");
+    rdeps.sort();
+    assert_eq!(rdeps, ["@@//:b", "@@//lib:c"]);
+}
+
+#[test]
+fn test_query_package_functions() {
+    let workspace = graph_workspace();
+    let siblings = query_lines(&workspace, "siblings(//:b)");
+    for target in ["@@//:a", "@@//:a.txt", "@@//:b", "@@//:d"] {
+        assert!(siblings.iter().any(|s| s == target), "{siblings:?}");
+    }
+    assert!(
+        !siblings.iter().any(|s| s.starts_with("@@//lib")),
+        "{siblings:?}"
+    );
+
+    // The output file //:b.out depends on the rule that generates it.
+    let mut rdeps = query_lines(&workspace, "same_pkg_direct_rdeps(//:b)");
+    rdeps.sort();
+    assert_eq!(rdeps, ["@@//:a", "@@//:b.out", "@@//:d"]);
+    assert_eq!(
+        query_lines(&workspace, "same_pkg_direct_rdeps(//:a.txt)"),
+        ["@@//:a"]
+    );
+    // //:b depends on //lib:c, but from another package.
+    assert!(query_lines(&workspace, "same_pkg_direct_rdeps(//lib:c)").is_empty());
+}
+
+#[test]
+fn test_query_visible() {
+    let workspace = TestWorkspace::new("visible");
+    workspace
+        .write(
+            "lib/BUILD.bazel",
+            r#"cc_library(name = "public", visibility = ["//visibility:public"])
+cc_library(name = "private")
+cc_library(name = "app_only", visibility = ["//app:__pkg__"])
+cc_library(name = "app_tree", visibility = ["//app:__subpackages__"])
+"#,
+        )
+        .write("app/BUILD.bazel", "filegroup(name = \"app\")\n")
+        .write("app/sub/BUILD.bazel", "filegroup(name = \"sub\")\n");
+
+    assert_eq!(
+        query_lines(&workspace, "visible(//app:app, //lib:all)"),
+        ["@@//lib:app_only", "@@//lib:app_tree", "@@//lib:public"]
+    );
+    assert_eq!(
+        query_lines(&workspace, "visible(//app/sub:sub, //lib:all)"),
+        ["@@//lib:app_tree", "@@//lib:public"]
+    );
+    assert_eq!(
+        query_lines(&workspace, "visible(//app/...:all, //lib:all)"),
+        ["@@//lib:app_tree", "@@//lib:public"]
+    );
+    assert_eq!(
+        query_lines(&workspace, "visible(//lib:private, //lib:all)").len(),
+        4
+    );
+}
+
+#[test]
+fn test_query_paths() {
+    let workspace = graph_workspace();
+    assert_eq!(
+        query_lines(&workspace, "somepath(//:a, //lib:c.cc)"),
+        ["@@//:a", "@@//:b", "@@//lib:c", "@@//lib:c.cc"]
+    );
+    assert_eq!(
+        query_lines(&workspace, "somepath(//:a + //:d, //:b)"),
+        ["@@//:a", "@@//:b"]
+    );
+    assert!(query_lines(&workspace, "somepath(//:d, //:a.txt)").is_empty());
+    assert_eq!(
+        query_lines(&workspace, "allpaths(//:a + //:d, //lib:c)"),
+        ["@@//:a", "@@//:b", "@@//:d", "@@//lib:c"]
+    );
+    assert!(query_lines(&workspace, "allpaths(//lib:c, //:a)").is_empty());
+}
+
+#[test]
+fn test_query_output_graph() {
+    let workspace = graph_workspace();
+    let outcome = workspace.run(&["query", "--output=graph", "deps(//:a + //:d)"]);
+    assert_eq!(outcome.code, Some(0), "{}", outcome.snapshot());
+    assert!(outcome.stdout.starts_with("digraph mygraph {\n"));
+    for edge in [
+        "\"@@//:a\" -> \"@@//:b\"",
+        "\"@@//:a\" -> \"@@//:a.txt\"",
+        "\"@@//:d\" -> \"@@//:b\"",
+        "\"@@//:b\" -> \"@@//lib:c\"",
+        "\"@@//lib:c\" -> \"@@//lib:c.cc\"",
+    ] {
+        assert!(outcome.stdout.contains(edge), "{edge}\n{}", outcome.stdout);
+    }
+    assert!(!outcome.stdout.contains("\"@@//:a\" -> \"@@//lib:c\""));
+}
+
+#[test]
+fn test_query_output_streamed_jsonproto() {
+    let workspace = graph_workspace();
+    let outcome = workspace.run(&[
+        "query",
+        "--output=streamed_jsonproto",
+        "//:a + //:a.out + //:a.txt",
+    ]);
+    assert_eq!(outcome.code, Some(0), "{}", outcome.snapshot());
+    let targets: Vec<serde_json::Value> = outcome
+        .stdout
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(targets.len(), 3, "{}", outcome.stdout);
+
+    let rule = &targets[0]["rule"];
+    assert_eq!(targets[0]["type"], "RULE");
+    assert_eq!(rule["name"], "@@//:a");
+    assert_eq!(rule["ruleClass"], "genrule");
+    assert_eq!(
+        rule["ruleInput"],
+        serde_json::json!(["@@//:b", "@@//:a.txt"])
+    );
+    assert_eq!(rule["ruleOutput"], serde_json::json!(["@@//:a.out"]));
+    let srcs = rule["attribute"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|attr| attr["name"] == "srcs")
+        .unwrap();
+    assert_eq!(srcs["type"], "LABEL_LIST");
+    assert_eq!(
+        srcs["stringListValue"],
+        serde_json::json!(["@@//:b", "@@//:a.txt"])
+    );
+    assert_eq!(rule["location"], "$WORKSPACE/BUILD.bazel:1:1");
+
+    assert_eq!(targets[1]["type"], "GENERATED_FILE");
+    assert_eq!(targets[1]["generatedFile"]["generatingRule"], "@@//:a");
+    assert_eq!(
+        targets[1]["generatedFile"]["location"],
+        "$WORKSPACE/BUILD.bazel:1:1"
+    );
+    assert_eq!(targets[2]["type"], "SOURCE_FILE");
+    assert_eq!(targets[2]["sourceFile"]["name"], "@@//:a.txt");
+    assert_eq!(targets[2]["sourceFile"]["location"], "$WORKSPACE/a.txt:1:1");
+}
+
+#[test]
+fn test_query_output_xml() {
+    let workspace = graph_workspace();
+    let outcome = workspace.run(&["query", "--output=xml", "//:b + //lib:c.cc"]);
+    assert_eq!(outcome.code, Some(0), "{}", outcome.snapshot());
+    for line in [
+        "    <rule class=\"genrule\" location=\"$WORKSPACE/BUILD.bazel:2:1\" name=\"@@//:b\">\n",
+        "            <output value=\"@@//:b.out\"/>\n",
+        "            <label value=\"@@//lib:c\"/>\n",
+        "    <source-file location=\"$WORKSPACE/lib/c.cc:1:1\" name=\"@@//lib:c.cc\"/>\n",
+    ] {
+        assert!(outcome.stdout.contains(line), "{line}\n{}", outcome.stdout);
+    }
+}
+
+#[test]
+fn test_query_output_build() {
+    let workspace = graph_workspace();
+    let outcome = workspace.run(&["query", "--output=build", "//:a + //:a.txt"]);
+    assert_eq!(outcome.code, Some(0), "{}", outcome.snapshot());
+    assert_eq!(
+        outcome.stdout,
+        r#"# $WORKSPACE/BUILD.bazel:1:1
+genrule(
+  name = "a",
+  cmd = "",
+  outs = ["@@//:a.out"],
+  srcs = ["@@//:b", "@@//:a.txt"],
+)
+
+"#
+    );
+}
+
+#[test]
+fn test_query_output_label_kind_and_location() {
+    let workspace = graph_workspace();
+    assert_eq!(
+        workspace
+            .run(&[
+                "query",
+                "--output=label_kind",
+                "//:b + //:b.out + //lib:c.cc"
+            ])
+            .stdout,
+        "genrule rule @@//:b\ngenerated file @@//:b.out\nsource file @@//lib:c.cc\n"
+    );
+
+    let outcome = workspace.run(&["query", "--output=location", "//:b + //lib:c + //lib:c.cc"]);
+    assert_eq!(outcome.code, Some(0), "{}", outcome.snapshot());
+    assert_eq!(
+        outcome.stdout,
+        "$WORKSPACE/BUILD.bazel:2:1: genrule rule @@//:b\n\
+         $WORKSPACE/lib/BUILD.bazel:1:1: cc_library rule @@//lib:c\n\
+         $WORKSPACE/lib/c.cc:1:1: source file @@//lib:c.cc\n"
+    );
+}
+
+#[test]
+fn test_query_set_operators_and_variables() {
+    let workspace = graph_workspace();
+    // Set operators associate to the left, with equal precedence.
+    assert_eq!(
+        query_lines(&workspace, "//:a + //:b ^ deps(//:d)"),
+        ["@@//:b"]
+    );
+    assert_eq!(
+        query_lines(&workspace, "deps(//:a) intersect deps(//:d) except //:b"),
+        ["@@//lib:c", "@@//lib:c.cc"]
+    );
+    assert_eq!(
+        query_lines(&workspace, "let x = deps(//:d) in $x - set(//:d //lib:c)"),
+        ["@@//:b", "@@//lib:c.cc"]
+    );
+    assert_eq!(
+        query_lines(&workspace, "set(//:b '//lib:c') union //:b"),
+        ["@@//:b", "@@//lib:c"]
+    );
+}
+
+#[test]
+fn test_query_kind_and_filter() {
+    let workspace = graph_workspace();
+    assert_eq!(
+        query_lines(&workspace, "kind(cc_library, deps(//:a))"),
+        ["@@//lib:c"]
+    );
+    assert_eq!(
+        query_lines(&workspace, "kind('source file', deps(//:a))"),
+        ["@@//:a.txt", "@@//lib:c.cc"]
+    );
+    assert_eq!(
+        query_lines(&workspace, "filter('\\.txt$', deps(//:a))"),
+        ["@@//:a.txt"]
+    );
+    assert_eq!(
+        query_lines(&workspace, "deps(//:a) except kind(rule, deps(//:a))"),
+        ["@@//:a.txt", "@@//lib:c.cc"]
+    );
+    assert_eq!(
+        query_lines(&workspace, "//... ^ deps(//:b)"),
+        ["@@//:b", "@@//lib:c"]
+    );
+}
+
+#[test]
+fn test_query_infer_universe_scope() {
+    let workspace = graph_workspace();
+    workspace.write("broken/BUILD.bazel", "fail(\"not loaded\")\n");
+
+    // The whole workspace is the default universe, and loading it fails.
+    let outcome = workspace.run(&["query", "allrdeps(//lib:c)"]);
+    assert_eq!(outcome.code, Some(7), "{}", outcome.snapshot());
+    assert!(
+        outcome.stderr.contains("@@//broken"),
+        "{}",
+        outcome.snapshot()
+    );
+
+    let outcome = workspace.run(&[
+        "query",
+        "--infer_universe_scope",
+        "allrdeps(//lib:c) ^ //:all",
+    ]);
+    assert_eq!(outcome.code, Some(0), "{}", outcome.snapshot());
+    assert_eq!(outcome.stdout, "@@//:a\n@@//:b\n@@//:d\n");
+
+    let outcome = workspace.run(&[
+        "query",
+        "--universe_scope=//:a,//lib:all",
+        "allrdeps(//lib:c, 1)",
+    ]);
+    assert_eq!(outcome.code, Some(0), "{}", outcome.snapshot());
+    assert_eq!(outcome.stdout, "@@//:b\n@@//lib:c\n");
+
+    // Targets excluded from the universe are only searched if something left in it depends on them.
+    let outcome = workspace.run(&[
+        "query",
+        "--universe_scope=//:all,//lib:all,-//:d",
+        "allrdeps(//lib:c)",
+    ]);
+    assert_eq!(outcome.code, Some(0), "{}", outcome.snapshot());
+    assert_eq!(outcome.stdout, "@@//:a\n@@//:b\n@@//lib:c\n");
+}
+
+/// A workspace with a test, whose root BUILD file loads `//defs:names.bzl`, which loads `//defs:common.bzl`.
+fn loading_workspace() -> TestWorkspace {
+    let workspace = TestWorkspace::new("loading");
+    workspace
+        .write(
+            "BUILD.bazel",
+            r#"load("//defs:names.bzl", "NAME")
+sh_binary(name = "tool", srcs = ["tool.sh"], data = [":config"])
+filegroup(name = "config", srcs = ["config.json"])
+sh_test(name = "tool_test", srcs = ["tool_test.sh"], data = [":tool"], size = "small")
+"#,
+        )
+        .write(
+            "lib/BUILD.bazel",
+            r#"cc_library(name = "c", srcs = ["c.cc"])
+config_setting(name = "opt", values = {"compilation_mode": "opt"}, define_values = {"mode": "fast"})
+config_setting_group(name = "opt_or_dbg", match_any = [":opt", ":dbg"])
+"#,
+        )
+        .write("defs/BUILD.bazel", "")
+        .write(
+            "defs/names.bzl",
+            "load(\":common.bzl\", \"PREFIX\")\nNAME = PREFIX + \"name\"\n",
+        )
+        .write("defs/common.bzl", "PREFIX = \"common_\"\n");
+    workspace
+}
+
+#[test]
+fn test_query_attr_labels_and_tests() {
+    let workspace = loading_workspace();
+    assert_eq!(
+        query_lines(&workspace, "attr(size, small, //...)"),
+        ["@@//:tool_test"]
+    );
+    assert_eq!(
+        query_lines(&workspace, "attr(name, '^tool', //:all)"),
+        ["@@//:tool", "@@//:tool_test"]
+    );
+    assert_eq!(
+        query_lines(&workspace, "labels(data, //:tool_test)"),
+        ["@@//:tool"]
+    );
+    assert_eq!(
+        query_lines(&workspace, "labels(srcs, //:all)"),
+        ["@@//:config.json", "@@//:tool.sh", "@@//:tool_test.sh"]
+    );
+    assert_eq!(query_lines(&workspace, "tests(//...)"), ["@@//:tool_test"]);
+    assert_eq!(
+        query_lines(
+            &workspace,
+            "attr(values, 'compilation_mode=opt', //lib:all)"
+        ),
+        ["@@//lib:opt"]
+    );
+    assert_eq!(
+        query_lines(&workspace, "kind(config_setting, //lib:all)"),
+        ["@@//lib:opt", "@@//lib:opt_or_dbg"]
+    );
+}
+
+#[test]
+fn test_query_buildfiles() {
+    let workspace = loading_workspace();
+    assert_eq!(
+        query_lines(&workspace, "buildfiles(//:tool + //lib:c)"),
+        [
+            "@@//:BUILD.bazel",
+            "@@//defs:common.bzl",
+            "@@//defs:names.bzl",
+            "@@//lib:BUILD.bazel"
+        ]
+    );
+    assert_eq!(
+        query_lines(&workspace, "rbuildfiles(defs/common.bzl)"),
+        ["@@//:BUILD.bazel"]
+    );
+    assert_eq!(
+        query_lines(&workspace, "rbuildfiles(lib/BUILD.bazel)"),
+        ["@@//lib:BUILD.bazel"]
+    );
+}
+
+#[test]
+fn test_query_strict_repo_visibility() {
+    let workspace = TestWorkspace::new("app");
+    workspace
+        .write(
+            "MODULE.bazel",
+            "module(name = \"app\", version = \"1.0\")\n\
+             bazel_dep(name = \"dep\", version = \"1.0\")\n",
+        )
+        .write("BUILD.bazel", "filegroup(name = \"a\")\n")
+        .write(
+            "vendor/dep+1.0/MODULE.bazel",
+            "module(name = \"dep\", version = \"1.0\")\n\
+             bazel_dep(name = \"lib\", version = \"1.0\")\n",
+        )
+        .write("vendor/dep+1.0/BUILD.bazel", "")
+        .write(
+            "vendor/lib+1.0/MODULE.bazel",
+            "module(name = \"lib\", version = \"1.0\")\n",
+        )
+        .write("vendor/lib+1.0/BUILD.bazel", "filegroup(name = \"x\")\n");
+
+    // A module sees itself under its own name.
+    let outcome = workspace.run(&["query", "--vendor_dir=vendor", "@app//:a"]);
+    assert_eq!(outcome.stdout, "@@//:a\n", "{}", outcome.snapshot());
+
+    // lib is only a dependency of dep, so the main repository can't see it.
+    let outcome = workspace.run(&["query", "--vendor_dir=vendor", "@lib//:x"]);
+    assert_ne!(outcome.code, Some(0), "{}", outcome.snapshot());
+    assert!(
+        outcome
+            .stderr
+            .contains("No repository visible as @lib from the main repository"),
+        "{}",
+        outcome.snapshot()
+    );
+
+    let outcome = workspace.run(&[
+        "query",
+        "--vendor_dir=vendor",
+        "--nostrict_repo_visibility",
+        "@lib//:x",
+    ]);
+    assert_eq!(outcome.stdout, "@@lib+1.0//:x\n", "{}", outcome.snapshot());
+    assert!(
+        outcome.stderr.contains(
+            "WARNING: the main repository refers to @lib, which it doesn't declare with bazel_dep"
+        ),
+        "{}",
+        outcome.snapshot()
+    );
+}
+
+#[test]
+fn test_query_extension_repos() {
+    let workspace = TestWorkspace::new("app");
+    workspace
+        .write(
+            "MODULE.bazel",
+            "module(name = \"app\", version = \"1.0\")\n\
+             bazel_dep(name = \"dep\", version = \"1.0\")\n\
+             tools = use_extension(\"//:extensions.bzl\", \"tools\")\n\
+             use_repo(tools, \"compiler\", linker = \"ld\")\n\
+             deps = use_extension(\"@dep//:extensions.bzl\", \"deps\")\n\
+             use_repo(deps, \"zlib\")\n",
+        )
+        .write("BUILD.bazel", "")
+        .write(
+            "vendor/dep+1.0/MODULE.bazel",
+            "module(name = \"dep\", version = \"1.0\")\n",
+        )
+        .write("vendor/dep+1.0/BUILD.bazel", "")
+        .write(
+            "vendor/+tools+compiler/BUILD.bazel",
+            "filegroup(name = \"cc\")\n",
+        )
+        .write("vendor/+tools+ld/BUILD.bazel", "filegroup(name = \"ld\")\n")
+        .write(
+            "vendor/dep+1.0+deps+zlib/BUILD.bazel",
+            "filegroup(name = \"z\")\n",
+        );
+
+    for (label, canonical) in [
+        ("@compiler//:cc", "@@+tools+compiler//:cc"),
+        ("@linker//:ld", "@@+tools+ld//:ld"),
+        ("@zlib//:z", "@@dep+1.0+deps+zlib//:z"),
+    ] {
+        let outcome = workspace.run(&["query", "--vendor_dir=vendor", label]);
+        assert_eq!(
+            outcome.stdout,
+            format!("{canonical}\n"),
+            "{}",
+            outcome.snapshot()
+        );
+    }
+
+    // Only the repositories use_repo imports are visible.
+    let outcome = workspace.run(&["query", "--vendor_dir=vendor", "@ld//:ld"]);
+    assert!(
+        outcome
+            .stderr
+            .contains("No repository visible as @ld from the main repository"),
+        "{}",
+        outcome.snapshot()
+    );
+}
+
+#[test]
+fn test_query_file() {
+    let workspace = graph_workspace();
+    workspace.write("query.txt", "deps(//:b, 1)\n  - //lib:c\n");
+    let outcome = workspace.run(&["query", "--query_file=query.txt"]);
+    assert_eq!(outcome.code, Some(0), "{}", outcome.snapshot());
+    assert_eq!(outcome.stdout, "@@//:b\n");
+
+    let mut cmd = workspace.razel();
+    cmd.args(["query", "--query_file=-"])
+        .write_stdin("kind(cc_library, //...)");
+    cmd.assert().success().stdout("@@//lib:c\n");
+
+    let outcome = workspace.run(&["query", "--query_file=missing.txt"]);
+    assert_eq!(outcome.code, Some(2), "{}", outcome.snapshot());
+    assert!(
+        outcome.stderr.contains("missing.txt"),
+        "{}",
+        outcome.snapshot()
+    );
+}
+
+#[test]
+fn test_query_check_determinism() {
+    let workspace = graph_workspace();
+    let outcome = workspace.run(&[
+        "query",
+        "--experimental_check_determinism",
+        "--output=build",
+        "deps(//:a)",
+    ]);
+    assert_eq!(outcome.code, Some(0), "{}", outcome.snapshot());
+    assert_eq!(
+        outcome.stdout,
+        workspace
+            .run(&["query", "--output=build", "deps(//:a)"])
+            .stdout
+    );
+
+    // Only query compares its output.
+    let outcome = workspace.run(&["cquery", "--experimental_check_determinism", "deps(//:a)"]);
+    assert_eq!(outcome.code, Some(2), "{}", outcome.snapshot());
+}
+
+#[test]
+fn test_query_tool_deps() {
+    let workspace = TestWorkspace::new("tool_deps");
+    workspace.write(
+        "BUILD.bazel",
+        r#"genrule(name = "gen", srcs = ["in.txt"], tools = [":tool"], outs = ["out"], cmd = "", visibility = ["//visibility:public"])
+sh_binary(name = "tool", srcs = ["tool.sh"])
+"#,
+    );
+    let mut deps = query_lines(&workspace, "deps(//:gen)");
+    deps.sort();
+    assert_eq!(
+        deps,
+        ["@@//:gen", "@@//:in.txt", "@@//:tool", "@@//:tool.sh"]
+    );
+
+    let outcome = workspace.run(&["query", "--notool_deps", "deps(//:gen)"]);
+    assert_eq!(outcome.code, Some(0), "{}", outcome.snapshot());
+    assert_eq!(outcome.stdout, "@@//:gen\n@@//:in.txt\n");
+
+    let outcome = workspace.run(&["query", "--notool_deps", "rdeps(//..., //:tool.sh)"]);
+    assert_eq!(outcome.code, Some(0), "{}", outcome.snapshot());
+    let mut rdeps: Vec<_> = outcome.stdout.lines().collect();
+    rdeps.sort();
+    assert_eq!(rdeps, ["@@//:tool", "@@//:tool.sh"]);
+}
+
+#[test]
+fn test_query_keep_going() {
+    let workspace = graph_workspace();
+    workspace.write("broken/BUILD.bazel", "fail(\"not loaded\")\n");
+
+    let outcome = workspace.run(&["query", "//..."]);
+    assert_eq!(outcome.code, Some(7), "{}", outcome.snapshot());
+
+    // The targets of the packages that load are still printed, with an exit code saying they're not all of them.
+    let outcome = workspace.run(&["query", "--keep_going", "kind(rule, //...)"]);
+    assert_eq!(outcome.code, Some(3), "{}", outcome.snapshot());
+    let mut labels: Vec<_> = outcome.stdout.lines().collect();
+    labels.sort();
+    assert_eq!(labels, ["@@//:a", "@@//:b", "@@//:d", "@@//lib:c"]);
+    assert!(
+        outcome.stderr.contains("not loaded"),
+        "{}",
+        outcome.snapshot()
+    );
+    assert!(outcome.stderr.contains("partial"), "{}", outcome.snapshot());
+
+    let outcome = workspace.run(&["query", "-k", "allrdeps(//lib:c, 1)"]);
+    assert_eq!(outcome.code, Some(3), "{}", outcome.snapshot());
+    assert_eq!(outcome.stdout, "@@//:b\n@@//lib:c\n");
+
+    // Targets of a broken package that others depend on are left out, and the package is listed at the end.
+    workspace.write(
+        "uses/BUILD.bazel",
+        "filegroup(name = \"e\", srcs = [\"//broken:x\", \"//lib:c\"])\n",
+    );
+    for order in ["--order_output=no", "--order_output=auto"] {
+        let outcome = workspace.run(&["query", "-k", order, "deps(//uses:e)"]);
+        assert_eq!(outcome.code, Some(3), "{}", outcome.snapshot());
+        let mut labels: Vec<_> = outcome.stdout.lines().collect();
+        labels.sort();
+        assert_eq!(labels, ["@@//lib:c", "@@//lib:c.cc", "@@//uses:e"]);
+        assert!(
+            outcome
+                .stderr
+                .contains("missing from the result, as they failed to load:\n  @@//broken\n"),
+            "{}",
+            outcome.snapshot()
+        );
+    }
+}
+
+#[test]
+fn test_query_order_output() {
+    let workspace = graph_workspace();
+    // By default, results are sorted by label.
+    assert_eq!(
+        query_lines(&workspace, "deps(//:d) + deps(//:a)"),
+        [
+            "@@//:a",
+            "@@//:a.txt",
+            "@@//:b",
+            "@@//:d",
+            "@@//lib:c",
+            "@@//lib:c.cc"
+        ]
+    );
+
+    // With full, each target comes before its dependencies.
+    let outcome = workspace.run(&["query", "--order_output=full", "deps(//:d) + deps(//:a)"]);
+    assert_eq!(outcome.code, Some(0), "{}", outcome.snapshot());
+    assert_eq!(
+        outcome.stdout,
+        "@@//:a\n@@//:a.txt\n@@//:d\n@@//:b\n@@//lib:c\n@@//lib:c.cc\n"
+    );
+
+    let outcome = workspace.run(&["query", "--order_output=deps", "//lib:c + //:b"]);
+    assert_eq!(outcome.code, Some(0), "{}", outcome.snapshot());
+    assert_eq!(outcome.stdout, "@@//:b\n@@//lib:c\n");
+
+    let outcome = workspace.run(&["query", "--order_output=no", "//lib:c + //:b"]);
+    assert_eq!(outcome.code, Some(0), "{}", outcome.snapshot());
+    assert_eq!(outcome.stdout, "@@//lib:c\n@@//:b\n");
+}
+
+#[test]
+fn test_cquery_toolchains() {
+    let workspace = TestWorkspace::new("toolchains");
+    workspace
+        .write(
+            "MODULE.bazel",
+            r#"module(name = "toolchains")
+register_execution_platforms("//:linux")
+register_toolchains("//:all")
+"#,
+        )
+        .write(
+            "BUILD.bazel",
+            r#"constraint_setting(name = "os")
+constraint_value(name = "linux_os", constraint_setting = ":os")
+constraint_value(name = "windows_os", constraint_setting = ":os")
+platform(name = "linux", constraint_values = [":linux_os"])
+platform(name = "windows", constraint_values = [":windows_os"])
+toolchain(
+    name = "cc_windows",
+    toolchain_type = "@bazel_tools//tools/cpp:toolchain_type",
+    target_compatible_with = [":windows_os"],
+    toolchain = ":cc_windows_impl",
+)
+toolchain(
+    name = "cc_linux",
+    toolchain_type = "@bazel_tools//tools/cpp:toolchain_type",
+    exec_compatible_with = [":linux_os"],
+    target_compatible_with = [":linux_os"],
+    toolchain = ":cc_linux_impl",
+)
+filegroup(name = "cc_windows_impl")
+filegroup(name = "cc_linux_impl")
+cc_library(name = "c", srcs = ["c.cc"])
+"#,
+        );
+
+    let outcome = workspace.run(&[
+        "cquery",
+        "--output=toolchains",
+        "--platforms=//:windows",
+        "//:c",
+    ]);
+    assert_eq!(outcome.code, Some(0), "{}", outcome.snapshot());
+    assert!(
+        outcome.stdout.contains(
+            "  target platform: @@//:windows\n  execution platform: @@//:linux\n  \
+             @bazel_tools//tools/cpp:toolchain_type: @@//:cc_windows_impl (from @@//:cc_windows)\n"
+        ),
+        "{}",
+        outcome.snapshot()
+    );
+
+    let outcome = workspace.run(&[
+        "cquery",
+        "--output=toolchains",
+        "--platforms=//:linux",
+        "//:c",
+    ]);
+    assert_eq!(outcome.code, Some(0), "{}", outcome.snapshot());
+    assert!(
+        outcome
+            .stdout
+            .contains("@@//:cc_linux_impl (from @@//:cc_linux)"),
+        "{}",
+        outcome.snapshot()
+    );
+
+    // The host platform has neither toolchain's constraints, and the failure says why.
+    let outcome = workspace.run(&["cquery", "--output=toolchains", "//:c"]);
+    assert_eq!(outcome.code, Some(7), "{}", outcome.snapshot());
+    assert!(
+        outcome.stdout.contains(
+            "toolchain resolution failed: no registered toolchain of type @bazel_tools//tools/cpp:toolchain_type"
+        ),
+        "{}",
+        outcome.snapshot()
+    );
+}
+
+#[test]
+fn test_cquery_starlark_output() {
+    let workspace = graph_workspace();
+    let outcome = workspace.run(&[
+        "cquery",
+        "--output=starlark",
+        "--starlark:expr=target.label + ' ' + target.kind + ' ' + str(hasattr(target, 'attr'))",
+        "//lib:c + //:a.txt",
+    ]);
+    assert_eq!(outcome.code, Some(0), "{}", outcome.snapshot());
+    assert_eq!(
+        outcome.stdout,
+        "@@//:a.txt source file False\n@@//lib:c cc_library rule True\n"
+    );
+
+    let outcome = workspace.run(&[
+        "cquery",
+        "--output=starlark",
+        "--starlark:expr=', '.join(target.attr.srcs)",
+        "//:a",
+    ]);
+    assert_eq!(outcome.code, Some(0), "{}", outcome.snapshot());
+    assert_eq!(outcome.stdout, ":b, a.txt\n");
+
+    let outcome = workspace.run(&[
+        "cquery",
+        "--output=starlark",
+        "--starlark:expr=target.nope",
+        "//:a",
+    ]);
+    assert_eq!(outcome.code, Some(7), "{}", outcome.snapshot());
+}
+
+#[test]
+fn test_aquery_filters() {
+    let workspace = graph_workspace();
+    let actions = |query: &str| -> Vec<String> {
+        let outcome = workspace.run(&["aquery", query]);
+        assert_eq!(outcome.code, Some(0), "{}", outcome.snapshot());
+        outcome
+            .stdout
+            .lines()
+            .filter(|line| line.starts_with("action "))
+            .map(str::to_string)
+            .collect()
+    };
+
+    assert_eq!(
+        actions("mnemonic(Genrule, //...)"),
+        [
+            "action 'Executing genrule @@//:a'",
+            "action 'Executing genrule @@//:b'"
+        ]
+    );
+    assert_eq!(
+        actions("outputs('.*/b\\.out', //...)"),
+        ["action 'Executing genrule @@//:b'"]
+    );
+    assert_eq!(
+        actions("inputs('a\\.txt', //...)"),
+        ["action 'Executing genrule @@//:a'"]
+    );
+    assert!(actions("mnemonic(CppCompile, //...)").is_empty());
+
+    let outcome = workspace.run(&["aquery", "//:a"]);
+    assert!(
+        outcome
+            .stdout
+            .contains("  Mnemonic: Genrule\n  Target: @@//:a\n"),
+        "{}",
+        outcome.snapshot()
+    );
+    assert!(
+        outcome.stdout.contains("/bin/b.out, a.txt]\n"),
+        "{}",
+        outcome.snapshot()
+    );
+
+    let outcome = workspace.run(&["aquery", "mnemonic(Genrule)"]);
+    assert_eq!(outcome.code, Some(7), "{}", outcome.snapshot());
+}
+
+#[test]
+fn test_aquery_starlark_output() {
+    let workspace = graph_workspace();
+    let outcome = workspace.run(&["aquery", "--output=starlark", "//..."]);
+    assert_eq!(outcome.code, Some(0), "{}", outcome.snapshot());
+    assert_eq!(outcome.stdout, "Genrule @@//:a\nGenrule @@//:b\n");
+
+    let outcome = workspace.run(&[
+        "aquery",
+        "--output=starlark",
+        "--starlark:expr=[o.split('/')[-1] for o in action.outputs]",
+        "//:a",
+    ]);
+    assert_eq!(outcome.code, Some(0), "{}", outcome.snapshot());
+    assert_eq!(outcome.stdout, "[\"a.out\"]\n");
+
+    let outcome = workspace.run(&[
+        "aquery",
+        "--output=starlark",
+        "--starlark:expr=(lambda: action)()",
+        "//:a",
+    ]);
+    assert_eq!(outcome.code, Some(2), "{}", outcome.snapshot());
+}
+
+#[test]
+fn test_query_stats() {
+    let workspace = graph_workspace();
+    let outcome = workspace.run(&["query", "--experimental_query_stats", "deps(//:a)"]);
+    assert_eq!(outcome.code, Some(0), "{}", outcome.snapshot());
+    for line in [
+        "  packages loaded: 2\n",
+        "  package cache hits: 3 of 5 (60.0%)\n",
+        "  targets visited: 5\n",
+    ] {
+        assert!(outcome.stderr.contains(line), "{}", outcome.snapshot());
+    }
+    assert!(
+        outcome.stderr.contains("  deps: 1 calls, "),
+        "{}",
+        outcome.snapshot()
+    );
+
+    let outcome = workspace.run(&["query", "deps(//:a)"]);
+    assert!(
+        !outcome.stderr.contains("Query statistics"),
+        "{}",
+        outcome.snapshot()
+    );
+}
+
+#[test]
+fn test_aquery_graph_output() {
+    let workspace = graph_workspace();
+    workspace.write("a.txt", "hello\n");
+    let outcome = workspace.run(&["aquery", "--output=graph", "//..."]);
+    assert_eq!(outcome.code, Some(0), "{}", outcome.snapshot());
+    assert_eq!(
+        outcome.stdout,
+        "digraph actions {\n  node [shape=box];\n  a0 [label=\"Genrule @@//:a\"]\n  \
+         a1 [label=\"Genrule @@//:b\"]\n  a1 -> a0 [label=\"bazel-out/k8-fastbuild/bin/b.out\"]\n}\n"
+    );
+
+    let outcome = workspace.run(&["aquery", "--output=json", "//..."]);
+    assert_eq!(outcome.code, Some(0), "{}", outcome.snapshot());
+    let graph: serde_json::Value = serde_json::from_str(&outcome.stdout).unwrap();
+    assert_eq!(graph["actions"][1]["owner"], "@@//:b");
+    let artifacts = graph["artifacts"].as_array().unwrap();
+    let artifact = |path: &str| {
+        artifacts
+            .iter()
+            .find(|a| a["path"] == path)
+            .unwrap_or_else(|| panic!("no artifact {path}: {}", outcome.stdout))
+    };
+    let b_out = artifact("bazel-out/k8-fastbuild/bin/b.out");
+    assert_eq!(b_out["producer"], 1);
+    assert_eq!(b_out["consumers"], serde_json::json!([0]));
+    assert_eq!(b_out["size"], serde_json::Value::Null);
+    let source = artifact("a.txt");
+    assert_eq!(source["producer"], serde_json::Value::Null);
+    assert_eq!(source["size"], 6);
+}
@@ -0,0 +1,42 @@
+mod common;
+
+use common::TestWorkspace;
+use predicates::prelude::*; // Used for writing assertions
+
+#[test]
+fn test_repl() -> Result<(), Box<dyn std::error::Error>> {
+    let workspace = TestWorkspace::new("repl");
+    workspace
+        .write("BUILD.bazel", "")
+        .write(
+            "pkg/defs.bzl",
+            "def double(x):\n    return x * 2\n\nGREETING = \"hi\"\n_PRIVATE = 1\n",
+        )
+        .write(
+            "pkg/BUILD.bazel",
+            "genrule(name = \"gen\", outs = [\"out\"], cmd = \"\")\n",
+        );
+    let mut cmd = workspace.razel();
+
+    cmd.arg("repl").write_stdin(
+        "1 + 2\n\
+         load(\"//pkg:defs.bzl\", \"double\")\n\
+         double(21)\n\
+         def triple(x):\n    return [x,\n        x, x]\n\n\
+         triple(\"a\")\n\
+         undefined\n\
+         :load //pkg:defs.bzl\n\
+         GREETING\n\
+         :package //pkg\n\
+         targets\n",
+    );
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "3\n42\n[\"a\", \"a\", \"a\"]\nLoaded //pkg:defs.bzl: GREETING, double\n\"hi\"\n\
+             //pkg:gen (genrule)\n{\"gen\": \"genrule\"}\n",
+        ))
+        .stderr(predicate::str::contains("Variable `undefined` not found"));
+
+    Ok(())
+}
@@ -0,0 +1,297 @@
+mod common;
+
+use common::TestWorkspace;
+use predicates::prelude::*; // Used for writing assertions
+use std::os::unix::fs::PermissionsExt as _;
+use std::time::{Duration, Instant};
+
+#[test]
+fn test_server_query() -> Result<(), Box<dyn std::error::Error>> {
+    let workspace = TestWorkspace::example("basic");
+    let razel = || workspace.razel();
+
+    // The first command starts the server, the second reuses its workspace.
+    for _ in 0..2 {
+        razel()
+            .arg("--batch=false")
+            .arg("query")
+            .arg("//...")
+            .assert()
+            .success()
+            .stdout(predicate::str::contains("//:hello_world"));
+    }
+    let port_file = workspace.output_base().join("server/command_port");
+    assert!(port_file.exists());
+    // Only the owner of the output base can read the cookie requests must carry.
+    let cookie = std::fs::metadata(workspace.output_base().join("server/request_cookie"))?;
+    assert_eq!(cookie.permissions().mode() & 0o777, 0o600);
+
+    // What the command writes to stderr reaches the client, not the server's log.
+    razel()
+        .arg("--batch=false")
+        .arg("query")
+        .arg("--experimental_query_stats")
+        .arg("//...")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Query statistics:"));
+
+    razel()
+        .arg("--batch=false")
+        .arg("query")
+        .arg("--no_such_flag")
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("--no_such_flag"));
+
+    // A request without the server's cookie is turned away.
+    let cookie_file = workspace.output_base().join("server/request_cookie");
+    let cookie = std::fs::read_to_string(&cookie_file)?;
+    std::fs::write(&cookie_file, "guessed")?;
+    razel()
+        .arg("--batch=false")
+        .arg("query")
+        .arg("//...")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("request cookie"));
+    std::fs::write(&cookie_file, cookie)?;
+
+    razel().arg("shutdown").assert().success();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while port_file.exists() && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    assert!(!port_file.exists(), "server still running");
+
+    Ok(())
+}
+
+#[test]
+fn test_server_dump() -> Result<(), Box<dyn std::error::Error>> {
+    let workspace = TestWorkspace::example("basic");
+    let razel = || workspace.razel();
+
+    razel()
+        .arg("--batch=false")
+        .arg("query")
+        .arg("//...")
+        .assert()
+        .success();
+    // The server still has what the query loaded.
+    razel()
+        .arg("--batch=false")
+        .arg("dump")
+        .arg("--packages")
+        .arg("--skyframe")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("  @@//\n    hello_world\n"))
+        .stdout(predicate::str::contains("  REPOSITORY:@@ [done]\n"))
+        .stdout(predicate::str::contains("  PACKAGE:@@// [done]\n"));
+
+    razel()
+        .arg("--batch=false")
+        .arg("dump")
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("Nothing to dump"));
+
+    // Nothing is kept from a command that doesn't keep its state.
+    razel()
+        .arg("--batch=false")
+        .arg("--keep_state_after_build=false")
+        .arg("query")
+        .arg("//...")
+        .assert()
+        .success();
+    razel()
+        .arg("--batch=false")
+        .arg("--keep_state_after_build=false")
+        .arg("dump")
+        .arg("--packages")
+        .assert()
+        .success()
+        .stdout("Packages (0):\n");
+
+    razel().arg("shutdown").assert().success();
+    Ok(())
+}
+
+#[test]
+fn test_server_query_skips_output_base_lock() -> Result<(), Box<dyn std::error::Error>> {
+    let workspace = TestWorkspace::example("basic");
+    let razel = || workspace.razel();
+
+    // Stands in for a long build holding the output base.
+    let lock = std::fs::File::create(workspace.output_base().join("lock"))?;
+    lock.lock()?;
+
+    razel()
+        .args(["--batch=false", "--noblock_for_lock", "query", "//..."])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("//:hello_world"));
+    razel()
+        .args(["--batch=false", "--noblock_for_lock", "build", "//..."])
+        .assert()
+        .code(9);
+
+    drop(lock);
+    razel().arg("shutdown").assert().success();
+    Ok(())
+}
+
+#[test]
+fn test_server_relative_output_files() -> Result<(), Box<dyn std::error::Error>> {
+    let workspace = TestWorkspace::example("basic");
+
+    // Written relative to the client's directory, not the server's.
+    workspace
+        .razel()
+        .current_dir(workspace.path().join("nested"))
+        .arg("--batch=false")
+        .arg("build")
+        .arg("--build_event_json_file=bep.json")
+        .arg("--explain=explain.log")
+        .arg("--nobuild")
+        .arg("//:hello_world")
+        .assert()
+        .success();
+    assert!(workspace.path().join("nested/bep.json").exists());
+    assert!(workspace.path().join("nested/explain.log").exists());
+
+    workspace.razel().arg("shutdown").assert().success();
+    Ok(())
+}
+
+#[test]
+fn test_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::{Read, Write};
+
+    let workspace = TestWorkspace::example("basic");
+    workspace
+        .razel()
+        .arg("--batch=false")
+        .arg("query")
+        .arg("--metrics_textfile=metrics.prom")
+        .arg("//...")
+        .assert()
+        .success();
+    let textfile = workspace.read("metrics.prom");
+    assert!(
+        textfile.contains("# TYPE razel_packages_loaded_total counter\n"),
+        "{textfile}"
+    );
+    assert!(
+        textfile.contains("razel_phase_seconds_total{phase=\"query\"} "),
+        "{textfile}"
+    );
+
+    // The server serves the same counters to Prometheus, given the request cookie.
+    let addr = std::fs::read_to_string(workspace.output_base().join("server/command_port"))?;
+    let cookie = std::fs::read_to_string(workspace.output_base().join("server/request_cookie"))?;
+    let get = |authorization: &str| -> std::io::Result<String> {
+        let mut stream = std::net::TcpStream::connect(addr.trim())?;
+        write!(
+            stream,
+            "GET /metrics HTTP/1.1\r\nHost: localhost\r\n{authorization}Connection: close\r\n\r\n"
+        )?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        Ok(response)
+    };
+    let response = get("")?;
+    assert!(response.starts_with("HTTP/1.1 401 "), "{response}");
+    let response = get(&format!("Authorization: Bearer {cookie}\r\n"))?;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(
+        response.contains("razel_phase_seconds_total{phase=\"query\"} "),
+        "{response}"
+    );
+
+    workspace.razel().arg("shutdown").assert().success();
+    Ok(())
+}
+
+#[test]
+fn test_server_relative_input_files() -> Result<(), Box<dyn std::error::Error>> {
+    let workspace = TestWorkspace::example("basic");
+    workspace.write("nested/patterns.txt", "//:hello_world\n");
+    let razel = || {
+        let mut cmd = workspace.razel();
+        cmd.current_dir(workspace.path().join("nested"));
+        cmd
+    };
+
+    // Read relative to the client's directory, not the server's.
+    razel()
+        .arg("--batch=false")
+        .arg("build")
+        .arg("--nobuild")
+        .arg("--target_pattern_file=patterns.txt")
+        .assert()
+        .success();
+
+    razel()
+        .arg("--profile=profile.json")
+        .arg("query")
+        .arg("//...")
+        .assert()
+        .success();
+    razel()
+        .arg("--batch=false")
+        .arg("analyze-profile")
+        .arg("profile.json")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("=== CRITICAL PATH"));
+
+    workspace.razel().arg("shutdown").assert().success();
+    Ok(())
+}
+
+#[test]
+fn test_server_cookies_differ() -> Result<(), Box<dyn std::error::Error>> {
+    let workspaces = [
+        TestWorkspace::example("basic"),
+        TestWorkspace::example("basic"),
+    ];
+    let mut cookies = Vec::new();
+    for workspace in &workspaces {
+        workspace
+            .razel()
+            .args(["--batch=false", "query", "//..."])
+            .assert()
+            .success();
+        let cookie =
+            std::fs::read_to_string(workspace.output_base().join("server/request_cookie"))?;
+        // 32 random bytes, in hex.
+        assert_eq!(cookie.len(), 64, "{cookie}");
+        cookies.push(cookie);
+    }
+    assert_ne!(cookies[0], cookies[1]);
+
+    for workspace in &workspaces {
+        workspace.razel().arg("shutdown").assert().success();
+    }
+    Ok(())
+}
+
+#[test]
+fn test_server_without_console_port() -> Result<(), Box<dyn std::error::Error>> {
+    let workspace = TestWorkspace::example("basic");
+
+    // The client keeps the tokio console's default port while the server it starts comes up.
+    workspace
+        .razel()
+        .env_remove("TOKIO_CONSOLE_BIND")
+        .args(["--batch=false", "query", "//..."])
+        .assert()
+        .success();
+    let log = std::fs::read_to_string(workspace.output_base().join("server/server.log"))?;
+    assert!(!log.contains("panicked"), "{log}");
+
+    workspace.razel().arg("shutdown").assert().success();
+    Ok(())
+}